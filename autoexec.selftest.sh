@@ -0,0 +1,11 @@
+# ./run_qemu.sh -a autoexec.selftest.sh
+# カーネル内のテストは起動時に走り、失敗すればpanicしてQEMUが失敗(35)で終わる
+# ここまで来ればUSBデバイスが揃うのを待ち、状態を出して成功(33)で終える
+#panic=exit
+#serial-mirror
+#delay 200
+#wait-usb-ready
+usbstat
+defer
+heapstat
+#exit-qemu
@@ -13,6 +13,18 @@ type Elf64_Sxword = i64;
 
 const EI_NIDENT: usize = 16;
 
+/// e_type: 位置独立実行形式。どこに置いても、再配置を当てれば動く
+pub const ET_DYN: Elf64_Half = 3;
+
+/// 動的セクションのタグ
+const DT_NULL: Elf64_Sxword = 0;
+const DT_RELA: Elf64_Sxword = 7;
+const DT_RELASZ: Elf64_Sxword = 8;
+const DT_RELAENT: Elf64_Sxword = 9;
+
+/// 置いた先の先頭アドレス + r_addend を書く再配置
+const R_X86_64_RELATIVE: Elf64_Word = 8;
+
 #[repr(C)]
 pub struct Elf64_Ehdr {
     pub e_ident: [u8; EI_NIDENT],
@@ -79,6 +91,22 @@ pub enum Elf64_PhdrType {
     PT_TLS = 7,
 }
 
+/// セクションヘッダの各要素
+/// ELFファイル中の sh_offset:sh_offset+sh_size がセクションの中身
+#[repr(C)]
+pub struct Elf64_Shdr {
+    pub sh_name: Elf64_Word, // .shstrtab中の名前のオフセット
+    pub sh_type: Elf64_Word,
+    pub sh_flags: Elf64_Xword,
+    pub sh_addr: Elf64_Addr,
+    pub sh_offset: Elf64_Off,
+    pub sh_size: Elf64_Xword,
+    pub sh_link: Elf64_Word,
+    pub sh_info: Elf64_Word,
+    pub sh_addralign: Elf64_Xword,
+    pub sh_entsize: Elf64_Xword,
+}
+
 #[repr(C)]
 union D_UN_Type {
     d_val: Elf64_Xword,
@@ -86,19 +114,42 @@ union D_UN_Type {
 }
 
 #[repr(C)]
-
 pub struct Elf64_Dyn {
     d_tag: Elf64_Sxword,
     d_un: D_UN_Type,
 }
 
+/// .rela.dynの各要素
+#[repr(C)]
+pub struct Elf64_Rela {
+    pub r_offset: Elf64_Addr,
+    pub r_info: Elf64_Xword,
+    pub r_addend: Elf64_Sxword,
+}
+
+impl Elf64_Rela {
+    pub fn r_type(&self) -> Elf64_Word {
+        (self.r_info & 0xffff_ffff) as Elf64_Word
+    }
+}
+
+#[derive(Debug)]
+pub enum RelocationError {
+    /// R_X86_64_RELATIVE以外の再配置。中身はその種類
+    Unsupported(Elf64_Word),
+    /// RELAの表が読み込んだ範囲の外にある
+    OutOfImage,
+}
+
 pub struct ElfFile<'a> {
+    pub buffer: &'a [u8],
     pub elf_header: &'a Elf64_Ehdr,
-    pub prog_headers: &'a [Elf64_Phdr]
+    pub prog_headers: &'a [Elf64_Phdr],
+    pub section_headers: &'a [Elf64_Shdr],
 }
 
 impl <'a> ElfFile<'a> {
-    pub unsafe fn from_buffer(buffer: &[u8]) -> Self{
+    pub unsafe fn from_buffer(buffer: &'a [u8]) -> Self{
         let elf_header: &Elf64_Ehdr = &*(buffer.as_ptr() as *const Elf64_Ehdr);
         
         let prog_headers: &[Elf64_Phdr] = from_raw_parts(
@@ -106,7 +157,87 @@ impl <'a> ElfFile<'a> {
             elf_header.e_phnum as usize,
         );
 
-        Self { elf_header, prog_headers }
+        // セクションヘッダは無いこともある(e_shoff=0)
+        let section_headers: &[Elf64_Shdr] = if elf_header.e_shoff == 0 || elf_header.e_shentsize as usize != core::mem::size_of::<Elf64_Shdr>() {
+            &[]
+        } else {
+            from_raw_parts(
+                buffer.as_ptr().offset(elf_header.e_shoff as isize) as *const Elf64_Shdr,
+                elf_header.e_shnum as usize,
+            )
+        };
+
+        Self { buffer, elf_header, prog_headers, section_headers }
+    }
+
+    /// ファイル中のセクションの中身
+    pub fn section_data(&self, shdr: &Elf64_Shdr) -> &'a [u8] {
+        self.buffer.get(shdr.sh_offset as usize .. (shdr.sh_offset + shdr.sh_size) as usize).unwrap_or(&[])
+    }
+
+    /// 名前でセクションを探す。名前は.shstrtab(e_shstrndx番目のセクション)から引く
+    pub fn find_section(&self, name: &str) -> Option<&'a Elf64_Shdr> {
+        let names = self.section_data(self.section_headers.get(self.elf_header.e_shstrndx as usize)?);
+        self.section_headers.iter().find(|shdr| {
+            let tail = names.get(shdr.sh_name as usize..).unwrap_or(&[]);
+            tail.strip_prefix(name.as_bytes()).is_some_and(|rest| rest.first() == Some(&0))
+        })
+    }
+
+    pub fn is_pie(&self) -> bool {
+        self.elf_header.e_type == ET_DYN
+    }
+
+    /// PT_DYNAMICの中身。無ければ空
+    fn dynamic(&self) -> &'a [Elf64_Dyn] {
+        let Some(phdr) = self.prog_headers.iter().find(|h| h.p_type == Elf64_PhdrType::PT_DYNAMIC) else {
+            return &[];
+        };
+        let Some(bytes) = self.buffer.get(phdr.infile_range().0 as usize .. phdr.infile_range().1 as usize) else {
+            return &[];
+        };
+        unsafe { from_raw_parts(bytes.as_ptr() as *const Elf64_Dyn, bytes.len() / core::mem::size_of::<Elf64_Dyn>()) }
+    }
+
+    /// imageに読み込んだPT_LOADに、PT_DYNAMICのRELAの再配置を当てる。imageの先頭はcalc_load_address_rangeのfirstに当たり、
+    /// biasはリンク時のアドレスに足すずれ。当てた数を返す
+    /// 位置独立なカーネルにはR_X86_64_RELATIVEしか無いはずなので、それ以外が来たら失敗にする
+    pub fn apply_relocations(&self, image: &mut [u8], bias: u64) -> Result<usize, RelocationError> {
+        let first = self.calc_load_address_range().0;
+        let offset_in_image = |vaddr: u64, len: usize| {
+            let at = vaddr.checked_sub(first)? as usize;
+            Some(at..at.checked_add(len)?)
+        };
+        let (mut rela, mut relasz, mut relaent) = (None, 0, core::mem::size_of::<Elf64_Rela>() as u64);
+        for dyn_ in self.dynamic() {
+            let val = unsafe { dyn_.d_un.d_val };
+            match dyn_.d_tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(val),
+                DT_RELASZ => relasz = val,
+                DT_RELAENT => relaent = val,
+                _ => (),
+            }
+        }
+        let Some(rela) = rela else {
+            return Ok(0);
+        };
+        if relaent < core::mem::size_of::<Elf64_Rela>() as u64 {
+            return Err(RelocationError::OutOfImage);
+        }
+        let count = relasz / relaent;
+        for i in 0..count {
+            let range = offset_in_image(rela + i * relaent, core::mem::size_of::<Elf64_Rela>()).ok_or(RelocationError::OutOfImage)?;
+            let entry = image.get(range).ok_or(RelocationError::OutOfImage)?;
+            let entry = unsafe { (entry.as_ptr() as *const Elf64_Rela).read_unaligned() };
+            if entry.r_type() != R_X86_64_RELATIVE {
+                return Err(RelocationError::Unsupported(entry.r_type()));
+            }
+            let range = offset_in_image(entry.r_offset, 8).ok_or(RelocationError::OutOfImage)?;
+            let target = image.get_mut(range).ok_or(RelocationError::OutOfImage)?;
+            target.copy_from_slice(&bias.wrapping_add(entry.r_addend as u64).to_le_bytes());
+        }
+        Ok(count as usize)
     }
 
     pub fn calc_load_address_range(&self) -> (u64, u64){
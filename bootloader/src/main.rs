@@ -11,7 +11,7 @@ use core::{arch::asm, ffi::c_void, mem::transmute};
 
 use frame_buffer::{FrameBufferConfig, PixelFormat};
 use memory_map::MemoryMapRaw;
-use uefi::{data_types::PhysicalAddress, prelude::*, proto::console::gop::GraphicsOutput, table::{boot::{AllocateType, MemoryType, OpenProtocolParams, ScopedProtocol, SearchType}, cfg::{ACPI2_GUID, ACPI_GUID}}, Result};
+use uefi::{data_types::PhysicalAddress, prelude::*, proto::console::gop::{GraphicsOutput, Mode}, table::{boot::{AllocateType, MemoryType, OpenProtocolParams, ScopedProtocol, SearchType}, cfg::{ACPI2_GUID, ACPI_GUID}}, Result};
 
 use crate::elf::{ElfFile, Elf64_PhdrType};
 
@@ -58,8 +58,33 @@ fn copy_slice_pad(to: &mut [u8], from: &[u8]) {
     to[from.len()..].fill(0);
 }
 
-type EntryPointFn = extern "sysv64" fn(*const FrameBufferConfig, *const MemoryMapRaw, *const c_void);
-unsafe fn load_kernel(boot_services: &BootServices, image_handle: Handle) -> EntryPointFn {
+/// カーネルに渡すautoexecスクリプト。無ければptrはnull
+#[repr(C)]
+struct BootScript {
+    ptr: *const u8,
+    len: usize,
+}
+
+/// カーネルの.symtabと.strtabのコピー。パニック時にアドレスを関数名にするのに使う。無ければsymtabはnull
+#[repr(C)]
+struct KernelSymbols {
+    symtab: *mut u8,
+    symtab_len: usize,
+    strtab: *const u8,
+    strtab_len: usize,
+    /// カーネルを置いたアドレスとリンク時のアドレスの差。位置独立でなければ0
+    load_bias: u64,
+}
+
+/// ESPの\initrd.imgの中身。無ければptrはnull
+#[repr(C)]
+struct Initrd {
+    ptr: *const u8,
+    len: usize,
+}
+
+type EntryPointFn = extern "sysv64" fn(*const FrameBufferConfig, *const MemoryMapRaw, *const c_void, *const BootScript, *const KernelSymbols, *const Initrd);
+unsafe fn load_kernel(boot_services: &BootServices, image_handle: Handle) -> (EntryPointFn, KernelSymbols) {
     let mut fs = boot_services.get_image_file_system(image_handle).expect("failed to get file system");
     let kernel_file = fs.read(cstr16!("\\kernel.elf")).expect("failed to read '\\kernel.elf'");
     
@@ -67,30 +92,201 @@ unsafe fn load_kernel(boot_services: &BootServices, image_handle: Handle) -> Ent
     let loads = elf_file.prog_headers.iter().filter(|h|h.p_type == Elf64_PhdrType::PT_LOAD);
     
     let (first, last) = elf_file.calc_load_address_range();
-    uefi_services::println!("Kernel: 0x{:0x} - 0x{:0x} ({} bytes)", first, last, last - first);
-
-    boot_services.allocate_pages(
-        AllocateType::Address(first), 
-        MemoryType::LOADER_DATA,
-        ((last - first) as usize + 0xfff) / 0x1000
-    ).expect("failed to allocate pages");
+    let pages = ((last - first) as usize + 0xfff) / 0x1000;
+    let bias = if elf_file.is_pie() {
+        // 位置独立ならどこに置いてもよい。PT_LOADの境界をずらさないよう、最大のp_alignに揃える
+        let align = elf_file.prog_headers.iter()
+            .filter(|h| h.p_type == Elf64_PhdrType::PT_LOAD)
+            .map(|h| h.p_align.max(0x1000))
+            .max().unwrap_or(0x1000);
+        let extra = (align / 0x1000) as usize - 1;
+        let addr = boot_services.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages + extra)
+            .unwrap_or_else(|e| {
+                uefi_services::println!("failed to allocate {} pages anywhere for the kernel ({:?})", pages + extra, e.status());
+                halt();
+            });
+        (addr + align - 1) / align * align - first
+    } else {
+        if let Err(e) = boot_services.allocate_pages(AllocateType::Address(first), MemoryType::LOADER_DATA, pages) {
+            uefi_services::println!("failed to allocate 0x{:0x} - 0x{:0x} for the kernel ({:?})", first, last, e.status());
+            print_memory_map_overlapping(boot_services, first, last);
+            halt();
+        }
+        0
+    };
+    uefi_services::println!("Kernel: 0x{:0x} - 0x{:0x} ({} bytes)", first + bias, last + bias, last - first);
 
     // copy LOAD sections from kernel file to memory
     for phdr in loads {
-        let buffer = core::slice::from_raw_parts_mut(phdr.inmem_range().0 as *mut u8, phdr.inmem_size() as usize);
+        let buffer = core::slice::from_raw_parts_mut((phdr.inmem_range().0 + bias) as *mut u8, phdr.inmem_size() as usize);
         let file = &kernel_file[phdr.infile_range().0 as usize .. phdr.infile_range().1 as usize];
         copy_slice_pad(buffer, file);
-        uefi_services::println!("Loaded section: 0x{:0x} - 0x{:0x} ({} bytes)", phdr.inmem_range().0 , phdr.inmem_range().1, phdr.inmem_size());
+        uefi_services::println!("Loaded section: 0x{:0x} - 0x{:0x} ({} bytes)", phdr.inmem_range().0 + bias, phdr.inmem_range().1 + bias, phdr.inmem_size());
+    }
+
+    if elf_file.is_pie() {
+        let image = core::slice::from_raw_parts_mut((first + bias) as *mut u8, (last - first) as usize);
+        match elf_file.apply_relocations(image, bias) {
+            Ok(n) => uefi_services::println!("Applied {} relocations, load bias 0x{:0x}", n, bias),
+            Err(e) => {
+                uefi_services::println!("failed to relocate the kernel ({:?})", e);
+                halt();
+            }
+        }
     }
     
-    uefi_services::println!("Entry point: 0x{:0x}", elf_file.elf_header.e_entry);
+    let entry = elf_file.elf_header.e_entry + bias;
+    uefi_services::println!("Entry point: 0x{:0x}", entry);
+
+    let mut symbols = load_kernel_symbols(boot_services, &elf_file);
+    symbols.load_bias = bias;
+    (unsafe { transmute(entry) }, symbols)
+}
+
+/// [first, last)に重なるメモリマップのエントリを出す。カーネルを置けなかったときに、何が使っているかを見る
+fn print_memory_map_overlapping(boot_services: &BootServices, first: u64, last: u64) {
+    let mut buf = [0u8; 4096*4];
+    let Ok(memmap) = boot_services.memory_map(&mut buf) else {
+        uefi_services::println!("  (memory map unavailable)");
+        return;
+    };
+    for desc in memmap.entries().filter(|d| d.phys_start < last && first < d.phys_start + d.page_count * 0x1000) {
+        uefi_services::println!(
+            "  {:?}: 0x{:0x} - 0x{:0x} ({} pages)",
+            desc.ty, desc.phys_start, desc.phys_start + desc.page_count * 0x1000, desc.page_count
+        );
+    }
+}
+
+/// 中身をLOADER_DATAのページにコピーする。カーネルはそのページをそのまま読み書きする
+fn copy_section_to_pages(boot_services: &BootServices, data: &[u8]) -> Option<*mut u8> {
+    let addr = boot_services.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, (data.len() + 0xfff) / 0x1000).ok()?;
+    let pages = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, data.len()) };
+    pages.copy_from_slice(data);
+    Some(pages.as_mut_ptr())
+}
+
+/// .symtabと.strtabを探してコピーする。stripされたカーネルなら何も渡さない
+fn load_kernel_symbols(boot_services: &BootServices, elf_file: &ElfFile) -> KernelSymbols {
+    let none = KernelSymbols { symtab: core::ptr::null_mut(), symtab_len: 0, strtab: core::ptr::null(), strtab_len: 0, load_bias: 0 };
+    let (Some(symtab), Some(strtab)) = (elf_file.find_section(".symtab"), elf_file.find_section(".strtab")) else {
+        uefi_services::println!("Kernel symbols: not found");
+        return none;
+    };
+    let (symtab, strtab) = (elf_file.section_data(symtab), elf_file.section_data(strtab));
+    match (copy_section_to_pages(boot_services, symtab), copy_section_to_pages(boot_services, strtab)) {
+        (Some(symtab_ptr), Some(strtab_ptr)) => {
+            uefi_services::println!("Kernel symbols: {} + {} bytes", symtab.len(), strtab.len());
+            KernelSymbols { symtab: symtab_ptr, symtab_len: symtab.len(), strtab: strtab_ptr, strtab_len: strtab.len(), load_bias: 0 }
+        }
+        _ => none,
+    }
+}
+
+/// ESPに\autoexec.shがあれば読み込む。バッファはLOADER_DATAなので、ブートサービス終了後もカーネルから読める
+fn load_autoexec(boot_services: &BootServices, image_handle: Handle) -> BootScript {
+    let script = boot_services.get_image_file_system(image_handle).ok()
+        .and_then(|mut fs| fs.read(cstr16!("\\autoexec.sh")).ok());
+    match script {
+        Some(script) => {
+            uefi_services::println!("autoexec.sh: {} bytes", script.len());
+            let script = script.leak();
+            BootScript { ptr: script.as_ptr(), len: script.len() }
+        }
+        None => BootScript { ptr: core::ptr::null(), len: 0 },
+    }
+}
+
+/// ESPの\boot.cfgの設定。1行に1つ`key=value`を書く
+#[derive(Default)]
+struct BootConfig {
+    /// resolution=WxH。PREFERRED_RESOLUTIONSより優先する
+    resolution: Option<(usize, usize)>,
+}
+
+fn parse_boot_config(text: &str) -> BootConfig {
+    let mut config = BootConfig::default();
+    for line in text.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match key.trim() {
+            "resolution" => {
+                config.resolution = value.trim().split_once('x')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+                if config.resolution.is_none() {
+                    uefi_services::println!("boot.cfg: ignoring resolution '{}'", value.trim());
+                }
+            }
+            _ => uefi_services::println!("boot.cfg: unknown key '{}'", key.trim()),
+        }
+    }
+    config
+}
+
+/// ESPに\boot.cfgがあれば読む。無ければ既定の設定
+fn load_boot_config(boot_services: &BootServices, image_handle: Handle) -> BootConfig {
+    let text = boot_services.get_image_file_system(image_handle).ok()
+        .and_then(|mut fs| fs.read(cstr16!("\\boot.cfg")).ok());
+    match text {
+        Some(text) => parse_boot_config(core::str::from_utf8(&text).unwrap_or("")),
+        None => BootConfig::default(),
+    }
+}
 
-    unsafe { transmute(elf_file.elf_header.e_entry) }
+/// 上から順に、GOPにあればその解像度のモードにする
+const PREFERRED_RESOLUTIONS: [(usize, usize); 3] = [(1920, 1080), (1366, 768), (1024, 768)];
+
+fn is_supported_mode(mode: &Mode) -> bool {
+    matches!(mode.info().pixel_format(), uefi::proto::console::gop::PixelFormat::Rgb | uefi::proto::console::gop::PixelFormat::Bgr)
+}
+
+/// boot.cfgの解像度、PREFERRED_RESOLUTIONSの順に探して切り替える。見つからない・切り替えに失敗したら今のモードのまま
+fn select_gop_mode(gop: &mut GraphicsOutput, config: &BootConfig) {
+    if let Some((w, h)) = config.resolution {
+        if !gop.modes().any(|m| is_supported_mode(&m) && m.info().resolution() == (w, h)) {
+            uefi_services::println!("boot.cfg: no {}x{} mode, using the preferred list", w, h);
+        }
+    }
+    let mode = config.resolution.into_iter().chain(PREFERRED_RESOLUTIONS)
+        .find_map(|res| gop.modes().find(|m| is_supported_mode(m) && m.info().resolution() == res));
+    let Some(mode) = mode else {
+        uefi_services::println!("GOP: no preferred mode, keeping the current one");
+        return;
+    };
+    if mode.info().resolution() == gop.current_mode_info().resolution() {
+        return;
+    }
+    if let Err(e) = gop.set_mode(&mode) {
+        let (w, h) = mode.info().resolution();
+        uefi_services::println!("GOP: failed to set {}x{} ({:?}), keeping the current mode", w, h, e.status());
+    }
+}
+
+/// ESPに\initrd.imgがあればLOADER_DATAのページに読み込む。カーネルはブートサービス終了後もそのまま読む
+fn load_initrd(boot_services: &BootServices, image_handle: Handle) -> Initrd {
+    let image = boot_services.get_image_file_system(image_handle).ok()
+        .and_then(|mut fs| fs.read(cstr16!("\\initrd.img")).ok());
+    let Some(image) = image else {
+        return Initrd { ptr: core::ptr::null(), len: 0 };
+    };
+    match copy_section_to_pages(boot_services, &image) {
+        Some(ptr) => {
+            uefi_services::println!("initrd.img: {} bytes at 0x{:0x}", image.len(), ptr as u64);
+            Initrd { ptr, len: image.len() }
+        }
+        None => {
+            uefi_services::println!("initrd.img: failed to allocate {} bytes, skipping", image.len());
+            Initrd { ptr: core::ptr::null(), len: 0 }
+        }
+    }
 }
 
-fn construct_frame_buffer(boot_services: &BootServices) -> Result<FrameBufferConfig> {
+fn construct_frame_buffer(boot_services: &BootServices, config: &BootConfig) -> Result<FrameBufferConfig> {
     let gop_handle = boot_services.get_handle_for_protocol::<GraphicsOutput>()?;
     let mut gop = boot_services.open_protocol_exclusive::<GraphicsOutput>(gop_handle)?;
+    select_gop_mode(&mut gop, config);
+    print_gop_info(&mut gop);
     Ok(FrameBufferConfig {
         frame_buffer: gop.frame_buffer().as_mut_ptr(),
         horizontal_resolution: gop.current_mode_info().resolution().0 as u32,
@@ -99,7 +295,7 @@ fn construct_frame_buffer(boot_services: &BootServices) -> Result<FrameBufferCon
         pixel_format: match gop.current_mode_info().pixel_format() {
             uefi::proto::console::gop::PixelFormat::Rgb => PixelFormat::PixelRGBResv8BitPerColor,
             uefi::proto::console::gop::PixelFormat::Bgr => PixelFormat::PixelBGRResv8BitPerColor,
-            _ => panic!("unsupported pixel format")
+            _ => return Err(Status::UNSUPPORTED.into())
         }
     })
 }
@@ -117,12 +313,17 @@ unsafe fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Sta
 
     let boot_services = system_table.boot_services();
 
-    let entry_point = load_kernel(boot_services, image_handle);
+    let (entry_point, symbols) = load_kernel(boot_services, image_handle);
+    let script = load_autoexec(boot_services, image_handle);
+    let config = load_boot_config(boot_services, image_handle);
+    let initrd = load_initrd(boot_services, image_handle);
     
     let acpi_table_address = find_acpi_table(&system_table);
     
-    let frame_buffer_config = construct_frame_buffer(boot_services)
-        .expect("failed to construct frame buffer config");
+    // GOPが無い・使えない環境ではフレームバッファ無し(ヘッドレス)でカーネルを起動する
+    let frame_buffer_config = construct_frame_buffer(boot_services, &config)
+        .map_err(|e| uefi_services::println!("no usable frame buffer ({:?}), booting headless", e.status()))
+        .ok();
 
     let mut memmap_buf = [0u8; 4096*4];
     let memmap = get_memory_map(boot_services, &mut memmap_buf);
@@ -130,7 +331,8 @@ unsafe fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Sta
 
     let (_, _) = system_table.exit_boot_services();
 
-    entry_point(&frame_buffer_config as _, &memmap as _, acpi_table_address);
+    let frame_buffer_config = frame_buffer_config.as_ref().map_or(core::ptr::null(), |c| c as _);
+    entry_point(frame_buffer_config, &memmap as _, acpi_table_address, &script as _, &symbols as _, &initrd as _);
 
     halt();
 }
@@ -0,0 +1,11 @@
+#!/bin/sh
+# ポート番号・スロットID・DCIを`as`でキャストしているところが無いか調べる
+# 生の値との変換はusb/doorbell.rsのPortId/SlotId/Dciにだけ置く(usb/context.rsはビルドされない)
+cd "$(dirname "$0")/src"
+found=$(grep -rnE "(^|[^A-Za-z_])(port|slot|dci)[A-Za-z_]*(\(\))?\)? as (u8|u16|u32|u64|usize)" --include=*.rs . \
+    | grep -v "^./usb/doorbell.rs:" | grep -v "^./usb/context.rs:")
+if [ -n "$found" ]; then
+    echo "casts of port/slot/dci numbers outside usb/doorbell.rs:"
+    echo "$found"
+    exit 1
+fi
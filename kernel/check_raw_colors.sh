@@ -0,0 +1,17 @@
+#!/bin/sh
+# 描画に関わるモジュールに (r, g, b) のような数値リテラルだけのタプルが無いか調べる
+# 色はgraphic/paletteの定数かColor::newで書く。色でない3つ組の行には`// not a color`を付ける
+cd "$(dirname "$0")/src"
+tuple="(^|[^A-Za-z0-9_])\( *[0-9][0-9A-Fa-fx_]* *, *[0-9][0-9A-Fa-fx_]* *, *[0-9][0-9A-Fa-fx_]* *\)"
+files="graphic/mod.rs graphic/desktop.rs graphic/window.rs graphic/font.rs graphic/graphics.rs
+    graphic/frame_buffer.rs graphic/buffered.rs graphic/focus.rs
+    console.rs mouse.rs latency.rs viewer.rs taskB.rs main.rs"
+found=$(grep -nE "$tuple" $files /dev/null | grep -v "// not a color")
+# palette.rs自身はテストより前だけを見る
+palette=$(sed '/^pub fn run_palette_tests/,$d' graphic/palette.rs | grep -nE "$tuple" | sed 's|^|graphic/palette.rs:|')
+found=$(printf "%s\n%s" "$found" "$palette" | sed '/^$/d')
+if [ -n "$found" ]; then
+    echo "raw color tuples; use graphic::palette:"
+    echo "$found"
+    exit 1
+fi
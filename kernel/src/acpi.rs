@@ -1,9 +1,10 @@
-use core::{mem::{size_of, size_of_val}, slice::from_raw_parts};
+use core::{mem::{size_of, size_of_val}, slice::from_raw_parts, sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering}};
 
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::vec::Vec;
 
-use crate::{memory_manager::LazyInit, asm};
+use crate::{addr::PhysAddr, asm, hpet, memory_manager::{LazyInit, Mutex}, paging::map_mmio, platform::qemu, println, shortcut::{self, Mods}, warn};
 
 #[repr(C, packed)]
 pub struct RSDP {
@@ -85,24 +86,217 @@ impl<'a> XSDT<'a> {
     }
 }
 
+/// Generic Address Structure。レジスタがどのアドレス空間のどこにあるか
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct GenericAddress {
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+const ADDRESS_SPACE_IO: u8 = 1;
+
+impl GenericAddress {
+    /// 8bitのレジスタに書く。メモリかI/Oポートの空間にあるものだけを扱い、書けたかを返す
+    unsafe fn write_8(&self, value: u8) -> bool {
+        let (space, address) = (self.address_space_id, self.address);
+        match space {
+            _ if address == 0 => false,
+            ADDRESS_SPACE_MEMORY => {
+                if map_mmio(PhysAddr::new(address), 0x1000).is_err() {
+                    return false;
+                }
+                core::ptr::write_volatile(address as *mut u8, value);
+                true
+            }
+            ADDRESS_SPACE_IO => {
+                asm::io_out_8(address as u16, value);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
 #[repr(C, packed)]
 struct FADT {
     header: DescriptionHeader,
-    reserved1: [u8;76-size_of::<DescriptionHeader>()],
+    reserved0: [u8; 40-size_of::<DescriptionHeader>()],
+    dsdt: u32,
+    reserved1a: [u8; 48-44],
+    smi_cmd: u32,
+    acpi_enable: u8,
+    reserved1b: [u8; 64-53],
+    pm1a_cnt_blk: u32,
+    pm1b_cnt_blk: u32,
+    reserved1c: [u8; 76-72],
     pm_tmr_blk: u32,
-    reserved2: [u8;112-80],
+    reserved2: [u8;108-80],
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved2b: [u8;112-111],
     flags: u32,
-    reserved3: [u8; 276-116]
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    reserved3: [u8; 140-129],
+    x_dsdt: u64,
+    reserved4: [u8; 244-148],
+    sleep_control_reg: GenericAddress,
+    reserved5: [u8; 276-256],
 }
 
+/// FADTのflagsのビット
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+/// FADTのIAPC_BOOT_ARCHのビット。8042(PS/2コントローラ)がある
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
 impl FADT {
     unsafe fn from_header(header: &DescriptionHeader) -> & FADT {
         &*(header as *const DescriptionHeader as *const FADT)
     }
+
+    /// offsetから始まるフィールドが表の中にあるか。古い版のFADTは短い
+    fn has_field(&self, offset: usize) -> bool {
+        (self.header.length as usize) > offset
+    }
+
+    /// DSDTの先頭。64bitのアドレスがあればそちらを使う
+    fn dsdt_address(&self) -> u64 {
+        let x_dsdt = if self.has_field(140) { self.x_dsdt } else { 0 };
+        if x_dsdt != 0 { x_dsdt } else { self.dsdt as u64 }
+    }
 }
 
 static FADT: LazyInit<&FADT> = LazyInit::new();
 
+/// HPETテーブル。base_addressはGeneric Address Structureで、addressがレジスタの先頭
+#[repr(C, packed)]
+struct HPET {
+    header: DescriptionHeader,
+    event_timer_block_id: u32,
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    reserved: u8,
+    address: u64,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+impl HPET {
+    unsafe fn from_header(header: &DescriptionHeader) -> &HPET {
+        &*(header as *const DescriptionHeader as *const HPET)
+    }
+}
+
+/// MADTが無いときのLocal APICのレジスタの物理アドレス
+const DEFAULT_LOCAL_APIC_BASE: u64 = 0xfee0_0000;
+/// 割り込みハンドラからも読むので、ロックを取らずに読めるようにしておく
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(DEFAULT_LOCAL_APIC_BASE);
+/// MADTに載っている有効なCPUの数。MADTが無ければ1
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static IO_APICS: LazyInit<&'static [IoApicInfo]> = LazyInit::new();
+static OVERRIDES: LazyInit<&'static [InterruptOverride]> = LazyInit::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    /// レジスタの物理アドレス
+    pub address: u32,
+    /// このI/O APICの最初の入力が受け持つGSI
+    pub gsi_base: u32,
+}
+
+/// ISAのIRQがI/O APICの別の入力(GSI)に繋がっていることを表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// 極性とトリガーモード(MPS INTI flags)
+    pub flags: u16,
+}
+
+/// MADTのエントリの一覧から読み取ったもの
+#[derive(Debug, Default, PartialEq, Eq)]
+struct MadtInfo {
+    local_apic_base: u64,
+    cpu_count: usize,
+    io_apics: Vec<IoApicInfo>,
+    overrides: Vec<InterruptOverride>,
+}
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+/// Local APICのエントリのフラグ。EnabledかOnline Capableなら起動できるCPU
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// MADTのヘッダの後ろ(Local APICのアドレスとフラグの後)に並ぶエントリを読む。壊れたエントリがあればそこで止める
+fn parse_madt_entries(local_apic_address: u32, mut entries: &[u8]) -> MadtInfo {
+    let mut info = MadtInfo { local_apic_base: local_apic_address as u64, ..Default::default() };
+    let u16_at = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+    let u32_at = |b: &[u8], i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+    while let &[ty, len, ..] = entries {
+        let Some(entry) = entries.get(..len as usize).filter(|_| len >= 2) else {
+            break;
+        };
+        match (ty, len) {
+            (MADT_LOCAL_APIC, 8..) => {
+                if u32_at(entry, 4) & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    info.cpu_count += 1;
+                }
+            }
+            (MADT_IO_APIC, 12..) => info.io_apics.push(IoApicInfo { id: entry[2], address: u32_at(entry, 4), gsi_base: u32_at(entry, 8) }),
+            (MADT_INTERRUPT_OVERRIDE, 10..) => info.overrides.push(InterruptOverride { irq: entry[3], gsi: u32_at(entry, 4), flags: u16_at(entry, 8) }),
+            (MADT_LOCAL_APIC_ADDRESS_OVERRIDE, 12..) => {
+                info.local_apic_base = u32_at(entry, 4) as u64 | (u32_at(entry, 8) as u64) << 32;
+            }
+            _ => (),
+        }
+        entries = &entries[len as usize..];
+    }
+    info
+}
+
+unsafe fn find_table<'a>(xsdt: &XSDT<'a>, signature: &[u8]) -> Option<&'a DescriptionHeader> {
+    (0..xsdt.count()).map(|i| &*xsdt.entry(i)).find(|entry| entry.is_valid(signature))
+}
+
+/// MADTからLocal APICのアドレスとCPUの数、I/O APICを読み取る。レジスタはキャッシュ無効で写像しておく
+unsafe fn initialize_madt(madt: &DescriptionHeader) {
+    let header_len = size_of::<DescriptionHeader>();
+    let body = from_raw_parts((madt as *const DescriptionHeader as *const u8).add(header_len), madt.length as usize - header_len);
+    let Some(local_apic_address) = body.get(..4) else {
+        return;
+    };
+    let info = parse_madt_entries(u32::from_le_bytes(local_apic_address.try_into().unwrap()), body.get(8..).unwrap_or(&[]));
+    if let Err(e) = map_mmio(PhysAddr::new(info.local_apic_base), 0x1000) {
+        warn!("acpi: could not map the local APIC at {:#x} ({:?}), keeping {:#x}", info.local_apic_base, e, DEFAULT_LOCAL_APIC_BASE);
+    } else {
+        LOCAL_APIC_BASE.store(info.local_apic_base, Ordering::Relaxed);
+    }
+    for io_apic in &info.io_apics {
+        if let Err(e) = map_mmio(PhysAddr::new(io_apic.address as u64), 0x1000) {
+            warn!("acpi: could not map I/O APIC {} at {:#x} ({:?})", io_apic.id, io_apic.address, e);
+        }
+    }
+    CPU_COUNT.store(info.cpu_count.max(1), Ordering::Relaxed);
+    println!(
+        "acpi: {} CPUs, local APIC at {:#x}, {} I/O APICs, {} interrupt overrides",
+        info.cpu_count, info.local_apic_base, info.io_apics.len(), info.overrides.len()
+    );
+    IO_APICS.lock().init(info.io_apics.leak());
+    OVERRIDES.lock().init(info.overrides.leak());
+}
+
 pub unsafe fn initialize(rsdp: &RSDP) {
     if !rsdp.is_valid() {
         panic!("RSDP is not valid");
@@ -115,19 +309,80 @@ pub unsafe fn initialize(rsdp: &RSDP) {
 
     let xsdt = XSDT(xsdt_header);
 
-    let fadt = (0..xsdt.count()).find_map(|i: usize|{
-        let entry = &*xsdt.entry(i);
-        if entry.is_valid(b"FACP") {
-            Some(entry)
-        } else {
-            None
-        }
-    }).expect("FADT is not found in XSDT");
-
+    let fadt = find_table(&xsdt, b"FACP").expect("FADT is not found in XSDT");
     FADT.lock().init(FADT::from_header(fadt));
+    initialize_power(FADT::from_header(fadt));
+
+    match find_table(&xsdt, b"APIC") {
+        Some(madt) => initialize_madt(madt),
+        None => warn!("acpi: no MADT, assuming 1 CPU and the local APIC at {:#x}", DEFAULT_LOCAL_APIC_BASE),
+    }
+    match find_table(&xsdt, b"HPET") {
+        Some(hpet) => initialize_hpet(HPET::from_header(hpet)),
+        None => println!("acpi: no HPET, timing with the PM timer"),
+    }
+}
+
+/// HPETのレジスタはシステムメモリ空間にあるものだけを使う
+unsafe fn initialize_hpet(table: &HPET) {
+    let (space, address) = (table.address_space_id, table.address);
+    if space != 0 {
+        warn!("acpi: HPET registers are in address space {}, timing with the PM timer", space);
+        return;
+    }
+    match hpet::initialize(PhysAddr::new(address)) {
+        Ok(()) if hpet::available() => println!("acpi: HPET at {:#x}, {} Hz", address, hpet::frequency()),
+        Ok(()) => warn!("acpi: HPET at {:#x} reports an invalid period, timing with the PM timer", address),
+        Err(e) => warn!("acpi: could not map the HPET at {:#x} ({:?}), timing with the PM timer", address, e),
+    }
+}
+
+/// Local APICのレジスタの物理アドレス。恒等写像なのでそのまま指せる
+pub fn local_apic_base() -> u64 {
+    LOCAL_APIC_BASE.load(Ordering::Relaxed)
+}
+
+/// Local APICのoffsetにあるレジスタ
+pub fn local_apic_register(offset: u64) -> *mut u32 {
+    (local_apic_base() + offset) as *mut u32
+}
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
+
+/// MADTに載っているI/O APIC。MADTが無ければ空
+pub fn io_apics() -> &'static [IoApicInfo] {
+    let io_apics = IO_APICS.lock();
+    if io_apics.is_init() { *io_apics.get() } else { &[] }
+}
+
+/// ISAのIRQの付け替え。MADTが無ければ空
+pub fn interrupt_overrides() -> &'static [InterruptOverride] {
+    let overrides = OVERRIDES.lock();
+    if overrides.is_init() { *overrides.get() } else { &[] }
+}
+/// RTCの世紀を持つCMOSのレジスタ。無ければNone
+pub fn century_register() -> Option<u8> {
+    let century = FADT.lock().century;
+    (century != 0).then_some(century)
 }
+
+/// FADTが8042(PS/2コントローラ)があると言っているか
+/// IAPC_BOOT_ARCHはACPI 2.0(FADTの版2)からなので、それより古ければあるものとする
+pub fn has_8042() -> bool {
+    let fadt = FADT.lock();
+    let (revision, boot_arch) = (fadt.header.revision, fadt.iapc_boot_arch);
+    revision < 2 || boot_arch & BOOT_ARCH_8042 != 0
+}
+
 const PM_TIMER_FREQ: u32 = 3579545;
+/// HPETがあればそれで、無ければPMタイマーで待つ
 pub fn wait_millis(msec: u32) {
+    if hpet::available() {
+        hpet::busy_wait_ns(msec as u64 * 1_000_000);
+        return;
+    }
     let fadt = FADT.lock();
     let pm_timer_is_32 = (fadt.flags >> 8) & 1 != 0;
 
@@ -143,4 +398,247 @@ pub fn wait_millis(msec: u32) {
         }
         while asm::io_in_32(fadt.pm_tmr_blk as u16) < end {}
     }
-}
\ No newline at end of file
+}
+/// PM1制御レジスタのビット
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_EN: u16 = 1 << 13;
+/// ハードウェア縮小版ACPIのSleep Control Registerのビット
+const SLEEP_CONTROL_TYP_SHIFT: u8 = 2;
+const SLEEP_CONTROL_SLP_EN: u8 = 1 << 5;
+/// ACPI_ENABLEを書いてからSCI_ENが立つのを待つ回数。1回がI/Oポートの読み出し1つ
+const ACPI_ENABLE_POLLS: usize = 1_000_000;
+/// FADTにリセットレジスタが無いときに使う、チップセットのリセット制御レジスタ
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+
+/// 電源を切る・リセットするのに使う、FADTとDSDTから読んだ値。パニックの後でも使えるよう起動時に写しておく
+#[derive(Clone, Copy)]
+struct PowerInfo {
+    hardware_reduced: bool,
+    smi_cmd: u16,
+    acpi_enable: u8,
+    pm1a_cnt: u16,
+    pm1b_cnt: u16,
+    sleep_control: Option<GenericAddress>,
+    /// \_S5のSLP_TYPaとSLP_TYPb。DSDTに見つからなければNone
+    s5: Option<(u8, u8)>,
+    /// リセットレジスタと、そこに書く値
+    reset: Option<(GenericAddress, u8)>,
+}
+
+static POWER: Mutex<Option<PowerInfo>> = Mutex::new(None);
+/// パニックしてからリセットするまでの秒数。0ならリセットせず止まったまま
+static PANIC_REBOOT_SECS: AtomicU32 = AtomicU32::new(0);
+
+/// 電源の操作に使う値をFADTとDSDTから読む
+unsafe fn initialize_power(fadt: &FADT) {
+    let dsdt_address = fadt.dsdt_address();
+    let dsdt = (dsdt_address != 0).then(|| &*(dsdt_address as *const DescriptionHeader));
+    let s5 = dsdt.filter(|dsdt| dsdt.is_valid(b"DSDT")).and_then(|dsdt| {
+        let header_len = size_of::<DescriptionHeader>();
+        find_s5_sleep_type(from_raw_parts((dsdt as *const DescriptionHeader as *const u8).add(header_len), dsdt.length as usize - header_len))
+    });
+    let flags = fadt.flags;
+    let info = PowerInfo {
+        hardware_reduced: flags & FADT_HW_REDUCED_ACPI != 0,
+        smi_cmd: fadt.smi_cmd as u16,
+        acpi_enable: fadt.acpi_enable,
+        pm1a_cnt: fadt.pm1a_cnt_blk as u16,
+        pm1b_cnt: fadt.pm1b_cnt_blk as u16,
+        sleep_control: fadt.has_field(244).then_some(fadt.sleep_control_reg).filter(|reg| { reg.address } != 0),
+        s5,
+        reset: (flags & FADT_RESET_REG_SUP != 0 && fadt.has_field(128)).then_some((fadt.reset_reg, fadt.reset_value)),
+    };
+    match s5 {
+        Some((a, b)) => println!("acpi: S5 sleep type {}/{}, PM1a control at {:#x}", a, b, info.pm1a_cnt),
+        None => warn!("acpi: no \\_S5 in the DSDT, shutdown only works on QEMU"),
+    }
+    *POWER.lock() = Some(info);
+}
+
+/// AMLのバイト列からName(_S5, Package(){...})を探し、最初の2つの要素(SLP_TYPaとSLP_TYPb)を返す。
+/// AMLは解釈せず、この形に並んだバイトだけを見る。要素が1つしかなければSLP_TYPbは0
+fn find_s5_sleep_type(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    for i in (0..aml.len().saturating_sub(3)).filter(|&i| &aml[i..i + 4] == b"_S5_") {
+        let before = &aml[..i];
+        if !before.ends_with(&[NAME_OP]) && !before.ends_with(&[NAME_OP, b'\\']) {
+            continue;
+        }
+        let Some(package) = aml[i + 4..].strip_prefix(&[PACKAGE_OP]) else {
+            continue;
+        };
+        // PkgLengthは先頭バイトの上2bitが後に続くバイト数。その次が要素の数
+        let &lead = package.first()?;
+        let mut elements = package.get(1 + (lead >> 6) as usize + 1..)?;
+        let mut next = || {
+            let (value, len) = match elements {
+                [0x00, ..] => (0, 1),
+                [0x01, ..] => (1, 1),
+                // BytePrefix, WordPrefix, DWordPrefix
+                [0x0a, v, ..] => (*v, 2),
+                [0x0b, v, _, ..] => (*v, 3),
+                [0x0c, v, _, _, _, ..] => (*v, 5),
+                _ => return None,
+            };
+            elements = &elements[len..];
+            Some(value)
+        };
+        let typ_a = next()?;
+        return Some((typ_a, next().unwrap_or(0)));
+    }
+    None
+}
+
+/// SLP_TYPをtypにし、SLP_ENを立てたPM1制御レジスタの値。他のビットはcurrentのまま
+fn pm1_cnt_value(current: u16, typ: u8) -> u16 {
+    (current & !(0b111 << PM1_SLP_TYP_SHIFT)) | ((typ as u16 & 0b111) << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN
+}
+
+fn sleep_control_value(typ: u8) -> u8 {
+    ((typ & 0b111) << SLEEP_CONTROL_TYP_SHIFT) | SLEEP_CONTROL_SLP_EN
+}
+
+/// SCI_ENが立っていなければ、SMI_CMDにACPI_ENABLEを書いてACPIモードにする
+unsafe fn enable_acpi(info: &PowerInfo) {
+    if asm::io_in_16(info.pm1a_cnt) & PM1_SCI_EN != 0 || info.smi_cmd == 0 || info.acpi_enable == 0 {
+        return;
+    }
+    asm::io_out_8(info.smi_cmd, info.acpi_enable);
+    for _ in 0..ACPI_ENABLE_POLLS {
+        if asm::io_in_16(info.pm1a_cnt) & PM1_SCI_EN != 0 {
+            break;
+        }
+    }
+}
+
+/// S5に入る。\_S5が分からないか、書けるレジスタが無ければ何もせずに戻る
+unsafe fn enter_s5(info: &PowerInfo) {
+    let Some((typ_a, typ_b)) = info.s5 else {
+        return;
+    };
+    if info.hardware_reduced {
+        if let Some(reg) = info.sleep_control {
+            reg.write_8(sleep_control_value(typ_a));
+        }
+        return;
+    }
+    if info.pm1a_cnt == 0 {
+        return;
+    }
+    enable_acpi(info);
+    asm::io_out_16(info.pm1a_cnt, pm1_cnt_value(asm::io_in_16(info.pm1a_cnt), typ_a));
+    if info.pm1b_cnt != 0 {
+        asm::io_out_16(info.pm1b_cnt, pm1_cnt_value(asm::io_in_16(info.pm1b_cnt), typ_b));
+    }
+}
+
+/// ACPIのS5で電源を切る。切れなければQEMUの電源断ポートを試し、それでもだめなら止まる
+/// パニックの後からも呼べるよう、ロックが取れなければACPIは使わない
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    let info = POWER.try_lock().and_then(|info| *info);
+    unsafe {
+        if let Some(info) = info {
+            enter_s5(&info);
+        }
+    }
+    qemu::power_off();
+    halt()
+}
+
+/// FADTのリセットレジスタでリセットする。無ければ0xcf9のリセット制御レジスタを使う
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    let reset = POWER.try_lock().and_then(|info| *info).and_then(|info| info.reset);
+    unsafe {
+        if let Some((reg, value)) = reset {
+            reg.write_8(value);
+        }
+        // 0x02でハードリセットを選んでから、0x04を立てて実行する
+        asm::io_out_8(RESET_CONTROL_PORT, 0x02);
+        asm::io_out_8(RESET_CONTROL_PORT, 0x06);
+    }
+    halt()
+}
+
+fn halt() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// パニックしたらsecs秒後にリセットする。0なら止まったまま
+pub fn set_panic_reboot(secs: u32) {
+    PANIC_REBOOT_SECS.store(secs, Ordering::Release);
+}
+
+pub fn panic_reboot_secs() -> Option<u32> {
+    let secs = PANIC_REBOOT_SECS.load(Ordering::Acquire);
+    (secs != 0).then_some(secs)
+}
+
+const KEY_Q: u8 = 0x14;
+const KEY_DELETE: u8 = 0x4c;
+
+/// Ctrl+Alt+Delでリセット、Ctrl+Alt+Qで電源断
+pub fn register_power_keys() {
+    let mods = Mods::CTRL.with(Mods::ALT);
+    shortcut::register_global(mods, KEY_DELETE, "acpi", |_| reboot(), 0).expect("acpi: Ctrl+Alt+Del");
+    shortcut::register_global(mods, KEY_Q, "acpi", |_| shutdown(), 0).expect("acpi: Ctrl+Alt+Q");
+}
+
+pub fn run_madt_tests() {
+    let mut entries = Vec::new();
+    // 有効なCPUが2つと、無効なCPUが1つ
+    entries.extend_from_slice(&[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+    entries.extend_from_slice(&[MADT_LOCAL_APIC, 8, 1, 1, 2, 0, 0, 0]);
+    entries.extend_from_slice(&[MADT_LOCAL_APIC, 8, 2, 2, 0, 0, 0, 0]);
+    entries.extend_from_slice(&[MADT_IO_APIC, 12, 4, 0]);
+    entries.extend_from_slice(&0xfec0_0000u32.to_le_bytes());
+    entries.extend_from_slice(&0u32.to_le_bytes());
+    entries.extend_from_slice(&[MADT_INTERRUPT_OVERRIDE, 10, 0, 0]);
+    entries.extend_from_slice(&2u32.to_le_bytes());
+    entries.extend_from_slice(&0u16.to_le_bytes());
+    // 知らない種類は読み飛ばす
+    entries.extend_from_slice(&[0x7f, 3, 0]);
+
+    let info = parse_madt_entries(0xfee0_0000, &entries);
+    assert!(info.local_apic_base == 0xfee0_0000 && info.cpu_count == 2);
+    assert!(info.io_apics == [IoApicInfo { id: 4, address: 0xfec0_0000, gsi_base: 0 }]);
+    assert!(info.overrides == [InterruptOverride { irq: 0, gsi: 2, flags: 0 }]);
+
+    // 64bitのアドレスの上書き
+    entries.extend_from_slice(&[MADT_LOCAL_APIC_ADDRESS_OVERRIDE, 12, 0, 0]);
+    entries.extend_from_slice(&0x1_fee0_0000u64.to_le_bytes());
+    assert!(parse_madt_entries(0xfee0_0000, &entries).local_apic_base == 0x1_fee0_0000);
+
+    // 長さが0や、表の終わりを越えるエントリでは止まる
+    let info = parse_madt_entries(0xfee0_0000, &[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0, MADT_LOCAL_APIC, 0, 0, 0]);
+    assert!(info.cpu_count == 1);
+    let info = parse_madt_entries(0xfee0_0000, &[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0, MADT_IO_APIC, 12, 0, 0]);
+    assert!(info.cpu_count == 1 && info.io_apics.is_empty());
+    // 短すぎるエントリは数えない
+    assert!(parse_madt_entries(0, &[MADT_LOCAL_APIC, 4, 0, 0]).cpu_count == 0);
+}
+
+pub fn run_power_tests() {
+    // QEMUのq35のDSDTにあるもの: Name(\_S5, Package(){0x05, 0x00})
+    let mut aml = Vec::new();
+    aml.extend_from_slice(&[0x10, 0x05, b'_', b'S', b'4', b'_']);
+    aml.extend_from_slice(&[0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0a, 0x05, 0x00]);
+    assert!(find_s5_sleep_type(&aml) == Some((5, 0)));
+    // \_S5をメソッドの中で参照しているだけの所は飛ばす
+    let aml = [0x70, b'_', b'S', b'5', b'_', 0x60, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x0a, 0x04, 0x0a, 0x07, 0x0b, 0x07, 0x00, 0x01];
+    assert!(find_s5_sleep_type(&aml) == Some((7, 7)));
+    // 2バイトのPkgLengthと、要素が1つだけのもの
+    assert!(find_s5_sleep_type(&[0x08, b'_', b'S', b'5', b'_', 0x12, 0x40, 0x00, 0x01, 0x01]) == Some((1, 0)));
+    assert!(find_s5_sleep_type(&[0x08, b'_', b'S', b'5', b'_', 0x12]).is_none());
+    assert!(find_s5_sleep_type(b"_S5_").is_none());
+
+    // SCI_ENなどは残し、前のSLP_TYPは消す
+    assert!(pm1_cnt_value(PM1_SCI_EN | (0b111 << PM1_SLP_TYP_SHIFT), 5) == 0x3401);
+    assert!(pm1_cnt_value(0, 0) == 0x2000);
+    assert!(sleep_control_value(5) == 0x34);
+}
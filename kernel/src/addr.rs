@@ -0,0 +1,113 @@
+use core::fmt;
+
+use crate::paging::IDENTITY_MAP_END;
+
+/// 物理アドレス。xHCなどのハードウェアに渡すアドレスはこの型で扱う
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct PhysAddr(u64);
+
+/// 仮想アドレス。カーネルが参照するポインタはこちら
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct VirtAddr(u64);
+
+impl PhysAddr {
+    pub const fn new(addr: u64) -> Self {
+        Self(addr)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// `align`は2の累乗
+    pub const fn is_aligned(self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+
+    pub const fn offset(self, bytes: u64) -> Self {
+        Self(self.0 + bytes)
+    }
+}
+
+impl VirtAddr {
+    pub const fn new(addr: u64) -> Self {
+        Self(addr)
+    }
+
+    pub fn from_ptr<T: ?Sized>(ptr: *const T) -> Self {
+        Self(ptr as *const u8 as u64)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub const fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub const fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// `align`は2の累乗
+    pub const fn is_aligned(self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+
+    pub const fn offset(self, bytes: u64) -> Self {
+        Self(self.0 + bytes)
+    }
+}
+
+impl fmt::Debug for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PhysAddr({:#x})", self.0)
+    }
+}
+
+impl fmt::Debug for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VirtAddr({:#x})", self.0)
+    }
+}
+
+/// 恒等写像された領域の仮想アドレスを物理アドレスに変換する
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    assert!(addr.0 < IDENTITY_MAP_END, "{addr:?} is outside the identity-mapped region");
+    PhysAddr(addr.0)
+}
+
+/// 物理アドレスを恒等写像された領域の仮想アドレスに変換する
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    assert!(addr.0 < IDENTITY_MAP_END, "{addr:?} is outside the identity-mapped region");
+    VirtAddr(addr.0)
+}
+
+/// ポインタの指す先の物理アドレス
+pub fn ptr_to_phys<T: ?Sized>(ptr: *const T) -> PhysAddr {
+    virt_to_phys(VirtAddr::from_ptr(ptr))
+}
+
+pub fn run_addr_tests() {
+    let v = VirtAddr::new(0x1234_5000);
+    assert!(virt_to_phys(v) == PhysAddr::new(0x1234_5000));
+    assert!(phys_to_virt(virt_to_phys(v)) == v);
+    assert!(virt_to_phys(VirtAddr::new(IDENTITY_MAP_END - 1)).as_u64() == IDENTITY_MAP_END - 1);
+
+    assert!(PhysAddr::new(0x1000).is_aligned(64));
+    assert!(PhysAddr::new(0x1000).is_aligned(4096));
+    assert!(!PhysAddr::new(0x1040).is_aligned(4096));
+    assert!(!PhysAddr::new(0x1020).is_aligned(64));
+    assert!(PhysAddr::new(0x1020).offset(0x20).is_aligned(64));
+    assert!(VirtAddr::new(0x30).is_aligned(16));
+    assert!(!VirtAddr::new(0x31).is_aligned(2));
+
+    let x = 0u64;
+    let p = ptr_to_phys(&x as *const u64);
+    assert!(p.as_u64() == &x as *const u64 as u64);
+    assert!(p.is_aligned(8));
+    assert!(phys_to_virt(p).as_ptr::<u64>() == &x as *const u64);
+}
@@ -0,0 +1,213 @@
+/// ログや出力に色を付けるためのSGR。シリアルの端末とコンソールの両方で解釈される
+pub const RED: &str = "\x1b[31m";
+pub const YELLOW: &str = "\x1b[33m";
+pub const RESET: &str = "\x1b[0m";
+
+/// CSIの引数の最大数。それより多い分は捨てる
+const MAX_PARAMS: usize = 8;
+
+const ESC: u8 = 0x1b;
+
+/// CSIの引数。省略された引数は0になる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    const fn new() -> Self {
+        Self { values: [0; MAX_PARAMS], len: 0 }
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.values[..self.len]
+    }
+
+    /// i番目の引数。無いか0ならdefault
+    pub fn get_or(&self, i: usize, default: u16) -> u16 {
+        match self.as_slice().get(i) {
+            Some(0) | None => default,
+            Some(v) => *v,
+        }
+    }
+}
+
+/// 解釈した結果の1つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// エスケープシーケンスでない1バイト。改行などの制御文字もそのまま渡す
+    Print(u8),
+    /// ESC [ ... m
+    Sgr(Params),
+    /// ESC [ row ; col H。1始まり
+    CursorPosition { row: u16, col: u16 },
+    /// ESC [ n K
+    EraseLine(u16),
+    /// ESC [ n J
+    EraseDisplay(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// ESC ( B のような文字集合の指定など。終端のバイトまで読み飛ばす
+    EscapeIgnore,
+    Csi,
+    /// 知らない形のCSI。終端のバイトまで読み飛ばす
+    CsiIgnore,
+}
+
+/// ANSI/VTのエスケープシーケンスのうち、色・カーソル位置・消去だけを解釈する状態機械
+/// 知らないシーケンスや壊れたシーケンスは何も表示せずに捨てる
+/// 途中で入力が切れても状態を持ち越すので、1つのシーケンスが複数回の書き込みに分かれてもよい
+pub struct AnsiParser {
+    state: State,
+    params: Params,
+    /// 引数がMAX_PARAMSを超えた。以降の引数は捨てる
+    params_full: bool,
+}
+
+impl AnsiParser {
+    pub const fn new() -> Self {
+        Self { state: State::Ground, params: Params::new(), params_full: false }
+    }
+
+    /// 1バイトを処理する。表示するものやするべき操作があれば返す
+    pub fn feed(&mut self, b: u8) -> Option<Action> {
+        match self.state {
+            State::Ground if b == ESC => {
+                self.state = State::Escape;
+                None
+            }
+            State::Ground => Some(Action::Print(b)),
+            State::Escape if b == b'[' => {
+                self.state = State::Csi;
+                self.params = Params::new();
+                self.params_full = false;
+                None
+            }
+            // ESC ESC は始め直し。それ以外の2バイトのシーケンスは扱わないので捨てる
+            State::Escape if b == ESC => None,
+            State::Escape if (0x20..=0x2f).contains(&b) => {
+                self.state = State::EscapeIgnore;
+                None
+            }
+            State::Escape => {
+                self.state = State::Ground;
+                None
+            }
+            State::EscapeIgnore => {
+                if !(0x20..=0x2f).contains(&b) {
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::Csi | State::CsiIgnore if b == ESC => {
+                self.state = State::Escape;
+                None
+            }
+            State::Csi => match b {
+                b'0'..=b'9' if self.params_full => None,
+                b'0'..=b'9' => {
+                    if self.params.len == 0 {
+                        self.params.len = 1;
+                    }
+                    // 大きすぎる値は飽和させる。そのような値を持つコードは知らないものとして無視される
+                    if let Some(v) = self.params.values.get_mut(self.params.len - 1) {
+                        *v = v.saturating_mul(10).saturating_add((b - b'0') as u16);
+                    }
+                    None
+                }
+                b';' if self.params.len == MAX_PARAMS => {
+                    self.params_full = true;
+                    None
+                }
+                b';' => {
+                    // 先頭の;は省略された最初の引数を表す
+                    self.params.len = self.params.len.max(1) + 1;
+                    None
+                }
+                0x40..=0x7e => {
+                    self.state = State::Ground;
+                    self.dispatch(b)
+                }
+                // ?などの私用の引数や中間バイト
+                0x20..=0x3f => {
+                    self.state = State::CsiIgnore;
+                    None
+                }
+                // 改行などの制御文字でシーケンスが途切れたら、それまでの分を捨てて制御文字は渡す
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Print(b))
+                }
+            },
+            State::CsiIgnore => {
+                if !(0x20..=0x3f).contains(&b) {
+                    self.state = State::Ground;
+                }
+                None
+            }
+        }
+    }
+
+    fn dispatch(&self, final_byte: u8) -> Option<Action> {
+        let p = &self.params;
+        match final_byte {
+            b'm' => Some(Action::Sgr(*p)),
+            b'H' | b'f' => Some(Action::CursorPosition { row: p.get_or(0, 1), col: p.get_or(1, 1) }),
+            b'K' => Some(Action::EraseLine(p.get_or(0, 0))),
+            b'J' => Some(Action::EraseDisplay(p.get_or(0, 0))),
+            _ => None,
+        }
+    }
+}
+
+pub fn run_ansi_tests() {
+    let parse = |bytes: &[u8]| {
+        let mut parser = AnsiParser::new();
+        let actions: alloc::vec::Vec<Action> = bytes.iter().filter_map(|b| parser.feed(*b)).collect();
+        (actions, parser.state)
+    };
+    let sgr = |values: &[u16]| {
+        let mut p = Params::new();
+        p.values[..values.len()].copy_from_slice(values);
+        p.len = values.len();
+        Action::Sgr(p)
+    };
+
+    // 正しいシーケンス
+    let (a, st) = parse(b"a\x1b[31;42mb\x1b[0m\n");
+    assert!(a == [Action::Print(b'a'), sgr(&[31, 42]), Action::Print(b'b'), sgr(&[0]), Action::Print(b'\n')]);
+    assert!(st == State::Ground);
+    assert!(parse(b"\x1b[m").0 == [sgr(&[])]);
+    assert!(parse(b"\x1b[5;10H\x1b[H\x1b[;3f").0 == [
+        Action::CursorPosition { row: 5, col: 10 },
+        Action::CursorPosition { row: 1, col: 1 },
+        Action::CursorPosition { row: 1, col: 3 },
+    ]);
+    assert!(parse(b"\x1b[K\x1b[2K\x1b[2J").0 == [Action::EraseLine(0), Action::EraseLine(2), Action::EraseDisplay(2)]);
+
+    // 途中で切れたシーケンスは何も出さず、続きを待つ
+    let (a, st) = parse(b"x\x1b[3");
+    assert!(a == [Action::Print(b'x')] && st == State::Csi);
+    let mut parser = AnsiParser::new();
+    assert!(b"\x1b[3".iter().all(|b| parser.feed(*b).is_none()));
+    assert!(parser.feed(b'1').is_none() && parser.feed(b'm') == Some(sgr(&[31])));
+
+    // 壊れたシーケンスや知らないシーケンスはゴミを出さない
+    assert!(parse(b"\x1b[?25lok").0 == [Action::Print(b'o'), Action::Print(b'k')]);
+    assert!(parse(b"\x1b[1;2;3zok").0 == [Action::Print(b'o'), Action::Print(b'k')]);
+    assert!(parse(b"\x1b(Bok").0 == [Action::Print(b'o'), Action::Print(b'k')]);
+    assert!(parse(b"\x1bcok").0 == [Action::Print(b'o'), Action::Print(b'k')]);
+    assert!(parse(b"\x1b[3\n1m").0 == [Action::Print(b'\n'), Action::Print(b'1'), Action::Print(b'm')]);
+    assert!(parse(b"\x1b[31\x1b[32m").0 == [sgr(&[32])]);
+    // 引数が多すぎても溢れない
+    assert!(parse(b"\x1b[1;2;3;4;5;6;7;8;9;10m").0 == [sgr(&[1, 2, 3, 4, 5, 6, 7, 8])]);
+
+    // ESC [ 999999 m は溢れずに飽和する
+    assert!(parse(b"\x1b[999999m").0 == [sgr(&[u16::MAX])]);
+    assert!(parse(b"\x1b[99999999999999999999;2H").0 == [Action::CursorPosition { row: u16::MAX, col: 2 }]);
+}
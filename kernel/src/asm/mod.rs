@@ -6,7 +6,13 @@ extern "sysv64" {
     pub fn io_in_32(addr: u16) -> u32;
     /// Write to IO address space
     pub fn io_out_32(addr: u16, data: u32);
+    pub fn io_in_16(addr: u16) -> u16;
+    pub fn io_out_16(addr: u16, data: u16);
+    pub fn io_in_8(addr: u16) -> u8;
+    pub fn io_out_8(addr: u16, data: u8);
     pub fn get_cr3() -> u64;
+    /// addrを含むページのTLBエントリを消す
+    pub fn invlpg(addr: u64);
 }
 
 global_asm!(r#" 
@@ -21,9 +27,35 @@ io_in_32:
     mov dx, di
     in eax, dx
     ret
+.globl io_out_16
+io_out_16:
+    mov dx, di
+    mov eax, esi
+    out dx, ax
+    ret
+.globl io_in_16
+io_in_16:
+    mov dx, di
+    in ax, dx
+    ret
+.globl io_out_8
+io_out_8:
+    mov dx, di
+    mov eax, esi
+    out dx, al
+    ret
+.globl io_in_8
+io_in_8:
+    mov dx, di
+    in al, dx
+    ret
 .globl get_cr3
 get_cr3:
     mov rax, cr3
     ret
+.globl invlpg
+invlpg:
+    invlpg [rdi]
+    ret
 "#
 );
@@ -0,0 +1,280 @@
+use core::fmt::{self, Write};
+
+use alloc::{string::{String, ToString}, vec::Vec};
+
+use crate::{acpi, ansi, command, console, heap_sweep, memory_manager::Mutex, println, platform::qemu, serial, timer::{add_timer_deferred, cancel_timer, get_current_tick, ms_to_ticks, TimerId}, usb::{self, HotplugEvent}};
+
+/// ブートローダが渡すスクリプト。ESPの\autoexec.shの中身で、無ければptrはnull
+/// バッファはLOADER_DATAに置かれ、カーネルは再利用しないのでそのまま読める
+#[repr(C)]
+pub struct BootScriptRaw {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+/// 次のコマンドまでの既定の間隔
+const DEFAULT_DELAY_MS: u64 = 100;
+/// パニックしたら失敗としてQEMUを終了させる起動オプション。parseではコメントとして読み飛ばす
+const PANIC_EXIT_OPTION: &str = "#panic=exit";
+/// パニックしたら指定した秒数の後にリセットする起動オプション。#panic-reboot=<seconds>
+const PANIC_REBOOT_OPTION: &str = "#panic-reboot=";
+/// ヒープの破損を見つけたらパニックさせる起動オプション
+const HEAP_SWEEP_PANIC_OPTION: &str = "#heap-sweep=panic";
+/// println!の出力を画面と一緒にシリアルにも出す起動オプション
+const SERIAL_MIRROR_OPTION: &str = "#serial-mirror";
+/// ヒープのスイープの間隔を変える起動オプション。#heap-sweep-interval=<ms>
+const HEAP_SWEEP_INTERVAL_OPTION: &str = "#heap-sweep-interval=";
+/// #wait-for-deviceの既定のタイムアウト
+const DEFAULT_WAIT_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// シェルのコマンド1行
+    Command(String),
+    /// #delay <ms>: 以降のコマンドの間隔を変える
+    Delay(u64),
+    /// #wait-for-device [timeout_ms]: デバイスが1つ繋がるまで待つ
+    WaitForDevice { timeout_ms: u64 },
+    /// #wait-usb-ready: 起動時に繋がっていたUSBデバイスが揃うまで待つ
+    WaitUsbReady,
+    /// #exit-qemu: 成功としてQEMUを終了する
+    ExitQemu,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1始まりの行番号
+    pub line: usize,
+    pub msg: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// 1行に1つのコマンド。#で始まる行は指示か、知らない語ならコメント
+pub fn parse(text: &str) -> Result<Vec<Step>, ParseError> {
+    let mut steps = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(directive) = line.strip_prefix('#') else {
+            steps.push(Step::Command(line.to_string()));
+            continue;
+        };
+        let err = |msg| ParseError { line: i + 1, msg };
+        let (name, arg) = directive.split_once(' ').unwrap_or((directive, ""));
+        let arg = arg.trim();
+        let ms = |default| {
+            if arg.is_empty() {
+                Ok(default)
+            } else {
+                arg.parse::<u64>().map_err(|_| err("expected milliseconds"))
+            }
+        };
+        match name {
+            "delay" if arg.is_empty() => return Err(err("#delay needs milliseconds")),
+            "delay" => steps.push(Step::Delay(ms(0)?)),
+            "wait-for-device" => steps.push(Step::WaitForDevice { timeout_ms: ms(DEFAULT_WAIT_MS)? }),
+            "wait-usb-ready" => steps.push(Step::WaitUsbReady),
+            "exit-qemu" => steps.push(Step::ExitQemu),
+            _ => (),
+        }
+    }
+    Ok(steps)
+}
+
+struct Runner {
+    steps: Vec<Step>,
+    next: usize,
+    delay_ms: u64,
+    /// まだ#wait-for-deviceで待っていない、繋がったデバイスの数
+    attached: usize,
+    /// #wait-for-deviceで待っているときのタイムアウト
+    waiting: Option<TimerId>,
+}
+
+static SCRIPT: Mutex<Option<&'static [u8]>> = Mutex::new(None);
+static RUNNER: Mutex<Option<Runner>> = Mutex::new(None);
+
+/// ブートローダからスクリプトを受け取る。メモリ割り当ては行わないので、起動直後に呼んでよい
+/// 起動オプションの行(#panic=exit など)はパニックより前に効くよう、ここで読む
+pub unsafe fn load(raw: *const BootScriptRaw) {
+    if raw.is_null() || (*raw).ptr.is_null() {
+        return;
+    }
+    let script = core::slice::from_raw_parts((*raw).ptr, (*raw).len);
+    for line in core::str::from_utf8(script).map_or("", |s| s).lines().map(str::trim) {
+        if line == PANIC_EXIT_OPTION {
+            qemu::set_panic_exit(true);
+        } else if line == HEAP_SWEEP_PANIC_OPTION {
+            heap_sweep::set_panic_on_corruption(true);
+        } else if line == SERIAL_MIRROR_OPTION {
+            serial::set_mirror(true);
+        } else if let Some(secs) = line.strip_prefix(PANIC_REBOOT_OPTION).and_then(|secs| secs.parse().ok()) {
+            acpi::set_panic_reboot(secs);
+        } else if let Some(ms) = line.strip_prefix(HEAP_SWEEP_INTERVAL_OPTION).and_then(|ms| ms.parse().ok()) {
+            heap_sweep::set_interval_ms(ms);
+        }
+    }
+    *SCRIPT.lock() = Some(script);
+}
+
+/// スクリプトの実行を始める。以降はメインループのタイマーで1行ずつ進む
+pub fn start() {
+    let Some(script) = SCRIPT.lock().take() else {
+        return;
+    };
+    let steps = match core::str::from_utf8(script).map_err(|_| ParseError { line: 0, msg: "not UTF-8" }).and_then(parse) {
+        Ok(steps) => steps,
+        Err(e) => {
+            finish(Err(format!("autoexec.sh {e}")));
+            return;
+        }
+    };
+    println!("autoexec: {} steps", steps.len());
+    *RUNNER.lock() = Some(Runner { steps, next: 0, delay_ms: DEFAULT_DELAY_MS, attached: 0, waiting: None });
+    add_timer_deferred(get_current_tick(), run_next, 0);
+}
+
+/// メインループで受け取ったUSBのホットプラグイベントを渡す
+pub fn on_hotplug(event: &HotplugEvent) {
+    if !matches!(event, HotplugEvent::Attached { .. }) {
+        return;
+    }
+    let mut runner = RUNNER.lock();
+    let Some(r) = runner.as_mut() else {
+        return;
+    };
+    match r.waiting.take() {
+        Some(timeout) => {
+            cancel_timer(timeout);
+            schedule_next(r.delay_ms);
+        }
+        None => r.attached += 1,
+    }
+}
+
+fn schedule_next(delay_ms: u64) {
+    add_timer_deferred(get_current_tick() + ms_to_ticks(delay_ms), run_next, 0);
+}
+
+fn run_next(_: usize) {
+    let mut runner = RUNNER.lock();
+    let Some(r) = runner.as_mut() else {
+        return;
+    };
+    let Some(step) = r.steps.get(r.next).cloned() else {
+        drop(runner);
+        finish(Ok(()));
+        return;
+    };
+    r.next += 1;
+    match step {
+        Step::Delay(ms) => {
+            r.delay_ms = ms;
+            schedule_next(0);
+        }
+        Step::WaitForDevice { .. } if r.attached > 0 => {
+            r.attached -= 1;
+            schedule_next(r.delay_ms);
+        }
+        Step::WaitForDevice { timeout_ms } => {
+            r.waiting = Some(add_timer_deferred(get_current_tick() + ms_to_ticks(timeout_ms), on_wait_timeout, 0));
+        }
+        Step::WaitUsbReady => {
+            // USBの準備完了にはタイムアウトがあるので、ここでは期限を設けない
+            usb::defer_until_ready(on_usb_ready, 0);
+        }
+        Step::Command(line) => {
+            let delay_ms = r.delay_ms;
+            // コマンドの中でログを出すかもしれないので、ロックを放してから実行する
+            drop(runner);
+            let _ = writeln!(ScriptWriter, "+ {line}");
+            if command::execute(&line, &mut ScriptWriter) {
+                schedule_next(delay_ms);
+            } else {
+                finish(Err(format!("command failed: {line}")));
+            }
+        }
+        Step::ExitQemu => {
+            drop(runner);
+            finish(Ok(()));
+            if qemu::is_qemu() {
+                qemu::exit_qemu(qemu::EXIT_SUCCESS);
+            }
+            warn!("autoexec: not running on QEMU, keep running");
+        }
+    }
+}
+
+fn on_usb_ready(_: usize) {
+    if let Some(r) = RUNNER.lock().as_ref() {
+        schedule_next(r.delay_ms);
+    }
+}
+
+fn on_wait_timeout(_: usize) {
+    let timed_out = RUNNER.lock().as_mut().is_some_and(|r| r.waiting.take().is_some());
+    if timed_out {
+        finish(Err("timed out waiting for a device".to_string()));
+    }
+}
+
+/// スクリプトを終える。失敗ならQEMUを失敗として終了させる
+fn finish(result: Result<(), String>) {
+    *RUNNER.lock() = None;
+    match result {
+        Ok(()) => {
+            let _ = writeln!(ScriptWriter, "autoexec: done");
+        }
+        Err(e) => {
+            let _ = writeln!(ScriptWriter, "{}autoexec: {e}, aborted{}", ansi::RED, ansi::RESET);
+            if qemu::is_qemu() {
+                qemu::exit_qemu(qemu::EXIT_FAILURE);
+            }
+        }
+    }
+}
+
+/// スクリプトの出力はコンソールとシリアルの両方に出す
+struct ScriptWriter;
+
+impl Write for ScriptWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        console::_log_nofmt(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub fn run_autoexec_tests() {
+    let steps = parse("# selftest\n\nusbstat  \n#delay 200\n#wait-for-device\n#wait-for-device 50\n#wait-usb-ready\necho done\n#exit-qemu\n").unwrap();
+    assert!(
+        steps
+            == [
+                Step::Command("usbstat".to_string()),
+                Step::Delay(200),
+                Step::WaitForDevice { timeout_ms: DEFAULT_WAIT_MS },
+                Step::WaitForDevice { timeout_ms: 50 },
+                Step::WaitUsbReady,
+                Step::Command("echo done".to_string()),
+                Step::ExitQemu,
+            ]
+    );
+
+    let e = parse("uptime\n#delay soon\n").unwrap_err();
+    assert!(e == ParseError { line: 2, msg: "expected milliseconds" });
+    assert!(format!("{e}") == "line 2: expected milliseconds");
+    assert!(parse("#delay\n").is_err());
+    // 起動オプションはloadで読むので、手順には含めない
+    assert!(parse("#panic=exit\n#heap-sweep=panic\n#heap-sweep-interval=100\n").unwrap().is_empty());
+
+    // 知らないコマンドはスクリプトを止める失敗になる
+    let mut out = String::new();
+    assert!(command::execute("echo hi", &mut out) && out == "hi\n");
+    assert!(!command::execute("no-such-command", &mut out));
+}
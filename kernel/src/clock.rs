@@ -0,0 +1,95 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    acpi, indicator, introspect, memory_manager::Mutex, println,
+    rtc::{read_rtc, DateTime, PortCmos},
+    timer::{add_timer_deferred, get_current_tick, TIMER_FREQ},
+};
+
+/// RTCを読み直してずれを補正する間隔(秒)
+const RESYNC_INTERVAL_SECS: u64 = 3600;
+
+/// ある時点のtickとそのときの時刻。現在時刻はここからの経過tickで求める
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Epoch {
+    unix: u64,
+    tick: u64,
+}
+
+impl Epoch {
+    fn now(&self, tick: u64) -> u64 {
+        self.unix + tick.saturating_sub(self.tick) / TIMER_FREQ as u64
+    }
+
+    fn needs_resync(&self, tick: u64) -> bool {
+        tick.saturating_sub(self.tick) >= RESYNC_INTERVAL_SECS * TIMER_FREQ as u64
+    }
+}
+
+static EPOCH: Mutex<Option<Epoch>> = Mutex::new(None);
+static LOG_TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+fn read_cmos() -> Option<DateTime> {
+    read_rtc(&mut PortCmos, acpi::century_register())
+}
+
+/// RTCを読んで起動時の時刻を決め、ステータス表示の更新を始める。タイマーとACPIの初期化の後に呼ぶ
+pub fn init_clock() {
+    let tick = get_current_tick();
+    let time = read_cmos().unwrap_or_else(|| {
+        println!("clock: RTC is unreadable, using {}", DateTime::FALLBACK);
+        DateTime::FALLBACK
+    });
+    *EPOCH.lock() = Some(Epoch { unix: time.to_unix(), tick });
+    println!("clock: {} UTC", time);
+    introspect::register("time/now", |_, out| writeln!(out, "{}", now_utc()), 0).expect("clock: time/now");
+    update_display(0);
+}
+
+/// 現在のUTCの日時。1時間ごとにRTCを読み直す
+pub fn now_utc() -> DateTime {
+    let tick = get_current_tick();
+    let mut epoch = EPOCH.lock();
+    let Some(e) = *epoch else {
+        return DateTime::FALLBACK;
+    };
+    if e.needs_resync(tick) {
+        // 読めなければ今の基準のまま使い、次の機会に読み直す
+        match read_cmos() {
+            Some(time) => *epoch = Some(Epoch { unix: time.to_unix(), tick }),
+            None => *epoch = Some(Epoch { unix: e.now(tick), tick }),
+        }
+    }
+    DateTime::from_unix(epoch.unwrap().now(tick))
+}
+
+/// ログの各行の先頭に時刻を付けるかどうか
+pub fn set_log_timestamps(enabled: bool) {
+    LOG_TIMESTAMPS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn log_timestamps() -> bool {
+    LOG_TIMESTAMPS.load(Ordering::Relaxed) && EPOCH.lock().is_some()
+}
+
+/// 時刻の表示を更新し、次の秒の変わり目に再び呼ばれるようにする
+fn update_display(_: usize) {
+    indicator::show_clock(&now_utc().hms());
+    let tick = get_current_tick();
+    let freq = TIMER_FREQ as u64;
+    let next = EPOCH.lock().map_or(tick + freq, |e| tick + freq - (tick.saturating_sub(e.tick) % freq));
+    add_timer_deferred(next, update_display, 0);
+}
+
+pub fn run_clock_tests() {
+    let freq = TIMER_FREQ as u64;
+    let e = Epoch { unix: 1_000_000, tick: 500 };
+    assert!(e.now(500) == 1_000_000);
+    assert!(e.now(500 + freq - 1) == 1_000_000);
+    assert!(e.now(500 + 61 * freq) == 1_000_061);
+    // 基準より前のtickでも過去には戻らない
+    assert!(e.now(0) == 1_000_000);
+    assert!(!e.needs_resync(500 + RESYNC_INTERVAL_SECS * freq - 1));
+    assert!(e.needs_resync(500 + RESYNC_INTERVAL_SECS * freq));
+    assert!(DateTime::from_unix(e.now(500 + 3600 * freq)).hour == DateTime::from_unix(1_000_000).hour + 1);
+}
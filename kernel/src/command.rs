@@ -0,0 +1,162 @@
+use core::{fmt::Write, sync::atomic::Ordering};
+
+use crate::{acpi, graphic, interrupt, introspect, latency, paging, symbols, viewer};
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(args: &str, out: &mut dyn Write),
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "help", help: "list commands", run: help },
+    Command { name: "echo", help: "print the arguments", run: |args, out| { let _ = writeln!(out, "{args}"); } },
+    Command { name: "show", help: "print a node, or every node under a directory", run: show },
+    Command { name: "ls", help: "list the nodes under a directory", run: ls },
+    Command { name: "time", help: "current UTC time", run: |_, out| show_nodes(&["time/now"], out) },
+    Command { name: "uptime", help: "seconds since boot", run: |_, out| show_nodes(&["time/uptime"], out) },
+    Command { name: "defer", help: "deferred work queue stats", run: |_, out| show_nodes(&["defer"], out) },
+    Command { name: "usb", help: "xHCI power state", run: |_, out| show_nodes(&["usb/power"], out) },
+    Command { name: "usbstat", help: "USB readiness and state of each device slot", run: |_, out| show_nodes(&["usb/ready", "usb/port-errors", "usb/slots"], out) },
+    Command { name: "channels", help: "queue depth, sent and dropped messages of each USB channel", run: |_, out| show_nodes(&["usb/channels"], out) },
+    Command { name: "ports", help: "power state of each root hub port", run: |_, out| show_nodes(&["usb/ports"], out) },
+    Command { name: "heapstat", help: "live heap usage per allocation tag", run: |_, out| show_nodes(&["mem/heap"], out) },
+    Command { name: "memstat", help: "free frames, heap sweep and heap usage", run: |_, out| show_nodes(&["mem"], out) },
+    Command { name: "shortcuts", help: "registered keyboard shortcuts and their owners", run: |_, out| show_nodes(&["input/shortcuts"], out) },
+    Command { name: "fps", help: "compositor frame pacing stats, or set the target frame rate", run: fps },
+    Command { name: "latency", help: "turn the input latency overlay on or off, or dump the histogram", run: latency },
+    Command { name: "view", help: "open a text file (built in or from the initrd) in the viewer", run: view },
+    Command { name: "wincap", help: "write a window's client area to serial as a BMP (ids are in gfx/layers)", run: wincap },
+    Command { name: "addr", help: "resolve a kernel address to a symbol", run: addr },
+    Command { name: "shutdown", help: "power off through ACPI (also Ctrl+Alt+Q)", run: |_, _| acpi::shutdown() },
+    Command { name: "reboot", help: "reset the machine (also Ctrl+Alt+Del)", run: |_, _| acpi::reboot() },
+    Command { name: "fault", help: "raise a CPU exception (pf, gp, ud, de) or an interrupt panic (irq) to check the handlers", run: fault },
+];
+
+fn help(_: &str, out: &mut dyn Write) {
+    for c in COMMANDS {
+        let _ = writeln!(out, "{:<8} {}", c.name, c.help);
+    }
+}
+
+/// 例外ハンドラの確認用。成功すればダンプを出して止まる
+fn fault(args: &str, out: &mut dyn Write) {
+    unsafe {
+        match args.trim() {
+            // 0番地は恒等写像に含まれるので、写像の外に書いて#PFを起こす
+            "pf" => core::ptr::write_volatile(paging::IDENTITY_MAP_END as *mut u8, 0),
+            // 非正規アドレスへのアクセスは#GPになる
+            "gp" => core::ptr::write_volatile(0x8000_0000_0000_0000u64 as *mut u8, 0),
+            "ud" => core::arch::asm!("ud2"),
+            "de" => core::arch::asm!("xor ecx, ecx", "div ecx", out("eax") _, out("edx") _, out("ecx") _),
+            // コンソールのロックを持てない所でのパニックを試す。USBの入力が来たときに起きる
+            "irq" => {
+                interrupt::PANIC_TEST.store(true, Ordering::Relaxed);
+                let _ = writeln!(out, "panicking in the next xHCI interrupt (move the mouse or press a key)");
+            }
+            _ => {
+                let _ = writeln!(out, "usage: fault pf|gp|ud|de|irq");
+            }
+        }
+    }
+}
+
+fn addr(args: &str, out: &mut dyn Write) {
+    let hex = args.trim().trim_start_matches("0x");
+    match u64::from_str_radix(hex, 16) {
+        Ok(a) => {
+            let _ = writeln!(out, "{}", symbols::Symbolized(a));
+        }
+        Err(_) => {
+            let _ = writeln!(out, "usage: addr <hex>");
+        }
+    }
+}
+
+/// introspectのノードを順に書く。stat系のコマンドはこれを呼ぶだけ
+fn show_nodes(paths: &[&str], out: &mut dyn Write) {
+    for path in paths {
+        if !introspect::show(path, out) {
+            let _ = writeln!(out, "{path}: no such node");
+        }
+    }
+}
+
+fn fps(args: &str, out: &mut dyn Write) {
+    if !args.is_empty() {
+        match args.parse() {
+            Ok(fps) => graphic::set_frame_rate(fps),
+            Err(_) => {
+                let _ = writeln!(out, "usage: fps [frames per second]");
+                return;
+            }
+        }
+    }
+    show_nodes(&["gfx/frames"], out);
+}
+
+fn latency(args: &str, out: &mut dyn Write) {
+    match args.trim() {
+        "on" => latency::set_overlay(true),
+        "off" => latency::set_overlay(false),
+        "dump" => {
+            let _ = latency::write_histogram(out);
+        }
+        _ => {
+            let _ = writeln!(out, "usage: latency on|off|dump");
+        }
+    }
+}
+
+fn view(args: &str, out: &mut dyn Write) {
+    let path = args.trim();
+    if path.is_empty() {
+        let _ = writeln!(out, "usage: view <path>");
+    } else if !graphic::is_initialized() {
+        let _ = writeln!(out, "view: no display");
+    } else if !viewer::open(path) {
+        let _ = writeln!(out, "{path}: no such text file");
+    }
+}
+
+fn wincap(args: &str, out: &mut dyn Write) {
+    let Ok(id) = args.trim().parse() else {
+        let _ = writeln!(out, "usage: wincap <layer id>");
+        return;
+    };
+    if !graphic::is_initialized() {
+        let _ = writeln!(out, "wincap: no display");
+    } else if !graphic::capture::dump_window_capture(id) {
+        let _ = writeln!(out, "wincap: no such layer {id}");
+    }
+}
+
+fn show(args: &str, out: &mut dyn Write) {
+    show_nodes(&[args], out);
+}
+
+fn ls(args: &str, out: &mut dyn Write) {
+    if !introspect::ls(args, out) {
+        let _ = writeln!(out, "{args}: not a directory");
+    }
+}
+
+/// 1行のコマンドを実行し、結果をoutに書く。空行なら何もしない
+/// コマンドが見つからなければfalse
+pub fn execute(line: &str, out: &mut dyn Write) -> bool {
+    let line = line.trim();
+    if line.is_empty() {
+        return true;
+    }
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    match COMMANDS.iter().find(|c| c.name == name) {
+        Some(c) => {
+            (c.run)(args.trim_start(), out);
+            true
+        }
+        None => {
+            let _ = writeln!(out, "{name}: command not found");
+            false
+        }
+    }
+}
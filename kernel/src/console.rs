@@ -1,50 +1,129 @@
-use core::iter::repeat_with;
+use core::{iter::repeat_with, sync::atomic::{AtomicBool, Ordering}};
 
-use alloc::vec::Vec;
+use alloc::{collections::VecDeque, vec::Vec};
 use lock_api::MutexGuard;
 use x86_64::instructions::interrupts::without_interrupts;
 
-use crate::{graphic::{font::{write_ascii, write_string}, frame_buffer::FrameBuffer, graphics::{PixelColor, Rect}, window::{LayerHandle, LayerId, Window}, with_layers}, memory_manager::{LazyInit, SpinMutex}, PixelWriter};
+use crate::{shortcut::{self, Mods}, ansi::{Action, AnsiParser, Params}, interrupt::in_interrupt, log_ring::{HistoryRing, LogRing}, platform::qemu::DebugconWriter, serial::{self, SerialWriter}, heap_profile::with_alloc_tag, graphic::{font::{write_ascii, write_string}, frame_buffer::FrameBuffer, desktop, graphics::{PixelColor, Rect, Vec2}, palette::{self, ANSI_COLORS}, window::{LayerHandle, LayerId, Placement, Window}, with_layers}, memory_manager::IrqLazyInit, PixelWriter};
 
-static CONSOLE: LazyInit<Console> = LazyInit::new();
+/// 例外ハンドラからも覗くので割り込みを止めて持つ。持っている間はタスクも切り替わらないため、
+/// 描画中に他のタスクが持っているロック(ウィンドウなど)を待つと戻れなくなる
+static CONSOLE: IrqLazyInit<Console> = IrqLazyInit::new();
 
 const CHAR_W: usize = 8;
 const CHAR_H: usize = 16;
+/// 画面の上に流れた行を何行まで残すか
+pub const DEFAULT_SCROLLBACK_LINES: usize = 1000;
+/// 文字バッファの1マス。色もマスごとに持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: u8,
+    pub fg: PixelColor,
+    pub bg: PixelColor,
+}
+
 pub struct Console {
     layer_handle: LayerHandle,
+    /// 文字を並べ始める位置。ウィンドウのクライアント領域の左上
+    origin: Vec2<i32>,
+    /// ESC[0mで戻る既定の色
     fg_color: PixelColor,
     bg_color: PixelColor,
+    /// 以降に書く文字の色。SGRで変わる
+    cur_fg: PixelColor,
+    cur_bg: PixelColor,
     n_rows: usize,
     n_cols: usize,
-    buffer: Vec<Vec<u8>>,
+    buffer: Vec<Vec<Cell>>,
     cursor_row: usize,
-    cursor_col: usize
+    cursor_col: usize,
+    parser: AnsiParser,
+    /// 画面の上に流れた行。古いものから捨てる
+    history: VecDeque<Vec<Cell>>,
+    history_limit: usize,
+    /// 何行遡って表示しているか。0なら最新の画面
+    view_offset: usize,
+    /// 遡って表示している間に出力があったら、最新の画面に戻す
+    follow_output: bool,
 }
 
 /// コンソールとコンソールウィンドウを初期化
-pub fn init_console(fg_color: (u8, u8, u8), bg_color: (u8, u8, u8)) {
+/// 壁紙があれば画面の中央に一回り小さく置き、無ければ画面全体を覆う
+pub fn init_console(fg_color: PixelColor, bg_color: PixelColor) {
+    let desktop = desktop::layer_id();
     with_layers(|l| {
-        let res = l.resolution();
-        let win = Window::new(res.0 as usize, res.1 as usize);
-        let hndl = l.new_layer(win);
-
-        l.up_down(hndl.layer_id(), 0);
+        let rect = console_rect(l.resolution(), desktop.is_some());
+        // 壁紙の上に置くときは枠を付け、文字はその内側に並べる。枠が画面からはみ出すなら付けない
+        let border = if desktop.is_some() && rect.x1 >= CONSOLE_BORDER && rect.y1 >= CONSOLE_BORDER { CONSOLE_BORDER } else { 0 };
+        let outer = Rect::from_points(rect.x1 - border, rect.y1 - border, rect.x2 + border, rect.y2 + border);
+        let mut win = Window::new(outer.size().x as usize, outer.size().y as usize, Some(bg_color));
+        win.move_to(Vec2::new(outer.x1, outer.y1));
+        if border > 0 {
+            let whole = Rect::from_wh(0, 0, outer.size().x as i32, outer.size().y as i32);
+            win.set_client_area(Some(Rect::from_wh(border, border, rect.size().x as i32, rect.size().y as i32)));
+            win.buffer().write_with(|back| {
+                back.draw_rect_outline(whole, palette::WINDOW_SHADOW);
+                back.draw_rect_outline(Rect::from_points(1, 1, whole.x2 - 1, whole.y2 - 1), palette::WINDOW_OUTLINE);
+            });
+            win.buffer().flush();
+        }
+        // 後から作るウィンドウもこれより前に来るよう奥に留める。壁紙よりは手前に置く
+        let hndl = l.new_layer(win, Placement::AboveConsole);
+        let _ = l.set_always_on_bottom(hndl.layer_id(), true);
+        if let Some(desktop) = desktop {
+            let _ = l.move_above(hndl.layer_id(), desktop);
+        }
         CONSOLE.lock().init(Console::new(hndl, fg_color, bg_color));
     });
 }
 
+/// 壁紙の上に置くときの、文字の範囲の外側に付ける枠の幅
+const CONSOLE_BORDER: i32 = 2;
+
+/// 壁紙があるときに、コンソールが画面の幅と高さのどれだけを占めるか(分子, 分母)
+const CONSOLE_SCREEN_RATIO: (u32, u32) = (3, 4);
+
+/// コンソールウィンドウの位置と大きさ。壁紙があれば画面の中央に置き、大きさは文字の大きさに揃える
+fn console_rect(resolution: (u32, u32), centered: bool) -> Rect {
+    let (w, h) = resolution;
+    if !centered {
+        return Rect::from_wh(0, 0, w as i32, h as i32);
+    }
+    let (num, den) = CONSOLE_SCREEN_RATIO;
+    let fit = |len: u32, cell: usize| (len * num / den / cell as u32 * cell as u32).max(cell as u32).min(len);
+    let (cw, ch) = (fit(w, CHAR_W), fit(h, CHAR_H));
+    Rect::from_wh(((w - cw) / 2) as i32, ((h - ch) / 2) as i32, cw as i32, ch as i32)
+}
+
 #[macro_export]
 macro_rules! println {
     () => {
         $crate::print!("\n")
     };
     ($($arg:tt)*) => {{
+        $crate::console::_print_timestamp();
         $crate::console::_print(core::format_args!($($arg)*));
         $crate::print!("\n")
 
     }};
 }
 
+/// 黄色で出すprintln
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::println!("{}{}{}", $crate::ansi::YELLOW, core::format_args!($($arg)*), $crate::ansi::RESET)
+    };
+}
+
+/// 赤で出すprintln
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::println!("{}{}{}", $crate::ansi::RED, core::format_args!($($arg)*), $crate::ansi::RESET)
+    };
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {{
@@ -52,45 +131,259 @@ macro_rules! print {
     }};
 }
 
+/// 割り込みハンドラの中で書かれたもの。メインループがflush_irq_logで画面に出す
+static IRQ_LOG: LogRing<4096> = LogRing::new();
+/// 割り込みハンドラからの出力を試すときに立てる。LAPICタイマーの割り込みで1度だけ出力して下ろす
+pub static IRQ_PRINT_TEST: AtomicBool = AtomicBool::new(false);
+pub const IRQ_PRINT_TEST_MESSAGE: &str = "console: hello from the timer interrupt";
+
+/// パニックハンドラが画面とシリアルに出す、最近の出力の大きさ
+pub const RECENT_LOG_LEN: usize = 2048;
+/// 最近出力したもの。コンソールのロックの中か割り込みハンドラで書く。
+/// コンソールのロックは割り込みを止めて持つので、両者が同時に書くことはない
+static RECENT_LOG: HistoryRing<RECENT_LOG_LEN> = HistoryRing::new();
+
+/// 最近出力したものを古い順にoutへ写し、写した長さを返す。ロックを取らないのでパニックハンドラから呼べる
+pub fn recent_log(out: &mut [u8]) -> usize {
+    RECENT_LOG.snapshot(out)
+}
+
+struct RecentLogWriter;
+
+impl core::fmt::Write for RecentLogWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        RECENT_LOG.push(s.as_bytes());
+        Ok(())
+    }
+}
+
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    CONSOLE.lock().write_fmt(args).unwrap();
+    // debugconはQEMUで見つかったときだけ書かれる
+    let _ = DebugconWriter.write_fmt(args);
+    // 割り込まれた側がコンソールやウィンドウ、アロケータのロックを持っているかもしれないので、
+    // スタック上で整形してリングに積むだけにする
+    if in_interrupt() {
+        let mut w = StackWriter::new();
+        let _ = w.write_fmt(args);
+        IRQ_LOG.push(w.as_bytes());
+        RECENT_LOG.push(w.as_bytes());
+        return;
+    }
+    let mut console = CONSOLE.lock();
+    let _ = RecentLogWriter.write_fmt(args);
+    if console.is_init() {
+        console.write_fmt(args).unwrap();
+        if serial::mirror() {
+            let _ = SerialWriter.write_fmt(args);
+        }
+    } else {
+        // 画面が無い(ヘッドレス)ときはシリアルに出す
+        let _ = SerialWriter.write_fmt(args);
+    }
+}
+
+/// 割り込みハンドラの中で書かれたものが残っている
+pub fn irq_log_pending() -> bool {
+    !IRQ_LOG.is_empty()
+}
+
+/// 割り込みハンドラの中で書かれたものを画面(無ければシリアル)に出す。メインループのロックを持たない所で呼ぶ
+pub fn flush_irq_log() {
+    if !irq_log_pending() {
+        return;
+    }
+    let mut console = CONSOLE.lock();
+    IRQ_LOG.drain(|bytes| {
+        if console.is_init() {
+            console.put_string(bytes);
+        }
+        if !console.is_init() || serial::mirror() {
+            SerialWriter::write_bytes(bytes);
+        }
+    });
+}
+
+/// clock::set_log_timestamps(true)なら "[HH:MM:SS] " を出力する
+/// 時計はロックを取るので、割り込みハンドラの中では付けない
+pub fn _print_timestamp() {
+    if crate::clock::log_timestamps() && !in_interrupt() {
+        let hms = crate::clock::now_utc().hms();
+        _print(format_args!("[{}] ", core::str::from_utf8(&hms).unwrap_or("??:??:??")));
+    }
+}
+
+/// メモリ割り当てを行わずに1行出力する。引数は&strと数値などのプリミティブのみ
+/// OOMハンドラなど、アロケータが使えないかもしれない場面で使う
+#[macro_export]
+macro_rules! log_nofmt {
+    ($($arg:expr),* $(,)?) => {{
+        let mut w = $crate::console::StackWriter::new();
+        $( $crate::console::NoAllocDisplay::write_to(&$arg, &mut w); )*
+        w.push_str("\n");
+        $crate::console::_log_nofmt(w.as_bytes());
+    }};
 }
 
+/// シリアルと(使えれば)コンソールに書き込む
+/// コンソールのロックが取れない・未初期化のときはシリアルにだけ出す
+pub fn _log_nofmt(bytes: &[u8]) {
+    serial::write_bytes(bytes);
+    DebugconWriter::write_bytes(bytes);
+    if let Some(mut console) = CONSOLE.try_lock() {
+        if console.is_init() {
+            console.put_string(bytes);
+        }
+    }
+}
+
+const STACK_WRITER_LEN: usize = 256;
+
+/// スタック上の固定長バッファに書き込むWriter。溢れた分は捨てる
+pub struct StackWriter {
+    buf: [u8; STACK_WRITER_LEN],
+    len: usize,
+}
+
+impl StackWriter {
+    pub const fn new() -> Self {
+        Self { buf: [0; STACK_WRITER_LEN], len: 0 }
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl core::fmt::Write for StackWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+/// log_nofmt!に渡せる型。表示にメモリ割り当てを必要としないものだけを実装する
+pub trait NoAllocDisplay {
+    fn write_to(&self, w: &mut StackWriter);
+}
+
+impl NoAllocDisplay for &str {
+    fn write_to(&self, w: &mut StackWriter) {
+        w.push_str(self);
+    }
+}
+
+macro_rules! impl_no_alloc_display {
+    ($($t:ty),*) => {
+        $(impl NoAllocDisplay for $t {
+            fn write_to(&self, w: &mut StackWriter) {
+                use core::fmt::Write;
+                let _ = write!(w, "{}", self);
+            }
+        })*
+    };
+}
+impl_no_alloc_display!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, bool, char);
+
 impl Console {
     pub fn new(layer_handle: LayerHandle, fg_color: PixelColor, bg_color: PixelColor) -> Self {
-        let (n_cols, n_rows) = {
-            let window = layer_handle.window().read();
-            (window.width() / CHAR_W, window.height() / CHAR_H)
+        let (origin, n_cols, n_rows) = {
+            let client = layer_handle.window().read().client_area();
+            let size = client.size();
+            (Vec2::new(client.x1, client.y1), size.x as usize / CHAR_W, size.y as usize / CHAR_H)
         };
-        let buffer: Vec<Vec<u8>> = repeat_with(||{vec![0u8;n_cols]}).take(n_rows).collect();
+        let blank = Cell { ch: 0, fg: fg_color, bg: bg_color };
+        let buffer: Vec<Vec<Cell>> = with_alloc_tag("console", || repeat_with(||{vec![blank;n_cols]}).take(n_rows).collect());
 
         {
             layer_handle.window().read().buffer().write_with(|back|{
                 for y in 0..16 * n_rows {
                     for x in 0..8 * n_cols {
-                        back.write((x as i32, y as i32).into(), bg_color);
+                        back.write(origin + (x as i32, y as i32).into(), bg_color);
                     }
                 }
             });
         }
 
-        Self { layer_handle, fg_color, bg_color, n_cols, n_rows, buffer, cursor_row: 0, cursor_col: 0 }
+        Self {
+            layer_handle, origin, fg_color, bg_color, cur_fg: fg_color, cur_bg: bg_color,
+            n_cols, n_rows, buffer, cursor_row: 0, cursor_col: 0, parser: AnsiParser::new(),
+            history: VecDeque::new(), history_limit: DEFAULT_SCROLLBACK_LINES, view_offset: 0, follow_output: false,
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        Cell { ch: 0, fg: self.cur_fg, bg: self.cur_bg }
+    }
+
+    /// 画面のrow行col列にcellを描く
+    fn paint_cell(&self, window: &mut FrameBuffer, row: usize, col: usize, cell: Cell) {
+        let (x, y) = (self.origin.x + (CHAR_W * col) as i32, self.origin.y + (CHAR_H * row) as i32);
+        window.fill_rect((x, y).into(), (CHAR_W as u32, CHAR_H as u32).into(), cell.bg);
+        write_ascii(window, x as u32, y as u32, cell.ch as char, cell.fg);
+    }
+
+    /// 文字バッファの1マスを描き直す。遡って表示している間は画面に出さない
+    fn draw_cell(&mut self, window: &mut FrameBuffer, row: usize, col: usize) {
+        if self.view_offset == 0 {
+            self.paint_cell(window, row, col, self.buffer[row][col]);
+        }
+    }
+
+    /// view_offsetに合わせて画面全体を描き直す
+    fn render_view(&self, window: &mut FrameBuffer) {
+        let blank = Cell { ch: 0, fg: self.fg_color, bg: self.bg_color };
+        let top = self.history.len() - self.view_offset;
+        for row in 0..self.n_rows {
+            let line = self.history.get(top + row).unwrap_or_else(|| &self.buffer[top + row - self.history.len()]);
+            for col in 0..self.n_cols {
+                self.paint_cell(window, row, col, line.get(col).copied().unwrap_or(blank));
+            }
+        }
+    }
+
+    /// rowのcolsの範囲を消す。消した後の背景は今の背景色
+    fn erase(&mut self, window: &mut FrameBuffer, row: usize, cols: core::ops::Range<usize>) {
+        let blank = self.blank();
+        for col in cols {
+            self.buffer[row][col] = blank;
+            self.draw_cell(window, row, col);
+        }
     }
 
     fn scroll_up(& mut self, window: &mut FrameBuffer) {
-        window.move_rect((0,0).into(), Rect::from_points(0, 16, 8*self.n_cols as i32, 16*self.n_rows as i32));
-        
-        for y in 16*(self.n_rows-1)..16 * self.n_rows {
-            for x in 0..8 * self.n_cols {
-                window.write((x as i32, y as i32).into(), self.bg_color);
+        if self.history_limit > 0 {
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
             }
+            self.history.push_back(self.buffer[0].clone());
+        }
+        let live = self.view_offset == 0;
+        if live {
+            let text = Rect::from_wh(self.origin.x, self.origin.y, 8*self.n_cols as i32, 16*self.n_rows as i32);
+            window.move_rect(self.origin, Rect::from_points(text.x1, text.y1 + 16, text.x2, text.y2));
         }
 
         for row in 0..self.n_rows-1 {
             self.buffer.swap(row, row+1);
         }
-        self.buffer[self.n_rows-1].fill(0u8);
+        let n_cols = self.n_cols;
+        self.erase(window, self.n_rows-1, 0..n_cols);
+
+        // 遡って表示している行がそのまま見えるよう、1行分ずらす。一番古い行を捨てた分だけは見えなくなる
+        if !live {
+            self.view_offset += 1;
+            if self.view_offset > self.history.len() {
+                self.view_offset = self.history.len();
+                self.render_view(window);
+            }
+        }
     }
 
     fn new_line(& mut self, window: &mut FrameBuffer) {
@@ -103,24 +396,154 @@ impl Console {
         }
     }
 
+    fn put_char(&mut self, window: &mut FrameBuffer, c: u8) {
+        if c == b'\n' {
+            self.new_line(window);
+            return;
+        }
+        // その他の制御文字は表示しない
+        if c < 0x20 {
+            return;
+        }
+        self.buffer[self.cursor_row][self.cursor_col] = Cell { ch: c, fg: self.cur_fg, bg: self.cur_bg };
+        self.draw_cell(window, self.cursor_row, self.cursor_col);
+        self.cursor_col += 1;
+        if self.cursor_col == self.n_cols {
+            self.new_line(window);
+        }
+    }
+
+    /// SGR: 0で既定の色に戻し、30-37/90-97で文字色、40-47/100-107で背景色を変える。知らない値は無視する
+    fn select_graphic_rendition(&mut self, params: &Params) {
+        if params.as_slice().is_empty() {
+            self.cur_fg = self.fg_color;
+            self.cur_bg = self.bg_color;
+        }
+        for p in params.as_slice() {
+            match *p as usize {
+                0 => {
+                    self.cur_fg = self.fg_color;
+                    self.cur_bg = self.bg_color;
+                }
+                n @ 30..=37 => self.cur_fg = ANSI_COLORS[n - 30],
+                39 => self.cur_fg = self.fg_color,
+                n @ 40..=47 => self.cur_bg = ANSI_COLORS[n - 40],
+                49 => self.cur_bg = self.bg_color,
+                n @ 90..=97 => self.cur_fg = ANSI_COLORS[n - 90 + 8],
+                n @ 100..=107 => self.cur_bg = ANSI_COLORS[n - 100 + 8],
+                _ => (),
+            }
+        }
+    }
+
+    fn apply(&mut self, window: &mut FrameBuffer, action: Action) {
+        let (row, col, n_cols) = (self.cursor_row, self.cursor_col, self.n_cols);
+        match action {
+            Action::Print(c) => self.put_char(window, c),
+            Action::Sgr(params) => self.select_graphic_rendition(&params),
+            Action::CursorPosition { row, col } => {
+                self.cursor_row = (row as usize - 1).min(self.n_rows - 1);
+                self.cursor_col = (col as usize - 1).min(self.n_cols - 1);
+            }
+            // 0: カーソルから行末まで、1: 行頭からカーソルまで、2: 行全体
+            Action::EraseLine(0) => self.erase(window, row, col..n_cols),
+            Action::EraseLine(1) => self.erase(window, row, 0..col + 1),
+            Action::EraseLine(2) => self.erase(window, row, 0..n_cols),
+            // 0: カーソルから画面の終わりまで、1: 画面の始めからカーソルまで、2: 画面全体
+            Action::EraseDisplay(n @ 0..=2) => {
+                let rows = match n {
+                    0 => row + 1..self.n_rows,
+                    1 => 0..row,
+                    _ => 0..self.n_rows,
+                };
+                match n {
+                    0 => self.erase(window, row, col..n_cols),
+                    1 => self.erase(window, row, 0..col + 1),
+                    _ => (),
+                }
+                for r in rows {
+                    self.erase(window, r, 0..n_cols);
+                }
+            }
+            Action::EraseLine(_) | Action::EraseDisplay(_) => (),
+        }
+    }
+
+    /// rowとcolのマスの内容。テスト用
+    pub fn cell(&self, row: usize, col: usize) -> Cell {
+        self.buffer[row][col]
+    }
+
+    /// 画面の上に流れた行の数
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn view_offset(&self) -> usize {
+        self.view_offset
+    }
+
+    /// 残す行数を変える。溢れた分は古いものから捨てる
+    pub fn set_scrollback_limit(&mut self, lines: usize) {
+        self.history_limit = lines;
+        while self.history.len() > lines {
+            self.history.pop_front();
+        }
+        if self.view_offset > self.history.len() {
+            self.set_view_offset(self.history.len());
+        }
+    }
+
+    /// 遡って表示している間に出力があったら、最新の画面に戻すか
+    pub fn set_follow_output(&mut self, follow: bool) {
+        self.follow_output = follow;
+    }
+
+    fn set_view_offset(&mut self, offset: usize) {
+        let offset = offset.min(self.history.len());
+        if offset == self.view_offset {
+            return;
+        }
+        self.view_offset = offset;
+        let window = self.layer_handle.window().clone();
+        let window_guard = window.read();
+        window_guard.buffer().write_with(|back| self.render_view(back));
+        window_guard.buffer().flush();
+    }
+
+    pub fn scroll_view_up(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset.saturating_add(lines));
+    }
+
+    pub fn scroll_view_down(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset.saturating_sub(lines));
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.set_view_offset(0);
+    }
+
+    /// どこかの行にtextが書かれている。テスト用
+    pub fn contains(&self, text: &[u8]) -> bool {
+        self.buffer.iter().any(|row| row.windows(text.len()).any(|w| w.iter().map(|c| c.ch).eq(text.iter().copied())))
+    }
+
+    /// ANSIのエスケープシーケンスのうち、色(SGR)、カーソル位置(CUP)、消去(EL/ED)を解釈する
     pub fn put_string(&mut self, str: &[u8]) {
+        if self.follow_output {
+            self.scroll_to_bottom();
+        }
         let window = self.layer_handle.window().clone();
-        let mut window_guard = window.read();
+        let window_guard = window.read();
         
         window_guard.buffer().write_with(|back|{
-
             for c in str {
-                if *c as char == '\n' {
-                    self.new_line(back);
-                } 
-                write_ascii(back, 8 * self.cursor_col as u32, 16 * self.cursor_row as u32, *c as char, self.fg_color);
-                self.buffer[self.cursor_row][self.cursor_col] = *c;
-                self.cursor_col += 1;
-                if self.cursor_col == self.n_cols {                
-                    self.new_line(back);
-                }        
+                if let Some(action) = self.parser.feed(*c) {
+                    self.apply(back, action);
+                }
             }
         });
+        // 1文字ならその1マスだけ、スクロールしたら文字の範囲全体が写る
         window_guard.buffer().flush();
     }
 }
@@ -131,3 +554,80 @@ impl  core::fmt::Write for Console {
         Ok(())
     }
 }
+
+/// 画面の行数。コンソールが無ければ0
+fn page_lines() -> usize {
+    let console = CONSOLE.lock();
+    if console.is_init() { console.n_rows } else { 0 }
+}
+
+fn with_console(f: impl FnOnce(&mut Console)) {
+    let mut console = CONSOLE.lock();
+    if console.is_init() {
+        f(&mut console);
+    }
+}
+
+pub fn scroll_view_up(lines: usize) {
+    with_console(|c| c.scroll_view_up(lines));
+}
+
+pub fn scroll_view_down(lines: usize) {
+    with_console(|c| c.scroll_view_down(lines));
+}
+
+pub fn scroll_to_bottom() {
+    with_console(|c| c.scroll_to_bottom());
+}
+
+/// ホイール1段で動かす行数
+pub const WHEEL_SCROLL_LINES: usize = 3;
+
+/// マウスのホイールで表示を動かす。奥へ回すと(正)過去の行へ遡る
+pub fn on_wheel(wheel: i8) {
+    let lines = wheel.unsigned_abs() as usize * WHEEL_SCROLL_LINES;
+    match wheel {
+        1.. => scroll_view_up(lines),
+        ..=-1 => scroll_view_down(lines),
+        0 => {}
+    }
+}
+
+const KEY_END: u8 = 0x4d;
+const KEY_PAGE_UP: u8 = 0x4b;
+const KEY_PAGE_DOWN: u8 = 0x4e;
+
+/// Shift+PageUp/PageDownで1画面分遡る・戻る、Shift+Endで最新の画面に戻る。
+/// PageUp/PageDownだけだとビューアのキーと重なるので、Shiftを付ける
+pub fn register_scroll_keys() {
+    let keys: [(u8, fn(usize)); 3] = [
+        (KEY_PAGE_UP, |_| scroll_view_up(page_lines().saturating_sub(1).max(1))),
+        (KEY_PAGE_DOWN, |_| scroll_view_down(page_lines().saturating_sub(1).max(1))),
+        (KEY_END, |_| scroll_to_bottom()),
+    ];
+    for (key, handler) in keys {
+        shortcut::register_global(Mods::SHIFT, key, "console", handler, 0).expect("console: scroll keys");
+    }
+}
+
+/// LAPICタイマーの割り込みハンドラの中で出力させ、メインループ側で画面に出ることを確かめる
+/// 割り込みを有効にしてから呼ぶ
+pub fn run_irq_log_tests() {
+    IRQ_PRINT_TEST.store(true, Ordering::Relaxed);
+    while IRQ_PRINT_TEST.load(Ordering::Relaxed) {
+        x86_64::instructions::hlt();
+    }
+    // ハンドラの中では画面に出さず、リングに残している
+    assert!(irq_log_pending());
+    flush_irq_log();
+    assert!(!irq_log_pending());
+    let console = CONSOLE.lock();
+    if console.is_init() {
+        assert!(console.contains(IRQ_PRINT_TEST_MESSAGE.as_bytes()));
+    }
+    drop(console);
+    // 割り込みハンドラの中の出力も、画面に出る前から最近の出力に入っている
+    let mut recent = vec![0; RECENT_LOG_LEN];
+    let len = recent_log(&mut recent);
+    assert!(recent[..len].windows(IRQ_PRINT_TEST_MESSAGE.len()).any(|w| w == IRQ_PRINT_TEST_MESSAGE.as_bytes()));
+}
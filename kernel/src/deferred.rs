@@ -0,0 +1,160 @@
+use alloc::{boxed::Box, vec::Vec};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{introspect, memory_manager::{IrqMutex, Mutex}};
+
+/// deferで溜めておける処理の数
+const CAPACITY: usize = 32;
+
+/// キューが一杯で処理を登録できなかった
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeferStats {
+    /// まだ実行されていない処理の数
+    pub depth: usize,
+    pub max_depth: usize,
+    /// キューが一杯で断った回数
+    pub overflows: usize,
+    pub executed: usize,
+}
+
+type Entry = (fn(usize), usize);
+
+/// 関数ポインタと引数の固定長リングバッファ。登録の際にメモリ割り当ては行わない
+struct DeferRing {
+    entries: [Option<Entry>; CAPACITY],
+    read: usize,
+    len: usize,
+    max_depth: usize,
+    overflows: usize,
+    executed: usize,
+}
+
+impl DeferRing {
+    const fn new() -> Self {
+        Self { entries: [None; CAPACITY], read: 0, len: 0, max_depth: 0, overflows: 0, executed: 0 }
+    }
+
+    fn push(&mut self, entry: Entry) -> Result<(), Full> {
+        if self.len == CAPACITY {
+            self.overflows += 1;
+            return Err(Full);
+        }
+        self.entries[(self.read + self.len) % CAPACITY] = Some(entry);
+        self.len += 1;
+        self.max_depth = self.max_depth.max(self.len);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Entry> {
+        if self.len == 0 {
+            return None;
+        }
+        let entry = self.entries[self.read].take();
+        self.read = (self.read + 1) % CAPACITY;
+        self.len -= 1;
+        self.executed += 1;
+        entry
+    }
+}
+
+// 割り込みの中からもdeferされる
+static RING: IrqMutex<DeferRing> = IrqMutex::new(DeferRing::new());
+// 割り込みの中からは使わない
+static BOXED: Mutex<Vec<Box<dyn FnOnce() + Send>>> = Mutex::new(Vec::new());
+
+/// f(arg)をメインループで実行させる。割り込みハンドラやUSBのタスクからも呼べる
+pub fn defer(f: fn(usize), arg: usize) -> Result<(), Full> {
+    RING.lock().push((f, arg))
+}
+
+/// fをメインループで実行させる。メモリ割り当てを行うので割り込みハンドラからは呼ばないこと
+pub fn defer_boxed(f: Box<dyn FnOnce() + Send>) {
+    without_interrupts(|| BOXED.lock().push(f));
+}
+
+pub fn pending() -> bool {
+    without_interrupts(|| RING.lock().len > 0 || !BOXED.lock().is_empty())
+}
+
+pub fn stats() -> DeferStats {
+    let ring = RING.lock();
+    DeferStats { depth: ring.len, max_depth: ring.max_depth, overflows: ring.overflows, executed: ring.executed }
+}
+
+/// deferを登録する
+pub fn register_nodes() {
+    introspect::register("defer", |_, out| {
+        let st = stats();
+        writeln!(out, "depth={} max={} overflows={} executed={}", st.depth, st.max_depth, st.overflows, st.executed)
+    }, 0)
+    .expect("deferred: defer");
+}
+
+/// メインループから毎回呼ぶ。登録された順に実行する(deferの分が先、defer_boxedの分が後)。
+/// 実行中に登録された処理は次の呼び出しで実行する
+pub fn run_deferred() -> usize {
+    let n = RING.lock().len;
+    for _ in 0..n {
+        // ロックは取り出す間だけ持ち、実行中は離しておく
+        if let Some((f, arg)) = RING.lock().pop() {
+            f(arg);
+        }
+    }
+    let boxed = without_interrupts(|| core::mem::take(&mut *BOXED.lock()));
+    let n = n + boxed.len();
+    for f in boxed {
+        f();
+    }
+    n
+}
+
+static TEST_LOG: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn record(arg: usize) {
+    TEST_LOG.lock().push(arg);
+}
+
+fn record_and_defer(arg: usize) {
+    record(arg);
+    defer(record, arg + 1).unwrap();
+}
+
+pub fn run_deferred_tests() {
+    run_deferred();
+    let before = stats();
+
+    // 登録順に実行され、defer_boxedの分はその後になる
+    defer_boxed(Box::new(|| record(100)));
+    defer(record, 1).unwrap();
+    defer(record, 2).unwrap();
+    assert!(pending());
+    assert!(run_deferred() == 3);
+    assert!(TEST_LOG.lock().as_slice() == [1, 2, 100]);
+    assert!(!pending());
+
+    // 実行中に登録した処理は次の呼び出しまで待つ
+    TEST_LOG.lock().clear();
+    defer(record_and_defer, 10).unwrap();
+    assert!(run_deferred() == 1);
+    assert!(TEST_LOG.lock().as_slice() == [10]);
+    assert!(run_deferred() == 1);
+    assert!(TEST_LOG.lock().as_slice() == [10, 11]);
+
+    // 一杯になったら断り、数える。取り出せばまた登録できる
+    TEST_LOG.lock().clear();
+    for i in 0..CAPACITY {
+        defer(record, i).unwrap();
+    }
+    assert!(defer(record, CAPACITY) == Err(Full));
+    let st = stats();
+    assert!(st.depth == CAPACITY && st.max_depth == CAPACITY && st.overflows == before.overflows + 1);
+    assert!(run_deferred() == CAPACITY);
+    assert!(TEST_LOG.lock().iter().copied().eq(0..CAPACITY));
+    assert!(defer(record, 0).is_ok());
+    run_deferred();
+    assert!(stats().executed == before.executed + CAPACITY + 5);
+    TEST_LOG.lock().clear();
+}
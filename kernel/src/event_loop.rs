@@ -0,0 +1,306 @@
+use core::{arch::asm, fmt::Write, str::from_utf8};
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{console::StackWriter, interrupt::set_interrupt_flag, introspect, memory_manager::IrqLazyInit, timer::Timestamp, warn};
+
+/// 割り込みハンドラから入れ、メインループが取り出す
+pub static EVENTS: IrqLazyInit<MessageQueue<1024>> = IrqLazyInit::new();
+
+/// 割り込みハンドラなどからメインループに送るメッセージ
+#[derive(Clone, Copy, Debug)]
+pub enum Message {
+    /// 最初の割り込みが到着した時刻と、取り出されるまでに来た割り込みの数
+    Xhci { arrival: Timestamp, count: usize },
+    TimerTimeout(u64),
+    /// xHCの割り込みとは別に、USBのタスクが起こされた
+    UsbPoll,
+    /// PS/2のキーボードとマウスから届いた1バイト
+    Ps2Keyboard(u8),
+    Ps2Mouse(u8),
+}
+
+/// Messageの種類。ハンドラはこの単位で登録する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    Xhci,
+    Timer,
+    UsbPoll,
+    Ps2,
+}
+
+impl Message {
+    const KINDS: usize = 4;
+    const NAMES: [&'static str; Self::KINDS] = ["xhci", "timer", "usb-poll", "ps2"];
+
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Xhci { .. } => MessageKind::Xhci,
+            Message::TimerTimeout(_) => MessageKind::Timer,
+            Message::UsbPoll => MessageKind::UsbPoll,
+            Message::Ps2Keyboard(_) | Message::Ps2Mouse(_) => MessageKind::Ps2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct QueueStats {
+    len: usize,
+    /// 満杯で捨てたメッセージの数。Message::kindごと
+    dropped: [usize; Message::KINDS],
+    /// 既に入っていたものにまとめたxHCIの割り込みの数
+    coalesced: usize,
+}
+
+pub struct MessageQueue<const N: usize> {
+    data: [Message; N],
+    read_pos: usize,
+    write_pos: usize,
+    cnt: usize,
+    /// 入っているMessage::Xhciの位置。on_xhc_interruptはイベントリングを全て処理するので、1つあれば足りる
+    xhci_pos: Option<usize>,
+    dropped: [usize; Message::KINDS],
+    coalesced: usize,
+}
+
+impl<const N: usize> MessageQueue<N> {
+    fn new() -> Self {
+        Self {
+            data: [Message::TimerTimeout(0); N],
+            read_pos: 0,
+            write_pos: 0,
+            cnt: 0,
+            xhci_pos: None,
+            dropped: [0; Message::KINDS],
+            coalesced: 0,
+        }
+    }
+
+    /// 満杯なら捨てて数える。xHCIの割り込みは、まだ取り出されていないものがあればそれにまとめる
+    pub fn push(&mut self, msg: Message) -> Result<(), ()>{
+        if let (Message::Xhci { count: n, .. }, Some(pos)) = (msg, self.xhci_pos) {
+            if let Message::Xhci { count, .. } = &mut self.data[pos] {
+                *count += n;
+            }
+            self.coalesced += n;
+            return Ok(());
+        }
+        if self.cnt == self.data.len() {
+            self.dropped[msg.kind() as usize] += 1;
+            return Err(());
+        }
+
+        if let Message::Xhci { .. } = msg {
+            self.xhci_pos = Some(self.write_pos);
+        }
+        self.cnt += 1;
+        self.data[self.write_pos] = msg;
+        self.write_pos = (self.write_pos + 1) % self.data.len();
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<Message>{
+        if self.cnt == 0 {
+            return None;
+        }
+
+        if self.xhci_pos == Some(self.read_pos) {
+            self.xhci_pos = None;
+        }
+        self.cnt -= 1;
+        let msg = self.data[self.read_pos];
+        self.read_pos = (self.read_pos + 1) % self.data.len();
+        Some(msg)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cnt
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cnt == 0
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats { len: self.cnt, dropped: self.dropped, coalesced: self.coalesced }
+    }
+}
+
+/// 捨てたメッセージの数を、前に報告したときより増えていれば警告する
+fn warn_dropped_messages(reported: &mut [usize; Message::KINDS]) {
+    let dropped = EVENTS.lock().stats().dropped;
+    if dropped == *reported {
+        return;
+    }
+    let mut w = StackWriter::new();
+    for (i, name) in Message::NAMES.iter().enumerate() {
+        let _ = write!(w, " {}={}(+{})", name, dropped[i], dropped[i] - reported[i]);
+    }
+    warn!("events: queue full, messages dropped:{}", from_utf8(w.as_bytes()).unwrap_or(""));
+    *reported = dropped;
+}
+
+/// 空き時間にだけ行う仕事。dueがtrueを返したら割り込みを有効にしてrunを呼ぶ
+struct Idle {
+    due: fn() -> bool,
+    run: fn(),
+}
+
+/// メインループで呼ぶ関数の登録。新しいメッセージや仕事はここに足すだけで、ループ自体は変えない
+#[derive(Default)]
+pub struct Handlers<'a> {
+    /// MessageKindごと
+    on_message: [Vec<Box<dyn FnMut(Message) + 'a>>; Message::KINDS],
+    /// 起きるたびに、メッセージを処理する前に呼ぶ
+    on_wake: Vec<Box<dyn FnMut() + 'a>>,
+    /// 1周の最後、メッセージを処理した後に呼ぶ
+    at_end: Vec<Box<dyn FnMut() + 'a>>,
+    /// 待ち行列の外にある仕事が残っているか。割り込みを止めたまま呼ぶので、軽く、ロックを待たないこと
+    wake_if: Vec<fn() -> bool>,
+    idle: Vec<Idle>,
+}
+
+impl<'a> Handlers<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// kindのメッセージが来たら呼ぶ。同じ種類に複数登録したら登録した順に呼ぶ
+    pub fn on_message(&mut self, kind: MessageKind, f: impl FnMut(Message) + 'a) -> &mut Self {
+        self.on_message[kind as usize].push(Box::new(f));
+        self
+    }
+
+    pub fn on_wake(&mut self, f: impl FnMut() + 'a) -> &mut Self {
+        self.on_wake.push(Box::new(f));
+        self
+    }
+
+    pub fn at_end(&mut self, f: impl FnMut() + 'a) -> &mut Self {
+        self.at_end.push(Box::new(f));
+        self
+    }
+
+    /// pendingがtrueを返す間は休まない
+    pub fn wake_if(&mut self, pending: fn() -> bool) -> &mut Self {
+        self.wake_if.push(pending);
+        self
+    }
+
+    /// 他に仕事が無く、休む前にdueがtrueなら、割り込みを有効にしてrunを呼ぶ。その間に来たメッセージはrunの後で処理する
+    pub fn when_idle(&mut self, due: fn() -> bool, run: fn()) -> &mut Self {
+        self.idle.push(Idle { due, run });
+        self
+    }
+
+    /// 休まずに処理すべきものがあるか。割り込みを止めて呼ぶ
+    fn has_work(&self) -> bool {
+        !EVENTS.lock().is_empty() || self.wake_if.iter().any(|pending| pending())
+    }
+}
+
+/// カーネルのメインループ
+pub struct EventLoop<'a> {
+    handlers: Handlers<'a>,
+    /// 前に警告したときの、捨てたメッセージの数
+    dropped_reported: [usize; Message::KINDS],
+}
+
+impl<'a> EventLoop<'a> {
+    /// 仕事が来るまで休み、起きたらメッセージを1つ取り出して登録された関数に渡す、を繰り返す
+    pub fn run(handlers: Handlers<'a>) -> ! {
+        let mut el = Self { handlers, dropped_reported: [0; Message::KINDS] };
+        loop {
+            el.sleep_until_work();
+            let msg = EVENTS.lock().pop();
+            warn_dropped_messages(&mut el.dropped_reported);
+            for f in &mut el.handlers.on_wake {
+                f();
+            }
+            if let Some(msg) = msg {
+                for f in &mut el.handlers.on_message[msg.kind() as usize] {
+                    f(msg);
+                }
+            }
+            for f in &mut el.handlers.at_end {
+                f();
+            }
+        }
+    }
+
+    /// 休む前に割り込みを止めて仕事が無いことを確かめ、sti; hltで休む。
+    /// stiの直後の1命令の間は割り込みが入らないので、確かめた後に来た割り込みでも必ずhltから起きる
+    fn sleep_until_work(&self) {
+        loop {
+            set_interrupt_flag(false);
+            if self.handlers.has_work() {
+                set_interrupt_flag(true);
+                return;
+            }
+            if let Some(idle) = self.handlers.idle.iter().find(|idle| (idle.due)()) {
+                set_interrupt_flag(true);
+                (idle.run)();
+                continue;
+            }
+            unsafe {
+                asm!("sti", "hlt");
+            }
+        }
+    }
+}
+
+/// メッセージの待ち行列を用意する。割り込みを有効にする前に呼ぶ
+pub fn init() {
+    EVENTS.lock().init(MessageQueue::new());
+    register_event_nodes();
+}
+
+fn register_event_nodes() {
+    introspect::register("events", |_, out| {
+        let st = EVENTS.lock().stats();
+        write!(out, "queued={} coalesced={}", st.len, st.coalesced)?;
+        for (name, dropped) in Message::NAMES.iter().zip(st.dropped) {
+            write!(out, " dropped.{}={}", name, dropped)?;
+        }
+        writeln!(out)
+    }, 0)
+    .expect("event_loop: events");
+}
+
+pub fn run_message_queue_tests() {
+    let t = |tick| Timestamp { tick, count: 0 };
+    let mut q = MessageQueue::<4>::new();
+
+    // 取り出される前のxHCIの割り込みは、最初のものにまとまって場所を取らない
+    assert!(q.push(Message::Xhci { arrival: t(1), count: 1 }).is_ok());
+    assert!(q.push(Message::TimerTimeout(7)).is_ok());
+    for tick in 2..10 {
+        assert!(q.push(Message::Xhci { arrival: t(tick), count: 1 }).is_ok());
+    }
+    assert!(q.cnt == 2 && q.stats().coalesced == 8);
+    assert!(matches!(q.pop(), Some(Message::Xhci { arrival: Timestamp { tick: 1, .. }, count: 9 })));
+    // 取り出した後は新しく入る
+    assert!(q.push(Message::Xhci { arrival: t(10), count: 1 }).is_ok());
+    assert!(matches!(q.pop(), Some(Message::TimerTimeout(7))));
+    assert!(matches!(q.pop(), Some(Message::Xhci { arrival: Timestamp { tick: 10, .. }, count: 1 })));
+    assert!(q.pop().is_none());
+
+    // 満杯なら種類ごとに数えて捨てる。xHCIが入っていればまとまるので捨てられない
+    for v in 0..4 {
+        assert!(q.push(Message::TimerTimeout(v)).is_ok());
+    }
+    assert!(q.push(Message::TimerTimeout(4)).is_err());
+    assert!(q.push(Message::Xhci { arrival: t(11), count: 1 }).is_err());
+    assert!(q.stats().dropped == [1, 1, 0, 0]);
+    assert!(matches!(q.pop(), Some(Message::TimerTimeout(0))));
+    assert!(q.push(Message::Xhci { arrival: t(12), count: 1 }).is_ok());
+    assert!(q.push(Message::Xhci { arrival: t(13), count: 1 }).is_ok());
+    assert!(q.push(Message::TimerTimeout(5)).is_err());
+    let st = q.stats();
+    assert!(st.len == 4 && st.dropped == [1, 2, 0, 0] && st.coalesced == 9);
+    for v in 1..4 {
+        assert!(matches!(q.pop(), Some(Message::TimerTimeout(x)) if x == v));
+    }
+    assert!(matches!(q.pop(), Some(Message::Xhci { count: 2, .. })) && q.pop().is_none());
+}
+
@@ -2,46 +2,89 @@ use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::memory_manager::Mutex;
 
-use super::frame_buffer::FrameBuffer;
+use super::{frame_buffer::FrameBuffer, graphics::{PixelColor, PixelWriter, Rect}};
 
 /// 書き込み用のFrameBufferと読み出し用のFrameBufferを合わせたキャンバス
 /// 書き込みスレッドと読み出しスレッドの間でロックの取り合いが起こるのを防ぐ
-/// foreとback両方をロックするのはflushのみであり、flushはmemcpyでforeからbackへのコピーを行う
+/// foreとback両方をロックするのはflushのみであり、flushはmemcpyでbackからforeへのコピーを行う
+/// backは書き換えた範囲を記録しているので、flushはその範囲だけをコピーする
+///
+/// 描画の約束: 書き手はwrite_withで描いてflushするだけでよい。flushで更新フラグが立ち、
+/// 次のフレームの合成(LayeredWindowManager::compose)でそのウィンドウの範囲が画面に反映され、フラグが下りる。
+/// 書き手がdraw()を呼ぶ必要はなく、flushが合成を依頼する
 pub struct BufferedCanvas {
     /// 読み出し用のFrameBuffer
     fore: Mutex<FrameBuffer>,
     /// まず最初に書き込みを受けるFrameBuffer
     back: Mutex<FrameBuffer>,
-    /// 部分描画用のフラグ
-    is_updated: AtomicBool
+    /// foreが前回の合成から変わったか
+    is_updated: AtomicBool,
+    /// 前回の合成から変わったforeの範囲(キャンバスの座標)
+    dirty: Mutex<Option<Rect>>,
 }
 
 impl BufferedCanvas {
-    pub fn new(width: usize, height: usize) -> Self {
-        Self { fore: Mutex::new(FrameBuffer::new(width, height)), back: Mutex::new(FrameBuffer::new(width, height)), is_updated: AtomicBool::new(false)}
+    /// foreとbackの両方をbackgroundで塗って作る。確保したメモリの中身に頼らない
+    pub fn new(width: usize, height: usize, background: PixelColor) -> Self {
+        let filled = || {
+            let mut buf = FrameBuffer::new(width, height);
+            buf.fill_rect((0, 0).into(), (width as u32, height as u32).into(), background);
+            buf
+        };
+        let mut back = filled();
+        back.track_writes();
+        Self { fore: Mutex::new(filled()), back: Mutex::new(back), is_updated: AtomicBool::new(false), dirty: Mutex::new(None) }
     }
-    /// backからforeへのコピー
+
+    /// backのうち前回のflushから書き換えた範囲だけをforeにコピーする
+    /// 何も書いていなければコピーせず、範囲の分からない更新としてキャンバス全体の合成を依頼する
+    /// (new_layer_deferredのウィンドウを背景色のまま出すときなど)
     /// foreとback両方のlockを取る
     pub fn flush(&self) {
-        self.fore.lock().copy((0,0).into(), &self.back.lock());
+        let mut fore = self.fore.lock();
+        let mut back = self.back.lock();
+        let written = back.take_written();
+        if let Some(rect) = written {
+            fore.copy_rect((0,0).into(), &back, rect);
+        }
+        drop(back);
+        drop(fore);
+        self.mark_dirty(written);
+    }
+
+    fn mark_dirty(&self, rect: Option<Rect>) {
+        if let Some(rect) = rect {
+            let mut dirty = self.dirty.lock();
+            *dirty = Some(dirty.map_or(rect, |d| d.union(&rect)));
+        }
+        self.is_updated.store(true, Ordering::Release);
+        super::request_redraw();
     }
 
     /// foreのlockを取り、fを実行
-    pub fn with_fore(&self, f: impl FnOnce(&FrameBuffer)) {
-        f(&self.fore.lock());
+    pub fn with_fore<R>(&self, f: impl FnOnce(&FrameBuffer) -> R) -> R {
+        f(&self.fore.lock())
     }
 
     /// backのlockを取り、draw_funcを実行
+    /// backへの書き込みはflushするまで画面に出ない。書き換えた範囲はbackが覚えているので、伝えなくてよい
     pub fn write_with(&self, draw_func: impl FnOnce(&mut FrameBuffer)) {
         draw_func(&mut self.back.lock());
-        self.is_updated.store(true, Ordering::Relaxed);
     }
 
     pub fn is_updated(&self) -> bool {
-        self.is_updated.load(Ordering::Relaxed)
+        self.is_updated.load(Ordering::Acquire)
     }
 
-    pub fn clear_update_flag(&self) {
-        self.is_updated.store(false, Ordering::Relaxed);
+    /// フラグを下ろし、立っていたら変わった範囲を返す。合成の直前に呼ぶので、合成中のflushは次のフレームで拾われる
+    /// 範囲を取った後、フラグを立てる前のflushとすれ違ったときは、範囲が分からないのでキャンバス全体を返す
+    pub fn take_dirty(&self) -> Option<Rect> {
+        if !self.is_updated.swap(false, Ordering::AcqRel) {
+            return None;
+        }
+        self.dirty.lock().take().or_else(|| {
+            let (w, h) = self.fore.lock().resolution();
+            Some(Rect::from_wh(0, 0, w as i32, h as i32))
+        })
     }
 }
@@ -0,0 +1,352 @@
+use core::fmt::Write;
+
+use alloc::vec::Vec;
+
+use crate::{console::{Cell, Console, StackWriter}, serial};
+
+use super::{graphics::{Color, PixelWriter, Rect}, palette, window::{LayerId, Placement, Window}, with_layers};
+
+/// 2つのキャプチャの差分
+#[derive(Debug, Clone, Copy)]
+pub struct DiffStats {
+    pub differing_pixels: usize,
+    /// 異なるピクセルを全て含む最小の矩形。差分がなければNone
+    pub bounding_rect: Option<Rect>,
+}
+
+/// Window::capture_clientで取った幅widthのRGBキャプチャを比べる。
+/// 長さが違う場合、短い方にない部分のピクセルは全て異なるものとして数える
+pub fn compare_capture(a: &[u8], b: &[u8], width: usize) -> DiffStats {
+    let pixels = a.len().max(b.len()) / 3;
+    let mut stats = DiffStats { differing_pixels: 0, bounding_rect: None };
+    for i in 0..pixels {
+        if a.get(3 * i..3 * i + 3) == b.get(3 * i..3 * i + 3) {
+            continue;
+        }
+        stats.differing_pixels += 1;
+        let (x, y) = ((i % width) as i32, (i / width) as i32);
+        stats.bounding_rect = Some(match stats.bounding_rect {
+            None => Rect::from_wh(x, y, 1, 1),
+            Some(r) => Rect::from_points(r.x1.min(x), r.y1.min(y), r.x2.max(x + 1), r.y2.max(y + 1)),
+        });
+    }
+    stats
+}
+
+/// RGBのキャプチャを24bitのBMPにする
+pub fn encode_bmp(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let row_size = (3 * width + 3) & !3;
+    let image_size = row_size * height;
+    let file_size = 54 + image_size;
+
+    let mut bmp = Vec::with_capacity(file_size);
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&(file_size as u32).to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&54u32.to_le_bytes());
+    // BITMAPINFOHEADER
+    bmp.extend_from_slice(&40u32.to_le_bytes());
+    bmp.extend_from_slice(&(width as i32).to_le_bytes());
+    bmp.extend_from_slice(&(height as i32).to_le_bytes());
+    bmp.extend_from_slice(&1u16.to_le_bytes());
+    bmp.extend_from_slice(&24u16.to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&(image_size as u32).to_le_bytes());
+    bmp.extend_from_slice(&[0; 16]);
+
+    // 行は下から上へ、各ピクセルはBGRの順
+    for y in (0..height).rev() {
+        for px in rgb[3 * width * y..3 * width * (y + 1)].chunks_exact(3) {
+            bmp.extend_from_slice(&[px[2], px[1], px[0]]);
+        }
+        bmp.resize(bmp.len() + row_size - 3 * width, 0);
+    }
+    bmp
+}
+
+/// レイヤのクライアント領域をBMPにしてシリアルに書き出す。
+/// 先頭に "wincap <id> <バイト数>" の行を置き、受け取る側が切り出せるようにする
+pub fn dump_window_capture(id: LayerId) -> bool {
+    let Some(window) = with_layers(|l| l.layer(id).cloned()) else {
+        return false;
+    };
+    let (client, rgb) = {
+        let window = window.read();
+        (window.client_area(), window.capture_client(None))
+    };
+    let bmp = encode_bmp((client.x2 - client.x1) as usize, (client.y2 - client.y1) as usize, &rgb);
+
+    let mut header = StackWriter::new();
+    header.push_str("wincap ");
+    let _ = write!(header, "{id} {}\n", bmp.len());
+    serial::write_bytes(header.as_bytes());
+    serial::write_bytes(&bmp);
+    serial::write_bytes(b"\n");
+    true
+}
+
+fn pixel(rgb: &[u8], width: usize, x: usize, y: usize) -> Color {
+    let i = 3 * (width * y + x);
+    Color::new(rgb[i], rgb[i + 1], rgb[i + 2])
+}
+
+/// 透明色のあるウィンドウの合成を、1ピクセルずつcolor_atで読む方法と比べる
+/// 結果が同じことを確かめ、かかった時間を出す (bench feature)
+#[cfg(feature = "bench")]
+pub fn run_capture_benchmark() {
+    use crate::{println, timer::Timestamp};
+    use super::frame_buffer::FrameBuffer;
+
+    const W: usize = 256;
+    const H: usize = 256;
+    let mut win = Window::new(W, H, Some(palette::TRANSPARENT_KEY));
+    win.set_transparent_color(Some(palette::TRANSPARENT_KEY));
+    win.buffer().write_with(|back| {
+        for i in 0..16 {
+            back.fill_rect((i * 16, i * 16).into(), (W as u32 - i as u32 * 16, 8).into(), palette::WHITE);
+        }
+    });
+    win.buffer().flush();
+
+    let start = Timestamp::now();
+    let mut per_pixel = FrameBuffer::new(W, H);
+    win.buffer().with_fore(|fore| {
+        for y in 0..H {
+            for x in 0..W {
+                let pixel = fore.color_at(x, y);
+                if pixel != palette::TRANSPARENT_KEY {
+                    per_pixel.write((x as i32, y as i32).into(), pixel);
+                }
+            }
+        }
+    });
+    let mid = Timestamp::now();
+    let mut rows = FrameBuffer::new(W, H);
+    win.draw_to(&mut rows, None);
+    let end = Timestamp::now();
+
+    let whole = Rect::from_wh(0, 0, W as i32, H as i32);
+    assert!(per_pixel.read_raw(whole) == rows.read_raw(whole));
+    println!("bench: shaped composite {W}x{H}: per-pixel {}us, rows {}us", start.micros_until(&mid), mid.micros_until(&end));
+}
+
+pub fn run_capture_tests() {
+    // 画面より大きいウィンドウでも、画面外の部分がそのまま読める
+    let (w, h) = with_layers(|l| l.resolution());
+    let (w, h) = (w as usize + 40, h as usize + 40);
+    let mut big = Window::new(w, h, None);
+    big.move_to((-20, -20).into());
+    big.buffer().write_with(|back| {
+        back.fill_rect((0, 0).into(), (w as u32, h as u32).into(), palette::BLACK);
+        back.fill_rect((w as i32 - 4, h as i32 - 4).into(), (4, 4).into(), palette::WHITE);
+    });
+    big.buffer().flush();
+    let corner = big.capture_client(Some(Rect::from_wh(w as i32 - 8, h as i32 - 8, 8, 8)));
+    assert!(corner.len() == 8 * 8 * 3);
+    assert!(pixel(&corner, 8, 3, 3) == palette::BLACK && pixel(&corner, 8, 4, 4) == palette::WHITE);
+    let black = [0u8; 8 * 8 * 3];
+    let diff = compare_capture(&black, &corner, 8);
+    assert!(diff.differing_pixels == 16);
+    assert!(matches!(diff.bounding_rect, Some(Rect { x1: 4, y1: 4, x2: 8, y2: 8 })));
+    assert!(compare_capture(&corner, &corner, 8).bounding_rect.is_none());
+
+    // クライアント領域の外は切り取られる
+    big.set_client_area(Some(Rect::from_wh(2, 2, 10, 10)));
+    assert!(big.capture_client(Some(Rect::from_wh(5, 5, 10, 10))).len() == 5 * 5 * 3);
+    assert!(big.capture_client(Some(Rect::from_wh(20, 20, 4, 4))).is_empty());
+    drop(big);
+
+    // コンソール: 改行は次の行の先頭から書き始め、改行文字自体は何も描かない
+    let hndl = with_layers(|l| l.new_layer(Window::new(8 * 4, 16 * 2, None), Placement::Hidden));
+    let window = hndl.window().clone();
+    let mut console = Console::new(hndl, palette::CONSOLE_FG, palette::CONSOLE_BG);
+    window.read().buffer().flush();
+    let blank = window.read().capture_client(None);
+    console.put_string(b"\n");
+    assert!(compare_capture(&blank, &window.read().capture_client(None), 8 * 4).differing_pixels == 0);
+    console.put_string(b"#");
+    let diff = compare_capture(&blank, &window.read().capture_client(None), 8 * 4);
+    let r = diff.bounding_rect.unwrap();
+    assert!(diff.differing_pixels > 0 && r.x1 >= 0 && r.x2 <= 8 && r.y1 >= 16 && r.y2 <= 32);
+
+    // エスケープシーケンス: 色は文字バッファのマスごとに残り、スクロールしても変わらない
+    console.put_string(b"\x1b[1;1H\x1b[41;93mA\x1b[0mB\x1b[?7h");
+    assert!(console.cell(0, 0) == Cell { ch: b'A', fg: palette::ANSI_COLORS[11], bg: palette::ANSI_COLORS[1] });
+    assert!(console.cell(0, 1) == Cell { ch: b'B', fg: palette::CONSOLE_FG, bg: palette::CONSOLE_BG });
+    assert!(pixel(&window.read().capture_client(None), 8 * 4, 0, 0) == palette::ANSI_COLORS[1]);
+    console.put_string(b"\x1b[2;1H\n");
+    assert!(console.cell(0, 0).ch == b'#' && console.cell(0, 1).ch == 0);
+    assert!(pixel(&window.read().capture_client(None), 8 * 4, 0, 0) == palette::CONSOLE_BG);
+    // 消去すると文字も色も消える
+    console.put_string(b"\x1b[42m\x1b[2J\x1b[0m");
+    assert!((0..2).all(|row| (0..4).all(|col| console.cell(row, col) == Cell { ch: 0, fg: palette::CONSOLE_FG, bg: palette::ANSI_COLORS[2] })));
+
+    // クライアント領域があれば文字はその内側に並べ、スクロールしても外側の枠は描き換えない
+    let (w, h) = (8 * 4 + 4, 16 * 2 + 4);
+    let mut win = Window::new(w, h, None);
+    win.set_client_area(Some(Rect::from_wh(2, 2, 8 * 4, 16 * 2)));
+    win.buffer().write_with(|back| back.fill_rect((0, 0).into(), (w as u32, h as u32).into(), palette::WHITE));
+    win.buffer().flush();
+    let hndl = with_layers(|l| l.new_layer(win, Placement::Hidden));
+    let window = hndl.window().clone();
+    let mut console = Console::new(hndl, palette::CONSOLE_FG, palette::CONSOLE_BG);
+    window.read().buffer().flush();
+    console.put_string(b"\x1b[41m \x1b[0m\n\x1b[41m \x1b[0m\n");
+    assert!(console.cell(0, 0).bg == palette::ANSI_COLORS[1] && console.cell(1, 0).bg == palette::CONSOLE_BG);
+    let client = window.read().capture_client(None);
+    assert!(pixel(&client, 8 * 4, 0, 0) == palette::ANSI_COLORS[1] && pixel(&client, 8 * 4, 7, 15) == palette::ANSI_COLORS[1]);
+    assert!(pixel(&client, 8 * 4, 8, 0) == palette::CONSOLE_BG && pixel(&client, 8 * 4, 0, 16) == palette::CONSOLE_BG);
+    window.read().buffer().with_fore(|fore| {
+        let border = |x: usize, y: usize| x < 2 || y < 2 || x >= w - 2 || y >= h - 2;
+        assert!((0..h).all(|y| (0..w).filter(|&x| border(x, y)).all(|x| fore.color_at(x, y) == palette::WHITE)));
+    });
+    drop(console);
+
+    // スクロールバック: 流れた行は残り、遡って表示している間に出力があっても表示は動かない
+    let hndl = with_layers(|l| l.new_layer(Window::new(8 * 4, 16 * 2, None), Placement::Hidden));
+    let window = hndl.window().clone();
+    let mut console = Console::new(hndl, palette::CONSOLE_FG, palette::CONSOLE_BG);
+    // 書いたマスだけがflushされ、スクロールすると文字の範囲全体になる
+    let _ = window.read().buffer().take_dirty();
+    console.put_string(b"a");
+    assert!(window.read().buffer().take_dirty() == Some(Rect::from_wh(0, 0, 8, 16)));
+    assert!(window.read().buffer().take_dirty().is_none());
+    console.put_string(b"\nb\nc");
+    assert!(window.read().buffer().take_dirty() == Some(Rect::from_wh(0, 0, 8 * 4, 16 * 2)));
+    assert!(console.history_len() == 1 && console.cell(0, 0).ch == b'b');
+    let live = window.read().capture_client(None);
+    console.scroll_view_up(5);
+    assert!(console.view_offset() == 1);
+    let scrolled = window.read().capture_client(None);
+    assert!(compare_capture(&live, &scrolled, 8 * 4).differing_pixels > 0);
+    console.put_string(b"\nd");
+    assert!(console.view_offset() == 2 && console.cell(1, 0).ch == b'd');
+    assert!(compare_capture(&scrolled, &window.read().capture_client(None), 8 * 4).differing_pixels == 0);
+    console.scroll_view_down(1);
+    assert!(console.view_offset() == 1);
+    console.scroll_to_bottom();
+    assert!(console.view_offset() == 0);
+    // 残す行数を減らすと古い行から捨てる。follow_outputなら出力で最新の画面に戻る
+    console.set_scrollback_limit(1);
+    assert!(console.history_len() == 1);
+    console.scroll_view_up(1);
+    console.set_follow_output(true);
+    console.put_string(b"e");
+    assert!(console.view_offset() == 0 && console.cell(1, 1).ch == b'e');
+
+    // 書き手はflushするだけで、draw()を呼ばなくても次のフレームの合成で画面に出る
+    let hndl = with_layers(|l| l.new_layer(Window::new(8, 8, None), Placement::Hidden));
+    let id = hndl.layer_id();
+    let on_screen = Rect::from_wh(0, 0, 8, 8);
+    let fill = |c: Color| hndl.window().read().buffer().write_with(|back| back.fill_rect((0, 0).into(), (8, 8).into(), c));
+    fill(palette::WHITE);
+    hndl.window().read().buffer().flush();
+    with_layers(|l| {
+        let _ = l.raise_to_top(id);
+        // 1フレーム分の合成
+        assert!(l.compose());
+        assert!(pixel(&l.capture_screen(on_screen), 8, 7, 7) == palette::WHITE);
+        // 変化がなければ合成しない
+        assert!(!l.compose());
+    });
+    // flushしていない書き込みは出ない
+    fill(palette::BLACK);
+    with_layers(|l| {
+        assert!(!l.compose());
+        assert!(pixel(&l.capture_screen(on_screen), 8, 0, 0) == palette::WHITE);
+    });
+    hndl.window().read().buffer().flush();
+    with_layers(|l| {
+        assert!(l.compose());
+        assert!(pixel(&l.capture_screen(on_screen), 8, 0, 0) == palette::BLACK);
+        // 閉じると、その範囲が下のレイヤで描き直される
+        let _ = l.close_layer(id);
+        assert!(l.compose());
+    });
+
+    // 作ったばかりのウィンドウは、何も描かなくても全体が背景色で出る
+    let bg = palette::WINDOW_GRAY;
+    let all_bg = |rgb: &[u8]| (0..8 * 8).all(|i| pixel(rgb, 8, i % 8, i / 8) == bg);
+    let hndl = with_layers(|l| l.new_layer(Window::new(8, 8, Some(bg)), Placement::Hidden));
+    let id = hndl.layer_id();
+    with_layers(|l| {
+        let _ = l.raise_to_top(id);
+        l.compose();
+        assert!(all_bg(&l.capture_screen(on_screen)));
+    });
+    // clearは前の内容を消して塗り直す
+    hndl.window().read().buffer().write_with(|back| back.fill_rect((2, 2).into(), (4, 4).into(), palette::WHITE));
+    hndl.window().read().clear(bg);
+    assert!(all_bg(&hndl.window().read().capture_client(None)));
+    with_layers(|l| {
+        let _ = l.close_layer(id);
+        l.compose();
+    });
+
+    // new_layer_deferredのウィンドウは最初のflushまで画面に出ない
+    let hndl = with_layers(|l| l.new_layer_deferred(Window::new(8, 8, Some(palette::WHITE)), Placement::Hidden));
+    let id = hndl.layer_id();
+    with_layers(|l| {
+        let _ = l.raise_to_top(id);
+        l.compose();
+        assert!(pixel(&l.capture_screen(on_screen), 8, 7, 7) != palette::WHITE);
+        assert!(l.find_layer((0, 0).into(), |lid| lid == id).is_none());
+    });
+    hndl.window().read().buffer().flush();
+    with_layers(|l| {
+        assert!(l.compose());
+        assert!(pixel(&l.capture_screen(on_screen), 8, 7, 7) == palette::WHITE);
+        assert!(l.find_layer((0, 0).into(), |lid| lid == id) == Some(id));
+        let _ = l.close_layer(id);
+        l.compose();
+    });
+
+    // スナップショットを取ってから描くまでの間に重なり順を変えても、描くのは取ったときの順番で、
+    // 変更は次のフレームで出る。閉じたレイヤもスナップショットが持っている間は描ける
+    let colors = [Color::new(0xff, 0, 0), Color::new(0, 0xff, 0), Color::new(0, 0, 0xff)];
+    let hndls: Vec<_> = colors.iter().map(|c| with_layers(|l| l.new_layer(Window::new(8, 8, Some(*c)), Placement::Hidden))).collect();
+    let ids: Vec<LayerId> = hndls.iter().map(|h| h.layer_id()).collect();
+    enum Op { Raise(usize), Hide(usize), Close(usize) }
+    let schedule = [Op::Raise(0), Op::Raise(1), Op::Raise(2), Op::Raise(0), Op::Hide(0), Op::Raise(1), Op::Close(1), Op::Raise(0), Op::Close(2), Op::Close(0)];
+    // 3つのうち表示中のもの。後ろほど上
+    let mut model: Vec<usize> = Vec::new();
+    let mut last_top = None;
+    for op in schedule {
+        // 表示中のものがあれば、毎回スナップショットが取れるように更新しておく
+        for h in &hndls {
+            h.window().read().buffer().flush();
+        }
+        with_layers(|l| {
+            let frame = l.prepare_frame();
+            match op {
+                Op::Raise(i) => {
+                    let _ = l.raise_to_top(ids[i]);
+                    model.retain(|m| *m != i);
+                    model.push(i);
+                }
+                Op::Hide(i) => {
+                    l.hide(ids[i]);
+                    model.retain(|m| *m != i);
+                }
+                Op::Close(i) => {
+                    let _ = l.close_layer(ids[i]);
+                    model.retain(|m| *m != i);
+                }
+            }
+            // 描いている途中のフレームには、変更の前の状態が出る
+            if let Some(frame) = frame {
+                l.screen().lock().render(&frame);
+                if let Some(top) = last_top {
+                    assert!(pixel(&l.capture_screen(on_screen), 8, 0, 0) == colors[top]);
+                }
+            }
+            l.compose();
+            let top = pixel(&l.capture_screen(on_screen), 8, 0, 0);
+            match model.last() {
+                Some(i) => assert!(top == colors[*i]),
+                None => assert!(!colors.contains(&top)),
+            }
+            last_top = model.last().copied();
+        });
+    }
+}
@@ -0,0 +1,128 @@
+use alloc::vec::Vec;
+
+use super::{frame_buffer::FrameBuffer, graphics::{Color, PixelWriter, Rect, Vec2}, palette};
+
+/// レイヤとは別に、合成した画面の上へ最後に描くマウスカーソル。
+/// 描く前に下の画素を退避しておき、動かすときはそれを戻してから新しい位置に描くので、レイヤの合成をしない
+pub struct Cursor {
+    width: usize,
+    height: usize,
+    /// 左上から行ごとに並べた色。Noneは透明
+    bitmap: Vec<Option<Color>>,
+    /// 左上の位置。ここがクリックの位置になる
+    pos: Vec2<i32>,
+    /// カーソルの下にあった画素。画面の形式で持つ
+    saved: Option<FrameBuffer>,
+    /// 画面に描いてあり、savedに下の画素があるか
+    drawn: bool,
+}
+
+impl Cursor {
+    pub const fn new() -> Self {
+        Self { width: 0, height: 0, bitmap: Vec::new(), pos: Vec2 { x: 0, y: 0 }, saved: None, drawn: false }
+    }
+
+    pub fn pos(&self) -> Vec2<i32> {
+        self.pos
+    }
+
+    /// 画面上でカーソルが覆う範囲
+    pub fn rect(&self) -> Rect {
+        Rect::from_wh(self.pos.x, self.pos.y, self.width as i32, self.height as i32)
+    }
+
+    pub fn is_drawn(&self) -> bool {
+        self.drawn
+    }
+
+    /// mouse::MOUSE_CURSOR_SHAPEと同じ形式の絵にする。'@'が黒、'.'が白で、それ以外は透明。
+    /// 幅は一番長い行に合わせる。画面に描いてあれば描き直す
+    pub fn set_bitmap(&mut self, screen: &mut FrameBuffer, shape: &[&str]) {
+        let drawn = self.drawn;
+        self.hide(screen);
+        self.width = shape.iter().map(|row| row.len()).max().unwrap_or(0);
+        self.height = shape.len();
+        self.bitmap = shape
+            .iter()
+            .flat_map(|row| (0..self.width).map(move |x| match row.as_bytes().get(x) {
+                Some(b'@') => Some(palette::BLACK),
+                Some(b'.') => Some(palette::WHITE),
+                _ => None,
+            }))
+            .collect();
+        self.saved = None;
+        if drawn {
+            self.show(screen);
+        }
+    }
+
+    /// 下の画素を戻し、posに描き直す。小さな2回の転送で済む
+    pub fn move_to(&mut self, screen: &mut FrameBuffer, pos: Vec2<i32>) {
+        let drawn = self.drawn;
+        self.hide(screen);
+        self.pos = pos;
+        if drawn {
+            self.show(screen);
+        }
+    }
+
+    /// 退避しておいた下の画素を画面に戻す
+    pub fn hide(&mut self, screen: &mut FrameBuffer) {
+        if !self.drawn {
+            return;
+        }
+        self.drawn = false;
+        if let Some(saved) = &self.saved {
+            screen.copy_rect(self.pos, saved, self.rect());
+        }
+    }
+
+    /// 下の画素を退避してからカーソルを描く。既に描いてあれば何もしない
+    pub fn show(&mut self, screen: &mut FrameBuffer) {
+        if self.drawn || self.bitmap.is_empty() {
+            return;
+        }
+        let saved = self.saved.get_or_insert_with(|| FrameBuffer::with_layout(self.width, self.height, self.width, screen.pixel_format()));
+        let all = saved.bounds();
+        saved.copy_rect(Vec2::new(-self.pos.x, -self.pos.y), screen, all);
+        let Some(visible) = self.rect().intersection(&screen.bounds()) else {
+            self.drawn = true;
+            return;
+        };
+        for y in visible.y1..visible.y2 {
+            for x in visible.x1..visible.x2 {
+                let i = (y - self.pos.y) as usize * self.width + (x - self.pos.x) as usize;
+                if let Some(c) = self.bitmap[i] {
+                    screen.write(Vec2::new(x, y), c);
+                }
+            }
+        }
+        self.drawn = true;
+    }
+}
+
+pub fn run_cursor_tests() {
+    use super::frame_buffer::PixelFormat;
+
+    let bg = palette::WINDOW_GRAY;
+    let mut screen = FrameBuffer::with_layout(8, 8, 8, PixelFormat::PixelBGRResv8BitPerColor);
+    screen.fill_rect((0, 0).into(), (8, 8).into(), bg);
+    let mut c = Cursor::new();
+    // 短い行は透明で埋める
+    c.set_bitmap(&mut screen, &["@.", "@"]);
+    assert!(c.rect() == Rect::from_wh(0, 0, 2, 2) && c.bitmap[3].is_none() && !c.is_drawn());
+
+    c.move_to(&mut screen, (3, 3).into());
+    c.show(&mut screen);
+    assert!(screen.color_at(3, 3) == palette::BLACK && screen.color_at(4, 3) == palette::WHITE && screen.color_at(4, 4) == bg);
+    // 動かすと跡が残らない
+    c.move_to(&mut screen, (6, 7).into());
+    assert!(screen.color_at(3, 3) == bg && screen.color_at(4, 3) == bg && screen.color_at(3, 4) == bg);
+    assert!(screen.color_at(6, 7) == palette::BLACK && screen.color_at(7, 7) == palette::WHITE);
+    // 画面からはみ出した部分は描かず、戻すときも見えていた所だけ
+    c.move_to(&mut screen, (-1, 0).into());
+    assert!(screen.color_at(6, 7) == bg && screen.color_at(7, 7) == bg);
+    assert!(screen.color_at(0, 0) == palette::WHITE && screen.color_at(0, 1) == bg);
+    c.hide(&mut screen);
+    assert!(screen.color_at(0, 0) == bg && !c.is_drawn());
+}
@@ -0,0 +1,126 @@
+use crate::memory_manager::LazyInit;
+
+use super::{frame_buffer::FrameBuffer, graphics::{Color, PixelWriter, Vec2}, palette, window::{LayerHandle, LayerId, Placement, Window}, with_layers};
+
+/// 一番奥に敷く、画面全体を覆うレイヤ。ファームウェアが残したVRAMの中身が見えないようにする
+static DESKTOP: LazyInit<LayerHandle> = LazyInit::new();
+
+/// 壁紙の描き方
+#[derive(Debug, Clone, Copy)]
+pub enum Background<'a> {
+    Solid(Color),
+    /// 上端のtopから下端のbottomへ縦に変わる
+    VerticalGradient { top: Color, bottom: Color },
+    /// 1ピクセル3バイト(R, G, B)を左上から詰めた画像。画面の中央に置き、はみ出した分は切り、余った所はfillで塗る
+    Image { width: usize, height: usize, rgb: &'a [u8], fill: Color },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundError {
+    /// init_desktopの前に呼ばれた
+    NoDesktop,
+    /// 画像の大きさに対してrgbが短い(バイト数)
+    ImageTooShort { expected: usize, actual: usize },
+}
+
+/// 画面全体の大きさの壁紙レイヤを作り、奥の帯の一番下に置く。graphic::initialize_winmgrから呼ぶ
+pub fn init_desktop() {
+    let hndl = with_layers(|l| {
+        let (w, h) = l.resolution();
+        let hndl = l.new_layer(Window::new(w as usize, h as usize, Some(palette::DESKTOP_BOTTOM)), Placement::AboveConsole);
+        let _ = l.set_always_on_bottom(hndl.layer_id(), true);
+        hndl
+    });
+    DESKTOP.lock().init(hndl);
+    let _ = set_background(Background::VerticalGradient { top: palette::DESKTOP_TOP, bottom: palette::DESKTOP_BOTTOM });
+}
+
+/// 壁紙のレイヤ。init_desktopの前ならNone
+pub fn layer_id() -> Option<LayerId> {
+    let desktop = DESKTOP.lock();
+    desktop.is_init().then(|| desktop.layer_id())
+}
+
+/// 壁紙を描き直す。画像は描くときに写すので、rgbはこの呼び出しの間だけ有効であればよい
+pub fn set_background(background: Background) -> Result<(), BackgroundError> {
+    if let Background::Image { width, height, rgb, .. } = background {
+        let expected = width.saturating_mul(height).saturating_mul(3);
+        if rgb.len() < expected {
+            return Err(BackgroundError::ImageTooShort { expected, actual: rgb.len() });
+        }
+    }
+    let window = {
+        let desktop = DESKTOP.lock();
+        if !desktop.is_init() {
+            return Err(BackgroundError::NoDesktop);
+        }
+        desktop.window().clone()
+    };
+    let window = window.read();
+    window.buffer().write_with(|back| draw_background(back, &background));
+    window.buffer().flush();
+    Ok(())
+}
+
+/// fbの全体にbackgroundを描く。画像の長さは確かめてあるものとする
+fn draw_background(fb: &mut FrameBuffer, background: &Background) {
+    let (w, h) = fb.resolution();
+    match *background {
+        Background::Solid(c) => fb.fill_rect((0, 0).into(), (w, h).into(), c),
+        Background::VerticalGradient { top, bottom } => {
+            for y in 0..h {
+                fb.fill_rect((0, y as i32).into(), (w, 1).into(), gradient_at(top, bottom, y, h));
+            }
+        }
+        Background::Image { width, height, rgb, fill } => {
+            fb.fill_rect((0, 0).into(), (w, h).into(), fill);
+            let pos = Vec2::new(centered(w, width), centered(h, height));
+            fb.write_rgb(pos, width, &rgb[..width * height * 3]);
+        }
+    }
+}
+
+/// 高さhのグラデーションのy行目の色。一番上がtop、一番下がbottomになる
+fn gradient_at(top: Color, bottom: Color, y: u32, h: u32) -> Color {
+    let a = (y as u64 * 255 / (h.max(2) - 1) as u64).min(255) as u8;
+    bottom.with_alpha(a).over(top)
+}
+
+/// 長さscreenの中に長さlenのものを中央に置いたときの開始位置。はみ出すときは負になる
+fn centered(screen: u32, len: usize) -> i32 {
+    ((screen as i64 - len as i64) / 2).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+pub fn run_desktop_tests() {
+    use super::frame_buffer::PixelFormat;
+
+    let (top, bottom) = (Color::gray(0), Color::gray(255));
+    assert!(gradient_at(top, bottom, 0, 256) == top && gradient_at(top, bottom, 255, 256) == bottom);
+    assert!(gradient_at(top, bottom, 128, 256) == Color::gray(128));
+    // 1行しかなければ上の色
+    assert!(gradient_at(top, bottom, 0, 1) == top);
+    assert!(centered(10, 4) == 3 && centered(4, 10) == -3);
+
+    let mut fb = FrameBuffer::with_layout(4, 3, 4, PixelFormat::PixelBGRResv8BitPerColor);
+    draw_background(&mut fb, &Background::VerticalGradient { top, bottom });
+    assert!(fb.row_pixels(0, 0..4).all(|c| c == top) && fb.row_pixels(2, 0..4).all(|c| c == bottom));
+    assert!(fb.color_at(3, 1) == Color::gray(127));
+
+    // 2x1の画像は中央に置かれ、周りはfillになる
+    let fill = palette::DESKTOP_BOTTOM;
+    let red = Color::new(0xff, 0, 0);
+    let rgb = [0xff, 0, 0, 0xff, 0, 0];
+    draw_background(&mut fb, &Background::Image { width: 2, height: 1, rgb: &rgb, fill });
+    assert!(fb.row_pixels(1, 0..4).eq([fill, red, red, fill]) && fb.row_pixels(0, 0..4).all(|c| c == fill));
+    // 画面より大きい画像は中央の部分だけが見える
+    let big: [u8; 6 * 3 * 3] = core::array::from_fn(|i| if i / 3 % 6 == 1 { 0xff } else { 0 });
+    draw_background(&mut fb, &Background::Image { width: 6, height: 3, rgb: &big, fill });
+    assert!(fb.color_at(0, 0) == Color::gray(0xff) && fb.color_at(1, 0) == Color::gray(0));
+
+    assert!(set_background(Background::Image { width: 2, height: 2, rgb: &rgb, fill })
+        == Err(BackgroundError::ImageTooShort { expected: 12, actual: 6 }));
+    if layer_id().is_some() {
+        assert!(set_background(Background::Solid(fill)).is_ok());
+        assert!(set_background(Background::VerticalGradient { top: palette::DESKTOP_TOP, bottom: palette::DESKTOP_BOTTOM }).is_ok());
+    }
+}
@@ -0,0 +1,123 @@
+use core::{fmt, ptr::null_mut, sync::atomic::{AtomicPtr, Ordering}};
+
+use super::{font::{write_ascii, GLYPH_H, GLYPH_W}, frame_buffer::{FrameBuffer, FrameBufferRaw}, graphics::PixelWriter, palette};
+
+/// ブートローダから受け取ったフレームバッファ。画面が無ければnull
+static FRAME_BUFFER: AtomicPtr<FrameBufferRaw> = AtomicPtr::new(null_mut());
+
+/// 起動直後に呼ぶ。rawはブートローダのデータで、カーネルが動いている間は有効なまま
+pub fn set_frame_buffer(raw: *const FrameBufferRaw) {
+    FRAME_BUFFER.store(raw as *mut FrameBufferRaw, Ordering::Release);
+}
+
+/// ESC[...mなどの制御シーケンスのどこを読んでいるか
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+/// パニック時の画面出力。コンソールやレイヤーのロックは持たれたままかもしれないので、
+/// フレームバッファにじかに左上から書く。画面の下まで来たらそれ以降は捨てる
+pub struct EmergencyWriter {
+    fb: FrameBuffer,
+    col: u32,
+    row: u32,
+    cols: u32,
+    rows: u32,
+    escape: Escape,
+}
+
+impl EmergencyWriter {
+    /// 起動時のフレームバッファを開いて塗りつぶす。画面が無ければNone
+    /// メモリ割り当てもロックも行わない
+    pub unsafe fn open() -> Option<Self> {
+        let raw = FRAME_BUFFER.load(Ordering::Acquire);
+        (!raw.is_null()).then(|| Self::new(FrameBuffer::from_raw(raw)))
+    }
+
+    pub fn new(mut fb: FrameBuffer) -> Self {
+        let (w, h) = fb.resolution();
+        fb.fill_rect((0, 0).into(), (w, h).into(), palette::PANIC_BG);
+        Self { fb, col: 0, row: 0, cols: w / GLYPH_W, rows: h / GLYPH_H, escape: Escape::None }
+    }
+
+    /// 今の行を含めて、あと何行書けるか
+    pub fn rows_left(&self) -> usize {
+        self.rows.saturating_sub(self.row) as usize
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.put_byte(*b);
+        }
+    }
+
+    fn put_byte(&mut self, b: u8) {
+        // 色は付けないので、制御シーケンスは終わりの文字まで読み飛ばす
+        match (self.escape, b) {
+            (Escape::Esc, b'[') => self.escape = Escape::Csi,
+            (Escape::Esc, _) => self.escape = Escape::None,
+            (Escape::Csi, 0x40..=0x7e) => self.escape = Escape::None,
+            (Escape::Csi, _) => {}
+            (Escape::None, 0x1b) => self.escape = Escape::Esc,
+            (Escape::None, b'\n') => {
+                self.col = 0;
+                self.row += 1;
+            }
+            (Escape::None, b'\r') => self.col = 0,
+            (Escape::None, _) => {
+                if self.col >= self.cols {
+                    self.col = 0;
+                    self.row += 1;
+                }
+                if self.row >= self.rows {
+                    return;
+                }
+                let c = if b == b' ' || b.is_ascii_graphic() { b as char } else { '?' };
+                write_ascii(&mut self.fb, self.col * GLYPH_W, self.row * GLYPH_H, c, palette::PANIC_FG);
+                self.col += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// bytesの最後のn行。末尾の改行で終わる空の行は数えない
+pub fn last_lines(bytes: &[u8], n: usize) -> &[u8] {
+    if n == 0 {
+        return &[];
+    }
+    let body = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let start = body.iter().enumerate().rev().filter(|(_, b)| **b == b'\n').nth(n - 1).map_or(0, |(i, _)| i + 1);
+    &bytes[start..]
+}
+
+pub fn run_emergency_tests() {
+    use super::frame_buffer::PixelFormat;
+
+    assert!(last_lines(b"a\nb\nc\n", 2) == b"b\nc\n");
+    assert!(last_lines(b"a\nb\nc", 2) == b"b\nc");
+    assert!(last_lines(b"a\nb\n", 5) == b"a\nb\n");
+    assert!(last_lines(b"a\nb\n", 0).is_empty());
+
+    // 4文字x2行の画面。折り返して、下に溢れた分は捨てる
+    let fb = FrameBuffer::with_layout(4 * GLYPH_W as usize, 2 * GLYPH_H as usize, 4 * GLYPH_W as usize, PixelFormat::PixelRGBResv8BitPerColor);
+    let mut w = EmergencyWriter::new(fb);
+    assert!(w.rows_left() == 2 && w.fb.color_at(0, 0) == palette::PANIC_BG);
+    // 色の指定は文字にならない
+    w.write_bytes(b"\x1b[31mpan\x1b[0mic!\nlost");
+    assert!(w.rows_left() == 0);
+    let drawn = |w: &EmergencyWriter, col: u32, row: u32| {
+        (0..GLYPH_H).any(|y| (0..GLYPH_W).any(|x| w.fb.color_at((col * GLYPH_W + x) as usize, (row * GLYPH_H + y) as usize) == palette::PANIC_FG))
+    };
+    assert!((0..4).all(|col| drawn(&w, col, 0)));
+    assert!(drawn(&w, 1, 1) && !drawn(&w, 2, 1));
+}
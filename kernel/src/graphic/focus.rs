@@ -0,0 +1,319 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::{deferred, memory_manager::LazyInit, println, shortcut::{self, Mods}, warn};
+
+use super::{snap, font::write_string, palette, graphics::{PixelWriter, Vec2}, window::{close_button_rect, stale_id_hits, title_bar_rect, Hit, LayerHandle, LayerId, Placement, StaleLayerId, Window}, with_layers};
+
+const KEY_TAB: u8 = 0x2b;
+const BUTTON_LEFT: u8 = 1;
+
+/// 切り替え画面に並べるウィンドウの最大数
+const SWITCHER_ROWS: usize = 8;
+/// 切り替え画面に表示するタイトルの最大文字数
+const TITLE_COLS: usize = 20;
+const SWITCHER_W: usize = 8 * TITLE_COLS + 16;
+const SWITCHER_H: usize = 16 * SWITCHER_ROWS + 16;
+
+struct Entry {
+    layer_id: LayerId,
+    title: &'static str,
+    /// 閉じるボタンが押されたときにメインループから呼ぶ。引数はレイヤのID
+    on_close: fn(usize),
+}
+
+/// フォーカスの履歴とAlt+Tabによる切り替えを管理する
+/// ステータスバーやマウスカーソルなど、register_windowされていないレイヤはフォーカスの対象にならない
+pub struct FocusManager {
+    /// 最近フォーカスされた順。先頭が現在フォーカスされているウィンドウ
+    mru: Vec<Entry>,
+    /// Alt+Tabで選択中のmru上の位置。Altが離されるまでSome
+    selecting: Option<usize>,
+    prev_buttons: u8,
+    switcher: LayerHandle,
+}
+
+static FOCUS: LazyInit<FocusManager> = LazyInit::new();
+/// 閉じられたウィンドウに届いたために捨てたクリックの数
+static DROPPED_CLICKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn dropped_clicks() -> usize {
+    DROPPED_CLICKS.load(Ordering::Relaxed)
+}
+
+/// 切り替え画面のウィンドウを作り、Alt+Tabを登録する
+pub fn init_focus() {
+    let switcher = with_layers(|l| {
+        let mut win = Window::new(SWITCHER_W, SWITCHER_H, Some(palette::TRANSPARENT_KEY));
+        win.set_transparent_color(Some(palette::TRANSPARENT_KEY));
+        l.new_layer(win, Placement::Hidden)
+    });
+    FOCUS.lock().init(FocusManager {
+        mru: Vec::new(),
+        selecting: None,
+        prev_buttons: 0,
+        switcher,
+    });
+    shortcut::register_global(Mods::ALT, KEY_TAB, "focus", on_switch_key, 0).expect("focus: Alt+Tab");
+    shortcut::register_global(Mods::ALT.with(Mods::SHIFT), KEY_TAB, "focus", on_switch_key, 1).expect("focus: Alt+Shift+Tab");
+}
+
+/// フォーカスの対象となるウィンドウを登録し、フォーカスする
+pub fn register_window(layer_id: LayerId, title: &'static str) {
+    let mut focus = FOCUS.lock();
+    focus.mru.insert(0, Entry { layer_id, title, on_close: hide_window });
+    focus.raise(layer_id);
+}
+
+/// 閉じるボタンが押されたときの処理を差し替える。既定ではhide_windowで隠す
+pub fn set_close_handler(layer_id: LayerId, on_close: fn(usize)) {
+    if let Some(e) = FOCUS.lock().mru.iter_mut().find(|e| e.layer_id == layer_id) {
+        e.on_close = on_close;
+    }
+}
+
+/// ウィンドウを画面から隠し、フォーカスの対象から外す。レイヤは残る
+pub fn hide_window(layer_id: LayerId) {
+    unregister_window(layer_id);
+    with_layers(|l| l.hide(layer_id));
+}
+
+/// ウィンドウを閉じたときに呼ぶ。切り替え画面の表示中でもよい
+pub fn unregister_window(layer_id: LayerId) {
+    let mut focus = FOCUS.lock();
+    let Some(index) = focus.mru.iter().position(|e| e.layer_id == layer_id) else {
+        return;
+    };
+    focus.mru.remove(index);
+
+    if let Some(sel) = focus.selecting {
+        if focus.mru.len() < 2 {
+            focus.close_switcher();
+        } else {
+            let sel = if index < sel { sel - 1 } else { sel };
+            focus.selecting = Some(sel % focus.mru.len());
+            focus.render_switcher();
+        }
+    }
+}
+
+/// ウィンドウを閉じ、レイヤを取り除く。その後に届いたこのウィンドウ宛てのクリックは捨てられる
+pub fn close_window(layer_id: LayerId) {
+    unregister_window(layer_id);
+    shortcut::unregister_window(layer_id);
+    snap::forget(layer_id);
+    let _ = with_layers(|l| l.close_layer(layer_id));
+}
+
+/// インジケータなどのレイヤを、フォーカスされたウィンドウより常に上に置いて表示する。マウスカーソルはさらにその上に描かれる
+pub fn keep_on_top(layer_id: LayerId) {
+    let _ = with_layers(|l| l.set_always_on_top(layer_id, true).and_then(|_| l.raise_to_top(layer_id)));
+}
+
+/// ウィンドウにフォーカスし、最前面に上げる。
+/// ウィンドウが既に閉じられていればクリックは捨ててfalseを返す
+pub fn set_focus(layer_id: LayerId) -> bool {
+    FOCUS.lock().focus(layer_id)
+}
+
+pub fn focused() -> Option<LayerId> {
+    FOCUS.lock().mru.first().map(|e| e.layer_id)
+}
+
+/// マウスのレポートを受け取り、左クリックされたウィンドウにフォーカスする
+/// 閉じるボタンのクリックはフォーカスせず、そのウィンドウのon_closeを後で呼ぶ
+pub fn on_mouse(buttons: u8, pos: Vec2<i32>) {
+    let mut focus = FOCUS.lock();
+    let clicked = buttons & BUTTON_LEFT != 0 && focus.prev_buttons & BUTTON_LEFT == 0;
+    focus.prev_buttons = buttons;
+    if !clicked || focus.selecting.is_some() {
+        return;
+    }
+
+    let target = with_layers(|l| {
+        let id = l.find_layer(pos, |id| focus.mru.iter().any(|e| e.layer_id == id))?;
+        let win = l.layer(id)?.read();
+        let p = win.pos();
+        Some((id, win.hit_test((pos.x - p.x, pos.y - p.y).into())))
+    });
+    match target {
+        Some((layer_id, Some(Hit::CloseButton))) => {
+            // 閉じる処理はFOCUSを取り直すことがあるので、ロックを持っていない所で呼ぶ
+            let on_close = focus.mru.iter().find(|e| e.layer_id == layer_id).map(|e| e.on_close);
+            if on_close.is_some_and(|f| deferred::defer(f, layer_id).is_err()) {
+                warn!("focus: deferred queue is full, close of layer {layer_id} dropped");
+            }
+        }
+        Some((layer_id, _)) => {
+            focus.focus(layer_id);
+        }
+        None => (),
+    }
+}
+
+/// Alt+Tab / Alt+Shift+Tab のショートカット。argが1なら逆順に選択を進める
+fn on_switch_key(backward: usize) {
+    FOCUS.lock().cycle(backward != 0);
+}
+
+/// キーボードのレポートを受け取り、Alt+Tabで選択中にAltが離されたら確定する
+pub fn on_key_report(alt: bool) {
+    let mut focus = FOCUS.lock();
+    if alt {
+        return;
+    }
+    if let Some(sel) = focus.selecting {
+        focus.close_switcher();
+        if let Some(layer_id) = focus.mru.get(sel).map(|e| e.layer_id) {
+            focus.focus(layer_id);
+        }
+    }
+}
+
+impl FocusManager {
+    fn focus(&mut self, layer_id: LayerId) -> bool {
+        if with_layers(|l| l.layer(layer_id).is_none()) {
+            DROPPED_CLICKS.fetch_add(1, Ordering::Relaxed);
+            println!("focus: layer {layer_id} is closed, click dropped");
+            return false;
+        }
+        let Some(index) = self.mru.iter().position(|e| e.layer_id == layer_id) else {
+            return false;
+        };
+        let entry = self.mru.remove(index);
+        self.mru.insert(0, entry);
+        self.raise(layer_id);
+        true
+    }
+
+    /// ウィンドウの中で一番上に上げる。カーソルとオーバーレイは常に手前にいるので、その下になる
+    fn raise(&self, layer_id: LayerId) {
+        let _ = with_layers(|l| l.raise_to_top(layer_id));
+    }
+
+    fn cycle(&mut self, backward: bool) {
+        let n = self.mru.len();
+        if n < 2 {
+            return;
+        }
+        let cur = self.selecting.unwrap_or(0);
+        let next = if backward { (cur + n - 1) % n } else { (cur + 1) % n };
+        let opening = self.selecting.is_none();
+        self.selecting = Some(next);
+        self.render_switcher();
+        if opening {
+            self.raise(self.switcher.layer_id());
+        }
+    }
+
+    fn close_switcher(&mut self) {
+        self.selecting = None;
+        let layer_id = self.switcher.layer_id();
+        with_layers(|l| l.hide(layer_id));
+    }
+
+    fn render_switcher(&self) {
+        let Some(sel) = self.selecting else {
+            return;
+        };
+        let rows = self.mru.len().min(SWITCHER_ROWS);
+        let (width, height) = with_layers(|l| l.resolution());
+        let box_h = 16 * rows + 16;
+
+        let pos = ((width as i32 - SWITCHER_W as i32) / 2, (height as i32 - box_h as i32) / 2).into();
+        let _ = with_layers(|l| l.move_to(self.switcher.layer_id(), pos));
+        let window = self.switcher.window().read();
+        window.buffer().write_with(|back| {
+            back.fill_rect((0, 0).into(), (SWITCHER_W as u32, SWITCHER_H as u32).into(), palette::TRANSPARENT_KEY);
+            back.fill_rect((0, 0).into(), (SWITCHER_W as u32, box_h as u32).into(), palette::WINDOW_GRAY);
+            // 選択中の項目が見えるように表示範囲をずらす
+            let first = (sel + 1).saturating_sub(rows);
+            for (row, entry) in self.mru.iter().skip(first).take(rows).enumerate() {
+                let y = 8 + 16 * row as u32;
+                let title = &entry.title.as_bytes()[..entry.title.len().min(TITLE_COLS)];
+                if first + row == sel {
+                    back.fill_rect((4, y as i32).into(), (SWITCHER_W as u32 - 8, 16).into(), palette::SELECTION_BG);
+                    write_string(back, 8, y, title, palette::contrast_text_color(palette::SELECTION_BG));
+                } else {
+                    write_string(back, 8, y, title, palette::contrast_text_color(palette::WINDOW_GRAY));
+                }
+            }
+        });
+        window.buffer().flush();
+    }
+}
+
+pub fn run_focus_tests() {
+    let hndl = with_layers(|l| l.new_layer(Window::new(16, 16, None), Placement::Hidden));
+    let layer_id = hndl.layer_id();
+    register_window(layer_id, "stale test");
+    assert!(focused() == Some(layer_id));
+
+    // ウィンドウ宛てのクリックがキューに残っている間に閉じる
+    let queued = [layer_id; 3];
+    let (dropped, stale) = (dropped_clicks(), stale_id_hits());
+    close_window(layer_id);
+    assert!(focused() != Some(layer_id));
+    for id in queued {
+        assert!(!set_focus(id));
+    }
+    assert!(dropped_clicks() == dropped + queued.len());
+    assert!(stale_id_hits() >= stale + queued.len());
+
+    // 閉じたレイヤへの操作は無視され、IDは使い回されない
+    with_layers(|l| {
+        assert!(l.layer(layer_id).is_none());
+        assert!(l.move_to(layer_id, (0, 0).into()) == Err(StaleLayerId(layer_id)));
+        assert!(l.raise_to_top(layer_id).is_err());
+        assert!(l.close_layer(layer_id).is_err());
+        assert!(l.find_layer((0, 0).into(), |id| id == layer_id).is_none());
+        l.draw();
+        let next = l.new_layer(Window::new(1, 1, None), Placement::Hidden);
+        assert!(next.layer_id() != layer_id);
+        let _ = l.close_layer(next.layer_id());
+    });
+    assert!(hndl.window().read().width() == 16);
+
+    // 枠のどこを押したか
+    let win = Window::new(200, 100, None);
+    let (bar, close) = (title_bar_rect(200), close_button_rect(200));
+    assert!(close.contained_by(&bar));
+    assert!(win.hit_test((bar.x1, bar.y1).into()) == Some(Hit::TitleBar));
+    assert!(win.hit_test((close.x1 - 1, bar.y2 - 1).into()) == Some(Hit::TitleBar));
+    assert!(win.hit_test((close.x1, close.y1).into()) == Some(Hit::CloseButton));
+    assert!(win.hit_test((close.x2 - 1, close.y2 - 1).into()) == Some(Hit::CloseButton));
+    assert!(win.hit_test((close.x2, close.y1).into()) == Some(Hit::TitleBar));
+    assert!(win.hit_test((0, 0).into()) == Some(Hit::Body) && win.hit_test((100, 50).into()) == Some(Hit::Body));
+    assert!(win.hit_test((-1, 0).into()).is_none() && win.hit_test((200, 0).into()).is_none() && win.hit_test((0, 100).into()).is_none());
+
+    // 透過色の点は下のレイヤに届く
+    let (w, h) = with_layers(|l| l.resolution());
+    let origin = Vec2::new(w as i32 - 40, h as i32 - 40);
+    let mut bottom = Window::new(32, 32, Some(palette::WINDOW_GRAY));
+    bottom.move_to(origin);
+    let mut top = Window::new(32, 32, Some(palette::TRANSPARENT_KEY));
+    top.set_transparent_color(Some(palette::TRANSPARENT_KEY));
+    top.move_to(origin);
+    top.buffer().write_with(|back| back.fill_rect((0, 0).into(), (8, 8).into(), palette::WINDOW_GRAY));
+    top.buffer().flush();
+    let (bottom, top) = with_layers(|l| (l.new_layer(bottom, Placement::TopMost), l.new_layer(top, Placement::TopMost)));
+    with_layers(|l| {
+        assert!(l.layer_at(origin + (2, 2).into()) == Some(top.layer_id()));
+        assert!(l.layer_at(origin + (20, 20).into()) == Some(bottom.layer_id()));
+        assert!(l.layer_at(origin + (40, 40).into()).is_none());
+    });
+
+    // 隠したウィンドウはフォーカスから外れるが、レイヤは残る
+    register_window(bottom.layer_id(), "hide test");
+    assert!(focused() == Some(bottom.layer_id()));
+    hide_window(bottom.layer_id());
+    assert!(focused() != Some(bottom.layer_id()));
+    with_layers(|l| {
+        assert!(l.height_of(bottom.layer_id()).is_none() && l.layer(bottom.layer_id()).is_some());
+        // 隠したウィンドウの下の壁紙に届く
+        assert!(l.layer_at(origin + (20, 20).into()) == super::desktop::layer_id());
+        let _ = l.close_layer(top.layer_id());
+        let _ = l.close_layer(bottom.layer_id());
+    });
+}
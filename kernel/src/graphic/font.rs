@@ -1,22 +1,130 @@
+use core::{ptr::null_mut, slice::from_raw_parts, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
+
 use x86_64::instructions::interrupts::without_interrupts;
 
-use super::graphics::{PixelColor, PixelWriter};
+use crate::{initrd, println, warn};
+
+use super::graphics::{PixelColor, PixelWriter, Rect, Vec2};
+
+/// 1文字の大きさ
+pub const GLYPH_W: u32 = 8;
+pub const GLYPH_H: u32 = 16;
+
+/// フォントに無い文字の代わりに描く枠
+const MISSING_GLYPH: [u8; 16] = [
+    0b00000000,
+    0b00000000,
+    0b01111110,
+    0b01000010,
+    0b01000010,
+    0b01000010,
+    0b01000010,
+    0b01000010,
+    0b01000010,
+    0b01000010,
+    0b01000010,
+    0b01000010,
+    0b01111110,
+    0b00000000,
+    0b00000000,
+    0b00000000,
+];
+
+/// initrdから読んだPSFフォントの字形。数が0なら組み込みのFONTSを使う
+static PSF_GLYPHS: AtomicPtr<[u8; 16]> = AtomicPtr::new(null_mut());
+static PSF_GLYPH_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// initrdの中のフォントの名前
+const INITRD_FONT: &str = "font.psf";
 
-pub fn write_ascii(graphics: &mut impl PixelWriter, x: u32, y: u32, c: char, color: PixelColor) {
-    if c as usize >= FONTS.len() {return;}
+/// cの字形。フォントに無ければMISSING_GLYPH
+fn glyph(c: char) -> &'static [u8; 16] {
+    fonts().get(c as usize).unwrap_or(&MISSING_GLYPH)
+}
+
+fn fonts() -> &'static [[u8; 16]] {
+    match PSF_GLYPH_COUNT.load(Ordering::Acquire) {
+        0 => FONTS,
+        n => unsafe { from_raw_parts(PSF_GLYPHS.load(Ordering::Relaxed), n) },
+    }
+}
 
-    for dy in 0..16u32 {
-        for dx in 0..8u32 {
-            if ((FONTS[c as usize][dy as usize] << dx) & 0b10000000) != 0 {
-                graphics.write(((x+dx) as i32, (y+dy) as i32).into(), color);
+/// PSF1かPSF2のフォントの字形。描けるのは8x16だけなので、それ以外の大きさならNone
+/// Unicodeの表は読まず、字形の番号をそのまま文字コードとみなす
+fn parse_psf(file: &[u8]) -> Option<&[[u8; 16]]> {
+    let (offset, count) = if file.starts_with(&[0x36, 0x04]) {
+        let (mode, charsize) = (*file.get(2)?, *file.get(3)?);
+        if charsize != 16 {
+            return None;
+        }
+        (4, if mode & 0x01 != 0 { 512 } else { 256 })
+    } else if file.starts_with(&[0x72, 0xb5, 0x4a, 0x86]) {
+        let word = |i: usize| Some(u32::from_le_bytes(file.get(4 * i..4 * i + 4)?.try_into().ok()?) as usize);
+        let (header_size, count, charsize, height, width) = (word(2)?, word(4)?, word(5)?, word(6)?, word(7)?);
+        if charsize != 16 || height != 16 || width != 8 {
+            return None;
+        }
+        (header_size, count)
+    } else {
+        return None;
+    };
+    let glyphs = file.get(offset..offset.checked_add(count.checked_mul(16)?)?)?;
+    Some(unsafe { from_raw_parts(glyphs.as_ptr() as *const [u8; 16], count) })
+}
+
+/// initrdにfont.psfがあれば、組み込みのフォントの代わりに使う
+pub fn load_initrd_font() {
+    let Some(file) = initrd::find_file(INITRD_FONT) else {
+        return;
+    };
+    match parse_psf(file).filter(|glyphs| !glyphs.is_empty()) {
+        Some(glyphs) => {
+            PSF_GLYPHS.store(glyphs.as_ptr() as *mut [u8; 16], Ordering::Relaxed);
+            PSF_GLYPH_COUNT.store(glyphs.len(), Ordering::Release);
+            println!("font: using {} glyphs from initrd {}", glyphs.len(), INITRD_FONT);
+        }
+        None => warn!("font: initrd {} is not an 8x16 PSF font, keeping the built-in font", INITRD_FONT),
+    }
+}
+
+/// 端にかかる文字は、はみ出した部分を書かない
+pub fn write_ascii(graphics: &mut impl PixelWriter, x: u32, y: u32, c: char, color: impl Into<PixelColor>) {
+    let color = color.into();
+    let glyph = glyph(c);
+    let pos = Vec2::new(x.min(i32::MAX as u32) as i32, y.min(i32::MAX as u32) as i32);
+    let clip = Rect::from_pos_size(pos, Vec2::new(GLYPH_W, GLYPH_H)).clamp_to(&graphics.bounds());
+
+    for py in clip.y1..clip.y2 {
+        let row = glyph[(py - pos.y) as usize];
+        for px in clip.x1..clip.x2 {
+            if ((row << (px - pos.x)) & 0b10000000) != 0 {
+                graphics.write((px, py).into(), color);
             }
         }
     }
 }
 
-pub fn write_string(graphics: &mut impl PixelWriter, x: u32, y: u32, str: &[u8], color: PixelColor) {
-    for i in 0u32..str.len() as u32 {
-        write_ascii(graphics, x + 8 * i, y, str[i as usize] as char, color);
+/// 改行で次の行の先頭(x)に戻る。行の高さは文字の高さ
+pub fn write_string(graphics: &mut impl PixelWriter, x: u32, y: u32, str: &[u8], color: impl Into<PixelColor>) {
+    write_lines(graphics, x, y, str, color, GLYPH_H);
+}
+
+/// write_stringと同じだが、改行でline_heightだけ下に進む
+pub fn write_lines(graphics: &mut impl PixelWriter, x: u32, y: u32, str: &[u8], color: impl Into<PixelColor>, line_height: u32) {
+    let color = color.into();
+    let bounds = graphics.bounds();
+    let (right, bottom) = (bounds.x2.max(0) as u32, bounds.y2.max(0) as u32);
+    for (row, line) in str.split(|c| *c == b'\n').enumerate() {
+        let y = y.saturating_add(line_height.saturating_mul(row as u32));
+        if y >= bottom {
+            break;
+        }
+        for (col, c) in line.iter().enumerate() {
+            let x = x.saturating_add(GLYPH_W.saturating_mul(col as u32));
+            if x >= right {
+                break;
+            }
+            write_ascii(graphics, x, y, *c as char, color);
+        }
     }
 }
 
@@ -4694,4 +4802,47 @@ const FONTS: &[[u8;16]] = &[
 0b00000000,
 0b00000000,
 ],
-];
\ No newline at end of file
+];
+pub fn run_font_tests() {
+    use super::{frame_buffer::{FrameBuffer, PixelFormat}, palette};
+    let lit = |fb: &FrameBuffer, x0: usize, y0: usize| {
+        (y0..y0 + GLYPH_H as usize).any(|y| (x0..x0 + GLYPH_W as usize).any(|x| fb.color_at(x, y) == palette::WHITE))
+    };
+
+    // 数字や記号も字形がある。空白は何も描かない
+    for c in "0123456789!#%&()*+,-./:;<=>?@[]{}|~".chars() {
+        assert!(glyph(c).iter().any(|row| *row != 0) && glyph(c) != &MISSING_GLYPH);
+    }
+    assert!(glyph(' ').iter().all(|row| *row == 0));
+    // フォントの外の文字は枠になる
+    assert!(glyph('\u{3042}') == &MISSING_GLYPH);
+
+    // PSF1は256か512字、PSF2はヘッダの大きさと字数を読む。8x16でなければ使わない
+    let mut psf1 = [0u8; 4 + 256 * 16];
+    psf1[..4].copy_from_slice(&[0x36, 0x04, 0, 16]);
+    psf1[4 + b'A' as usize * 16] = 0xff;
+    let glyphs = parse_psf(&psf1).unwrap();
+    assert!(glyphs.len() == 256 && glyphs[b'A' as usize][0] == 0xff);
+    psf1[2] = 0x01;
+    assert!(parse_psf(&psf1).is_none());
+    psf1[2..4].copy_from_slice(&[0, 14]);
+    assert!(parse_psf(&psf1).is_none());
+
+    let mut psf2 = [0u8; 36 + 2 * 16];
+    for (i, v) in [0x864ab572u32, 0, 36, 0, 2, 16, 16, 8].into_iter().enumerate() {
+        psf2[4 * i..4 * i + 4].copy_from_slice(&v.to_le_bytes());
+    }
+    psf2[36 + 16] = 0x81;
+    let glyphs = parse_psf(&psf2).unwrap();
+    assert!(glyphs.len() == 2 && glyphs[1][0] == 0x81);
+    assert!(parse_psf(&psf2[..36 + 16]).is_none());
+    psf2[7 * 4] = 9;
+    assert!(parse_psf(&psf2).is_none());
+    assert!(parse_psf(b"not a font").is_none());
+
+    // 改行で次の行の先頭に戻る
+    let mut fb = FrameBuffer::with_layout(8 * 4, 16 * 4, 8 * 4, PixelFormat::PixelBGRResv8BitPerColor);
+    fb.fill_rect((0, 0).into(), (8 * 4, 16 * 4).into(), palette::BLACK);
+    write_lines(&mut fb, 8, 0, b"A\nB", palette::WHITE, 20);
+    assert!(lit(&fb, 8, 0) && lit(&fb, 8, 20) && !lit(&fb, 16, 0) && !lit(&fb, 16, 20));
+}
@@ -1,9 +1,9 @@
-use core::slice::from_raw_parts_mut;
+use core::{ops::Range, slice::{from_raw_parts_mut, ChunksExact}};
 
 use alloc::vec::Vec;
 
 use crate::{
-    graphic::graphics::{PixelWriter, Rect, Vec2},
+    graphic::graphics::{blend_channel, Color, PixelWriter, Rect, Vec2},
     memory_manager::Mutex,
 };
 
@@ -23,29 +23,65 @@ impl PixelFormat {
         }
     }
 
-    pub fn write(self, color: (u8, u8, u8), buffer: &mut [u8]) {
+    pub fn write(self, color: Color, buffer: &mut [u8]) {
         match self {
             PixelFormat::PixelBGRResv8BitPerColor => {
-                buffer[0] = color.2;
-                buffer[1] = color.1;
-                buffer[2] = color.0;
+                buffer[0] = color.b;
+                buffer[1] = color.g;
+                buffer[2] = color.r;
             }
             PixelFormat::PixelRGBResv8BitPerColor => {
-                buffer[0] = color.0;
-                buffer[1] = color.1;
-                buffer[2] = color.2;
+                buffer[0] = color.r;
+                buffer[1] = color.g;
+                buffer[2] = color.b;
             }
         }
     }
 
-    pub fn raw_to_color(self, raw: &[u8]) -> (u8,u8,u8) {
+    pub fn raw_to_color(self, raw: &[u8]) -> Color {
         match self {
-            PixelFormat::PixelBGRResv8BitPerColor => (raw[2], raw[1], raw[0]),
-            PixelFormat::PixelRGBResv8BitPerColor => (raw[0], raw[1], raw[2])
+            PixelFormat::PixelBGRResv8BitPerColor => Color::new(raw[2], raw[1], raw[0]),
+            PixelFormat::PixelRGBResv8BitPerColor => Color::new(raw[0], raw[1], raw[2])
         }
     }
+
+    /// 1ピクセルの中のr, g, bの位置
+    fn channel_offsets(self) -> [usize; 3] {
+        match self {
+            PixelFormat::PixelBGRResv8BitPerColor => [2, 1, 0],
+            PixelFormat::PixelRGBResv8BitPerColor => [0, 1, 2],
+        }
+    }
+}
+
+/// 詰めて並んだピクセルを左から色にする。ピクセル形式の判定は作るときに1度だけ行う
+pub struct RowPixels<'a> {
+    pixels: ChunksExact<'a, u8>,
+    offsets: [usize; 3],
+}
+
+impl<'a> RowPixels<'a> {
+    pub fn new(raw: &'a [u8], format: PixelFormat) -> Self {
+        Self { pixels: raw.chunks_exact(format.bytes_per_pixel()), offsets: format.channel_offsets() }
+    }
+}
+
+impl Iterator for RowPixels<'_> {
+    type Item = Color;
+
+    #[inline]
+    fn next(&mut self) -> Option<Color> {
+        let [r, g, b] = self.offsets;
+        self.pixels.next().map(|px| Color::new(px[r], px[g], px[b]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.pixels.size_hint()
+    }
 }
 
+impl ExactSizeIterator for RowPixels<'_> {}
+
 enum FrameBufferData {
     Vram(&'static mut [u8]),
     Shadow(Vec<u8>),
@@ -84,6 +120,9 @@ impl FrameBufferConf {
 pub struct FrameBuffer {
     data: FrameBufferData,
     conf: FrameBufferConf,
+    /// track_writesしていれば、書き換えた範囲をwrittenに足していく
+    tracking: bool,
+    written: Option<Rect>,
 }
 
 static DEFAULT_PIXEL_FORMAT: Mutex<Option<PixelFormat>> = Mutex::new(None);
@@ -104,20 +143,45 @@ impl FrameBuffer {
                 vertical_resolution: raw.vertical_resolution,
                 pixel_format: raw.pixel_format,
             },
+            tracking: false,
+            written: None,
         }
     }
 
     pub fn new(width: usize, height: usize) -> Self {
         let format = DEFAULT_PIXEL_FORMAT.lock().unwrap();
-        let data = vec![0u8; width * height * format.bytes_per_pixel()];
+        Self::with_layout(width, height, width, format)
+    }
+
+    /// 行の長さとピクセル形式を指定してメモリ上に作る
+    pub fn with_layout(width: usize, height: usize, pixels_per_scanline: usize, format: PixelFormat) -> Self {
+        let data = vec![0u8; pixels_per_scanline * height * format.bytes_per_pixel()];
         FrameBuffer {
             data: FrameBufferData::Shadow(data),
             conf: FrameBufferConf {
-                pixels_per_scanline: width as u32,
+                pixels_per_scanline: pixels_per_scanline as u32,
                 horizontal_resolution: width as u32,
                 vertical_resolution: height as u32,
                 pixel_format: format,
             },
+            tracking: false,
+            written: None,
+        }
+    }
+
+    /// これから書き換えた範囲を記録する。BufferedCanvasのbackのように、変わった所だけを写したいものに使う
+    pub fn track_writes(&mut self) {
+        self.tracking = true;
+    }
+
+    /// 前に呼んでから書き換えた範囲を返して忘れる。何も書いていないか、記録していなければNone
+    pub fn take_written(&mut self) -> Option<Rect> {
+        self.written.take()
+    }
+
+    fn mark_written(&mut self, rect: Rect) {
+        if self.tracking && !rect.is_empty() {
+            self.written = Some(self.written.map_or(rect, |w| w.union(&rect)));
         }
     }
     pub fn pixels_per_scanline(&self) -> u32 {
@@ -142,21 +206,15 @@ impl FrameBuffer {
             self.conf.horizontal_resolution as i32,
             self.conf.vertical_resolution as i32,
         );
-        let rect_from = Rect::from_wh(
-            pos.x,
-            pos.y,
-            from.conf.horizontal_resolution as i32,
-            from.conf.vertical_resolution as i32,
-        );
+        self.copy_rect(pos, from, rect_buf);
+    }
 
-        let modified_rect = match rect_buf.intersection(&rect_from) {
-            None => {
-                return;
-            }
-            Some(r) => r,
+    /// fromをposに置いたときに、clip(このFrameBufferの座標)に入る部分だけをコピーする
+    pub fn copy_rect(&mut self, pos: Vec2<i32>, from: &FrameBuffer, clip: Rect) {
+        let Some((modified_rect, copied_rect)) = self.copy_area(pos, from, clip) else {
+            return;
         };
-
-        let copied_rect = modified_rect.move_relative(-pos.x, -pos.y);
+        self.mark_written(modified_rect);
 
         let buf_to = self.data.get_mut();
         let buf_from = from.data.get();
@@ -176,8 +234,50 @@ impl FrameBuffer {
         }
     }
 
+    /// copy_rectと同じだが、fromの全体を不透明度alphaで重ねる。255ならcopy_rectと同じ速い経路を通る
+    pub fn blend_rect(&mut self, pos: Vec2<i32>, from: &FrameBuffer, clip: Rect, alpha: u8) {
+        match alpha {
+            255 => return self.copy_rect(pos, from, clip),
+            0 => return,
+            _ => {}
+        }
+        let Some((modified_rect, copied_rect)) = self.copy_area(pos, from, clip) else {
+            return;
+        };
+        self.mark_written(modified_rect);
+        let same_format = self.conf.pixel_format.channel_offsets() == from.conf.pixel_format.channel_offsets();
+        for (y_to, y_from) in (modified_rect.y1..modified_rect.y2).zip(copied_rect.y1..copied_rect.y2) {
+            if same_format {
+                // 並びが同じならバイトごとに混ぜればよい。予約バイトも混ざるが使われない
+                let (start, end) = (self.conf.to_index(modified_rect.x1, y_to), self.conf.to_index(modified_rect.x2, y_to));
+                let src = from.row(y_from as usize, copied_rect.x1 as usize..copied_rect.x2 as usize);
+                for (d, s) in self.data.get_mut()[start..end].iter_mut().zip(src) {
+                    *d = blend_channel(*s, *d, alpha);
+                }
+            } else {
+                let pixels = from.row_pixels(y_from as usize, copied_rect.x1 as usize..copied_rect.x2 as usize);
+                for (x, c) in (modified_rect.x1..).zip(pixels) {
+                    self.write(Vec2::new(x, y_to), c.with_alpha(alpha));
+                }
+            }
+        }
+    }
+
+    /// fromをposに置いたとき、clipとこのFrameBufferに入る範囲。(このFrameBufferの座標, fromの座標)
+    fn copy_area(&self, pos: Vec2<i32>, from: &FrameBuffer, clip: Rect) -> Option<(Rect, Rect)> {
+        let modified_rect = self.bounds().intersection(&clip)?.intersection(&Rect::from_wh(
+            pos.x,
+            pos.y,
+            from.conf.horizontal_resolution as i32,
+            from.conf.vertical_resolution as i32,
+        ))?;
+        Some((modified_rect, modified_rect.move_relative(-pos.x, -pos.y)))
+    }
+
     pub fn move_rect(&mut self, to: Vec2<i32>, rect: Rect) {
         assert!(rect.contained_by(&Rect::from_wh(0, 0, self.conf.horizontal_resolution as i32, self.conf.vertical_resolution as i32)));
+        // 元の範囲は、移した先と重ならない部分も書き換えない
+        self.mark_written(Rect::from_pos_size(to, rect.size()));
         let buf = self.data.get_mut();
 
         if to.y <= rect.y1 {
@@ -207,7 +307,54 @@ impl FrameBuffer {
         }
     }
 
-    pub fn color_at(&self, x: usize, y: usize) -> (u8,u8,u8) {
+    /// rectの範囲のピクセルを、このFrameBufferのピクセル形式のまま詰めてコピーする
+    pub fn read_raw(&self, rect: Rect) -> Vec<u8> {
+        let bpp = self.pixel_format().bytes_per_pixel();
+        let mut out = Vec::with_capacity(((rect.x2 - rect.x1) * (rect.y2 - rect.y1)) as usize * bpp);
+        for y in rect.y1..rect.y2 {
+            out.extend_from_slice(self.row(y as usize, rect.x1 as usize..rect.x2 as usize));
+        }
+        out
+    }
+
+    /// y行目のx_rangeのピクセルの、コピーしない生のバイト列。行の詰め物は含まない
+    /// Vramでも同じように普通に読む。合成でVRAMを読むことはないので、volatileにはしていない
+    pub fn row(&self, y: usize, x_range: Range<usize>) -> &[u8] {
+        assert!(y < self.conf.vertical_resolution as usize);
+        assert!(x_range.start <= x_range.end && x_range.end <= self.conf.horizontal_resolution as usize);
+        let start = self.conf.to_index(x_range.start as i32, y as i32);
+        let end = self.conf.to_index(x_range.end as i32, y as i32);
+        &self.data.get()[start..end]
+    }
+
+    /// y行目のx_rangeのピクセルの色を左から順に返す
+    pub fn row_pixels(&self, y: usize, x_range: Range<usize>) -> RowPixels<'_> {
+        RowPixels::new(self.row(y, x_range), self.pixel_format())
+    }
+
+    /// 1ピクセル3バイト(R, G, B)を左上から詰めた幅widthの画像を、左上がposに来るように書く。
+    /// 高さはrgbの長さから決まり、半端な行は書かない。はみ出した部分も書かない
+    pub fn write_rgb(&mut self, pos: Vec2<i32>, width: usize, rgb: &[u8]) {
+        let height = rgb.len().checked_div(width * 3).unwrap_or(0);
+        let size = Vec2::new(width.min(u32::MAX as usize) as u32, height.min(u32::MAX as usize) as u32);
+        let rect = Rect::from_pos_size(pos, size).clamp_to(&self.bounds());
+        self.mark_written(rect);
+        let bpp = self.conf.pixel_format.bytes_per_pixel();
+        let [r, g, b] = self.conf.pixel_format.channel_offsets();
+        let x_from = (rect.x1 as i64 - pos.x as i64) as usize;
+        for y in rect.y1..rect.y2 {
+            let src_start = ((y as i64 - pos.y as i64) as usize * width + x_from) * 3;
+            let src = &rgb[src_start..src_start + rect.size().x as usize * 3];
+            let (start, end) = (self.conf.to_index(rect.x1, y), self.conf.to_index(rect.x2, y));
+            for (dst, s) in self.data.get_mut()[start..end].chunks_exact_mut(bpp).zip(src.chunks_exact(3)) {
+                dst[r] = s[0];
+                dst[g] = s[1];
+                dst[b] = s[2];
+            }
+        }
+    }
+
+    pub fn color_at(&self, x: usize, y: usize) -> Color {
         let index = self.conf.to_index(x as i32, y as i32);
         let len = self.pixel_format().bytes_per_pixel();
         self.pixel_format().raw_to_color(&self.data.get()[index..index+len])
@@ -216,6 +363,12 @@ impl FrameBuffer {
 
 impl PixelWriter for FrameBuffer {
     fn write(&mut self, pos: crate::graphic::graphics::Vec2<i32>, color: crate::graphic::graphics::PixelColor) {
+        let color = match color.a {
+            255 => color,
+            0 => return,
+            _ => color.over(self.color_at(pos.x as usize, pos.y as usize)),
+        };
+        self.mark_written(Rect::from_wh(pos.x, pos.y, 1, 1));
         let i_pixel: usize =
             self.conf.pixels_per_scanline as usize * pos.y as usize + pos.x as usize;
         match &mut self.data {
@@ -231,6 +384,112 @@ impl PixelWriter for FrameBuffer {
             ),
         }
     }
+
+    /// 不透明な色は1ピクセル分のバイト列を作り、行ごとに並べて埋める。画面全体をwriteで1つずつ塗ると遅い
+    fn fill_rect(&mut self, pos: Vec2<i32>, size: Vec2<u32>, c: impl Into<crate::graphic::graphics::PixelColor>) {
+        let c = c.into();
+        let rect = Rect::from_pos_size(pos, size).clamp_to(&self.bounds());
+        if !c.is_opaque() {
+            for y in rect.y1..rect.y2 {
+                for x in rect.x1..rect.x2 {
+                    self.write(Vec2::new(x, y), c);
+                }
+            }
+            return;
+        }
+        self.mark_written(rect);
+        let bpp = self.conf.pixel_format.bytes_per_pixel();
+        let mut pixel = [0u8; 4];
+        self.conf.pixel_format.write(c, &mut pixel);
+        for y in rect.y1..rect.y2 {
+            let (start, end) = (self.conf.to_index(rect.x1, y), self.conf.to_index(rect.x2, y));
+            for dst in self.data.get_mut()[start..end].chunks_exact_mut(bpp) {
+                dst.copy_from_slice(&pixel[..bpp]);
+            }
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::from_wh(0, 0, self.conf.horizontal_resolution as i32, self.conf.vertical_resolution as i32)
+    }
+}
+
+pub fn run_frame_buffer_tests() {
+    let colors = |y: usize| [Color::new(1, 2, 3), Color::new(4, 5, 6 + y as u8), Color::new(7, 8, 9)];
+    for format in [PixelFormat::PixelRGBResv8BitPerColor, PixelFormat::PixelBGRResv8BitPerColor] {
+        // 3ピクセル幅で、行は4ピクセル分ある
+        let mut fb = FrameBuffer::with_layout(3, 2, 4, format);
+        for y in 0..2 {
+            for (x, c) in colors(y).iter().enumerate() {
+                fb.write((x as i32, y as i32).into(), *c);
+            }
+        }
+        // 行の詰め物を飛ばし、行の境界をまたがない
+        assert!(fb.row(0, 0..3).len() == 12 && fb.row(1, 1..3).len() == 8 && fb.row(1, 3..3).is_empty());
+        let expected_first = match format {
+            PixelFormat::PixelRGBResv8BitPerColor => [1, 2, 3],
+            PixelFormat::PixelBGRResv8BitPerColor => [3, 2, 1],
+        };
+        assert!(fb.row(0, 0..1)[..3] == expected_first);
+        for y in 0..2 {
+            assert!(fb.row_pixels(y, 0..3).eq(colors(y)));
+            assert!(fb.row_pixels(y, 2..3).eq([colors(y)[2]]));
+            assert!(fb.row_pixels(y, 1..3).len() == 2);
+            assert!((0..3).all(|x| fb.color_at(x, y) == colors(y)[x]));
+        }
+        assert!(fb.read_raw(Rect::from_wh(1, 0, 2, 2)).len() == 2 * 2 * 4);
+
+        // 不透明でない色は今の色に重なり、透明な色は何も変えない
+        let (white, black) = (Color::gray(255), Color::gray(0));
+        fb.write((0, 0).into(), white);
+        fb.write((0, 0).into(), black.with_alpha(128));
+        fb.write((1, 0).into(), black.with_alpha(0));
+        assert!(fb.color_at(0, 0) == Color::gray(127) && fb.color_at(1, 0) == colors(0)[1]);
+
+        // 半透明に重ねる。255ならそのまま写し、0なら何もしない
+        let mut src = FrameBuffer::with_layout(2, 1, 2, format);
+        src.fill_rect((0, 0).into(), (2, 1).into(), white);
+        let mut dst = FrameBuffer::with_layout(3, 2, 4, format);
+        dst.fill_rect((0, 0).into(), (3, 2).into(), black);
+        dst.blend_rect((1, 1).into(), &src, dst.bounds(), 0);
+        assert!(dst.row_pixels(1, 0..3).all(|c| c == black));
+        dst.blend_rect((1, 1).into(), &src, dst.bounds(), 64);
+        assert!(dst.row_pixels(1, 0..3).eq([black, Color::gray(64), Color::gray(64)]) && dst.color_at(1, 0) == black);
+        dst.blend_rect((-1, 0).into(), &src, dst.bounds(), 255);
+        assert!(dst.color_at(0, 0) == white && dst.color_at(1, 0) == black);
+
+        // 行ごとに埋める速い経路は、1ピクセルずつ書いたのと同じ結果になり、行の詰め物には触れない
+        let red = Color::new(0xff, 0, 0);
+        let mut fast = FrameBuffer::with_layout(3, 2, 4, format);
+        fast.fill_rect((1, -1).into(), (5, 2).into(), red);
+        assert!(fast.row_pixels(0, 0..3).eq([black, red, red]) && fast.row_pixels(1, 0..3).all(|c| c == black));
+        assert!(fast.data.get()[3 * 4..4 * 4].iter().all(|b| *b == 0));
+        fast.fill_rect((0, 0).into(), (3, 2).into(), white.with_alpha(128));
+        assert!(fast.color_at(0, 1) == Color::gray(128));
+
+        // 3バイトずつのRGBを書く。はみ出した分と半端な行は捨てる
+        let rgb = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 99];
+        let mut img = FrameBuffer::with_layout(3, 2, 4, format);
+        img.write_rgb((2, 1).into(), 2, &rgb);
+        assert!(img.color_at(2, 1) == Color::new(1, 2, 3) && img.color_at(1, 1) == black);
+        img.write_rgb((-1, -1).into(), 2, &rgb);
+        assert!(img.color_at(0, 0) == Color::new(10, 11, 12) && img.color_at(1, 0) == black);
+        img.write_rgb((0, 0).into(), 0, &rgb);
+
+        // 記録するのはtrack_writesしてからで、はみ出した分や透明な色の点は含まない
+        img.write((0, 0).into(), red);
+        img.track_writes();
+        assert!(img.take_written().is_none());
+        img.write((2, 1).into(), red);
+        img.write((0, 0).into(), red.with_alpha(0));
+        img.fill_rect((-5, -5).into(), (2, 2).into(), red);
+        assert!(img.take_written() == Some(Rect::from_wh(2, 1, 1, 1)) && img.take_written().is_none());
+        img.fill_rect((1, -1).into(), (1, 2).into(), red);
+        img.move_rect((0, 1).into(), Rect::from_wh(0, 0, 2, 1));
+        assert!(img.take_written() == Some(Rect::from_wh(0, 0, 2, 2)));
+        img.copy_rect((2, 0).into(), &src, img.bounds());
+        assert!(img.take_written() == Some(Rect::from_wh(2, 0, 1, 1)));
+    }
 }
 
 #[repr(C)]
@@ -1,35 +1,237 @@
-use core::ops::Add;
+use core::ops::{Add, RangeInclusive};
 
-pub type PixelColor = (u8,u8,u8);
+/// RGBと不透明度の色。名前のついた色はpaletteにある
+/// aはストレートアルファ(r, g, bに掛けていない)で、255が不透明、0が完全に透明
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    /// 不透明な色
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b, a: 255 }
+    }
+
+    pub const fn gray(v: u8) -> Self {
+        Self::new(v, v, v)
+    }
+
+    pub const fn with_alpha(self, a: u8) -> Self {
+        Self { a, ..self }
+    }
+
+    pub const fn is_opaque(&self) -> bool {
+        self.a == 255
+    }
+
+    /// selfを不透明なdstの上に重ねた色。結果は不透明になる
+    pub const fn over(self, dst: Color) -> Color {
+        Self::new(blend_channel(self.r, dst.r, self.a), blend_channel(self.g, dst.g, self.a), blend_channel(self.b, dst.b, self.a))
+    }
+
+    /// 相対輝度 (ITU-R BT.601の重み, 0..=255)
+    pub const fn luminance(&self) -> u8 {
+        ((299 * self.r as u32 + 587 * self.g as u32 + 114 * self.b as u32) / 1000) as u8
+    }
+}
+
+/// srcを不透明度aでdstに重ねた値。整数演算だけで四捨五入する
+#[inline]
+pub const fn blend_channel(src: u8, dst: u8, a: u8) -> u8 {
+    ((src as u32 * a as u32 + dst as u32 * (255 - a as u32) + 127) / 255) as u8
+}
+
+/// 移行中のコードのために、(r, g, b)のタプルからも変換できるようにしておく。不透明になる
+impl From<(u8, u8, u8)> for Color {
+    fn from((r, g, b): (u8, u8, u8)) -> Self {
+        Self::new(r, g, b)
+    }
+}
+
+impl From<Color> for (u8, u8, u8) {
+    fn from(c: Color) -> Self {
+        (c.r, c.g, c.b)
+    }
+}
+
+pub type PixelColor = Color;
 
 pub trait PixelWriter {
+    /// posはbounds()の中でなければならない。範囲の外に書くかもしれないときはfill_rectなどを使う
+    /// colorが不透明でなければ今の色に重ね、aが0なら何もしない
     fn write(&mut self, pos: Vec2<i32>, color: PixelColor);
 
-    fn fill_rect(&mut self, pos: Vec2<i32>, size: Vec2<u32>, c: PixelColor) {
-        for x in 0..size.x as i32 {
-            for y in 0..size.y as i32 {
-                self.write(pos + Vec2::new(x,y), c);
+    /// 書き込める範囲。左上は(0, 0)
+    fn bounds(&self) -> Rect;
+
+    /// はみ出した部分は書かない。負の位置や大きさ0、完全に外にある矩形では何もしない
+    fn fill_rect(&mut self, pos: Vec2<i32>, size: Vec2<u32>, c: impl Into<PixelColor>) {
+        let rect = Rect::from_pos_size(pos, size).clamp_to(&self.bounds());
+        fill_clipped(self, rect, c.into());
+    }
+
+    /// patternの各行の上位ビットから左に並べ、1のビットをscale四方の正方形で描く。はみ出した部分は書かない
+    fn draw_bitpattern(&mut self, pos: Vec2<i32>, pattern: &[u64], c: impl Into<PixelColor>, scale: u32) {
+        let c = c.into();
+        let bounds = self.bounds();
+        let step = scale.min(i32::MAX as u32) as i32;
+        for (dy, row) in pattern.iter().enumerate() {
+            let y = pos.y.saturating_add((dy as i32).saturating_mul(step));
+            if y >= bounds.y2 {
+                break;
+            }
+            for dx in (0..64).filter(|dx| (row >> (63 - dx)) & 1 == 1) {
+                let x = pos.x.saturating_add(dx.saturating_mul(step));
+                let cell = Rect::from_pos_size(Vec2::new(x, y), Vec2::new(scale, scale)).clamp_to(&bounds);
+                fill_clipped(self, cell, c);
             }
         }
     }
 
-    fn draw_bitpattern(&mut self, pos: Vec2<i32>, pattern: &[u64], c: PixelColor, scale: u32) {
-        for dy in 0..pattern.len() {
-            for dx in 0usize..64 {
-                if (pattern[dy] >> (63-dx)) & 1 == 1 {
-                    self.fill_rect(
-                        &pos + &Vec2::new((scale*dx as u32) as i32, (scale * dy as u32) as i32),
-                        Vec2::new(scale, scale), 
-                        c
-                    );
-                }
+    /// p1からp2まで、両端を含む線を引く。長い方の軸で1画素ずつ進み、短い方はBresenhamと同じく四捨五入した位置に置く。
+    /// bounds()に入る範囲だけを進むので、遠くまで伸びる線でも画面の大きさ分しか数えない
+    fn draw_line(&mut self, p1: Vec2<i32>, p2: Vec2<i32>, c: impl Into<PixelColor>) {
+        let c = c.into();
+        let b = self.bounds();
+        let (dx, dy) = (p2.x as i64 - p1.x as i64, p2.y as i64 - p1.y as i64);
+        let x_major = dx.abs() >= dy.abs();
+        // major: 1画素ずつ進む軸、minor: もう一方の軸
+        let (major, minor, m0, n0, lo, hi) = if x_major {
+            (dx, dy, p1.x as i64, p1.y as i64, b.x1 as i64, b.x2 as i64)
+        } else {
+            (dy, dx, p1.y as i64, p1.x as i64, b.y1 as i64, b.y2 as i64)
+        };
+        let (len, rise) = (major.abs(), minor.abs() as i128);
+        // m0 + major.signum() * iがlo..hiに入るi
+        let (i_lo, i_hi) = if major >= 0 { (lo - m0, hi - 1 - m0) } else { (m0 - (hi - 1), m0 - lo) };
+        for i in i_lo.max(0)..=i_hi.min(len) {
+            let j = if len == 0 { 0 } else { ((2 * i as i128 * rise + len as i128) / (2 * len as i128)) as i64 };
+            let (m, n) = (m0 + major.signum() * i, n0 + minor.signum() * j);
+            let (x, y) = if x_major { (m, n) } else { (n, m) };
+            if (b.x1 as i64..b.x2 as i64).contains(&x) && (b.y1 as i64..b.y2 as i64).contains(&y) {
+                self.write(Vec2::new(x as i32, y as i32), c);
             }
         }
     }
+
+    /// rectの内側の縁を1画素の幅で描く。角の画素も1度だけ書く
+    fn draw_rect_outline(&mut self, rect: Rect, c: impl Into<PixelColor>) {
+        if rect.is_empty() {
+            return;
+        }
+        let c = c.into();
+        let size = rect.size();
+        self.fill_rect(Vec2::new(rect.x1, rect.y1), Vec2::new(size.x, 1), c);
+        if size.y > 1 {
+            self.fill_rect(Vec2::new(rect.x1, rect.y2 - 1), Vec2::new(size.x, 1), c);
+        }
+        if size.y > 2 {
+            let side = Vec2::new(1, size.y - 2);
+            self.fill_rect(Vec2::new(rect.x1, rect.y1 + 1), side, c);
+            if size.x > 1 {
+                self.fill_rect(Vec2::new(rect.x2 - 1, rect.y1 + 1), side, c);
+            }
+        }
+    }
+
+    /// centerからの距離の2乗がr*r以下の画素を塗る。rが0なら1画素
+    fn fill_circle(&mut self, center: Vec2<i32>, r: u32, c: impl Into<PixelColor>) {
+        let c = c.into();
+        let (cx, cy, r) = (center.x as i64, center.y as i64, r as i64);
+        for dy in rows_in_bounds(self.bounds(), cy, r) {
+            let half = isqrt(sq(r) - sq(dy));
+            fill_span(self, cx - half, cx + half, cy + dy, c);
+        }
+    }
+
+    /// fill_circleの半径rの円から半径r-1の円を除いた輪。fill_circleと同じ画素の縁になり、どの画素も1度だけ書く
+    fn draw_circle(&mut self, center: Vec2<i32>, r: u32, c: impl Into<PixelColor>) {
+        let c = c.into();
+        let (cx, cy, r) = (center.x as i64, center.y as i64, r as i64);
+        for dy in rows_in_bounds(self.bounds(), cy, r) {
+            let outer = isqrt(sq(r) - sq(dy));
+            if dy.abs() >= r {
+                fill_span(self, cx - outer, cx + outer, cy + dy, c);
+                continue;
+            }
+            let inner = isqrt(sq(r - 1) - sq(dy));
+            fill_span(self, cx - outer, cx - inner - 1, cy + dy, c);
+            fill_span(self, cx + inner + 1, cx + outer, cy + dy, c);
+        }
+    }
+
+    /// 角を半径radiusの四分円で丸めたrectを塗る。角の円がはみ出さないよう、radiusは(短い辺-1)/2までに切り詰める
+    fn fill_rounded_rect(&mut self, rect: Rect, radius: u32, c: impl Into<PixelColor>) {
+        if rect.is_empty() {
+            return;
+        }
+        let c = c.into();
+        let size = rect.size();
+        let r = radius.min((size.x - 1) / 2).min((size.y - 1) / 2) as i64;
+        let (top, bottom) = (rect.y1 as i64 + r, rect.y2 as i64 - 1 - r);
+        let b = self.bounds();
+        for y in (rect.y1.max(b.y1) as i64)..(rect.y2.min(b.y2) as i64) {
+            // 角の四分円の中心からの縦の距離。まっすぐな部分では0
+            let dy = (top - y).max(y - bottom).max(0);
+            let inset = if dy == 0 { 0 } else { r - isqrt(sq(r) - sq(dy)) };
+            fill_span(self, rect.x1 as i64 + inset, rect.x2 as i64 - 1 - inset, y, c);
+        }
+    }
+}
+
+/// y行目のx1..=x2を塗る。位置はi32に収まらなくてもよく、bounds()の外は書かない
+fn fill_span<W: PixelWriter + ?Sized>(w: &mut W, x1: i64, x2: i64, y: i64, c: PixelColor) {
+    let b = w.bounds();
+    let (x1, x2) = (x1.max(b.x1 as i64), x2.min(b.x2 as i64 - 1));
+    if x1 > x2 || y < b.y1 as i64 || y >= b.y2 as i64 {
+        return;
+    }
+    w.fill_rect(Vec2::new(x1 as i32, y as i32), Vec2::new((x2 - x1 + 1) as u32, 1), c);
+}
+
+/// 中心の行cyから上下にrまでの行のうち、boundsに入るもののcyからの差
+fn rows_in_bounds(b: Rect, cy: i64, r: i64) -> RangeInclusive<i64> {
+    (-r).max(b.y1 as i64 - cy)..=r.min(b.y2 as i64 - 1 - cy)
 }
 
+/// 半径はu32まで取るので、2乗はi128で計算する
+fn sq(v: i64) -> i128 {
+    v as i128 * v as i128
+}
+
+/// nの平方根の切り捨て。負なら0
+fn isqrt(n: i128) -> i64 {
+    if n <= 0 {
+        return 0;
+    }
+    // 二分探索で、x*x <= nを満たす最大のxを探す。u32の半径の2乗までなので、答えはi64に収まる
+    let (mut lo, mut hi) = (0i128, n.min(1 << 33) + 1);
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if mid * mid <= n {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    lo as i64
+}
 
-#[derive(Debug, Clone, Copy)]
+/// bounds()で切り詰め済みのrectを塗る
+fn fill_clipped<W: PixelWriter + ?Sized>(w: &mut W, rect: Rect, c: PixelColor) {
+    for y in rect.y1..rect.y2 {
+        for x in rect.x1..rect.x2 {
+            w.write(Vec2::new(x, y), c);
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Vec2<T>{
     pub x: T,
     pub y: T
@@ -41,6 +243,17 @@ impl<T> Vec2<T> {
     }
 }
 
+impl Vec2<i32> {
+    /// 溢れる成分はi32の端で止める
+    pub fn saturating_add(&self, rhs: Self) -> Self {
+        Self { x: self.x.saturating_add(rhs.x), y: self.y.saturating_add(rhs.y) }
+    }
+
+    pub fn checked_add(&self, rhs: Self) -> Option<Self> {
+        Some(Self { x: self.x.checked_add(rhs.x)?, y: self.y.checked_add(rhs.y)? })
+    }
+}
+
 impl<T: Ord+Copy> Vec2<T>{
     pub fn clamp(&self, min: Self, max: Self) -> Self {
         Self { x: self.x.max(min.x).min(max.x), y: self.y.max(min.y).min(max.y) }
@@ -74,7 +287,7 @@ impl<T> From<(T,T)> for Vec2<T> {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rect{
     pub x1: i32,
     pub y1: i32,
@@ -91,6 +304,28 @@ impl Rect {
         Self {x1, y1, x2: x1+w, y2: y1+h}
     }
 
+    /// 左上がposで大きさがsizeの矩形。右下がi32に収まらなければi32::MAXで止める
+    pub fn from_pos_size(pos: Vec2<i32>, size: Vec2<u32>) -> Self {
+        let clamp = |v: u32| v.min(i32::MAX as u32) as i32;
+        Self {
+            x1: pos.x,
+            y1: pos.y,
+            x2: pos.x.saturating_add(clamp(size.x)),
+            y2: pos.y.saturating_add(clamp(size.y)),
+        }
+    }
+
+    /// 幅か高さが0以下
+    pub fn is_empty(&self) -> bool {
+        self.x1 >= self.x2 || self.y1 >= self.y2
+    }
+
+    /// 幅と高さ。空なら0
+    pub fn size(&self) -> Vec2<u32> {
+        let len = |a: i32, b: i32| (b as i64 - a as i64).max(0) as u32;
+        Vec2::new(len(self.x1, self.x2), len(self.y1, self.y2))
+    }
+
     pub fn to_origin(&self) -> Self {
         Self {
             x1: 0,
@@ -109,10 +344,34 @@ impl Rect {
         }
     }
 
+    /// move_relativeと同じだが、i32の端で止まる
+    pub fn translate(&self, d: Vec2<i32>) -> Self {
+        Self {
+            x1: self.x1.saturating_add(d.x),
+            x2: self.x2.saturating_add(d.x),
+            y1: self.y1.saturating_add(d.y),
+            y2: self.y2.saturating_add(d.y),
+        }
+    }
+
+    pub fn contains(&self, p: Vec2<i32>) -> bool {
+        self.x1 <= p.x && p.x < self.x2 && self.y1 <= p.y && p.y < self.y2
+    }
+
     pub fn contained_by(&self, other: &Self) -> bool {
         other.x1 <= self.x1 && other.y1 <= self.y1 && self.x2 <= other.x2 && self.y2 <= other.y2
     }
 
+    /// 両方を含む最小の矩形
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            x1: self.x1.min(other.x1),
+            y1: self.y1.min(other.y1),
+            x2: self.x2.max(other.x2),
+            y2: self.y2.max(other.y2),
+        }
+    }
+
     pub fn intersection(&self, other: &Self) -> Option<Self> {
         let x1 = self.x1.max(other.x1);
         let x2 = self.x2.min(other.x2);
@@ -126,4 +385,156 @@ impl Rect {
             x1, x2, y1, y2
         })
     }
+
+    /// boundsの中に切り詰める。重ならなければ空の矩形(is_emptyがtrue)。
+    /// fill_rectなどはこれを1度だけ行ってから画素を書く
+    pub fn clamp_to(&self, bounds: &Self) -> Self {
+        self.intersection(bounds).unwrap_or(Self { x1: bounds.x1, y1: bounds.y1, x2: bounds.x1, y2: bounds.y1 })
+    }
+}
+
+/// 色の合成と矩形の計算、はみ出した描画が何もしないことを確かめる
+pub fn run_graphics_tests() {
+    let (red, blue) = (Color::new(255, 0, 0), Color::new(0, 0, 255));
+    assert!(red.is_opaque() && !red.with_alpha(254).is_opaque());
+    assert!(red.with_alpha(128).over(blue) == Color::new(128, 0, 127));
+    assert!(red.over(blue) == red && red.with_alpha(0).over(blue) == blue);
+    assert!(blend_channel(200, 100, 255) == 200 && blend_channel(200, 100, 0) == 100 && blend_channel(255, 0, 1) == 1);
+
+    let r = Rect::from_wh(10, 20, 30, 40);
+    assert!(!r.is_empty() && r.size() == Vec2::new(30, 40));
+    assert!(Rect::from_wh(5, 5, 0, 3).is_empty() && Rect::from_wh(5, 5, -3, 3).size() == Vec2::new(0, 3));
+    assert!(r.translate(Vec2::new(-10, 5)) == Rect::from_wh(0, 25, 30, 40));
+    assert!(r.translate(Vec2::new(i32::MAX, 0)).x2 == i32::MAX);
+    assert!(r.intersection(&Rect::from_wh(0, 0, 15, 25)) == Some(Rect::from_points(10, 20, 15, 25)));
+    assert!(r.clamp_to(&Rect::from_wh(100, 100, 5, 5)).is_empty());
+    // 接しているだけなら重ならない
+    assert!(r.intersection(&Rect::from_wh(40, 20, 5, 5)).is_none());
+    let screen = Rect::from_wh(0, 0, 64, 48);
+    assert!(Rect::from_wh(-5, -5, 10, 10).clamp_to(&screen) == Rect::from_points(0, 0, 5, 5));
+    assert!(Rect::from_wh(60, 40, 10, 10).clamp_to(&screen) == Rect::from_points(60, 40, 64, 48));
+    assert!(Rect::from_wh(-20, 0, 10, 10).clamp_to(&screen).is_empty());
+    // 大きさがi32に収まらなくても溢れない
+    let huge = Rect::from_pos_size(Vec2::new(i32::MAX - 1, -5), Vec2::new(u32::MAX, 10));
+    assert!(huge.x2 == i32::MAX && huge.y2 == 5 && huge.size() == Vec2::new(1, 10));
+    assert!(Vec2::new(i32::MAX, 0).checked_add(Vec2::new(1, 0)).is_none());
+    assert!(Vec2::new(i32::MIN, 3).saturating_add(Vec2::new(-1, 4)) == Vec2::new(i32::MIN, 7));
+
+    // 書いた画素を数えるだけのWriter。範囲の外に書けば止まる
+    struct Counter {
+        bounds: Rect,
+        written: usize,
+    }
+    impl PixelWriter for Counter {
+        fn write(&mut self, pos: Vec2<i32>, _: PixelColor) {
+            assert!(self.bounds.contains(pos));
+            self.written += 1;
+        }
+        fn bounds(&self) -> Rect {
+            self.bounds
+        }
+    }
+    let mut w = Counter { bounds: Rect::from_wh(0, 0, 8, 4), written: 0 };
+    let black = Color::gray(0);
+    w.fill_rect(Vec2::new(-2, -1), Vec2::new(4, 3), black);
+    assert!(w.written == 2 * 2);
+    w.written = 0;
+    for (pos, size) in [((-10, 0), (5, 5)), ((8, 0), (1, 1)), ((0, 4), (1, 1)), ((1, 1), (0, 2)), ((i32::MAX, i32::MAX), (u32::MAX, u32::MAX))] {
+        w.fill_rect(pos.into(), size.into(), black);
+    }
+    assert!(w.written == 0);
+    // 右端の1列と、下にはみ出した行は書かない
+    w.draw_bitpattern(Vec2::new(6, 2), &[0b11 << 62, 0b1 << 63, 0b1 << 63], black, 2);
+    assert!(w.written == 2 * 2);
+    w.written = 0;
+    w.draw_bitpattern(Vec2::new(-1, 0), &[u64::MAX], black, u32::MAX);
+    assert!(w.written == 8 * 4);
+
+    // 線、円、角の丸い矩形。画素ごとに書いた回数を数え、同じ画素を2度書かないことも確かめる
+    struct Grid {
+        size: i32,
+        hits: [[u8; 16]; 16],
+    }
+    impl PixelWriter for Grid {
+        fn write(&mut self, pos: Vec2<i32>, _: PixelColor) {
+            assert!(self.bounds().contains(pos));
+            self.hits[pos.y as usize][pos.x as usize] += 1;
+        }
+        fn bounds(&self) -> Rect {
+            Rect::from_wh(0, 0, self.size, self.size)
+        }
+    }
+    impl Grid {
+        fn new(size: i32) -> Self {
+            Self { size, hits: [[0; 16]; 16] }
+        }
+        fn count(&self) -> usize {
+            assert!(self.hits.iter().flatten().all(|h| *h <= 1));
+            self.hits.iter().flatten().filter(|h| **h == 1).count()
+        }
+        fn at(&self, x: i32, y: i32) -> bool {
+            self.hits[y as usize][x as usize] == 1
+        }
+        /// 左右と上下に折り返しても同じ形か。nは形の端から端までの幅
+        fn symmetric(&self, n: i32) -> bool {
+            (0..n).all(|y| (0..n).all(|x| self.at(x, y) == self.at(n - 1 - x, y) && self.at(x, y) == self.at(x, n - 1 - y)))
+        }
+    }
+
+    // 8方向どれでも、長い方の差+1画素で両端を通り、逆向きに引いても同じ
+    for (dx, dy) in [(7, 3), (3, 7), (-3, 7), (-7, 3), (-7, -3), (-3, -7), (3, -7), (7, -3), (7, 0), (0, -7), (5, 5), (0, 0)] {
+        let (from, to) = (Vec2::new(8, 8), Vec2::new(8 + dx, 8 + dy));
+        let mut g = Grid::new(16);
+        g.draw_line(from, to, black);
+        assert!(g.count() == dx.abs().max(dy.abs()) as usize + 1 && g.at(from.x, from.y) && g.at(to.x, to.y));
+        let mut back = Grid::new(16);
+        back.draw_line(to, from, black);
+        assert!(back.hits == g.hits);
+    }
+    // はみ出した部分は書かず、画面をはるかに越える線でも画面の中の分だけ
+    let mut g = Grid::new(4);
+    g.draw_line(Vec2::new(-1000, -1000), Vec2::new(1000, 1000), black);
+    assert!(g.count() == 4 && (0..4).all(|i| g.at(i, i)));
+    let mut g = Grid::new(4);
+    g.draw_line(Vec2::new(i32::MIN, 2), Vec2::new(i32::MAX, 2), black);
+    g.draw_line(Vec2::new(-5, -1), Vec2::new(-1, 3), black);
+    assert!(g.count() == 4 && (0..4).all(|x| g.at(x, 2)));
+
+    let mut g = Grid::new(16);
+    g.draw_rect_outline(Rect::from_wh(1, 1, 5, 4), black);
+    assert!(g.count() == 2 * 5 + 2 * 2 && g.at(1, 1) && g.at(5, 4) && !g.at(2, 2));
+    let mut g = Grid::new(16);
+    g.draw_rect_outline(Rect::from_wh(0, 0, 1, 3), black);
+    g.draw_rect_outline(Rect::from_wh(2, 0, 3, 1), black);
+    g.draw_rect_outline(Rect::from_wh(14, 14, 4, 4), black);
+    assert!(g.count() == 3 + 3 + 3);
+
+    // 半径3の円は29画素、その縁は半径2の円(13画素)を除いた16画素。どちらも上下左右に対称
+    let mut disc = Grid::new(7);
+    disc.fill_circle(Vec2::new(3, 3), 3, black);
+    assert!(disc.count() == 29 && disc.symmetric(7));
+    let mut ring = Grid::new(7);
+    ring.draw_circle(Vec2::new(3, 3), 3, black);
+    assert!(ring.count() == 29 - 13 && ring.symmetric(7) && !ring.at(3, 3) && ring.at(0, 3) && ring.at(3, 6));
+    assert!((0..7).all(|y| (0..7).all(|x| !ring.at(x, y) || disc.at(x, y))));
+    // 半径0は1画素。半径が画面よりずっと大きくても、画面の中の行だけを塗る
+    let mut g = Grid::new(4);
+    g.fill_circle(Vec2::new(0, 0), 0, black);
+    g.draw_circle(Vec2::new(3, 3), 0, black);
+    assert!(g.count() == 2 && g.at(0, 0) && g.at(3, 3));
+    let mut g = Grid::new(4);
+    g.fill_circle(Vec2::new(i32::MAX, 0), u32::MAX, black);
+    assert!(g.count() == 16);
+
+    // 角を丸めた矩形は4, 6, 8, 8, 6, 4画素の行になる。半径は(短い辺-1)/2まで
+    let mut g = Grid::new(8);
+    g.fill_rounded_rect(Rect::from_wh(0, 1, 8, 6), 2, black);
+    assert!((1..7).map(|y| (0..8).filter(|x| g.at(*x, y)).count()).eq([4, 6, 8, 8, 6, 4]) && g.count() == 36);
+    assert!((0..8).all(|x| !g.at(x, 0) && !g.at(x, 7)) && g.at(2, 1) && !g.at(1, 1));
+    let mut g = Grid::new(8);
+    g.fill_rounded_rect(Rect::from_wh(0, 0, 8, 8), 100, black);
+    assert!(g.symmetric(8) && !g.at(0, 0) && g.at(0, 4) && g.at(4, 0));
+    let mut g = Grid::new(8);
+    g.fill_rounded_rect(Rect::from_wh(-2, -2, 20, 20), 0, black);
+    assert!(g.count() == 64);
 }
@@ -1,21 +1,119 @@
-use crate::{ memory_manager::LazyInit};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use self::{frame_buffer::{FrameBuffer, FrameBufferRaw}, window::LayeredWindowManager};
+use crate::{addr::PhysAddr, introspect, memory_manager::{LazyInit, Mutex}, paging::map_mmio, warn, timer::{add_timer_deferred, get_current_tick, uptime_micros}};
+
+use self::{frame_buffer::{FrameBuffer, FrameBufferRaw}, pacing::{FrameStats, Pacer}, window::LayeredWindowManager};
 
 pub mod window;
 pub mod font;
 pub mod graphics;
 pub mod frame_buffer;
 pub mod buffered;
+pub mod focus;
+pub mod palette;
+pub mod capture;
+pub mod snap;
+pub mod emergency;
+pub mod desktop;
+pub mod pacing;
+pub mod cursor;
 
 static LAYERS: LazyInit<LayeredWindowManager> = LazyInit::new();
 
 pub unsafe fn initialize_winmgr(fb: *const FrameBufferRaw) {
+    // 書いた画素がキャッシュに残らないよう、VRAMはキャッシュ無効で写像し直す
+    let raw = &*fb;
+    let len = raw.pixels_per_scanline as u64 * raw.vertical_resolution as u64 * 4;
+    if let Err(e) = map_mmio(PhysAddr::new(raw.buf as u64), len) {
+        warn!("graphic: could not map the frame buffer at {:p} uncached ({:?})", raw.buf, e);
+    }
     let mut fb = FrameBuffer::from_raw(fb);
     frame_buffer::set_default_pixel_format(fb.pixel_format());
     LAYERS.lock().init(LayeredWindowManager::new(fb));
+    introspect::register("gfx/layers", |_, out| with_layers(|l| l.write_stack(out)), 0).expect("graphic: gfx/layers");
+    introspect::register("gfx/resolution", |_, out| {
+        let (w, h) = with_layers(|l| l.resolution());
+        writeln!(out, "{w}x{h}")
+    }, 0)
+    .expect("graphic: gfx/resolution");
+    introspect::register("gfx/frames", |_, out| FRAME_STATS.lock().write(frame_rate(), out), 0).expect("graphic: gfx/frames");
+    desktop::init_desktop();
+}
+
+/// 画面があるか。ヘッドレスで起動したときはfalseで、with_layersを呼んではいけない
+pub fn is_initialized() -> bool {
+    LAYERS.lock().is_init()
 }
 
 pub fn with_layers<R>(f: impl FnOnce(&mut LayeredWindowManager) -> R) -> R {
     f(&mut LAYERS.lock())
 }
+
+/// フレームを出すtick。タイマーで決まった時刻にだけ合成し、それ以外の所は依頼するだけにする
+static PACER: Mutex<Pacer> = Mutex::new(Pacer::new(pacing::DEFAULT_FPS));
+static FRAME_STATS: Mutex<FrameStats> = Mutex::new(FrameStats::new());
+
+/// 合成の依頼が来ているか。ロックを取らずに立てられるので、ウィンドウやLAYERSのロックを持ったままでも、割り込みからでも依頼できる
+/// flushやレイヤの移動でも立つ
+static REDRAW_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// フレームの時刻になった。メインループが次に一巡したところで下ろす
+static FRAME_DUE: AtomicBool = AtomicBool::new(false);
+
+/// フレームごとの合成を始める。これ以降、ウィンドウの書き手はflushするだけでよい
+pub fn start_compositor() {
+    PACER.lock().restart(pacing::DEFAULT_FPS, get_current_tick());
+    on_frame(0);
+}
+
+pub fn frame_rate() -> u32 {
+    PACER.lock().fps()
+}
+
+/// 合成のフレームレートを変える。1からTIMER_FREQの間に丸め、次のフレームから効く
+pub fn set_frame_rate(fps: u32) {
+    PACER.lock().restart(fps, get_current_tick());
+}
+
+/// 1フレームの長さ(tick)。アニメーションはこの間隔でコマを進める
+pub fn frame_period() -> u64 {
+    PACER.lock().period()
+}
+
+/// 1フレーム分を合成する。重なり順のスナップショットだけをロックの中で取り、描くのはロックの外で行う。
+/// 描いている間の重なり順の変更は次のフレームに出る
+/// ウィンドウのロックは合成の中で1つずつ短く取るので、呼ぶ側はどのウィンドウのロックも持っていてはいけない
+pub fn compose_frame() -> bool {
+    let (frame, screen) = with_layers(|l| (l.prepare_frame(), l.screen()));
+    let Some(frame) = frame else {
+        return false;
+    };
+    screen.lock().render(&frame);
+    true
+}
+
+fn on_frame(_: usize) {
+    FRAME_DUE.store(true, Ordering::Release);
+    let (next, skipped) = PACER.lock().advance(get_current_tick());
+    FRAME_STATS.lock().skipped += skipped;
+    add_timer_deferred(next, on_frame, 0);
+}
+
+/// 次のフレームで合成してもらう。変化が無ければフレームの時刻が来ても何も描かない
+pub fn request_redraw() {
+    REDRAW_REQUESTED.store(true, Ordering::Release);
+}
+
+/// フレームの時刻が来ていて、合成の依頼もある。メインループが休む前に割り込みを止めて確かめる
+pub fn compose_pending() -> bool {
+    FRAME_DUE.load(Ordering::Acquire) && REDRAW_REQUESTED.load(Ordering::Acquire)
+}
+
+/// フレームの時刻が来ていて依頼があれば合成する。メインループがメッセージを処理し終えた所からだけ呼び、そこではどのロックも持っていない
+pub fn compose_if_due() {
+    if !FRAME_DUE.swap(false, Ordering::AcqRel) || !REDRAW_REQUESTED.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    if compose_frame() {
+        FRAME_STATS.lock().record(uptime_micros());
+    }
+}
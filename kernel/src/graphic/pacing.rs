@@ -0,0 +1,143 @@
+use core::fmt::{self, Write};
+
+use crate::timer::TIMER_FREQ;
+
+/// 既定のフレームレート(毎秒)
+pub const DEFAULT_FPS: u32 = 60;
+
+/// 合成のフレームを出すtickを決める。TIMER_FREQで割り切れないフレームレートは、
+/// 間隔を1tickずつ揺らして平均がfpsになるようにする
+#[derive(Debug, Clone, Copy)]
+pub struct Pacer {
+    fps: u32,
+    /// 数え始めたtick
+    start: u64,
+    /// 次に出すフレームの番号
+    frame: u64,
+}
+
+impl Pacer {
+    pub const fn new(fps: u32) -> Self {
+        Self { fps: clamp_fps(fps), start: 0, frame: 0 }
+    }
+
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+
+    /// nowから数え直す
+    pub fn restart(&mut self, fps: u32, now: u64) {
+        *self = Self { fps: clamp_fps(fps), start: now, frame: 0 };
+    }
+
+    /// 次のフレームのtickとしてnowより後で一番早いものを返す。遅れて間に合わなかったフレームは飛ばし、その数も返す
+    pub fn advance(&mut self, now: u64) -> (u64, u64) {
+        let elapsed = now.saturating_sub(self.start);
+        // frame_tick(f) > nowとなる最小のf
+        let first_future = elapsed * self.fps as u64 / TIMER_FREQ as u64 + 1;
+        let next = (self.frame + 1).max(first_future);
+        let skipped = next - (self.frame + 1);
+        self.frame = next;
+        (self.frame_tick(next), skipped)
+    }
+
+    /// 1フレームの長さ(tick)。割り切れなければ切り上げる。アニメーションのコマ送りに使う
+    pub fn period(&self) -> u64 {
+        (TIMER_FREQ as u64).div_ceil(self.fps as u64)
+    }
+
+    fn frame_tick(&self, frame: u64) -> u64 {
+        self.start + (frame * TIMER_FREQ as u64).div_ceil(self.fps as u64)
+    }
+}
+
+/// tickより細かいフレームは出せないので、1からTIMER_FREQの間に収める
+const fn clamp_fps(fps: u32) -> u32 {
+    if fps == 0 {
+        1
+    } else if fps > TIMER_FREQ {
+        TIMER_FREQ
+    } else {
+        fps
+    }
+}
+
+/// 実際に画面を描き直した間隔の記録(マイクロ秒)。変化の無いフレームは描かないので数えない
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub frames: u64,
+    /// 遅れて飛ばしたフレームの数
+    pub skipped: u64,
+    last_at: Option<u64>,
+    pub last_interval: u64,
+    pub min_interval: u64,
+    pub max_interval: u64,
+    total_interval: u64,
+}
+
+impl FrameStats {
+    pub const fn new() -> Self {
+        Self { frames: 0, skipped: 0, last_at: None, last_interval: 0, min_interval: 0, max_interval: 0, total_interval: 0 }
+    }
+
+    /// now_usに1フレーム描いた
+    pub fn record(&mut self, now_us: u64) {
+        if let Some(last) = self.last_at {
+            let interval = now_us.saturating_sub(last);
+            self.min_interval = if self.frames > 1 { self.min_interval.min(interval) } else { interval };
+            self.max_interval = self.max_interval.max(interval);
+            self.total_interval += interval;
+            self.last_interval = interval;
+        }
+        self.last_at = Some(now_us);
+        self.frames += 1;
+    }
+
+    /// 間隔の平均。2フレーム描くまでは0
+    pub fn avg_interval(&self) -> u64 {
+        self.total_interval / self.frames.saturating_sub(1).max(1)
+    }
+
+    pub fn write(&self, fps: u32, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "target {fps} fps, {} frames drawn, {} skipped", self.frames, self.skipped)?;
+        writeln!(
+            out,
+            "interval us: last {} avg {} min {} max {}",
+            self.last_interval,
+            self.avg_interval(),
+            self.min_interval,
+            self.max_interval
+        )
+    }
+}
+
+pub fn run_pacing_tests() {
+    // 100Hzのtickで60fpsなら、2tickと1tickの間隔が混ざり、100tickでちょうど60フレームになる
+    let mut p = Pacer::new(60);
+    let ticks: [u64; 5] = core::array::from_fn(|_| p.advance(0).0);
+    assert!(ticks == [2, 4, 5, 7, 9]);
+    let mut p = Pacer::new(60);
+    let mut last = 0;
+    for _ in 0..60 {
+        last = p.advance(last).0;
+    }
+    assert!(last == 100);
+
+    // 遅れたら、過ぎたフレームは飛ばして次の未来のフレームにする
+    let mut p = Pacer::new(50);
+    p.restart(50, 1000);
+    assert!(p.advance(1000) == (1002, 0));
+    assert!(p.advance(1009) == (1010, 3));
+    assert!(p.advance(1010) == (1012, 0));
+
+    assert!(Pacer::new(0).fps() == 1 && Pacer::new(1000).fps() == TIMER_FREQ);
+    assert!(Pacer::new(60).period() == 2 && Pacer::new(TIMER_FREQ).period() == 1);
+
+    let mut s = FrameStats::new();
+    s.record(1_000);
+    assert!(s.frames == 1 && s.avg_interval() == 0);
+    s.record(17_000);
+    s.record(50_000);
+    assert!(s.last_interval == 33_000 && s.min_interval == 16_000 && s.max_interval == 33_000);
+    assert!(s.avg_interval() == 24_500);
+}
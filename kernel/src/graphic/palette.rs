@@ -0,0 +1,80 @@
+use super::graphics::Color;
+
+pub const BLACK: Color = Color::gray(0x00);
+pub const WHITE: Color = Color::gray(0xff);
+
+/* ウィンドウ枠 (draw_window) */
+pub const WINDOW_GRAY: Color = Color::gray(0xc6);
+pub const WINDOW_SHADOW: Color = Color::gray(0x84);
+pub const WINDOW_HIGHLIGHT: Color = WHITE;
+pub const WINDOW_OUTLINE: Color = BLACK;
+pub const TITLE_BLUE: Color = Color::new(0x00, 0x00, 0x84);
+pub const TITLE_TEXT: Color = WHITE;
+pub const WINDOW_TEXT: Color = BLACK;
+
+/// Window::newで背景を指定しないときの色。バッファをゼロで埋めていた頃と同じ黒
+pub const WINDOW_BG: Color = BLACK;
+
+pub const CONSOLE_FG: Color = WHITE;
+pub const CONSOLE_BG: Color = Color::gray(100);
+
+/// SGRの16色。0-7がESC[30-37m/40-47m、8-15が明るい色のESC[90-97m/100-107m
+pub const ANSI_COLORS: [Color; 16] = [
+    BLACK,
+    Color::new(0xaa, 0x00, 0x00),
+    Color::new(0x00, 0xaa, 0x00),
+    Color::new(0xaa, 0xaa, 0x00),
+    Color::new(0x00, 0x00, 0xaa),
+    Color::new(0xaa, 0x00, 0xaa),
+    Color::new(0x00, 0xaa, 0xaa),
+    Color::gray(0xaa),
+    Color::gray(0x55),
+    Color::new(0xff, 0x55, 0x55),
+    Color::new(0x55, 0xff, 0x55),
+    Color::new(0xff, 0xff, 0x55),
+    Color::new(0x55, 0x55, 0xff),
+    Color::new(0xff, 0x55, 0xff),
+    Color::new(0x55, 0xff, 0xff),
+    WHITE,
+];
+
+/// 選択中の項目の背景
+pub const SELECTION_BG: Color = TITLE_BLUE;
+/// テキスト表示領域の背景
+pub const TEXT_BG: Color = WHITE;
+
+/// ウィンドウを吸着させる先の表示
+pub const SNAP_PREVIEW: Color = SELECTION_BG;
+
+/// 起動時の壁紙のグラデーション (graphic::desktop)
+pub const DESKTOP_TOP: Color = Color::new(0x00, 0x40, 0x80);
+pub const DESKTOP_BOTTOM: Color = Color::new(0x00, 0x10, 0x30);
+
+/// パニックの画面 (graphic::emergency)
+pub const PANIC_BG: Color = Color::new(0x84, 0x00, 0x00);
+pub const PANIC_FG: Color = WHITE;
+
+pub const OVERLAY_BG: Color = Color::gray(0x20);
+pub const OVERLAY_FG: Color = Color::new(0x00, 0xff, 0x00);
+
+/// 透過色として使う色。他の用途には使わない
+pub const TRANSPARENT_KEY: Color = Color::gray(0x01);
+
+/// 背景bgの上に描く文字に使う色 (黒か白)
+pub const fn contrast_text_color(bg: Color) -> Color {
+    if bg.luminance() >= 128 {
+        BLACK
+    } else {
+        WHITE
+    }
+}
+
+pub fn run_palette_tests() {
+    assert!(WHITE.luminance() == 255 && BLACK.luminance() == 0);
+    assert!(contrast_text_color(WINDOW_GRAY) == BLACK);
+    assert!(contrast_text_color(TITLE_BLUE) == WHITE);
+    assert!(contrast_text_color(CONSOLE_BG) == WHITE);
+    assert!(contrast_text_color(Color::new(0xff, 0xff, 0x00)) == BLACK);
+    assert!(Color::from((1, 2, 3)) == Color::new(1, 2, 3));
+    assert!(<(u8, u8, u8)>::from(TITLE_BLUE) == (0x00, 0x00, 0x84));
+}
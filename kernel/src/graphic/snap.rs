@@ -0,0 +1,524 @@
+use alloc::vec::Vec;
+
+use crate::{memory_manager::Mutex, shortcut::{self, Mods}, timer::{add_timer_deferred, get_current_tick}};
+
+use super::{focus, graphics::{PixelWriter, Rect, Vec2}, palette, window::{Hit, LayerHandle, LayerId, Placement, Window}, with_layers, frame_period};
+
+const KEY_F: u8 = 0x09;
+const KEY_RIGHT: u8 = 0x4f;
+const KEY_LEFT: u8 = 0x50;
+const KEY_DOWN: u8 = 0x51;
+const KEY_UP: u8 = 0x52;
+const BUTTON_LEFT: u8 = 1;
+
+/// ドラッグ中のカーソルが画面の端からこの距離までに入ったら吸着先を示す
+const EDGE_PX: i32 = 8;
+/// 端のうち、角とみなす部分の長さ
+const CORNER_PX: i32 = 48;
+/// キーボードで吸着させたときに動かすフレーム数
+const ANIM_FRAMES: u32 = 6;
+
+/// 画面の端に確保する帯の位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Top,
+    Bottom,
+}
+
+impl Edge {
+    /// 上下に確保した帯の高さreservedに、この端の高さsizeの帯を加える。同じ端では高い方に合わせる
+    pub fn reserve(self, (top, bottom): (i32, i32), size: i32) -> (i32, i32) {
+        match self {
+            Edge::Top => (top.max(size), bottom),
+            Edge::Bottom => (top, bottom.max(size)),
+        }
+    }
+}
+
+/// 解像度から、上下に確保された帯を除いた領域
+pub fn work_area(resolution: (u32, u32), top: i32, bottom: i32) -> Rect {
+    let (w, h) = (resolution.0 as i32, resolution.1 as i32);
+    Rect::from_points(0, top.min(h), w, (h - bottom).max(top.min(h)))
+}
+
+/// 1つの向きで吸着先が占める範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Span {
+    /// 左または上の半分
+    Low,
+    /// 右または下の半分
+    High,
+    Whole,
+}
+
+/// ウィンドウを吸着させる先。横と縦の範囲の組で、半分・4分の1・全体を表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapTarget {
+    h: Span,
+    v: Span,
+}
+
+impl SnapTarget {
+    pub const LEFT: Self = Self { h: Span::Low, v: Span::Whole };
+    pub const RIGHT: Self = Self { h: Span::High, v: Span::Whole };
+    pub const TOP: Self = Self { h: Span::Whole, v: Span::Low };
+    pub const BOTTOM: Self = Self { h: Span::Whole, v: Span::High };
+    pub const TOP_LEFT: Self = Self { h: Span::Low, v: Span::Low };
+    pub const TOP_RIGHT: Self = Self { h: Span::High, v: Span::Low };
+    pub const BOTTOM_LEFT: Self = Self { h: Span::Low, v: Span::High };
+    pub const BOTTOM_RIGHT: Self = Self { h: Span::High, v: Span::High };
+    pub const FULL: Self = Self { h: Span::Whole, v: Span::Whole };
+
+    pub const ALL: [Self; 9] = [
+        Self::LEFT, Self::RIGHT, Self::TOP, Self::BOTTOM,
+        Self::TOP_LEFT, Self::TOP_RIGHT, Self::BOTTOM_LEFT, Self::BOTTOM_RIGHT, Self::FULL,
+    ];
+
+    fn is_half(self) -> bool {
+        (self.h == Span::Whole) != (self.v == Span::Whole)
+    }
+
+    /// 作業領域workの中で占める矩形。奇数の長さは右・下の側が1画素広い
+    pub fn area(self, work: Rect) -> Rect {
+        let split = |lo: i32, hi: i32, span: Span| {
+            let mid = lo + (hi - lo) / 2;
+            match span {
+                Span::Low => (lo, mid),
+                Span::High => (mid, hi),
+                Span::Whole => (lo, hi),
+            }
+        };
+        let (x1, x2) = split(work.x1, work.x2, self.h);
+        let (y1, y2) = split(work.y1, work.y2, self.v);
+        Rect::from_points(x1, y1, x2, y2)
+    }
+
+    /// 大きさw×hのウィンドウを置く位置
+    /// ウィンドウのバッファは大きさを変えられないので、矩形のうち画面の端に接する側に寄せ、両端に接する向きでは真ん中に置く。
+    /// 作業領域からはみ出すなら押し戻し、作業領域より大きければ左上に合わせる
+    pub fn place(self, work: Rect, w: i32, h: i32) -> Vec2<i32> {
+        let area = self.area(work);
+        let fit = |lo: i32, hi: i32, span: Span, len: i32, work_lo: i32, work_hi: i32| {
+            let p = match span {
+                Span::Low => lo,
+                Span::High => hi - len,
+                Span::Whole => lo + (hi - lo - len) / 2,
+            };
+            p.min(work_hi - len).max(work_lo)
+        };
+        Vec2::new(fit(area.x1, area.x2, self.h, w, work.x1, work.x2), fit(area.y1, area.y2, self.v, h, work.y1, work.y2))
+    }
+}
+
+/// 大きさsizeのウィンドウを左上posに置くとき、screenからはみ出さない位置。screenより大きければ左上に合わせる
+fn clamp_window(pos: Vec2<i32>, size: Rect, screen: Rect) -> Vec2<i32> {
+    let clamp = |p: i32, len: i32, lo: i32, hi: i32| p.min(hi - len).max(lo);
+    Vec2::new(clamp(pos.x, size.x2 - size.x1, screen.x1, screen.x2), clamp(pos.y, size.y2 - size.y1, screen.y1, screen.y2))
+}
+
+/// ドラッグ中のカーソルの位置から吸着先を決める
+/// 左右の端なら半分、上下の端なら全体と下半分、その角なら4分の1
+pub fn edge_target(pos: Vec2<i32>, screen: Rect) -> Option<SnapTarget> {
+    let side = |p: i32, lo: i32, hi: i32, margin: i32| {
+        if p < lo + margin {
+            Span::Low
+        } else if p >= hi - margin {
+            Span::High
+        } else {
+            Span::Whole
+        }
+    };
+    let h_edge = side(pos.x, screen.x1, screen.x2, EDGE_PX);
+    let v_edge = side(pos.y, screen.y1, screen.y2, EDGE_PX);
+    match (h_edge, v_edge) {
+        (Span::Whole, Span::Whole) => None,
+        (Span::Whole, Span::Low) => match side(pos.x, screen.x1, screen.x2, CORNER_PX) {
+            Span::Whole => Some(SnapTarget::FULL),
+            h => Some(SnapTarget { h, v: Span::Low }),
+        },
+        (Span::Whole, Span::High) => Some(SnapTarget { h: side(pos.x, screen.x1, screen.x2, CORNER_PX), v: Span::High }),
+        (h, _) => Some(SnapTarget { h, v: side(pos.y, screen.y1, screen.y2, CORNER_PX) }),
+    }
+}
+
+/// 今curに吸着しているウィンドウでpressedのショートカットが押されたときの吸着先。Noneなら元に戻す
+/// 同じものをもう一度押すと戻り、半分に吸着しているときに直交する向きを押すと4分の1になる
+fn next_target(cur: Option<SnapTarget>, pressed: SnapTarget) -> Option<SnapTarget> {
+    match cur {
+        Some(c) if c == pressed => None,
+        Some(c) if c.is_half() && pressed.is_half() && (c.h == Span::Whole) != (pressed.h == Span::Whole) => Some(SnapTarget {
+            h: if c.h == Span::Whole { pressed.h } else { c.h },
+            v: if c.v == Span::Whole { pressed.v } else { c.v },
+        }),
+        _ => Some(pressed),
+    }
+}
+
+struct Snapped {
+    layer_id: LayerId,
+    target: SnapTarget,
+    /// 吸着する前の位置
+    restore: Vec2<i32>,
+}
+
+/// 吸着しているウィンドウと、吸着を解いたときに戻す位置
+pub struct SnapBook {
+    entries: Vec<Snapped>,
+}
+
+impl SnapBook {
+    pub const fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    pub fn target(&self, layer_id: LayerId) -> Option<SnapTarget> {
+        self.entries.iter().find(|e| e.layer_id == layer_id).map(|e| e.target)
+    }
+
+    /// targetに吸着させたことを記録する。吸着していなかったなら今の位置currentを戻し先として覚える
+    /// 吸着先を移るだけなら、最初に吸着する前の位置のまま
+    pub fn snap(&mut self, layer_id: LayerId, target: SnapTarget, current: Vec2<i32>) {
+        match self.entries.iter_mut().find(|e| e.layer_id == layer_id) {
+            Some(e) => e.target = target,
+            None => self.entries.push(Snapped { layer_id, target, restore: current }),
+        }
+    }
+
+    /// 吸着を解き、戻し先を返す。吸着していなければNone
+    pub fn unsnap(&mut self, layer_id: LayerId) -> Option<Vec2<i32>> {
+        let i = self.entries.iter().position(|e| e.layer_id == layer_id)?;
+        Some(self.entries.swap_remove(i).restore)
+    }
+}
+
+/// fromからtoへframes回で動かすときの、frame回目の位置。終わりに近づくほど遅くなる
+fn interpolate(from: Vec2<i32>, to: Vec2<i32>, frame: u32, frames: u32) -> Vec2<i32> {
+    let frame = frame.min(frames) as i64;
+    let frames = frames.max(1) as i64;
+    // 1 - (1 - t)^2
+    let eased = |a: i32, b: i32| {
+        let rest = frames - frame;
+        a + ((b - a) as i64 * (frames * frames - rest * rest) / (frames * frames)) as i32
+    };
+    Vec2::new(eased(from.x, to.x), eased(from.y, to.y))
+}
+
+#[derive(Clone, Copy)]
+struct Drag {
+    layer_id: LayerId,
+    /// ウィンドウの左上からカーソルまで
+    grab: Vec2<i32>,
+    /// ドラッグを始める前の位置。吸着したらここを戻し先にする
+    origin: Vec2<i32>,
+}
+
+struct Anim {
+    layer_id: LayerId,
+    from: Vec2<i32>,
+    to: Vec2<i32>,
+    frame: u32,
+}
+
+struct SnapManager {
+    book: SnapBook,
+    drag: Option<Drag>,
+    prev_buttons: u8,
+    /// ドラッグ中に示している吸着先と、それを描いたレイヤ
+    preview: Option<(SnapTarget, LayerHandle)>,
+    anims: Vec<Anim>,
+}
+
+/// ロックの順番はSNAP、FOCUS、LAYERSの順。SNAPを持ったままwith_layersを呼んでよいが、逆はしない
+static SNAP: Mutex<SnapManager> = Mutex::new(SnapManager {
+    book: SnapBook::new(),
+    drag: None,
+    prev_buttons: 0,
+    preview: None,
+    anims: Vec::new(),
+});
+
+const TILE_KEYS: [(Mods, u8, SnapTarget); 5] = [
+    (Mods::GUI, KEY_LEFT, SnapTarget::LEFT),
+    (Mods::GUI, KEY_RIGHT, SnapTarget::RIGHT),
+    (Mods::GUI, KEY_UP, SnapTarget::TOP),
+    (Mods::GUI, KEY_DOWN, SnapTarget::BOTTOM),
+    (Mods::GUI, KEY_F, SnapTarget::FULL),
+];
+
+/// GUI+矢印キーとGUI+Fを登録する
+pub fn init_snap() {
+    for (i, (mods, usage, _)) in TILE_KEYS.iter().enumerate() {
+        shortcut::register_global(*mods, *usage, "snap", on_tile_key, i).expect("snap: tiling keys");
+    }
+}
+
+/// ウィンドウを閉じたら、覚えている位置を捨てる
+pub fn forget(layer_id: LayerId) {
+    let mut snap = SNAP.lock();
+    snap.book.unsnap(layer_id);
+    snap.anims.retain(|a| a.layer_id != layer_id);
+    if snap.drag.is_some_and(|d| d.layer_id == layer_id) {
+        snap.drag = None;
+        snap.show_preview(None, layer_id);
+    }
+}
+
+/// フォーカスされたウィンドウを吸着させるか、同じキーなら元に戻す
+fn on_tile_key(index: usize) {
+    let Some(layer_id) = focus::focused() else {
+        return;
+    };
+    let mut snap = SNAP.lock();
+    if snap.drag.is_some() {
+        return;
+    }
+    let Some((work, pos, w, h)) = with_layers(|l| {
+        let win = l.layer(layer_id)?.read();
+        Some((l.work_area(), win.pos(), win.width() as i32, win.height() as i32))
+    }) else {
+        return;
+    };
+    // 動いている途中なら、向かっている位置から測る
+    let pos = snap.anims.iter().find(|a| a.layer_id == layer_id).map_or(pos, |a| a.to);
+    let to = match next_target(snap.book.target(layer_id), TILE_KEYS[index].2) {
+        Some(target) => {
+            snap.book.snap(layer_id, target, pos);
+            target.place(work, w, h)
+        }
+        None => snap.book.unsnap(layer_id).unwrap_or(pos),
+    };
+    snap.animate(layer_id, to);
+}
+
+fn on_anim_frame(_: usize) {
+    let mut snap = SNAP.lock();
+    for a in snap.anims.iter_mut() {
+        a.frame += 1;
+        let pos = interpolate(a.from, a.to, a.frame, ANIM_FRAMES);
+        let _ = with_layers(|l| l.move_to(a.layer_id, pos));
+    }
+    snap.anims.retain(|a| a.frame < ANIM_FRAMES);
+    if !snap.anims.is_empty() {
+        add_timer_deferred(get_current_tick() + frame_period(), on_anim_frame, 0);
+    }
+}
+
+/// マウスのレポートを受け取り、タイトルバーを掴んだウィンドウのドラッグと、画面の端への吸着を行う
+/// focus::on_mouseの後に呼ぶ(クリックしたウィンドウが先にフォーカスされている)
+pub fn on_mouse(buttons: u8, pos: Vec2<i32>) {
+    let mut snap = SNAP.lock();
+    let was_down = snap.prev_buttons & BUTTON_LEFT != 0;
+    let down = buttons & BUTTON_LEFT != 0;
+    snap.prev_buttons = buttons;
+
+    match (was_down, down, snap.drag) {
+        (false, true, _) => {
+            let Some(layer_id) = focus::focused() else {
+                return;
+            };
+            // 掴めるのは、カーソルの下で一番上にあるウィンドウのタイトルバーだけ
+            let origin = with_layers(|l| {
+                if l.layer_at(pos) != Some(layer_id) {
+                    return None;
+                }
+                let win = l.layer(layer_id)?.read();
+                let p = win.pos();
+                (win.hit_test((pos.x - p.x, pos.y - p.y).into()) == Some(Hit::TitleBar)).then_some(p)
+            });
+            if let Some(origin) = origin {
+                snap.anims.retain(|a| a.layer_id != layer_id);
+                snap.drag = Some(Drag { layer_id, grab: Vec2::new(pos.x - origin.x, pos.y - origin.y), origin });
+            }
+        }
+        (true, true, Some(drag)) => {
+            // 吸着したウィンドウを引き離したら、吸着は解ける。位置はカーソルに付いていき、画面からははみ出さない
+            let screen = with_layers(|l| {
+                let (w, h) = l.resolution();
+                let screen = Rect::from_wh(0, 0, w as i32, h as i32);
+                l.height_of(drag.layer_id)?;
+                let size = l.layer(drag.layer_id).map(|w| w.read().rect().to_origin())?;
+                let _ = l.move_to(drag.layer_id, clamp_window(Vec2::new(pos.x - drag.grab.x, pos.y - drag.grab.y), size, screen));
+                Some(screen)
+            });
+            let Some(screen) = screen else {
+                // ドラッグ中に隠されたか閉じられた
+                snap.drag = None;
+                snap.show_preview(None, drag.layer_id);
+                return;
+            };
+            snap.book.unsnap(drag.layer_id);
+            snap.show_preview(edge_target(pos, screen), drag.layer_id);
+        }
+        (true, false, Some(drag)) => {
+            snap.drag = None;
+            let Some(target) = snap.preview.as_ref().map(|(t, _)| *t) else {
+                return;
+            };
+            snap.show_preview(None, drag.layer_id);
+            let Some((work, w, h)) = with_layers(|l| {
+                let win = l.layer(drag.layer_id)?.read();
+                Some((l.work_area(), win.width() as i32, win.height() as i32))
+            }) else {
+                return;
+            };
+            snap.book.snap(drag.layer_id, target, drag.origin);
+            snap.animate(drag.layer_id, target.place(work, w, h));
+        }
+        _ => (),
+    }
+}
+
+impl SnapManager {
+    /// 今の位置からtoへ動かし始める
+    fn animate(&mut self, layer_id: LayerId, to: Vec2<i32>) {
+        let Some(from) = with_layers(|l| l.layer(layer_id).map(|w| w.read().pos())) else {
+            return;
+        };
+        let idle = self.anims.is_empty();
+        self.anims.retain(|a| a.layer_id != layer_id);
+        self.anims.push(Anim { layer_id, from, to, frame: 0 });
+        if idle {
+            add_timer_deferred(get_current_tick() + frame_period(), on_anim_frame, 0);
+        }
+    }
+
+    /// 吸着先を作業領域の上に半透明に示す。ドラッグしているウィンドウのすぐ下に置く
+    fn show_preview(&mut self, target: Option<SnapTarget>, dragged: LayerId) {
+        if self.preview.as_ref().map(|(t, _)| *t) == target {
+            return;
+        }
+        if let Some((_, handle)) = self.preview.take() {
+            let _ = with_layers(|l| l.close_layer(handle.layer_id()));
+        }
+        let Some(target) = target else {
+            return;
+        };
+        let handle = with_layers(|l| {
+            let area = target.area(l.work_area());
+            let (w, h) = ((area.x2 - area.x1) as usize, (area.y2 - area.y1) as usize);
+            let mut win = Window::new(w, h, Some(palette::TRANSPARENT_KEY));
+            win.set_transparent_color(Some(palette::TRANSPARENT_KEY));
+            win.move_to(Vec2::new(area.x1, area.y1));
+            // 1行おきに塗って、下のウィンドウが透けて見えるようにする
+            win.buffer().write_with(|back| {
+                for y in (0..h as i32).step_by(2) {
+                    back.fill_rect(Vec2::new(0, y), Vec2::new(w as u32, 1), palette::SNAP_PREVIEW);
+                }
+                for (x, y, bw, bh) in [(0, 0, w, 2), (0, h - 2, w, 2), (0, 0, 2, h), (w - 2, 0, 2, h)] {
+                    back.fill_rect(Vec2::new(x as i32, y as i32), Vec2::new(bw as u32, bh as u32), palette::SNAP_PREVIEW);
+                }
+            });
+            win.buffer().flush();
+            let handle = l.new_layer(win, Placement::Hidden);
+            let _ = l.move_below(handle.layer_id(), dragged);
+            handle
+        });
+        self.preview = Some((target, handle));
+    }
+}
+
+pub fn run_snap_tests() {
+    // 上にステータスバー、下にタスクバーがあってもなくても
+    for (res, top, bottom) in [((640, 480), 20, 0), ((1024, 768), 20, 32), ((1366, 768), 0, 0), ((1920, 1080), 20, 40), ((801, 601), 21, 7)] {
+        let work = work_area(res, top, bottom);
+        assert!(work == Rect::from_points(0, top, res.0 as i32, res.1 as i32 - bottom));
+        let a = |t: SnapTarget| t.area(work);
+
+        // 半分と4分の1は作業領域を隙間なく重ならずに分ける
+        assert!(a(SnapTarget::FULL) == work);
+        assert!(a(SnapTarget::LEFT).x2 == a(SnapTarget::RIGHT).x1 && a(SnapTarget::LEFT).union(&a(SnapTarget::RIGHT)) == work);
+        assert!(a(SnapTarget::TOP).y2 == a(SnapTarget::BOTTOM).y1 && a(SnapTarget::TOP).union(&a(SnapTarget::BOTTOM)) == work);
+        assert!(a(SnapTarget::TOP_LEFT).union(&a(SnapTarget::BOTTOM_LEFT)) == a(SnapTarget::LEFT));
+        assert!(a(SnapTarget::TOP_RIGHT).union(&a(SnapTarget::BOTTOM_RIGHT)) == a(SnapTarget::RIGHT));
+        assert!(a(SnapTarget::TOP_LEFT).union(&a(SnapTarget::TOP_RIGHT)) == a(SnapTarget::TOP));
+        assert!(a(SnapTarget::TOP_LEFT).x2 == a(SnapTarget::TOP_RIGHT).x1 && a(SnapTarget::TOP_LEFT).y2 == a(SnapTarget::BOTTOM_LEFT).y1);
+        for t in SnapTarget::ALL {
+            let r = a(t);
+            assert!(r.contained_by(&work) && r.x1 < r.x2 && r.y1 < r.y2);
+            // 半分の幅は切り捨て。残りは右・下の側に付く
+            let (ww, wh) = (work.x2 - work.x1, work.y2 - work.y1);
+            assert!(r.x2 - r.x1 == match t.h { Span::Low => ww / 2, Span::High => ww - ww / 2, Span::Whole => ww });
+            assert!(r.y2 - r.y1 == match t.v { Span::Low => wh / 2, Span::High => wh - wh / 2, Span::Whole => wh });
+
+            // 収まるウィンドウは、画面の端に接する側に寄せ、そうでない向きは真ん中に置く。帯には重ならない
+            let p = t.place(work, 200, 100);
+            assert!(Rect::from_wh(p.x, p.y, 200, 100).contained_by(&work));
+            match t.h {
+                Span::Low => assert!(p.x == work.x1),
+                Span::High => assert!(p.x + 200 == work.x2),
+                Span::Whole => assert!(p.x == work.x1 + (ww - 200) / 2),
+            }
+            match t.v {
+                Span::Low => assert!(p.y == work.y1),
+                Span::High => assert!(p.y + 100 == work.y2),
+                Span::Whole => assert!(p.y == work.y1 + (wh - 100) / 2),
+            }
+            // 吸着先より大きければ作業領域の中に押し戻し、作業領域より大きければ左上
+            let p = t.place(work, ww - 10, 100);
+            assert!(p.x >= work.x1 && p.x + ww - 10 <= work.x2);
+            assert!(t.place(work, 4000, 4000) == Vec2::new(work.x1, work.y1));
+        }
+    }
+    assert!(SnapTarget::LEFT.area(work_area((1024, 768), 20, 0)) == Rect::from_points(0, 20, 512, 768));
+    assert!(SnapTarget::BOTTOM_RIGHT.area(work_area((1024, 768), 20, 32)) == Rect::from_points(512, 378, 1024, 736));
+    let reserved = Edge::Top.reserve((0, 0), 20);
+    let reserved = Edge::Bottom.reserve(Edge::Top.reserve(reserved, 16), 32);
+    assert!(reserved == (20, 32));
+    // 帯が画面より大きくても壊れない
+    assert!(work_area((100, 50), 40, 40) == Rect::from_points(0, 40, 100, 40));
+
+    let screen = Rect::from_wh(0, 0, 1024, 768);
+    // ドラッグしたウィンドウは画面に収まる
+    let size = Rect::from_wh(0, 0, 200, 100);
+    assert!(clamp_window(Vec2::new(10, 20), size, screen) == Vec2::new(10, 20));
+    assert!(clamp_window(Vec2::new(-50, -5), size, screen) == Vec2::new(0, 0));
+    assert!(clamp_window(Vec2::new(900, 700), size, screen) == Vec2::new(824, 668));
+    assert!(clamp_window(Vec2::new(900, 700), Rect::from_wh(0, 0, 2000, 100), screen) == Vec2::new(0, 668));
+
+    // 画面の端と角
+    assert!(edge_target(Vec2::new(500, 400), screen).is_none());
+    assert!(edge_target(Vec2::new(0, 400), screen) == Some(SnapTarget::LEFT));
+    assert!(edge_target(Vec2::new(1024, 400), screen) == Some(SnapTarget::RIGHT));
+    assert!(edge_target(Vec2::new(500, 0), screen) == Some(SnapTarget::FULL));
+    assert!(edge_target(Vec2::new(500, 768), screen) == Some(SnapTarget::BOTTOM));
+    assert!(edge_target(Vec2::new(0, 10), screen) == Some(SnapTarget::TOP_LEFT));
+    assert!(edge_target(Vec2::new(20, 0), screen) == Some(SnapTarget::TOP_LEFT));
+    assert!(edge_target(Vec2::new(1023, 767), screen) == Some(SnapTarget::BOTTOM_RIGHT));
+    assert!(edge_target(Vec2::new(1000, 0), screen) == Some(SnapTarget::TOP_RIGHT));
+
+    // キーボード: 同じキーで戻る。半分から直交する向きで4分の1
+    assert!(next_target(None, SnapTarget::LEFT) == Some(SnapTarget::LEFT));
+    assert!(next_target(Some(SnapTarget::LEFT), SnapTarget::LEFT).is_none());
+    assert!(next_target(Some(SnapTarget::LEFT), SnapTarget::TOP) == Some(SnapTarget::TOP_LEFT));
+    assert!(next_target(Some(SnapTarget::BOTTOM), SnapTarget::RIGHT) == Some(SnapTarget::BOTTOM_RIGHT));
+    assert!(next_target(Some(SnapTarget::LEFT), SnapTarget::RIGHT) == Some(SnapTarget::RIGHT));
+    assert!(next_target(Some(SnapTarget::TOP_LEFT), SnapTarget::BOTTOM) == Some(SnapTarget::BOTTOM));
+    assert!(next_target(Some(SnapTarget::LEFT), SnapTarget::FULL) == Some(SnapTarget::FULL));
+    assert!(next_target(Some(SnapTarget::FULL), SnapTarget::FULL).is_none());
+    assert!(next_target(Some(SnapTarget::FULL), SnapTarget::TOP) == Some(SnapTarget::TOP));
+
+    // 吸着と解除を繰り返しても、戻し先は吸着する直前の位置
+    let mut book = SnapBook::new();
+    for i in 0..5 {
+        let start = Vec2::new(10 * i, 20 + i);
+        book.snap(1, SnapTarget::LEFT, start);
+        // 吸着先を移っても、戻し先は最初のまま
+        book.snap(1, SnapTarget::TOP_LEFT, Vec2::new(0, 20));
+        book.snap(2, SnapTarget::RIGHT, Vec2::new(300 + i, 40));
+        assert!(book.target(1) == Some(SnapTarget::TOP_LEFT) && book.target(2) == Some(SnapTarget::RIGHT));
+        assert!(book.unsnap(1) == Some(start));
+        assert!(book.unsnap(1).is_none() && book.target(1).is_none());
+        assert!(book.unsnap(2) == Some(Vec2::new(300 + i, 40)));
+    }
+    assert!(book.entries.is_empty());
+
+    // 動きは始めと終わりがちょうどで、行き過ぎない
+    let (from, to) = (Vec2::new(100, 50), Vec2::new(-20, 400));
+    assert!(interpolate(from, to, 0, ANIM_FRAMES) == from && interpolate(from, to, ANIM_FRAMES, ANIM_FRAMES) == to);
+    let mut prev = from;
+    for f in 1..=ANIM_FRAMES {
+        let p = interpolate(from, to, f, ANIM_FRAMES);
+        assert!(p.x <= prev.x && p.x >= to.x && p.y >= prev.y && p.y <= to.y);
+        prev = p;
+    }
+}
@@ -1,57 +1,124 @@
-use core::iter::repeat_with;
+use core::{fmt::{self, Write}, iter::repeat_with, sync::atomic::{AtomicUsize, Ordering}};
 
 use alloc::{sync::Arc, vec::Vec};
 
-use crate::memory_manager::{Mutex, RwLock};
-use super::{buffered::BufferedCanvas, frame_buffer::FrameBuffer, graphics::{PixelColor, PixelWriter, Rect, Vec2}};
+use crate::{heap_profile::with_alloc_tag, memory_manager::{Mutex, RwLock}};
+use super::{snap::{work_area, Edge}, buffered::BufferedCanvas, cursor::Cursor, palette, frame_buffer::{FrameBuffer, PixelFormat, RowPixels}, graphics::{PixelColor, PixelWriter, Rect, Vec2}};
 pub struct Window {
     pos: Vec2<i32>,
     width: usize,
     height: usize,
     transparant_color: Option<PixelColor>,
+    /// ウィンドウ全体の不透明度。255なら下のレイヤは透けない
+    opacity: u8,
+    /// タイトルや枠を除いた領域。Noneならウィンドウ全体
+    client_area: Option<Rect>,
+    background: PixelColor,
     buffer: BufferedCanvas
 }
 
 impl Window {
-    pub fn new(width: usize, height: usize) -> Self {
+    /// バッファはbackground(Noneならpalette::WINDOW_BG)で塗られた状態で始まる
+    pub fn new(width: usize, height: usize, background: Option<PixelColor>) -> Self {
+        let background = background.unwrap_or(palette::WINDOW_BG);
         Self {
             pos: (0,0).into(),
             width,
             height,
-            buffer: BufferedCanvas::new(width, height),
+            buffer: with_alloc_tag("window", || BufferedCanvas::new(width, height, background)),
             transparant_color: None,
+            opacity: 255,
+            client_area: None,
+            background,
         }
     }
 
+    pub fn background(&self) -> PixelColor {
+        self.background
+    }
+
+    /// ウィンドウ全体をcolorで塗ってflushする。使い回すときに前の内容を消すのに使う
+    pub fn clear(&self, color: PixelColor) {
+        self.buffer.write_with(|back| {
+            back.fill_rect((0, 0).into(), (self.width as u32, self.height as u32).into(), color);
+        });
+        self.buffer.flush();
+    }
+
+    /// この色の点は描かない(不透明度0として扱う)。マウスカーソルのような形のあるウィンドウに使う
     pub fn set_transparent_color(&mut self, color: Option<PixelColor>) {
         self.transparant_color = color;
     }
 
+    /// ウィンドウ全体を不透明度opacityで下のレイヤに重ねる。表示中なら
+    /// LayeredWindowManager::set_opacityで変えると、次の合成で描き直される
+    pub fn set_opacity(&mut self, opacity: u8) {
+        self.opacity = opacity;
+    }
+
+    pub fn opacity(&self) -> u8 {
+        self.opacity
+    }
+
     #[inline]
     pub fn is_inside(&self, pos: Vec2<i32>) -> bool {
         0 <= pos.x && pos.x < self.width as i32 && 0 <= pos.y && pos.y < self.height as i32
     }
 
-    pub fn draw_to(&self, buf: &mut FrameBuffer) {
+    /// ウィンドウ内の位置posに透過色でない点があるか。半透明でも見えていれば含む
+    pub fn is_opaque_at(&self, pos: Vec2<i32>) -> bool {
+        self.opacity != 0 && self.is_inside(pos) && self.transparant_color.map_or(true, |tc| {
+            self.buffer.with_fore(|fore| fore.color_at(pos.x as usize, pos.y as usize) != tc)
+        })
+    }
+
+    /// ウィンドウ内の位置posが、draw_windowの描く枠のどこにあたるか。ウィンドウの外ならNone
+    pub fn hit_test(&self, pos: Vec2<i32>) -> Option<Hit> {
+        if !self.is_inside(pos) {
+            None
+        } else if close_button_rect(self.width).contains(pos) {
+            Some(Hit::CloseButton)
+        } else if title_bar_rect(self.width).contains(pos) {
+            Some(Hit::TitleBar)
+        } else {
+            Some(Hit::Body)
+        }
+    }
+
+    /// bufに描く。dirty(ウィンドウの座標)があればその範囲だけを描き直す
+    pub fn draw_to(&self, buf: &mut FrameBuffer, dirty: Option<Rect>) {
+        let r_fb = Rect::from_wh(0,0,buf.resolution().0 as i32, buf.resolution().1 as i32);
+        let clip = match dirty {
+            None => r_fb,
+            Some(d) => match d.move_relative(self.pos.x, self.pos.y).intersection(&r_fb) {
+                Some(r) => r,
+                None => return,
+            },
+        };
+        self.draw_to_rect(buf, clip);
+    }
+
+    /// bufのclipの範囲だけに描く。透過色も不透明度もなければ行ごとのコピーで済ませる
+    pub fn draw_to_rect(&self, buf: &mut FrameBuffer, clip: Rect) {
         self.buffer.with_fore(|fore|{
             match self.transparant_color {
                 None => {
-                    buf.copy(self.pos, fore)
+                    buf.blend_rect(self.pos, fore, clip, self.opacity)
                 }
                 Some(tc) => {
-                    let r_window = Rect::from_wh(self.pos.x, self.pos.y, self.width as i32, self.height as i32);
+                    let r_window = self.rect();
                     let r_fb = Rect::from_wh(0,0,buf.resolution().0 as i32, buf.resolution().1 as i32);
-                    let r_draw = match r_fb.intersection(&r_window).map(|r|r.move_relative(-self.pos.x, -self.pos.y)) {
+                    let r_draw = match r_fb.intersection(&clip).and_then(|r| r.intersection(&r_window)).map(|r|r.move_relative(-self.pos.x, -self.pos.y)) {
                         None => return,
                         Some(r) => r
                     }; 
 
+                    let x1 = r_draw.x1 as usize;
                     for y in r_draw.y1 as usize..r_draw.y2 as usize {
-                        for x in r_draw.x1 as usize..r_draw.x2 as usize {
-                            let pixel = fore.color_at(x, y);
-                            if pixel != tc {
-                                buf.write(self.pos + (x as i32, y as i32).into(), pixel);
-                            }
+                        for (i, pixel) in fore.row_pixels(y, x1..r_draw.x2 as usize).enumerate() {
+                            // 透過色は不透明度0の点。writeは不透明度0の点を書かず、半端なら重ねる
+                            let alpha = if pixel == tc { 0 } else { self.opacity };
+                            buf.write(self.pos + ((x1 + i) as i32, y as i32).into(), pixel.with_alpha(alpha));
                         }
                     }
                 }
@@ -79,6 +146,11 @@ impl Window {
         self.pos
     }
 
+    /// 画面上で占める矩形
+    pub fn rect(&self) -> Rect {
+        Rect::from_wh(self.pos.x, self.pos.y, self.width as i32, self.height as i32)
+    }
+
     pub fn width(&self) -> usize {
         self.width
     }
@@ -90,10 +162,96 @@ impl Window {
     pub fn buffer(&self) -> &BufferedCanvas {
         &self.buffer
     }
+
+    pub fn set_client_area(&mut self, rect: Option<Rect>) {
+        self.client_area = rect;
+    }
+
+    /// ウィンドウ座標でのクライアント領域
+    pub fn client_area(&self) -> Rect {
+        let whole = Rect::from_wh(0, 0, self.width as i32, self.height as i32);
+        self.client_area.and_then(|r| r.intersection(&whole)).unwrap_or(whole)
+    }
+
+    /// foreのクライアント領域のうちrect(クライアント座標、Noneなら全体)の部分を、
+    /// 行の詰め物のないRGBの列として取り出す。rectはクライアント領域で切り取られる。
+    /// 画面外にはみ出した部分もウィンドウ自身のバッファから読むので、画面上の位置によらない
+    pub fn capture_client(&self, rect: Option<Rect>) -> Vec<u8> {
+        let client = self.client_area();
+        let rect = match rect {
+            None => client,
+            Some(r) => match r.move_relative(client.x1, client.y1).intersection(&client) {
+                Some(r) => r,
+                None => return Vec::new(),
+            },
+        };
+
+        // foreのロックはコピーの間だけ持ち、色の並べ替えはロックの外で行う
+        let (raw, format) = self.buffer.with_fore(|fore| (fore.read_raw(rect), fore.pixel_format()));
+        raw_to_rgb(&raw, format)
+    }
+}
+
+fn raw_to_rgb(raw: &[u8], format: PixelFormat) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(raw.len() / format.bytes_per_pixel() * 3);
+    for c in RowPixels::new(raw, format) {
+        rgb.extend_from_slice(&[c.r, c.g, c.b]);
+    }
+    rgb
+}
+
+/// draw_windowの描く枠のうち、クリックされた部分
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hit {
+    TitleBar,
+    CloseButton,
+    Body,
+}
+
+/// 幅widthのウィンドウでdraw_windowがタイトルバーを描く範囲
+pub fn title_bar_rect(width: usize) -> Rect {
+    Rect::from_wh(3, 3, (width as i32 - 6).max(0), 18)
+}
+
+/// 閉じるボタンの範囲。タイトルバーの右端に置く
+pub fn close_button_rect(width: usize) -> Rect {
+    Rect::from_wh(width as i32 - 21, 5, 16, 14)
 }
 
 pub type LayerId = usize;
 
+/// 閉じられたレイヤのIDが使われた
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleLayerId(pub LayerId);
+
+/// 閉じられたレイヤのIDで検索された回数
+static STALE_HITS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn stale_id_hits() -> usize {
+    STALE_HITS.load(Ordering::Relaxed)
+}
+
+/// new_layerで作ったレイヤを最初にどこに置くか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// 普通のレイヤの一番上。常に手前に置くレイヤ(マウスカーソルなど)よりは下
+    TopMost,
+    /// 普通のレイヤの一番下。常に奥に置くレイヤ(コンソール)のすぐ上
+    AboveConsole,
+    /// 表示しない。raise_to_topなどで後から出す
+    Hidden,
+}
+
+/// 重なり順の帯。レイヤは自分の帯の中でだけ上げ下げでき、下の帯のレイヤが上の帯のレイヤより上に来ることはない
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Band {
+    /// set_always_on_bottom
+    Bottom,
+    Normal,
+    /// set_always_on_top
+    Top,
+}
+
 pub struct LayerHandle {
     window: Arc<RwLock<Window>>,
     layer_id: LayerId
@@ -109,12 +267,127 @@ impl LayerHandle {
     }
 }
 
+/// 合成する中身のスナップショット。重なり順とウィンドウのArcを複製して持つので、
+/// 描いている間にレイヤの順番が変わったり閉じられたりしても影響を受けない
+pub struct Frame {
+    layers: Vec<Arc<RwLock<Window>>>,
+    damage: Vec<Rect>,
+}
+
+/// 次の合成で描き直す範囲。重なる範囲はまとめ、MAX_RECTSを超えたら広がりの一番少ない組をまとめる。
+/// 離れた所で同時に起きた変更(カーソルの移動と時計の更新など)を、その間ごと描き直さずに済む
+struct Damage {
+    rects: Vec<Rect>,
+}
+
+impl Damage {
+    const MAX_RECTS: usize = 8;
+
+    const fn new() -> Self {
+        Self { rects: Vec::new() }
+    }
+
+    fn area(r: &Rect) -> i64 {
+        (r.x2 - r.x1) as i64 * (r.y2 - r.y1) as i64
+    }
+
+    fn add(&mut self, rect: Rect) {
+        if rect.x1 >= rect.x2 || rect.y1 >= rect.y2 {
+            return;
+        }
+        let mut rect = rect;
+        // まとめた結果がさらに別の範囲と重なることがあるので、重なりが無くなるまで繰り返す
+        while let Some(i) = self.rects.iter().position(|r| r.intersection(&rect).is_some()) {
+            rect = rect.union(&self.rects.swap_remove(i));
+        }
+        self.rects.push(rect);
+        if self.rects.len() > Self::MAX_RECTS {
+            let n = self.rects.len();
+            let (i, j) = (0..n)
+                .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+                .min_by_key(|&(i, j)| {
+                    let (a, b) = (&self.rects[i], &self.rects[j]);
+                    Self::area(&a.union(b)) - Self::area(a) - Self::area(b)
+                })
+                .unwrap();
+            let b = self.rects.swap_remove(j);
+            let a = self.rects.swap_remove(i);
+            self.add(a.union(&b));
+        }
+    }
+
+    /// 画面の範囲に切り詰めて取り出す
+    fn take(&mut self, screen: Rect) -> Vec<Rect> {
+        let mut rects = core::mem::take(&mut self.rects);
+        rects.retain_mut(|r| match r.intersection(&screen) {
+            Some(c) => {
+                *r = c;
+                true
+            }
+            None => false,
+        });
+        rects
+    }
+}
+
+/// 合成先。LayeredWindowManagerのロックを放したまま描けるように分けてある
+pub struct Screen {
+    shadow: FrameBuffer,
+    buffer: FrameBuffer,
+    /// shadowには入れず、bufferにだけ最後に描く
+    cursor: Cursor,
+}
+
+impl Screen {
+    /// スナップショットのdamageの範囲を下のレイヤから描いて画面に出し、重なったカーソルを描き直す
+    pub fn render(&mut self, frame: &Frame) {
+        let cursor = self.cursor.rect();
+        let covers_cursor = frame.damage.iter().any(|r| r.intersection(&cursor).is_some());
+        if covers_cursor {
+            self.cursor.hide(&mut self.buffer);
+        }
+        for rect in &frame.damage {
+            for win in &frame.layers {
+                win.read().draw_to_rect(&mut self.shadow, *rect);
+            }
+            self.buffer.copy_rect((0,0).into(), &self.shadow, *rect);
+        }
+        if covers_cursor {
+            self.cursor.show(&mut self.buffer);
+        }
+    }
+
+    /// 画面(合成済みの結果)のrectの部分をRGBの列として読む
+    pub fn capture(&self, rect: Rect) -> Vec<u8> {
+        let (w, h) = self.buffer.resolution();
+        let Some(rect) = rect.intersection(&Rect::from_wh(0, 0, w as i32, h as i32)) else {
+            return Vec::new();
+        };
+        raw_to_rgb(&self.buffer.read_raw(rect), self.buffer.pixel_format())
+    }
+}
+
 /// 複数のウィンドウを層状に並べて管理・描画する
+/// レイヤを閉じてもIDは再利用しないので、古いIDが別のウィンドウを指すことはない
+///
+/// 順序の保証: prepare_frameより前に行った変更(重なり順・移動・表示・閉じる)はそのフレームに出る。
+/// スナップショットを取った後の変更は描画中のフレームには影響せず、damageに積まれて次のフレームで出る。
+/// ウィンドウのflushも同じで、スナップショットの後のflushは次のフレームで必ず描かれる
 pub struct LayeredWindowManager {
-    layers: Vec<Arc<RwLock<Window>>>,
+    /// 閉じたレイヤはNone
+    layers: Vec<Option<Arc<RwLock<Window>>>>,
+    /// レイヤごとの帯。layersと同じくIDで引く
+    bands: Vec<Band>,
+    /// 表示中のレイヤを下から順に並べたもの。帯の順に並んでいる
     layer_stack: Vec<LayerId>,
-    shadow: FrameBuffer,
-    buffer: FrameBuffer
+    /// new_layer_deferredで作られ、まだ一度もflushされていないレイヤ。重ねる順番が決まっていても画面には出さない
+    waiting_flush: Vec<LayerId>,
+    /// 次の合成で描き直す画面上の範囲。レイヤの移動や重なり順の変更で増える
+    damage: Damage,
+    resolution: (u32, u32),
+    /// 画面の上端と下端に確保された帯の高さ。吸着したウィンドウはここに重ならない
+    reserved: (i32, i32),
+    screen: Arc<Mutex<Screen>>,
 }
 
 impl LayeredWindowManager {
@@ -122,64 +395,480 @@ impl LayeredWindowManager {
         let (width, height) = buffer.resolution();
         Self {
             layers: Vec::new(),
+            bands: Vec::new(),
             layer_stack: Vec::new(),
-            shadow: FrameBuffer::new(width as usize, height as usize),
-            buffer
+            waiting_flush: Vec::new(),
+            damage: Damage::new(),
+            resolution: (width, height),
+            reserved: (0, 0),
+            screen: Arc::new(Mutex::new(Screen { shadow: FrameBuffer::new(width as usize, height as usize), buffer, cursor: Cursor::new() })),
         }
     }
 
-    pub fn new_layer(&mut self, window: Window) -> LayerHandle {
+    pub fn new_layer(&mut self, window: Window, placement: Placement) -> LayerHandle {
         let arc = Arc::new(RwLock::new(window));
-        self.layers.push(arc.clone());
-        LayerHandle { layer_id: self.layers.len()-1, window: arc}
+        self.layers.push(Some(arc.clone()));
+        self.bands.push(Band::Normal);
+        let layer_id = self.layers.len() - 1;
+        match placement {
+            Placement::TopMost => self.place(layer_id, |_| usize::MAX),
+            Placement::AboveConsole => self.place(layer_id, |_| 0),
+            Placement::Hidden => {}
+        }
+        LayerHandle { layer_id, window: arc }
+    }
+
+    /// new_layerと同じだが、ウィンドウが最初にflushされるまでは表示しない。
+    /// 描き終わる前のウィンドウが1フレームだけ見えるのを防ぐ。アプリのウィンドウはこちらで作る
+    pub fn new_layer_deferred(&mut self, window: Window, placement: Placement) -> LayerHandle {
+        // 重ねる前に待ちに入れておき、まだ描いていない範囲を描き直さないようにする
+        self.waiting_flush.push(self.layers.len());
+        self.new_layer(window, placement)
+    }
+
+    /// 画面に出してよいレイヤか。最初のflushを待っているものは出さない
+    fn is_shown(&self, id: LayerId) -> bool {
+        !self.waiting_flush.contains(&id)
     }
 
-    pub fn move_to(&mut self, id: LayerId, pos: Vec2<i32>) {
-        self.layers[id].write().move_to(pos);
+    /// レイヤを取り除く。ウィンドウ自体はLayerHandleが残っている間は生きている
+    pub fn close_layer(&mut self, id: LayerId) -> Result<(), StaleLayerId> {
+        self.layer_checked(id)?;
+        self.hide(id);
+        self.waiting_flush.retain(|lid| *lid != id);
+        self.layers[id] = None;
+        Ok(())
     }
 
-    pub fn move_relative(&mut self, id: LayerId, pos_diff: Vec2<i32>) {
-        self.layers[id].write().move_relative(pos_diff);
+    /// 閉じたレイヤや存在しないIDならNone
+    pub fn layer(&self, id: LayerId) -> Option<&Arc<RwLock<Window>>> {
+        self.layer_checked(id).ok()
     }
 
+    fn layer_checked(&self, id: LayerId) -> Result<&Arc<RwLock<Window>>, StaleLayerId> {
+        match self.layers.get(id) {
+            Some(Some(layer)) => Ok(layer),
+            _ => {
+                STALE_HITS.fetch_add(1, Ordering::Relaxed);
+                Err(StaleLayerId(id))
+            }
+        }
+    }
+
+    pub fn move_to(&mut self, id: LayerId, pos: Vec2<i32>) -> Result<(), StaleLayerId> {
+        self.damage_layer(id);
+        self.layer_checked(id)?.write().move_to(pos);
+        self.damage_layer(id);
+        Ok(())
+    }
+
+    /// レイヤ全体の不透明度を変えて、次の合成で描き直す
+    pub fn set_opacity(&mut self, id: LayerId, opacity: u8) -> Result<(), StaleLayerId> {
+        self.layer_checked(id)?.write().set_opacity(opacity);
+        self.damage_layer(id);
+        Ok(())
+    }
+
+    pub fn move_relative(&mut self, id: LayerId, pos_diff: Vec2<i32>) -> Result<(), StaleLayerId> {
+        self.damage_layer(id);
+        self.layer_checked(id)?.write().move_relative(pos_diff);
+        self.damage_layer(id);
+        Ok(())
+    }
+
+    fn add_damage(&mut self, rect: Rect) {
+        self.damage.add(rect);
+        super::request_redraw();
+    }
+
+    /// 表示中のレイヤが今いる範囲を次の合成で描き直す
+    fn damage_layer(&mut self, id: LayerId) {
+        if !self.layer_stack.contains(&id) || !self.is_shown(id) {
+            return;
+        }
+        if let Some(Some(win)) = self.layers.get(id) {
+            let rect = win.read().rect();
+            self.add_damage(rect);
+        }
+    }
+
+    /// 画面全体を描き直す
     pub fn draw(&mut self) {
-        for id in &self.layer_stack {
-            let win = self.layers[*id].read();
-            if win.buffer().is_updated() {
-                win.draw_to(&mut self.shadow);
+        let (w, h) = self.resolution;
+        self.draw_rect(Rect::from_wh(0, 0, w as i32, h as i32));
+    }
+
+    /// 画面上のrectの範囲を、そこに重なるレイヤを下から描き直して画面に出す。溜まっていた変更も一緒に出る
+    pub fn draw_rect(&mut self, rect: Rect) {
+        self.add_damage(rect);
+        self.compose();
+    }
+
+    /// レイヤが今いる範囲を描き直す。動かした後なら、動かす前の範囲もmove_toが積んでいるので一緒に出る
+    pub fn draw_layer(&mut self, id: LayerId) -> Result<(), StaleLayerId> {
+        self.layer_checked(id)?;
+        self.damage_layer(id);
+        self.compose();
+        Ok(())
+    }
+
+    /// 更新フラグの立った表示中のレイヤと、移動などで変わった範囲だけを合成して画面に出し、フラグを下ろす。
+    /// 何も変わっていなければ何もせずfalseを返す。フレームごとの合成はgraphic::compose_frameで、
+    /// このロックを放してから描く
+    pub fn compose(&mut self) -> bool {
+        let Some(frame) = self.prepare_frame() else {
+            return false;
+        };
+        self.screen.lock().render(&frame);
+        true
+    }
+
+    /// 合成先。prepare_frameで取ったスナップショットを、このマネージャのロックを放してから描くのに使う
+    pub fn screen(&self) -> Arc<Mutex<Screen>> {
+        self.screen.clone()
+    }
+
+    /// 次のフレームで描くもののスナップショットを取り、damageと更新フラグを下ろす。何も変わっていなければNone
+    pub fn prepare_frame(&mut self) -> Option<Frame> {
+        // flushされたレイヤはここから表示する。フラグはまだ立っているので、下でその範囲が描かれる
+        let layers = &self.layers;
+        self.waiting_flush.retain(|id| !matches!(layers.get(*id), Some(Some(win)) if win.read().buffer().is_updated()));
+
+        for id in self.layer_stack.iter().filter(|id| !self.waiting_flush.contains(id)) {
+            // 閉じたレイヤはlayer_stackから外しているので、ここで見つからないことはないはず
+            let Some(Some(win)) = self.layers.get(*id) else {
+                STALE_HITS.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            let win = win.read();
+            if let Some(dirty) = win.buffer().take_dirty() {
+                let pos = win.pos();
+                if let Some(rect) = dirty.move_relative(pos.x, pos.y).intersection(&win.rect()) {
+                    self.damage.add(rect);
+                }
             }
         }
-        self.buffer.copy((0,0).into(), &self.shadow);
+        let (w, h) = self.resolution;
+        let damage = self.damage.take(Rect::from_wh(0, 0, w as i32, h as i32));
+        if damage.is_empty() {
+            return None;
+        }
+
+        let layers = self.layer_stack.iter()
+            .filter(|id| !self.waiting_flush.contains(id))
+            .filter_map(|id| self.layers.get(*id).cloned().flatten())
+            .collect();
+        Some(Frame { layers, damage })
+    }
+
+    /// 画面(合成済みの結果)のrectの部分をRGBの列として読む
+    pub fn capture_screen(&self, rect: Rect) -> Vec<u8> {
+        self.screen.lock().capture(rect)
+    }
+
+    /// マウスカーソルの絵を変えて表示する。形式はmouse::MOUSE_CURSOR_SHAPEと同じ。
+    /// カーソルはレイヤではなく、どのレイヤよりも手前に描かれる
+    pub fn set_cursor_bitmap(&mut self, shape: &[&str]) {
+        let mut screen = self.screen.lock();
+        let Screen { buffer, cursor, .. } = &mut *screen;
+        cursor.set_bitmap(buffer, shape);
+        cursor.show(buffer);
+    }
+
+    /// マウスカーソルをすぐに動かす。下の画素を戻して描き直すだけで、レイヤの合成は待たない
+    pub fn move_cursor(&mut self, pos: Vec2<i32>) {
+        let mut screen = self.screen.lock();
+        let Screen { buffer, cursor, .. } = &mut *screen;
+        cursor.move_to(buffer, pos);
+    }
+
+    pub fn cursor_pos(&self) -> Vec2<i32> {
+        self.screen.lock().cursor.pos()
     }
 
     pub fn hide(&mut self, id: LayerId) {
+        self.damage_layer(id);
         self.layer_stack.retain(|lid| *lid != id);
     }
 
-    pub fn up_down(&mut self, id: usize, new_height: i32) {
-        if new_height < 0 {
-            self.hide(id);
-            return;
+    /// レイヤを自分の帯の一番上に出す。隠していたレイヤもこれで表示する
+    pub fn raise_to_top(&mut self, id: LayerId) -> Result<(), StaleLayerId> {
+        self.layer_checked(id)?;
+        self.place(id, |_| usize::MAX);
+        Ok(())
+    }
+
+    /// レイヤを自分の帯の一番下に出す
+    pub fn lower_to_bottom(&mut self, id: LayerId) -> Result<(), StaleLayerId> {
+        self.layer_checked(id)?;
+        self.place(id, |_| 0);
+        Ok(())
+    }
+
+    /// レイヤをotherのすぐ上に出す。otherが表示されていなければ帯の一番上。
+    /// otherが別の帯にいれば、自分の帯の中でできるだけ近い所に置く
+    pub fn move_above(&mut self, id: LayerId, other: LayerId) -> Result<(), StaleLayerId> {
+        self.layer_checked(id)?;
+        self.layer_checked(other)?;
+        self.place(id, |stack| stack.iter().position(|lid| *lid == other).map_or(usize::MAX, |h| h + 1));
+        Ok(())
+    }
+
+    /// レイヤをotherのすぐ下に出す。otherが表示されていなければ帯の一番上
+    pub fn move_below(&mut self, id: LayerId, other: LayerId) -> Result<(), StaleLayerId> {
+        self.layer_checked(id)?;
+        self.layer_checked(other)?;
+        self.place(id, |stack| stack.iter().position(|lid| *lid == other).unwrap_or(usize::MAX));
+        Ok(())
+    }
+
+    /// 常に普通のレイヤより手前に置く。インジケータなどに使うので、後から作ったウィンドウに隠れない。
+    /// 表示中なら、付けたときは手前の帯の一番上に、外したときは普通の帯の一番上に移る
+    pub fn set_always_on_top(&mut self, id: LayerId, on: bool) -> Result<(), StaleLayerId> {
+        self.set_band(id, Band::Top, on)
+    }
+
+    /// 常に普通のレイヤより奥に置く。画面全体を覆うコンソールに使う。
+    /// 表示中なら、付けたときは奥の帯の一番下に、外したときは普通の帯の一番下に移る
+    pub fn set_always_on_bottom(&mut self, id: LayerId, on: bool) -> Result<(), StaleLayerId> {
+        self.set_band(id, Band::Bottom, on)
+    }
+
+    fn set_band(&mut self, id: LayerId, band: Band, on: bool) -> Result<(), StaleLayerId> {
+        self.layer_checked(id)?;
+        let old = self.bands[id];
+        let new = match (on, old == band) {
+            (true, _) => band,
+            (false, true) => Band::Normal,
+            // 別の帯に付いているものは外さない
+            (false, false) => old,
+        };
+        if new == old {
+            return Ok(());
         }
+        self.bands[id] = new;
+        if self.height_of(id).is_some() {
+            // 外したときは元の帯に近い側の端に置くので、見た目の前後は変わらない
+            let top = if on { band == Band::Top } else { old == Band::Top };
+            self.place(id, |_| if top { usize::MAX } else { 0 });
+        }
+        Ok(())
+    }
 
-        let new_height = (new_height as usize).min(self.layer_stack.len());
-        match self.layer_stack.iter().find(|&&lid| lid == id) {
-            None => {
-                self.layer_stack.insert(
-                    new_height,
-                    id
-                );
-            }
-            Some(layer) => {
-                let layer = layer.clone();
-                self.hide(id);
-                let new_height = new_height.min(self.layer_stack.len());
-                self.layer_stack.insert(new_height, layer);
-            }
+    /// layer_stackの中で、bandのレイヤを入れられる位置の範囲(両端を含む)
+    fn band_range(&self, band: Band) -> (usize, usize) {
+        let band_of = |id: &&LayerId| self.bands.get(**id).copied().unwrap_or(Band::Normal);
+        let start = self.layer_stack.iter().take_while(|id| band_of(id) < band).count();
+        let len = self.layer_stack[start..].iter().take_while(|id| band_of(id) == band).count();
+        (start, start + len)
+    }
+
+    /// レイヤを一度外し、外した後のlayer_stackでatが返す位置に入れ直す。位置は自分の帯の中に収める
+    fn place(&mut self, id: LayerId, at: impl FnOnce(&[LayerId]) -> usize) {
+        self.hide(id);
+        let (start, end) = self.band_range(self.bands[id]);
+        let height = at(&self.layer_stack).clamp(start, end);
+        self.layer_stack.insert(height, id);
+        self.damage_layer(id);
+    }
+
+    /// 表示中のレイヤの下からの位置。表示されていなければNone
+    pub fn height_of(&self, id: LayerId) -> Option<usize> {
+        self.layer_stack.iter().position(|lid| *lid == id)
+    }
+
+    /// 重なり順(下から)に、レイヤのIDと位置と大きさを1行ずつ書く。まだ表示していないものには印を付ける
+    pub fn write_stack(&self, out: &mut dyn Write) -> fmt::Result {
+        for id in &self.layer_stack {
+            let Some(Some(win)) = self.layers.get(*id) else {
+                continue;
+            };
+            let win = win.read();
+            let p = win.pos();
+            let waiting = if self.is_shown(*id) { "" } else { " (waiting flush)" };
+            let band = match self.bands[*id] {
+                Band::Bottom => " (always on bottom)",
+                Band::Normal => "",
+                Band::Top => " (always on top)",
+            };
+            writeln!(out, "{id:>3}: ({}, {}) {}x{}{band}{waiting}", p.x, p.y, win.width(), win.height())?;
         }
+        Ok(())
+    }
+
+    /// posを含む表示中のレイヤのうち、predを満たす最も上のもの。透過色の点は含まないものとする
+    pub fn find_layer(&self, pos: Vec2<i32>, pred: impl Fn(LayerId) -> bool) -> Option<LayerId> {
+        self.layer_stack.iter().rev().copied().filter(|id| self.is_shown(*id)).find(|id| {
+            let Some(Some(win)) = self.layers.get(*id) else {
+                return false;
+            };
+            let win = win.read();
+            let p = win.pos();
+            pred(*id) && win.is_opaque_at((pos.x - p.x, pos.y - p.y).into())
+        })
+    }
+
+    /// 画面上の位置posに見えているレイヤ
+    pub fn layer_at(&self, pos: Vec2<i32>) -> Option<LayerId> {
+        self.find_layer(pos, |_| true)
     }
 
     pub fn resolution(&self) -> (u32, u32) {
-        self.buffer.resolution()
+        self.resolution
+    }
+
+    /// ステータスバーなどのために画面の端に高さsizeの帯を確保する。同じ端に複数あれば高い方に合わせる
+    pub fn reserve(&mut self, edge: Edge, size: i32) {
+        self.reserved = edge.reserve(self.reserved, size);
+    }
+
+    /// 確保された帯を除いた、ウィンドウを並べるための領域
+    pub fn work_area(&self) -> Rect {
+        work_area(self.resolution, self.reserved.0, self.reserved.1)
+    }
+}
+
+pub fn run_window_tests() {
+    // 重なるものだけがまとまり、離れたものは別々に残る
+    let mut d = Damage::new();
+    d.add(Rect::from_wh(0, 0, 10, 10));
+    d.add(Rect::from_wh(100, 100, 10, 10));
+    d.add(Rect::from_wh(5, 5, 10, 10));
+    d.add(Rect::from_wh(3, 3, 0, 5));
+    assert!(d.rects.len() == 2 && d.rects.contains(&Rect::from_points(0, 0, 15, 15)));
+    // 2つをつなぐ範囲が来ると、まとめた結果がもう1つとも重なる
+    d.add(Rect::from_points(12, 12, 102, 102));
+    assert!(d.rects == [Rect::from_points(0, 0, 110, 110)]);
+    // 数が増えすぎたら近いものからまとめる
+    let mut d = Damage::new();
+    for i in 0..Damage::MAX_RECTS as i32 {
+        d.add(Rect::from_wh(100 * i, 0, 4, 4));
     }
+    d.add(Rect::from_wh(6, 0, 4, 4));
+    assert!(d.rects.len() == Damage::MAX_RECTS && d.rects.contains(&Rect::from_wh(0, 0, 10, 4)));
+    let rects = d.take(Rect::from_wh(0, 0, 250, 2));
+    assert!(rects.len() == 3 && rects.iter().all(|r| r.y2 == 2 && r.x2 <= 250) && d.rects.is_empty());
+
+    // flushは書いた範囲だけを写して合成を依頼する。何も書かずにflushしたらキャンバス全体
+    let mut small = Window::new(6, 4, Some(palette::WHITE));
+    small.buffer().write_with(|back| back.write((1, 2).into(), palette::BLACK));
+    small.buffer().flush();
+    assert!(small.buffer().take_dirty() == Some(Rect::from_wh(1, 2, 1, 1)) && small.buffer().take_dirty().is_none());
+    small.buffer().flush();
+    assert!(small.buffer().take_dirty() == Some(Rect::from_wh(0, 0, 6, 4)));
+    // draw_toはdirtyを渡せばその範囲だけを描く
+    small.move_to((2, 1).into());
+    small.buffer().write_with(|back| back.fill_rect((0, 0).into(), (6, 4).into(), palette::BLACK));
+    small.buffer().flush();
+    let mut target = FrameBuffer::new(8, 8);
+    target.fill_rect((0, 0).into(), (8, 8).into(), palette::WHITE);
+    small.draw_to(&mut target, Some(Rect::from_wh(1, 1, 2, 1)));
+    assert!(target.row_pixels(2, 0..8).eq([palette::WHITE, palette::WHITE, palette::WHITE, palette::BLACK, palette::BLACK, palette::WHITE, palette::WHITE, palette::WHITE]));
+    assert!(target.row_pixels(1, 0..8).all(|c| c == palette::WHITE));
+    small.draw_to(&mut target, None);
+    assert!(target.color_at(7, 4) == palette::BLACK && target.color_at(1, 1) == palette::WHITE);
+
+    // 枠より小さいウィンドウでも、はみ出した部分を描かないだけで止まらない
+    for (w, h) in [(0, 0), (1, 1), (5, 21), (30, 10)] {
+        crate::draw_window(&mut FrameBuffer::new(w, h), b"tiny");
+    }
+
+    // 小さな画面で、透過色のあるカーソルを背景の上で動かす
+    let mut l = LayeredWindowManager::new(FrameBuffer::new(64, 48));
+    let _bg = l.new_layer(Window::new(64, 48, Some(palette::WINDOW_GRAY)), Placement::TopMost);
+    let mut cursor = Window::new(4, 4, Some(palette::TRANSPARENT_KEY));
+    cursor.set_transparent_color(Some(palette::TRANSPARENT_KEY));
+    cursor.buffer().write_with(|back| back.fill_rect((0, 0).into(), (2, 2).into(), palette::WINDOW_OUTLINE));
+    cursor.buffer().flush();
+    let cursor = l.new_layer(cursor, Placement::TopMost);
+    let _ = l.set_always_on_top(cursor.layer_id(), true);
+    let clock = l.new_layer(Window::new(8, 4, Some(palette::WINDOW_GRAY)), Placement::TopMost);
+    let _ = l.move_to(cursor.layer_id(), (10, 10).into());
+    let _ = l.move_to(clock.layer_id(), (56, 0).into());
+    l.draw();
+    let pixel = |l: &LayeredWindowManager, x: i32, y: i32| -> (u8, u8, u8) {
+        let rgb = l.capture_screen(Rect::from_wh(x, y, 1, 1));
+        (rgb[0], rgb[1], rgb[2])
+    };
+    let (gray, black) = (palette::WINDOW_GRAY.into(), palette::WINDOW_OUTLINE.into());
+    assert!(pixel(&l, 10, 10) == black && pixel(&l, 12, 12) == gray);
+
+    // 動かすと、前と後のカーソルの範囲と、その間に描き換わった時計の範囲だけを描き直す
+    let _ = l.move_to(cursor.layer_id(), (40, 30).into());
+    clock.window().read().buffer().write_with(|back| back.fill_rect((0, 0).into(), (8, 4).into(), palette::WINDOW_OUTLINE));
+    clock.window().read().buffer().flush();
+    let frame = l.prepare_frame().unwrap();
+    assert!(frame.damage.len() == 3);
+    for r in [Rect::from_wh(10, 10, 4, 4), Rect::from_wh(40, 30, 4, 4), Rect::from_wh(56, 0, 8, 4)] {
+        assert!(frame.damage.contains(&r));
+    }
+    l.screen().lock().render(&frame);
+    // 跡は残らず、透過色の部分には下の背景が見える
+    assert!(pixel(&l, 10, 10) == gray && pixel(&l, 11, 11) == gray);
+    assert!(pixel(&l, 40, 30) == black && pixel(&l, 43, 33) == gray && pixel(&l, 60, 2) == black);
+    assert!(l.prepare_frame().is_none());
+
+    // draw_layerは今いる範囲だけを描く
+    let _ = l.move_relative(cursor.layer_id(), (-20, -20).into());
+    assert!(l.draw_layer(cursor.layer_id()).is_ok());
+    assert!(pixel(&l, 20, 10) == black && pixel(&l, 40, 30) == gray);
+    l.draw_rect(Rect::from_wh(0, 0, 1, 1));
+    assert!(l.prepare_frame().is_none());
+    let _ = l.close_layer(clock.layer_id());
+    assert!(l.draw_layer(clock.layer_id()) == Err(StaleLayerId(clock.layer_id())));
+
+    // 半透明のレイヤは下の色と混ざる。不透明度0なら見えず、クリックも下に通す
+    let mut glass = Window::new(4, 4, Some(palette::BLACK));
+    glass.move_to((30, 0).into());
+    let glass = l.new_layer(glass, Placement::TopMost);
+    let _ = l.set_opacity(glass.layer_id(), 128);
+    l.compose();
+    let mixed: (u8, u8, u8) = palette::BLACK.with_alpha(128).over(palette::WINDOW_GRAY).into();
+    assert!(pixel(&l, 31, 1) == mixed && l.layer_at((31, 1).into()) == Some(glass.layer_id()));
+    let _ = l.set_opacity(glass.layer_id(), 0);
+    l.compose();
+    assert!(pixel(&l, 31, 1) == gray && l.layer_at((31, 1).into()) != Some(glass.layer_id()));
+
+    // カーソルはどのレイヤよりも手前に描かれ、動かすだけなら合成は要らない
+    l.set_cursor_bitmap(&["@@", "@@"]);
+    l.move_cursor((50, 40).into());
+    assert!(pixel(&l, 50, 40) == black && l.cursor_pos() == (50, 40).into() && l.prepare_frame().is_none());
+    let mut top = Window::new(8, 8, Some(palette::WHITE));
+    top.move_to((48, 38).into());
+    let _top = l.new_layer(top, Placement::TopMost);
+    l.compose();
+    let white = palette::WHITE.into();
+    assert!(pixel(&l, 51, 41) == black && pixel(&l, 52, 42) == white);
+    l.move_cursor((0, 40).into());
+    assert!(pixel(&l, 50, 40) == white && pixel(&l, 0, 40) == black && l.prepare_frame().is_none());
+
+    // 重なり順は帯の中でだけ変わる。常に手前のカーソルは後から作ったウィンドウにも隠れない
+    let mut l = LayeredWindowManager::new(FrameBuffer::new(16, 16));
+    let new = |l: &mut LayeredWindowManager, placement: Placement| l.new_layer(Window::new(1, 1, None), placement).layer_id();
+    let console = new(&mut l, Placement::AboveConsole);
+    let _ = l.set_always_on_bottom(console, true);
+    let cursor = new(&mut l, Placement::TopMost);
+    let _ = l.set_always_on_top(cursor, true);
+    let a = new(&mut l, Placement::TopMost);
+    let b = new(&mut l, Placement::AboveConsole);
+    let hidden = new(&mut l, Placement::Hidden);
+    assert!(l.layer_stack == [console, b, a, cursor] && l.height_of(hidden).is_none());
+    let _ = l.raise_to_top(b);
+    assert!(l.layer_stack == [console, a, b, cursor]);
+    let _ = l.move_above(hidden, cursor);
+    let _ = l.move_below(a, b);
+    assert!(l.layer_stack == [console, a, b, hidden, cursor]);
+    let _ = l.move_below(cursor, console);
+    let _ = l.lower_to_bottom(b);
+    let _ = l.raise_to_top(console);
+    assert!(l.layer_stack == [console, b, a, hidden, cursor]);
+    // 外すと元の帯に近い端に残り、その後は普通に上げ下げできる
+    let _ = l.set_always_on_top(cursor, false);
+    let _ = l.raise_to_top(a);
+    assert!(l.layer_stack == [console, b, hidden, cursor, a]);
+    let _ = l.set_always_on_bottom(console, false);
+    let _ = l.move_above(console, a);
+    assert!(l.layer_stack == [b, hidden, cursor, a, console]);
+    let _ = l.close_layer(a);
+    assert!(l.move_above(b, a) == Err(StaleLayerId(a)) && l.layer_stack == [b, hidden, cursor, console]);
 }
@@ -0,0 +1,162 @@
+#[cfg(feature = "heap-profile")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::introspect;
+
+#[cfg(feature = "heap-profile")]
+use crate::{memory_manager::Mutex, task::{local_get, local_replace, local_set, ALLOC_TAG}};
+
+/// ヒープの使用量をタグごとに数えるか(heap-profile feature)
+/// 無効ならwith_alloc_tagはfを呼ぶだけで、アロケータにも何も入らない
+pub const ENABLED: bool = cfg!(feature = "heap-profile");
+
+/// タグの数の上限。0番はタグの無い確保を数える"other"
+#[cfg(feature = "heap-profile")]
+pub const MAX_TAGS: usize = 16;
+#[cfg(feature = "heap-profile")]
+const OTHER: &str = "other";
+
+#[derive(Debug, Clone, Copy)]
+pub struct TagStat {
+    pub name: &'static str,
+    pub live_bytes: usize,
+    pub live_allocs: usize,
+}
+
+#[cfg(feature = "heap-profile")]
+static NAMES: Mutex<[Option<&'static str>; MAX_TAGS]> = Mutex::new({
+    let mut names = [None; MAX_TAGS];
+    names[0] = Some(OTHER);
+    names
+});
+#[cfg(feature = "heap-profile")]
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "heap-profile")]
+static LIVE_BYTES: [AtomicUsize; MAX_TAGS] = [ZERO; MAX_TAGS];
+#[cfg(feature = "heap-profile")]
+static LIVE_ALLOCS: [AtomicUsize; MAX_TAGS] = [ZERO; MAX_TAGS];
+
+/// タグの番号。初めて見るタグなら表に加え、表が一杯なら"other"にする
+#[cfg(feature = "heap-profile")]
+fn tag_index(tag: &'static str) -> u8 {
+    let mut names = NAMES.lock();
+    if let Some(i) = names.iter().position(|n| *n == Some(tag)) {
+        return i as u8;
+    }
+    match names.iter().position(|n| n.is_none()) {
+        Some(i) => {
+            names[i] = Some(tag);
+            i as u8
+        }
+        None => 0,
+    }
+}
+
+/// fの中での確保をtagに数える。入れ子にでき、抜けると外側のタグに戻る
+/// タグはタスクローカルに持つので、タスクを切り替えるとそのタスクのタグに替わる。asyncのawaitをまたいではいけない
+#[cfg(feature = "heap-profile")]
+pub fn with_alloc_tag<R>(tag: &'static str, f: impl FnOnce() -> R) -> R {
+    let prev = local_replace(ALLOC_TAG, tag_index(tag) as usize);
+    let r = f();
+    local_set(ALLOC_TAG, prev);
+    r
+}
+
+#[cfg(not(feature = "heap-profile"))]
+#[inline(always)]
+pub fn with_alloc_tag<R>(_tag: &'static str, f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// 確保したsizeバイトを今のタグに数え、そのタグの番号を返す。アロケータから呼ぶ
+#[cfg(feature = "heap-profile")]
+pub(crate) fn on_alloc(size: usize) -> u8 {
+    let tag = local_get(ALLOC_TAG) as u8;
+    LIVE_BYTES[tag as usize].fetch_add(size, Ordering::Relaxed);
+    LIVE_ALLOCS[tag as usize].fetch_add(1, Ordering::Relaxed);
+    tag
+}
+
+/// 確保したときのタグから差し引く
+#[cfg(feature = "heap-profile")]
+pub(crate) fn on_dealloc(tag: u8, size: usize) {
+    let tag = (tag as usize).min(MAX_TAGS - 1);
+    LIVE_BYTES[tag].fetch_sub(size, Ordering::Relaxed);
+    LIVE_ALLOCS[tag].fetch_sub(1, Ordering::Relaxed);
+}
+
+/// タグごとの使用量を、使用中のバイト数の多い順に返す。無効なら空
+pub fn heap_stats() -> Vec<TagStat> {
+    #[cfg(feature = "heap-profile")]
+    {
+        // 表を作る確保自体も数えられるので、先に読んでから並べる
+        let names = *NAMES.lock();
+        let mut stats = Vec::with_capacity(MAX_TAGS);
+        for (i, name) in names.iter().enumerate() {
+            if let Some(name) = name {
+                stats.push(TagStat {
+                    name,
+                    live_bytes: LIVE_BYTES[i].load(Ordering::Relaxed),
+                    live_allocs: LIVE_ALLOCS[i].load(Ordering::Relaxed),
+                });
+            }
+        }
+        stats.sort_by(|a, b| b.live_bytes.cmp(&a.live_bytes));
+        stats
+    }
+    #[cfg(not(feature = "heap-profile"))]
+    Vec::new()
+}
+
+/// mem/heapを登録する
+pub fn register_nodes() {
+    introspect::register("mem/heap", |_, out| {
+        if !ENABLED {
+            return writeln!(out, "heap profiling is disabled (build with --features heap-profile)");
+        }
+        writeln!(out, "{:<12} {:>10} {:>8}", "tag", "bytes", "allocs")?;
+        for s in heap_stats() {
+            writeln!(out, "{:<12} {:>10} {:>8}", s.name, s.live_bytes, s.live_allocs)?;
+        }
+        Ok(())
+    }, 0)
+    .expect("heap_profile: mem/heap");
+}
+
+pub fn run_heap_profile_tests() {
+    assert!(with_alloc_tag("test", || 42) == 42);
+
+    #[cfg(feature = "heap-profile")]
+    {
+        use alloc::{boxed::Box, vec};
+
+        let stat = |name: &str| {
+            let i = NAMES.lock().iter().position(|n| *n == Some(name)).unwrap();
+            (LIVE_BYTES[i].load(Ordering::Relaxed), LIVE_ALLOCS[i].load(Ordering::Relaxed))
+        };
+
+        // 入れ子のスコープを抜けると外側のタグに戻る
+        let outer = tag_index("test-outer");
+        with_alloc_tag("test-outer", || {
+            with_alloc_tag("test-inner", || {
+                assert!(local_get(ALLOC_TAG) == tag_index("test-inner") as usize);
+            });
+            assert!(local_get(ALLOC_TAG) == outer as usize);
+        });
+        assert!(local_get(ALLOC_TAG) == 0);
+
+        // 解放は確保したときのタグから引かれる。ページ単位の大きな確保でも同じ
+        let small = with_alloc_tag("test-outer", || Box::new([0u8; 100]));
+        let large = with_alloc_tag("test-outer", || vec![0u8; 3 * 4096]);
+        assert!(stat("test-outer") == (100 + 3 * 4096, 2));
+        with_alloc_tag("test-inner", || {
+            drop(small);
+            drop(large);
+        });
+        assert!(stat("test-outer") == (0, 0));
+        assert!(stat("test-inner") == (0, 0));
+    }
+}
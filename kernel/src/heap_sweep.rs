@@ -0,0 +1,179 @@
+use core::{alloc::Layout, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+
+use crate::{error, introspect, memory_manager::{free_frames, memory_stats, sweep_heap_step, HeapCorruption, Mutex, ObjectAllocator, SweepCursor, POISON}, timer::{get_current_tick, ms_to_ticks, Timestamp}};
+
+/// 1回のスイープに使ってよい時間。入力の遅延に響かないよう短くする
+pub const BUDGET_US: u64 = 50;
+/// 時間を確かめる間隔(ページかブロックの数)
+const NODES_PER_STEP: usize = 16;
+/// スイープの既定の間隔
+const DEFAULT_INTERVAL_MS: u64 = 500;
+
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL_MS);
+static NEXT_TICK: AtomicU64 = AtomicU64::new(0);
+/// 破損を見つけたらpanicする(#heap-sweep=panic)
+static PANIC_ON_CORRUPTION: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SweepStats {
+    pub passes: u64,
+    /// すべての大きさを一巡した回数
+    pub cycles: u64,
+    pub checked: u64,
+    pub last_us: u64,
+    pub max_us: u64,
+    pub corruptions: u64,
+    pub last_corruption: Option<HeapCorruption>,
+}
+
+struct SweepState {
+    cursor: SweepCursor,
+    stats: SweepStats,
+}
+
+static STATE: Mutex<SweepState> = Mutex::new(SweepState {
+    cursor: SweepCursor::new(),
+    stats: SweepStats { passes: 0, cycles: 0, checked: 0, last_us: 0, max_us: 0, corruptions: 0, last_corruption: None },
+});
+
+pub fn set_interval_ms(ms: u64) {
+    INTERVAL_MS.store(ms, Ordering::Relaxed);
+}
+
+pub fn set_panic_on_corruption(enable: bool) {
+    PANIC_ON_CORRUPTION.store(enable, Ordering::Relaxed);
+}
+
+pub fn stats() -> SweepStats {
+    STATE.lock().stats
+}
+
+/// mem/frames・mem/stats・mem/sweepを登録する。アロケータの初期化より前に呼んでよい
+pub fn register_nodes() {
+    introspect::register("mem/frames", |_, out| writeln!(out, "{}", free_frames()), 0).expect("heap_sweep: mem/frames");
+    introspect::register("mem/stats", |_, out| writeln!(out, "{}", memory_stats()), 0).expect("heap_sweep: mem/stats");
+    introspect::register("mem/sweep", |_, out| {
+        let st = stats();
+        writeln!(
+            out,
+            "passes={} cycles={} checked={} last={}us max={}us budget={}us",
+            st.passes, st.cycles, st.checked, st.last_us, st.max_us, BUDGET_US
+        )?;
+        writeln!(out, "corruptions: {}", st.corruptions)?;
+        if let Some(e) = st.last_corruption {
+            writeln!(out, "last: {} in page {:#x} at offset {:#x}", e.kind, e.page, e.offset)?;
+        }
+        Ok(())
+    }, 0)
+    .expect("heap_sweep: mem/sweep");
+}
+
+/// 前のスイープから間隔が空いた。メインループがhltする前に確かめる
+pub fn due() -> bool {
+    get_current_tick() >= NEXT_TICK.load(Ordering::Relaxed)
+}
+
+/// ヒープの続きをBUDGET_USの間だけ確かめる。壊れていたらログに出す
+pub fn sweep() {
+    let start = Timestamp::now();
+    let mut found = None;
+    {
+        let mut state = STATE.lock();
+        let SweepState { cursor, stats } = &mut *state;
+        loop {
+            let progress = sweep_heap_step(cursor, NODES_PER_STEP);
+            stats.checked += progress.checked as u64;
+            if progress.cycle_done {
+                stats.cycles += 1;
+            }
+            if let Some(e) = progress.error {
+                stats.corruptions += 1;
+                // 同じ破損は一巡ごとに見つかるので、続けて同じものは出さない
+                if stats.last_corruption.map_or(true, |last| (last.page, last.offset) != (e.page, e.offset)) {
+                    found = Some(e);
+                }
+                stats.last_corruption = Some(e);
+            }
+            if found.is_some() || progress.cycle_done || start.micros_until(&Timestamp::now()) >= BUDGET_US {
+                break;
+            }
+        }
+        let elapsed = start.micros_until(&Timestamp::now());
+        stats.passes += 1;
+        stats.last_us = elapsed;
+        stats.max_us = stats.max_us.max(elapsed);
+    }
+    NEXT_TICK.store(get_current_tick() + ms_to_ticks(INTERVAL_MS.load(Ordering::Relaxed)), Ordering::Relaxed);
+
+    // ログはヒープを使うので、アロケータのロックを放してから出す
+    if let Some(e) = found {
+        report(&e);
+        if PANIC_ON_CORRUPTION.load(Ordering::Relaxed) {
+            panic!("heap corruption at {:#x}+{:#x}", e.page, e.offset);
+        }
+    }
+}
+
+fn report(e: &HeapCorruption) {
+    error!("heap: corrupt {} in page {:#x} at offset {:#x}", e.kind, e.page, e.offset);
+    error!("heap:   +{:#05x}: {:02x?}", e.bytes_start, e.bytes);
+}
+
+/// 自分用のアロケータを作り、空きブロックを壊して、スイープが2巡のうちにその位置を見つけることを確かめる
+/// 作ったアロケータのページは返さない
+pub fn run_heap_sweep_tests() {
+    let run = |a: &ObjectAllocator, max_cycles: usize| {
+        let mut cursor = SweepCursor::new();
+        let mut cycles = 0;
+        while cycles < max_cycles {
+            let progress = a.sweep_step(&mut cursor, NODES_PER_STEP);
+            if progress.error.is_some() {
+                return progress.error;
+            }
+            cycles += progress.cycle_done as usize;
+        }
+        None
+    };
+
+    let mut a = ObjectAllocator::new();
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    // 1ページ目を使い切らせて、足したページも辿らせる
+    let ptrs: alloc::vec::Vec<*mut u8> = (0..40).map(|_| a.alloc(layout)).collect();
+    for p in ptrs.iter().step_by(2) {
+        unsafe { a.dealloc(*p, layout) };
+    }
+    assert!(run(&a, 2).is_none());
+
+    // 解放したブロックの途中を書き換える
+    let victim = ptrs[20];
+    unsafe {
+        assert!(*victim.add(40) == POISON);
+        *victim.add(40) = 0;
+    }
+    let e = run(&a, 2).expect("corruption not found");
+    let page = victim as usize & !0xfff;
+    assert!(e.kind == "poison" && e.page == page && e.offset == victim as usize - page + 40);
+    assert!(e.bytes[e.offset - e.bytes_start] == 0);
+    unsafe { *victim.add(40) = POISON };
+    assert!(run(&a, 2).is_none());
+}
+
+/// 本物のヒープの空きブロックを壊し、idleのスイープと同じ経路で2巡のうちに見つかることを確かめる (heap-sweep-test feature)
+#[cfg(feature = "heap-sweep-test")]
+pub fn run_heap_sweep_corruption_test() {
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let target = unsafe {
+        let ptr = alloc::alloc::alloc(layout);
+        alloc::alloc::dealloc(ptr, layout);
+        *ptr.add(40) = 0;
+        ptr.add(40)
+    };
+    let found = |st: &SweepStats| st.last_corruption.is_some_and(|e| e.page + e.offset == target as usize);
+    let cycles = stats().cycles;
+    while stats().cycles < cycles + 2 && !found(&stats()) {
+        sweep();
+    }
+    assert!(found(&stats()), "heap-sweep-test: corruption at {:#x} not found", target as usize);
+    unsafe { *target = POISON };
+    crate::println!("heap-sweep-test: found {:#x}", target as usize);
+}
@@ -0,0 +1,97 @@
+use core::{hint::spin_loop, ptr::{read_volatile, write_volatile}, sync::atomic::{AtomicU64, Ordering}};
+
+use crate::{addr::PhysAddr, paging::{map_mmio, MapError}};
+
+/// General Capabilities and ID。上位32bitがカウンタの周期(フェムト秒)、bit13が64bitカウンタ
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const ENABLE_CNF: u64 = 1 << 0;
+/// タイマー32個分までのレジスタの大きさ
+const REGISTERS_LEN: u64 = 0x400;
+const FS_PER_NS: u128 = 1_000_000;
+
+/// レジスタの物理アドレス。0ならHPETは無い
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// 32bitのカウンタなら0xffff_ffff。差を取るときに使う
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// ACPIのHPETテーブルにあったレジスタを写像し、止まっていればカウンタを動かす
+pub unsafe fn initialize(base: PhysAddr) -> Result<(), MapError> {
+    map_mmio(base, REGISTERS_LEN)?;
+    let reg = |offset: u64| (base.as_u64() + offset) as *mut u64;
+    let caps = read_volatile(reg(CAPABILITIES));
+    let period = caps >> 32;
+    // 仕様では周期は0より大きく100ナノ秒以下
+    if period == 0 || period > 100_000_000 {
+        return Ok(());
+    }
+    let config = read_volatile(reg(CONFIGURATION));
+    if config & ENABLE_CNF == 0 {
+        write_volatile(reg(CONFIGURATION), config | ENABLE_CNF);
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    COUNTER_MASK.store(if caps & COUNT_SIZE_CAP != 0 { u64::MAX } else { u32::MAX as u64 }, Ordering::Relaxed);
+    BASE.store(base.as_u64(), Ordering::Release);
+    Ok(())
+}
+
+/// HPETが見つかって動いている
+pub fn available() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// カウンタの周波数(Hz)
+pub fn frequency() -> u64 {
+    (1_000_000_000_000_000 / PERIOD_FS.load(Ordering::Relaxed).max(1) as u128) as u64
+}
+
+fn read_counter() -> u64 {
+    let base = BASE.load(Ordering::Acquire);
+    assert!(base != 0, "hpet: not available");
+    unsafe { read_volatile((base + MAIN_COUNTER) as *const u64) & COUNTER_MASK.load(Ordering::Relaxed) }
+}
+
+fn ticks_to_ns(ticks: u64, period_fs: u64) -> u64 {
+    (ticks as u128 * period_fs as u128 / FS_PER_NS).min(u64::MAX as u128) as u64
+}
+
+fn ns_to_ticks(ns: u64, period_fs: u64) -> u64 {
+    (ns as u128 * FS_PER_NS).div_ceil(period_fs.max(1) as u128).min(u64::MAX as u128) as u64
+}
+
+/// カウンタの値をナノ秒にしたもの。32bitのカウンタでは数分で一周する。availableのときだけ呼ぶ
+pub fn now_ns() -> u64 {
+    ticks_to_ns(read_counter(), PERIOD_FS.load(Ordering::Relaxed))
+}
+
+/// 少なくともnsナノ秒待つ。カウンタが一周しても差で測るので、一周より短ければ正しく待てる。availableのときだけ呼ぶ
+pub fn busy_wait_ns(ns: u64) {
+    let ticks = ns_to_ticks(ns, PERIOD_FS.load(Ordering::Relaxed));
+    let mask = COUNTER_MASK.load(Ordering::Relaxed);
+    let start = read_counter();
+    while read_counter().wrapping_sub(start) & mask < ticks {
+        spin_loop();
+    }
+}
+
+pub fn run_hpet_tests() {
+    // QEMUのHPETは100MHz(10ns)、実機の多くは14.318MHz(約69.8ns)
+    assert!(ticks_to_ns(100, 10_000_000) == 1000);
+    assert!(ns_to_ticks(1000, 10_000_000) == 100);
+    assert!(ticks_to_ns(14_318_180, 69_841_279) == 1_000_000_004);
+    // 待ち時間は切り上げるので、短くなることはない
+    assert!(ns_to_ticks(1, 69_841_279) == 1);
+    assert!(ticks_to_ns(ns_to_ticks(10_000_000, 69_841_279), 69_841_279) >= 10_000_000);
+    // 溢れる分は最大値で止める
+    assert!(ticks_to_ns(u64::MAX, 100_000_000) == u64::MAX && ns_to_ticks(u64::MAX, 1) == u64::MAX);
+
+    if available() {
+        let start = read_counter();
+        busy_wait_ns(1_000_000);
+        let elapsed = read_counter().wrapping_sub(start) & COUNTER_MASK.load(Ordering::Relaxed);
+        assert!(elapsed >= ns_to_ticks(1_000_000, PERIOD_FS.load(Ordering::Relaxed)));
+    }
+}
@@ -0,0 +1,297 @@
+use alloc::vec::Vec;
+
+use crate::{
+    graphic::{capture::compare_capture, focus, font::write_string, graphics::{Color, PixelWriter, Rect, Vec2}, palette, snap::Edge, window::{LayerHandle, Placement, Window}, with_layers},
+    memory_manager::{LazyInit, Mutex},
+    usb::{self, new_channel, KeyEvent, KeyReport, LockState, ModifierSet, ReadySummary, Receiver, Sender, Subscription, KEY_CAPS_LOCK, KEY_NUM_LOCK},
+};
+
+/// セルの数。Ctrl, Alt, Shift, GUI, Caps, Num, USBの順
+const N_CELLS: usize = 7;
+const CELL_W: i32 = 8 * 3;
+const CELL_H: i32 = 16;
+const CELL_GAP: i32 = 4;
+/// セルの右に置く時刻 (HH:MM:SS) の幅
+const CLOCK_W: i32 = 8 * 8;
+const CLOCK_X: i32 = CELL_GAP + (CELL_W + CELL_GAP) * N_CELLS as i32;
+const INDICATOR_W: usize = (CLOCK_X + CLOCK_W + CELL_GAP) as usize;
+const INDICATOR_H: usize = (CELL_H + CELL_GAP) as usize;
+
+const CELL_CAPS: usize = 4;
+const CELL_NUM: usize = 5;
+const CELL_USB: usize = 6;
+
+const ON_BG: Color = palette::SELECTION_BG;
+const OFF_BG: Color = palette::WINDOW_GRAY;
+
+/// 1つのセルに表示する内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    text: [u8; 3],
+    active: bool,
+}
+
+/// 修飾キーは押されている側に括弧を付け ("[C", "C]", "[C]")、どちらかが押されていれば反転する。
+/// ロックキーはオンのとき反転する。USBは起動時のデバイスが揃うと反転し、失敗があれば"US!"になる
+fn cells(modifier: ModifierSet, locks: LockState, usb: Option<ReadySummary>) -> [Cell; N_CELLS] {
+    let m = |ch: u8, l: bool, r: bool| Cell {
+        text: [if l { b'[' } else { b' ' }, ch, if r { b']' } else { b' ' }],
+        active: l || r,
+    };
+    [
+        m(b'C', modifier.l_ctrl(), modifier.r_ctrl()),
+        m(b'A', modifier.l_alt(), modifier.r_alt()),
+        m(b'S', modifier.l_shift(), modifier.r_shift()),
+        m(b'G', modifier.l_gui(), modifier.r_gui()),
+        Cell { text: *b"Cap", active: locks.caps_lock() },
+        Cell { text: *b"Num", active: locks.num_lock() },
+        match usb {
+            None => Cell { text: *b"usb", active: false },
+            Some(s) if s.failed > 0 || s.timed_out => Cell { text: *b"US!", active: true },
+            Some(_) => Cell { text: *b"USB", active: true },
+        },
+    ]
+}
+
+/// ウィンドウ内でのセルの矩形
+fn cell_rect(i: usize) -> Rect {
+    Rect::from_wh(CELL_GAP + (CELL_W + CELL_GAP) * i as i32, CELL_GAP / 2, CELL_W, CELL_H)
+}
+
+/// ウィンドウ内の位置posにあるクリックできるセルのロックキー
+fn hit_test(pos: Vec2<i32>) -> Option<u8> {
+    [(CELL_CAPS, KEY_CAPS_LOCK), (CELL_NUM, KEY_NUM_LOCK)].into_iter()
+        .find(|(i, _)| {
+            let r = cell_rect(*i);
+            r.x1 <= pos.x && pos.x < r.x2 && r.y1 <= pos.y && pos.y < r.y2
+        })
+        .map(|(_, key)| key)
+}
+
+struct Indicator {
+    layer: LayerHandle,
+    rx: Receiver<KeyEvent>,
+    _sub: Option<Subscription>,
+    /// 描画済みのセル。Noneならまだ何も描いていない
+    shown: Option<[Cell; N_CELLS]>,
+    modifier: ModifierSet,
+    locks: LockState,
+    /// 起動時のUSBデバイスが揃っていればその要約
+    usb: Option<ReadySummary>,
+    /// 表示中の時刻
+    clock: Option<[u8; 8]>,
+    prev_buttons: u8,
+    /// ロックキーのセルがクリックされたときに呼ぶ
+    toggle_lock: fn(u8),
+}
+
+impl Indicator {
+    fn new(layer: LayerHandle, rx: Receiver<KeyEvent>, sub: Option<Subscription>, toggle_lock: fn(u8)) -> Self {
+        let mut indicator = Self {
+            layer,
+            rx,
+            _sub: sub,
+            shown: None,
+            modifier: ModifierSet::default(),
+            locks: LockState::default(),
+            usb: None,
+            clock: None,
+            prev_buttons: 0,
+            toggle_lock,
+        };
+        indicator.render();
+        indicator
+    }
+
+    /// 溜まっているキーボードのイベントを反映する。描き直したセルの数を返す
+    fn on_key_events(&mut self) -> usize {
+        while let Some(event) = self.rx.receive() {
+            self.modifier = event.report.modifier;
+            self.locks = event.locks;
+        }
+        self.render()
+    }
+
+    /// 前回から変わったセルだけを描き直す。描き直したセルの数を返す
+    fn render(&mut self) -> usize {
+        let new = cells(self.modifier, self.locks, self.usb);
+        let dirty: Vec<usize> = (0..N_CELLS)
+            .filter(|&i| self.shown.map_or(true, |shown| shown[i] != new[i]))
+            .collect();
+        if dirty.is_empty() {
+            return 0;
+        }
+
+        let window = self.layer.window().read();
+        window.buffer().write_with(|back| {
+            if self.shown.is_none() {
+                back.fill_rect((0, 0).into(), (INDICATOR_W as u32, INDICATOR_H as u32).into(), palette::WINDOW_SHADOW);
+            }
+            for &i in &dirty {
+                let r = cell_rect(i);
+                let bg = if new[i].active { ON_BG } else { OFF_BG };
+                back.fill_rect((r.x1, r.y1).into(), (CELL_W as u32, CELL_H as u32).into(), bg);
+                write_string(back, r.x1 as u32, r.y1 as u32, &new[i].text, palette::contrast_text_color(bg));
+            }
+        });
+        window.buffer().flush();
+        self.shown = Some(new);
+        dirty.len()
+    }
+
+    /// USBの準備ができたことを反映する。描き直したセルの数を返す
+    fn on_usb_ready(&mut self, summary: ReadySummary) -> usize {
+        self.usb = Some(summary);
+        self.render()
+    }
+
+    /// 時刻の表示を更新する。変わっていればtrue
+    fn show_clock(&mut self, hms: &[u8; 8]) -> bool {
+        if self.clock.as_ref() == Some(hms) {
+            return false;
+        }
+        let window = self.layer.window().read();
+        window.buffer().write_with(|back| {
+            back.fill_rect((CLOCK_X, CELL_GAP / 2).into(), (CLOCK_W as u32, CELL_H as u32).into(), palette::WINDOW_SHADOW);
+            write_string(back, CLOCK_X as u32, (CELL_GAP / 2) as u32, hms, palette::contrast_text_color(palette::WINDOW_SHADOW));
+        });
+        window.buffer().flush();
+        self.clock = Some(*hms);
+        true
+    }
+
+    /// 画面上の位置posでのマウスの状態を受け取る。ロックキーのセルが左クリックされたら切り替えを要求し、そのキーコードを返す
+    fn on_mouse(&mut self, buttons: u8, pos: Vec2<i32>) -> Option<u8> {
+        let clicked = buttons & 1 != 0 && self.prev_buttons & 1 == 0;
+        self.prev_buttons = buttons;
+        if !clicked {
+            return None;
+        }
+        let origin = self.layer.window().read().pos();
+        let key = hit_test((pos.x - origin.x, pos.y - origin.y).into())?;
+        (self.toggle_lock)(key);
+        Some(key)
+    }
+}
+
+static INDICATOR: LazyInit<Indicator> = LazyInit::new();
+
+/// 画面右上にインジケータを作り、ウィンドウより上に置く
+pub fn init_indicator() {
+    let layer = with_layers(|l| {
+        let (width, _) = l.resolution();
+        let mut win = Window::new(INDICATOR_W, INDICATOR_H, Some(palette::WINDOW_SHADOW));
+        win.move_to((width as i32 - INDICATOR_W as i32, 0).into());
+        // 吸着したウィンドウがインジケータに重ならないようにする
+        l.reserve(Edge::Top, INDICATOR_H as i32);
+        l.new_layer(win, Placement::Hidden)
+    });
+    let layer_id = layer.layer_id();
+    let (tx, rx) = new_channel("indicator-keyboard");
+    let sub = usb::subscribe_keyboard(tx);
+    INDICATOR.lock().init(Indicator::new(layer, rx, Some(sub), usb::toggle_lock));
+    focus::keep_on_top(layer_id);
+}
+
+/// キーボードのイベントを処理した後に呼ぶ
+pub fn on_key_events() {
+    let mut ind = INDICATOR.lock();
+    if ind.is_init() {
+        ind.on_key_events();
+    }
+}
+
+/// 時刻 (HH:MM:SS) をセルの右に表示する
+pub fn show_clock(hms: &[u8; 8]) {
+    let mut ind = INDICATOR.lock();
+    // ヘッドレスでは表示先が無い
+    if ind.is_init() {
+        ind.show_clock(hms);
+    }
+}
+
+pub fn on_mouse(buttons: u8, pos: Vec2<i32>) {
+    let mut ind = INDICATOR.lock();
+    if ind.is_init() {
+        ind.on_mouse(buttons, pos);
+    }
+}
+
+/// usb::defer_until_readyから呼ばれる
+pub fn on_usb_ready(_: usize) {
+    let mut ind = INDICATOR.lock();
+    if let (true, Some((summary, _))) = (ind.is_init(), usb::ready_summary()) {
+        ind.on_usb_ready(summary);
+    }
+}
+
+static TOGGLED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+fn inject(tx: &Sender<KeyEvent>, modifier: u8, locks: u8) {
+    tx.send(KeyEvent {
+        slot: usb::SlotId::new(1).unwrap(),
+        report: KeyReport { modifier: ModifierSet::from_bits(modifier), _rsvd: 0, keycodes: [0; 6] },
+        locks: LockState::from_bits(locks),
+    });
+}
+
+pub fn run_indicator_tests() {
+    // 状態からセルの表示
+    let c = cells(ModifierSet::from_bits(0b0001_0001 | 0b10), LockState::from_bits(0b10), None);
+    assert!(&c[0].text == b"[C]" && c[0].active);
+    assert!(&c[1].text == b" A " && !c[1].active);
+    assert!(&c[2].text == b"[S " && c[2].active);
+    assert!(c[CELL_CAPS].active && !c[CELL_NUM].active);
+    assert!(&c[CELL_USB].text == b"usb" && !c[CELL_USB].active);
+    let ready = ReadySummary { devices: 2, failed: 0, timed_out: false, elapsed_ms: 640 };
+    let c = cells(ModifierSet::default(), LockState::default(), Some(ready));
+    assert!(&c[CELL_USB].text == b"USB" && c[CELL_USB].active);
+    let c = cells(ModifierSet::default(), LockState::default(), Some(ReadySummary { timed_out: true, ..ready }));
+    assert!(&c[CELL_USB].text == b"US!");
+    assert!(hit_test(((cell_rect(CELL_CAPS).x1 + 1), 4).into()) == Some(KEY_CAPS_LOCK));
+    assert!(hit_test((0, 0).into()).is_none());
+
+    let layer = with_layers(|l| l.new_layer(Window::new(INDICATOR_W, INDICATOR_H, None), Placement::Hidden));
+    let window = layer.window().clone();
+    window.write().move_to((100, 100).into());
+    let (tx, rx) = new_channel("indicator-test");
+    let mut indicator = Indicator::new(layer, rx, None, |key| TOGGLED.lock().push(key));
+    let blank = window.read().capture_client(None);
+
+    // 変わったセルだけが描き直される
+    inject(&tx, 1 << 5, 0);
+    assert!(indicator.on_key_events() == 1);
+    let shift = window.read().capture_client(None);
+    let diff = compare_capture(&blank, &shift, INDICATOR_W);
+    let r = diff.bounding_rect.unwrap();
+    let cell = cell_rect(2);
+    assert!(r.x1 >= cell.x1 && r.x2 <= cell.x2 && r.y1 >= cell.y1 && r.y2 <= cell.y2);
+    inject(&tx, 1 << 5, 0);
+    assert!(indicator.on_key_events() == 0);
+
+    // Capsのセルをクリックすると切り替えが要求され、キーボードからの通知で表示が変わる
+    let caps = cell_rect(CELL_CAPS);
+    let pos = Vec2::new(100 + caps.x1 + 2, 100 + caps.y1 + 2);
+    assert!(indicator.on_mouse(1, pos) == Some(KEY_CAPS_LOCK));
+    assert!(indicator.on_mouse(1, pos).is_none());
+    assert!(indicator.on_mouse(0, pos).is_none());
+    assert!(indicator.on_mouse(1, (0, 0).into()).is_none());
+    assert!(TOGGLED.lock().as_slice() == [KEY_CAPS_LOCK]);
+    inject(&tx, 1 << 5, 0b10);
+    assert!(indicator.on_key_events() == 1);
+    let diff = compare_capture(&shift, &window.read().capture_client(None), INDICATOR_W);
+    let r = diff.bounding_rect.unwrap();
+    assert!(r.x1 >= caps.x1 && r.x2 <= caps.x2);
+    TOGGLED.lock().clear();
+
+    // 時刻は変わったときだけ、時刻の部分だけ描き直す
+    let before = window.read().capture_client(None);
+    assert!(indicator.show_clock(b"12:34:56"));
+    assert!(!indicator.show_clock(b"12:34:56"));
+    let r = compare_capture(&before, &window.read().capture_client(None), INDICATOR_W).bounding_rect.unwrap();
+    assert!(r.x1 >= CLOCK_X && r.x2 <= CLOCK_X + CLOCK_W);
+
+    // USBの準備ができるとUSBのセルだけが描き直される
+    assert!(indicator.on_usb_ready(ready) == 1);
+    assert!(indicator.on_usb_ready(ready) == 0);
+}
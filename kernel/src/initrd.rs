@@ -0,0 +1,131 @@
+use crate::{memory_manager::Mutex, println};
+
+/// ブートローダが渡すESPの\initrd.imgの中身。無ければptrはnull
+/// バッファはLOADER_DATAに置かれ、カーネルは再利用しないのでそのまま読める
+#[repr(C)]
+pub struct InitrdRaw {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+static INITRD: Mutex<Option<&'static [u8]>> = Mutex::new(None);
+
+/// cpio(newc)のヘッダの大きさ。マジックの後に8桁の16進数が13個並ぶ
+const HEADER_LEN: usize = 110;
+const MAGIC: &[u8] = b"070701";
+const TRAILER: &str = "TRAILER!!!";
+
+/// ブートローダから受け取る。メモリ割り当ては行わないので、起動直後に呼んでよい
+pub unsafe fn load(raw: *const InitrdRaw) {
+    if raw.is_null() || (*raw).ptr.is_null() {
+        return;
+    }
+    let bytes = core::slice::from_raw_parts((*raw).ptr, (*raw).len);
+    if !bytes.starts_with(MAGIC) {
+        println!("initrd: {} bytes, not a cpio (newc) archive, ignoring", bytes.len());
+        return;
+    }
+    println!("initrd: {} bytes, {} files", bytes.len(), Entries::new(bytes).count());
+    *INITRD.lock() = Some(bytes);
+}
+
+/// initrdの中身全体。渡されていなければNone
+pub fn bytes() -> Option<&'static [u8]> {
+    *INITRD.lock()
+}
+
+/// initrdの中のファイル。名前の先頭の"./"や"/"は無視する
+pub fn find_file(name: &str) -> Option<&'static [u8]> {
+    find_entry(name).map(|e| e.data)
+}
+
+/// find_fileと同じだが、アーカイブ内の名前も返す
+pub fn find_entry(name: &str) -> Option<Entry<'static>> {
+    let name = normalize(name);
+    Entries::new(bytes()?).find(|e| normalize(e.name) == name)
+}
+
+pub fn find_in<'a>(archive: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let name = normalize(name);
+    Entries::new(archive).find(|e| normalize(e.name) == name).map(|e| e.data)
+}
+
+fn normalize(name: &str) -> &str {
+    name.trim_start_matches("./").trim_start_matches('/')
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub data: &'a [u8],
+}
+
+/// cpio(newc)のエントリを先頭から読む。壊れたヘッダかTRAILER!!!で終わる
+pub struct Entries<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Entries<'a> {
+    pub fn new(archive: &'a [u8]) -> Self {
+        Self { rest: archive }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        let header = self.rest.get(..HEADER_LEN).filter(|h| h.starts_with(MAGIC))?;
+        // i番目のフィールド。0番目はc_ino
+        let field = |i: usize| {
+            let hex = core::str::from_utf8(&header[6 + 8 * i..14 + 8 * i]).ok()?;
+            usize::from_str_radix(hex, 16).ok()
+        };
+        let (filesize, namesize) = (field(6)?, field(11)?);
+        // 名前はNUL終端込みでnamesizeバイト。ヘッダと名前、中身はそれぞれ4バイト境界に揃う
+        let name = self.rest.get(HEADER_LEN..HEADER_LEN + namesize.checked_sub(1)?)?;
+        let name = core::str::from_utf8(name).ok()?;
+        let data_start = (HEADER_LEN + namesize).next_multiple_of(4);
+        let data = self.rest.get(data_start..data_start.checked_add(filesize)?)?;
+        if name == TRAILER {
+            self.rest = &[];
+            return None;
+        }
+        self.rest = self.rest.get((data_start + filesize).next_multiple_of(4)..).unwrap_or(&[]);
+        Some(Entry { name, data })
+    }
+}
+
+pub fn run_initrd_tests() {
+    use alloc::vec::Vec;
+
+    let mut archive = Vec::new();
+    let mut push = |name: &str, data: &[u8]| {
+        // c_ino, c_mode, c_uidからc_mtimeまで, c_filesize, c_devmajorからc_rdevminorまで, c_namesize, c_check
+        let header = format!("070701{:08x}{:08x}{:032x}{:08x}{:032x}{:08x}{:08x}", 1, 0o100644, 0, data.len(), 0, name.len() + 1, 0);
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    };
+    push("./font.psf", b"psf");
+    push("wallpaper.bmp", b"BM12345");
+    push("empty", b"");
+    push(TRAILER, b"");
+    push("after-trailer", b"x");
+
+    let names: Vec<_> = Entries::new(&archive).map(|e| e.name).collect();
+    assert!(names == ["./font.psf", "wallpaper.bmp", "empty"]);
+    assert!(find_in(&archive, "font.psf") == Some(&b"psf"[..]));
+    assert!(find_in(&archive, "/wallpaper.bmp") == Some(&b"BM12345"[..]));
+    assert!(find_in(&archive, "empty") == Some(&b""[..]));
+    assert!(find_in(&archive, "after-trailer").is_none());
+    assert!(find_in(&archive, "missing").is_none());
+
+    // 途中で切れていれば、そこまでのエントリだけを返す。最初のエントリは128バイトで、次は名前の途中で切れる
+    let cut = &archive[..128 + HEADER_LEN + 4];
+    assert!(Entries::new(cut).count() == 1);
+    assert!(Entries::new(b"not an archive").next().is_none());
+}
@@ -1,8 +1,9 @@
-use core::{arch::{asm, global_asm}, fmt::{Debug, Formatter, Result, Write}, iter, mem::{self, size_of, transmute_copy, MaybeUninit}};
+use core::{arch::{asm, global_asm}, fmt::{Debug, Formatter, Result, Write}, iter, mem::{self, size_of, transmute_copy, MaybeUninit}, sync::atomic::{AtomicBool, AtomicUsize, Ordering}};
 
 use bitfield::bitfield;
 use cty::c_void;
-use crate::println;
+use x86_64::{registers::control::Cr2, structures::idt::InterruptStackFrame};
+use crate::{addr, ansi, console::{self, StackWriter}, paging, println, segment::DOUBLE_FAULT_IST, symbols};
 
 /// 割り込みベクタ。各割り込み要因に対応するInterruptDescriptorが格納される。
 static mut IDT: [InterruptDescriptor; 256] = [ZERO_DESCRIPTOR; 256];
@@ -26,6 +27,32 @@ pub fn load_idt() {
     }
 }
 
+/// 割り込みハンドラの入れ子の深さ
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// 割り込みハンドラの中のパニックを試すときに立てる。次のxHCIの割り込みでpanic!する
+pub static PANIC_TEST: AtomicBool = AtomicBool::new(false);
+
+/// 割り込みハンドラの中にいる間持つ。落とすと抜けたことになる
+pub struct InterruptContext(());
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 割り込みハンドラの始めに呼ぶ。タスクを切り替える前には落とすこと
+pub fn enter_interrupt() -> InterruptContext {
+    INTERRUPT_DEPTH.fetch_add(1, Ordering::Relaxed);
+    InterruptContext(())
+}
+
+/// 割り込みハンドラの中ならtrue。コンソールなどロックを取るものを使ってはいけない
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.load(Ordering::Relaxed) > 0
+}
+
 /// 全ての割り込みを一括で有効・無効にする。
 pub fn set_interrupt_flag(flag: bool) {
     unsafe {
@@ -62,8 +89,15 @@ bitfield! {
 // Interrupt Vector Index
 #[derive(Debug, Clone, Copy)]
 pub enum IVIndex {
+    DivideError = 0x00,
+    InvalidOpcode = 0x06,
+    DoubleFault = 0x08,
+    GeneralProtection = 0x0d,
+    PageFault = 0x0e,
     XHCI = 0x40,
-    LapicTimer = 0x41
+    LapicTimer = 0x41,
+    Ps2Keyboard = 0x42,
+    Ps2Mouse = 0x43,
 }
 
 #[repr(u8)]
@@ -121,6 +155,72 @@ impl InterruptDescriptorAttribute {
     }
 }
 
+/// CPU例外のハンドラをIDTに登録する。load_idtより前に呼ぶ
+pub fn set_exception_handlers(cs: u16) {
+    let handlers: [(IVIndex, *const c_void); 5] = [
+        (IVIndex::DivideError, divide_error_handler as *const c_void),
+        (IVIndex::InvalidOpcode, invalid_opcode_handler as *const c_void),
+        (IVIndex::DoubleFault, double_fault_handler as *const c_void),
+        (IVIndex::GeneralProtection, general_protection_handler as *const c_void),
+        (IVIndex::PageFault, page_fault_handler as *const c_void),
+    ];
+    for (index, handler) in handlers {
+        let mut attr = InterruptDescriptorAttribute::new(0, DescriptorType::InterruptGate);
+        // スタックが壊れて起きた#DFでも動けるよう、専用のスタックに切り替える
+        if let IVIndex::DoubleFault = index {
+            attr.set_interrupt_stack_table(DOUBLE_FAULT_IST);
+        }
+        set_idt_entry(index, InterruptDescriptor::new(cs, attr, handler));
+    }
+}
+
+/// 例外の名前・エラーコード・発生場所をスタック上で整形して出力し、止まる
+fn dump_exception(name: &str, frame: &InterruptStackFrame, error_code: Option<u64>, extra: impl FnOnce(&mut StackWriter)) -> ! {
+    let mut w = StackWriter::new();
+    let _ = write!(w, "{}{name}{}", ansi::RED, ansi::RESET);
+    if let Some(code) = error_code {
+        let _ = write!(w, " (error={code:#x})");
+    }
+    let _ = writeln!(w, "\n  rip={} rsp={:#x} cs={:#x} rflags={:#x}",
+        symbols::Symbolized(frame.instruction_pointer.as_u64()), frame.stack_pointer.as_u64(),
+        frame.code_segment, frame.cpu_flags);
+    extra(&mut w);
+    console::_log_nofmt(w.as_bytes());
+    unsafe {
+        loop {
+            asm!("cli", "hlt");
+        }
+    }
+}
+
+extern "x86-interrupt" fn divide_error_handler(frame: InterruptStackFrame) {
+    dump_exception("divide error (#DE)", &frame, None, |_| {});
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(frame: InterruptStackFrame) {
+    dump_exception("invalid opcode (#UD)", &frame, None, |_| {});
+}
+
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, error_code: u64) -> ! {
+    dump_exception("double fault (#DF)", &frame, Some(error_code), |_| {});
+}
+
+extern "x86-interrupt" fn general_protection_handler(frame: InterruptStackFrame, error_code: u64) {
+    // エラーコードが0でなければ原因になったセグメントセレクタ
+    dump_exception("general protection fault (#GP)", &frame, Some(error_code), |_| {});
+}
+
+extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, error_code: u64) {
+    let addr = Cr2::read().as_u64();
+    dump_exception("page fault (#PF)", &frame, Some(error_code), |w| {
+        let _ = writeln!(w, "  cr2={addr:#x}");
+        // 恒等写像の外なら原因はほぼこれなので、そう書いておく
+        if !paging::is_mapped(addr::PhysAddr::new(addr), 1) {
+            let _ = writeln!(w, "  {addr:#x} is beyond the identity-mapped limit {:#x}", paging::IDENTITY_MAP_END);
+        }
+    });
+}
+
 extern "sysv64" {
     fn _load_idt(limit: u16, offset: *const InterruptDescriptor);
 } 
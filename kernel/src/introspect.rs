@@ -0,0 +1,327 @@
+use core::{cmp::Ordering, fmt::{self, Write}};
+
+use crate::memory_manager::Mutex;
+
+/// パスの最大の長さ
+pub const MAX_PATH: usize = 48;
+/// 登録できるノードの数
+const MAX_NODES: usize = 64;
+/// 1つのノードの値を書き出すバッファの大きさ。溢れた分は捨てる
+const SNAPSHOT_LEN: usize = 1024;
+
+/// ノードの値をoutに書く関数。argは登録したときのもの
+/// 呼ばれている間は表のロックを持っていないので、自分のロックを取ってよい
+pub type ShowFn = fn(arg: usize, out: &mut dyn Write) -> fmt::Result;
+
+/// "mem/frames"のような、/で区切られた名前で引ける1つの値
+#[derive(Clone, Copy)]
+pub struct Node {
+    path: [u8; MAX_PATH],
+    path_len: usize,
+    show: ShowFn,
+    arg: usize,
+}
+
+impl Node {
+    pub fn path(&self) -> &str {
+        // 登録のときにASCIIであることを確かめている
+        core::str::from_utf8(&self.path[..self.path_len]).unwrap_or("?")
+    }
+
+    pub fn show(&self, out: &mut dyn Write) -> fmt::Result {
+        (self.show)(self.arg, out)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// 同じパスが既にある
+    Duplicate,
+    /// 値のノードの下にノードを作ろうとした、またはディレクトリと同じパスに値を置こうとした
+    Conflict,
+    /// 空の要素や使えない文字を含む、または長すぎる
+    BadPath,
+    /// 表がいっぱい
+    Full,
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::Duplicate => f.write_str("already registered"),
+            RegisterError::Conflict => f.write_str("conflicts with an existing node"),
+            RegisterError::BadPath => f.write_str("bad path"),
+            RegisterError::Full => f.write_str("too many nodes"),
+        }
+    }
+}
+
+/// 要素ごとに比べる。"mem-x"が"mem"と"mem/a"の間に入らないようにするため
+fn cmp_path(a: &str, b: &str) -> Ordering {
+    a.split('/').cmp(b.split('/'))
+}
+
+/// pathがdirそのものか、その下にある。dirが空なら全て
+fn is_under(path: &str, dir: &str) -> bool {
+    dir.is_empty() || path.strip_prefix(dir).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn valid_path(path: &str) -> bool {
+    path.len() <= MAX_PATH
+        && path.split('/').all(|c| !c.is_empty() && c.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b)))
+}
+
+/// 前後の/を取り除く。空ならルート
+fn normalize(path: &str) -> &str {
+    path.trim().trim_matches('/')
+}
+
+/// パスの順に並んだノードの表。メモリ割り当てを行わないので、アロケータより前から登録できる
+pub struct Tree<const N: usize> {
+    nodes: [Option<Node>; N],
+    len: usize,
+}
+
+impl<const N: usize> Tree<N> {
+    pub const fn new() -> Self {
+        Self { nodes: [None; N], len: 0 }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Node> {
+        self.nodes[..self.len].iter().flatten()
+    }
+
+    pub fn insert(&mut self, path: &str, show: ShowFn, arg: usize) -> Result<(), RegisterError> {
+        if !valid_path(path) {
+            return Err(RegisterError::BadPath);
+        }
+        if self.iter().any(|n| n.path() == path) {
+            return Err(RegisterError::Duplicate);
+        }
+        if self.iter().any(|n| is_under(n.path(), path) || is_under(path, n.path())) {
+            return Err(RegisterError::Conflict);
+        }
+        if self.len == N {
+            return Err(RegisterError::Full);
+        }
+        let mut node = Node { path: [0; MAX_PATH], path_len: path.len(), show, arg };
+        node.path[..path.len()].copy_from_slice(path.as_bytes());
+        // 登録の順によらず、常にパスの順に並べておく
+        let at = self.iter().take_while(|n| cmp_path(n.path(), path) == Ordering::Less).count();
+        self.nodes[at..=self.len].rotate_right(1);
+        self.nodes[at] = Some(node);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn get(&self, path: &str) -> Option<Node> {
+        self.iter().find(|n| n.path() == path).copied()
+    }
+
+    /// dirの下にあって、パスの順でafterより後にある最初の値のノード
+    /// ロックを外している間にノードが増えても、飛ばしたり繰り返したりせずに辿れる
+    pub fn next_under(&self, dir: &str, after: Option<&str>) -> Option<Node> {
+        self.iter()
+            .filter(|n| is_under(n.path(), dir))
+            .find(|n| after.map_or(true, |a| cmp_path(n.path(), a) == Ordering::Greater))
+            .copied()
+    }
+
+    /// dirの直下の名前を順にfに渡す。2つ目の引数はディレクトリならtrue
+    /// dirが値のノードか、何も無ければfalse
+    pub fn list(&self, dir: &str, mut f: impl FnMut(&str, bool)) -> bool {
+        let mut prev: Option<&str> = None;
+        let mut found = false;
+        for n in self.iter().filter(|n| is_under(n.path(), dir)) {
+            let rest = n.path()[dir.len()..].trim_start_matches('/');
+            if rest.is_empty() {
+                return false;
+            }
+            let (name, is_dir) = match rest.split_once('/') {
+                Some((name, _)) => (name, true),
+                None => (rest, false),
+            };
+            // 並んでいるので、同じディレクトリの中身は続いて現れる
+            if prev != Some(name) {
+                f(name, is_dir);
+                prev = Some(name);
+            }
+            found = true;
+        }
+        found
+    }
+}
+
+static TREE: Mutex<Tree<MAX_NODES>> = Mutex::new(Tree::new());
+
+pub fn register(path: &str, show: ShowFn, arg: usize) -> Result<(), RegisterError> {
+    TREE.lock().insert(path, show, arg)
+}
+
+/// スタック上でノードの値を作るためのバッファ。溢れた分は捨てる
+struct Snapshot {
+    buf: [u8; SNAPSHOT_LEN],
+    len: usize,
+    truncated: bool,
+}
+
+impl Snapshot {
+    /// nodeの値を書き出す。nodeが取ったロックはこの中で全て外れている
+    fn take(node: &Node) -> Self {
+        let mut s = Snapshot { buf: [0; SNAPSHOT_LEN], len: 0, truncated: false };
+        if node.show(&mut s).is_err() {
+            s.truncated = true;
+        }
+        s
+    }
+
+    fn as_str(&self) -> &str {
+        // 途中で切れても、切れ目までは正しい
+        match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl Write for Snapshot {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        self.truncated |= n < s.len();
+        Ok(())
+    }
+}
+
+/// ノードを1つ書く。ディレクトリの中身として書くときは、1行なら"path: 値"、複数行なら字下げする
+fn write_node(node: &Node, with_path: bool, out: &mut dyn Write) -> fmt::Result {
+    let snap = Snapshot::take(node);
+    let value = snap.as_str().trim_end_matches('\n');
+    if !with_path {
+        writeln!(out, "{value}")?;
+    } else if value.contains('\n') {
+        writeln!(out, "{}:", node.path())?;
+        for line in value.lines() {
+            writeln!(out, "  {line}")?;
+        }
+    } else {
+        writeln!(out, "{}: {value}", node.path())?;
+    }
+    if snap.truncated {
+        writeln!(out, "  ... (truncated)")?;
+    }
+    Ok(())
+}
+
+/// pathが値ならそれを、ディレクトリなら下にある値を全て書く。空ならルートで、表全体になる
+/// 表のロックはノードを写す間だけ持ち、値を作る間も書く間も持たない
+/// 見つからなければfalse
+pub fn show(path: &str, out: &mut dyn Write) -> bool {
+    let path = normalize(path);
+    let leaf = TREE.lock().get(path);
+    if let Some(node) = leaf {
+        let _ = write_node(&node, false, out);
+        return true;
+    }
+    let mut prev: Option<Node> = None;
+    loop {
+        let next = TREE.lock().next_under(path, prev.as_ref().map(|n| n.path()));
+        let Some(node) = next else {
+            return prev.is_some();
+        };
+        let _ = write_node(&node, true, out);
+        prev = Some(node);
+    }
+}
+
+/// pathの直下の名前を1行に1つ書く。ディレクトリには/を付ける
+/// 見つからないか、値のノードならfalse
+pub fn ls(path: &str, out: &mut dyn Write) -> bool {
+    let path = normalize(path);
+    // 名前は表の中を指しているので、ロックを持ったままスタックに集めてから書く
+    let mut names = Snapshot { buf: [0; SNAPSHOT_LEN], len: 0, truncated: false };
+    let found = TREE.lock().list(path, |name, is_dir| {
+        let _ = writeln!(names, "{name}{}", if is_dir { "/" } else { "" });
+    });
+    let _ = out.write_str(names.as_str());
+    found
+}
+
+pub fn run_introspect_tests() {
+    fn show_arg(arg: usize, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "{arg}")
+    }
+    let paths = ["mem/frames", "usb/slots/3", "mem-x", "gfx/layers", "usb/slots/10", "task/2/stack", "mem/heap"];
+
+    // 登録の順によらず、パスの要素ごとの順に並ぶ
+    let collect = |t: &Tree<16>| {
+        let mut order = [[0u8; MAX_PATH]; 7];
+        let mut after: Option<Node> = None;
+        for slot in order.iter_mut() {
+            let n = t.next_under("", after.as_ref().map(|n| n.path())).unwrap();
+            slot[..n.path_len].copy_from_slice(&n.path[..n.path_len]);
+            after = Some(n);
+        }
+        assert!(t.next_under("", after.as_ref().map(|n| n.path())).is_none());
+        order
+    };
+    let mut a = Tree::<16>::new();
+    for (i, p) in paths.iter().enumerate() {
+        assert!(a.insert(p, show_arg, i) == Ok(()));
+    }
+    let mut b = Tree::<16>::new();
+    for (i, p) in paths.iter().enumerate().rev() {
+        assert!(b.insert(p, show_arg, i) == Ok(()));
+    }
+    let order = collect(&a);
+    assert!(order == collect(&b));
+    let is = |o: &[u8; MAX_PATH], p: &str| o[..p.len()] == *p.as_bytes() && o[p.len()] == 0;
+    assert!(is(&order[0], "gfx/layers") && is(&order[1], "mem/frames") && is(&order[2], "mem/heap") && is(&order[3], "mem-x"));
+    assert!(is(&order[5], "usb/slots/10") && is(&order[6], "usb/slots/3"));
+
+    // 引ける。argは登録したときのもの
+    let mut w = Snapshot { buf: [0; SNAPSHOT_LEN], len: 0, truncated: false };
+    assert!(a.get("usb/slots/3").is_some_and(|n| n.show(&mut w).is_ok()));
+    assert!(w.as_str() == "1\n");
+    assert!(a.get("usb/slots").is_none() && a.get("usb/slots/4").is_none() && a.get("").is_none());
+
+    // 重複や、値とディレクトリの食い違いは拒む
+    assert!(a.insert("mem/frames", show_arg, 0) == Err(RegisterError::Duplicate));
+    assert!(a.insert("mem", show_arg, 0) == Err(RegisterError::Conflict));
+    assert!(a.insert("mem/frames/free", show_arg, 0) == Err(RegisterError::Conflict));
+    assert!(a.insert("mem//x", show_arg, 0) == Err(RegisterError::BadPath));
+    assert!(a.insert("/mem/x", show_arg, 0) == Err(RegisterError::BadPath));
+    assert!(a.insert("mem/a b", show_arg, 0) == Err(RegisterError::BadPath));
+    assert!(a.insert("", show_arg, 0) == Err(RegisterError::BadPath));
+    let mut full = Tree::<1>::new();
+    assert!(full.insert("a", show_arg, 0).is_ok() && full.insert("b", show_arg, 0) == Err(RegisterError::Full));
+
+    // ディレクトリの直下の名前
+    let mut names = Snapshot { buf: [0; SNAPSHOT_LEN], len: 0, truncated: false };
+    assert!(a.list("", |name, is_dir| { let _ = write!(names, "{name}{} ", if is_dir { "/" } else { "" }); }));
+    assert!(names.as_str() == "gfx/ mem/ mem-x task/ usb/ ");
+    let mut names = Snapshot { buf: [0; SNAPSHOT_LEN], len: 0, truncated: false };
+    assert!(a.list("usb/slots", |name, _| { let _ = write!(names, "{name} "); }));
+    assert!(names.as_str() == "10 3 ");
+    assert!(!a.list("mem/frames", |_, _| ()) && !a.list("nothing", |_, _| ()) && !a.list("me", |_, _| ()));
+
+    // ディレクトリの中身を辿る間に増えても、順に1度ずつ辿れる
+    let n = a.next_under("mem", None).unwrap();
+    assert!(n.path() == "mem/frames");
+    assert!(a.insert("mem/a", show_arg, 0).is_ok());
+    let n = a.next_under("mem", Some(n.path())).unwrap();
+    assert!(n.path() == "mem/heap");
+    assert!(a.next_under("mem", Some(n.path())).is_none());
+
+    // 溢れた値は切り詰める
+    fn show_long(_: usize, out: &mut dyn Write) -> fmt::Result {
+        for _ in 0..SNAPSHOT_LEN {
+            out.write_str("xy")?;
+        }
+        Ok(())
+    }
+    assert!(a.insert("long", show_long, 0).is_ok());
+    let snap = Snapshot::take(&a.get("long").unwrap());
+    assert!(snap.truncated && snap.as_str().len() == SNAPSHOT_LEN);
+}
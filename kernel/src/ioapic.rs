@@ -0,0 +1,112 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::{acpi::{self, InterruptOverride, IoApicInfo}, asm::io_out_8};
+
+/// レジスタの番号を書くIOREGSELと、選んだレジスタを読み書きするIOWINの位置
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPICVER: u32 = 0x01;
+/// n番目の入力のリダイレクションエントリは0x10 + 2n(下位)と0x11 + 2n(上位)
+const REDIRECTION_TABLE: u32 = 0x10;
+
+/// リダイレクションエントリのビット。配送モードは固定、宛先は物理モード
+const POLARITY_LOW: u64 = 1 << 13;
+const TRIGGER_LEVEL: u64 = 1 << 15;
+const DESTINATION_SHIFT: u64 = 56;
+
+/// MPS INTI flagsの極性とトリガーモード。0b00は「バスに従う」で、ISAならアクティブハイ、エッジ
+const INTI_POLARITY_MASK: u16 = 0b11;
+const INTI_ACTIVE_LOW: u16 = 0b11;
+const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+const INTI_LEVEL: u16 = 0b11 << 2;
+
+/// 8259 PICのマスクレジスタ(マスター、スレーブ)
+const PIC_MASK_PORTS: [u16; 2] = [0x21, 0xa1];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    /// このGSIを受け持つI/O APICがMADTに無い
+    NoIoApic { gsi: u32 },
+}
+
+/// ISAのIRQが繋がっているGSIと、そのINTI flags。付け替えが無ければIRQと同じ番号
+fn isa_irq_to_gsi(irq: u8, overrides: &[InterruptOverride]) -> (u32, u16) {
+    overrides.iter().find(|o| o.irq == irq).map_or((irq as u32, 0), |o| (o.gsi, o.flags))
+}
+
+/// gsiを受け持つ候補。gsi_baseがgsi以下で一番大きいもの。入力の数は書き込むときに確かめる
+fn owner_of(gsi: u32, io_apics: &[IoApicInfo]) -> Option<&IoApicInfo> {
+    io_apics.iter().filter(|a| a.gsi_base <= gsi).max_by_key(|a| a.gsi_base)
+}
+
+/// apic_idのLocal APICのvectorへ届けるエントリ。マスクはしない
+fn redirection_entry(vector: u8, flags: u16, apic_id: u8) -> u64 {
+    let mut entry = vector as u64 | (apic_id as u64) << DESTINATION_SHIFT;
+    if flags & INTI_POLARITY_MASK == INTI_ACTIVE_LOW {
+        entry |= POLARITY_LOW;
+    }
+    if flags & INTI_TRIGGER_MASK == INTI_LEVEL {
+        entry |= TRIGGER_LEVEL;
+    }
+    entry
+}
+
+unsafe fn read(io_apic: &IoApicInfo, reg: u32) -> u32 {
+    write_volatile((io_apic.address as u64 + IOREGSEL) as *mut u32, reg);
+    read_volatile((io_apic.address as u64 + IOWIN) as *const u32)
+}
+
+unsafe fn write(io_apic: &IoApicInfo, reg: u32, value: u32) {
+    write_volatile((io_apic.address as u64 + IOREGSEL) as *mut u32, reg);
+    write_volatile((io_apic.address as u64 + IOWIN) as *mut u32, value);
+}
+
+/// 入力の数。バージョンレジスタのbit 16-23が最後のエントリの番号
+unsafe fn input_count(io_apic: &IoApicInfo) -> u32 {
+    ((read(io_apic, IOAPICVER) >> 16) & 0xff) + 1
+}
+
+/// ISAのIRQをI/O APICで受け、apic_idのCPUのvectorに届ける。使ったGSIを返す
+/// MADTの付け替えがあればそれに従い、極性とトリガーモードもそこから取る
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u8) -> Result<u32, IoApicError> {
+    let (gsi, flags) = isa_irq_to_gsi(irq, acpi::interrupt_overrides());
+    let io_apic = owner_of(gsi, acpi::io_apics()).ok_or(IoApicError::NoIoApic { gsi })?;
+    let input = gsi - io_apic.gsi_base;
+    let entry = redirection_entry(vector, flags, apic_id);
+    unsafe {
+        if input >= input_count(io_apic) {
+            return Err(IoApicError::NoIoApic { gsi });
+        }
+        // 宛先を先に書いてから、マスクの無い下位を書く
+        write(io_apic, REDIRECTION_TABLE + 2 * input + 1, (entry >> 32) as u32);
+        write(io_apic, REDIRECTION_TABLE + 2 * input, entry as u32);
+    }
+    Ok(gsi)
+}
+
+/// 8259 PICの入力を全てマスクする。I/O APICから受けるIRQが、PICからも古いベクタで届かないようにする
+pub fn mask_legacy_pic() {
+    for port in PIC_MASK_PORTS {
+        unsafe {
+            io_out_8(port, 0xff);
+        }
+    }
+}
+
+pub fn run_ioapic_tests() {
+    let overrides = [InterruptOverride { irq: 0, gsi: 2, flags: 0 }, InterruptOverride { irq: 9, gsi: 9, flags: 0b1111 }];
+    assert!(isa_irq_to_gsi(0, &overrides) == (2, 0));
+    assert!(isa_irq_to_gsi(1, &overrides) == (1, 0));
+    assert!(isa_irq_to_gsi(9, &overrides) == (9, 0b1111));
+
+    let io_apics = [IoApicInfo { id: 0, address: 0xfec0_0000, gsi_base: 0 }, IoApicInfo { id: 1, address: 0xfec0_1000, gsi_base: 24 }];
+    assert!(owner_of(12, &io_apics).is_some_and(|a| a.id == 0));
+    assert!(owner_of(30, &io_apics).is_some_and(|a| a.id == 1));
+    assert!(owner_of(1, &io_apics[1..]).is_none());
+
+    // ISAの既定はアクティブハイ、エッジ。アクティブローでレベルのもの(ACPIのSCIなど)はビットが立つ
+    assert!(redirection_entry(0x42, 0, 3) == 0x0300_0000_0000_0042);
+    assert!(redirection_entry(0x43, 0b1111, 0) == 0x43 | POLARITY_LOW | TRIGGER_LEVEL);
+    // 0b01は明示的なアクティブハイ、エッジ
+    assert!(redirection_entry(0x43, 0b0101, 0) == 0x43);
+}
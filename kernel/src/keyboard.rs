@@ -0,0 +1,189 @@
+use core::ops::RangeInclusive;
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{
+    memory_manager::Mutex,
+    timer::{add_timer_deferred, get_current_tick, ms_to_ticks},
+    usb::{self, KeyReport, LockState, ModifierSet, KEY_CAPS_LOCK, KEY_NUM_LOCK, KEY_SCROLL_LOCK},
+};
+
+/// 同時押しが多すぎるときに全てのキーコードの位置に入る値
+const KEY_ERROR_ROLL_OVER: u8 = 0x01;
+/// 修飾キーのUsage ID。ふつうは修飾キーのバイトで来るが、キーコードに入れてくるキーボードもある
+const MODIFIER_KEYS: RangeInclusive<u8> = 0xe0..=0xe7;
+
+/// レポートの差から作るキーの出来事。押し続けている間はPressedが繰り返し来る
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// キーコードと、そのときの修飾キー
+    Pressed(u8, ModifierSet),
+    Released(u8),
+}
+
+/// キーリピートの間隔
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatConfig {
+    /// 押してから繰り返し始めるまで(ミリ秒)
+    pub delay_ms: u64,
+    /// 繰り返しの間隔(ミリ秒)。tickより短くはならない
+    pub interval_ms: u64,
+}
+
+impl RepeatConfig {
+    pub const DEFAULT: RepeatConfig = RepeatConfig { delay_ms: 500, interval_ms: 33 };
+}
+
+/// 前のレポートを覚えておき、新しいレポートとの差を押したキーと離したキーにする。
+/// 最後に押したキーを押し続けている間は繰り返す
+struct KeyTracker {
+    prev_keys: [u8; 6],
+    modifier: ModifierSet,
+    locks: LockState,
+    /// 繰り返しているキー
+    held: Option<u8>,
+    /// heldが変わるたびに進める。止めた繰り返しのタイマーを見分ける
+    generation: usize,
+}
+
+impl KeyTracker {
+    const fn new() -> Self {
+        Self { prev_keys: [0; 6], modifier: ModifierSet::from_bits(0), locks: LockState::from_bits(0), held: None, generation: 0 }
+    }
+
+    /// レポートを取り込み、起きたことを離したもの、押したものの順にoutへ足す。
+    /// 新しく繰り返すキーができたら、その繰り返しの世代を返す
+    fn update(&mut self, report: &KeyReport, locks: LockState, out: &mut Vec<KeyEvent>) -> Option<usize> {
+        // ロールオーバーのレポートにはどのキーが押されているかの情報がないので、前の状態のままにする
+        if report.keycodes.contains(&KEY_ERROR_ROLL_OVER) {
+            return None;
+        }
+        self.modifier = report.modifier;
+        self.locks = locks;
+        let keys = report.keycodes;
+        out.extend(self.prev_keys.iter().filter(|k| **k != 0 && !keys.contains(k)).map(|k| KeyEvent::Released(*k)));
+        let mut newest = None;
+        for key in keys.iter().copied().filter(|k| *k != 0 && !self.prev_keys.contains(k)) {
+            out.push(KeyEvent::Pressed(key, self.modifier));
+            if repeats(key) {
+                newest = Some(key);
+            }
+        }
+        self.prev_keys = keys;
+
+        if newest.is_some() {
+            self.held = newest;
+        } else if self.held.is_some_and(|k| !keys.contains(&k)) {
+            self.held = None;
+        } else {
+            return None;
+        }
+        self.generation = self.generation.wrapping_add(1);
+        self.held.map(|_| self.generation)
+    }
+
+    /// 世代generationの繰り返しがまだ続いていれば、次に出すPressed
+    fn repeat(&self, generation: usize) -> Option<KeyEvent> {
+        self.held.filter(|_| generation == self.generation).map(|k| KeyEvent::Pressed(k, self.modifier))
+    }
+}
+
+/// 押し続けて繰り返すキーか。修飾キーとロックキーは繰り返さない
+fn repeats(keycode: u8) -> bool {
+    !MODIFIER_KEYS.contains(&keycode) && ![KEY_CAPS_LOCK, KEY_NUM_LOCK, KEY_SCROLL_LOCK].contains(&keycode)
+}
+
+static TRACKER: Mutex<KeyTracker> = Mutex::new(KeyTracker::new());
+static REPEAT: Mutex<RepeatConfig> = Mutex::new(RepeatConfig::DEFAULT);
+static LISTENERS: Mutex<Vec<Box<dyn Fn(KeyEvent) + Send>>> = Mutex::new(Vec::new());
+
+/// キーの出来事を受け取る関数を登録する。メインループから登録した順に呼ばれる
+/// 呼ばれている間は登録の一覧をロックしているので、中からon_key_eventを呼んではいけない
+pub fn on_key_event(listener: Box<dyn Fn(KeyEvent) + Send>) {
+    LISTENERS.lock().push(listener);
+}
+
+pub fn set_repeat(config: RepeatConfig) {
+    *REPEAT.lock() = config;
+}
+
+pub fn repeat_config() -> RepeatConfig {
+    *REPEAT.lock()
+}
+
+/// USBキーボードのレポートを1つ取り込み、差を登録された関数に渡す。locksはこのレポートを処理した後のロックキーの状態
+pub fn feed(report: &KeyReport, locks: LockState) {
+    let mut events = Vec::new();
+    let started = TRACKER.lock().update(report, locks, &mut events);
+    if let Some(generation) = started {
+        add_timer_deferred(get_current_tick() + ms_to_ticks(repeat_config().delay_ms), on_repeat, generation);
+    }
+    dispatch(&events);
+}
+
+fn on_repeat(generation: usize) {
+    let Some(event) = TRACKER.lock().repeat(generation) else {
+        return;
+    };
+    add_timer_deferred(get_current_tick() + ms_to_ticks(repeat_config().interval_ms).max(1), on_repeat, generation);
+    dispatch(&[event]);
+}
+
+fn dispatch(events: &[KeyEvent]) {
+    let listeners = LISTENERS.lock();
+    for event in events {
+        for listener in listeners.iter() {
+            listener(*event);
+        }
+    }
+}
+
+/// 最後に受け取ったロックキーの状態とmodifierで、keycodeを文字に変換する
+pub fn translate(keycode: u8, modifier: ModifierSet) -> Option<char> {
+    let locks = TRACKER.lock().locks;
+    usb::translate_key(keycode, modifier.is_shifted(), locks)
+}
+
+pub fn run_keyboard_tests() {
+    use KeyEvent::{Pressed, Released};
+
+    let report = |modifier: u8, keycodes: [u8; 6]| KeyReport { modifier: ModifierSet::from_bits(modifier), _rsvd: 0, keycodes };
+    let none = ModifierSet::default();
+    let shift = ModifierSet::from_bits(0b10);
+    let mut t = KeyTracker::new();
+    let feed = |t: &mut KeyTracker, r: KeyReport| {
+        let mut out = Vec::new();
+        let started = t.update(&r, LockState::default(), &mut out);
+        (out, started)
+    };
+
+    // 押し続けても1度だけ。離してからもう一度押せばまた出る
+    let (out, started) = feed(&mut t, report(0, [0x04, 0, 0, 0, 0, 0]));
+    assert!(out == [Pressed(0x04, none)] && started.is_some());
+    let first = started.unwrap();
+    let (out, started) = feed(&mut t, report(0, [0x04, 0, 0, 0, 0, 0]));
+    assert!(out.is_empty() && started.is_none() && t.repeat(first) == Some(Pressed(0x04, none)));
+    // 後から押したキーが繰り返しを引き継ぎ、前の繰り返しは止まる。修飾キーは今の状態で付く
+    let (out, started) = feed(&mut t, report(0b10, [0x04, 0x05, 0, 0, 0, 0]));
+    assert!(out == [Pressed(0x05, shift)] && t.repeat(first).is_none());
+    let second = started.unwrap();
+    assert!(t.repeat(second) == Some(Pressed(0x05, shift)));
+    // ロールオーバーのレポートは無視する
+    let (out, started) = feed(&mut t, report(0, [KEY_ERROR_ROLL_OVER; 6]));
+    assert!(out.is_empty() && started.is_none() && t.repeat(second) == Some(Pressed(0x05, shift)));
+    // 繰り返していないキーを離しても、繰り返しは続く
+    let (out, started) = feed(&mut t, report(0, [0x05, 0, 0, 0, 0, 0]));
+    assert!(out == [Released(0x04)] && started.is_none() && t.repeat(second) == Some(Pressed(0x05, none)));
+    let (out, started) = feed(&mut t, report(0, [0; 6]));
+    assert!(out == [Released(0x05)] && started.is_none() && t.repeat(second).is_none());
+    // 位置が変わっても同じキーなら押し直しではない
+    feed(&mut t, report(0, [0, 0, 0, 0, 0x04, 0]));
+    let (out, _) = feed(&mut t, report(0, [0x04, 0, 0, 0, 0, 0]));
+    assert!(out.is_empty());
+
+    // ロックキーと修飾キーは繰り返さない
+    let mut t = KeyTracker::new();
+    let (out, started) = feed(&mut t, report(0, [KEY_CAPS_LOCK, 0xe1, 0, 0, 0, 0]));
+    assert!(out == [Pressed(KEY_CAPS_LOCK, none), Pressed(0xe1, none)] && started.is_none());
+    assert!(repeats(0x04) && !repeats(KEY_NUM_LOCK) && !repeats(0xe7));
+}
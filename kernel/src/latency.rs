@@ -0,0 +1,259 @@
+use core::{fmt::{self, Write}, sync::atomic::{AtomicBool, Ordering}};
+
+use alloc::string::String;
+
+use crate::{graphic::{focus, font::write_string, palette::{OVERLAY_BG, OVERLAY_FG}, graphics::PixelWriter, window::{LayerHandle, Placement, Window}, with_layers}, memory_manager::{LazyInit, Mutex}, shortcut::{self, Mods}, timer::{add_timer_deferred, get_current_tick, Timestamp}};
+
+/// 統計の対象にする直近のイベント数
+const N_SAMPLES: usize = 256;
+
+/// オーバーレイを描き直す間隔(tick)
+const REFRESH_INTERVAL: u64 = 50;
+
+/// オーバーレイの表示・非表示を切り替えるキー (F12)
+const KEY_TOGGLE_OVERLAY: u8 = 0x45;
+/// ヒストグラムをコンソールに出力するキー (F11)
+const KEY_DUMP: u8 = 0x44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Mouse,
+    Keyboard,
+}
+
+/// 計測区間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// 割り込みの到着からメインループでの取り出しまで
+    Dequeue,
+    /// 取り出しから画面更新の完了まで
+    Render,
+    /// 割り込みの到着から画面更新の完了まで
+    Total,
+}
+
+impl Stage {
+    const ALL: [Stage; 3] = [Stage::Dequeue, Stage::Render, Stage::Total];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Dequeue => "dequeue",
+            Stage::Render => "render",
+            Stage::Total => "total",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    kind: EventKind,
+    arrival: Timestamp,
+    dequeue: Timestamp,
+    done: Timestamp,
+}
+
+impl Sample {
+    const EMPTY: Sample = Sample {
+        kind: EventKind::Mouse,
+        arrival: Timestamp { tick: 0, count: 0 },
+        dequeue: Timestamp { tick: 0, count: 0 },
+        done: Timestamp { tick: 0, count: 0 },
+    };
+
+    fn micros(&self, stage: Stage) -> u64 {
+        match stage {
+            Stage::Dequeue => self.arrival.micros_until(&self.dequeue),
+            Stage::Render => self.dequeue.micros_until(&self.done),
+            Stage::Total => self.arrival.micros_until(&self.done),
+        }
+    }
+}
+
+/// 直近N_SAMPLES件の計測結果を保持するリングバッファ
+/// 記録の際にメモリ割り当ては行わない
+struct LatencyLog {
+    samples: [Sample; N_SAMPLES],
+    next: usize,
+    len: usize,
+    /// 処理中のxHCI割り込みの (到着時刻, 取り出し時刻)
+    current: Option<(Timestamp, Timestamp)>,
+}
+
+impl LatencyLog {
+    const fn new() -> Self {
+        Self { samples: [Sample::EMPTY; N_SAMPLES], next: 0, len: 0, current: None }
+    }
+
+    fn push(&mut self, sample: Sample) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % N_SAMPLES;
+        self.len = (self.len + 1).min(N_SAMPLES);
+    }
+}
+
+static LOG: Mutex<LatencyLog> = Mutex::new(LatencyLog::new());
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub count: usize,
+    pub min: u64,
+    pub avg: u64,
+    pub p99: u64,
+}
+
+/// メインループでxHCIの割り込みを取り出したときに呼ぶ
+pub fn begin(arrival: Timestamp) {
+    LOG.lock().current = Some((arrival, Timestamp::now()));
+}
+
+/// 割り込みに対する処理が終わったときに呼ぶ
+pub fn end() {
+    LOG.lock().current = None;
+}
+
+/// イベントによる画面更新が完了したときに呼ぶ
+pub fn complete(kind: EventKind) {
+    let done = Timestamp::now();
+    let mut log = LOG.lock();
+    if let Some((arrival, dequeue)) = log.current {
+        log.push(Sample { kind, arrival, dequeue, done });
+    }
+}
+
+/// 直近のイベントについての区間ごとの統計。kindがNoneなら全種類のイベントを対象にする
+pub fn stats(stage: Stage, kind: Option<EventKind>) -> Option<Stats> {
+    let mut values = [0u64; N_SAMPLES];
+    let mut count = 0;
+    {
+        let log = LOG.lock();
+        for sample in &log.samples[..log.len] {
+            if kind.map_or(true, |k| k == sample.kind) {
+                values[count] = sample.micros(stage);
+                count += 1;
+            }
+        }
+    }
+    if count == 0 {
+        return None;
+    }
+
+    let values = &mut values[..count];
+    values.sort_unstable();
+    Some(Stats {
+        count,
+        min: values[0],
+        avg: values.iter().sum::<u64>() / count as u64,
+        p99: values[(count * 99 / 100).min(count - 1)],
+    })
+}
+
+/// 区間ごとのヒストグラムを書く
+pub fn write_histogram(out: &mut dyn Write) -> fmt::Result {
+    const BUCKETS_US: [u64; 8] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000];
+
+    for stage in Stage::ALL {
+        let Some(st) = stats(stage, None) else {
+            return writeln!(out, "latency: no samples");
+        };
+        writeln!(out, "[{}] n={} min={}us avg={}us p99={}us", stage.name(), st.count, st.min, st.avg, st.p99)?;
+
+        let mut hist = [0usize; BUCKETS_US.len() + 1];
+        {
+            let log = LOG.lock();
+            for sample in &log.samples[..log.len] {
+                let us = sample.micros(stage);
+                let i = BUCKETS_US.iter().position(|b| us < *b).unwrap_or(BUCKETS_US.len());
+                hist[i] += 1;
+            }
+        }
+        for (i, n) in hist.iter().enumerate() {
+            if i < BUCKETS_US.len() {
+                write!(out, "  <{:>6}us {:>3} ", BUCKETS_US[i], n)?;
+            } else {
+                write!(out, "  >={:>5}us {:>3} ", BUCKETS_US[i - 1], n)?;
+            }
+            for _ in 0..(n * 40 + st.count - 1) / st.count {
+                write!(out, "#")?;
+            }
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// ヒストグラムをコンソールに出力する
+pub fn dump() {
+    let mut out = String::new();
+    let _ = write_histogram(&mut out);
+    for line in out.lines() {
+        println!("{line}");
+    }
+}
+
+static OVERLAY: LazyInit<LayerHandle> = LazyInit::new();
+static OVERLAY_ENABLED: AtomicBool = AtomicBool::new(false);
+
+const OVERLAY_W: usize = 8 * 30;
+const OVERLAY_H: usize = 16 * 4 + 8;
+
+/// オーバーレイのウィンドウを作り、ホットキーを登録する。表示は最初はオフ
+pub fn init_overlay() {
+    with_layers(|l| {
+        let (width, _) = l.resolution();
+        let mut win = Window::new(OVERLAY_W, OVERLAY_H, Some(OVERLAY_BG));
+        win.move_to((width as i32 - OVERLAY_W as i32, 0).into());
+        OVERLAY.lock().init(l.new_layer(win, Placement::Hidden));
+    });
+    shortcut::register_global(Mods::NONE, KEY_TOGGLE_OVERLAY, "latency", |_| set_overlay(!overlay_enabled()), 0)
+        .expect("latency: overlay hotkey");
+    shortcut::register_global(Mods::NONE, KEY_DUMP, "latency", |_| dump(), 0).expect("latency: dump hotkey");
+}
+
+pub fn overlay_enabled() -> bool {
+    OVERLAY_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_overlay(enabled: bool) {
+    if !OVERLAY.lock().is_init() {
+        return;
+    }
+    if OVERLAY_ENABLED.swap(enabled, Ordering::Relaxed) == enabled {
+        return;
+    }
+
+    let layer_id = OVERLAY.lock().layer_id();
+    if enabled {
+        render_overlay();
+        // ウィンドウより上、マウスカーソルより下に置く
+        focus::keep_on_top(layer_id);
+        add_timer_deferred(get_current_tick() + REFRESH_INTERVAL, on_timer, 0);
+    } else {
+        with_layers(|l| l.hide(layer_id));
+    }
+}
+
+fn on_timer(_: usize) {
+    if !overlay_enabled() {
+        return;
+    }
+    render_overlay();
+    add_timer_deferred(get_current_tick() + REFRESH_INTERVAL, on_timer, 0);
+}
+
+fn render_overlay() {
+    let overlay = OVERLAY.lock();
+    let window = overlay.window().read();
+    window.buffer().write_with(|back| {
+        back.fill_rect((0, 0).into(), (OVERLAY_W as u32, OVERLAY_H as u32).into(), OVERLAY_BG);
+        let header = format!("{:<8}{:>7}{:>7}{:>7}", "[us]", "min", "avg", "p99");
+        write_string(back, 4, 4, header.as_bytes(), OVERLAY_FG);
+        for (i, stage) in Stage::ALL.iter().enumerate() {
+            let line = match stats(*stage, None) {
+                Some(st) => format!("{:<8}{:>7}{:>7}{:>7}", stage.name(), st.min, st.avg, st.p99),
+                None => format!("{:<8}{:>7}{:>7}{:>7}", stage.name(), "-", "-", "-"),
+            };
+            write_string(back, 4, 4 + 16 * (i as u32 + 1), line.as_bytes(), OVERLAY_FG);
+        }
+    });
+    window.buffer().flush();
+}
@@ -0,0 +1,152 @@
+use core::{cell::UnsafeCell, sync::atomic::{AtomicUsize, Ordering}};
+
+/// ロックを使わないバイト列のリングバッファ
+/// 書き手と読み手がそれぞれ同時に1つだけのときに使える(割り込みハンドラが書き、メインループが読む)
+/// 割り込みは入れ子にならないので、ハンドラが書き手なら同時に書くことはない
+pub struct LogRing<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    /// これまでに書いたバイト数
+    head: AtomicUsize,
+    /// これまでに読んだバイト数
+    tail: AtomicUsize,
+    /// 入りきらずに捨てたメッセージの数
+    dropped: AtomicUsize,
+}
+
+unsafe impl<const N: usize> Sync for LogRing<N> {}
+
+impl<const N: usize> LogRing<N> {
+    pub const fn new() -> Self {
+        Self { buf: UnsafeCell::new([0; N]), head: AtomicUsize::new(0), tail: AtomicUsize::new(0), dropped: AtomicUsize::new(0) }
+    }
+
+    /// bytesを丸ごと追加する。空きが足りなければ何も書かずにfalse
+    pub fn push(&self, bytes: &[u8]) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head - tail + bytes.len() > N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let buf = unsafe { &mut *self.buf.get() };
+        for (i, b) in bytes.iter().enumerate() {
+            buf[(head + i) % N] = *b;
+        }
+        self.head.store(head + bytes.len(), Ordering::Release);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// 溜まっているものを古い順にfに渡して取り除く。末尾で折り返していれば2回に分けて渡す
+    pub fn drain(&self, mut f: impl FnMut(&[u8])) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return;
+        }
+        let buf = unsafe { &*self.buf.get() };
+        let (start, end) = (tail % N, head % N);
+        if start < end {
+            f(&buf[start..end]);
+        } else {
+            f(&buf[start..]);
+            f(&buf[..end]);
+        }
+        self.tail.store(head, Ordering::Release);
+    }
+}
+
+/// 最後に書かれたNバイトだけを残すリングバッファ。古いものから上書きする
+/// 書き手は同時に1つだけ(コンソールのロックの中か割り込みハンドラ)。読み手はパニックハンドラで、
+/// 書き手を止めた後にロックを取らずに写す
+pub struct HistoryRing<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    /// これまでに書いたバイト数
+    head: AtomicUsize,
+}
+
+unsafe impl<const N: usize> Sync for HistoryRing<N> {}
+
+impl<const N: usize> HistoryRing<N> {
+    pub const fn new() -> Self {
+        Self { buf: UnsafeCell::new([0; N]), head: AtomicUsize::new(0) }
+    }
+
+    pub fn push(&self, bytes: &[u8]) {
+        let head = self.head.load(Ordering::Relaxed);
+        // Nバイトより長ければ、どのみち残るのは最後のNバイトだけ
+        let skip = bytes.len().saturating_sub(N);
+        let buf = unsafe { &mut *self.buf.get() };
+        for (i, b) in bytes[skip..].iter().enumerate() {
+            buf[(head + skip + i) % N] = *b;
+        }
+        self.head.store(head + bytes.len(), Ordering::Release);
+    }
+
+    /// 残っているもの(outに入りきらなければその最後の部分)を古い順にoutへ写し、写した長さを返す
+    pub fn snapshot(&self, out: &mut [u8]) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let len = head.min(N).min(out.len());
+        let buf = unsafe { &*self.buf.get() };
+        for (i, b) in out[..len].iter_mut().enumerate() {
+            *b = buf[(head - len + i) % N];
+        }
+        len
+    }
+}
+
+pub fn run_log_ring_tests() {
+    let ring: LogRing<8> = LogRing::new();
+    let collect = |ring: &LogRing<8>| {
+        let mut out = [0u8; 16];
+        let mut len = 0;
+        ring.drain(|bytes| {
+            out[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        });
+        (out, len)
+    };
+
+    assert!(ring.is_empty());
+    assert!(ring.push(b"abc") && ring.push(b"de"));
+    // 入りきらないものは丸ごと捨てる
+    assert!(!ring.push(b"fghi") && ring.dropped() == 1);
+    let (out, len) = collect(&ring);
+    assert!(&out[..len] == b"abcde" && ring.is_empty());
+
+    // 末尾をまたいでも順番どおりに読める
+    assert!(ring.push(b"123456"));
+    let (out, len) = collect(&ring);
+    assert!(&out[..len] == b"123456");
+    assert!(ring.push(b"12345678"));
+    assert!(!ring.push(b"9"));
+    let (out, len) = collect(&ring);
+    assert!(&out[..len] == b"12345678");
+    assert!(collect(&ring).1 == 0);
+
+    // アロケータより前に走るので、写す先はスタックに置く
+    let history: HistoryRing<8> = HistoryRing::new();
+    let mut out = [0u8; 16];
+    assert!(history.snapshot(&mut out) == 0);
+    history.push(b"abc");
+    history.push(b"def");
+    let len = history.snapshot(&mut out);
+    assert!(&out[..len] == b"abcdef");
+    // 古いものから上書きされ、最後の8バイトが古い順に読める
+    history.push(b"ghij");
+    let len = history.snapshot(&mut out);
+    assert!(&out[..len] == b"cdefghij");
+    // 読み手のバッファが小さければ新しい方を残す
+    let mut small = [0u8; 3];
+    assert!(history.snapshot(&mut small) == 3 && &small == b"hij");
+    history.push(b"0123456789");
+    let len = history.snapshot(&mut out);
+    assert!(&out[..len] == b"23456789");
+}
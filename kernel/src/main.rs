@@ -4,6 +4,8 @@
 #![feature(alloc_error_handler)]
 #![feature(allocator_api)]
 
+mod addr;
+mod ansi;
 mod graphic;
 #[macro_use]
 mod console;
@@ -15,7 +17,31 @@ mod memory_manager;
 mod segment;
 mod paging;
 mod acpi;
+mod ioapic;
+mod serial;
 mod timer;
+mod latency;
+mod log_ring;
+mod introspect;
+mod command;
+mod autoexec;
+mod platform;
+mod symbols;
+mod heap_profile;
+mod heap_sweep;
+mod hpet;
+mod initrd;
+mod serial_console;
+mod shortcut;
+mod rtc;
+mod clock;
+mod deferred;
+mod event_loop;
+mod indicator;
+mod viewer;
+mod textfield;
+mod keyboard;
+mod ps2;
 mod usb;
 mod asm;
 mod task;
@@ -30,33 +56,37 @@ use core::panic::PanicInfo;
 use core::arch::{asm, global_asm};
 use core::ptr::write_volatile;
 use core::str::from_utf8;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use acpi::RSDP;
-use alloc::string::ToString;
-use alloc::boxed::Box;
+use autoexec::BootScriptRaw;
+use initrd::InitrdRaw;
+use symbols::KernelSymbolsRaw;
 use graphic::frame_buffer::{FrameBuffer, FrameBufferRaw};
 use graphic::graphics::PixelWriter;
-use graphic::with_layers;
+use graphic::{emergency::{self, EmergencyWriter}, palette, with_layers};
 use interrupt::{set_idt_entry, IVIndex, InterruptDescriptor, InterruptDescriptorAttribute, DescriptorType, load_idt};
-use memory_manager::LazyInit;
+use memory_manager::Mutex;
 use memory_map::{MemoryMapRaw, MemoryMap};
+use platform::qemu::DebugconWriter;
 use pci::{PCIController, PCIDevice, configure_msi_fixed_destination};
 
 use task::switch_tasks;
 
-use crate::asm::get_cr3;
-use crate::console::init_console;
+use crate::console::{init_console, StackWriter};
+use crate::event_loop::{EventLoop, Handlers, Message, MessageKind, EVENTS};
 use crate::graphic::font::write_string;
 use crate::interrupt::set_interrupt_flag;
 use crate::memory_manager::init_allocators;
-use crate::mouse::draw_cursor;
+use crate::mouse::MOUSE_CURSOR_SHAPE;
 use crate::paging::setup_identity_page_table;
-use crate::segment::{setup_segments, KERNEL_CS, KERNEL_SS};
-use crate::task::{init_task_manager, TaskContext};
-use crate::timer::{add_timer, get_current_tick, initialize_timer};
+use crate::segment::setup_segments;
+use crate::task::init_task_manager;
+use crate::timer::{add_periodic_timer, get_current_tick, initialize_timer, Timestamp};
 use crate::usb::init_usb;
 use crate::usb::xhci::initialize_xhci;
-use crate::graphic::window::Window;
+use crate::graphic::window::{Placement, Window};
 
 
 const LOGO: [u64;26] = [
@@ -88,12 +118,10 @@ const LOGO: [u64;26] = [
     0b00000000000000000000001111110000000,
 ];
 
-static EVENTS: LazyInit<MessageQueue<1024>> = LazyInit::new();
-
 fn scan_pci_devices() -> PCIController {
     let mut pci = PCIController::new();
     unsafe {
-        pci.scan_all_bus().unwrap();
+        pci.scan_all_bus();
         for dev in pci.get_devices() {
             let classcode = dev.read_class_code();
 
@@ -110,11 +138,8 @@ fn scan_pci_devices() -> PCIController {
     pci
 }
 
-fn find_xhc_device() -> PCIDevice {
-    let mut pci = PCIController::new();
+fn find_xhc_device(pci: &PCIController) -> PCIDevice {
     unsafe {
-        pci.scan_all_bus().unwrap();
-
         // look for xhc devices, prioritizing Intel ones
         let mut xhc_device = None;
         for dev in pci.get_devices() {
@@ -125,7 +150,7 @@ fn find_xhc_device() -> PCIDevice {
                 }
             }
         }
-        xhc_device.unwrap().clone()
+        xhc_device.expect("pci: no xHC found").clone()
     }
 }
 
@@ -161,10 +186,10 @@ static mut kernel_main_stack: Stack = Stack([0u8;1024*1024]);
 
 #[no_mangle]
 #[allow(unreachable_code)]
-pub unsafe extern "sysv64" fn KernelMain(fb: *const FrameBufferRaw, mm: *const MemoryMapRaw, rsdp: *const RSDP) -> ! {
+pub unsafe extern "sysv64" fn KernelMain(fb: *const FrameBufferRaw, mm: *const MemoryMapRaw, rsdp: *const RSDP, script: *const BootScriptRaw, syms: *const KernelSymbolsRaw, rd: *const InitrdRaw) -> ! {
     unsafe { 
         asm!("lea rsp, [kernel_main_stack + 1024 * 1024]");
-        KernelMain2(fb, mm, rsdp);
+        KernelMain2(fb, mm, rsdp, script, syms, rd);
         asm!(
             "   hlt",
             "   jmp .fin"
@@ -174,67 +199,168 @@ pub unsafe extern "sysv64" fn KernelMain(fb: *const FrameBufferRaw, mm: *const M
 
 pub fn draw_window(window: &mut FrameBuffer, title: &[u8]) {
     let (win_w, win_h) = window.resolution();
-    window.fill_rect((0,0).into(), (win_w,1).into(), (0xc6,0xc6,0xc6));
-    window.fill_rect((1,1).into(), (win_w-2,1).into(), (0xff,0xff,0xff));
-    window.fill_rect((0,0).into(), (1, win_h).into(), (0xc6,0xc6,0xc6));
-    window.fill_rect((1,1).into(), (1, win_h-2).into(), (0xff,0xff,0xff));
-    window.fill_rect((win_w as i32 - 2,1).into(), (1, win_h-2).into(), (0x84,0x84,0x84));
-    window.fill_rect((win_w as i32 - 1,0).into(), (1, win_h).into(), (0x00,0x00,0x00));
-    window.fill_rect((2, 2).into(), (win_w-4, win_h-4).into(), (0xc6,0xc6,0xc6));
-    window.fill_rect((3, 3).into(), (win_w-6, 18).into(), (0x00,0x00,0x84));
-    window.fill_rect((1, win_h as i32 - 2).into(), (win_w-2, 1).into(), (0x84,0x84,0x84));
-    window.fill_rect((0, win_h as i32 - 1).into(), (win_w, 1).into(), (0x00,0x00,0x00));
+    // 枠より小さいウィンドウでも、はみ出した部分は描かれないだけにする
+    let inner = |margin: u32| (win_w.saturating_sub(margin), win_h.saturating_sub(margin));
+    window.fill_rect((0,0).into(), (win_w,1).into(), palette::WINDOW_GRAY);
+    window.fill_rect((1,1).into(), (inner(2).0,1).into(), palette::WINDOW_HIGHLIGHT);
+    window.fill_rect((0,0).into(), (1, win_h).into(), palette::WINDOW_GRAY);
+    window.fill_rect((1,1).into(), (1, inner(2).1).into(), palette::WINDOW_HIGHLIGHT);
+    window.fill_rect((win_w as i32 - 2,1).into(), (1, inner(2).1).into(), palette::WINDOW_SHADOW);
+    window.fill_rect((win_w as i32 - 1,0).into(), (1, win_h).into(), palette::WINDOW_OUTLINE);
+    window.fill_rect((2, 2).into(), inner(4).into(), palette::WINDOW_GRAY);
+    let bar = graphic::window::title_bar_rect(win_w as usize);
+    window.fill_rect((bar.x1, bar.y1).into(), bar.size(), palette::TITLE_BLUE);
+    window.fill_rect((1, win_h as i32 - 2).into(), (inner(2).0, 1).into(), palette::WINDOW_SHADOW);
+    window.fill_rect((0, win_h as i32 - 1).into(), (win_w, 1).into(), palette::WINDOW_OUTLINE);
     
-    write_string(window, 24, 4, title, (0xff,0xff,0xff));
+    write_string(window, 24, 4, title, palette::TITLE_TEXT);
+    draw_close_button(window, graphic::window::close_button_rect(win_w as usize));
+}
 
+/// 浮き出たボタンに×を描く
+fn draw_close_button(window: &mut FrameBuffer, r: graphic::graphics::Rect) {
+    let (w, h) = ((r.x2 - r.x1) as u32, (r.y2 - r.y1) as u32);
+    window.fill_rect((r.x1, r.y1).into(), (w, h).into(), palette::WINDOW_OUTLINE);
+    window.fill_rect((r.x1, r.y1).into(), (w - 1, h - 1).into(), palette::WINDOW_HIGHLIGHT);
+    window.fill_rect((r.x1 + 1, r.y1 + 1).into(), (w - 2, h - 2).into(), palette::WINDOW_SHADOW);
+    window.fill_rect((r.x1 + 1, r.y1 + 1).into(), (w - 3, h - 3).into(), palette::WINDOW_GRAY);
+    // 中央の8x7に2画素幅の斜線を2本引く
+    let (x0, y0) = (r.x1 + (w as i32 - 8) / 2, r.y1 + (h as i32 - 7) / 2);
+    for dx in 0..2 {
+        window.draw_line((x0 + dx, y0).into(), (x0 + dx + 6, y0 + 6).into(), palette::WINDOW_TEXT);
+        window.draw_line((x0 + dx + 6, y0).into(), (x0 + dx, y0 + 6).into(), palette::WINDOW_TEXT);
+    }
 }
 
-unsafe fn initialize_windows() -> (graphic::window::LayerHandle, graphic::window::LayerHandle) {
+unsafe fn initialize_windows() -> graphic::window::LayerHandle {
     with_layers(|layer_mgr|{
-        let mut mouse_window = Window::new(15, 24);
-        mouse_window.set_transparent_color(Some((1,1,1)));
-        mouse_window.buffer().write_with(|back|{
-            draw_cursor(back);
-        });
-        mouse_window.buffer().flush();
+        // カーソルはレイヤではないので、後から作るウィンドウに隠れない
+        layer_mgr.set_cursor_bitmap(&MOUSE_CURSOR_SHAPE);
 
-        let mouse_window_hndl = layer_mgr.new_layer(mouse_window);
-        
-        let mut test_window = Window::new(160, 68);
+        let mut test_window = Window::new(160, 68, Some(palette::WINDOW_GRAY));
         test_window.move_to((100,200).into());
         test_window.buffer().write_with(|back|{
 
-            write_string(back, 24, 28, "Welcome to".as_bytes(), (0,0,0));
-            write_string(back, 24, 44, "Mikanami world!".as_bytes(), (0,0,0));
+            write_string(back, 24, 28, "Welcome to".as_bytes(), palette::WINDOW_TEXT);
+            write_string(back, 24, 44, "Mikanami world!".as_bytes(), palette::WINDOW_TEXT);
             draw_window(back, "test window".as_bytes());
         });
         test_window.buffer().flush();
-        let test_window_hndl = layer_mgr.new_layer(test_window);
-        
-        layer_mgr.up_down(test_window_hndl.layer_id(), 1);
-        layer_mgr.up_down(mouse_window_hndl.layer_id(), 2);
-        (mouse_window_hndl, test_window_hndl)
+        layer_mgr.new_layer_deferred(test_window, Placement::TopMost)
     })
 }
 
 #[no_mangle]
-pub unsafe extern "sysv64" fn KernelMain2(fb: *const FrameBufferRaw, mm: *const MemoryMapRaw, rsdp: *const RSDP) -> ! {
+pub unsafe extern "sysv64" fn KernelMain2(fb: *const FrameBufferRaw, mm: *const MemoryMapRaw, rsdp: *const RSDP, script: *const BootScriptRaw, syms: *const KernelSymbolsRaw, rd: *const InitrdRaw) -> ! {
     let memmap: MemoryMap = (&*mm).into();
+    serial::init_serial();
+    platform::init();
+    autoexec::load(script);
+    symbols::load(syms);
+    initrd::load(rd);
+    graphic::emergency::set_frame_buffer(fb);
     setup_segments();
     setup_identity_page_table();
+    addr::run_addr_tests();
+    paging::run_paging_tests();
+    graphic::palette::run_palette_tests();
+    graphic::graphics::run_graphics_tests();
+    graphic::pacing::run_pacing_tests();
+    graphic::font::load_initrd_font();
+    rtc::run_rtc_tests();
+    log_ring::run_log_ring_tests();
+    platform::run_platform_tests();
+    symbols::run_symbols_tests();
+    introspect::run_introspect_tests();
+    graphic::snap::run_snap_tests();
+    textfield::run_text_field_tests();
+    event_loop::run_message_queue_tests();
+    // 表は静的に確保しているので、アロケータより前に登録できる
+    heap_sweep::register_nodes();
+    heap_profile::register_nodes();
+    deferred::register_nodes();
+    shortcut::register_nodes();
+    usb::register_nodes();
     init_allocators(&memmap);
+    paging::run_map_mmio_tests();
+    initrd::run_initrd_tests();
+    viewer::run_viewer_tests();
+    shortcut::run_shortcut_tests();
+    ansi::run_ansi_tests();
+    keyboard::run_keyboard_tests();
+    graphic::frame_buffer::run_frame_buffer_tests();
+    graphic::font::run_font_tests();
+    graphic::emergency::run_emergency_tests();
+    graphic::cursor::run_cursor_tests();
     set_interrupt_flag(false);   
 
-    graphic::initialize_winmgr(fb);
-    let (mouse_window_hndl, test_window_hndl) = initialize_windows();
+    // フレームバッファが無ければ画面まわりは初期化せず、シリアルコンソールだけで動かす
+    let gui = if fb.is_null() {
+        None
+    } else {
+        graphic::initialize_winmgr(fb);
+        let test_window_hndl = initialize_windows();
+        graphic::focus::init_focus();
+        graphic::focus::register_window(test_window_hndl.layer_id(), "test window");
+        graphic::focus::run_focus_tests();
+        graphic::window::run_window_tests();
+        graphic::desktop::run_desktop_tests();
+        Some(test_window_hndl)
+    };
     acpi::initialize(&*rsdp);
+    acpi::run_madt_tests();
+    acpi::run_power_tests();
+    hpet::run_hpet_tests();
     initialize_timer();
-
-    init_console((255,255,255), (100,100,100));
+    if gui.is_some() {
+        latency::init_overlay();
+        viewer::init_viewer();
+        indicator::init_indicator();
+        indicator::run_indicator_tests();
+        graphic::snap::init_snap();
+        textfield::init_text_field();
+        keyboard::on_key_event(alloc::boxed::Box::new(textfield::on_key_event));
+    }
+    clock::run_clock_tests();
+
+    if gui.is_some() {
+        init_console(palette::CONSOLE_FG, palette::CONSOLE_BG);
+        console::register_scroll_keys();
+        acpi::register_power_keys();
+        usb::register_debug_keys();
+        graphic::start_compositor();
+    }
+    clock::init_clock();
+    clock::set_log_timestamps(true);
+    if gui.is_some() {
+        graphic::capture::run_capture_tests();
+        #[cfg(feature = "bench")]
+        graphic::capture::run_capture_benchmark();
+    }
+    serial_console::run_serial_console_tests();
+    autoexec::run_autoexec_tests();
+    if gui.is_none() {
+        println!("no frame buffer, running headless on the serial console");
+        serial_console::attach(serial::COM1);
+        if serial::COM2.exists() {
+            serial::COM2.init();
+            serial_console::attach(serial::COM2);
+        }
+    }
+    #[cfg(feature = "oom-test")]
+    memory_manager::run_oom_test();
+    #[cfg(feature = "heap-sweep-test")]
+    heap_sweep::run_heap_sweep_corruption_test();
     
+    let scan_start = timer::uptime_micros();
     let pci = scan_pci_devices();
+    println!("PCI scan took {}us", timer::uptime_micros() - scan_start);
+    pci::run_pci_tests(&pci);
 
-    EVENTS.lock().init(MessageQueue::new());
+    event_loop::init();
+    timer::run_timer_tests();
+    deferred::run_deferred_tests();
+    interrupt::set_exception_handlers(get_cs());
     set_idt_entry(
         IVIndex::XHCI, 
         InterruptDescriptor::new(
@@ -251,103 +377,265 @@ pub unsafe extern "sysv64" fn KernelMain2(fb: *const FrameBufferRaw, mm: *const
             transmute(lapic_interrupt_handler as *const fn())
         )
     );
+    for (index, handler) in [
+        (IVIndex::Ps2Keyboard, ps2_keyboard_interrupt_handler as *const fn()),
+        (IVIndex::Ps2Mouse, ps2_mouse_interrupt_handler as *const fn()),
+    ] {
+        set_idt_entry(
+            index,
+            InterruptDescriptor::new(get_cs(), InterruptDescriptorAttribute::new(0, DescriptorType::InterruptGate), transmute(handler))
+        );
+    }
     load_idt();
 
-    let xhc = find_xhc_device();
-    let local_apic_id = *(0xfee00020 as *const u32) >> 24;
+    let xhc = find_xhc_device(&pci);
+    let local_apic_id = *acpi::local_apic_register(0x20) >> 24;
     println!("apic_id: {}", local_apic_id);
-    configure_msi_fixed_destination(&xhc, local_apic_id as u8, IVIndex::XHCI as u8);
+    match configure_msi_fixed_destination(&xhc, local_apic_id as u8, IVIndex::XHCI as u8) {
+        Ok(kind) => println!("xhc: interrupts via {:?}", kind),
+        Err(e) => warn!("xhc: no message-signaled interrupt ({:?})", e),
+    }
+    // USBのHIDデバイスが無い機械でも入力できるよう、PS/2があれば使う
+    ioapic::run_ioapic_tests();
+    ps2::run_ps2_tests();
+    ioapic::mask_legacy_pic();
+    match ps2::init(local_apic_id as u8, IVIndex::Ps2Keyboard as u8, IVIndex::Ps2Mouse as u8) {
+        Ok(devices) => println!("ps2: {:?}", devices),
+        Err(ps2::Ps2Error::NotPresent) => println!("ps2: no 8042 controller"),
+        Err(e) => warn!("ps2: {:?}", e),
+    }
 
     let intel_ehci_found = pci.get_devices().iter().any(|dev|{
         dev.read_vendor_id() == 0x8086 &&  dev.read_class_code().matches(0x0c, 0x03, 0x20) 
     });
 
-    init_usb(xhc, intel_ehci_found, Box::new(move |report| {
-        {
-            let (display_width, display_height) = with_layers(|l|l.resolution());
-            let (dx,dy) = (report.dx(), report.dy());
-            {
-                let mut window = mouse_window_hndl.window().write();
-                let new_pos = (window.pos() + (dx as i32, dy as i32).into()).clamp((0,0).into(), (display_width as i32, display_height as i32).into());
-                window.move_to(new_pos);
-            }
-            with_layers(|l|l.draw());
-        }
-    }), Box::new(move |report|{
-        println!("{:?}", report);
-    }));
+    let (mouse_tx, mouse_rx) = usb::new_channel("main-mouse");
+    let (key_tx, key_rx) = usb::new_channel("main-keyboard");
+    let _mouse_sub = usb::subscribe_mouse(mouse_tx);
+    let _key_sub = usb::subscribe_keyboard(key_tx);
+    let (hotplug_tx, hotplug_rx) = usb::new_channel("main-hotplug");
+    let _hotplug_sub = usb::subscribe_hotplug(hotplug_tx);
+    init_usb(xhc, intel_ehci_found);
+    usb::defer_until_ready(indicator::on_usb_ready, 0);
 
     print!("finish\n");
     // LAYERS.lock().draw();
 
-    let task_b_stack = vec![0u64;1024];
-    let task_b_stack_end = (&task_b_stack[1023]) as *const u64 as u64 + 8;
+    init_task_manager();
+    task::run_scheduler_tests();
+    // taskBはウィンドウに描くので、画面がなければ起こさない
+    if gui.is_some() {
+        task::spawn(taskB::taskB, 1, 42);
+    }
+    task::run_task_local_tests();
+    set_interrupt_flag(true);   
+    memory_manager::run_lock_tests();
+    #[cfg(feature = "bench")]
+    memory_manager::run_frame_allocator_benchmark();
+    console::run_irq_log_tests();
+    
+    add_periodic_timer(200, 1);
+    add_periodic_timer(600, 2);
+    autoexec::start();
+
+    // USBのタスクを実行した後で、タスクが送ってきた入力を受け取る
+    let receive_usb_events = || {
+        while let Some(event) = mouse_rx.receive() {
+            if gui.is_some() {
+                on_mouse_event(&event.report);
+            }
+        }
+        while let Some(event) = key_rx.receive() {
+            on_key_event(&event.report, event.locks, gui.is_some());
+        }
+        if gui.is_some() {
+            indicator::on_key_events();
+        }
+        while let Some(event) = hotplug_rx.receive() {
+            println!("usb: {:?}", event);
+            autoexec::on_hotplug(&event);
+        }
+    };
+
+    let mut handlers = Handlers::new();
+    handlers
+        .wake_if(usb::timer_pending)
+        .wake_if(deferred::pending)
+        .wake_if(serial_console::pending)
+        .wake_if(console::irq_log_pending)
+        // 休む前に、間隔が空いていればヒープを少しだけ確かめる
+        .when_idle(heap_sweep::due, heap_sweep::sweep)
+        .on_wake(usb::on_timer)
+        .on_wake(|| {
+            deferred::run_deferred();
+        })
+        .on_wake(console::flush_irq_log)
+        .on_wake(serial_console::poll)
+        .on_message(MessageKind::Xhci, |msg| {
+            if let Message::Xhci { arrival, .. } = msg {
+                latency::begin(arrival);
+            }
+            usb::on_xhc_interrupt();
+            receive_usb_events();
+            latency::end();
+        })
+        .on_message(MessageKind::UsbPoll, |_| {
+            usb::poll_tasks();
+            receive_usb_events();
+        })
+        .on_message(MessageKind::Ps2, |msg| match msg {
+            Message::Ps2Keyboard(byte) => {
+                if let Some((report, locks)) = ps2::on_keyboard_byte(byte) {
+                    on_key_event(&report, locks, gui.is_some());
+                    if gui.is_some() {
+                        indicator::on_key_events();
+                    }
+                }
+            }
+            Message::Ps2Mouse(byte) => {
+                if let Some(report) = ps2::on_mouse_byte(byte) {
+                    if gui.is_some() {
+                        on_mouse_event(&report);
+                    }
+                }
+            }
+            _ => (),
+        })
+        .on_message(MessageKind::Timer, |msg| match msg {
+            Message::TimerTimeout(1) => println!("tick {}: timer 1", get_current_tick()),
+            Message::TimerTimeout(2) => println!("tick {}: timer 2", get_current_tick()),
+            _ => (),
+        });
+    if let Some(test_window_hndl) = &gui {
+        // テストウィンドウに表示しているtick。tickが変わったときだけ描き直す
+        let mut shown_tick = u64::MAX;
+        handlers.on_wake(move || {
+            let tick_now = get_current_tick();
+            if tick_now == shown_tick {
+                return;
+            }
+            shown_tick = tick_now;
+            let window = test_window_hndl.window().read();
+            let mut tick = StackWriter::new();
+            let _ = write!(tick, "{}", tick_now);
+            window.buffer().write_with(|back|{
+                back.fill_rect((24,28).into(), (8*10,16).into(), palette::WINDOW_GRAY);
+                write_string(back, 24, 28, tick.as_bytes(), palette::WINDOW_TEXT);
+            });
+            window.buffer().flush();
+        });
+        // 合成はここでだけ行う。フレームの時刻が来ていれば、それまでに立った依頼をロックを何も持っていないこの時点でまとめて描く
+        handlers.wake_if(graphic::compose_pending).at_end(graphic::compose_if_due);
+    }
+    EventLoop::run(handlers)
+}
 
-    let mut task_b_ctx = TaskContext::new();
-    task_b_ctx.rip = taskB::taskB as *const fn() as u64;
-    task_b_ctx.rdi = 1;
-    task_b_ctx.rsi = 42;
+fn on_mouse_event(report: &usb::MouseReport) {
+    let (dx,dy) = (report.dx(), report.dy());
+    let new_pos = with_layers(|l| {
+        let (display_width, display_height) = l.resolution();
+        let new_pos = (l.cursor_pos() + (dx as i32, dy as i32).into()).clamp((0,0).into(), (display_width as i32, display_height as i32).into());
+        l.move_cursor(new_pos);
+        new_pos
+    });
+    console::on_wheel(report.wheel());
+    graphic::focus::on_mouse(report.buttons(), new_pos);
+    graphic::snap::on_mouse(report.buttons(), new_pos);
+    indicator::on_mouse(report.buttons(), new_pos);
+    latency::complete(latency::EventKind::Mouse);
+}
 
-    task_b_ctx.cr3 = get_cr3();
-    task_b_ctx.rflags = 0x202;
-    task_b_ctx.cs = KERNEL_CS as u64;
-    task_b_ctx.ss = KERNEL_SS as u64;
-    task_b_ctx.rsp = (task_b_stack_end & !0xfu64) - 8;
-    task_b_ctx.fxsave_area[6] = 0x1f80;
+/// USBとPS/2のどちらのキーボードからも来る
+fn on_key_event(report: &usb::KeyReport, locks: usb::LockState, gui: bool) {
+    println!("{:?}", report);
+    latency::complete(latency::EventKind::Keyboard);
+    if !gui {
+        keyboard::feed(report, locks);
+        return;
+    }
+    // ショートカットになったキーはここで消費され、残りのキーが押した・離したの出来事になって入力欄などに渡る
+    let keys = shortcut::dispatch(report);
+    graphic::focus::on_key_report(report.modifier.alt());
+    keyboard::feed(&usb::KeyReport { keycodes: keys, ..report.clone() }, locks);
+}
 
-    init_task_manager(task_b_ctx);
-    set_interrupt_flag(true);   
-    
-    add_timer(get_current_tick() + 200, 1);
-    add_timer(get_current_tick() + 600, 2);
-
-    loop {
-        set_interrupt_flag(false);
-        if EVENTS.lock().cnt == 0 {
-            set_interrupt_flag(true);
-            asm!("hlt"); // 割り込みがあるまで休眠
-            continue;
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // 戻らないので、割り込みハンドラやタスクの切り替えに出力を邪魔させない
+    x86_64::instructions::interrupts::disable();
+    // 画面への出力の途中でまたパニックしたら、画面には触らずシリアルにだけ出す
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        crate::println_serial!("nested panic: {info}");
+        halt_after_panic();
+    }
+    // コンソールやレイヤーのロックは持たれたままかもしれないので、どちらも使わずに
+    // シリアル(とdebugcon)と起動時のフレームバッファへじかに書く
+    let mut out = PanicWriter { screen: unsafe { EmergencyWriter::open() } };
+    let _ = writeln!(out, "{}{info}{}", ansi::RED, ansi::RESET);
+    let _ = writeln!(out, "tick: {}, task: {}, in interrupt: {}", OrUnknown(timer::try_current_tick()), OrUnknown(task::current_task()), interrupt::in_interrupt());
+    if let Some(stats) = memory_manager::try_memory_stats() {
+        let _ = writeln!(out, "{stats}");
+    }
+    symbols::write_backtrace(&mut out);
+    // 最近の出力は、シリアルには全部、画面には残りの行に入る分だけ出す
+    if let Some(mut log) = PANIC_LOG.try_lock() {
+        let len = console::recent_log(&mut *log);
+        let _ = writeln!(out, "recent log:");
+        serial::write_bytes(&log[..len]);
+        DebugconWriter::write_bytes(&log[..len]);
+        if let Some(screen) = &mut out.screen {
+            let rows = screen.rows_left();
+            screen.write_bytes(emergency::last_lines(&log[..len], rows));
         }
+    }
+    if let Some(secs) = acpi::panic_reboot_secs() {
+        let _ = writeln!(out, "rebooting in {secs} s");
+    }
+    halt_after_panic();
+}
 
-        let msg = EVENTS.lock().pop();
-        set_interrupt_flag(true);
-
-        {
-            {
-                let window = test_window_hndl.window().read();
-                let tick = get_current_tick();
-                window.buffer().write_with(|back|{
-                    back.fill_rect((24,28).into(), (8*10,16).into(), (0xc6, 0xc6, 0xc6));
-                    write_string(back, 24, 28, tick.to_string().as_bytes(), (0,0,0));
-                });
-            }
-            with_layers(|l|l.draw());
+static PANICKING: AtomicBool = AtomicBool::new(false);
+/// パニックハンドラが最近の出力を写す先。タスクのスタックは小さいので静的に置く
+static PANIC_LOG: Mutex<[u8; console::RECENT_LOG_LEN]> = Mutex::new([0; console::RECENT_LOG_LEN]);
+
+/// Noneを"?"と書く
+struct OrUnknown<T>(Option<T>);
+
+impl<T: core::fmt::Display> core::fmt::Display for OrUnknown<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &self.0 {
+            Some(v) => v.fmt(f),
+            None => f.write_str("?"),
         }
+    }
+}
 
-        match msg {
-            Some(Message::Xhci) => usb::on_xhc_interrupt(),
-            Some(Message::TimerTimeout(val)) => match val {
-                1 => {
-                    let tick = get_current_tick();
-                    println!("tick {}: timer 1", tick);
-                    add_timer(tick + 200, 1);
-                },
-                2 => {
-                    let tick = get_current_tick();
-                    println!("tick {}: timer 2", tick);
-                    add_timer(tick + 600, 2);
-                }, 
-                _ => ()
-            }
-            _ => ()
+/// パニックの出力先。ロックを取らずにシリアルとdebugcon、(あれば)画面に書く
+struct PanicWriter {
+    screen: Option<EmergencyWriter>,
+}
+
+impl Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        serial::write_bytes(s.as_bytes());
+        DebugconWriter::write_bytes(s.as_bytes());
+        if let Some(screen) = &mut self.screen {
+            screen.write_bytes(s.as_bytes());
         }
+        Ok(())
     }
-    
 }
 
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    println!("{_info}");
+fn halt_after_panic() -> ! {
+    // CIではpanic=exitで、止まったままにせず失敗としてQEMUを終わらせる
+    if platform::qemu::panic_exit_enabled() {
+        platform::qemu::exit_qemu(platform::qemu::EXIT_FAILURE);
+    }
+    // panic-rebootが指定されていれば、画面を読む時間を置いてからリセットする
+    if let Some(secs) = acpi::panic_reboot_secs() {
+        acpi::wait_millis(secs.saturating_mul(1000));
+        acpi::reboot();
+    }
     unsafe {
         loop {
             asm!("hlt");
@@ -355,9 +643,19 @@ fn panic(_info: &PanicInfo) -> ! {
     }
 }
 
+/// リークか断片化かを見分けられるよう、止まる前にフレームの空きを出す
+fn print_memory_stats() {
+    if let Some(stats) = memory_manager::try_memory_stats() {
+        let mut w = StackWriter::new();
+        let _ = writeln!(w, "{stats}");
+        console::_log_nofmt(w.as_bytes());
+    }
+}
+
 #[alloc_error_handler]
-fn oom(_: Layout) -> ! {
-    print!("out of memory.");
+fn oom(layout: Layout) -> ! {
+    log_nofmt!(ansi::RED, "out of memory", ansi::RESET, ": size=", layout.size(), ", align=", layout.align());
+    print_memory_stats();
     unsafe {
         loop {
             asm!("hlt");
@@ -375,61 +673,39 @@ get_cs:
     ret
 "#);
 
-#[derive(Clone, Copy, Debug)]
-enum Message {
-    Xhci,
-    TimerTimeout(u64)
-}
-
-struct MessageQueue<const N: usize> {
-    data: [Message; N],
-    read_pos: usize,
-    write_pos: usize,
-    cnt: usize
-}
-
-impl<const N: usize> MessageQueue<N> {
-    fn new() -> Self {
-        Self {
-            data: [Message::Xhci; N],
-            read_pos: 0,
-            write_pos: 0,
-            cnt: 0
-        }
-    }
-
-    fn push(&mut self, msg: Message) -> Result<(), ()>{
-        if self.cnt == self.data.len() {
-            return Err(());
-        }
-
-        self.cnt += 1;
-        self.data[self.write_pos] = msg;
-        self.write_pos = (self.write_pos + 1) % self.data.len();
-        Ok(())
+#[allow(dead_code)]
+extern "x86-interrupt" fn xhci_interrupt_handler() {
+    let _ctx = interrupt::enter_interrupt();
+    if interrupt::PANIC_TEST.swap(false, Ordering::Relaxed) {
+        panic!("test");
     }
+    let arrival = Timestamp::now();
+    let mut lock = EVENTS.lock();
+    let _ = lock.push(Message::Xhci { arrival, count: 1 });
+    notify_end_of_interrupt();
+}
 
-    fn pop(&mut self) -> Option<Message>{
-        if self.cnt == 0 {
-            return None;
-        }
-
-        self.cnt -= 1;
-        let msg = self.data[self.read_pos];
-        self.read_pos = (self.read_pos + 1) % self.data.len();
-        Some(msg)
-    }
+extern "x86-interrupt" fn ps2_keyboard_interrupt_handler() {
+    let _ctx = interrupt::enter_interrupt();
+    let _ = EVENTS.lock().push(Message::Ps2Keyboard(ps2::read_data()));
+    notify_end_of_interrupt();
 }
 
-#[allow(dead_code)]
-extern "x86-interrupt" fn xhci_interrupt_handler() {
-    let mut lock = EVENTS.lock();
-    let _ = lock.push(Message::Xhci);
+extern "x86-interrupt" fn ps2_mouse_interrupt_handler() {
+    let _ctx = interrupt::enter_interrupt();
+    let _ = EVENTS.lock().push(Message::Ps2Mouse(ps2::read_data()));
     notify_end_of_interrupt();
 }
 
 extern "x86-interrupt" fn lapic_interrupt_handler() {
-    let task_timer_timeout = timer::on_lapic_interrupt(1);
+    // タスクを切り替える前に割り込みの中を抜ける
+    let task_timer_timeout = {
+        let _ctx = interrupt::enter_interrupt();
+        if console::IRQ_PRINT_TEST.swap(false, Ordering::Relaxed) {
+            println!("{}", console::IRQ_PRINT_TEST_MESSAGE);
+        }
+        timer::on_lapic_interrupt(1)
+    };
     notify_end_of_interrupt();
     if task_timer_timeout {
         unsafe {
@@ -440,7 +716,6 @@ extern "x86-interrupt" fn lapic_interrupt_handler() {
 
 fn notify_end_of_interrupt() {
     unsafe {
-        let end_of_interrupt = 0xfee000b0u64 as *mut u32;
-        write_volatile(end_of_interrupt, 0);
+        write_volatile(acpi::local_apic_register(0xb0), 0);
     }
 }
@@ -1,165 +1,437 @@
 use core::{
-    alloc::{GlobalAlloc, Layout}, arch::asm, cell::UnsafeCell, marker::PhantomData, mem::{transmute, MaybeUninit}, ptr::null_mut, slice::from_raw_parts_mut, sync::atomic::{AtomicBool, AtomicU64, Ordering}
+    alloc::{GlobalAlloc, Layout}, arch::asm, hint::spin_loop, marker::PhantomData, mem::{transmute, MaybeUninit}, ptr::null_mut, slice::from_raw_parts_mut, sync::atomic::{AtomicBool, AtomicU64, Ordering}
 };
 
 use bitfield::size_of;
 use lock_api::{GuardNoSend, MutexGuard, RawMutex, RawRwLock};
 
-use crate::memory_map::MemoryMap;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::{addr::{phys_to_virt, ptr_to_phys, PhysAddr}, console::StackWriter, memory_map::{MemoryDescriptor, MemoryMap, MemoryType}, paging::{self, IDENTITY_MAP_END}};
+#[cfg(feature = "lock-debug")]
+use crate::{ansi, console, symbols};
+
+/// spin_loopでこれだけ待ってもロックが取れなければ、二重ロックを疑って持ち主を出す
+const LONG_WAIT_SPINS: u64 = 1 << 26;
+/// hltで待つ場合はタイマー割り込み1回が1周なので、数秒分
+const LONG_WAIT_HLTS: u64 = 500;
+
+/// lock-debugのとき、ロックを持っている所の戻りアドレスを新しい順に覚えておく
+#[cfg(feature = "lock-debug")]
+const LOCK_OWNER_FRAMES: usize = 4;
+
+#[cfg(feature = "lock-debug")]
+struct LockOwner {
+    frames: [AtomicU64; LOCK_OWNER_FRAMES],
+}
+
+#[cfg(not(feature = "lock-debug"))]
+struct LockOwner;
+
+impl LockOwner {
+    #[cfg(feature = "lock-debug")]
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self { frames: [ZERO; LOCK_OWNER_FRAMES] }
+    }
+
+    #[cfg(not(feature = "lock-debug"))]
+    const fn new() -> Self {
+        Self
+    }
+
+    /// ロックを取った直後に呼ぶ。symbols::walk_framesはロックを取るので、ここでは自前でrbpを辿る
+    #[cfg(feature = "lock-debug")]
+    fn record(&self) {
+        let mut rbp: u64;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) rbp);
+        }
+        for slot in &self.frames {
+            let ret = if rbp != 0 && rbp % 8 == 0 && paging::is_mapped(PhysAddr::new(rbp), 16) {
+                let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+                rbp = if next > rbp { next } else { 0 };
+                ret
+            } else {
+                0
+            };
+            slot.store(ret, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(not(feature = "lock-debug"))]
+    fn record(&self) {}
+
+    #[cfg(feature = "lock-debug")]
+    fn clear(&self) {
+        self.frames[0].store(0, Ordering::Relaxed);
+    }
+
+    #[cfg(not(feature = "lock-debug"))]
+    fn clear(&self) {}
+
+    /// ロックが長く取れないときに、今の持ち主を出す
+    #[cfg(feature = "lock-debug")]
+    fn report(&self) {
+        use core::fmt::Write;
+        let mut w = StackWriter::new();
+        let _ = writeln!(w, "{}lock is still held{}, taken at:", ansi::YELLOW, ansi::RESET);
+        for frame in &self.frames {
+            let ret = frame.load(Ordering::Relaxed);
+            if ret == 0 {
+                break;
+            }
+            let _ = writeln!(w, "  {}", symbols::Symbolized(ret));
+        }
+        console::_log_nofmt(w.as_bytes());
+    }
+
+    #[cfg(not(feature = "lock-debug"))]
+    fn report(&self) {}
+}
 
 /**
  * シングルプロセス専用のMutex
- * ロックされた状態でさらにロックを獲得しようと試みた場合、panicする
+ * ロックされた状態でさらにロックを獲得しようとした場合、解放されるまで回り続ける
  */
 pub struct SingleMutex {
-    locked: UnsafeCell<bool>,
+    locked: AtomicBool,
+    owner: LockOwner,
 }
 
 unsafe impl RawMutex for SingleMutex {
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self {
-        locked: UnsafeCell::new(false),
+        locked: AtomicBool::new(false),
+        owner: LockOwner::new(),
     };
     type GuardMarker = GuardNoSend;
     fn lock(&self) {
-        unsafe {
-            let locked = self.locked.get();
-            assert!(!*locked);
-            *locked = true;
+        let mut spins = 0;
+        while self.locked.swap(true, Ordering::Acquire) {
+            spins += 1;
+            if spins == LONG_WAIT_SPINS {
+                self.owner.report();
+            }
+            spin_loop();
         }
+        self.owner.record();
     }
 
     fn try_lock(&self) -> bool {
-        self.lock();
-        true
+        let acquired = !self.locked.swap(true, Ordering::Acquire);
+        if acquired {
+            self.owner.record();
+        }
+        acquired
     }
 
     unsafe fn unlock(&self) {
-        *self.locked.get() = false;
+        self.owner.clear();
+        self.locked.store(false, Ordering::Release);
     }
 }
 
-unsafe impl Sync for SingleMutex {}
-
 pub struct SpinMutex {
     locked: AtomicBool,
+    owner: LockOwner,
 }
 
 unsafe impl RawMutex for SpinMutex {
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self {
         locked: AtomicBool::new(false),
+        owner: LockOwner::new(),
     };
     type GuardMarker = GuardNoSend;
     fn lock(&self) {
+        let mut waits = 0;
         while self.locked.swap(true, Ordering::AcqRel) {
-            unsafe {asm!("hlt");}
+            waits += 1;
+            // 割り込みが止まっているとhltから戻れないので、そのときは回って待つ
+            if interrupts::are_enabled() {
+                if waits == LONG_WAIT_HLTS {
+                    self.owner.report();
+                }
+                unsafe {asm!("hlt");}
+            } else {
+                if waits == LONG_WAIT_SPINS {
+                    self.owner.report();
+                }
+                spin_loop();
+            }
         }
+        self.owner.record();
     }
 
     fn try_lock(&self) -> bool {
-        !self.locked.swap(true, Ordering::Acquire)
+        let acquired = !self.locked.swap(true, Ordering::Acquire);
+        if acquired {
+            self.owner.record();
+        }
+        acquired
     }
 
     unsafe fn unlock(&self) {
+        self.owner.clear();
         self.locked.store(false, Ordering::Release);
     }
 }
 
 pub type Mutex<T> = lock_api::Mutex<SpinMutex, T>;
 
+/// 割り込みハンドラからも取られるロック。持っている間は割り込みを止め、外すと元の状態(IF)に戻す。
+/// 複数持つときは取ったのと逆の順に外すこと
+pub struct IrqSpinMutex {
+    locked: AtomicBool,
+    /// ロックを取る前に割り込みが有効だったか
+    saved_if: AtomicBool,
+    owner: LockOwner,
+}
+
+unsafe impl RawMutex for IrqSpinMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+        saved_if: AtomicBool::new(false),
+        owner: LockOwner::new(),
+    };
+    type GuardMarker = GuardNoSend;
+    fn lock(&self) {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        // 割り込みを止めて持つので、待つことになるのは同じ文脈での二重ロックだけ
+        let mut spins = 0;
+        while self.locked.swap(true, Ordering::Acquire) {
+            spins += 1;
+            if spins == LONG_WAIT_SPINS {
+                self.owner.report();
+            }
+            spin_loop();
+        }
+        self.saved_if.store(enabled, Ordering::Relaxed);
+        self.owner.record();
+    }
+
+    fn try_lock(&self) -> bool {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self.locked.swap(true, Ordering::Acquire) {
+            if enabled {
+                interrupts::enable();
+            }
+            return false;
+        }
+        self.saved_if.store(enabled, Ordering::Relaxed);
+        self.owner.record();
+        true
+    }
+
+    unsafe fn unlock(&self) {
+        let enabled = self.saved_if.load(Ordering::Relaxed);
+        self.owner.clear();
+        self.locked.store(false, Ordering::Release);
+        if enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+pub type IrqMutex<T> = lock_api::Mutex<IrqSpinMutex, T>;
+
+/// 読み手は何人でも、書き手は1人だけ持てるロック。書き手が待っている間は新しい読み手を入れない。
+/// 同じ文脈で読み取りを入れ子にすると、間に書き手が来たときに戻れなくなるので避けること
 pub struct SpinRwLock {
-    /// 0 when unlocked
-    /// 2n when n readers exist
-    /// 1 when a writer exists
-    count: AtomicU64
+    /// bit0: 書き手が持っている
+    /// bit1: 書き手が待っている
+    /// それより上: 読み手の数(READERずつ増える)
+    state: AtomicU64
+}
+
+const RW_WRITER: u64 = 1;
+const RW_WRITER_WAITING: u64 = 2;
+const RW_READER: u64 = 4;
+
+/// ロックが空くのを待つ間の1回分。割り込みが止まっているとhltから戻れないので回る
+fn relax() {
+    if interrupts::are_enabled() {
+        unsafe {asm!("hlt");}
+    } else {
+        spin_loop();
+    }
+}
+
+impl SpinRwLock {
+    /// 書き手がおらず待ってもいなければ、読み手を1人増やす
+    fn try_add_reader(&self) -> bool {
+        let old = self.state.load(Ordering::Relaxed);
+        old & (RW_WRITER | RW_WRITER_WAITING) == 0
+            && self.state.compare_exchange_weak(old, old + RW_READER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    /// 誰も持っていなければ書き手になる。待っている印は消す
+    fn try_set_writer(&self) -> bool {
+        let old = self.state.load(Ordering::Relaxed);
+        old & !RW_WRITER_WAITING == 0
+            && self.state.compare_exchange(old, RW_WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
 }
 
 unsafe impl RawRwLock for SpinRwLock {
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self {
-        count: AtomicU64::new(0)
+        state: AtomicU64::new(0)
     };
     type GuardMarker = GuardNoSend;
     fn lock_exclusive(&self) {
-        loop {
-            while self.count.load(Ordering::Relaxed) != 0 {unsafe {asm!("hlt");}}
-            if self.count.compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
-                break;
-            }
+        while !self.try_set_writer() {
+            // 読み手が抜けきるまでに新しい読み手が入ってこないようにする
+            self.state.fetch_or(RW_WRITER_WAITING, Ordering::Relaxed);
+            relax();
         }
     }
 
     fn lock_shared(&self) {
-        loop {
-            let old = self.count.load(Ordering::Relaxed);
-            if old != 1 && self.count.compare_exchange_weak(old, old+2, Ordering::Acquire, Ordering::Relaxed).is_ok() {
-                break;
-            }
+        while !self.try_add_reader() {
+            relax();
         }
     }
 
     fn try_lock_exclusive(&self) -> bool {
-        self.count.compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        self.try_set_writer()
     }
 
     fn try_lock_shared(&self) -> bool {
-        let old = self.count.load(Ordering::Relaxed);
-        old != 1 && self.count.compare_exchange_weak(old, old+2, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        // weakの見かけ上の失敗でNoneを返さないよう、状態が変わっただけなら取り直す
+        loop {
+            let old = self.state.load(Ordering::Relaxed);
+            if old & (RW_WRITER | RW_WRITER_WAITING) != 0 {
+                return false;
+            }
+            if self.state.compare_exchange_weak(old, old + RW_READER, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return true;
+            }
+        }
     }
 
     unsafe fn unlock_exclusive(&self) {
-        self.count.store(0, Ordering::Release)
+        // 他の書き手の待っている印は残す
+        self.state.fetch_and(!RW_WRITER, Ordering::Release);
     }
 
     unsafe fn unlock_shared(&self) {
-        self.count.fetch_sub(2, Ordering::Relaxed);
+        self.state.fetch_sub(RW_READER, Ordering::Release);
     }
 }
 
 pub type RwLock<T> = lock_api::RwLock<SpinRwLock, T>;
 
-type FrameId = usize;
+pub type FrameId = usize;
 
 const KB: usize = 1024;
 const GB: usize = 1024 * 1024 * 1024;
-const BYTES_PER_FRAME: usize = 4 * KB;
+pub const BYTES_PER_FRAME: usize = 4 * KB;
 const UEFI_PAGE_SIZE: usize = 4 * KB;
-const MAX_PHYSICAL_MEMORY_BYTES: usize = 128 * GB;
-const FRAME_COUNT: usize = MAX_PHYSICAL_MEMORY_BYTES / BYTES_PER_FRAME;
+/// 最近返された連続領域を覚えておく数
+const FREE_RUN_CACHE: usize = 8;
+
 struct BitMapMemoryManager {
     // 1bit per frame, 1 representing "in use"
-    alloc_map: [u8; FRAME_COUNT / 8],
-    // the (first, last + 1) frame number to be managed
-    available_range: (usize, usize),
+    // last_usableまでの分だけあり、メモリマップ上の使用可能な領域の中に置く
+    alloc_map: &'static mut [u8],
+    // 使用可能な領域を含む最初のフレームと最後のフレーム+1。間の穴はビットマップ上で使用中になっている
+    first_usable: FrameId,
+    last_usable: FrameId,
+    free_frames: usize,
+    /// 起動時に使えたフレームの数
+    total_frames: usize,
+    /// 恒等写像の範囲外にあるため使わないRAMのフレーム数
+    unmapped_frames: usize,
+    /// 次に探し始める位置。前回取った所の後ろで、返されたらそこまで戻す(next-fit)
+    cursor: FrameId,
+    /// 最近返された連続領域(先頭, 長さ)。長さ0は空き。
+    /// ビットマップとは別に取られていることがあるので、使う前にビットマップで確かめる
+    free_runs: [(FrameId, usize); FREE_RUN_CACHE],
 }
 
 impl BitMapMemoryManager {
-    unsafe fn new_at(ptr: *mut u8, map: &MemoryMap) {
-        let manager = ptr as *mut BitMapMemoryManager;
-
-        (*manager).alloc_map.fill(0);
-
-        let mut available_end = 0usize;
-        for desc in map.entries() {
-            if available_end < desc.physical_start as usize {
-                (*manager).mark_allocated(
-                    available_end / BYTES_PER_FRAME,
-                    (desc.physical_start as usize - available_end) / BYTES_PER_FRAME,
-                );
+    /// 使用可能な領域を、フレーム0と恒等写像の外を除いたフレームの範囲にして渡す
+    /// 外したフレーム数も返す
+    fn usable_ranges(map: &MemoryMap, mut f: impl FnMut(FrameId, FrameId)) -> usize {
+        // 写像されていないフレームを渡すと、触った時点で#PFになる
+        let mapped_end = IDENTITY_MAP_END as usize / BYTES_PER_FRAME;
+        let mut unmapped = 0;
+        for desc in map.entries().filter(|d| d.is_available()) {
+            // フレーム0はnullと区別できないので使わない
+            let start = (desc.physical_start as usize / BYTES_PER_FRAME).max(1);
+            let end = (desc.physical_start as usize + desc.num_pages as usize * UEFI_PAGE_SIZE) / BYTES_PER_FRAME;
+            unmapped += end.saturating_sub(start.max(mapped_end));
+            let end = end.min(mapped_end);
+            if start < end {
+                f(start, end);
             }
-            let physical_end =
-                desc.physical_start as usize + desc.num_pages as usize * UEFI_PAGE_SIZE;
-            if desc.is_available() {
-                available_end = physical_end;
-            } else {
-                (*manager).mark_allocated(
-                    desc.physical_start as usize / BYTES_PER_FRAME,
-                    desc.num_pages as usize * UEFI_PAGE_SIZE / BYTES_PER_FRAME,
-                );
+        }
+        unmapped
+    }
+
+    /// mapを管理するのに要るビットマップのバイト数
+    fn bitmap_len(map: &MemoryMap) -> usize {
+        let mut last = 0;
+        Self::usable_ranges(map, |_, end| last = last.max(end));
+        (last + 7) / 8
+    }
+
+    /// ビットマップを置ける、nフレーム以上続く使用可能な領域の先頭
+    fn find_bitmap_frames(map: &MemoryMap, n: usize) -> Option<FrameId> {
+        let mut found = None;
+        Self::usable_ranges(map, |start, end| {
+            if found.is_none() && end - start >= n {
+                found = Some(start);
+            }
+        });
+        found
+    }
+
+    /// 全フレームを使用中にしてから、メモリマップ上の使用可能な領域だけを解放する
+    /// alloc_mapはbitmap_len(map)バイト以上。ディスクリプタがアドレス順に並んでいることは仮定しない
+    fn new(map: &MemoryMap, alloc_map: &'static mut [u8]) -> Self {
+        alloc_map.fill(0xff);
+        let mut manager = BitMapMemoryManager {
+            alloc_map,
+            first_usable: usize::MAX,
+            last_usable: 0,
+            free_frames: 0,
+            total_frames: 0,
+            unmapped_frames: 0,
+            cursor: 0,
+            free_runs: [(0, 0); FREE_RUN_CACHE],
+        };
+        let unmapped = Self::usable_ranges(map, |start, end| {
+            manager.free(start, end - start);
+            manager.first_usable = manager.first_usable.min(start);
+            manager.last_usable = manager.last_usable.max(end);
+        });
+        manager.unmapped_frames = unmapped;
+        if manager.first_usable > manager.last_usable {
+            manager.first_usable = 0;
+        }
+        manager.total_frames = manager.free_frames;
+        manager.cursor = manager.first_usable;
+        // 初期化で積んだ領域は大きすぎて役に立たないので、空から始める
+        manager.free_runs = [(0, 0); FREE_RUN_CACHE];
+        manager
+    }
+
+    /// 空いているフレームを、返さない前提で使用中にする(ビットマップ自身など)
+    fn reserve(&mut self, start: FrameId, nframes: usize) {
+        for frame in start..start + nframes {
+            if !self.get_bit(frame) {
+                self.set_bit(frame, true);
+                self.free_frames -= 1;
+                self.total_frames -= 1;
             }
         }
-        (*manager).available_range = (1, available_end / BYTES_PER_FRAME);
     }
 
     fn set_bit(&mut self, frame: FrameId, allocated: bool) {
@@ -184,36 +456,146 @@ impl BitMapMemoryManager {
         }
     }
 
-    pub fn allocate(&mut self, nframes: usize) -> Option<FrameId> {
-        let range = self.available_range;
-        let mut start = range.0;
+    fn is_free_run(&self, start: FrameId, nframes: usize) -> bool {
+        start + nframes <= self.last_usable && (start..start + nframes).all(|f| !self.get_bit(f))
+    }
 
-        while start < range.1 - nframes {
+    /// [from, to)の中で、nframes続けて空いている最初の位置
+    fn find_free(&self, from: FrameId, to: FrameId, nframes: usize) -> Option<FrameId> {
+        let mut start = from;
+        while start + nframes <= to {
+            // 8フレームすべて使用中なら(穴の中など)まとめて飛ばす
+            if start % 8 == 0 && self.alloc_map[start / 8] == 0xff {
+                start += 8;
+                continue;
+            }
             let mut nfree = 0;
-            while nfree < nframes && start + nfree < range.1 && !self.get_bit(start + nfree) {
+            while nfree < nframes && !self.get_bit(start + nfree) {
                 nfree += 1;
             }
             if nfree == nframes {
-                self.mark_allocated(start, nframes);
                 return Some(start);
-            } else {
-                start += nfree + 1;
             }
+            start += nfree + 1;
+        }
+        None
+    }
+
+    /// 覚えている領域から取る。ビットマップと食い違っていた領域は忘れる
+    fn take_cached_run(&mut self, nframes: usize) -> Option<FrameId> {
+        for i in 0..FREE_RUN_CACHE {
+            let (start, len) = self.free_runs[i];
+            if len < nframes {
+                continue;
+            }
+            if !self.is_free_run(start, nframes) {
+                self.free_runs[i] = (0, 0);
+                continue;
+            }
+            self.free_runs[i] = if len == nframes { (0, 0) } else { (start + nframes, len - nframes) };
+            return Some(start);
         }
         None
     }
 
+    /// 返された領域を覚える。一杯なら一番短いものと入れ替える
+    fn cache_run(&mut self, start: FrameId, nframes: usize) {
+        let (i, shortest) = self.free_runs.iter().enumerate().min_by_key(|(_, (_, len))| *len).unwrap();
+        if shortest.1 < nframes {
+            self.free_runs[i] = (start, nframes);
+        }
+    }
+
+    fn take(&mut self, start: FrameId, nframes: usize) {
+        self.mark_allocated(start, nframes);
+        self.free_frames -= nframes;
+        self.cursor = start + nframes;
+    }
+
+    pub fn allocate(&mut self, nframes: usize) -> Option<FrameId> {
+        if nframes == 0 || nframes > self.free_frames {
+            return None;
+        }
+
+        // 前回の続きから探し、無ければ先頭からcursorをまたぐ所まで探し直す
+        let start = self.take_cached_run(nframes)
+            .or_else(|| self.find_free(self.cursor, self.last_usable, nframes))
+            .or_else(|| self.find_free(self.first_usable, (self.cursor + nframes - 1).min(self.last_usable), nframes))?;
+        self.take(start, nframes);
+        Some(start)
+    }
+
     pub fn free(&mut self, start: FrameId, nframes: usize) {
         for frame in start..start + nframes {
-            self.set_bit(frame, false);
+            if self.get_bit(frame) {
+                self.set_bit(frame, false);
+                self.free_frames += 1;
+            }
+        }
+        self.cursor = self.cursor.min(start);
+        if nframes > 1 {
+            self.cache_run(start, nframes);
+        }
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// 連続して空いているフレームの最大数。ビットマップを端から数える
+    fn largest_free_run(&self) -> usize {
+        let (mut largest, mut run) = (0, 0);
+        let mut frame = self.first_usable;
+        while frame < self.last_usable {
+            // 8フレームすべて使用中ならまとめて飛ばす
+            if frame % 8 == 0 && self.alloc_map[frame / 8] == 0xff {
+                run = 0;
+                frame += 8;
+                continue;
+            }
+            if self.get_bit(frame) {
+                run = 0;
+            } else {
+                run += 1;
+                largest = largest.max(run);
+            }
+            frame += 1;
         }
+        largest
+    }
+
+    fn stats(&self) -> MemoryStats {
+        MemoryStats { total_frames: self.total_frames, free_frames: self.free_frames, largest_free_run: self.largest_free_run() }
     }
+}
 
-    pub fn get_frame_start(&self, frame: FrameId) -> *mut u8 {
-        (frame * BYTES_PER_FRAME) as *mut u8
+/// 物理フレームの使用状況。空きが多いのに大きく取れなければ断片化、空き自体が減り続けるならリーク
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub largest_free_run: usize,
+}
+
+/// "memory: free 1234/5678 frames (4 MiB), largest free run 100 frames"
+impl core::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f, "memory: free {}/{} frames ({} MiB), largest free run {} frames",
+            self.free_frames, self.total_frames, self.free_frames * BYTES_PER_FRAME / (1024 * KB), self.largest_free_run
+        )
     }
 }
 
+/// フレームの先頭を指すポインタ
+pub fn frame_to_ptr(frame: FrameId) -> *mut u8 {
+    phys_to_virt(PhysAddr::new((frame * BYTES_PER_FRAME) as u64)).as_mut_ptr()
+}
+
+fn ptr_to_frame(ptr: *mut u8) -> FrameId {
+    ptr_to_phys(ptr).as_u64() as usize / BYTES_PER_FRAME
+}
+
 pub struct LazyInitVal<T> {
     init: bool,
     // 制約: init=trueなら初期化されている
@@ -228,18 +610,16 @@ impl<T> LazyInitVal<T> {
         }
     }
 
-    pub unsafe fn init_inplace(&mut self, initializer: &dyn Fn(&mut MaybeUninit<T>)) {
-        assert!(!self.init);
-        initializer(&mut self.inner);
-        self.init = true;
-    }
-
     pub fn init(&mut self, content: T) {
         assert!(!self.init);
         self.inner = MaybeUninit::new(content);
         self.init = true;
     }
 
+    pub fn is_init(&self) -> bool {
+        self.init
+    }
+
     pub fn get(&self) -> &T {
         assert!(self.init);
         unsafe { self.inner.assume_init_ref() }
@@ -264,21 +644,28 @@ impl <T> core::ops::DerefMut for LazyInitVal<T> {
     }
 }
 
-pub struct LazyInit<T> {
+pub struct LazyInit<T, R: RawMutex = SpinMutex> {
     // in-placeに初期化したいので、Mutex<Option<T>>は使えない(おそらく)
-    inner: Mutex<LazyInitVal<T>>,
+    inner: lock_api::Mutex<R, LazyInitVal<T>>,
 }
 
-impl<T> LazyInit<T> {
+/// 割り込みハンドラからも触るLazyInit
+pub type IrqLazyInit<T> = LazyInit<T, IrqSpinMutex>;
+
+impl<T, R: RawMutex> LazyInit<T, R> {
     pub const fn new() -> Self {
         LazyInit {
-            inner: Mutex::new(LazyInitVal::new()),
+            inner: lock_api::Mutex::new(LazyInitVal::new()),
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<'_, SpinMutex, LazyInitVal<T>> {
+    pub fn lock(&self) -> MutexGuard<'_, R, LazyInitVal<T>> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, R, LazyInitVal<T>>> {
+        self.inner.try_lock()
+    }
 }
 
 static MEM: LazyInit<BitMapMemoryManager> = LazyInit::new();
@@ -293,17 +680,33 @@ static MEM: LazyInit<BitMapMemoryManager> = LazyInit::new();
  *
  */
 
+/// 空きブロックのヘッダより後ろを埋める値。idle中のスイープで書き換わっていないか確かめる
+pub const POISON: u8 = 0x6b;
+/// 空きブロックの先頭に置くヘッダの大きさ。ここより後ろがPOISONで埋まる
+const NODE_SIZE: usize = size_of::<Mutex<ObjectHeader>>();
+
 struct FreeList {
     head: Option<&'static Mutex<ObjectHeader>>,
+    len: usize,
+    /// 出し入れのたびに増える。スイープが途中で中断したあと、続きから読めるかの判定に使う
+    generation: u64,
 }
 
 impl FreeList {
-    unsafe fn push_front(&mut self, obj: *mut u8) {
+    const fn new() -> Self {
+        Self { head: None, len: 0, generation: 0 }
+    }
+
+    /// objの大きさはobj_szで、ヘッダより後ろをPOISONで埋めてから繋ぐ
+    unsafe fn push_front(&mut self, obj: *mut u8, obj_sz: usize) {
+        obj.add(NODE_SIZE).write_bytes(POISON, obj_sz - NODE_SIZE);
         let obj = obj as *mut Mutex<ObjectHeader>;
         *obj = Mutex::new(ObjectHeader {
             next_free: self.head,
         });
         self.head = Some(&*obj);
+        self.len += 1;
+        self.generation += 1;
     }
 
     fn pop_front(&mut self) -> *mut u8 {
@@ -312,74 +715,69 @@ impl FreeList {
             Some(head) => {
                 // println!("{:x} -> {:x}", head.data_ptr() as usize, head.lock().next_free.map_or(null_mut(), |x|x.data_ptr()) as usize);
                 self.head = head.lock().next_free;
+                self.len -= 1;
+                self.generation += 1;
                 head as *const Mutex<ObjectHeader> as *mut u8
             }
         }
     }
-
-    fn pop_filter(&mut self, predicate: &dyn Fn(*mut u8) -> bool) -> *mut u8 {
-        match self.head {
-            None => null_mut(),
-            Some(head) => {
-                if predicate(head as *const Mutex<ObjectHeader> as *mut u8) {
-                    self.pop_front()
-                } else {
-                    let mut prev = head.lock();
-                    while let Some(obj) = prev.next_free {
-                        if predicate(obj as *const Mutex<ObjectHeader> as *mut u8) {
-                            prev.next_free = obj.lock().next_free;
-                            return obj as *const Mutex<ObjectHeader> as *mut u8;
-                        }
-                        prev = obj.lock();
-                    }
-                    null_mut()
-                }
-            }
-        }
-    }
 }
 
+/// 各大きさの最初のページはMutexに包んで置き、空きリストはそこにだけ持つ
+/// 足したページにも同じ位置にヘッダを置き、nextで最初のページから繋ぐ
 #[repr(C)]
 struct PageHeader {
     next: *mut PageHeader,
     free_list: FreeList,
+    /// このページに入るブロックの数
     n_objs: usize,
     obj_sz: usize,
 }
 
 impl PageHeader {
+    /// ページ内で最初のブロックの位置。ヘッダの後ろをブロックの大きさに揃える
+    const fn objs_offset(obj_sz: usize) -> usize {
+        (size_of::<Mutex<PageHeader>>() + obj_sz - 1) / obj_sz * obj_sz
+    }
+
+    const fn capacity(obj_sz: usize) -> usize {
+        (BYTES_PER_FRAME - Self::objs_offset(obj_sz)) / obj_sz
+    }
+
     pub unsafe fn new_at(page_head: *mut u8, obj_sz: usize) -> &'static Mutex<PageHeader> {
         let page = unsafe {
             let ptr = page_head as *mut Mutex<PageHeader>;
             *ptr = Mutex::new(PageHeader {
                 next: null_mut(),
-                free_list: FreeList { head: None },
-                n_objs: 0,
+                free_list: FreeList::new(),
+                n_objs: Self::capacity(obj_sz),
                 obj_sz,
             });
             &*ptr
         };
 
-        let mut page_lock = page.lock();
-
-        // initialize object headers
-        let objs_start =
-            page_head as usize + (size_of::<PageHeader>() + obj_sz - 1) / obj_sz * obj_sz;
-        let mut ptr = page_head as usize + BYTES_PER_FRAME - obj_sz;
-        while ptr >= objs_start {
-            page_lock.free_list.push_front(ptr as *mut u8);
-            // let head = page_lock.free_list.head.unwrap();
-            // println!("{:x} -> {:x}", head.data_ptr() as usize, head.lock().next_free.map_or(null_mut(), |x|x.data_ptr()) as usize);
-            ptr -= obj_sz;
-        }
-
+        page.lock().push_objects(page_head);
         page
     }
 
     pub unsafe fn extend(&mut self, page: *mut u8) {
+        let header = page as *mut PageHeader;
+        *header = PageHeader {
+            next: self.next,
+            free_list: FreeList::new(),
+            n_objs: Self::capacity(self.obj_sz),
+            obj_sz: self.obj_sz,
+        };
+        self.next = header;
+        self.push_objects(page);
+    }
+
+    /// pageのブロックをすべて空きリストに入れる。アドレスの小さい方から出てくる
+    unsafe fn push_objects(&mut self, page: *mut u8) {
+        let objs_start = page as usize + Self::objs_offset(self.obj_sz);
         let mut ptr = page as usize + BYTES_PER_FRAME - self.obj_sz;
-        while ptr >= page as usize {
-            self.free_list.push_front(ptr as *mut u8);
+        while ptr >= objs_start {
+            self.free_list.push_front(ptr as *mut u8, self.obj_sz);
             ptr -= self.obj_sz;
         }
     }
@@ -401,7 +799,7 @@ impl ObjectAllocator {
         let mut pages: [MaybeUninit<&'static Mutex<PageHeader>>; ObjectAllocator::N_BLOCK_SIZES] =
             unsafe { MaybeUninit::uninit().assume_init() };
         for (i, size) in ObjectAllocator::BLOCK_SZ.iter().enumerate() {
-            let ptr = (MEM.lock().allocate(1).unwrap() * BYTES_PER_FRAME) as *mut u8;
+            let ptr = frame_to_ptr(MEM.lock().allocate(1).unwrap());
             let page = unsafe { PageHeader::new_at(ptr, *size) };
             pages[i] = MaybeUninit::new(page);
         }
@@ -410,58 +808,274 @@ impl ObjectAllocator {
         }
     }
 
+    /// プロファイル中はタグを置く1バイトを末尾に足す
+    const TAG_BYTES: usize = if cfg!(feature = "heap-profile") { 1 } else { 0 };
+
+    /// ページ単位で確保するときのフレーム数
+    fn page_count(size: usize) -> usize {
+        (size + Self::TAG_BYTES + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME
+    }
+
+    /// layoutが入るブロックの大きさの番号。Noneならページ単位で確保する
+    /// ブロックはページ内で自分の大きさの倍数の位置に並ぶので、align以上の大きさなら必ず揃っている
+    fn class_of(layout: Layout) -> Option<usize> {
+        let need = (layout.size() + Self::TAG_BYTES).max(layout.align());
+        ObjectAllocator::BLOCK_SZ.iter().position(|sz| *sz >= need)
+    }
+
     pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        if layout.size() > 2048 {
+        let ptr = self.alloc_untagged(layout);
+        #[cfg(feature = "heap-profile")]
+        if !ptr.is_null() {
+            unsafe { *Self::tag_ptr(ptr, layout) = crate::heap_profile::on_alloc(layout.size()) };
+        }
+        ptr
+    }
+
+    /// タグを置く場所。ブロックはオブジェクトより必ず大きいので、その末尾の余りを使う
+    #[cfg(feature = "heap-profile")]
+    fn tag_ptr(ptr: *mut u8, layout: Layout) -> *mut u8 {
+        let block = match Self::class_of(layout) {
+            Some(class) => ObjectAllocator::BLOCK_SZ[class],
+            None => Self::page_count(layout.size()) * BYTES_PER_FRAME,
+        };
+        ptr.wrapping_add(block - 1)
+    }
+
+    fn alloc_untagged(&mut self, layout: Layout) -> *mut u8 {
+        let Some(class) = Self::class_of(layout) else {
+            // フレームは4KiB境界にしか揃えられない
             if layout.align() > BYTES_PER_FRAME {
-                unimplemented!("Page allocator cannot alloc pages aligned to >{BYTES_PER_FRAME}B.");
+                return null_mut();
             }
-            return match MEM.lock().allocate((layout.size() + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME) {
-                Some(id) => (id * BYTES_PER_FRAME) as *mut u8,
+            return match MEM.lock().allocate(Self::page_count(layout.size())) {
+                Some(id) => frame_to_ptr(id),
                 None => null_mut()
             };
-        }
+        };
 
-        let size = ObjectAllocator::BLOCK_SZ
-            .iter()
-            .enumerate()
-            .find(|(_, sz)| **sz > layout.size());
-        if size.is_none() {
-            return null_mut();
+        let mut page = self.pages[class].lock();
+        let addr = page.free_list.pop_front();
+        if !addr.is_null() {
+            return addr;
         }
-        let (index, _) = size.unwrap();
-        let mut page = self.pages[index].lock();
+        // 空きが無くなったらフレームを1つ足して繋ぐ
+        let frame = match MEM.lock().allocate(1) {
+            None => return null_mut(),
+            Some(p) => frame_to_ptr(p)
+        };
+        unsafe { page.extend(frame) };
+        page.free_list.pop_front()
+    }
 
-        let is_aligned_fn = |ptr| ptr as usize % layout.align() == 0;
-        let addr = page.free_list.pop_filter(&is_aligned_fn);
-        if addr.is_null() {
-            unsafe {
-                let addr = match MEM.lock().allocate(1) {
-                    None => {return null_mut();},
-                    Some(p) => (p * BYTES_PER_FRAME) as *mut u8
-                };
-                page.extend(addr);
-            }
-            page.free_list.pop_filter(&is_aligned_fn)
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "heap-profile")]
+        crate::heap_profile::on_dealloc(*Self::tag_ptr(ptr, layout), layout.size());
+
+        let Some(class) = Self::class_of(layout) else {
+            MEM.lock().free(ptr_to_frame(ptr), Self::page_count(layout.size()));
+            return;
+        };
+        let mut page = self.pages[class].lock();
+        let obj_sz = page.obj_sz;
+
+        page.free_list.push_front(ptr, obj_sz)
+    }
+
+    /// 最初のページのアドレス
+    fn head_page(&self, class: usize) -> usize {
+        self.pages[class] as *const Mutex<PageHeader> as usize
+    }
+
+    /// pageのヘッダを読み、classのページとして辻褄が合っているか確かめる
+    /// headはclassの最初のページで、呼び出し側がロックしているもの
+    fn page_header<'a>(&self, class: usize, head: &'a PageHeader, page: usize) -> Result<&'a PageHeader, HeapCorruption> {
+        let header = if page == self.head_page(class) {
+            head
         } else {
-            addr
+            unsafe { &*(page as *const PageHeader) }
+        };
+        let obj_sz = ObjectAllocator::BLOCK_SZ[class];
+        if header.obj_sz != obj_sz || header.n_objs != PageHeader::capacity(obj_sz) {
+            return Err(HeapCorruption::new(page, 0, "page header"));
         }
+        Ok(header)
     }
 
-    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        if layout.size() > 2048 {
-            MEM.lock().free(ptr as usize / BYTES_PER_FRAME, (layout.size() + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME);
-            return;
+    /// 空きリストのブロックを1つ確かめ、次のブロックのアドレス(無ければ0)を返す
+    /// リンクは無事で中身だけ壊れていたら、エラーに次のブロックを添える
+    fn check_free_block(&self, class: usize, head: &PageHeader, node: usize) -> Result<usize, (HeapCorruption, Option<usize>)> {
+        let page = node & !(BYTES_PER_FRAME - 1);
+        let offset = node - page;
+        let obj_sz = self.page_header(class, head, page).map_err(|e| (e, None))?.obj_sz;
+        let objs_offset = PageHeader::objs_offset(obj_sz);
+        if offset < objs_offset || (offset - objs_offset) % obj_sz != 0 {
+            return Err((HeapCorruption::new(page, offset, "misaligned free-list node"), None));
+        }
+
+        let header = unsafe { &*(node as *const Mutex<ObjectHeader>) };
+        // 空きブロックのロックは取られないので、立っていたら書き換えられている
+        if header.is_locked() {
+            return Err((HeapCorruption::new(page, offset, "free-list node"), None));
+        }
+        let next = unsafe { (*header.data_ptr()).next_free }.map_or(0, |n| n as *const Mutex<ObjectHeader> as usize);
+        let next_page = next & !(BYTES_PER_FRAME - 1);
+        if next != 0 && !paging::is_mapped(PhysAddr::new(next_page as u64), BYTES_PER_FRAME as u64) {
+            return Err((HeapCorruption::new(page, offset, "free-list link"), None));
         }
-        
-        let (index, _) = ObjectAllocator::BLOCK_SZ
-            .iter()
-            .enumerate()
-            .find(|(_, sz)| **sz > layout.size())
-            .unwrap();
-        let mut page = self.pages[index].lock();
 
-        page.free_list.push_front(ptr)
+        let body = unsafe { core::slice::from_raw_parts((node + NODE_SIZE) as *const u8, obj_sz - NODE_SIZE) };
+        if let Some(i) = body.iter().position(|b| *b != POISON) {
+            return Err((HeapCorruption::new(page, offset + NODE_SIZE + i, "poison"), Some(next)));
+        }
+        Ok(next)
     }
+
+    /// cursorの続きから、多くてもmax_nodes個のページかブロックを確かめる
+    /// 空きリストが途中で変わっていたら、その大きさを最初から確かめ直す
+    pub fn sweep_step(&self, cursor: &mut SweepCursor, max_nodes: usize) -> SweepProgress {
+        let mut progress = SweepProgress { checked: 0, cycle_done: false, error: None };
+        // 続きを辿れないほど壊れていた
+        let mut broken = false;
+        let class = cursor.class;
+        let head = self.pages[class].lock();
+        if cursor.phase == SweepPhase::Start || cursor.generation != head.free_list.generation {
+            cursor.phase = SweepPhase::Pages;
+            cursor.pos = self.head_page(class);
+            cursor.generation = head.free_list.generation;
+            cursor.pages = 0;
+            cursor.capacity = 0;
+            cursor.visited = 0;
+        }
+
+        let finished = |cursor: &SweepCursor| cursor.phase == SweepPhase::FreeList && cursor.pos == 0;
+        while progress.checked < max_nodes && progress.error.is_none() && !finished(cursor) {
+            progress.checked += 1;
+            match cursor.phase {
+                SweepPhase::Pages if cursor.pos == 0 => {
+                    if head.free_list.len > cursor.capacity {
+                        progress.error = Some(HeapCorruption::new(self.head_page(class), 0, "free count exceeds capacity"));
+                        broken = true;
+                        break;
+                    }
+                    cursor.phase = SweepPhase::FreeList;
+                    cursor.pos = head.free_list.head.map_or(0, |n| n as *const Mutex<ObjectHeader> as usize);
+                }
+                SweepPhase::Pages => {
+                    let page = cursor.pos;
+                    cursor.pages += 1;
+                    // 循環していれば、恒等写像に収まるページの数より長くなる
+                    let too_long = cursor.pages > IDENTITY_MAP_END as usize / BYTES_PER_FRAME;
+                    if too_long || page % BYTES_PER_FRAME != 0 || !paging::is_mapped(PhysAddr::new(page as u64), BYTES_PER_FRAME as u64) {
+                        progress.error = Some(HeapCorruption::new(self.head_page(class), 0, "page link"));
+                        broken = true;
+                        break;
+                    }
+                    match self.page_header(class, &head, page) {
+                        Ok(header) => {
+                            cursor.capacity += header.n_objs;
+                            cursor.pos = header.next as usize;
+                        }
+                        Err(e) => {
+                            progress.error = Some(e);
+                            broken = true;
+                        }
+                    }
+                }
+                SweepPhase::FreeList => {
+                    cursor.visited += 1;
+                    // 循環していれば数より長くなる
+                    if cursor.visited > head.free_list.len {
+                        progress.error = Some(HeapCorruption::new(self.head_page(class), 0, "free list cycle"));
+                        broken = true;
+                        break;
+                    }
+                    match self.check_free_block(class, &head, cursor.pos) {
+                        Ok(next) => cursor.pos = next,
+                        Err((e, Some(next))) => {
+                            cursor.pos = next;
+                            progress.error = Some(e);
+                        }
+                        Err((e, None)) => {
+                            progress.error = Some(e);
+                            broken = true;
+                            break;
+                        }
+                    }
+                }
+                SweepPhase::Start => unreachable!(),
+            }
+        }
+
+        let finished = finished(cursor);
+        if finished && progress.error.is_none() && cursor.visited != head.free_list.len {
+            progress.error = Some(HeapCorruption::new(self.head_page(class), 0, "free count mismatch"));
+        }
+        // 読み終えたか、続きを辿れなくなったら次の大きさへ
+        if finished || broken {
+            cursor.class = (class + 1) % ObjectAllocator::N_BLOCK_SIZES;
+            cursor.phase = SweepPhase::Start;
+            progress.cycle_done = cursor.class == 0;
+        }
+        progress
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SweepPhase {
+    /// 次はこの大きさを最初から
+    Start,
+    /// ページの繋がりを辿り、容量を数えている
+    Pages,
+    /// 空きリストを辿っている
+    FreeList,
+}
+
+/// スイープの続きの位置。ページは解放されないので、アドレスのまま持ってよい
+#[derive(Debug)]
+pub struct SweepCursor {
+    class: usize,
+    phase: SweepPhase,
+    /// 次に調べるページかブロック。0なら終わり
+    pos: usize,
+    generation: u64,
+    pages: usize,
+    capacity: usize,
+    visited: usize,
+}
+
+impl SweepCursor {
+    /// 最初の大きさの最初から
+    pub const fn new() -> Self {
+        Self { class: 0, phase: SweepPhase::Start, pos: 0, generation: 0, pages: 0, capacity: 0, visited: 0 }
+    }
+}
+
+/// 見つけた破損。offsetはページの先頭からで、bytesはその前後
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapCorruption {
+    pub page: usize,
+    pub offset: usize,
+    pub kind: &'static str,
+    /// bytes[0]のページ内の位置
+    pub bytes_start: usize,
+    pub bytes: [u8; 16],
+}
+
+impl HeapCorruption {
+    fn new(page: usize, offset: usize, kind: &'static str) -> Self {
+        let bytes_start = offset.saturating_sub(8).min(BYTES_PER_FRAME - 16);
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(unsafe { core::slice::from_raw_parts((page + bytes_start) as *const u8, 16) });
+        Self { page, offset, kind, bytes_start, bytes }
+    }
+}
+
+pub struct SweepProgress {
+    pub checked: usize,
+    /// すべての大きさを一巡した
+    pub cycle_done: bool,
+    pub error: Option<HeapCorruption>,
 }
 
 unsafe impl GlobalAlloc for LazyInit<ObjectAllocator> {
@@ -474,20 +1088,161 @@ unsafe impl GlobalAlloc for LazyInit<ObjectAllocator> {
     }
 }
 
-unsafe impl<T> Sync for LazyInit<T> {}
+unsafe impl<T, R: RawMutex> Sync for LazyInit<T, R> {}
+
+/// 空いている物理フレームの数
+pub fn free_frames() -> usize {
+    MEM.lock().free_frames()
+}
+
+/// フレームの使用状況。ビットマップを走査する間、割り込みを止めてアロケータのロックを持つ
+pub fn memory_stats() -> MemoryStats {
+    without_interrupts(|| MEM.lock().stats())
+}
+
+/// panic・OOMのハンドラ用。ロックが取れなければ(確保の途中で止まったなど)待たずにNone
+pub fn try_memory_stats() -> Option<MemoryStats> {
+    without_interrupts(|| MEM.try_lock().filter(|m| m.is_init()).map(|m| m.stats()))
+}
+
+/// 連続したnフレームを直接取る。足りなければNoneで、ヒープのようにOOMハンドラで止まらない
+/// 取れた量に合わせて小さくできる処理(リングやスクラッチパッドなど)はこちらを使う
+pub fn try_allocate_frames(n: usize) -> Option<FrameId> {
+    without_interrupts(|| MEM.lock().allocate(n))
+}
+
+/// 割り込みを止めてヒープのスイープを1歩進める。割り込みハンドラがヒープを使ってもロックで止まらないように
+pub fn sweep_heap_step(cursor: &mut SweepCursor, max_nodes: usize) -> SweepProgress {
+    without_interrupts(|| GLOBAL_ALLOCATOR.lock().sweep_step(cursor, max_nodes))
+}
 
 #[global_allocator]
 static GLOBAL_ALLOCATOR: LazyInit<ObjectAllocator> = LazyInit::new();
 
 pub fn init_allocators(map: &MemoryMap) {
-    unsafe {
-        let mem_init = |inner: &mut MaybeUninit<BitMapMemoryManager>| {
-            BitMapMemoryManager::new_at(inner.as_mut_ptr() as *mut u8, map)
-        };
-        MEM.lock().init_inplace(&mem_init);
+    // ビットマップは使用可能な領域の先頭に置き、そのフレームは使用中にしておく
+    let len = BitMapMemoryManager::bitmap_len(map);
+    let nframes = (len + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME;
+    let at = BitMapMemoryManager::find_bitmap_frames(map, nframes).expect("memory: no room for the frame bitmap");
+    let bitmap = unsafe { from_raw_parts_mut(frame_to_ptr(at), len) };
+    let mut manager = BitMapMemoryManager::new(map, bitmap);
+    manager.reserve(at, nframes);
+    MEM.lock().init(manager);
+    let unmapped = MEM.lock().unmapped_frames;
+    if unmapped > 0 {
+        // まだコンソールもヒープも無い
+        crate::log_nofmt!(
+            "memory: ignoring ", unmapped * BYTES_PER_FRAME / (1024 * KB), " MiB of RAM beyond the identity-mapped limit (",
+            IDENTITY_MAP_END / GB as u64, " GiB)"
+        );
     }
     GLOBAL_ALLOCATOR.lock().init(ObjectAllocator::new());
     run_allocator_tests();
+    run_frame_allocator_tests();
+    run_rwlock_tests();
+    crate::heap_profile::run_heap_profile_tests();
+    crate::heap_sweep::run_heap_sweep_tests();
+}
+
+/// 順不同で、穴と4GiB以上のRAMを含むメモリマップでフレームアロケータを初期化し、穴のフレームが返らないことを確かめる
+pub fn run_frame_allocator_tests() {
+    use core::fmt::Write;
+    const fn desc(type_: MemoryType, physical_start: u64, num_pages: u64) -> MemoryDescriptor {
+        MemoryDescriptor { type_, physical_start, virtual_start: 0, num_pages, attribute: 0xf }
+    }
+    let descs = [
+        desc(MemoryType::EfiConventionalMemory, 0x1_0000_0000, 64),
+        desc(MemoryType::EfiMemoryMappedIO, 0xc000_0000, 0x40000),
+        desc(MemoryType::EfiConventionalMemory, 0x0, 0x9f),
+        desc(MemoryType::EfiLoaderData, 0x20_0000, 16),
+        desc(MemoryType::EfiBootServicesData, 0x10_0000, 32),
+        desc(MemoryType::EfiReservedMemoryType, 0x9f000, 0x61),
+        desc(MemoryType::EfiConventionalMemory, 0xbfff_0000, 16),
+    ];
+    let map = MemoryMap::from_descriptors(&descs);
+    let usable = |frame: FrameId| {
+        descs.iter().any(|d| {
+            let start = d.physical_start as usize / BYTES_PER_FRAME;
+            d.is_available() && frame != 0 && start <= frame && frame < start + d.num_pages as usize
+        })
+    };
+
+    // ビットマップは本物のフレームに置く。恒等写像の終端まで数える2つ目の表の分を取っておく
+    let nframes = (IDENTITY_MAP_END as usize / BYTES_PER_FRAME / 8 + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME;
+    let storage = MEM.lock().allocate(nframes).unwrap();
+    let bitmap_for = |map: &MemoryMap| {
+        let len = BitMapMemoryManager::bitmap_len(map);
+        assert!(len <= nframes * BYTES_PER_FRAME);
+        unsafe { from_raw_parts_mut(frame_to_ptr(storage), len) }
+    };
+    assert!(BitMapMemoryManager::bitmap_len(&map) == (0x1_0000_0000 / BYTES_PER_FRAME + 64 + 7) / 8);
+    // 表の順に、足りる長さの最初の領域
+    assert!(BitMapMemoryManager::find_bitmap_frames(&map, 33) == Some(0x1_0000_0000 / BYTES_PER_FRAME));
+    assert!(BitMapMemoryManager::find_bitmap_frames(&map, 100) == Some(1));
+    assert!(BitMapMemoryManager::find_bitmap_frames(&map, 200).is_none());
+    let manager = &mut BitMapMemoryManager::new(&map, bitmap_for(&map));
+    // フレーム0を除く
+    assert!(manager.free_frames() == 0x9f - 1 + 32 + 16 + 64);
+    assert!(manager.first_usable == 1);
+    assert!(manager.last_usable == 0x1_0000_0000 / BYTES_PER_FRAME + 64);
+    // 一番長い空きは0x9e(フレーム0を除く0x9f)
+    let total = 0x9f - 1 + 32 + 16 + 64;
+    assert!(manager.stats() == MemoryStats { total_frames: total, free_frames: total, largest_free_run: 0x9f - 1 });
+
+    // 2フレームずつ取ると、領域の境界をまたがないこと
+    let mut count = 0;
+    while let Some(frame) = manager.allocate(2) {
+        assert!(usable(frame) && usable(frame + 1));
+        count += 2;
+    }
+    while let Some(frame) = manager.allocate(1) {
+        assert!(usable(frame));
+        count += 1;
+    }
+    assert!(count == 0x9f - 1 + 32 + 16 + 64);
+    assert!(manager.free_frames() == 0);
+
+    manager.free(0x10_0000 / BYTES_PER_FRAME, 32);
+    assert!(manager.allocate(33).is_none());
+    assert!(manager.allocate(32) == Some(0x10_0000 / BYTES_PER_FRAME));
+    assert!(manager.unmapped_frames == 0);
+
+    // 飛び飛びに返すと、空きの数は増えても続きは短いまま
+    for frame in (0x10_0000 / BYTES_PER_FRAME..0x10_0000 / BYTES_PER_FRAME + 32).step_by(2) {
+        manager.free(frame, 1);
+    }
+    assert!(manager.stats() == MemoryStats { total_frames: total, free_frames: 16, largest_free_run: 1 });
+    let mut w = StackWriter::new();
+    let _ = write!(w, "{}", manager.stats());
+    assert!(w.as_bytes() == b"memory: free 16/270 frames (0 MiB), largest free run 1 frames");
+
+    // 返した領域は覚えておき、同じ大きさならそこから返す
+    let base = 0x1_0000_0000 / BYTES_PER_FRAME;
+    manager.free(base + 8, 4);
+    assert!(manager.allocate(4) == Some(base + 8));
+    // 覚えた後に別の経路で1フレーム取られていたら、その領域はもう渡さない
+    manager.free(base + 8, 4);
+    manager.take(base + 9, 1);
+    assert!(manager.allocate(4).is_none() && manager.allocate(3).is_none());
+    assert!(manager.allocate(2) == Some(base + 10));
+
+    // 恒等写像の終端をまたぐ領域は手前までだけ使い、その先は数えておく
+    const END_PAGE: u64 = IDENTITY_MAP_END / UEFI_PAGE_SIZE as u64;
+    let descs = [
+        desc(MemoryType::EfiConventionalMemory, IDENTITY_MAP_END - 16 * UEFI_PAGE_SIZE as u64, 48),
+        desc(MemoryType::EfiConventionalMemory, (END_PAGE + 0x1000) * UEFI_PAGE_SIZE as u64, 8),
+        desc(MemoryType::EfiConventionalMemory, IDENTITY_MAP_END * 2, 8),
+    ];
+    let map = MemoryMap::from_descriptors(&descs);
+    let manager = &mut BitMapMemoryManager::new(&map, bitmap_for(&map));
+    assert!(manager.free_frames() == 16);
+    assert!(manager.unmapped_frames == 32 + 8 + 8);
+    assert!(manager.last_usable == IDENTITY_MAP_END as usize / BYTES_PER_FRAME);
+    assert!(manager.allocate(17).is_none());
+    let frame = manager.allocate(16).unwrap();
+    assert!(frame + 16 == IDENTITY_MAP_END as usize / BYTES_PER_FRAME);
+
+    MEM.lock().free(storage, nframes);
 }
 
 pub fn run_allocator_tests() {
@@ -526,5 +1281,144 @@ pub fn run_allocator_tests() {
             // println!("ok: size = {size}, align = {align}");
         }
     }
+    run_allocator_growth_tests();
     // println!("run_allocator_tests: finished");
 }
+
+/// 1ページに収まらない数のブロックと、ページ単位の確保を混ぜて取り、すべて読み書きできることを確かめる
+fn run_allocator_growth_tests() {
+    const COUNT: usize = 3000;
+    let sizes = [8, 24, 48, 64, 100, 200, 500, 1000, 2047, 2048, 3000, 5000];
+    let layout_of = |i: usize| Layout::from_size_align(sizes[i % sizes.len()], 8).unwrap();
+    let fill = |ptr: *mut u8, i: usize| (ptr, layout_of(i).size(), (i * 7 + 3) as u8);
+
+    let round = || {
+        let mut ptrs = alloc::vec::Vec::with_capacity(COUNT);
+        for i in 0..COUNT {
+            let ptr = unsafe { GLOBAL_ALLOCATOR.alloc(layout_of(i)) };
+            assert!(!ptr.is_null() && ptr as usize % 8 == 0);
+            let (ptr, size, value) = fill(ptr, i);
+            unsafe { ptr.write_bytes(value, size) };
+            ptrs.push(ptr);
+        }
+        for (i, ptr) in ptrs.iter().enumerate() {
+            let (ptr, size, value) = fill(*ptr, i);
+            assert!(unsafe { from_raw_parts_mut(ptr, size) }.iter().all(|b| *b == value));
+            unsafe { GLOBAL_ALLOCATOR.dealloc(ptr, layout_of(i)) };
+        }
+    };
+    round();
+    // 足したページは空きリストに残るので、2回目はフレームを新しく取らない
+    let free = free_frames();
+    round();
+    assert!(free_frames() == free);
+
+    // 大きさより大きいalignは、alignの大きさのブロックから取る
+    let layout = Layout::from_size_align(16, 1024).unwrap();
+    let ptr = unsafe { GLOBAL_ALLOCATOR.alloc(layout) };
+    assert!(ptr as usize % 1024 == 0);
+    unsafe { GLOBAL_ALLOCATOR.dealloc(ptr, layout) };
+}
+
+/// フレームの確保と解放をPAIRS組ずつ行い、かかった時間を出す。タイマーの初期化後に呼ぶ
+/// 1組ごとにロックを外すので、その間もtickは進む (bench feature)
+#[cfg(feature = "bench")]
+pub fn run_frame_allocator_benchmark() {
+    const PAIRS: usize = 10_000;
+    let bench = |nframes: usize| {
+        let (tick, us) = (crate::timer::get_current_tick(), crate::timer::uptime_micros());
+        for _ in 0..PAIRS {
+            let frame = try_allocate_frames(nframes).expect("memory: benchmark ran out of frames");
+            without_interrupts(|| MEM.lock().free(frame, nframes));
+        }
+        (crate::timer::get_current_tick() - tick, crate::timer::uptime_micros() - us)
+    };
+    let single = bench(1);
+    // 1フレームおきに持ったままにして、連続した空きを探させる
+    let held: alloc::vec::Vec<FrameId> = (0..512).filter_map(|_| try_allocate_frames(1)).collect();
+    for frame in held.iter().step_by(2) {
+        without_interrupts(|| MEM.lock().free(*frame, 1));
+    }
+    let multi = bench(4);
+    for frame in held.iter().skip(1).step_by(2) {
+        without_interrupts(|| MEM.lock().free(*frame, 1));
+    }
+    crate::println!(
+        "frame allocator: {PAIRS} x 1 frame in {} ticks ({}us), {PAIRS} x 4 frames in {} ticks ({}us)",
+        single.0, single.1, multi.0, multi.1
+    );
+}
+
+/// IrqMutexが割り込みを止めて持ち、外すと元の状態に戻すことを確かめる。割り込みを有効にしてから呼ぶ
+pub fn run_lock_tests() {
+    let enabled = interrupts::are_enabled();
+    let m = IrqMutex::new(0);
+
+    interrupts::enable();
+    {
+        let mut a = m.lock();
+        assert!(!interrupts::are_enabled());
+        *a += 1;
+        // 持っている間は取れず、割り込みも止まったまま
+        assert!(m.try_lock().is_none() && !interrupts::are_enabled());
+        let n = IrqMutex::new(0);
+        drop(n.lock());
+        // 内側を外しても、外側を持っている間は止まったまま
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled() && *m.lock() == 1);
+
+    // 止まっているところで取って外しても、勝手に有効にしない
+    interrupts::disable();
+    drop(m.lock());
+    drop(m.try_lock());
+    assert!(!interrupts::are_enabled());
+
+    let s: Mutex<u8> = Mutex::new(0);
+    let g = s.lock();
+    assert!(s.try_lock().is_none());
+    drop(g);
+    assert!(s.try_lock().is_some());
+
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+/// 読み手同士は同時に持て、書き手とは排他になることを確かめる
+pub fn run_rwlock_tests() {
+    let lock = RwLock::new(1);
+    {
+        let a = lock.read();
+        let b = lock.read();
+        assert!(*a + *b == 2);
+        assert!(lock.try_write().is_none());
+        assert!(lock.try_read().is_some());
+    }
+    {
+        let mut w = lock.write();
+        *w += 1;
+        assert!(lock.try_read().is_none() && lock.try_write().is_none());
+    }
+    assert!(*lock.read() == 2);
+
+    // 書き手が待っている間は新しい読み手を入れない
+    let r = lock.read();
+    unsafe { lock.raw() }.state.fetch_or(RW_WRITER_WAITING, Ordering::Relaxed);
+    assert!(lock.try_read().is_none());
+    drop(r);
+    // 待っていた書き手が取ると印は消え、外せば読み手が入れる
+    let w = lock.try_write().unwrap();
+    assert!(unsafe { lock.raw() }.state.load(Ordering::Relaxed) == RW_WRITER);
+    drop(w);
+    assert!(lock.try_read().is_some());
+}
+
+/// 物理フレームを使い切った状態で割り当てを行い、OOMハンドラを起動させる
+/// OOMハンドラのメッセージがシリアルに出ることを確認する (QEMU_ARGS="-serial stdio" など)
+#[cfg(feature = "oom-test")]
+pub fn run_oom_test() -> ! {
+    while MEM.lock().allocate(1).is_some() {}
+    let v: alloc::vec::Vec<u8> = alloc::vec::Vec::with_capacity(2 * BYTES_PER_FRAME);
+    unreachable!("allocation succeeded with {} bytes", v.capacity());
+}
@@ -27,6 +27,12 @@ pub struct MemoryMap<'a> {
 }
 
 impl<'a> MemoryMap<'a> {
+    /// ディスクリプタの配列をメモリマップとして扱う
+    pub fn from_descriptors(descs: &'a [MemoryDescriptor]) -> Self {
+        let buffer = unsafe { &*slice_from_raw_parts(descs.as_ptr() as *const u8, core::mem::size_of_val(descs)) };
+        MemoryMap { buffer, map_key: 0, descriptor_size: core::mem::size_of::<MemoryDescriptor>() }
+    }
+
     pub fn entries(&self) -> MemoryMapIter {
         MemoryMapIter {
             memmap: self,
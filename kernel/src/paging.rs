@@ -1,8 +1,46 @@
-use core::arch::global_asm;
+use core::{arch::global_asm, ptr::write_bytes};
+
+use crate::{
+    addr::{phys_to_virt, ptr_to_phys, PhysAddr},
+    asm::invlpg,
+    memory_manager::{frame_to_ptr, try_allocate_frames, Mutex, BYTES_PER_FRAME},
+};
 
 const PAGESIZE_4K: u64 = 4096;
 const PAGESIZE_2M: u64 = 512 * PAGESIZE_4K;
 const PAGESIZE_1G: u64 = 512 * PAGESIZE_2M;
+const NUM_PAGE_DIRS: usize = 64;
+
+/// 恒等写像されている領域の終端
+pub const IDENTITY_MAP_END: u64 = NUM_PAGE_DIRS as u64 * PAGESIZE_1G;
+/// map_mmioで写像できる終端。PML4の下半分で、これより上は非正規アドレスになる
+const MAPPABLE_END: u64 = 256 * 512 * PAGESIZE_1G;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+/// PATの既定値では、PWTとPCDを両方立てるとUC(キャッシュ無効)になる
+const PTE_PWT: u64 = 1 << 3;
+const PTE_PCD: u64 = 1 << 4;
+/// PDのエントリでは2MiBページ
+const PTE_HUGE: u64 = 1 << 7;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// ページテーブルを書き換えるときに持つ
+static TABLES_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// 範囲が写像できる終端を超えている
+    OutOfRange,
+    /// 新しいページテーブルに使うフレームが取れなかった
+    OutOfMemory,
+}
+
+/// [start, start+len) がすべて恒等写像の範囲に収まっているか
+pub fn is_mapped(start: PhysAddr, len: u64) -> bool {
+    start.as_u64() < IDENTITY_MAP_END
+        && start.as_u64().checked_add(len).map_or(false, |end| end <= IDENTITY_MAP_END)
+}
 
 #[repr(align(4096))]
 struct  PageMapLv4Table ([u64;512]);
@@ -11,30 +49,175 @@ struct  PageMapLv4Table ([u64;512]);
 struct PageDirectoryPointerTable ([u64; 512]);
 
 #[repr(align(4096))]
-struct PageDirectory ([[u64;512];64]);
+struct PageDirectory ([[u64;512];NUM_PAGE_DIRS]);
 
 static mut PML4_TABLE: PageMapLv4Table = PageMapLv4Table([0;512]);
 static mut PDP_TABLE: PageDirectoryPointerTable = PageDirectoryPointerTable([0;512]);
-static mut PAGE_DIRS: PageDirectory = PageDirectory([[0u64;512];64]);
+static mut PAGE_DIRS: PageDirectory = PageDirectory([[0u64;512];NUM_PAGE_DIRS]);
 
 pub fn setup_identity_page_table() {
     unsafe {
-        PML4_TABLE.0[0] = (&PDP_TABLE.0[0] as *const _ as u64) | 0x003;
+        PML4_TABLE.0[0] = ptr_to_phys(&PDP_TABLE.0[0]).as_u64() | 0x003;
         for i_pdpt in 0..PAGE_DIRS.0.len() {
-            PDP_TABLE.0[i_pdpt] = (&PAGE_DIRS.0[i_pdpt] as *const _ as u64) | 0x003;
+            PDP_TABLE.0[i_pdpt] = ptr_to_phys(&PAGE_DIRS.0[i_pdpt]).as_u64() | 0x003;
             for i_pd in 0..512 {
                 PAGE_DIRS.0[i_pdpt][i_pd] = (i_pdpt as u64 * PAGESIZE_1G + i_pd as u64 * PAGESIZE_2M) | 0x083;
             }
         }
-        set_cr3(&PML4_TABLE.0[0] as *const _ as u64);
+        set_cr3(ptr_to_phys(&PML4_TABLE.0[0]));
+    }
+}
+
+/// MMIOの[phys, phys+len)をキャッシュ無効で恒等写像する。恒等写像の範囲の外にあれば表を足して届くようにし、
+/// 2MiBページの一部だけにかかるところは4KiBの表に分ける
+pub fn map_mmio(phys: PhysAddr, len: u64) -> Result<(), MapError> {
+    let start = phys.as_u64() & !(PAGESIZE_4K - 1);
+    let end = phys.as_u64()
+        .checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(PAGESIZE_4K))
+        .filter(|&end| end <= MAPPABLE_END)
+        .ok_or(MapError::OutOfRange)?;
+    let _lock = TABLES_LOCK.lock();
+    let mut addr = start;
+    while addr < end {
+        let pd = unsafe { page_directory(addr)? };
+        let entry = &mut pd[(addr / PAGESIZE_2M % 512) as usize];
+        let chunk_end = (addr / PAGESIZE_2M + 1) * PAGESIZE_2M;
+        let is_table = *entry & PTE_PRESENT != 0 && *entry & PTE_HUGE == 0;
+        if addr % PAGESIZE_2M == 0 && chunk_end <= end && !is_table {
+            *entry = addr | PTE_PRESENT | PTE_WRITE | PTE_HUGE | PTE_PWT | PTE_PCD;
+            unsafe { invlpg(addr) };
+            addr = chunk_end;
+            continue;
+        }
+        let pt = unsafe { split_or_next_table(entry)? };
+        while addr < chunk_end.min(end) {
+            pt[(addr / PAGESIZE_4K % 512) as usize] = addr | PTE_PRESENT | PTE_WRITE | PTE_PWT | PTE_PCD;
+            unsafe { invlpg(addr) };
+            addr += PAGESIZE_4K;
+        }
+    }
+    Ok(())
+}
+
+/// addrを含む2MiBページを並べるPD。無い段は作る
+unsafe fn page_directory(addr: u64) -> Result<&'static mut [u64; 512], MapError> {
+    let pdpt = next_table(&mut PML4_TABLE.0[(addr / (512 * PAGESIZE_1G)) as usize])?;
+    next_table(&mut pdpt[(addr / PAGESIZE_1G % 512) as usize])
+}
+
+/// 表を指すエントリの先の表。エントリが空なら0で埋めたフレームを取って繋ぐ
+unsafe fn next_table(entry: &mut u64) -> Result<&'static mut [u64; 512], MapError> {
+    if *entry & PTE_PRESENT == 0 {
+        *entry = new_table()?.as_u64() | PTE_PRESENT | PTE_WRITE;
+    }
+    Ok(&mut *phys_to_virt(PhysAddr::new(*entry & PTE_ADDR_MASK)).as_mut_ptr())
+}
+
+/// PDのエントリが2MiBページなら、同じ写像と属性の4KiBページ512枚の表に置き換える
+unsafe fn split_or_next_table(entry: &mut u64) -> Result<&'static mut [u64; 512], MapError> {
+    if *entry & PTE_PRESENT != 0 && *entry & PTE_HUGE != 0 {
+        let base = *entry & PTE_ADDR_MASK & !(PAGESIZE_2M - 1);
+        let flags = *entry & (PTE_PRESENT | PTE_WRITE | PTE_PWT | PTE_PCD);
+        let table = new_table()?;
+        let pt = &mut *phys_to_virt(table).as_mut_ptr::<[u64; 512]>();
+        for (i, pte) in pt.iter_mut().enumerate() {
+            *pte = (base + i as u64 * PAGESIZE_4K) | flags;
+        }
+        *entry = table.as_u64() | PTE_PRESENT | PTE_WRITE;
+        // 2MiBページ内のどのアドレスでも、そのページのTLBエントリが消える
+        invlpg(base);
     }
+    next_table(entry)
+}
+
+fn new_table() -> Result<PhysAddr, MapError> {
+    let frame = try_allocate_frames(1).ok_or(MapError::OutOfMemory)?;
+    let table = frame_to_ptr(frame);
+    unsafe { write_bytes(table, 0, BYTES_PER_FRAME) };
+    Ok(ptr_to_phys(table))
+}
+
+/// addrを写像している末端のエントリ。4KiBページでも2MiBページでもよい
+fn leaf_entry(addr: u64) -> Option<u64> {
+    let _lock = TABLES_LOCK.lock();
+    let mut table = unsafe { &PML4_TABLE.0 };
+    for shift in [39, 30, 21, 12] {
+        let entry = table[(addr >> shift) as usize % 512];
+        if entry & PTE_PRESENT == 0 {
+            return None;
+        }
+        if shift == 12 || (shift == 21 && entry & PTE_HUGE != 0) {
+            return Some(entry);
+        }
+        table = unsafe { &*phys_to_virt(PhysAddr::new(entry & PTE_ADDR_MASK)).as_ptr::<[u64; 512]>() };
+    }
+    None
+}
+
+/// 恒等写像の範囲の外だけを写像し、中身には触らずに表を調べる
+pub fn run_map_mmio_tests() {
+    let uc = PTE_PWT | PTE_PCD;
+    let high = IDENTITY_MAP_END + 16 * PAGESIZE_1G;
+    assert!(leaf_entry(high).is_none());
+
+    // 2MiBの境界をまたぐ半端な範囲は4KiBページで、端のページも含む
+    map_mmio(PhysAddr::new(high + PAGESIZE_2M - 0x800), 0x1000).unwrap();
+    for addr in [high + PAGESIZE_2M - PAGESIZE_4K, high + PAGESIZE_2M] {
+        let e = leaf_entry(addr).unwrap();
+        assert!(e & PTE_ADDR_MASK == addr && e & uc == uc && e & PTE_HUGE == 0);
+    }
+    assert!(leaf_entry(high + PAGESIZE_2M - 2 * PAGESIZE_4K).is_none());
+    assert!(leaf_entry(high + PAGESIZE_2M + PAGESIZE_4K).is_none());
+
+    // 2MiBを丸ごと覆えば2MiBページ。その一部を写像し直すと、残りの写像と属性を保ったまま分かれる
+    let chunk = high + 4 * PAGESIZE_2M;
+    map_mmio(PhysAddr::new(chunk), PAGESIZE_2M).unwrap();
+    let e = leaf_entry(chunk + 0x1234).unwrap();
+    assert!(e & PTE_HUGE != 0 && e & uc == uc && e & PTE_ADDR_MASK == chunk);
+    map_mmio(PhysAddr::new(chunk + 5 * PAGESIZE_4K), 1).unwrap();
+    for i in [0, 5, 511] {
+        let e = leaf_entry(chunk + i * PAGESIZE_4K).unwrap();
+        assert!(e & PTE_HUGE == 0 && e & uc == uc && e & PTE_ADDR_MASK == chunk + i * PAGESIZE_4K);
+    }
+
+    // 恒等写像の範囲の外でも、別のPML4エントリの下に表を作って届く
+    let far = 3 * 512 * PAGESIZE_1G;
+    map_mmio(PhysAddr::new(far), PAGESIZE_4K).unwrap();
+    assert!(leaf_entry(far).unwrap() & PTE_ADDR_MASK == far);
+
+    assert!(map_mmio(PhysAddr::new(MAPPABLE_END - PAGESIZE_4K), PAGESIZE_4K).is_ok());
+    assert!(map_mmio(PhysAddr::new(MAPPABLE_END - PAGESIZE_4K), PAGESIZE_4K + 1) == Err(MapError::OutOfRange));
+    assert!(map_mmio(PhysAddr::new(u64::MAX), 2) == Err(MapError::OutOfRange));
+
+    // 恒等写像の中はキャッシュ有効の2MiBページのまま
+    let e = leaf_entry(0x20_0000).unwrap();
+    assert!(e & PTE_HUGE != 0 && e & uc == 0);
+}
+
+pub fn run_paging_tests() {
+    let end = PhysAddr::new(IDENTITY_MAP_END);
+    let page = PhysAddr::new(IDENTITY_MAP_END - PAGESIZE_4K);
+    assert!(is_mapped(PhysAddr::new(0), IDENTITY_MAP_END));
+    assert!(is_mapped(page, PAGESIZE_4K));
+    assert!(!is_mapped(page, PAGESIZE_4K + 1));
+    assert!(!is_mapped(end, 0));
+    assert!(!is_mapped(end, PAGESIZE_4K));
+    assert!(!is_mapped(PhysAddr::new(0), IDENTITY_MAP_END + 1));
+    // 終端の計算が溢れても写像済みとはみなさない
+    assert!(!is_mapped(page, u64::MAX));
+}
+
+/// CR3にPML4テーブルの物理アドレスを設定する
+pub unsafe fn set_cr3(pml4: PhysAddr) {
+    _set_cr3(pml4.as_u64());
 }
 
 extern "sysv64" {
-    fn set_cr3(val: u64);
+    fn _set_cr3(val: u64);
 }
 global_asm!(r#"
-set_cr3:
+_set_cr3:
     mov cr3, rdi
     ret
 "#);
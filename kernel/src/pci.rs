@@ -1,7 +1,8 @@
 /// Peripheral Component Interconnect (PCI) デバイス
 
-use core::{mem::{MaybeUninit, transmute, transmute_copy}};
-use crate::{asm, println};
+use core::{mem::{transmute, transmute_copy}, ptr::write_volatile};
+use alloc::vec::Vec;
+use crate::{addr::PhysAddr, asm, paging::is_mapped, println};
 use bitfield::bitfield;
 
 fn make_address(bus: u8, device: u8, function: u8, reg_addr: u8) -> u32 {
@@ -25,12 +26,16 @@ unsafe fn write_confreg(address: u32, value: u32) {
 }
 
 
+/// 見つけたPCIデバイスの一覧。メモリを確保するので、アロケータの初期化後に使う
 pub struct PCIController {
-    devices: [MaybeUninit<PCIDevice>; 32],
-    num_devices: usize,
+    devices: Vec<PCIDevice>,
+    /// スキャン済みのバス。2つのブリッジから同じバスが見えても1度しか数えない
+    scanned_buses: [bool; 256],
+    /// スキャンを諦めたブリッジとその理由
+    errors: Vec<(PCIDevice, PCIError)>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PCIDevice {
     bus: u8,
     device: u8,
@@ -47,68 +52,79 @@ pub struct ClassCode {
 impl PCIController {
     pub fn new() -> Self {
         Self {
-            devices: unsafe { MaybeUninit::uninit().assume_init() },
-            num_devices: 0,
+            devices: Vec::new(),
+            scanned_buses: [false; 256],
+            errors: Vec::new(),
         }
     }
 
-    /// 全てのPCIバスをスキャンし、接続されたデバイスを記憶する
-    pub unsafe fn scan_all_bus(&mut self) -> Result<(), PCIError> {
+    /// 全てのPCIバスをスキャンし、接続されたデバイスを記憶する。
+    /// 先に進めないブリッジがあってもそこだけ飛ばして続け、errorsに残す
+    pub unsafe fn scan_all_bus(&mut self) {
         let host_bridge = PCIDevice::new(0, 0, 0);
 
         if host_bridge.is_single_function_device() {
-            self.scan_bus(0)?;
+            self.scan_bus(0);
         } else {
             for function in 0..8 {
                 if PCIDevice::new(0, 0, function).read_vendor_id() != 0xffff {
-                    self.scan_bus(function)?;
+                    self.scan_bus(function);
                 }
             }
         }
-        Ok(())
     }
 
-    /// 現在記憶しているデバイスを返す
+    /// 現在記憶しているデバイスを返す。同じファンクションは1度しか入らない
     pub fn get_devices(&self) -> &[PCIDevice] {
-        unsafe {transmute(&self.devices[..self.num_devices])}
+        &self.devices
     }
 
     pub fn num_devices(&self) -> usize {
-        self.num_devices
+        self.devices.len()
+    }
+
+    /// スキャンを諦めたブリッジ
+    pub fn errors(&self) -> &[(PCIDevice, PCIError)] {
+        &self.errors
     }
 
-    fn add_device(&mut self, device: PCIDevice) -> Result<(), PCIError> {
-        if self.num_devices == self.devices.len() {
-            return Err(PCIError::DevicesAreFull);
+    /// 新しいデバイスならtrue
+    fn add_device(&mut self, device: &PCIDevice) -> bool {
+        if self.devices.contains(device) {
+            return false;
         }
-        self.devices[self.num_devices] = MaybeUninit::new(device);
-        self.num_devices += 1;
-        Ok(())
+        self.devices.push(device.clone());
+        true
+    }
+
+    /// まだスキャンしていないバスならtrueを返し、スキャン済みにする
+    fn visit_bus(&mut self, bus: u8) -> bool {
+        !core::mem::replace(&mut self.scanned_buses[bus as usize], true)
     }
 
-    unsafe fn scan_bus(&mut self, bus: u8) -> Result<(), PCIError> {
+    unsafe fn scan_bus(&mut self, bus: u8) {
+        if !self.visit_bus(bus) {
+            return;
+        }
         for device in 0..32 {
             if PCIDevice::new(bus, device, 0).is_valid() {
-                self.scan_device(bus, device)?;
+                self.scan_device(bus, device);
             }
         }
-        Ok(())
     }
 
-    unsafe fn scan_device(&mut self, bus: u8, device: u8) -> Result<(), PCIError> {
-        let device_zero = self.scan_function(bus, device, 0)?;
+    unsafe fn scan_device(&mut self, bus: u8, device: u8) {
+        let device_zero = self.scan_function(bus, device, 0);
 
         if device_zero.is_single_function_device() {
-            return Ok(());
+            return;
         }
 
         for function in 1..8 {
             if PCIDevice::new(bus, device, function).is_valid() {
-                self.scan_function(bus, device, function)?;
+                self.scan_function(bus, device, function);
             }
         }
-
-        Ok(())
     }
 
     unsafe fn scan_function(
@@ -116,18 +132,26 @@ impl PCIController {
         bus: u8,
         device: u8,
         function: u8,
-    ) -> Result<PCIDevice, PCIError> {
+    ) -> PCIDevice {
         let device = PCIDevice::new(bus, device, function);
-        self.add_device(device.clone())?;
+        if !self.add_device(&device) {
+            return device;
+        }
 
         let class_code = device.read_class_code();
         if class_code.base == 0x06 && class_code.sub == 0x04 {
             // standard PCI-PCI bridge
             let bus_numbers = device.read_bus_numbers();
             let secondary_bus = ((bus_numbers >> 8) & 0xff) as u8;
-            self.scan_bus(secondary_bus)?;
+            // 設定されていないブリッジは2次バスが0や自分のバス以下になっている
+            if secondary_bus <= bus {
+                println!("pci: bridge {}.{}.{} has secondary bus {}, skipped", bus, device.device, function, secondary_bus);
+                self.errors.push((device.clone(), PCIError::InvalidSecondaryBus(secondary_bus)));
+            } else {
+                self.scan_bus(secondary_bus);
+            }
         }
-        Ok(device)
+        device
     }
 }
 
@@ -196,6 +220,60 @@ impl PCIDevice {
         (bar_upper << 32) | bar
     }
 
+    /// BARの指す領域。全ビットに1を書いて読み返し、元に戻して大きさを調べる。
+    /// I/O空間のBARは大きさを調べない(sizeは0)。番号が範囲外ならNone
+    pub unsafe fn bar_info(&self, index: u8) -> Option<BarInfo> {
+        if index >= 6 {
+            return None;
+        }
+        let reg = 0x10 + 0x04 * index;
+        let lower = self.read_confreg(reg);
+        if lower & BAR_IO != 0 {
+            return Some(decode_bar(lower, 0, 0, 0));
+        }
+        let is_64bit = lower & BAR_TYPE_MASK == BAR_TYPE_64;
+        if is_64bit && index == 5 {
+            return None;
+        }
+        let upper = if is_64bit { self.read_confreg(reg + 4) } else { 0 };
+
+        // 調べている間に変な範囲をデコードしないよう、メモリ空間を止めておく
+        let command = self.read_command();
+        self.write_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+        self.write_confreg(reg, !0);
+        let lower_mask = self.read_confreg(reg);
+        self.write_confreg(reg, lower);
+        let upper_mask = if is_64bit {
+            self.write_confreg(reg + 4, !0);
+            let m = self.read_confreg(reg + 4);
+            self.write_confreg(reg + 4, upper);
+            m
+        } else {
+            0
+        };
+        self.write_command(command);
+        Some(decode_bar(lower, upper, lower_mask, upper_mask))
+    }
+
+    unsafe fn read_command(&self) -> u16 {
+        (self.read_confreg(0x04) & 0xffff) as u16
+    }
+
+    /// 上位16ビットのステータスは1を書くとクリアされるビットがあるので、0を書く
+    unsafe fn write_command(&self, command: u16) {
+        self.write_confreg(0x04, command as u32);
+    }
+
+    /// デバイスがDMAでメモリを読み書きできるようにする。MSIのメッセージもこれが無いと届かない
+    pub unsafe fn enable_bus_master(&self) {
+        self.write_command(self.read_command() | COMMAND_BUS_MASTER);
+    }
+
+    /// メモリ空間のBARへのアクセスにデバイスが応答するようにする
+    pub unsafe fn enable_memory_space(&self) {
+        self.write_command(self.read_command() | COMMAND_MEMORY_SPACE);
+    }
+
     pub unsafe fn read_cap_ptr(&self) -> u8 {
         (self.read_confreg(0x34) & 0xff) as u8
     }
@@ -205,22 +283,74 @@ impl PCIDevice {
     }
 }
 
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarInfo {
+    pub addr: u64,
+    /// 領域の大きさ。I/O空間のBARや、何も割り当てられていないBARでは0
+    pub size: u64,
+    pub prefetchable: bool,
+    pub is_64bit: bool,
+    pub is_io: bool,
+}
+
+/// BARの値(lower, upper)と、全ビットに1を書いて読み返した値(mask)から領域を求める。
+/// 32ビットのBARではupperとupper_maskは0
+fn decode_bar(lower: u32, upper: u32, lower_mask: u32, upper_mask: u32) -> BarInfo {
+    if lower & BAR_IO != 0 {
+        return BarInfo { addr: (lower & !0b11) as u64, size: 0, prefetchable: false, is_64bit: false, is_io: true };
+    }
+    let is_64bit = lower & BAR_TYPE_MASK == BAR_TYPE_64;
+    let addr = (upper as u64) << 32 | (lower & !0b1111) as u64;
+    let mask = if is_64bit {
+        (upper_mask as u64) << 32 | (lower_mask & !0b1111) as u64
+    } else {
+        // 上位32ビットは全て1として扱い、32ビットの範囲で大きさを求める
+        0xffff_ffff_0000_0000 | (lower_mask & !0b1111) as u64
+    };
+    let size = if mask & 0xffff_ffff == 0 && !is_64bit { 0 } else { (!mask).wrapping_add(1) };
+    BarInfo { addr, size, prefetchable: lower & BAR_PREFETCHABLE != 0, is_64bit, is_io: false }
+}
+
 impl ClassCode {
     pub fn matches(&self, base: u8, sub: u8, interface: u8) -> bool {
         (self.base, self.sub, self.interface) == (base, sub, interface)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PCIError {
-    DevicesAreFull,
+    /// ブリッジの2次バス番号が使えない値だった
+    InvalidSecondaryBus(u8),
+    /// MSIもMSI-Xも持っていない
+    NoMsiCapability,
+    /// MSI-Xのテーブルを置くBARがメモリ空間を指していない
+    MsixTableBarInvalid(u8),
+    /// MSI-Xのテーブルが恒等写像の範囲外にある
+    MsixTableNotMapped(u64),
+}
+
+/// 割り込みをどちらの方式で届けるように設定したか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiKind {
+    Msi,
+    MsiX,
 }
 
 
 #[repr(u8)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PCICapabilityId {
     MSI = 0x05,
+    MSIX = 0x11,
 }
 
 #[repr(packed)]
@@ -306,21 +436,149 @@ fn configure_msi_register(dev: &PCIDevice, cap_addr: u8, apic_id: u8, vector: u8
     }
 }
 
-pub fn configure_msi_fixed_destination(
-        dev: &PCIDevice, apic_id: u8, vector: u8) {
+/// ケーパビリティのリストをたどり、idのものの位置を返す
+fn find_capability(dev: &PCIDevice, id: PCICapabilityId) -> Option<u8> {
     unsafe {
         let mut cap_addr = dev.read_cap_ptr();
-        while cap_addr != 0 {
+        // 壊れたリストで回り続けないよう、コンフィグ空間に入る数までしか見ない
+        for _ in 0..48 {
+            if cap_addr == 0 {
+                break;
+            }
             let header: PCICapabilityHeader = transmute(dev.read_confreg(cap_addr));
-            
-            println!("!header: addr {}, cap_id: {}", cap_addr, header.cap_id);
-            println!("!header: {}", transmute_copy::<_,u32>(&header));
-       
-            if header.cap_id == PCICapabilityId::MSI as u8 {
-                configure_msi_register(dev, cap_addr, apic_id, vector);
-                return;
+            if header.cap_id == id as u8 {
+                return Some(cap_addr);
             }
             cap_addr = header.next_cap_ptr;
         }
+        None
+    }
+}
+
+/// MSIがあればMSIで、無ければMSI-Xで、apic_idのvectorに割り込みが届くようにする
+pub fn configure_msi_fixed_destination(
+        dev: &PCIDevice, apic_id: u8, vector: u8) -> Result<MsiKind, PCIError> {
+    if let Some(cap_addr) = find_capability(dev, PCICapabilityId::MSI) {
+        configure_msi_register(dev, cap_addr, apic_id, vector);
+        return Ok(MsiKind::Msi);
+    }
+    configure_msix_fixed_destination(dev, apic_id, vector)?;
+    Ok(MsiKind::MsiX)
+}
+
+bitfield!{
+    /// MSI-Xのケーパビリティの最初の4バイト
+    struct MSIXCapabilityHeader (u32);
+    u16;
+    cap_id, _: 7,0;
+    next_cap_ptr, _: 15,8;
+    table_size, _: 26,16;
+    function_mask, set_function_mask: 30;
+    msix_enable, set_msix_enable: 31;
+}
+
+/// Table Offset/BIRレジスタを、テーブルを置くBARの番号とその中のオフセットに分ける
+fn decode_table_location(reg: u32) -> (u8, u32) {
+    ((reg & 0b111) as u8, reg & !0b111)
+}
+
+/// MSI-Xのテーブルの1エントリ。メッセージアドレス(下位・上位)、データ、ベクタ制御の順
+fn msix_entry(apic_id: u8, vector: u8) -> [u32; 4] {
+    let mut addr = MSIMessageAddr(0);
+    addr.set_FEE(0xfee);
+    addr.set_destination_id(apic_id as u16);
+    let mut data = MSIMessageData(0);
+    data.set_vector(vector);
+    data.set_delivery_mode(0);
+    data.set_trigger_mode(true);
+    data.set_trigger_level(true);
+    // ベクタ制御のビット0がマスク。0にして有効にする
+    [addr.0, 0, data.0, 0]
+}
+
+/// MSI-Xのテーブルのエントリ0を、apic_idのvectorに届くように書く
+pub fn configure_msix_fixed_destination(dev: &PCIDevice, apic_id: u8, vector: u8) -> Result<(), PCIError> {
+    let cap_addr = find_capability(dev, PCICapabilityId::MSIX).ok_or(PCIError::NoMsiCapability)?;
+    unsafe {
+        let (bir, offset) = decode_table_location(dev.read_confreg(cap_addr + 4));
+        let bar = match dev.bar_info(bir) {
+            Some(bar) if !bar.is_io && bar.addr != 0 => bar,
+            _ => return Err(PCIError::MsixTableBarInvalid(bir)),
+        };
+        let table = bar.addr + offset as u64;
+        if !is_mapped(PhysAddr::new(table), 16) {
+            return Err(PCIError::MsixTableNotMapped(table));
+        }
+        dev.enable_memory_space();
+
+        // 書き換えている間は関数全体をマスクしておく
+        let mut header = MSIXCapabilityHeader(dev.read_confreg(cap_addr));
+        println!("msix: cap {}, table size {}, bar {} + {:#x}", cap_addr, header.table_size() + 1, bir, offset);
+        header.set_msix_enable(true);
+        header.set_function_mask(true);
+        dev.write_confreg(cap_addr, header.0);
+
+        let entry = table as *mut u32;
+        for (i, value) in msix_entry(apic_id, vector).into_iter().enumerate() {
+            write_volatile(entry.add(i), value);
+        }
+
+        header.set_function_mask(false);
+        dev.write_confreg(cap_addr, header.0);
+    }
+    Ok(())
+}
+
+/// スキャンした結果のpciも確かめる
+pub fn run_pci_tests(pci: &PCIController) {
+    let devices = pci.get_devices();
+    assert!(devices.iter().enumerate().all(|(i, d)| !devices[..i].contains(d)));
+
+    // 2つのブリッジから同じバスやファンクションが見えても、1度しか数えない
+    let mut c = PCIController::new();
+    assert!(c.visit_bus(3) && !c.visit_bus(3) && c.visit_bus(4));
+    assert!(c.add_device(&PCIDevice::new(3, 0, 0)) && c.add_device(&PCIDevice::new(3, 0, 1)));
+    assert!(!c.add_device(&PCIDevice::new(3, 0, 0)));
+    assert!(c.get_devices() == [PCIDevice::new(3, 0, 0), PCIDevice::new(3, 0, 1)]);
+    // 32個の上限はない
+    for device in 0..32 {
+        for function in 0..8 {
+            c.add_device(&PCIDevice::new(5, device, function));
+        }
+    }
+    assert!(c.num_devices() == 2 + 256 && c.errors().is_empty());
+
+    // BARの大きさ
+    let bar = decode_bar(0xfebf_0000, 0, 0xffff_c000, 0);
+    assert!(bar == BarInfo { addr: 0xfebf_0000, size: 0x4000, prefetchable: false, is_64bit: false, is_io: false });
+    let bar = decode_bar(0x0001_000c, 0x8, 0xffff_000c, 0xffff_ffff);
+    assert!(bar == BarInfo { addr: 0x8_0001_0000, size: 0x1_0000, prefetchable: true, is_64bit: true, is_io: false });
+    // 4GiBを超える大きさは上位の値に出る
+    let bar = decode_bar(0x0000_000c, 0x4, 0x0000_000c, 0xffff_fffe);
+    assert!(bar.addr == 0x4_0000_0000 && bar.size == 0x2_0000_0000);
+    // 使われていないBARとI/O空間のBAR
+    assert!(decode_bar(0, 0, 0, 0).size == 0 && decode_bar(0x4, 0, 0x4, 0).size == 0);
+    let bar = decode_bar(0xc001, 0, 0, 0);
+    assert!(bar.is_io && bar.addr == 0xc000 && bar.size == 0);
+
+    // MSI-X
+    assert!(decode_table_location(0x0000_3004) == (4, 0x3000) && decode_table_location(0x2000) == (0, 0x2000));
+    let header = MSIXCapabilityHeader(0x8007_7011);
+    assert!(header.cap_id() == 0x11 && header.next_cap_ptr() == 0x70 && header.table_size() == 7 && header.msix_enable() && !header.function_mask());
+    let entry = msix_entry(3, 0x40);
+    assert!(entry[0] == 0xfee0_3000 && entry[1] == 0 && entry[2] & 0xff == 0x40 && entry[2] & 0x700 == 0 && entry[3] & 1 == 0);
+
+    // 実際のデバイスで調べても、BARは元の値に戻っている
+    for dev in devices {
+        unsafe {
+            let before = dev.read_confreg(0x10);
+            let Some(info) = dev.bar_info(0) else {
+                continue;
+            };
+            assert!(dev.read_confreg(0x10) == before);
+            if !info.is_io {
+                assert!(info.addr == dev.read_bar(0) & !0b1111 && (info.size == 0 || info.size.is_power_of_two()));
+            }
+        }
     }
 }
@@ -0,0 +1,10 @@
+pub mod qemu;
+
+/// 起動時に実行環境を調べる。メモリ割り当ては行わないので、起動直後に呼んでよい
+pub fn init() {
+    qemu::detect();
+}
+
+pub fn run_platform_tests() {
+    qemu::run_qemu_tests();
+}
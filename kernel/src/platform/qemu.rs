@@ -0,0 +1,134 @@
+use core::{arch::{asm, x86_64::__cpuid}, fmt, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::asm::{io_in_8, io_out_16, io_out_8};
+
+/// QEMUのisa-debug-exitデバイスのI/Oポート
+/// qemu -device isa-debug-exit,iobase=0xf4,iosize=0x01 で有効になる
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+/// QEMU(とBochs)のdebugconのI/Oポート。qemu -debugcon file:debug.log で有効になる
+const DEBUGCON_PORT: u16 = 0xe9;
+
+/// ACPIのPM1a制御レジスタ。SLP_TYPが0でSLP_ENだけを立てると電源が切れる
+/// 今のQEMUは0x604、古いQEMUとBochsは0xb004にある
+const POWER_OFF_PORTS: [u16; 2] = [0x604, 0xb004];
+const POWER_OFF_VALUE: u16 = 0x2000;
+
+/// QEMUの終了コードは(code << 1) | 1になる。成功なら33、失敗なら35
+pub const EXIT_SUCCESS: u8 = 0x10;
+pub const EXIT_FAILURE: u8 = 0x11;
+
+/// CPUIDのleaf 1のECXのビット31。ハイパーバイザの上で動いていると立つ
+const CPUID_HYPERVISOR_BIT: u32 = 1 << 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    None,
+    /// QEMUのTCG(エミュレーション)
+    Tcg,
+    /// KVM。QEMUから使われているとは限らない
+    Kvm,
+    Other([u8; 12]),
+}
+
+/// CPUIDのleaf 1のECXと、leaf 0x40000000のEBX,ECX,EDXからハイパーバイザを判定する
+pub fn parse_hypervisor(leaf1_ecx: u32, vendor: [u32; 3]) -> Hypervisor {
+    if leaf1_ecx & CPUID_HYPERVISOR_BIT == 0 {
+        return Hypervisor::None;
+    }
+    let mut id = [0u8; 12];
+    for (i, reg) in vendor.iter().enumerate() {
+        id[4 * i..4 * i + 4].copy_from_slice(&reg.to_le_bytes());
+    }
+    match &id {
+        b"TCGTCGTCGTCG" => Hypervisor::Tcg,
+        b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+        _ => Hypervisor::Other(id),
+    }
+}
+
+static IS_QEMU: AtomicBool = AtomicBool::new(false);
+static DEBUGCON: AtomicBool = AtomicBool::new(false);
+/// panic=exit: パニックしたら失敗としてQEMUを終了する
+static PANIC_EXIT: AtomicBool = AtomicBool::new(false);
+
+/// CPUIDでQEMUかを調べ、そうならdebugconがあるかも調べる
+/// ポートにやみくもに書き込まないよう、QEMUと分かるまでは何も触らない
+pub fn detect() {
+    let hv = unsafe { parse_hypervisor(__cpuid(1).ecx, { let r = __cpuid(0x4000_0000); [r.ebx, r.ecx, r.edx] }) };
+    let is_qemu = matches!(hv, Hypervisor::Tcg | Hypervisor::Kvm);
+    IS_QEMU.store(is_qemu, Ordering::Release);
+    // debugconのポートを読むと0xe9が返る
+    let debugcon = is_qemu && unsafe { io_in_8(DEBUGCON_PORT) } == DEBUGCON_PORT as u8;
+    DEBUGCON.store(debugcon, Ordering::Release);
+}
+
+pub fn is_qemu() -> bool {
+    IS_QEMU.load(Ordering::Acquire)
+}
+
+pub fn set_panic_exit(enabled: bool) {
+    PANIC_EXIT.store(enabled, Ordering::Release);
+}
+
+/// panic=exitが指定されていて、QEMUの上で動いている
+pub fn panic_exit_enabled() -> bool {
+    PANIC_EXIT.load(Ordering::Acquire) && is_qemu()
+}
+
+/// QEMUを終了させる。isa-debug-exitデバイスが無ければ止まったままになる
+pub fn exit_qemu(code: u8) -> ! {
+    unsafe {
+        io_out_8(DEBUG_EXIT_PORT, code);
+        loop {
+            asm!("hlt");
+        }
+    }
+}
+
+/// ACPIを使わずにQEMUの電源を切る。切れなければ戻る
+pub fn power_off() {
+    if !is_qemu() {
+        return;
+    }
+    for port in POWER_OFF_PORTS {
+        unsafe {
+            io_out_16(port, POWER_OFF_VALUE);
+        }
+    }
+}
+
+/// debugconへ書く。1バイトにつきout命令1つで、UARTより軽い
+pub struct DebugconWriter;
+
+impl DebugconWriter {
+    /// debugconが見つかっていなければ何もしない
+    pub fn write_bytes(bytes: &[u8]) {
+        if !DEBUGCON.load(Ordering::Acquire) {
+            return;
+        }
+        for b in bytes {
+            unsafe {
+                io_out_8(DEBUGCON_PORT, *b);
+            }
+        }
+    }
+}
+
+impl fmt::Write for DebugconWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Self::write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub fn run_qemu_tests() {
+    let vendor = |s: &[u8; 12]| {
+        let word = |i: usize| u32::from_le_bytes([s[i], s[i + 1], s[i + 2], s[i + 3]]);
+        [word(0), word(4), word(8)]
+    };
+    assert!(parse_hypervisor(CPUID_HYPERVISOR_BIT, vendor(b"TCGTCGTCGTCG")) == Hypervisor::Tcg);
+    assert!(parse_hypervisor(CPUID_HYPERVISOR_BIT | 1, vendor(b"KVMKVMKVM\0\0\0")) == Hypervisor::Kvm);
+    assert!(parse_hypervisor(CPUID_HYPERVISOR_BIT, vendor(b"VMwareVMware")) == Hypervisor::Other(*b"VMwareVMware"));
+    // ビットが立っていなければleaf 0x40000000の中身は見ない
+    assert!(parse_hypervisor(!CPUID_HYPERVISOR_BIT, vendor(b"TCGTCGTCGTCG")) == Hypervisor::None);
+}
@@ -0,0 +1,467 @@
+use crate::{
+    acpi,
+    asm::{io_in_8, io_out_8},
+    ioapic::{self, IoApicError},
+    memory_manager::Mutex,
+    usb::{KeyReport, Keymap, LockState, ModifierSet, MouseReport},
+};
+
+const DATA_PORT: u16 = 0x60;
+/// 読むとステータス、書くとコントローラへのコマンド
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// コントローラへのコマンド
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_MOUSE_PORT: u8 = 0xa7;
+const CMD_ENABLE_MOUSE_PORT: u8 = 0xa8;
+const CMD_TEST_MOUSE_PORT: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_KEYBOARD_PORT: u8 = 0xab;
+const CMD_DISABLE_KEYBOARD_PORT: u8 = 0xad;
+const CMD_ENABLE_KEYBOARD_PORT: u8 = 0xae;
+/// 次にデータポートへ書くバイトをマウスのポートへ送る
+const CMD_WRITE_MOUSE_PORT: u8 = 0xd4;
+const SELF_TEST_OK: u8 = 0x55;
+
+/// コントローラの設定バイト
+const CONFIG_KEYBOARD_IRQ: u8 = 1 << 0;
+const CONFIG_MOUSE_IRQ: u8 = 1 << 1;
+/// マウスのポートのクロックを止めている。有効にしても立ったままならポートが無い
+const CONFIG_MOUSE_CLOCK_OFF: u8 = 1 << 5;
+/// キーボードのスキャンコードセット2をセット1に変換する
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+/// デバイスへのコマンドと応答
+const DEV_RESET: u8 = 0xff;
+const DEV_ENABLE_REPORTING: u8 = 0xf4;
+const DEV_SET_SAMPLE_RATE: u8 = 0xf3;
+const DEV_GET_ID: u8 = 0xf2;
+const KBD_SET_LEDS: u8 = 0xed;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const RESET_OK: u8 = 0xaa;
+/// このサンプルレートを順に設定した後のIDが3なら、ホイールのあるIntelliMouse
+const WHEEL_KNOCK: [u8; 3] = [200, 100, 80];
+const MOUSE_ID_WHEEL: u8 = 3;
+
+/// 1バイトを待つ回数。1回がステータスの読み出し1つ(1µsくらい)。リセットの後の自己診断が長い
+const WAIT_POLLS: usize = 1_000_000;
+const RESEND_RETRIES: usize = 3;
+
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const MOUSE_IRQ: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// FADTに8042が無いとある
+    NotPresent,
+    /// ステータスが0xffで、ポートの先に何も無い
+    NoController,
+    Timeout,
+    /// コントローラの自己診断の結果
+    SelfTest(u8),
+    /// デバイスがACKの代わりに返したもの
+    Nak(u8),
+    Route(IoApicError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Port {
+    Keyboard,
+    Mouse,
+}
+
+/// initで見つかったもの
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Devices {
+    pub keyboard: bool,
+    pub mouse: bool,
+    /// マウスがホイールの付いた4バイトのパケットを送ってくる
+    pub wheel: bool,
+}
+
+unsafe fn wait_status(bit: u8, set: bool) -> Result<(), Ps2Error> {
+    for _ in 0..WAIT_POLLS {
+        if (io_in_8(STATUS_PORT) & bit != 0) == set {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+unsafe fn read_byte() -> Result<u8, Ps2Error> {
+    wait_status(STATUS_OUTPUT_FULL, true)?;
+    Ok(io_in_8(DATA_PORT))
+}
+
+unsafe fn write_byte(port: u16, byte: u8) -> Result<(), Ps2Error> {
+    wait_status(STATUS_INPUT_FULL, false)?;
+    io_out_8(port, byte);
+    Ok(())
+}
+
+unsafe fn controller_command(cmd: u8) -> Result<u8, Ps2Error> {
+    write_byte(COMMAND_PORT, cmd)?;
+    read_byte()
+}
+
+unsafe fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_byte(COMMAND_PORT, CMD_WRITE_CONFIG)?;
+    write_byte(DATA_PORT, config)
+}
+
+/// 読まれずに残っているバイトを捨てる
+unsafe fn flush() {
+    for _ in 0..16 {
+        if io_in_8(STATUS_PORT) & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        io_in_8(DATA_PORT);
+    }
+}
+
+/// デバイスに1バイト送り、ACKを待つ。RESENDなら何度か送り直す
+unsafe fn send(port: Port, byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..RESEND_RETRIES {
+        if port == Port::Mouse {
+            write_byte(COMMAND_PORT, CMD_WRITE_MOUSE_PORT)?;
+        }
+        write_byte(DATA_PORT, byte)?;
+        match read_byte()? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            other => return Err(Ps2Error::Nak(other)),
+        }
+    }
+    Err(Ps2Error::Nak(RESEND))
+}
+
+unsafe fn reset_device(port: Port) -> Result<(), Ps2Error> {
+    send(port, DEV_RESET)?;
+    match read_byte()? {
+        RESET_OK => Ok(()),
+        other => Err(Ps2Error::Nak(other)),
+    }
+}
+
+/// マウスをリセットし、ホイールを有効にしてから報告を始めさせる。ホイールがあればtrue
+unsafe fn init_mouse() -> Result<bool, Ps2Error> {
+    reset_device(Port::Mouse)?;
+    // リセットの後にはデバイスIDが続く
+    read_byte()?;
+    for rate in WHEEL_KNOCK {
+        send(Port::Mouse, DEV_SET_SAMPLE_RATE)?;
+        send(Port::Mouse, rate)?;
+    }
+    send(Port::Mouse, DEV_GET_ID)?;
+    let wheel = read_byte()? == MOUSE_ID_WHEEL;
+    send(Port::Mouse, DEV_ENABLE_REPORTING)?;
+    Ok(wheel)
+}
+
+/// 割り込みで受け取ったバイトを組み立てる状態
+struct Input {
+    keyboard: Set1Decoder,
+    keymap: Keymap,
+    mouse: PacketDecoder,
+}
+
+static INPUT: Mutex<Option<Input>> = Mutex::new(None);
+
+/// 8042を初期化し、キーボードとマウスのIRQをI/O APICでapic_idのCPUのベクタに届ける
+/// キーボードはセット1のスキャンコードで受け取れるよう、コントローラに変換させる
+pub fn init(apic_id: u8, keyboard_vector: u8, mouse_vector: u8) -> Result<Devices, Ps2Error> {
+    if !acpi::has_8042() {
+        return Err(Ps2Error::NotPresent);
+    }
+    let mut devices = Devices::default();
+    unsafe {
+        if io_in_8(STATUS_PORT) == 0xff {
+            return Err(Ps2Error::NoController);
+        }
+        write_byte(COMMAND_PORT, CMD_DISABLE_KEYBOARD_PORT)?;
+        write_byte(COMMAND_PORT, CMD_DISABLE_MOUSE_PORT)?;
+        flush();
+        let config = (controller_command(CMD_READ_CONFIG)? & !(CONFIG_KEYBOARD_IRQ | CONFIG_MOUSE_IRQ)) | CONFIG_TRANSLATE;
+        write_config(config)?;
+        match controller_command(CMD_SELF_TEST)? {
+            SELF_TEST_OK => (),
+            other => return Err(Ps2Error::SelfTest(other)),
+        }
+        // 自己診断で設定が戻るコントローラがある
+        write_config(config)?;
+        write_byte(COMMAND_PORT, CMD_ENABLE_MOUSE_PORT)?;
+        let has_mouse_port = controller_command(CMD_READ_CONFIG)? & CONFIG_MOUSE_CLOCK_OFF == 0;
+        write_byte(COMMAND_PORT, CMD_DISABLE_MOUSE_PORT)?;
+
+        if controller_command(CMD_TEST_KEYBOARD_PORT)? == 0 {
+            write_byte(COMMAND_PORT, CMD_ENABLE_KEYBOARD_PORT)?;
+            devices.keyboard = reset_device(Port::Keyboard).is_ok();
+        }
+        if has_mouse_port && controller_command(CMD_TEST_MOUSE_PORT)? == 0 {
+            write_byte(COMMAND_PORT, CMD_ENABLE_MOUSE_PORT)?;
+            if let Ok(wheel) = init_mouse() {
+                devices.mouse = true;
+                devices.wheel = wheel;
+            }
+        }
+    }
+    *INPUT.lock() = Some(Input { keyboard: Set1Decoder::new(), keymap: Keymap::default(), mouse: PacketDecoder::new(devices.wheel) });
+
+    let mut config = 0;
+    if devices.keyboard {
+        ioapic::route_isa_irq(KEYBOARD_IRQ, keyboard_vector, apic_id).map_err(Ps2Error::Route)?;
+        config |= CONFIG_KEYBOARD_IRQ;
+    }
+    if devices.mouse {
+        ioapic::route_isa_irq(MOUSE_IRQ, mouse_vector, apic_id).map_err(Ps2Error::Route)?;
+        config |= CONFIG_MOUSE_IRQ;
+    }
+    unsafe {
+        let current = controller_command(CMD_READ_CONFIG)?;
+        write_config(current | config)?;
+    }
+    Ok(devices)
+}
+
+/// 割り込みハンドラから呼ぶ。届いたバイトを読む
+pub fn read_data() -> u8 {
+    unsafe { io_in_8(DATA_PORT) }
+}
+
+/// キーボードから届いた1バイトを取り込む。押されているキーが変わったら、USBのキーボードと同じ形のレポートと、
+/// それを処理した後のロックキーの状態を返す。ロックキーが切り替わればLEDも変える
+pub fn on_keyboard_byte(byte: u8) -> Option<(KeyReport, LockState)> {
+    let mut input = INPUT.lock();
+    let input = input.as_mut()?;
+    let report = input.keyboard.feed(byte)?;
+    if let Some(locks) = input.keymap.update(&report.keycodes) {
+        set_leds(locks);
+    }
+    Some((report, input.keymap.locks()))
+}
+
+/// マウスから届いた1バイトを取り込む。パケットが揃ったらUSBのマウスと同じ形のレポートを返す
+pub fn on_mouse_byte(byte: u8) -> Option<MouseReport> {
+    INPUT.lock().as_mut()?.mouse.feed(byte)
+}
+
+/// キーボードのLEDを変える。ACKは割り込みで届き、Set1Decoderが読み捨てる
+fn set_leds(locks: LockState) {
+    let bits = (locks.scroll_lock() as u8) | (locks.num_lock() as u8) << 1 | (locks.caps_lock() as u8) << 2;
+    unsafe {
+        let _ = write_byte(DATA_PORT, KBD_SET_LEDS).and_then(|_| write_byte(DATA_PORT, bits));
+    }
+}
+
+/// 修飾キーのスキャンコードと、レポートの修飾キーのバイトのビット
+fn modifier_bit(extended: bool, code: u8) -> Option<u8> {
+    match (extended, code) {
+        (false, 0x1d) => Some(1 << 0),
+        (false, 0x2a) => Some(1 << 1),
+        (false, 0x38) => Some(1 << 2),
+        (true, 0x5b) => Some(1 << 3),
+        (true, 0x1d) => Some(1 << 4),
+        (false, 0x36) => Some(1 << 5),
+        (true, 0x38) => Some(1 << 6),
+        (true, 0x5c) => Some(1 << 7),
+        _ => None,
+    }
+}
+
+/// セット1のスキャンコード(0x00-0x58)からUSB HIDのUsage ID。0は対応するキーが無いか修飾キー
+#[rustfmt::skip]
+const SET1_TO_USAGE: [u8; 0x59] = [
+    0x00, 0x29, 0x1e, 0x1f, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x2d, 0x2e, 0x2a, 0x2b, // 0x00 Esc 1-0 - = BS Tab
+    0x14, 0x1a, 0x08, 0x15, 0x17, 0x1c, 0x18, 0x0c, 0x12, 0x13, 0x2f, 0x30, 0x28, 0x00, 0x04, 0x16, // 0x10 Q-P [ ] Enter Ctrl A S
+    0x07, 0x09, 0x0a, 0x0b, 0x0d, 0x0e, 0x0f, 0x33, 0x34, 0x35, 0x00, 0x31, 0x1d, 0x1b, 0x06, 0x19, // 0x20 D-L ; ' ` Shift \ Z X C V
+    0x05, 0x11, 0x10, 0x36, 0x37, 0x38, 0x00, 0x55, 0x00, 0x2c, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, // 0x30 B N M , . / Shift KP* Alt Space Caps F1-F5
+    0x3f, 0x40, 0x41, 0x42, 0x43, 0x53, 0x47, 0x5f, 0x60, 0x61, 0x56, 0x5c, 0x5d, 0x5e, 0x57, 0x59, // 0x40 F6-F10 Num Scroll KP7-9 KP- KP4-6 KP+ KP1
+    0x5a, 0x5b, 0x62, 0x63, 0x00, 0x00, 0x64, 0x44, 0x45,                                           // 0x50 KP2 KP3 KP0 KP. - - NonUS\ F11 F12
+];
+
+/// 0xe0の後に続くスキャンコードからUsage ID
+fn extended_usage(code: u8) -> Option<u8> {
+    Some(match code {
+        0x1c => 0x58, // KP Enter
+        0x35 => 0x54, // KP /
+        0x37 => 0x46, // Print Screen
+        0x47 => 0x4a, // Home
+        0x48 => 0x52, // Up
+        0x49 => 0x4b, // Page Up
+        0x4b => 0x50, // Left
+        0x4d => 0x4f, // Right
+        0x4f => 0x4d, // End
+        0x50 => 0x51, // Down
+        0x51 => 0x4e, // Page Down
+        0x52 => 0x49, // Insert
+        0x53 => 0x4c, // Delete
+        0x5d => 0x65, // Menu
+        // 0x2aや0x36はPrint Screenなどに付いてくる偽のShift
+        _ => return None,
+    })
+}
+
+/// スキャンコードセット1のバイト列から、USBのブートキーボードと同じレポートを組み立てる
+#[derive(Debug)]
+struct Set1Decoder {
+    /// 直前に0xe0が来た
+    extended: bool,
+    /// 0xe1(Pause)の後に読み飛ばすバイトの数
+    skip: u8,
+    modifier: u8,
+    keys: [u8; 6],
+}
+
+impl Set1Decoder {
+    const fn new() -> Self {
+        Self { extended: false, skip: 0, modifier: 0, keys: [0; 6] }
+    }
+
+    /// 1バイト取り込み、押されているキーが変わったらレポートを返す。押し続けて繰り返し届くものは変化にならない
+    fn feed(&mut self, byte: u8) -> Option<KeyReport> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            0xe0 => {
+                self.extended = true;
+                return None;
+            }
+            0xe1 => {
+                self.skip = 2;
+                return None;
+            }
+            // コマンドへの応答と、バッファのあふれ
+            ACK | RESEND | 0x00 | 0xff => return None,
+            _ => (),
+        }
+        let extended = core::mem::take(&mut self.extended);
+        let released = byte & 0x80 != 0;
+        let code = byte & 0x7f;
+        let before = (self.modifier, self.keys);
+        if let Some(bit) = modifier_bit(extended, code) {
+            if released {
+                self.modifier &= !bit;
+            } else {
+                self.modifier |= bit;
+            }
+        } else {
+            let usage = if extended {
+                extended_usage(code)?
+            } else {
+                SET1_TO_USAGE.get(code as usize).copied().filter(|u| *u != 0)?
+            };
+            if released {
+                self.keys.iter_mut().filter(|k| **k == usage).for_each(|k| *k = 0);
+            } else if !self.keys.contains(&usage) {
+                // 7つ目からは入らない
+                if let Some(slot) = self.keys.iter_mut().find(|k| **k == 0) {
+                    *slot = usage;
+                }
+            }
+        }
+        ((self.modifier, self.keys) != before)
+            .then(|| KeyReport { modifier: ModifierSet::from_bits(self.modifier), _rsvd: 0, keycodes: self.keys })
+    }
+}
+
+/// マウスのパケットを組み立てる。ホイールがあれば4バイト、無ければ3バイト
+#[derive(Debug)]
+struct PacketDecoder {
+    packet_len: usize,
+    buf: [u8; 4],
+    len: usize,
+}
+
+impl PacketDecoder {
+    const fn new(wheel: bool) -> Self {
+        Self { packet_len: if wheel { 4 } else { 3 }, buf: [0; 4], len: 0 }
+    }
+
+    fn feed(&mut self, byte: u8) -> Option<MouseReport> {
+        // 1バイト目はbit 3が必ず立っている。ずれたら揃うまで捨てる
+        if self.len == 0 && byte & 0x08 == 0 {
+            return None;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_len {
+            return None;
+        }
+        self.len = 0;
+        decode_packet(&self.buf[..self.packet_len])
+    }
+}
+
+/// USBのマウスに合わせ、yは下、ホイールは奥へ回すと正にする。あふれたパケットは捨てる
+fn decode_packet(packet: &[u8]) -> Option<MouseReport> {
+    let flags = packet[0];
+    if flags & 0xc0 != 0 {
+        return None;
+    }
+    // 9bitの符号付き。符号はflagsのbit 4と5にある
+    let dx = packet[1] as i16 - if flags & 0x10 != 0 { 0x100 } else { 0 };
+    let dy = packet[2] as i16 - if flags & 0x20 != 0 { 0x100 } else { 0 };
+    // 4バイト目の下位4bitが符号付きのホイールで、手前に回すと正
+    let wheel = packet.get(3).map_or(0, |z| -(((z << 4) as i8) >> 4));
+    Some(MouseReport::new(flags & 0b111, dx, -dy, wheel, 0))
+}
+
+pub fn run_ps2_tests() {
+    let mut d = Set1Decoder::new();
+    let keys = |r: Option<KeyReport>| r.map(|r| (r.modifier, r.keycodes));
+    let none = ModifierSet::default();
+    // A、Shift+B、Bを離す
+    assert!(keys(d.feed(0x1e)) == Some((none, [0x04, 0, 0, 0, 0, 0])));
+    // 押し続けて繰り返し届くのは変化ではない
+    assert!(d.feed(0x1e).is_none());
+    assert!(keys(d.feed(0x2a)) == Some((ModifierSet::from_bits(0b10), [0x04, 0, 0, 0, 0, 0])));
+    assert!(keys(d.feed(0x30)) == Some((ModifierSet::from_bits(0b10), [0x04, 0x05, 0, 0, 0, 0])));
+    assert!(keys(d.feed(0x9e)) == Some((ModifierSet::from_bits(0b10), [0, 0x05, 0, 0, 0, 0])));
+    assert!(keys(d.feed(0xaa)) == Some((none, [0, 0x05, 0, 0, 0, 0])));
+    assert!(keys(d.feed(0xb0)) == Some((none, [0; 6])));
+    // 0xe0の付くキーと右の修飾キー
+    assert!(d.feed(0xe0).is_none());
+    assert!(keys(d.feed(0x48)) == Some((none, [0x52, 0, 0, 0, 0, 0])));
+    d.feed(0xe0);
+    assert!(keys(d.feed(0x1d)) == Some((ModifierSet::from_bits(1 << 4), [0x52, 0, 0, 0, 0, 0])));
+    // 偽のShiftとACK、Pauseの並びは何も変えない
+    for byte in [0xe0, 0x2a, ACK, 0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5] {
+        assert!(d.feed(byte).is_none());
+    }
+    d.feed(0xe0);
+    d.feed(0xc8);
+    d.feed(0xe0);
+    assert!(keys(d.feed(0x9d)) == Some((none, [0; 6])));
+    assert!(SET1_TO_USAGE[0x1c] == 0x28 && SET1_TO_USAGE[0x39] == 0x2c && SET1_TO_USAGE[0x58] == 0x45);
+
+    // 3バイトのパケット。yは上が正なので反転する
+    let mut m = PacketDecoder::new(false);
+    assert!(m.feed(0x09).is_none() && m.feed(5).is_none());
+    let r = m.feed(3).unwrap();
+    assert!(r.buttons() == 1 && r.dx() == 5 && r.dy() == -3 && r.wheel() == 0);
+    // 負の移動。bit 3の立っていない先頭は捨てて揃える
+    assert!(m.feed(0x00).is_none());
+    m.feed(0x38);
+    m.feed(0xfe);
+    let r = m.feed(0xff).unwrap();
+    assert!(r.buttons() == 0 && r.dx() == -2 && r.dy() == 1);
+    // あふれたパケットは捨てる
+    m.feed(0x48);
+    m.feed(1);
+    assert!(m.feed(1).is_none());
+
+    // 4バイト目のホイールは手前が正なので反転する
+    let mut m = PacketDecoder::new(true);
+    m.feed(0x0c);
+    m.feed(0);
+    m.feed(0);
+    let r = m.feed(0x0f).unwrap();
+    assert!(r.buttons() == 0b100 && r.wheel() == 1);
+}
@@ -0,0 +1,306 @@
+use core::fmt;
+
+use crate::asm::{io_in_8, io_out_8};
+
+const CMOS_ADDR: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// アドレスポートのビット7はNMIの無効化なので立てたままにする
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+/// Status A: 更新中
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Status B: 24時間表記
+const MODE_24H: u8 = 0x02;
+/// Status B: BCDではなく2進数
+const MODE_BINARY: u8 = 0x04;
+/// 12時間表記のときの時のレジスタの午後のビット
+const HOUR_PM: u8 = 0x80;
+
+/// 同じ値が2回続けて読めるまでに試す回数
+const MAX_READ_ATTEMPTS: usize = 16;
+/// 更新中フラグが下りるのを待つ回数。更新は1回あたり2ms程度
+const MAX_UIP_SPINS: usize = 100_000;
+
+/// CMOSのレジスタの読み出し。テストでは決まった値を返す偽物に差し替える
+pub trait Cmos {
+    fn read(&mut self, reg: u8) -> u8;
+}
+
+/// I/Oポート0x70/0x71を使う本物のCMOS
+pub struct PortCmos;
+
+impl Cmos for PortCmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        unsafe {
+            io_out_8(CMOS_ADDR, NMI_DISABLE | reg);
+            io_in_8(CMOS_DATA)
+        }
+    }
+}
+
+/// UTCの日時
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// RTCが読めないときに使う日時
+    pub const FALLBACK: DateTime = DateTime { year: 2000, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+
+    /// 範囲外の値がなければtrue
+    pub fn is_valid(&self) -> bool {
+        (1970..2200).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && self.day >= 1 && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24 && self.minute < 60 && self.second < 60
+    }
+
+    /// 1970-01-01 00:00:00からの秒数
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix(secs: u64) -> Self {
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let rem = secs % 86400;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// "HH:MM:SS"
+    pub fn hms(&self) -> [u8; 8] {
+        let d = |n: u8| [b'0' + n / 10, b'0' + n % 10];
+        let (h, m, s) = (d(self.hour), d(self.minute), d(self.second));
+        [h[0], h[1], b':', m[0], m[1], b':', s[0], s[1]]
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn is_leap(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// 1970-01-01からの日数 (H. Hinnantのdays_from_civil)
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if m <= 2 { 1 } else { 0 }, m, d)
+}
+
+pub fn bcd_to_binary(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0x0f)
+}
+
+/// 変換前のレジスタの値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(cmos: &mut impl Cmos, century_reg: Option<u8>) -> Option<RawTime> {
+    let mut spins = 0;
+    while cmos.read(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        spins += 1;
+        if spins >= MAX_UIP_SPINS {
+            return None;
+        }
+    }
+    Some(RawTime {
+        second: cmos.read(REG_SECOND),
+        minute: cmos.read(REG_MINUTE),
+        hour: cmos.read(REG_HOUR),
+        day: cmos.read(REG_DAY),
+        month: cmos.read(REG_MONTH),
+        year: cmos.read(REG_YEAR),
+        century: century_reg.map_or(0, |r| cmos.read(r)),
+    })
+}
+
+/// RTCを読む。読んでいる途中で更新されることがあるので、同じ値が2回続くまで読み直す。
+/// century_regはFADTのCENTURYレジスタ(0なら無し)。無ければ2000年代とみなす。
+/// 値が読めない・おかしいときはNone
+pub fn read_rtc(cmos: &mut impl Cmos, century_reg: Option<u8>) -> Option<DateTime> {
+    let century_reg = century_reg.filter(|r| *r != 0);
+    let mut prev = read_raw(cmos, century_reg)?;
+    let mut raw = None;
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let cur = read_raw(cmos, century_reg)?;
+        if cur == prev {
+            raw = Some(cur);
+            break;
+        }
+        prev = cur;
+    }
+    let raw = raw?;
+
+    let status_b = cmos.read(REG_STATUS_B);
+    let conv = |v: u8| if status_b & MODE_BINARY != 0 { v } else { bcd_to_binary(v) };
+    let mut hour = conv(raw.hour & !HOUR_PM);
+    if status_b & MODE_24H == 0 {
+        // 12時間表記: 12時は0時、午後は12を足す
+        hour %= 12;
+        if raw.hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = match century_reg {
+        Some(_) => conv(raw.century) as u16,
+        None => 20,
+    };
+
+    let time = DateTime {
+        year: century * 100 + conv(raw.year) as u16,
+        month: conv(raw.month),
+        day: conv(raw.day),
+        hour,
+        minute: conv(raw.minute),
+        second: conv(raw.second),
+    };
+    time.is_valid().then_some(time)
+}
+
+/// 決まった順にレジスタの値を返す偽物のCMOS
+struct ScriptedCmos {
+    /// 読むたびに1つ進む時刻の列。最後まで行ったら最後のものを返し続ける
+    times: [[u8; 7]; 3],
+    /// 最初の何回かは更新中と答える
+    busy: usize,
+    status_b: u8,
+    century_reg: u8,
+    reads: usize,
+}
+
+impl Cmos for ScriptedCmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        let t = &self.times[self.reads.min(self.times.len() - 1)];
+        match reg {
+            REG_STATUS_A if self.busy > 0 => {
+                self.busy -= 1;
+                UPDATE_IN_PROGRESS
+            }
+            REG_STATUS_A => 0,
+            REG_STATUS_B => self.status_b,
+            REG_SECOND => t[0],
+            REG_MINUTE => t[1],
+            REG_HOUR => t[2],
+            REG_DAY => t[3],
+            REG_MONTH => t[4],
+            // 年の後に世紀を読むので、そこで1回分の読み出しが終わる
+            REG_YEAR if self.century_reg == 0 => {
+                self.reads += 1;
+                t[5]
+            }
+            REG_YEAR => t[5],
+            r if r == self.century_reg => {
+                self.reads += 1;
+                t[6]
+            }
+            _ => 0,
+        }
+    }
+}
+
+pub fn run_rtc_tests() {
+    assert!(bcd_to_binary(0x59) == 59 && bcd_to_binary(0x00) == 0 && bcd_to_binary(0x12) == 12);
+
+    // 日時と秒数の変換
+    let t = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    assert!(t.to_unix() == 1_709_251_198);
+    assert!(DateTime::from_unix(t.to_unix()) == t);
+    assert!(DateTime::from_unix(0) == DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+    assert!(DateTime::from_unix(t.to_unix() + 2) == DateTime { year: 2024, month: 3, day: 1, hour: 0, minute: 0, second: 0 });
+    assert!(&t.hms() == b"23:59:58");
+    assert!(!DateTime { month: 2, day: 30, ..t }.is_valid());
+
+    // 1回目の読み出しの途中で秒が進んでも、2回続けて同じ値になったものを使う
+    let mut cmos = ScriptedCmos {
+        times: [[0x58, 0x59, 0x23, 0x31, 0x12, 0x99, 0x20], [0x59, 0x59, 0x23, 0x31, 0x12, 0x99, 0x20], [0x59, 0x59, 0x23, 0x31, 0x12, 0x99, 0x20]],
+        busy: 3,
+        status_b: MODE_24H,
+        century_reg: 0x32,
+        reads: 0,
+    };
+    let t = read_rtc(&mut cmos, Some(0x32)).unwrap();
+    assert!(t == DateTime { year: 2099, month: 12, day: 31, hour: 23, minute: 59, second: 59 });
+    assert!(cmos.busy == 0 && cmos.reads == 3);
+
+    // 2進数・12時間表記・世紀レジスタ無し
+    let pm_12 = [[30, 15, HOUR_PM | 12, 1, 7, 24, 0]; 3];
+    let mut cmos = ScriptedCmos { times: pm_12, busy: 0, status_b: MODE_BINARY, century_reg: 0, reads: 0 };
+    assert!(read_rtc(&mut cmos, None) == Some(DateTime { year: 2024, month: 7, day: 1, hour: 12, minute: 15, second: 30 }));
+    let am_12 = [[30, 15, 12, 1, 7, 24, 0]; 3];
+    let mut cmos = ScriptedCmos { times: am_12, busy: 0, status_b: MODE_BINARY, century_reg: 0, reads: 0 };
+    assert!(read_rtc(&mut cmos, Some(0)).unwrap().hour == 0);
+
+    // おかしな値と、ずっと変わり続ける値は読めない扱い
+    let bogus = [[0x99, 0x99, 0x99, 0x00, 0x13, 0x99, 0x20]; 3];
+    let mut cmos = ScriptedCmos { times: bogus, busy: 0, status_b: MODE_24H, century_reg: 0, reads: 0 };
+    assert!(read_rtc(&mut cmos, None).is_none());
+    struct Ticking(u8);
+    impl Cmos for Ticking {
+        fn read(&mut self, reg: u8) -> u8 {
+            if reg == REG_SECOND {
+                self.0 = (self.0 + 1) % 60;
+            }
+            if reg == REG_STATUS_A { 0 } else { self.0 }
+        }
+    }
+    assert!(read_rtc(&mut Ticking(0), None).is_none());
+}
@@ -1,11 +1,24 @@
-use core::{arch::global_asm, mem::size_of_val};
+use core::{arch::{asm, global_asm}, mem::{size_of, size_of_val}};
 
 use bitfield::bitfield;
+use x86_64::{structures::tss::TaskStateSegment, VirtAddr};
 
 use crate::interrupt::DescriptorType;
 
 pub const KERNEL_CS: u16 = 1 << 3;
 pub const KERNEL_SS: u16 = 2 << 3;
+/// TSSディスクリプタは16バイトあり、GDTの3,4番を使う
+pub const KERNEL_TSS: u16 = 3 << 3;
+
+/// #DFで使うIST番号(1始まり)
+pub const DOUBLE_FAULT_IST: u8 = 1;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 4;
+
+#[repr(C, align(16))]
+struct ExceptionStack([u8; DOUBLE_FAULT_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: ExceptionStack = ExceptionStack([0; DOUBLE_FAULT_STACK_SIZE]);
+static mut TASK_STATE_SEGMENT: TaskStateSegment = TaskStateSegment::new();
 
 bitfield! {
     pub struct SegmentDescriptor(u64);
@@ -60,19 +73,41 @@ fn set_data_segment(type_: DescriptorType, dpl: u16, base: u32, limit: u32) -> S
     desc
 }
 
-static mut GLOBAL_DESCRIPTOR_TABLE: [SegmentDescriptor; 3] = [SegmentDescriptor(0), SegmentDescriptor(0), SegmentDescriptor(0)];
+/// 64bitのTSSディスクリプタ。上位32bitのベースアドレスが次のエントリにはみ出す
+fn set_tss_segment(base: u64, limit: u32) -> [SegmentDescriptor; 2] {
+    let mut desc = SegmentDescriptor(0);
+    desc.set_base(base as u32);
+    desc.set_limit_low(limit as u16);
+    desc.set_limit_high((limit >> 16) as u16 & 0xf);
+    desc.set_type_(DescriptorType::TSSAvailable as u16);
+    desc.set_system_segment(false);
+    desc.set_descriptor_privilege_level(0);
+    desc.set_present(true);
+    [desc, SegmentDescriptor(base >> 32)]
+}
+
+static mut GLOBAL_DESCRIPTOR_TABLE: [SegmentDescriptor; 5] = [SegmentDescriptor(0), SegmentDescriptor(0), SegmentDescriptor(0), SegmentDescriptor(0), SegmentDescriptor(0)];
 pub fn setup_segments() {
     unsafe {
         GLOBAL_DESCRIPTOR_TABLE[0] = SegmentDescriptor(0);
         GLOBAL_DESCRIPTOR_TABLE[1] = set_code_segment(DescriptorType::ExecuteRead, 0, 0, 0xfffff);
         GLOBAL_DESCRIPTOR_TABLE[2] = set_data_segment(DescriptorType::LDTOrReadWrite, 0, 0, 0xfffff);
+
+        // スタックは下に伸びるので末尾を渡す
+        let stack_end = &DOUBLE_FAULT_STACK as *const _ as u64 + DOUBLE_FAULT_STACK_SIZE as u64;
+        TASK_STATE_SEGMENT.interrupt_stack_table[(DOUBLE_FAULT_IST - 1) as usize] = VirtAddr::new(stack_end);
+        let tss = set_tss_segment(&TASK_STATE_SEGMENT as *const _ as u64, size_of::<TaskStateSegment>() as u32 - 1);
+        let [low, high] = tss;
+        GLOBAL_DESCRIPTOR_TABLE[3] = low;
+        GLOBAL_DESCRIPTOR_TABLE[4] = high;
+
         load_gdt();
         set_ds_es_fs_gs(0);
         set_cs_ss(
             KERNEL_CS, // GLOBAL_DESCRIPTOR_TABLE[1]
             KERNEL_SS   // GLOBAL_DESCRIPTOR_TABLE[2]
         );
-
+        asm!("ltr {0:x}", in(reg) KERNEL_TSS);
     }
 }
 
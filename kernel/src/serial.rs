@@ -0,0 +1,120 @@
+use core::{fmt, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::asm::{io_in_8, io_out_8};
+
+/// 16550互換のUARTのI/Oポート
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialPort(u16);
+
+pub const COM1: SerialPort = SerialPort(0x3f8);
+pub const COM2: SerialPort = SerialPort(0x2f8);
+
+/// LSR: 受信データあり
+const LSR_DATA_READY: u8 = 0x01;
+/// LSR: 送信バッファが空
+const LSR_THR_EMPTY: u8 = 0x20;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+/// println!の出力を、画面に出るときもシリアルに写す
+static MIRROR: AtomicBool = AtomicBool::new(false);
+
+impl SerialPort {
+    /// 115200bps, 8N1で初期化する
+    pub fn init(&self) {
+        let port = self.0;
+        unsafe {
+            io_out_8(port + 1, 0x00); // 割り込みは使わない
+            io_out_8(port + 3, 0x80); // DLAB
+            io_out_8(port, 0x01);     // 分周比 1 (115200bps)
+            io_out_8(port + 1, 0x00);
+            io_out_8(port + 3, 0x03); // 8N1
+            io_out_8(port + 2, 0xc7); // FIFO有効
+            io_out_8(port + 4, 0x03); // DTR, RTS
+        }
+    }
+
+    /// スクラッチレジスタに書いた値が読み返せればUARTがある
+    /// 無いポートはLSRが0xffになり、常に受信データがあるように見えてしまう
+    pub fn exists(&self) -> bool {
+        unsafe {
+            io_out_8(self.0 + 7, 0x5a);
+            io_in_8(self.0 + 7) == 0x5a
+        }
+    }
+
+    /// ロックもメモリ割り当ても行わない
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        for b in bytes {
+            unsafe {
+                // 送信バッファが空くのを待つ
+                while io_in_8(self.0 + 5) & LSR_THR_EMPTY == 0 {}
+                io_out_8(self.0, *b);
+            }
+        }
+    }
+
+    pub fn has_data(&self) -> bool {
+        unsafe { io_in_8(self.0 + 5) & LSR_DATA_READY != 0 }
+    }
+
+    /// 受信したバイトがあれば1つ取り出す
+    pub fn read_byte(&self) -> Option<u8> {
+        self.has_data().then(|| unsafe { io_in_8(self.0) })
+    }
+}
+
+/// COM1を115200bps, 8N1で初期化する
+pub fn init_serial() {
+    COM1.init();
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+/// COM1にバイト列を書き込む。ロックもメモリ割り当ても行わない
+pub fn write_bytes(bytes: &[u8]) {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+    COM1.write_bytes(bytes);
+}
+
+pub fn write_byte(b: u8) {
+    write_bytes(&[b]);
+}
+
+pub fn set_mirror(on: bool) {
+    MIRROR.store(on, Ordering::Relaxed);
+}
+
+pub fn mirror() -> bool {
+    MIRROR.load(Ordering::Relaxed)
+}
+
+/// COM1に書くfmt::Write。端末で行頭に戻るよう、改行は\r\nにする
+pub struct SerialWriter;
+
+impl SerialWriter {
+    pub fn write_bytes(bytes: &[u8]) {
+        for (i, part) in bytes.split(|b| *b == b'\n').enumerate() {
+            if i > 0 {
+                write_bytes(b"\r\n");
+            }
+            write_bytes(part);
+        }
+    }
+}
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Self::write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// コンソールを通さずにCOM1へ出すprintln。コンソールの初期化前や、コンソールが信用できないときに使う
+#[macro_export]
+macro_rules! println_serial {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        let _ = writeln!($crate::serial::SerialWriter, $($arg)*);
+    }};
+}
@@ -0,0 +1,148 @@
+use alloc::{string::String, vec::Vec};
+
+use crate::{command, memory_manager::Mutex, serial::SerialPort};
+
+/// 1行の最大長。超えた分は捨てる
+const LINE_MAX: usize = 128;
+/// 緑の"> "
+const PROMPT: &[u8] = b"\x1b[32m>\x1b[0m ";
+
+/// 受け取ったバイト列から1行を組み立てる。エコーバックとバックスペースを扱う
+pub struct LineEditor {
+    buf: [u8; LINE_MAX],
+    len: usize,
+    /// 直前がCR。CRLFを1つの改行として扱うのに使う
+    prev_cr: bool,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self { buf: [0; LINE_MAX], len: 0, prev_cr: false }
+    }
+
+    /// 1バイトを処理し、端末に返すべきバイトをechoに書く。行が完成したらその内容を返す
+    pub fn feed(&mut self, b: u8, echo: &mut dyn FnMut(&[u8])) -> Option<String> {
+        let prev_cr = core::mem::replace(&mut self.prev_cr, b == b'\r');
+        match b {
+            b'\n' if prev_cr => None,
+            b'\r' | b'\n' => {
+                echo(b"\r\n");
+                let line = String::from_utf8_lossy(&self.buf[..self.len]).into_owned();
+                self.len = 0;
+                Some(line)
+            }
+            // BS, DEL
+            0x08 | 0x7f => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    echo(b"\x08 \x08");
+                }
+                None
+            }
+            // Ctrl-U: 行を消す
+            0x15 => {
+                for _ in 0..self.len {
+                    echo(b"\x08 \x08");
+                }
+                self.len = 0;
+                None
+            }
+            0x20..=0x7e if self.len < LINE_MAX => {
+                self.buf[self.len] = b;
+                self.len += 1;
+                echo(&[b]);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// シリアルポートに結びついたコンソール。受け取った行をコマンドとして実行し、結果を同じポートに返す
+pub struct SerialConsole {
+    port: SerialPort,
+    editor: LineEditor,
+}
+
+struct PortWriter(SerialPort);
+
+impl core::fmt::Write for PortWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // 端末ではLFだけだと行頭に戻らない
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_bytes(b"\r\n");
+            }
+            self.0.write_bytes(part.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+static CONSOLES: Mutex<Vec<SerialConsole>> = Mutex::new(Vec::new());
+
+/// portでコマンドを受け付ける。portは初期化済みであること
+pub fn attach(port: SerialPort) {
+    port.write_bytes(PROMPT);
+    CONSOLES.lock().push(SerialConsole { port, editor: LineEditor::new() });
+}
+
+/// 未処理の入力があるか。メインループが休眠してよいかの判断に使う
+pub fn pending() -> bool {
+    CONSOLES.lock().iter().any(|c| c.port.has_data())
+}
+
+/// 受信したバイトを処理し、完成した行を実行する。メインループから呼ぶ
+pub fn poll() {
+    let mut lines = Vec::new();
+    for console in CONSOLES.lock().iter_mut() {
+        let port = console.port;
+        while let Some(b) = port.read_byte() {
+            if let Some(line) = console.editor.feed(b, &mut |bytes| port.write_bytes(bytes)) {
+                lines.push((port, line));
+            }
+        }
+    }
+    // コマンドはコンソールのロックを離してから実行する
+    for (port, line) in lines {
+        command::execute(&line, &mut PortWriter(port));
+        port.write_bytes(PROMPT);
+    }
+}
+
+pub fn run_serial_console_tests() {
+    let mut editor = LineEditor::new();
+    let mut echoed = Vec::new();
+    let feed = |editor: &mut LineEditor, bytes: &[u8], echoed: &mut Vec<u8>| {
+        let mut lines = Vec::new();
+        for b in bytes {
+            if let Some(line) = editor.feed(*b, &mut |e| echoed.extend_from_slice(e)) {
+                lines.push(line);
+            }
+        }
+        lines
+    };
+
+    // バックスペースは1文字消し、CRLFは1つの改行になる
+    let lines = feed(&mut editor, b"ecx\x7fho hi\r\n", &mut echoed);
+    assert!(lines == ["echo hi"]);
+    assert!(echoed == b"ecx\x08 \x08ho hi\r\n");
+
+    // 空の行でのバックスペースは何もしない。LFだけでも行になり、制御文字は無視する
+    echoed.clear();
+    let lines = feed(&mut editor, b"\x08a\x1bb\x15ls\n\n", &mut echoed);
+    assert!(lines == ["ls", ""]);
+    assert!(echoed == b"ab\x08 \x08\x08 \x08ls\r\n\r\n");
+
+    // 長すぎる行は切り詰める
+    let long = [b'x'; LINE_MAX + 10];
+    assert!(feed(&mut editor, &long, &mut echoed).is_empty());
+    assert!(feed(&mut editor, b"\r", &mut echoed)[0].len() == LINE_MAX);
+
+    // コマンドの実行結果
+    let mut out = String::new();
+    command::execute("echo  hello world", &mut out);
+    command::execute("nope", &mut out);
+    command::execute("   ", &mut out);
+    assert!(out == "hello world\nnope: command not found\n");
+}
@@ -0,0 +1,269 @@
+use core::fmt::{self, Write};
+
+use alloc::vec::Vec;
+
+use crate::{console::StackWriter, deferred, introspect, graphic::{focus, window::LayerId}, memory_manager::Mutex, usb::{KeyReport, ModifierSet}, warn};
+
+/// 修飾キーの組。左右は区別しない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mods(u8);
+
+impl Mods {
+    pub const NONE: Mods = Mods(0);
+    pub const CTRL: Mods = Mods(1 << 0);
+    pub const SHIFT: Mods = Mods(1 << 1);
+    pub const ALT: Mods = Mods(1 << 2);
+    pub const GUI: Mods = Mods(1 << 3);
+
+    pub const fn with(self, other: Mods) -> Mods {
+        Mods(self.0 | other.0)
+    }
+
+    pub fn from_modifier_set(m: ModifierSet) -> Self {
+        let bit = |on: bool, mods: Mods| if on { mods.0 } else { 0 };
+        Mods(bit(m.ctrl(), Self::CTRL) | bit(m.is_shifted(), Self::SHIFT) | bit(m.alt(), Self::ALT) | bit(m.gui(), Self::GUI))
+    }
+}
+
+impl fmt::Display for Mods {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (mods, name) in [(Self::CTRL, "Ctrl+"), (Self::SHIFT, "Shift+"), (Self::ALT, "Alt+"), (Self::GUI, "Gui+")] {
+            if self.0 & mods.0 != 0 {
+                f.write_str(name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// キーの名前。知らないキーはUsage IDのまま
+pub struct KeyName(pub u8);
+
+impl fmt::Display for KeyName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0x04..=0x1d => write!(f, "{}", (b'A' + self.0 - 0x04) as char),
+            0x2b => f.write_str("Tab"),
+            0x3a..=0x45 => write!(f, "F{}", self.0 - 0x3a + 1),
+            0x4b => f.write_str("PageUp"),
+            0x4c => f.write_str("Delete"),
+            0x4e => f.write_str("PageDown"),
+            0x4f => f.write_str("Right"),
+            0x50 => f.write_str("Left"),
+            0x51 => f.write_str("Down"),
+            0x52 => f.write_str("Up"),
+            usage => write!(f, "{usage:#04x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Global,
+    /// そのウィンドウにフォーカスがあるときだけ
+    Window(LayerId),
+}
+
+/// 登録された1つのショートカット。押されたらdeferredでhandler(arg)を呼ぶ
+#[derive(Debug, Clone, Copy)]
+pub struct Shortcut {
+    pub mods: Mods,
+    pub usage: u8,
+    pub scope: Scope,
+    /// 登録した機能の名前。shortcutsコマンドで表示する
+    pub owner: &'static str,
+    pub handler: fn(usize),
+    pub arg: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShortcutError {
+    /// 同じ範囲に同じキーが既にある
+    Duplicate { owner: &'static str },
+}
+
+impl fmt::Display for ShortcutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShortcutError::Duplicate { owner } => write!(f, "already registered by {owner}"),
+        }
+    }
+}
+
+/// ショートカットの表と、キーの押し下げの検出
+/// グローバルなものがウィンドウのものより優先する
+pub struct ShortcutTable {
+    entries: Vec<Shortcut>,
+    prev_keys: [u8; 6],
+    /// ショートカットとして消費したキー。離されるまで通常の配送から隠す
+    consumed: [u8; 6],
+}
+
+impl ShortcutTable {
+    pub const fn new() -> Self {
+        Self { entries: Vec::new(), prev_keys: [0; 6], consumed: [0; 6] }
+    }
+
+    /// 登録する。グローバルなものとウィンドウのものが同じキーを使うなら、隠される(隠す)側のownerを返す
+    pub fn register(&mut self, shortcut: Shortcut) -> Result<Option<&'static str>, ShortcutError> {
+        let same_key = |s: &&Shortcut| s.mods == shortcut.mods && s.usage == shortcut.usage;
+        if let Some(dup) = self.entries.iter().filter(same_key).find(|s| s.scope == shortcut.scope) {
+            return Err(ShortcutError::Duplicate { owner: dup.owner });
+        }
+        let shadowed = self.entries.iter().filter(same_key)
+            .find(|s| s.scope == Scope::Global || shortcut.scope == Scope::Global)
+            .map(|s| s.owner);
+        self.entries.push(shortcut);
+        Ok(shadowed)
+    }
+
+    /// ウィンドウを閉じたら、そのウィンドウのショートカットを消す
+    pub fn unregister_window(&mut self, layer_id: LayerId) {
+        self.entries.retain(|s| s.scope != Scope::Window(layer_id));
+    }
+
+    pub fn entries(&self) -> &[Shortcut] {
+        &self.entries
+    }
+
+    /// focusedにフォーカスがあるときにmods+usageで起動するもの
+    pub fn lookup(&self, mods: Mods, usage: u8, focused: Option<LayerId>) -> Option<&Shortcut> {
+        let matches = |s: &&Shortcut| s.mods == mods && s.usage == usage;
+        self.entries.iter().filter(matches).find(|s| s.scope == Scope::Global)
+            .or_else(|| self.entries.iter().filter(matches).find(|s| focused.is_some_and(|id| s.scope == Scope::Window(id))))
+    }
+
+    /// キーボードのレポートを受け取り、新たに押されたキーのうちショートカットになるものを消費する
+    /// 起動するものをfiredに積み、通常の配送に回すキーを返す。消費したキーの位置は0になる
+    pub fn filter(&mut self, mods: Mods, keycodes: &[u8; 6], focused: Option<LayerId>, fired: &mut Vec<Shortcut>) -> [u8; 6] {
+        // 離されたキーはもう隠さない
+        for k in self.consumed.iter_mut() {
+            if !keycodes.contains(k) {
+                *k = 0;
+            }
+        }
+        let mut passed = *keycodes;
+        for k in passed.iter_mut().filter(|k| **k != 0) {
+            let pressed = !self.prev_keys.contains(k);
+            if pressed {
+                if let Some(s) = self.lookup(mods, *k, focused) {
+                    fired.push(*s);
+                    if let Some(slot) = self.consumed.iter_mut().find(|c| **c == 0) {
+                        *slot = *k;
+                    }
+                }
+            }
+            if self.consumed.contains(k) {
+                *k = 0;
+            }
+        }
+        self.prev_keys = *keycodes;
+        passed
+    }
+}
+
+static TABLE: Mutex<ShortcutTable> = Mutex::new(ShortcutTable::new());
+
+fn register(shortcut: Shortcut) -> Result<(), ShortcutError> {
+    let result = TABLE.lock().register(shortcut);
+    match result {
+        Ok(Some(other)) => {
+            warn!("shortcut: {}{} of {} overlaps {}; the global one wins", shortcut.mods, KeyName(shortcut.usage), shortcut.owner, other);
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn register_global(mods: Mods, usage: u8, owner: &'static str, handler: fn(usize), arg: usize) -> Result<(), ShortcutError> {
+    register(Shortcut { mods, usage, scope: Scope::Global, owner, handler, arg })
+}
+
+pub fn register_window(layer_id: LayerId, mods: Mods, usage: u8, owner: &'static str, handler: fn(usize), arg: usize) -> Result<(), ShortcutError> {
+    register(Shortcut { mods, usage, scope: Scope::Window(layer_id), owner, handler, arg })
+}
+
+pub fn unregister_window(layer_id: LayerId) {
+    TABLE.lock().unregister_window(layer_id);
+}
+
+pub fn shortcuts() -> Vec<Shortcut> {
+    TABLE.lock().entries().to_vec()
+}
+
+/// input/shortcutsを登録する
+pub fn register_nodes() {
+    introspect::register("input/shortcuts", |_, out| {
+        for s in shortcuts() {
+            let mut key = StackWriter::new();
+            let _ = write!(key, "{}{}", s.mods, KeyName(s.usage));
+            let key = core::str::from_utf8(key.as_bytes()).unwrap_or("?");
+            match s.scope {
+                Scope::Global => writeln!(out, "{key:<16} global     {}", s.owner)?,
+                Scope::Window(id) => writeln!(out, "{key:<16} window {id:<3} {}", s.owner)?,
+            }
+        }
+        Ok(())
+    }, 0)
+    .expect("shortcut: input/shortcuts");
+}
+
+/// 入力の振り分けでキーボードのレポートを最初に通す所
+/// 一致したショートカットはメインループで後から呼び、そのキーを除いたものを返す
+pub fn dispatch(report: &KeyReport) -> [u8; 6] {
+    let mut fired = Vec::new();
+    let passed = TABLE.lock().filter(Mods::from_modifier_set(report.modifier), &report.keycodes, focus::focused(), &mut fired);
+    for s in fired {
+        if deferred::defer(s.handler, s.arg).is_err() {
+            warn!("shortcut: deferred queue is full, {}{} dropped", s.mods, KeyName(s.usage));
+        }
+    }
+    passed
+}
+
+pub fn run_shortcut_tests() {
+    fn nop(_: usize) {}
+    let s = |mods, usage, scope, arg| Shortcut { mods, usage, scope, owner: "test", handler: nop, arg };
+    let mut t = ShortcutTable::new();
+
+    // 完全に同じものはエラー、グローバルとウィンドウの重なりは警告
+    assert!(t.register(s(Mods::NONE, 0x42, Scope::Global, 1)) == Ok(None));
+    assert!(t.register(s(Mods::NONE, 0x42, Scope::Global, 2)) == Err(ShortcutError::Duplicate { owner: "test" }));
+    assert!(t.register(s(Mods::SHIFT, 0x42, Scope::Global, 3)) == Ok(None));
+    assert!(t.register(s(Mods::NONE, 0x42, Scope::Window(7), 4)) == Ok(Some("test")));
+    assert!(t.register(s(Mods::NONE, 0x14, Scope::Window(7), 5)) == Ok(None));
+    assert!(t.register(s(Mods::NONE, 0x14, Scope::Window(8), 6)) == Ok(None));
+    assert!(t.register(s(Mods::NONE, 0x14, Scope::Window(8), 7)).is_err());
+
+    // ウィンドウのものはフォーカスがあるときだけ。グローバルが優先する
+    assert!(t.lookup(Mods::NONE, 0x14, Some(7)).is_some_and(|s| s.arg == 5));
+    assert!(t.lookup(Mods::NONE, 0x14, Some(8)).is_some_and(|s| s.arg == 6));
+    assert!(t.lookup(Mods::NONE, 0x14, Some(9)).is_none());
+    assert!(t.lookup(Mods::NONE, 0x14, None).is_none());
+    assert!(t.lookup(Mods::NONE, 0x42, Some(7)).is_some_and(|s| s.arg == 1));
+    assert!(t.lookup(Mods::ALT, 0x42, None).is_none());
+
+    // 一致したキーは消費され、押している間は隠れ続ける。それ以外はそのまま通る
+    let mut fired = Vec::new();
+    let passed = t.filter(Mods::NONE, &[0x14, 0x04, 0, 0, 0, 0], Some(7), &mut fired);
+    assert!(passed == [0, 0x04, 0, 0, 0, 0]);
+    assert!(fired.len() == 1 && fired[0].arg == 5);
+    fired.clear();
+    let passed = t.filter(Mods::NONE, &[0x14, 0x04, 0x42, 0, 0, 0], Some(7), &mut fired);
+    assert!(passed == [0, 0x04, 0, 0, 0, 0]);
+    assert!(fired.len() == 1 && fired[0].arg == 1);
+    fired.clear();
+    // 押しっぱなしでは起動しない。離せば同じキーがまた通る
+    let passed = t.filter(Mods::NONE, &[0x42, 0, 0, 0, 0, 0], Some(7), &mut fired);
+    assert!(passed == [0; 6] && fired.is_empty());
+    let passed = t.filter(Mods::NONE, &[0, 0, 0, 0, 0, 0], Some(7), &mut fired);
+    assert!(passed == [0; 6]);
+    // フォーカスが無ければウィンドウのショートカットは通常のキーとして通る
+    let passed = t.filter(Mods::NONE, &[0x14, 0, 0, 0, 0, 0], Some(9), &mut fired);
+    assert!(passed == [0x14, 0, 0, 0, 0, 0] && fired.is_empty());
+
+    t.unregister_window(7);
+    assert!(t.lookup(Mods::NONE, 0x14, Some(7)).is_none());
+    assert!(t.entries().len() == 3);
+}
@@ -0,0 +1,220 @@
+use core::{arch::asm, fmt::{self, Write}};
+
+use crate::{addr::PhysAddr, console::StackWriter, memory_manager::Mutex, paging};
+
+/// ブートローダが渡すカーネルの.symtabと.strtabのコピー。無ければsymtabはnull
+/// バッファはLOADER_DATAに置かれ、カーネルは再利用しないのでそのまま読み書きできる
+#[repr(C)]
+pub struct KernelSymbolsRaw {
+    pub symtab: *mut u8,
+    pub symtab_len: usize,
+    pub strtab: *const u8,
+    pub strtab_len: usize,
+    /// カーネルを置いたアドレスとリンク時のアドレスの差。位置独立でなければ0
+    pub load_bias: u64,
+}
+
+/// .symtabの1エントリ(Elf64_Sym)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64Sym {
+    pub st_name: u32,
+    pub st_info: u8,
+    pub st_other: u8,
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+const STT_FUNC: u8 = 2;
+
+/// バックトレースで辿る最大のフレーム数
+pub const MAX_FRAMES: usize = 16;
+
+impl Elf64Sym {
+    fn is_func(&self) -> bool {
+        self.st_info & 0xf == STT_FUNC && self.st_value != 0
+    }
+}
+
+/// アドレスの昇順に並んだ関数シンボル
+pub struct SymbolTable<'a> {
+    syms: &'a [Elf64Sym],
+    strtab: &'a [u8],
+    /// 実行時のアドレスからこれを引くとst_valueと比べられる
+    bias: u64,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// 関数シンボルをアドレス順に前に詰めて並べ替え、その部分だけを使う。メモリ割り当ては行わない
+    pub fn from_unsorted(syms: &'a mut [Elf64Sym], strtab: &'a [u8]) -> Self {
+        syms.sort_unstable_by_key(|s| (!s.is_func(), s.st_value));
+        let n = syms.iter().take_while(|s| s.is_func()).count();
+        Self { syms: &syms[..n], strtab, bias: 0 }
+    }
+
+    /// 位置独立なカーネルを別の場所に置いたときのずれ
+    pub fn with_bias(self, bias: u64) -> Self {
+        Self { bias, ..self }
+    }
+
+    pub fn len(&self) -> usize {
+        self.syms.len()
+    }
+
+    fn name(&self, sym: &Elf64Sym) -> &'a str {
+        let tail = self.strtab.get(sym.st_name as usize..).unwrap_or(&[]);
+        let end = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+        core::str::from_utf8(&tail[..end]).unwrap_or("?")
+    }
+
+    /// addrを含む関数の名前と、その先頭からのオフセット
+    /// 最初の関数より前、または最後の関数の終わりより後ならNone
+    pub fn resolve(&self, addr: u64) -> Option<(&'a str, u64)> {
+        let addr = addr.checked_sub(self.bias)?;
+        let i = self.syms.partition_point(|s| s.st_value <= addr).checked_sub(1)?;
+        let sym = &self.syms[i];
+        let offset = addr - sym.st_value;
+        // 大きさの分かっている最後の関数を越えたら、カーネルの外とみなす
+        if i == self.syms.len() - 1 && sym.st_size != 0 && offset >= sym.st_size {
+            return None;
+        }
+        Some((self.name(sym), offset))
+    }
+}
+
+static SYMBOLS: Mutex<Option<SymbolTable<'static>>> = Mutex::new(None);
+
+/// ブートローダからシンボルを受け取って並べ替える。メモリ割り当ては行わないので、起動直後に呼んでよい
+pub unsafe fn load(raw: *const KernelSymbolsRaw) {
+    if raw.is_null() || (*raw).symtab.is_null() {
+        return;
+    }
+    let raw = &*raw;
+    let syms = core::slice::from_raw_parts_mut(raw.symtab as *mut Elf64Sym, raw.symtab_len / core::mem::size_of::<Elf64Sym>());
+    let strtab = core::slice::from_raw_parts(raw.strtab, raw.strtab_len);
+    *SYMBOLS.lock() = Some(SymbolTable::from_unsorted(syms, strtab).with_bias(raw.load_bias));
+}
+
+/// "name+0x1c"の形で書く。シンボルが無ければアドレスだけ
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // panic中にロックを持ったままかもしれないので待たない
+        let resolved = SYMBOLS.try_lock().and_then(|s| s.as_ref().and_then(|t| t.resolve(self.0)));
+        match resolved {
+            Some((name, offset)) => write!(f, "{:#x} {}+{:#x}", self.0, Demangled(name), offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// Rustのlegacyマングリング(_ZN3foo3bar17h0123456789abcdefE)をfoo::barにする。それ以外はそのまま
+struct Demangled<'a>(&'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN").filter(|s| s.ends_with('E')) else {
+            return f.write_str(self.0);
+        };
+        let mut first = true;
+        while let Some(len_end) = rest.find(|c: char| !c.is_ascii_digit()).filter(|i| *i > 0) {
+            let Some(len) = rest[..len_end].parse::<usize>().ok().filter(|len| len_end + len <= rest.len()) else {
+                break;
+            };
+            let part = &rest[len_end..len_end + len];
+            rest = &rest[len_end + len..];
+            // 末尾のハッシュは読みにくいだけなので落とす
+            if rest == "E" && part.len() == 17 && part.starts_with('h') && part[1..].bytes().all(|b| b.is_ascii_hexdigit()) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            f.write_str(part)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// フレームポインタを辿って、呼び出し元への戻りアドレスを新しいものから順にfに渡す
+/// カーネルはforce-frame-pointersでビルドしているので、rbpが前のフレームのrbpを指している
+pub fn walk_frames(mut f: impl FnMut(u64)) {
+    let mut rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp);
+    }
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 || !paging::is_mapped(PhysAddr::new(rbp), 16) {
+            break;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        // シンボルがあるのにカーネルの外へ戻るなら、ブートローダのフレームまで来た
+        let outside = SYMBOLS.try_lock().is_some_and(|s| s.as_ref().is_some_and(|t| t.resolve(ret).is_none()));
+        if outside {
+            break;
+        }
+        f(ret);
+        // スタックは下に伸びるので、呼び出し元のフレームは必ず上にある
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// バックトレースを1フレーム1行でoutに書く。ヒープは使わない
+pub fn write_backtrace(out: &mut impl Write) {
+    let _ = writeln!(out, "backtrace:");
+    let mut depth = 0;
+    walk_frames(|ret| {
+        let _ = writeln!(out, "  #{depth:<2} {}", Symbolized(ret));
+        depth += 1;
+    });
+}
+
+pub fn run_symbols_tests() {
+    let sym = |st_name, st_value, st_size, st_info| Elf64Sym { st_name, st_info, st_other: 0, st_shndx: 1, st_value, st_size };
+    let strtab = b"\0main\0_ZN6kernel5usb3foo17h0123456789abcdefE\0data\0last\0";
+    let mut syms = [
+        sym(50, 0x3000, 0x40, STT_FUNC),
+        sym(45, 0x2800, 0x10, 1),
+        sym(1, 0x1000, 0x100, STT_FUNC),
+        sym(6, 0x2000, 0, STT_FUNC),
+        sym(0, 0, 0, 0),
+    ];
+    let table = SymbolTable::from_unsorted(&mut syms, strtab);
+    assert!(table.len() == 3);
+
+    // 最初の関数より前
+    assert!(table.resolve(0xfff).is_none());
+    // ちょうど先頭
+    assert!(table.resolve(0x1000) == Some(("main", 0)));
+    // 関数の途中。大きさ0の関数は次の関数まで続くとみなす
+    assert!(table.resolve(0x10ff) == Some(("main", 0xff)));
+    assert!(table.resolve(0x2fff).is_some_and(|(_, off)| off == 0xfff));
+    // 最後の関数の中と、その後
+    assert!(table.resolve(0x303f) == Some(("last", 0x3f)));
+    assert!(table.resolve(0x3040).is_none());
+
+    let mut w = StackWriter::new();
+    let _ = write!(w, "{}", Demangled(table.resolve(0x2008).unwrap().0));
+    assert!(w.as_bytes() == b"kernel::usb::foo");
+    let mut w = StackWriter::new();
+    let _ = write!(w, "{}", Demangled("KernelMain"));
+    assert!(w.as_bytes() == b"KernelMain");
+
+    // 別の場所に置かれたカーネルでは、ずれを引いてから引く
+    let table = table.with_bias(0x10_0000);
+    assert!(table.resolve(0x10_1000) == Some(("main", 0)));
+    assert!(table.resolve(0x1000).is_none());
+
+    // 空の表では何も引けない
+    let table = SymbolTable::from_unsorted(&mut [], b"");
+    assert!(table.resolve(0x1000).is_none());
+}
@@ -1,11 +1,80 @@
-use core::arch::global_asm;
+use core::{arch::global_asm, sync::atomic::{AtomicUsize, Ordering}};
 
-use alloc::collections::VecDeque;
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{asm::get_cr3, segment::{KERNEL_CS, KERNEL_SS}, timer::{add_timer_deferred, add_timer_task, get_current_tick, TIMER_FREQ}};
 
 static mut TASKS: Option<TaskManager> = None;
 
+/// タスクごとに持つ値の枠の数
+pub const N_LOCALS: usize = 8;
+
+/// タスクローカルの枠の番号。task_local_keys!でだけ作る
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalKey(usize);
+
+/// 枠の番号を宣言順に0から割り当てる。枠が足りなければコンパイルエラーになる
+macro_rules! task_local_keys {
+    ($($(#[$m:meta])* $name:ident),* $(,)?) => {
+        task_local_keys!(@assign 0usize, $($(#[$m])* $name,)*);
+        const _: () = assert!(N_LOCAL_KEYS <= N_LOCALS, "too many task local keys");
+    };
+    (@assign $n:expr, $(#[$m:meta])* $name:ident, $($rest:tt)*) => {
+        $(#[$m])*
+        pub const $name: LocalKey = LocalKey($n);
+        task_local_keys!(@assign $n + 1, $($rest)*);
+    };
+    (@assign $n:expr,) => {
+        const N_LOCAL_KEYS: usize = $n;
+    };
+}
+
+task_local_keys! {
+    /// heap_profileの今のタグ
+    ALLOC_TAG,
+    /// run_task_local_testsで使う
+    SELFTEST,
+}
+
+/// 実行中のタスクの値。切り替えのときにTaskContext::localsと入れ替える
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+static CURRENT_LOCALS: [AtomicUsize; N_LOCALS] = [ZERO; N_LOCALS];
+
+/// 実行中のタスクのkeyの枠に書く
+pub fn local_set(key: LocalKey, value: usize) {
+    CURRENT_LOCALS[key.0].store(value, Ordering::Relaxed);
+}
+
+/// 実行中のタスクのkeyの枠を読む。作られてから一度も書いていなければ0
+pub fn local_get(key: LocalKey) -> usize {
+    CURRENT_LOCALS[key.0].load(Ordering::Relaxed)
+}
+
+/// 実行中のタスクのkeyの枠を書き換え、前の値を返す
+pub fn local_replace(key: LocalKey, value: usize) -> usize {
+    CURRENT_LOCALS[key.0].swap(value, Ordering::Relaxed)
+}
+
+/// spawnが返すタスクの番号。メインタスクは0
+pub type TaskId = usize;
+
+/// タスクごとのスタックの大きさ(u64の個数)
+const STACK_WORDS: usize = 1024;
+
+struct Task {
+    /// switch_contextに渡している間も動かないようにBoxに入れる
+    ctx: Box<TaskContext>,
+    /// 作ったタスクのスタック。メインタスクは持たない
+    _stack: Vec<u64>,
+    sleeping: bool,
+}
+
+/// 実行できるタスクを順番に回す。先頭が実行中のタスク
 pub struct TaskManager {
-    ctxs: VecDeque<TaskContext>,
+    tasks: Vec<Task>,
+    run_queue: VecDeque<TaskId>,
 }
 
 #[repr(C, align(16))]
@@ -16,52 +85,208 @@ pub struct TaskContext {
     pub rdi: u64, pub rsi: u64, pub rsp: u64, pub rbp: u64,
     pub r8: u64, pub r9: u64, pub r10: u64, pub r11: u64,
     pub r12: u64, pub r13: u64, pub r14: u64, pub r15: u64,
-    pub fxsave_area: [u32; 128]
+    pub fxsave_area: [u32; 128],
+    /// 実行していない間のタスクローカルの値。switch_contextは触らないので末尾に置く
+    pub locals: [usize; N_LOCALS],
 }
 
-pub fn init_task_manager(ctx_taskB: TaskContext) {
-    let mut ctxs = VecDeque::new();
-    let ctx_main = TaskContext::new();
-    ctxs.push_back(ctx_main);
-    ctxs.push_back(ctx_taskB);
-
-    unsafe {TASKS = Some(TaskManager { ctxs });}
+/// 今動いている処理をメインタスクにする
+pub fn init_task_manager() {
+    let mut tm = TaskManager::new();
+    tm.add(TaskContext::new(), Vec::new());
+    unsafe {TASKS = Some(tm);}
 }
 
+/// タスク切り替えのタイマーが切れたら、割り込みハンドラを抜けるところで呼ぶ
 pub unsafe fn switch_tasks() {
     TASKS.as_mut().unwrap().switch_tasks();
 }
 
+/// entry(arg0, arg1)を実行するタスクを作って実行待ちに加える。entryから戻ってはいけない。終えるときはexitを呼ぶ
+pub fn spawn(entry: fn(u64, u64), arg0: u64, arg1: u64) -> TaskId {
+    let stack = vec![0u64; STACK_WORDS];
+    let stack_end = stack.as_ptr_range().end as u64;
+
+    let mut ctx = TaskContext::new();
+    ctx.rip = entry as *const fn(u64, u64) as u64;
+    ctx.rdi = arg0;
+    ctx.rsi = arg1;
+
+    ctx.cr3 = unsafe { get_cr3() };
+    ctx.rflags = 0x202;
+    ctx.cs = KERNEL_CS as u64;
+    ctx.ss = KERNEL_SS as u64;
+    ctx.rsp = (stack_end & !0xfu64) - 8;
+    ctx.fxsave_area[6] = 0x1f80; // MXCSR: 全ての例外をマスク
+
+    without_interrupts(|| unsafe {
+        TASKS.as_mut().unwrap().add(ctx, stack)
+    })
+}
+
+/// 実行中のタスクをticksの間止める。他に動けるタスクが無ければその場で待つ
+pub fn sleep(ticks: u64) {
+    let deadline = get_current_tick() + ticks;
+    let slept = without_interrupts(|| unsafe {
+        let tm = TASKS.as_mut().unwrap();
+        let Some((current, next)) = tm.sleep_current() else {
+            return false;
+        };
+        add_timer_task(deadline, current);
+        tm.switch_between(current, next);
+        true
+    });
+    if !slept {
+        while get_current_tick() < deadline {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+/// 実行中のタスクを終える。起こすタイマーを置かずに眠るので二度と動かない。スタックは解放しない
+pub fn exit() -> ! {
+    loop {
+        without_interrupts(|| unsafe {
+            let tm = TASKS.as_mut().unwrap();
+            if let Some((current, next)) = tm.sleep_current() {
+                tm.switch_between(current, next);
+            }
+        });
+        // 他に動けるタスクが無ければ、出てくるまで待つ
+        x86_64::instructions::hlt();
+    }
+}
+
+/// 実行中のタスク。ロックを取らないので、割り込みを止めた後のパニックハンドラから呼べる
+pub fn current_task() -> Option<TaskId> {
+    unsafe { TASKS.as_ref()?.run_queue.front().copied() }
+}
+
+/// タイマー割り込みの中でsleepしているタスクを実行待ちに戻す
+pub fn wake(id: TaskId) {
+    without_interrupts(|| unsafe {
+        TASKS.as_mut().unwrap().wake(id);
+    })
+}
+
 extern "C" {
     /// 現在のレジスタの値をcurrent_ctxに退避し、next_ctxに保存されたレジスタの値をCPUに反映する
     fn switch_context(next_ctx: &TaskContext, current_ctx: &mut TaskContext);
 }
 
 impl TaskManager {
+    fn new() -> Self {
+        Self { tasks: Vec::new(), run_queue: VecDeque::new() }
+    }
+
+    fn add(&mut self, ctx: TaskContext, stack: Vec<u64>) -> TaskId {
+        let id = self.tasks.len();
+        self.tasks.push(Task { ctx: Box::new(ctx), _stack: stack, sleeping: false });
+        // wakeは割り込みの中で呼ばれるので、全てのタスクが並べるだけの容量を先に取っておく
+        self.run_queue.reserve(self.tasks.len() - self.run_queue.len());
+        self.run_queue.push_back(id);
+        id
+    }
+
+    /// 実行中のタスクを後ろに回す。切り替えるなら(今のタスク, 次のタスク)
+    fn rotate(&mut self) -> Option<(TaskId, TaskId)> {
+        let current = self.run_queue.pop_front()?;
+        self.run_queue.push_back(current);
+        let next = self.run_queue[0];
+        (next != current).then_some((current, next))
+    }
+
+    /// 実行中のタスクを実行待ちから外す。他に動けるタスクが無ければNoneで、何もしない
+    fn sleep_current(&mut self) -> Option<(TaskId, TaskId)> {
+        let next = *self.run_queue.get(1)?;
+        let current = self.run_queue.pop_front().unwrap();
+        self.tasks[current].sleeping = true;
+        Some((current, next))
+    }
+
+    /// 眠っているタスクを実行待ちの最後に戻す。メモリ割り当てはしない
+    fn wake(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.get_mut(id).filter(|t| t.sleeping) {
+            task.sleeping = false;
+            self.run_queue.push_back(id);
+        }
+    }
+
     pub unsafe fn switch_tasks(&mut self) {
-        let old_task = self.ctxs.pop_front().unwrap();
-        self.ctxs.push_back(old_task);
-
-        let (front, tail) = self.ctxs.as_mut_slices();
-        let (new_task, old_task) = {
-            if tail.is_empty() {
-                let (f, t) = front.split_at_mut(1);
-                (f.first().unwrap(), t.last_mut().unwrap())
-            } else {
-                (front.first().unwrap(), tail.last_mut().unwrap())
-            }
-        };
-        switch_context(new_task, old_task);
+        if let Some((current, next)) = self.rotate() {
+            self.switch_between(current, next);
+        }
+    }
+
+    unsafe fn switch_between(&mut self, current: TaskId, next: TaskId) {
+        let old_task: *mut TaskContext = &mut *self.tasks[current].ctx;
+        let new_task: *const TaskContext = &*self.tasks[next].ctx;
+        for (i, current) in CURRENT_LOCALS.iter().enumerate() {
+            (*old_task).locals[i] = current.swap((*new_task).locals[i], Ordering::Relaxed);
+        }
+        switch_context(&*new_task, &mut *old_task);
     }
 }
 
 impl TaskContext {
     pub const fn new() -> Self {
-        Self { cr3: 0, rip: 0, rflags: 0, rsvd1: 0, cs: 0, ss: 0, fs: 0, gs: 0, rax: 0, rbx: 0, rcx: 0, rdx: 0, rdi: 0, rsi: 0, rsp: 0, rbp: 0, r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0, fxsave_area: [0;128] }
+        Self { cr3: 0, rip: 0, rflags: 0, rsvd1: 0, cs: 0, ss: 0, fs: 0, gs: 0, rax: 0, rbx: 0, rcx: 0, rdx: 0, rdi: 0, rsi: 0, rsp: 0, rbp: 0, r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0, fxsave_area: [0;128], locals: [0; N_LOCALS] }
     }
 }
 
+const MAIN_MARK: usize = 0x6d61696e;
+const TEST_MARK: usize = 0x7465_7374;
+
+/// run_task_local_testsが作るタスク。自分の枠を確かめたら、メインタスクの枠の確認を頼んで終わる
+fn task_local_test_task(_: u64, _: u64) {
+    // 作られたばかりのタスクの枠は0
+    assert!(local_get(SELFTEST) == 0);
+    local_set(SELFTEST, TEST_MARK);
+    // 眠っている間にメインタスクが動いても、書いた値は変わらない
+    sleep(1);
+    assert!(local_get(SELFTEST) == TEST_MARK);
+    add_timer_deferred(get_current_tick(), |_| {
+        assert!(local_get(SELFTEST) == MAIN_MARK);
+        local_set(SELFTEST, 0);
+    }, 0);
+    exit();
+}
+
+/// 2つのタスクが同じ枠に別の値を書き、タスク切り替えをまたいでも混ざらないことを確かめる
+/// メインタスクの値は、試験用のタスクが動き終わってからメインループで見る
+pub fn run_task_local_tests() {
+    let keys = [ALLOC_TAG, SELFTEST];
+    assert!(keys.iter().enumerate().all(|(i, k)| k.0 == i) && N_LOCAL_KEYS == keys.len());
+
+    local_set(SELFTEST, MAIN_MARK);
+    assert!(local_replace(SELFTEST, MAIN_MARK) == MAIN_MARK);
+    spawn(task_local_test_task, 0, 0);
+}
+
+/// 実行待ちの並びだけを確かめる。switch_contextは呼ばない
+pub fn run_scheduler_tests() {
+    let mut tm = TaskManager::new();
+    let ids: Vec<TaskId> = (0..3).map(|_| tm.add(TaskContext::new(), Vec::new())).collect();
+    assert!(ids == [0, 1, 2]);
 
+    // 順番に回る
+    assert!(tm.rotate() == Some((0, 1)) && tm.rotate() == Some((1, 2)) && tm.rotate() == Some((2, 0)));
+
+    // 眠ったタスクは回ってこず、起こすと最後に並ぶ。2回起こしても1回だけ並ぶ
+    assert!(tm.sleep_current() == Some((0, 1)));
+    assert!(tm.rotate() == Some((1, 2)) && tm.rotate() == Some((2, 1)));
+    let capacity = tm.run_queue.capacity();
+    tm.wake(0);
+    tm.wake(0);
+    tm.wake(2);
+    assert!(tm.run_queue == [1, 2, 0] && tm.run_queue.capacity() == capacity);
+
+    // 最後の1つは眠れない
+    assert!(tm.sleep_current() == Some((1, 2)) && tm.sleep_current() == Some((2, 0)));
+    assert!(tm.sleep_current().is_none() && tm.rotate().is_none());
+    assert!(tm.run_queue == [0] && !tm.tasks[0].sleeping);
+    tm.wake(7);
+}
 
 global_asm!(r#"
 switch_context:
@@ -1,36 +1,38 @@
-use crate::graphic::{font::write_string, window::{self, Window}, with_layers};
+use core::fmt::Write;
+
+use crate::console::StackWriter;
+use crate::graphic::{focus, font::write_string, palette, window::{self, Placement, Window}, with_layers};
 use crate::graphic::graphics::PixelWriter;
 use crate::println;
 
 fn initialize_taskB_window() -> window::LayerHandle {
-    let mut win = Window::new(160, 52);
+    let mut win = Window::new(160, 52, Some(palette::WINDOW_GRAY));
     win.move_to((100,200).into());
     win.buffer().write_with(|back|{
         crate::draw_window(back, "taskB!".as_bytes());
     });
     win.buffer().flush();
 
-    let handle = with_layers(|l|{
-        let h = l.new_layer(win);
-        l.up_down(h.layer_id(), 2);
-        h
-    });
+    let handle = with_layers(|l| l.new_layer_deferred(win, Placement::TopMost));
+    focus::register_window(handle.layer_id(), "taskB!");
     handle
 }
 
-#[allow(unused)]
-pub fn taskB() {
+/// 数を数えて表示し続ける。表示は1tickに1回だけ更新し、数字の部分だけを描き直す
+pub fn taskB(_task_id: u64, _data: u64) {
     let mut cnt = 0;
     let win = initialize_taskB_window();
     loop {
         cnt += 1;
-        let a = format!("{:010}", cnt);
+        let mut a = StackWriter::new();
+        let _ = write!(a, "{:010}", cnt);
         let win = win.window().read();
         win.buffer().write_with(|back|{
-            crate::draw_window(back, "taskB!".as_bytes());
-            back.fill_rect((24,28).into(), (80,16).into(), (0xc6,0xc6,0xc6));
-            write_string(back, 24, 28, a.as_bytes(), (0,0,0));
+            back.fill_rect((24,28).into(), (80,16).into(), palette::WINDOW_GRAY);
+            write_string(back, 24, 28, a.as_bytes(), palette::WINDOW_TEXT);
         });
         win.buffer().flush();
+        drop(win);
+        crate::task::sleep(1);
     }
 }
\ No newline at end of file
@@ -0,0 +1,179 @@
+use crate::{
+    draw_window,
+    graphic::{focus, font::write_ascii, graphics::{Color, PixelWriter}, palette, window::{LayerHandle, Placement, Window}, with_layers},
+    keyboard::{self, KeyEvent},
+    memory_manager::Mutex,
+};
+
+const COLS: usize = 32;
+const ROWS: usize = 6;
+/// 文字を書く領域の左上の位置
+const TEXT_X: i32 = 8;
+const TEXT_Y: i32 = 28;
+const WIN_W: usize = 8 * COLS + 16;
+const WIN_H: usize = 16 * ROWS + 36;
+const BG: Color = palette::TEXT_BG;
+const FG: Color = palette::contrast_text_color(palette::TEXT_BG);
+const CURSOR: Color = palette::SELECTION_BG;
+const BACKSPACE: char = '\x08';
+
+/// 折り返しとスクロールをする文字の格子。カーソルは次に文字を書く位置
+struct TextGrid {
+    cells: [[u8; COLS]; ROWS],
+    col: usize,
+    row: usize,
+}
+
+impl TextGrid {
+    const fn new() -> Self {
+        Self { cells: [[b' '; COLS]; ROWS], col: 0, row: 0 }
+    }
+
+    /// 1文字書く。スクロールしたらtrue
+    /// 行の最後の桁に書いたら次の行の先頭に進み、最後の行からはみ出すと全体を1行上げる
+    fn put(&mut self, c: char) -> bool {
+        match c {
+            '\n' => self.new_line(),
+            BACKSPACE => {
+                if self.col > 0 {
+                    self.col -= 1;
+                } else if self.row > 0 {
+                    self.row -= 1;
+                    self.col = COLS - 1;
+                }
+                self.cells[self.row][self.col] = b' ';
+                false
+            }
+            ' '..='~' => {
+                self.cells[self.row][self.col] = c as u8;
+                self.col += 1;
+                self.col == COLS && self.new_line()
+            }
+            _ => false,
+        }
+    }
+
+    fn new_line(&mut self) -> bool {
+        self.col = 0;
+        if self.row + 1 < ROWS {
+            self.row += 1;
+            return false;
+        }
+        self.cells.copy_within(1.., 0);
+        self.cells[ROWS - 1] = [b' '; COLS];
+        true
+    }
+}
+
+/// キーボードで打った文字を表示するウィンドウ
+struct TextField {
+    layer: LayerHandle,
+    grid: TextGrid,
+}
+
+static FIELD: Mutex<Option<TextField>> = Mutex::new(None);
+
+/// 入力欄を作ってフォーカスできるようにする
+pub fn init_text_field() {
+    let mut win = Window::new(WIN_W, WIN_H, Some(palette::WINDOW_GRAY));
+    win.move_to((100, 300).into());
+    let layer = with_layers(|l| l.new_layer_deferred(win, Placement::Hidden));
+    let layer_id = layer.layer_id();
+    let field = TextField { layer, grid: TextGrid::new() };
+    field.render_all();
+    *FIELD.lock() = Some(field);
+    focus::register_window(layer_id, "text field");
+}
+
+/// 入力欄にフォーカスがあれば、押されたキーの文字を書く。押し続けたキーの繰り返しも同じように書く
+/// ショートカットに使われたキーはkeyboardに渡る前に除かれている
+pub fn on_key_event(event: KeyEvent) {
+    let KeyEvent::Pressed(keycode, modifier) = event else {
+        return;
+    };
+    if modifier.ctrl() || modifier.alt() || modifier.gui() {
+        return;
+    }
+    let focused = focus::focused();
+    let mut field = FIELD.lock();
+    let Some(f) = field.as_mut().filter(|f| focused == Some(f.layer.layer_id())) else {
+        return;
+    };
+    let Some(c) = keyboard::translate(keycode, modifier) else {
+        return;
+    };
+    let prev_row = f.grid.row;
+    if f.grid.put(c) {
+        f.render_text();
+    } else {
+        f.render_rows(prev_row.min(f.grid.row), prev_row.max(f.grid.row));
+    }
+}
+
+impl TextField {
+    fn render_all(&self) {
+        let window = self.layer.window().read();
+        window.buffer().write_with(|back| {
+            draw_window(back, b"text field");
+            back.fill_rect((TEXT_X - 4, TEXT_Y - 4).into(), (8 * COLS as u32 + 8, 16 * ROWS as u32 + 8).into(), BG);
+        });
+        drop(window);
+        self.render_text();
+    }
+
+    fn render_text(&self) {
+        self.render_rows(0, ROWS - 1);
+    }
+
+    /// first行目からlast行目までを、カーソルも含めて描き直す
+    fn render_rows(&self, first: usize, last: usize) {
+        let window = self.layer.window().read();
+        window.buffer().write_with(|back| {
+            for row in first..=last {
+                let y = TEXT_Y + 16 * row as i32;
+                back.fill_rect((TEXT_X, y).into(), (8 * COLS as u32, 16).into(), BG);
+                for (col, &c) in self.grid.cells[row].iter().enumerate() {
+                    write_ascii(back, (TEXT_X + 8 * col as i32) as u32, y as u32, c as char, FG);
+                }
+                if row == self.grid.row {
+                    back.fill_rect((TEXT_X + 8 * self.grid.col as i32, y + 14).into(), (8, 2).into(), CURSOR);
+                }
+            }
+        });
+        window.buffer().flush();
+    }
+}
+
+pub fn run_text_field_tests() {
+    let text = |g: &TextGrid, row: usize| core::str::from_utf8(&g.cells[row]).unwrap().trim_end().len();
+    let mut g = TextGrid::new();
+    for c in "ab\nc".chars() {
+        assert!(!g.put(c));
+    }
+    assert!(&g.cells[0][..2] == b"ab" && g.cells[1][0] == b'c' && (g.row, g.col) == (1, 1));
+
+    // 最後の桁に書いたら次の行へ。バックスペースは行をまたいで戻る
+    let mut g = TextGrid::new();
+    for _ in 0..COLS {
+        g.put('x');
+    }
+    assert!((g.row, g.col) == (1, 0) && text(&g, 0) == COLS);
+    g.put(BACKSPACE);
+    assert!((g.row, g.col) == (0, COLS - 1) && text(&g, 0) == COLS - 1);
+    g.put(BACKSPACE);
+    g.put('y');
+    assert!(g.cells[0][COLS - 2] == b'y' && (g.row, g.col) == (0, COLS - 1));
+    // 先頭でのバックスペースと表示できない文字は何もしない
+    let mut g = TextGrid::new();
+    assert!(!g.put(BACKSPACE) && !g.put('\t') && (g.row, g.col) == (0, 0));
+
+    // 最後の行からはみ出すと1行上がり、最後の行は空になる
+    let mut g = TextGrid::new();
+    for i in 0..ROWS - 1 {
+        g.put((b'0' + i as u8) as char);
+        assert!(!g.put('\n'));
+    }
+    g.put('z');
+    assert!(g.put('\n'));
+    assert!(g.cells[0][0] == b'1' && g.cells[ROWS - 2][0] == b'z' && text(&g, ROWS - 1) == 0 && (g.row, g.col) == (ROWS - 1, 0));
+}
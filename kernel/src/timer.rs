@@ -1,28 +1,53 @@
 use core::ptr::{write_volatile, read_volatile};
 
-use alloc::collections::BinaryHeap;
-use x86_64::instructions::interrupts::without_interrupts;
+use core::{sync::atomic::{AtomicU32, AtomicUsize, Ordering}, task::Waker};
 
-use crate::{acpi, interrupt, memory_manager::LazyInit, EVENTS};
+use alloc::{collections::BinaryHeap, sync::Arc};
+use futures::task::{waker, ArcWake};
 
-const DIVIDE_CONF_ADDR: *mut u32 = 0xfee003e0 as *mut u32;
-const LVT_TIMER_ADDR: *mut u32 = 0xfee00320 as *mut u32;
-const INITIAL_COUNT_ADDR: *mut u32 = 0xfee00380 as *mut u32;
-const CURRENT_COUNT_ADDR: *mut u32 = 0xfee00390 as *mut u32;
+use crate::{acpi, deferred, event_loop::{Message, EVENTS}, hpet, interrupt, introspect, task, memory_manager::IrqLazyInit, usb::{new_bounded_channel, SendPolicy, Sender}};
+
+/// Local APICの中のレジスタの位置。ベースはacpi::local_apic_base
+const DIVIDE_CONF: u64 = 0x3e0;
+const LVT_TIMER: u64 = 0x320;
+const INITIAL_COUNT: u64 = 0x380;
+const CURRENT_COUNT: u64 = 0x390;
 
 const COUNT_MAX: u32 = 0xffffffff;
-const TIMER_FREQ: u32 = 100; // per sec
+pub const TIMER_FREQ: u32 = 100; // per sec
 
-const TASK_TIMER_VALUE: u64 = u64::MIN;
 const TASK_TIMER_PERIOD: u64 = TIMER_FREQ as u64 / 50;
 
-static mut LAPIC_TIMER_FREQ: u32 = 0;
+/// 較正したLAPICタイマーの周波数(Hz)
+static LAPIC_TIMER_FREQ: AtomicU32 = AtomicU32::new(0);
+
+static TIMER: IrqLazyInit<TimerManager> = IrqLazyInit::new();
+
+/// add_timer系の関数が返す、タイマーを取り消すためのID
+pub type TimerId = u64;
+
+/// タイマーが切れたときの通知先。通知はタイマー割り込みの中で行われる
+pub enum TimerTarget {
+    /// メインループにMessage::TimerTimeout(値)を送る
+    Message(u64),
+    /// チャネルに値を送る。容量のあるチャネルでなければならない
+    Sender(Sender<u64>, u64),
+    /// Wakerを起こす。割り込みの中で呼ばれるので、wakeはロックやメモリ割り当てをしてはいけない
+    Waker(Waker),
+    /// メインループでf(arg)を実行させる
+    Deferred(fn(usize), usize),
+    /// タスク切り替え
+    TaskSwitch,
+    /// task::sleepで眠っているタスクを起こす
+    Task(task::TaskId),
+}
 
-static TIMER: LazyInit<TimerManager> = LazyInit::new();
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Timer {
     timeout: u64,
-    value: u64
+    id: TimerId,
+    target: TimerTarget,
+    /// 0でなければ、切れるたびにこの間隔で掛け直す
+    period: u64,
 }
 
 impl Timer {
@@ -31,10 +56,18 @@ impl Timer {
     }
 }
 
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.timeout == other.timeout && self.id == other.id
+    }
+}
+
+impl Eq for Timer {}
+
 impl Ord for Timer {
     // self <= other :=: self.timeout >= other.timeout
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        other.timeout.cmp(&self.timeout)
+        other.timeout.cmp(&self.timeout).then(other.id.cmp(&self.id))
     }
 }
 
@@ -46,13 +79,14 @@ impl PartialOrd for Timer {
 
 pub struct TimerManager {
     tick: u64,
-    timers: BinaryHeap<Timer>
+    timers: BinaryHeap<Timer>,
+    next_id: TimerId,
 }
 
 impl TimerManager {
     pub fn new() -> Self {
         let timers = BinaryHeap::new();
-        Self {tick: 0, timers}
+        Self {tick: 0, timers, next_id: 0}
     }
 
     /// returns task_timer_timeout
@@ -62,22 +96,65 @@ impl TimerManager {
         self.inc_tick_volatile(elapsed);
         
         while self.timers.peek().filter(|top|top.is_over(self.tick)).is_some() {
-            let top = self.timers.pop().unwrap();
-
-            if top.value == TASK_TIMER_VALUE {
-                task_timer_timeout = true;
-                // タイマーをpopした直後なので、pushしてもメモリ割り当てが起こらない: 割り込み中に実行しても安全
-                self.timers.push(Timer {timeout: self.tick + TASK_TIMER_PERIOD, value: TASK_TIMER_VALUE});
-            } else {
-                let _ = EVENTS.lock().push(crate::Message::TimerTimeout(top.value));
+            let mut top = self.timers.pop().unwrap();
+
+            // タイマーをpopした直後なので、pushしてもメモリ割り当てが起こらない: 割り込み中に実行しても安全
+            match top.target {
+                TimerTarget::TaskSwitch => {
+                    task_timer_timeout = true;
+                    top.timeout = self.tick + TASK_TIMER_PERIOD;
+                    self.timers.push(top);
+                    continue;
+                }
+                TimerTarget::Message(value) => {
+                    let _ = EVENTS.lock().push(Message::TimerTimeout(value));
+                }
+                TimerTarget::Sender(ref sender, value) => {
+                    if sender.try_send(value).is_err() {
+                        // 割り込まれた側がチャネルをロックしているので、次のtickで送り直す
+                        top.timeout = self.tick;
+                        self.timers.push(top);
+                        break;
+                    }
+                }
+                TimerTarget::Waker(ref waker) => waker.wake_by_ref(),
+                TimerTarget::Task(id) => task::wake(id),
+                TimerTarget::Deferred(f, arg) => {
+                    if deferred::defer(f, arg).is_err() {
+                        // キューが空くまで次のtickで登録し直す
+                        top.timeout = self.tick;
+                        self.timers.push(top);
+                        break;
+                    }
+                }
+            }
+            if top.period != 0 {
+                // 遅れて処理したときは、逃した分を飛ばして次の周期に合わせる
+                top.timeout += top.period * ((self.tick - top.timeout) / top.period + 1);
+                self.timers.push(top);
             }
         }
 
         task_timer_timeout
     }
 
-    pub fn add_timer(&mut self, timeout: u64, value: u64) {
-        self.timers.push(Timer {timeout, value});
+    pub fn add_timer(&mut self, timeout: u64, target: TimerTarget) -> TimerId {
+        self.add_periodic_timer(timeout, 0, target)
+    }
+
+    /// timeoutで最初に切れ、その後はperiodごとに切れるタイマー。periodが0なら1回だけ
+    pub fn add_periodic_timer(&mut self, timeout: u64, period: u64, target: TimerTarget) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.timers.push(Timer {timeout, id, target, period});
+        id
+    }
+
+    /// まだ切れていないタイマーを取り消す。取り消せたらtrue
+    pub fn cancel_timer(&mut self, id: TimerId) -> bool {
+        let len = self.timers.len();
+        self.timers.retain(|t| t.id != id);
+        self.timers.len() != len
     }
 
     fn inc_tick_volatile(&mut self, elapsed: u64) {
@@ -90,35 +167,39 @@ impl TimerManager {
 
 fn initialize_lapic_timer() {
     unsafe {
-        write_volatile(DIVIDE_CONF_ADDR, 0b1011); // divide 1:1
-        write_volatile(LVT_TIMER_ADDR, 0b001 << 16); // masked, one-shot
+        write_volatile(acpi::local_apic_register(DIVIDE_CONF), 0b1011); // divide 1:1
+        write_volatile(acpi::local_apic_register(LVT_TIMER), 0b001 << 16); // masked, one-shot
 
+        // HPETは精度が高いので短く測れば足りる。PMタイマーでは長めに測って誤差を薄める
+        let window_ms = if hpet::available() { 10 } else { 100 };
         start_lapic_timer();
-        acpi::wait_millis(100);
+        acpi::wait_millis(window_ms);
         let elapsed = lapic_timer_elapsed();
         stop_lapic_timer();
         
-        LAPIC_TIMER_FREQ = elapsed * 10;
-        write_volatile(LVT_TIMER_ADDR, (0b010 << 16) | (interrupt::IVIndex::LapicTimer as u32)); // not-masked, periodic
-        write_volatile(INITIAL_COUNT_ADDR, LAPIC_TIMER_FREQ / TIMER_FREQ);
+        let freq = elapsed * (1000 / window_ms);
+        LAPIC_TIMER_FREQ.store(freq, Ordering::Relaxed);
+        crate::println!("timer: LAPIC timer {} Hz, calibrated for {}ms against the {}", freq, window_ms, if hpet::available() { "HPET" } else { "PM timer" });
+        write_volatile(acpi::local_apic_register(LVT_TIMER), (0b010 << 16) | (interrupt::IVIndex::LapicTimer as u32)); // not-masked, periodic
+        write_volatile(acpi::local_apic_register(INITIAL_COUNT), freq / TIMER_FREQ);
     }
 }
 
 fn start_lapic_timer() {
     unsafe {
-        write_volatile(INITIAL_COUNT_ADDR, COUNT_MAX);
+        write_volatile(acpi::local_apic_register(INITIAL_COUNT), COUNT_MAX);
     }
 }
 
 fn lapic_timer_elapsed() -> u32 {
     unsafe {
-        COUNT_MAX - read_volatile(CURRENT_COUNT_ADDR)
+        COUNT_MAX - read_volatile(acpi::local_apic_register(CURRENT_COUNT))
     }
 }
 
 fn stop_lapic_timer() {
     unsafe {
-        write_volatile(INITIAL_COUNT_ADDR, 0);
+        write_volatile(acpi::local_apic_register(INITIAL_COUNT), 0);
     }
 }
 
@@ -127,21 +208,194 @@ pub fn initialize_timer() {
     let mut tmr_lock = TIMER.lock();
     tmr_lock.init(TimerManager::new());
     let timeout = tmr_lock.tick + TASK_TIMER_PERIOD;
-    tmr_lock.add_timer(timeout, TASK_TIMER_VALUE);
+    tmr_lock.add_timer(timeout, TimerTarget::TaskSwitch);
+    introspect::register("time/uptime", |_, out| {
+        let ms = uptime_millis();
+        writeln!(out, "{}.{:03}s", ms / 1000, ms % 1000)
+    }, 0)
+    .expect("timer: time/uptime");
+}
+
+/// 起動時に較正したLAPICタイマーの周波数(Hz)。initialize_timerの前は0
+pub fn lapic_timer_freq() -> u32 {
+    LAPIC_TIMER_FREQ.load(Ordering::Relaxed)
 }
 
 pub fn on_lapic_interrupt(elapsed: u64) -> bool {
     TIMER.lock().tick(elapsed)
 }
 
+/// ミリ秒をタイマーのtick数に変換する(切り上げ)
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TIMER_FREQ as u64 + 999) / 1000
+}
+
+/// tickとLAPICタイマーのカウント値の組。tickより細かい精度で時間を測るのに使う
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    pub tick: u64,
+    pub count: u32,
+}
+
+impl Timestamp {
+    /// 割り込みハンドラからも呼べる
+    /// tickとカウントの間でタイマーが一周すると組が食い違うので、tickが変わっていたら読み直す
+    /// 割り込みを止めたまま呼ぶとtickが進まないので、一周した直後は最大1tick小さい値になることがある
+    pub fn now() -> Self {
+        loop {
+            let tick = get_current_tick();
+            let count = unsafe { read_volatile(acpi::local_apic_register(CURRENT_COUNT)) };
+            if get_current_tick() == tick {
+                return Self { tick, count };
+            }
+        }
+    }
+
+    /// 起動からの経過時間(マイクロ秒)
+    pub fn as_micros(&self) -> u64 {
+        let lapic_freq = lapic_timer_freq() as u64;
+        let initial_count = lapic_freq / TIMER_FREQ as u64;
+        let in_tick = initial_count.saturating_sub(self.count as u64) * 1_000_000 / lapic_freq.max(1);
+        self.tick * 1_000_000 / TIMER_FREQ as u64 + in_tick
+    }
+
+    /// selfからlaterまでの経過時間(マイクロ秒)
+    pub fn micros_until(&self, later: &Timestamp) -> u64 {
+        later.as_micros().saturating_sub(self.as_micros())
+    }
+}
+
+/// 起動からの経過時間(マイクロ秒)。tickの間もLAPICタイマーのカウントで補う
+pub fn uptime_micros() -> u64 {
+    Timestamp::now().as_micros()
+}
+
+/// 起動からの経過時間(ミリ秒)
+pub fn uptime_millis() -> u64 {
+    uptime_micros() / 1000
+}
+
 pub fn get_current_tick() -> u64 {
-    without_interrupts(||{
-        TIMER.lock().tick
-    })
+    TIMER.lock().tick
+}
+
+/// ロックが取れないか、タイマーがまだ無ければNone。パニックハンドラなど待てない所で使う
+pub fn try_current_tick() -> Option<u64> {
+    TIMER.try_lock().filter(|t| t.is_init()).map(|t| t.tick)
+}
+
+/// periodごとにメインループにMessage::TimerTimeout(value)を送る。cancel_timerで止める
+pub fn add_periodic_timer(period: u64, value: u64) -> TimerId {
+    let period = period.max(1);
+    let mut tm = TIMER.lock();
+    let timeout = tm.tick + period;
+    tm.add_periodic_timer(timeout, period, TimerTarget::Message(value))
+}
+
+/// tick `timeout`を過ぎたらsenderにvalueを送る。senderは容量のあるチャネルでなければならない
+pub fn add_timer_sender(timeout: u64, sender: Sender<u64>, value: u64) -> TimerId {
+    TIMER.lock().add_timer(timeout, TimerTarget::Sender(sender, value))
+}
+
+/// tick `timeout`を過ぎたらwakerを起こす
+pub fn add_timer_waker(timeout: u64, waker: Waker) -> TimerId {
+    TIMER.lock().add_timer(timeout, TimerTarget::Waker(waker))
+}
+
+/// tick `timeout`を過ぎたらタスクidを実行待ちに戻す
+pub fn add_timer_task(timeout: u64, id: task::TaskId) -> TimerId {
+    TIMER.lock().add_timer(timeout, TimerTarget::Task(id))
+}
+
+/// tick `timeout`を過ぎたらメインループでf(arg)を実行させる
+pub fn add_timer_deferred(timeout: u64, f: fn(usize), arg: usize) -> TimerId {
+    TIMER.lock().add_timer(timeout, TimerTarget::Deferred(f, arg))
+}
+
+pub fn cancel_timer(id: TimerId) -> bool {
+    TIMER.lock().cancel_timer(id)
 }
 
-pub fn add_timer(timeout: u64, value: u64) {
-    without_interrupts(||{
-        TIMER.lock().add_timer(timeout, value);
-    });
+struct CountWaker(AtomicUsize);
+
+impl ArcWake for CountWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// 割り込みを止めた状態で呼ぶこと(EVENTSを直接見るため)
+pub fn run_timer_tests() {
+    let mut tm = TimerManager::new();
+    let (tx, rx) = new_bounded_channel("test-timer", 4, SendPolicy::DropNewest);
+    let wake_count = Arc::new(CountWaker(AtomicUsize::new(0)));
+    let events_before = EVENTS.lock().len();
+
+    tm.add_timer(2, TimerTarget::Message(0x7e57));
+    tm.add_timer(2, TimerTarget::Sender(tx.clone(), 42));
+    tm.add_timer(2, TimerTarget::Waker(waker(wake_count.clone())));
+    let cancelled = [
+        tm.add_timer(2, TimerTarget::Message(0xdead)),
+        tm.add_timer(2, TimerTarget::Sender(tx.clone(), 43)),
+        tm.add_timer(2, TimerTarget::Waker(waker(wake_count.clone()))),
+    ];
+    for id in cancelled {
+        assert!(tm.cancel_timer(id));
+        assert!(!tm.cancel_timer(id));
+    }
+
+    // timeoutちょうどではまだ切れない
+    tm.tick(2);
+    assert!(EVENTS.lock().len() == events_before && !rx.has_content() && wake_count.0.load(Ordering::Relaxed) == 0);
+
+    for _ in 0..3 {
+        tm.tick(1);
+    }
+    assert!(tm.timers.is_empty());
+    assert!(EVENTS.lock().len() == events_before + 1);
+    let mut events = EVENTS.lock();
+    for _ in 0..events_before {
+        let msg = events.pop().unwrap();
+        let _ = events.push(msg);
+    }
+    assert!(matches!(events.pop(), Some(Message::TimerTimeout(0x7e57))));
+    drop(events);
+    assert!(rx.receive() == Some(42) && rx.receive().is_none());
+    assert!(wake_count.0.load(Ordering::Relaxed) == 1);
+
+    // tickの途中はカウントの減った分だけ進む
+    let initial_count = lapic_timer_freq() / TIMER_FREQ;
+    let tick_us = 1_000_000 / TIMER_FREQ as u64;
+    assert!(Timestamp { tick: 3, count: initial_count }.as_micros() == 3 * tick_us);
+    let half = Timestamp { tick: 3, count: initial_count / 2 }.as_micros();
+    assert!(half.abs_diff(3 * tick_us + tick_us / 2) <= 1);
+    assert!(Timestamp { tick: 3, count: 0 }.as_micros().abs_diff(4 * tick_us) <= 1);
+    let now = Timestamp::now();
+    assert!(now.count <= initial_count && (now.tick..=now.tick + 1).contains(&(now.as_micros() / tick_us)));
+
+    // 周期タイマーは同じidのまま掛け直され、そのたびに割り当てはしない
+    let mut tm = TimerManager::new();
+    let id = tm.add_periodic_timer(3, 3, TimerTarget::Sender(tx.clone(), 7));
+    let capacity = tm.timers.capacity();
+    let mut fired = 0;
+    for _ in 0..10 {
+        tm.tick(1);
+        while rx.receive() == Some(7) {
+            fired += 1;
+        }
+    }
+    assert!(fired == 3 && tm.timers.len() == 1 && tm.timers.capacity() == capacity);
+    // 大きく遅れても逃した分はまとめて1回
+    tm.tick(20);
+    assert!(rx.receive() == Some(7) && rx.receive().is_none());
+    tm.tick(1);
+    assert!(!rx.has_content());
+    // 取り消したら二度と切れない
+    let events_before = EVENTS.lock().len();
+    let msg_id = tm.add_periodic_timer(tm.tick + 1, 1, TimerTarget::Message(0xbeef));
+    assert!(tm.cancel_timer(id) && tm.cancel_timer(msg_id));
+    for _ in 0..10 {
+        tm.tick(1);
+    }
+    assert!(tm.timers.is_empty() && !rx.has_content() && EVENTS.lock().len() == events_before);
 }
@@ -1,175 +1,698 @@
-use alloc::vec::Vec;
+use alloc::{collections::{BTreeMap, BTreeSet}, vec::Vec};
+
+use xhci::{context::{EndpointHandler, SlotHandler}, registers::operational::PortStatusAndControlRegister, ring::trb::{command::{AddressDevice, Allowed, DisableSlot, EnableSlot}, event::{CompletionCode, PortStatusChange}}};
+
+use crate::{addr::PhysAddr, memory_manager::Mutex, timer::{get_current_tick, ms_to_ticks}, usb::{publish_hotplug, HotplugEvent, device::{ContextSize, InputContext}, doorbell::{Dci, PortId, SlotId}, ready::{self, Resolution}, registry, slot::SlotState, runtime::{new_channel, sleep, timeout_at, Receiver, Sender}, spawn, xhci::{is_usb3_port, notify_port_status, push_command_async, root_ports, with_dcbaa, with_regs, with_trf_rings, ErrorContext, ErrorKind, Operation, XhciError}}};
+
+/// 接続を検知してから、ポートをリセットするまでに待つ時間
+const PORT_SETTLE_TIME_MS: u64 = 100;
+/// RETRY_WINDOW_MSの間にMAX_RESETS_PER_WINDOW回を超えてリセットが必要になったポートは、切断されるまで諦める
+const RETRY_WINDOW_MS: u64 = 5000;
+const MAX_RESETS_PER_WINDOW: u32 = 3;
+/// ポートリセット(USB2)・ウォームリセット(USB3)の完了を待つ時間
+const RESET_TIMEOUT_MS: u64 = 500;
+/// USB3のリンクトレーニングが終わるのを待つ時間
+const LINK_TRAINING_TIMEOUT_MS: u64 = 1000;
+/// 過電流が収まってから、ポートを再び列挙するまでに待つ時間
+const OVER_CURRENT_COOLDOWN_MS: u64 = 2000;
+
+/* Port Link State (xHCI 5.4.8) */
+const PLS_U0: u8 = 0;
+const PLS_U3: u8 = 3;
+const PLS_RX_DETECT: u8 = 5;
+const PLS_INACTIVE: u8 = 6;
+const PLS_POLLING: u8 = 7;
+const PLS_RECOVERY: u8 = 8;
+const PLS_COMPLIANCE: u8 = 10;
+
+fn link_state_name(pls: u8) -> &'static str {
+    match pls {
+        0 => "U0",
+        1 => "U1",
+        2 => "U2",
+        3 => "U3",
+        4 => "Disabled",
+        5 => "RxDetect",
+        6 => "Inactive",
+        7 => "Polling",
+        8 => "Recovery",
+        9 => "HotReset",
+        10 => "Compliance",
+        11 => "TestMode",
+        15 => "Resume",
+        _ => "Reserved",
+    }
+}
+
+/// リセット中のポートが何を待っているか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResetPhase {
+    /// Port Reset Changeを待つ
+    Reset,
+    /// Warm Port Reset Change (と Port Reset Change)を待つ
+    WarmReset,
+    /// USB3のリンクトレーニングが終わりU0になるのを待つ
+    LinkTraining,
+}
+
+/// ポートごとの列挙の統計
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PortStat {
+    /// 発行したポートリセットの総数
+    pub resets: u32,
+    /// 待機後に切断されていたため、リセットしなかった回数
+    pub debounced: u32,
+    /// 列挙に失敗した回数
+    pub failures: u32,
+    /// trueなら切断されるまでこのポートを列挙しない
+    pub failed: bool,
+    /// 過電流を検知した回数
+    pub over_currents: u32,
+    /// 過電流で止めている。Someなら収まった後の待ち時間が明けるtick
+    pub over_current: Option<Option<u64>>,
+    /// 直近のリセットのtick。古い順
+    recent_resets: [Option<u64>; MAX_RESETS_PER_WINDOW as usize],
+}
+
+static PORT_STATS: Mutex<BTreeMap<PortId, PortStat>> = Mutex::new(BTreeMap::new());
+
+/// PORTSCから読んだポートの電源まわりの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortPower {
+    pub powered: bool,
+    pub connected: bool,
+    pub enabled: bool,
+    /// U3(サスペンド)にいる
+    pub suspended: bool,
+    pub over_current: bool,
+    pub link_state: &'static str,
+}
+
+impl PortPower {
+    fn from_portsc(p: &PortStatusAndControlRegister) -> Self {
+        Self {
+            powered: p.port_power(),
+            connected: p.current_connect_status(),
+            enabled: p.port_enabled_disabled(),
+            suspended: p.port_link_state() == PLS_U3,
+            over_current: p.over_current_active(),
+            link_state: link_state_name(p.port_link_state()),
+        }
+    }
+}
 
-use xhci::{context::{EndpointHandler, SlotHandler}, ring::trb::{command::{AddressDevice, Allowed, EnableSlot}, event::{CompletionCode, PortStatusChange}}, Registers};
+/// 全ポートのPORTSCを今読んで返す
+pub fn port_power_states() -> Vec<(PortId, PortPower)> {
+    root_ports()
+        .map(|p| (p, PortPower::from_portsc(&with_regs(|r| r.port_register_set.read_volatile_at(p.index()).portsc))))
+        .collect()
+}
+
+/// 各ポートの統計のスナップショットを返す
+pub fn port_stats() -> Vec<(PortId, PortStat)> {
+    PORT_STATS.lock().iter().map(|(port, stat)| (*port, *stat)).collect()
+}
+
+impl PortStat {
+    /// 切断されたら故障扱いを解く。過電流の待ち時間中なら解除は待ち時間が明けてから。
+    /// 窓の中のリセット回数は残すので、抜き差しを繰り返すポートでも窓ごとのリセットは上限までしか行わない
+    fn on_disconnect(&mut self) {
+        self.failed = self.over_current.is_some();
+    }
+
+    /// nowにリセットしてよいなら数えてtrue。nowまでのwindowの間に既に上限までリセットしていたら故障扱いにしてfalse
+    /// どこからwindowを測っても、その間のリセットは上限を超えない
+    fn try_reset(&mut self, now: u64, window: u64) -> bool {
+        if self.failed {
+            return false;
+        }
+        let in_window = self.recent_resets.iter().flatten().filter(|t| now.saturating_sub(**t) < window).count();
+        if in_window >= self.recent_resets.len() {
+            self.failed = true;
+            return false;
+        }
+        self.recent_resets.rotate_left(1);
+        *self.recent_resets.last_mut().unwrap() = Some(now);
+        self.resets += 1;
+        true
+    }
+}
 
-use crate::usb::{device::{ContextSize, InputContext}, runtime::{Receiver, Sender}, xhci::{push_command, with_dcbaa, with_regs, with_trf_rings, LinearMapper, XhciError}};
+fn with_port_stat<R>(port_id: PortId, f: impl FnOnce(&mut PortStat) -> R) -> R {
+    f(PORT_STATS.lock().entry(port_id).or_default())
+}
 
-pub struct DeviceInitAction {
-    current_port: Option<usize>,
-    waiting_port: Vec<usize>,
+/// DeviceInitActionがポートや時刻、デバイスを扱う所。テストでは偽物に差し替える
+pub(crate) trait PortHost {
+    fn portsc(&self, port_id: PortId) -> PortStatusAndControlRegister;
+    /// RW1Cのビットを0にしてからfで書き換える
+    fn update_portsc(&mut self, port_id: PortId, f: impl FnOnce(&mut PortStatusAndControlRegister));
+    fn is_usb3(&self, port_id: PortId) -> bool;
+    fn now(&self) -> u64;
+    async fn sleep(&mut self, ticks: u64);
+    /// ルートポートに繋がったデバイスにアドレスを割り当てる
+    async fn address(&mut self, port_id: PortId) -> Result<SlotId, XhciError>;
+    async fn teardown(&mut self, slot_id: SlotId);
+}
+
+/// xHCの本物のルートポート
+pub(crate) struct RootPorts;
+
+impl PortHost for RootPorts {
+    fn portsc(&self, port_id: PortId) -> PortStatusAndControlRegister {
+        self.host.portsc(port_id)
+    }
+
+    fn update_portsc(&mut self, port_id: PortId, f: impl FnOnce(&mut PortStatusAndControlRegister)) {
+        update_portsc(port_id, f);
+    }
+
+    fn is_usb3(&self, port_id: PortId) -> bool {
+        is_usb3_port(port_id)
+    }
+
+    fn now(&self) -> u64 {
+        get_current_tick()
+    }
+
+    async fn sleep(&mut self, ticks: u64) {
+        sleep(ticks).await;
+    }
+
+    async fn address(&mut self, port_id: PortId) -> Result<SlotId, XhciError> {
+        address_device(&DeviceRoute::root(port_id)).await
+    }
+
+    async fn teardown(&mut self, slot_id: SlotId) {
+        teardown_slot(slot_id).await;
+    }
+}
+
+pub(crate) struct DeviceInitAction<H: PortHost = RootPorts> {
+    host: H,
+    current_port: Option<PortId>,
+    waiting_port: BTreeSet<PortId>,
     status_change: Receiver<PortStatusChange>,
-    address_device_listener: Sender<usize>
+    address_device_listener: Sender<SlotId>,
+    settle_ticks: u64,
+    /// current_portのリセットの段階と、その期限のtick
+    reset_phase: Option<(ResetPhase, u64)>,
+    /// アドレスを割り当てたデバイスのスロット。切断の通知に使う
+    slots: BTreeMap<PortId, SlotId>,
 }
 
 impl DeviceInitAction {
-    pub fn new(status_change: Receiver<PortStatusChange>, address_device_listener: Sender<usize>) -> Self {
-        Self { current_port: None, waiting_port: Vec::new(), status_change, address_device_listener }
+    pub fn new(status_change: Receiver<PortStatusChange>, address_device_listener: Sender<SlotId>) -> Self {
+        Self::with_host(RootPorts, status_change, address_device_listener)
+    }
+}
+
+impl<H: PortHost> DeviceInitAction<H> {
+    fn with_host(host: H, status_change: Receiver<PortStatusChange>, address_device_listener: Sender<SlotId>) -> Self {
+        Self {
+            host,
+            current_port: None,
+            waiting_port: BTreeSet::new(),
+            status_change,
+            address_device_listener,
+            settle_ticks: ms_to_ticks(PORT_SETTLE_TIME_MS),
+            reset_phase: None,
+            slots: BTreeMap::new(),
+        }
+    }
+
+    /// 接続を検知してからポートをリセットするまでの待ち時間を設定する
+    pub fn set_settle_time(&mut self, ms: u64) {
+        self.settle_ticks = ms_to_ticks(ms);
     }
 
     pub async fn main_loop(&mut self) {
-        let num_ports = with_regs(|r|r.capability.hcsparams1.read_volatile().number_of_ports()) as usize;
-        for port_id in 0..num_ports {
-            if with_regs(|r|r.port_register_set.read_volatile_at(port_id).portsc.current_connect_status()) {
-                with_regs(|r|r.port_register_set.update_volatile_at(port_id, |p|{
-                    p.portsc.clear_connect_status_change();
-                }));
-                self.init_device_async(port_id as usize).await;
+        let connected: Vec<PortId> = root_ports().filter(|p| self.host.portsc(*p).current_connect_status()).collect();
+        ready::set_expected(connected.len());
+        for port_id in connected {
+            self.clear_csc(port_id);
+            if let Err(e) = self.init_device_async(port_id).await.on_port(port_id) {
+                error!("failed to initialize device: {e}");
+                ready::resolve(Resolution::Failed(None));
             }
         }
 
         loop {
-            let event = self.status_change.receive_async().await;
-            let port_id = (event.port_id() - 1) as usize;
-
-            let portsc = with_regs(|r|r.port_register_set.read_volatile_at(port_id).portsc);
-
-            if portsc.connect_status_change() && portsc.current_connect_status() {
-                clear_csc(port_id);
-                self.waiting_port.push(port_id);
-            } else if portsc.port_reset_change() {
-                clear_port_reset(port_id);
-                if self.current_port.is_some_and(|p|p == port_id) {
-                    self.init_device_async(port_id).await;
+            let event = match self.reset_phase {
+                Some((_, deadline)) => timeout_at(deadline, self.status_change.receive_async()).await,
+                None => Some(self.status_change.receive_async().await),
+            };
+            self.handle(event).await;
+        }
+    }
+
+    /// 届いた変化を1つ(Noneならリセットの期限切れ)処理してから、次のポートに取りかかる
+    async fn handle(&mut self, event: Option<PortStatusChange>) {
+        match event {
+            Some(event) => self.on_status_change(event).await,
+            None => self.on_reset_timeout().await,
+        }
+        // 既に届いているイベントはまとめて処理し、同じポートを何度もリセットしないようにする
+        while let Some(event) = self.status_change.receive() {
+            self.on_status_change(event).await;
+        }
+
+        self.start_next_port().await;
+    }
+
+    async fn on_status_change(&mut self, event: PortStatusChange) {
+        let Some(port_id) = PortId::new(event.port_id()) else {
+            warn!("port status change for port 0, ignored");
+            return;
+        };
+        let portsc = self.host.portsc(port_id);
+
+        if portsc.over_current_change() {
+            self.clear_over_current_change(port_id);
+            self.on_over_current_change(port_id, portsc.over_current_active()).await;
+        }
+        if portsc.over_current_active() {
+            // 過電流が続いている間は他の変化も無視する。収まったときにまたOCCが立つ
+            return;
+        }
+        self.resume_after_over_current(port_id, portsc.current_connect_status());
+
+        if portsc.warm_port_reset_change() {
+            // ウォームリセットの完了時にはPort Reset Changeも立つので、列挙はそちらで行う
+            self.clear_warm_port_reset(port_id);
+        }
+
+        if portsc.connect_status_change() {
+            self.clear_csc(port_id);
+            if portsc.current_connect_status() {
+                if self.current_port != Some(port_id) && !with_port_stat(port_id, |s| s.failed) {
+                    self.waiting_port.insert(port_id);
+                }
+            } else {
+                // 切断されたら待ち行列から外し、故障扱いを解除する
+                self.waiting_port.remove(&port_id);
+                with_port_stat(port_id, PortStat::on_disconnect);
+                if self.current_port == Some(port_id) {
+                    self.current_port = None;
+                    self.reset_phase = None;
+                }
+                if let Some(slot) = self.slots.remove(&port_id) {
+                    publish_hotplug(HotplugEvent::Detached { slot });
+                    self.host.teardown(slot).await;
+                }
+            }
+        } else if portsc.port_reset_change() {
+            self.clear_port_reset(port_id);
+            if self.current_port == Some(port_id) {
+                self.enumerate_current_port(port_id).await;
+            }
+        } else if portsc.port_link_state_change() {
+            self.clear_port_link_state_change(port_id);
+            if self.current_port == Some(port_id)
+                && matches!(self.reset_phase, Some((ResetPhase::LinkTraining, _)))
+                && portsc.port_link_state() == PLS_U0
+                && portsc.port_enabled_disabled()
+            {
+                self.enumerate_current_port(port_id).await;
+            }
+        }
+    }
+
+    /// 過電流が起きたら、そのポートのデバイスを外したものとして扱い、収まって待ち時間が明けるまで列挙しない
+    async fn on_over_current_change(&mut self, port_id: PortId, active: bool) {
+        if !active {
+            let until = self.host.now() + ms_to_ticks(OVER_CURRENT_COOLDOWN_MS);
+            let was_tripped = with_port_stat(port_id, |s| {
+                let tripped = s.over_current.is_some();
+                if tripped {
+                    s.over_current = Some(Some(until));
                 }
+                tripped
+            });
+            if was_tripped {
+                println!("port {port_id}: over-current cleared, retrying in {OVER_CURRENT_COOLDOWN_MS}ms");
+                // 待ち時間が明けたら、状態変化として受け取って列挙し直す
+                spawn(async move {
+                    sleep(ms_to_ticks(OVER_CURRENT_COOLDOWN_MS)).await;
+                    notify_port_status(port_id);
+                    Ok(())
+                });
+            }
+            return;
+        }
+        warn!("port {port_id}: over-current detected");
+        with_port_stat(port_id, |s| {
+            s.over_currents += 1;
+            s.over_current = Some(None);
+            s.failed = true;
+        });
+        self.waiting_port.remove(&port_id);
+        if self.current_port == Some(port_id) {
+            self.current_port = None;
+            self.reset_phase = None;
+        }
+        if let Some(slot) = self.slots.remove(&port_id) {
+            publish_hotplug(HotplugEvent::Detached { slot });
+            self.host.teardown(slot).await;
+        }
+    }
+
+    /// 過電流が収まって待ち時間が明けていれば、故障扱いを解いて列挙し直す
+    fn resume_after_over_current(&mut self, port_id: PortId, connected: bool) {
+        let now = self.host.now();
+        let resumed = with_port_stat(port_id, |s| match s.over_current {
+            Some(Some(until)) if now >= until => {
+                s.over_current = None;
+                s.failed = false;
+                s.recent_resets = Default::default();
+                true
+            }
+            _ => false,
+        });
+        if resumed && connected && self.current_port != Some(port_id) {
+            self.waiting_port.insert(port_id);
+        }
+    }
+
+    /// リセットが完了したポートのデバイスを初期化する
+    async fn enumerate_current_port(&mut self, port_id: PortId) {
+        self.reset_phase = None;
+        if let Err(e) = self.init_device_async(port_id).await.on_port(port_id) {
+            error!("failed to initialize device: {e}");
+            with_port_stat(port_id, |s| s.failures += 1);
+            // 接続されたままなら再試行する(回数はstart_next_portで制限される)
+            if self.host.portsc(port_id).current_connect_status() {
+                self.waiting_port.insert(port_id);
+            }
+        }
+        self.current_port = None;
+    }
+
+    /// リセットやリンクトレーニングが期限内に終わらなかった
+    async fn on_reset_timeout(&mut self) {
+        let (Some(port_id), Some((phase, _))) = (self.current_port, self.reset_phase) else {
+            self.reset_phase = None;
+            return;
+        };
+        let portsc = self.host.portsc(port_id);
+        let pls = portsc.port_link_state();
+        warn!("port {port_id}: {phase:?} timed out (link state={})", link_state_name(pls));
+
+        if pls == PLS_U0 && portsc.port_enabled_disabled() {
+            // 変化のイベントを取りこぼしただけで、ポートは使える状態になっている
+            self.enumerate_current_port(port_id).await;
+            return;
+        }
+
+        // リンクトレーニングが終わらないUSB3ポートはウォームリセットで復帰を試みる
+        if phase == ResetPhase::LinkTraining && portsc.current_connect_status() && count_reset(port_id, self.host.now()) {
+            self.start_warm_reset(port_id);
+            return;
+        }
+
+        with_port_stat(port_id, |s| s.failures += 1);
+        self.reset_phase = None;
+        self.current_port = None;
+        if portsc.current_connect_status() {
+            self.waiting_port.insert(port_id);
+        }
+    }
+
+    async fn start_next_port(&mut self) {
+        while self.current_port.is_none() {
+            let Some(port_id) = self.waiting_port.pop_first() else {
+                return;
+            };
+
+            // 接続直後は接点のばたつきで接続・切断を繰り返すことがあるので、落ち着くのを待ってから読み直す
+            self.host.sleep(self.settle_ticks).await;
+            let portsc = self.host.portsc(port_id);
+            if !portsc.current_connect_status() {
+                with_port_stat(port_id, |s| s.debounced += 1);
+                continue;
+            }
+
+            let usb3 = self.host.is_usb3(port_id);
+            let pls = portsc.port_link_state();
+            println!(
+                "port {port_id}: USB{}, link state={}, enabled={}",
+                if usb3 { 3 } else { 2 },
+                link_state_name(pls),
+                portsc.port_enabled_disabled()
+            );
+
+            // USB3のポートは通常リセットなしでリンクトレーニングを終え、U0で有効になる
+            if usb3 && pls == PLS_U0 && portsc.port_enabled_disabled() {
+                self.current_port = Some(port_id);
+                self.enumerate_current_port(port_id).await;
+                continue;
+            }
+
+            if usb3 && matches!(pls, PLS_POLLING | PLS_RX_DETECT | PLS_RECOVERY) {
+                self.current_port = Some(port_id);
+                self.reset_phase = Some((ResetPhase::LinkTraining, self.host.now() + ms_to_ticks(LINK_TRAINING_TIMEOUT_MS)));
+                continue;
             }
 
-            if self.current_port.is_none() && !self.waiting_port.is_empty() {
-                self.current_port = Some(self.waiting_port.remove(0));
-                self.reset_port(self.current_port.unwrap());
+            if !count_reset(port_id, self.host.now()) {
+                continue;
+            }
+            self.current_port = Some(port_id);
+            if usb3 && matches!(pls, PLS_INACTIVE | PLS_COMPLIANCE) {
+                self.start_warm_reset(port_id);
+            } else {
+                self.reset_port(port_id);
             }
         }
     }
 
-    fn reset_port(&self, port_id: usize) {
-        let portsc = with_regs(|r|r.port_register_set.read_volatile_at(port_id).portsc);
+    fn reset_port(&mut self, port_id: PortId) {
+        let portsc = self.host.portsc(port_id);
         println!(
             "resetting port {port_id}(CCS={}, CSC={})",
             portsc.current_connect_status(),
             portsc.connect_status_change()
         );
-        set_port_reset(port_id);
+        self.reset_phase = Some((ResetPhase::Reset, self.host.now() + ms_to_ticks(RESET_TIMEOUT_MS)));
+        self.set_port_reset(port_id);
+    }
+
+    fn start_warm_reset(&mut self, port_id: PortId) {
+        println!("warm resetting port {port_id}");
+        self.reset_phase = Some((ResetPhase::WarmReset, self.host.now() + ms_to_ticks(RESET_TIMEOUT_MS)));
+        self.set_warm_port_reset(port_id);
     }
     
-    async fn init_device_async(&mut self, port_id: usize) -> Result<(), XhciError> {
+    async fn init_device_async(&mut self, port_id: PortId) -> Result<(), XhciError> {
         println!("Addressing device at port={port_id}");
-        let slot_id = self.enable_slot_async().await?;
-
-        self.address_device_async(port_id, slot_id, false).await?;
-        // wait_for(200);
-
+        let slot_id = self.host.address(port_id).await?;
         println!("Addressing finished: port={port_id}, slot={slot_id}");
 
+        self.slots.insert(port_id, slot_id);
         self.address_device_listener.send(slot_id);
 
         Ok(())
     }
 
-    async fn enable_slot_async(&self) -> Result<usize, XhciError> {
-        let recv = push_command(Allowed::EnableSlot(EnableSlot::new()))?;
-        Ok(recv.await.unwrap().slot_id() as usize)
+    fn clear_over_current_change(&mut self, port_id: PortId) {
+        self.host.update_portsc(port_id, |p| {
+            p.clear_over_current_change();
+        });
     }
 
-    
-    async fn address_device_async(
-        &self,
-        port_id: usize,
-        slot_id: usize,
-        bsr: bool,
-    ) -> Result<(), XhciError> {
-        with_dcbaa(|d|d.init_context_at(slot_id));
-        let trf_ring_ptr = with_trf_rings(|r|r.init_ring_at(slot_id, 1));
-
-        let input_ctx = with_regs(|r|{
-            prepare_input_ctx_for_address_device(port_id, slot_id, trf_ring_ptr, with_dcbaa(|d|d.ctx_size()), r)
+    fn clear_csc(&mut self, port_id: PortId) {
+        self.host.update_portsc(port_id, |p| {
+            p.clear_connect_status_change();
+        });
+    }
+
+    fn clear_port_reset(&mut self, port_id: PortId) {
+        self.host.update_portsc(port_id, |p| {
+            p.clear_port_reset_change();
+        });
+    }
+
+    fn clear_warm_port_reset(&mut self, port_id: PortId) {
+        self.host.update_portsc(port_id, |p| {
+            p.clear_warm_port_reset_change();
+        });
+    }
+
+    fn clear_port_link_state_change(&mut self, port_id: PortId) {
+        self.host.update_portsc(port_id, |p| {
+            p.clear_port_link_state_change();
+        });
+    }
+
+    fn set_port_reset(&mut self, port_id: PortId) {
+        self.host.update_portsc(port_id, |p| {
+            p.set_port_reset();
         });
+    }
 
-        let mut trb = AddressDevice::new();
-        trb.set_input_context_pointer(input_ctx.get_address())
-            .set_slot_id(slot_id as u8);
-        if bsr {
-            trb.set_block_set_address_request();
+    fn set_warm_port_reset(&mut self, port_id: PortId) {
+        self.host.update_portsc(port_id, |p| {
+            p.set_warm_port_reset();
+        });
+    }
+}
+
+/// デバイスがどこに繋がっているか。Address Deviceでスロットコンテキストに書く
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceRoute {
+    /// 辿っていくと行き着くルートハブのポート
+    pub root_port: PortId,
+    /// 途中のハブのポート番号を4bitずつ並べたもの(xHCI 8.9)。ルートハブ直下なら0
+    pub route_string: u32,
+    /// PORTSCのPort Speedと同じ値
+    pub speed: u8,
+    /// LS/FSのデバイスがHSのハブの下にいるときの、Transaction Translatorを持つハブのスロットとポート
+    pub tt: Option<(SlotId, u8)>,
+}
+
+pub const SPEED_FULL: u8 = 1;
+pub const SPEED_LOW: u8 = 2;
+pub const SPEED_HIGH: u8 = 3;
+pub const SPEED_SUPER: u8 = 4;
+
+impl DeviceRoute {
+    /// ルートハブのポートに直接繋がったデバイス
+    pub fn root(port_id: PortId) -> Self {
+        let speed = with_regs(|r| r.port_register_set.read_volatile_at(port_id.index()).portsc.port_speed());
+        Self { root_port: port_id, route_string: 0, speed, tt: None }
+    }
+
+    /// このルートのハブ(slotに割り当て済み)のportに繋がった、speedのデバイス。
+    /// 経路文字列は5段までだが、ここではルートハブ直下のハブの下の1段だけを扱う
+    pub fn behind_hub(&self, hub_slot: SlotId, port: u8, speed: u8) -> Option<Self> {
+        if self.route_string != 0 || !(1..=15).contains(&port) {
+            return None;
         }
+        let tt = (self.speed == SPEED_HIGH && matches!(speed, SPEED_LOW | SPEED_FULL)).then_some((hub_slot, port));
+        Some(Self { root_port: self.root_port, route_string: u32::from(port), speed, tt })
+    }
+}
 
-        let result = push_command(Allowed::AddressDevice(trb))?.await.unwrap();
+/// スロットを割り当ててデバイスにアドレスを振る。失敗したらスロットを後始末する
+pub(crate) async fn address_device(route: &DeviceRoute) -> Result<SlotId, XhciError> {
+    let slot_id = enable_slot_async().await?;
+    if let Err(e) = address_device_async(route, slot_id, false).await {
+        teardown_slot(slot_id).await;
+        return Err(e);
+    }
+    Ok(slot_id)
+}
 
-        let success = result
-            .completion_code()
-            .map_or(false, |code| matches!(code, CompletionCode::Success));
+async fn enable_slot_async() -> Result<SlotId, XhciError> {
+    let recv = push_command_async(Allowed::EnableSlot(EnableSlot::new())).await.during(Operation::EnableSlot)?;
+    let slot_id = SlotId::new(recv.await.map_err(XhciError::from).during(Operation::EnableSlot)?.slot_id())
+        .ok_or(XhciError::from(ErrorKind::InvalidCommandCompletionTrb).during(Operation::EnableSlot))?;
+    with_dcbaa(|d| d.slots_mut().transition(slot_id, SlotState::Enabled)).during(Operation::EnableSlot)?;
+    Ok(slot_id)
+}
+
+async fn address_device_async(
+    route: &DeviceRoute,
+    slot_id: SlotId,
+    bsr: bool,
+) -> Result<(), XhciError> {
+    with_dcbaa(|d|d.init_context_at(slot_id));
+    let trf_ring_ptr = with_trf_rings(|r|r.init_ring_at(slot_id, Dci::CONTROL));
+
+    let input_ctx = prepare_input_ctx_for_address_device(route, trf_ring_ptr, with_dcbaa(|d|d.ctx_size()));
+
+    let mut trb = AddressDevice::new();
+    trb.set_input_context_pointer(input_ctx.get_address().as_u64())
+        .set_slot_id(slot_id.get());
+    if bsr {
+        trb.set_block_set_address_request();
+    }
 
-        if success {
-            drop(input_ctx);
-            Ok(())
-        } else {
-            Err(XhciError::AddressDeviceCommandFailed(result))
+    let result = push_command_async(Allowed::AddressDevice(trb)).await.during(Operation::AddressDevice).on_slot(slot_id)?.await.map_err(XhciError::from).during(Operation::AddressDevice).on_slot(slot_id)?;
+
+    let success = result
+        .completion_code()
+        .map_or(false, |code| matches!(code, CompletionCode::Success));
+
+    if success {
+        drop(input_ctx);
+        with_dcbaa(|d| d.slots_mut().transition(slot_id, SlotState::Addressed)).during(Operation::AddressDevice)
+    } else {
+        Err(XhciError::from(ErrorKind::CommandFailed(result)).during(Operation::AddressDevice).on_slot(slot_id))
+    }
+}
+
+/// スロットの後始末。リングを捨て(待っていたタスクにはDisconnectedが届く)、Disable Slotを発行する
+/// 既に後始末中・空のスロットなら何もしないので、何度呼んでもよい
+pub(crate) async fn teardown_slot(slot_id: SlotId) {
+    if !with_dcbaa(|d| d.slots_mut().begin_teardown(slot_id)) {
+        return;
+    }
+    with_trf_rings(|r| r.remove_slot(slot_id));
+    registry::remove(slot_id);
+
+    let mut cmd = DisableSlot::new();
+    cmd.set_slot_id(slot_id.get());
+    let result = match push_command_async(Allowed::DisableSlot(cmd)).await {
+        Ok(recv) => {
+            match recv.await {
+                Ok(c) if c.completion_code() == Ok(CompletionCode::Success) => Ok(()),
+                Ok(c) => Err(XhciError::from(ErrorKind::CommandFailed(c))),
+                Err(e) => Err(e.into()),
+            }
         }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result.during(Operation::DisableSlot).on_slot(slot_id) {
+        error!("{e}");
     }
 
+    with_dcbaa(|d| {
+        d.remove_context_at(slot_id);
+        let _ = d.slots_mut().transition(slot_id, SlotState::Empty);
+    });
 }
 
-fn clear_csc(port_id: usize) {
-    with_regs(|r|r.port_register_set.update_volatile_at(port_id, |p|{
-        let p = &mut p.portsc;
-        p.set_0_connect_status_change();
-        p.set_0_over_current_change();
-        p.set_0_port_config_error_change();
-        p.set_0_port_enabled_disabled();
-        p.set_0_port_enabled_disabled_change();
-        p.set_0_port_link_state_change();
-        p.set_0_port_reset_change();
-        p.set_0_warm_port_reset_change();
-
-        p.clear_connect_status_change();
-    }));
+/// リセット回数を記録する。上限を超えた場合はポートを故障扱いにしてfalseを返す
+fn count_reset(port_id: PortId, now: u64) -> bool {
+    with_port_stat(port_id, |s| {
+        let was_failed = s.failed;
+        let ok = s.try_reset(now, ms_to_ticks(RETRY_WINDOW_MS));
+        if s.failed && !was_failed {
+            warn!(
+                "port {port_id}: enumeration failed {MAX_RESETS_PER_WINDOW} times within {RETRY_WINDOW_MS}ms, giving up until disconnect"
+            );
+        }
+        ok
+    })
 }
 
-fn clear_port_reset(port_id: usize) {
-    with_regs(|r|r.port_register_set.update_volatile_at(port_id, |p|{
-        let p = &mut p.portsc;
-        p.set_0_connect_status_change();
-        p.set_0_over_current_change();
-        p.set_0_port_config_error_change();
-        p.set_0_port_enabled_disabled();
-        p.set_0_port_enabled_disabled_change();
-        p.set_0_port_link_state_change();
-        p.set_0_port_reset_change();
-        p.set_0_warm_port_reset_change();
-
-        p.clear_port_reset_change();
-    }));
+/// 読んだPORTSCをそのまま書き戻すと、立っているRW1Cのビット(変化ビットとPED)が全部クリアされてしまう。
+/// 書く前にそれらを0にして、fで指定したビットだけが書かれるようにする
+fn mask_rw1c(p: &mut PortStatusAndControlRegister) {
+    p.set_0_connect_status_change();
+    p.set_0_over_current_change();
+    p.set_0_port_config_error_change();
+    p.set_0_port_enabled_disabled();
+    p.set_0_port_enabled_disabled_change();
+    p.set_0_port_link_state_change();
+    p.set_0_port_reset_change();
+    p.set_0_warm_port_reset_change();
 }
 
-fn set_port_reset(port_id: usize) {
-    with_regs(|r|r.port_register_set.update_volatile_at(port_id, |p|{
+/// PORTSCを書き換える。RW1Cのビットは0にしてから書くので、fで指定したもの以外はクリアされない
+pub(crate) fn update_portsc(port_id: PortId, f: impl FnOnce(&mut PortStatusAndControlRegister)) {
+    with_regs(|r|r.port_register_set.update_volatile_at(port_id.index(), |p|{
         let p = &mut p.portsc;
-        p.set_0_connect_status_change();
-        p.set_0_over_current_change();
-        p.set_0_port_config_error_change();
-        p.set_0_port_enabled_disabled();
-        p.set_0_port_enabled_disabled_change();
-        p.set_0_port_link_state_change();
-        p.set_0_port_reset_change();
-        p.set_0_warm_port_reset_change();
-
-        p.set_port_reset();
+        mask_rw1c(p);
+        f(p);
     }));
 }
 
 fn prepare_input_ctx_for_address_device(
-    port_id: usize,
-    slot_id: usize,
-    deque_ptr: u64,
-    ctx_size: ContextSize, 
-    regs: &mut Registers<LinearMapper>
+    route: &DeviceRoute,
+    deque_ptr: PhysAddr,
+    ctx_size: ContextSize,
 ) -> InputContext {
     /* 4.3.3 Device Slot Initialization */
     let mut input_ctx = InputContext::new(ctx_size);
@@ -179,38 +702,38 @@ fn prepare_input_ctx_for_address_device(
         control.set_add_context_flag(0);
         control.set_add_context_flag(1);
     }
-    config_slot_context(input_ctx.handler_mut().device_mut().slot_mut(), port_id, regs);
+    config_slot_context(input_ctx.handler_mut().device_mut().slot_mut(), route);
     config_default_control_pipe(
         input_ctx.handler_mut().device_mut().endpoint_mut(1),
-        port_id,
+        route.speed,
         deque_ptr,
-        regs
     );
 
     input_ctx
 }
 
 
-fn config_slot_context(slot: &mut dyn SlotHandler, port_id: usize, regs: &mut Registers<LinearMapper>) {
-    let speed = regs.port_register_set.read_volatile_at(port_id).portsc.port_speed();
-    slot.set_root_hub_port_number(port_id as u8 + 1);
-    slot.set_route_string(0);
+fn config_slot_context(slot: &mut dyn SlotHandler, route: &DeviceRoute) {
+    slot.set_root_hub_port_number(route.root_port.get());
+    slot.set_route_string(route.route_string);
     slot.set_context_entries(1);
-    slot.set_speed(speed);
+    slot.set_speed(route.speed);
+    if let Some((hub_slot, port)) = route.tt {
+        slot.set_parent_hub_slot_id(hub_slot.get());
+        slot.set_parent_port_number(port);
+    }
 }
 
 fn config_default_control_pipe(
     pipe: &mut dyn EndpointHandler,
-    port_id: usize,
-    tr_deque_ptr: u64,
-    regs: &mut Registers<LinearMapper>
+    speed: u8,
+    tr_deque_ptr: PhysAddr,
 ) {
-    let speed = regs.port_register_set.read_volatile_at(port_id).portsc.port_speed();
     let max_packet_size = match speed {
-        1 => 64,  // full-speed
-        2 => 8,   // Low-speed
-        3 => 64,  // High-speed
-        4 => 512, //SuperSpeed
+        SPEED_FULL => 64,
+        SPEED_LOW => 8,
+        SPEED_HIGH => 64,
+        SPEED_SUPER => 512,
         _ => 8,
     };
 
@@ -219,7 +742,7 @@ fn config_default_control_pipe(
     pipe.set_max_burst_size(0);
 
     // xhci crate の仕様上、tr_deque_pointer を deque_cycle_state より先に設定する必要がある
-    pipe.set_tr_dequeue_pointer(tr_deque_ptr);
+    pipe.set_tr_dequeue_pointer(tr_deque_ptr.as_u64());
     pipe.set_dequeue_cycle_state();
 
     pipe.set_interval(0);
@@ -227,3 +750,204 @@ fn config_default_control_pipe(
     pipe.set_mult(0);
     pipe.set_error_count(3);
 }
+
+pub fn run_init_device_tests() {
+    // PortStatusAndControlRegisterはu32をそのまま包んだ型
+    let portsc = |raw: u32| unsafe { core::mem::transmute::<u32, PortStatusAndControlRegister>(raw) };
+    let raw = |p: PortStatusAndControlRegister| unsafe { core::mem::transmute::<PortStatusAndControlRegister, u32>(p) };
+    // PED(1)と変化ビット(17-23)
+    const RW1C: u32 = 1 << 1 | 0x7f << 17;
+
+    // 全部立っているところから1つだけクリアしても、他の変化ビットには1を書かない
+    for (bit, clear) in [
+        (17, PortStatusAndControlRegister::clear_connect_status_change as fn(&mut PortStatusAndControlRegister) -> &mut PortStatusAndControlRegister),
+        (20, PortStatusAndControlRegister::clear_over_current_change),
+        (21, PortStatusAndControlRegister::clear_port_reset_change),
+        (22, PortStatusAndControlRegister::clear_port_link_state_change),
+    ] {
+        let mut p = portsc(u32::MAX);
+        mask_rw1c(&mut p);
+        clear(&mut p);
+        assert!(raw(p) & RW1C == 1 << bit);
+    }
+    // RW1C以外(電源など)はそのまま書き戻す
+    let mut p = portsc(1 << 9 | 1 << 0 | RW1C);
+    mask_rw1c(&mut p);
+    assert!(raw(p) == 1 << 9 | 1 << 0);
+
+    // ハブの下のルート。HSのハブの下のLS/FSだけがTTを使う
+    let hub_slot = SlotId::new(4).unwrap();
+    let hub = DeviceRoute { root_port: PortId::new(2).unwrap(), route_string: 0, speed: SPEED_HIGH, tt: None };
+    let child = hub.behind_hub(hub_slot, 3, SPEED_LOW).unwrap();
+    assert!(child == DeviceRoute { root_port: hub.root_port, route_string: 3, speed: SPEED_LOW, tt: Some((hub_slot, 3)) });
+    assert!(hub.behind_hub(hub_slot, 15, SPEED_HIGH).is_some_and(|r| r.tt.is_none() && r.route_string == 15));
+    assert!(hub.behind_hub(hub_slot, 0, SPEED_FULL).is_none() && hub.behind_hub(hub_slot, 16, SPEED_FULL).is_none());
+    let fs_hub = DeviceRoute { speed: SPEED_FULL, ..hub };
+    assert!(fs_hub.behind_hub(hub_slot, 1, SPEED_LOW).is_some_and(|r| r.tt.is_none()));
+    // ハブの下のハブの下は扱わない
+    assert!(child.behind_hub(hub_slot, 1, SPEED_LOW).is_none());
+
+    let state = PortPower::from_portsc(&portsc(1 << 0 | 1 << 1 | 1 << 3 | u32::from(PLS_U3) << 5 | 1 << 9));
+    assert!(state == PortPower { powered: true, connected: true, enabled: true, suspended: true, over_current: true, link_state: "U3" });
+
+    run_flapping_tests();
+}
+
+/// 抜き差しを繰り返すUSB2のルートポートの偽物。繋がったデバイスはアドレスの割り当てに必ず失敗する
+struct FlappingPort {
+    /// (tick, 繋がっているか) の変化
+    levels: Vec<(u64, bool)>,
+    now: u64,
+    connect_change: bool,
+    reset_change: bool,
+    enabled: bool,
+    /// PRが書かれたtick
+    resets: Vec<u64>,
+    resetting: bool,
+}
+
+impl FlappingPort {
+    fn connected(&self) -> bool {
+        self.levels.iter().rev().find(|(at, _)| *at <= self.now).is_some_and(|(_, c)| *c)
+    }
+}
+
+impl PortHost for FlappingPort {
+    fn portsc(&self, _: PortId) -> PortStatusAndControlRegister {
+        let connected = self.connected();
+        let raw = u32::from(connected)
+            | u32::from(connected && self.enabled) << 1
+            | 1 << 9
+            | u32::from(self.connect_change) << 17
+            | u32::from(self.reset_change) << 21;
+        unsafe { core::mem::transmute::<u32, PortStatusAndControlRegister>(raw) }
+    }
+
+    fn update_portsc(&mut self, port_id: PortId, f: impl FnOnce(&mut PortStatusAndControlRegister)) {
+        let mut p = self.portsc(port_id);
+        mask_rw1c(&mut p);
+        f(&mut p);
+        let written = unsafe { core::mem::transmute::<PortStatusAndControlRegister, u32>(p) };
+        if written & 1 << 17 != 0 {
+            self.connect_change = false;
+        }
+        if written & 1 << 21 != 0 {
+            self.reset_change = false;
+        }
+        if written & 1 << 4 != 0 {
+            self.resets.push(self.now);
+            self.resetting = true;
+        }
+    }
+
+    fn is_usb3(&self, _: PortId) -> bool {
+        false
+    }
+
+    fn now(&self) -> u64 {
+        self.now
+    }
+
+    async fn sleep(&mut self, ticks: u64) {
+        self.now += ticks;
+    }
+
+    async fn address(&mut self, _: PortId) -> Result<SlotId, XhciError> {
+        Err(ErrorKind::Timeout(0).into())
+    }
+
+    async fn teardown(&mut self, _: SlotId) {}
+}
+
+/// 偽物のポートは待たないので、1回pollすれば終わる
+fn run_now<F: core::future::Future>(fut: F) -> F::Output {
+    let mut fut = core::pin::pin!(fut);
+    match fut.as_mut().poll(&mut core::task::Context::from_waker(futures::task::noop_waker_ref())) {
+        core::task::Poll::Ready(v) => v,
+        core::task::Poll::Pending => panic!("init_device: the fake port never waits"),
+    }
+}
+
+/// levelsの通りにポートを抜き差しし、PORTSCが変わるたびにPortStatusChangeを届けてDeviceInitActionを動かす。
+/// リセットは1tickで終わり、その間に切れていれば期限切れになる。PRが書かれたtickの列を返す
+fn drive_flapping_port(levels: &[(u64, bool)]) -> Vec<u64> {
+    let port = PortId::new(u8::MAX).unwrap();
+    let event = || {
+        let mut raw = PortStatusChange::default().into_raw();
+        raw[0] = u32::from(port.get()) << 24;
+        PortStatusChange::try_from(raw).unwrap()
+    };
+    let (_status_tx, status_rx) = new_channel("test-port-status");
+    let (address_tx, _address_rx) = new_channel("test-address");
+    let host = FlappingPort {
+        levels: levels.to_vec(),
+        now: 0,
+        connect_change: false,
+        reset_change: false,
+        enabled: false,
+        resets: Vec::new(),
+        resetting: false,
+    };
+    let mut action = DeviceInitAction::with_host(host, status_rx, address_tx);
+    let mut next = 0;
+    for _ in 0..10_000 {
+        if core::mem::take(&mut action.host.resetting) {
+            action.host.now += 1;
+            if action.host.connected() {
+                action.host.enabled = true;
+                action.host.reset_change = true;
+                run_now(action.handle(Some(event())));
+                continue;
+            }
+        }
+        let deadline = action.reset_phase.map(|(_, deadline)| deadline);
+        match levels.get(next) {
+            Some(&(at, connected)) if deadline.map_or(true, |d| at <= d) => {
+                next += 1;
+                action.host.now = action.host.now.max(at);
+                action.host.connect_change = true;
+                action.host.enabled &= connected;
+                run_now(action.handle(Some(event())));
+            }
+            _ => match deadline {
+                Some(deadline) => {
+                    action.host.now = action.host.now.max(deadline);
+                    run_now(action.handle(None));
+                }
+                None => {
+                    PORT_STATS.lock().remove(&port);
+                    return action.host.resets;
+                }
+            },
+        }
+    }
+    panic!("init_device: the port never settled");
+}
+
+fn run_flapping_tests() {
+    let window = ms_to_ticks(RETRY_WINDOW_MS);
+    let settle = ms_to_ticks(PORT_SETTLE_TIME_MS);
+    let max = MAX_RESETS_PER_WINDOW as usize;
+    // どこからwindowを測っても、その間のリセットは上限まで
+    let bounded = |resets: &[u64]| resets.iter().all(|t| resets.iter().filter(|u| (*t..t + window).contains(*u)).count() <= max);
+
+    // 繋がったままで列挙できないデバイスは、上限までリセットして諦める
+    let resets = drive_flapping_port(&[(0, true)]);
+    assert!(resets.len() == max && bounded(&resets));
+
+    // 待ち時間より速いばたつきは、落ち着く前に切れているのでリセットしない
+    let fast: Vec<(u64, bool)> = (0..100).map(|i| (i * settle / 5, i % 2 == 0)).collect();
+    assert!(drive_flapping_port(&fast).is_empty());
+
+    // 待ち時間より遅いばたつきでは、切断で故障扱いが解けても窓の中のリセットは上限まで。窓が過ぎればまた試す
+    let period = settle * 6;
+    let slow: Vec<(u64, bool)> = (0..3 * window / period).flat_map(|i| [(i * period, true), (i * period + settle * 4, false)]).collect();
+    let resets = drive_flapping_port(&slow);
+    assert!(resets.len() > max && bounded(&resets));
+
+    // 諦めたポートも、窓が過ぎてから繋ぎ直せばまたリセットする。窓の中で繋ぎ直してもリセットしない
+    let resets = drive_flapping_port(&[(0, true), (window / 2, false), (window * 2, true)]);
+    assert!(resets.len() == 2 * max && resets[max] >= window * 2 && bounded(&resets));
+    let resets = drive_flapping_port(&[(0, true), (settle * 5, false), (settle * 6, true)]);
+    assert!(resets.len() == max);
+}
@@ -0,0 +1,263 @@
+use alloc::vec::Vec;
+
+use super::mouse::MouseReport;
+
+const USAGE_PAGE_GENERIC_DESKTOP: u32 = 0x01;
+const USAGE_PAGE_BUTTON: u32 = 0x09;
+const USAGE_PAGE_CONSUMER: u32 = 0x0c;
+const USAGE_X: u32 = USAGE_PAGE_GENERIC_DESKTOP << 16 | 0x30;
+const USAGE_Y: u32 = USAGE_PAGE_GENERIC_DESKTOP << 16 | 0x31;
+const USAGE_WHEEL: u32 = USAGE_PAGE_GENERIC_DESKTOP << 16 | 0x38;
+/// 横スクロール(AC Pan)
+const USAGE_PAN: u32 = USAGE_PAGE_CONSUMER << 16 | 0x238;
+/// ボタンとして読む数。MouseReportのbuttonsに収まる分
+const MAX_BUTTONS: u32 = 8;
+/// Push/Popで積めるグローバル項目の深さ
+const MAX_PUSH: usize = 4;
+
+/// レポートの中の1つの値の位置。ビット単位で、先頭のレポートIDは含まない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub offset: u32,
+    pub size: u32,
+    /// Logical Minimumが負なら2の補数で読む
+    pub signed: bool,
+}
+
+impl Field {
+    /// reportからこの値を読む。reportが短ければNone
+    pub fn read(&self, report: &[u8]) -> Option<i32> {
+        if self.size == 0 || self.size > 32 {
+            return None;
+        }
+        let mut v = 0u64;
+        for i in 0..self.size {
+            let bit = self.offset.checked_add(i)?;
+            let byte = *report.get((bit / 8) as usize)?;
+            v |= ((byte >> (bit % 8)) as u64 & 1) << i;
+        }
+        if self.signed && v >> (self.size - 1) & 1 == 1 {
+            v |= u64::MAX << self.size;
+        }
+        Some(v as i32)
+    }
+}
+
+/// マウスのレポートのどこに何があるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseLayout {
+    /// レポートの先頭に付くID。IDを使わないデバイスならNone
+    pub report_id: Option<u8>,
+    /// ボタン1から順に並んだビット。sizeがボタンの数
+    pub buttons: Option<Field>,
+    pub x: Field,
+    pub y: Field,
+    pub wheel: Option<Field>,
+    pub pan: Option<Field>,
+}
+
+impl MouseLayout {
+    /// ブートプロトコルの(ボタン, X, Y)の3バイト。ボタンの上位5bitと4バイト目はデバイスによるが、
+    /// ほとんどのマウスはサイドボタンとホイールを置くのでそのまま読む
+    pub const BOOT: MouseLayout = MouseLayout {
+        report_id: None,
+        buttons: Some(Field { offset: 0, size: 8, signed: false }),
+        x: Field { offset: 8, size: 8, signed: true },
+        y: Field { offset: 16, size: 8, signed: true },
+        wheel: Some(Field { offset: 24, size: 8, signed: true }),
+        pan: None,
+    };
+
+    /// 受け取ったレポートを読む。別のレポートIDのものや、X, Yまで無いものはNone。ホイールが足りなければ0
+    pub fn decode(&self, report: &[u8]) -> Option<MouseReport> {
+        let body = match self.report_id {
+            Some(id) => report.strip_prefix(&[id])?,
+            None => report,
+        };
+        let buttons = match self.buttons {
+            Some(f) => f.read(body)? as u8,
+            None => 0,
+        };
+        let dx = self.x.read(body)?.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let dy = self.y.read(body)?.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let wheel = |field: Option<Field>| field.and_then(|f| f.read(body)).unwrap_or(0).clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+        Some(MouseReport::new(buttons, dx, dy, wheel(self.wheel), wheel(self.pan)))
+    }
+}
+
+/// Push/Popの対象になる、Main項目をまたいで残る値
+#[derive(Debug, Clone, Copy, Default)]
+struct Globals {
+    usage_page: u32,
+    logical_min: i32,
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+/// Main項目ごとに消える値。Usageはページを上位16bitに持つ。0ならその時のUsage Pageを使う
+#[derive(Debug, Default)]
+struct Locals {
+    usages: Vec<u32>,
+    usage_min: Option<u32>,
+    usage_max: Option<u32>,
+}
+
+impl Locals {
+    /// Input項目のn番目の値のUsage。Usageが足りなければ最後のものを繰り返す
+    fn usage(&self, n: u32, page: u32) -> Option<u32> {
+        let with_page = |u: u32| if u >> 16 == 0 { page << 16 | u } else { u };
+        if let Some(u) = self.usages.get(n as usize).or(self.usages.last().filter(|_| self.usage_min.is_none())) {
+            return Some(with_page(*u));
+        }
+        let min = self.usage_min?;
+        let u = min.checked_add(n.checked_sub(self.usages.len() as u32)?)?;
+        (u <= self.usage_max.unwrap_or(u)).then(|| with_page(u))
+    }
+}
+
+/// レポートディスクリプタを読み、マウスとして使う値の位置を探す。
+/// 最初にXが見つかったレポートIDのX, Y, ホイールとボタンを使う。Xが無ければNone
+pub fn parse_mouse_layout(desc: &[u8]) -> Option<MouseLayout> {
+    let mut globals = Globals::default();
+    let mut stack = [Globals::default(); MAX_PUSH];
+    let mut depth = 0;
+    let mut locals = Locals::default();
+    let mut uses_report_id = false;
+    // レポートIDごとに、次のInputの値が始まるビット位置
+    let mut offsets: Vec<(u8, u32)> = Vec::new();
+    // (レポートID, Usage, 位置)
+    let mut fields: Vec<(u8, u32, Field)> = Vec::new();
+
+    let mut rest = desc;
+    while let Some((&prefix, tail)) = rest.split_first() {
+        // Long項目は使わないので飛ばす。2バイト目がデータの長さ
+        if prefix == 0xfe {
+            rest = tail.get(2 + *tail.first()? as usize..)?;
+            continue;
+        }
+        let size = [0, 1, 2, 4][(prefix & 0b11) as usize];
+        let data = tail.get(..size)?;
+        rest = &tail[size..];
+        let unsigned = data.iter().rev().fold(0u32, |acc, b| acc << 8 | *b as u32);
+        let signed = match size {
+            1 => data[0] as i8 as i32,
+            2 => i16::from_le_bytes([data[0], data[1]]) as i32,
+            _ => unsigned as i32,
+        };
+
+        match (prefix >> 2 & 0b11, prefix >> 4) {
+            // Input
+            (0, 0x8) => {
+                let i = match offsets.iter().position(|(id, _)| *id == globals.report_id) {
+                    Some(i) => i,
+                    None => {
+                        offsets.push((globals.report_id, 0));
+                        offsets.len() - 1
+                    }
+                };
+                let offset = &mut offsets[i].1;
+                let constant = unsigned & 1 != 0;
+                let variable = unsigned & 2 != 0;
+                if !constant && variable {
+                    for n in 0..globals.report_count {
+                        let Some(usage) = locals.usage(n, globals.usage_page) else {
+                            continue;
+                        };
+                        let field = Field { offset: offset.saturating_add(n.saturating_mul(globals.report_size)), size: globals.report_size, signed: globals.logical_min < 0 };
+                        fields.push((globals.report_id, usage, field));
+                    }
+                }
+                *offset = offset.saturating_add(globals.report_size.saturating_mul(globals.report_count));
+                locals = Locals::default();
+            }
+            // Output, Feature, Collection, End Collection
+            (0, _) => locals = Locals::default(),
+            (1, 0x0) => globals.usage_page = unsigned & 0xffff,
+            (1, 0x1) => globals.logical_min = signed,
+            (1, 0x7) => globals.report_size = unsigned,
+            (1, 0x8) => {
+                globals.report_id = unsigned as u8;
+                uses_report_id = true;
+            }
+            (1, 0x9) => globals.report_count = unsigned,
+            (1, 0xa) => {
+                *stack.get_mut(depth)? = globals;
+                depth += 1;
+            }
+            (1, 0xb) => {
+                depth = depth.checked_sub(1)?;
+                globals = stack[depth];
+            }
+            // 4バイトのUsageは上位16bitにページを含む
+            (2, 0x0) => locals.usages.push(unsigned),
+            (2, 0x1) => locals.usage_min = Some(unsigned),
+            (2, 0x2) => locals.usage_max = Some(unsigned),
+            _ => {}
+        }
+    }
+
+    let (id, _, x) = *fields.iter().find(|(_, usage, _)| *usage == USAGE_X)?;
+    let find = |usage: u32| fields.iter().find(|(i, u, _)| *i == id && *u == usage).map(|(_, _, f)| *f);
+    let y = find(USAGE_Y)?;
+    // ボタン1から、1ビットずつ続いている所までをボタンとする
+    let buttons = find(USAGE_PAGE_BUTTON << 16 | 1).filter(|f| f.size == 1).map(|first| {
+        let count = (1..MAX_BUTTONS)
+            .take_while(|n| find(USAGE_PAGE_BUTTON << 16 | (n + 1)) == Some(Field { offset: first.offset + n, ..first }))
+            .count() as u32
+            + 1;
+        Field { offset: first.offset, size: count, signed: false }
+    });
+    Some(MouseLayout { report_id: uses_report_id.then_some(id), buttons, x, y, wheel: find(USAGE_WHEEL), pan: find(USAGE_PAN) })
+}
+
+pub fn run_hid_tests() {
+    // ブートプロトコルと同じ並びのマウス(HID 1.11 付録E.10)
+    const BOOT_MOUSE: &[u8] = &[
+        0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00,
+        0x05, 0x09, 0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02,
+        0x95, 0x01, 0x75, 0x05, 0x81, 0x01,
+        0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x02, 0x81, 0x06,
+        0xc0, 0xc0,
+    ];
+    let layout = parse_mouse_layout(BOOT_MOUSE).unwrap();
+    assert!(layout == MouseLayout { buttons: Some(Field { offset: 0, size: 3, signed: false }), wheel: None, ..MouseLayout::BOOT });
+    let r = MouseLayout::BOOT.decode(&[0b101, 0xff, 0x02]).unwrap();
+    assert!(r.buttons() == 0b101 && r.dx() == -1 && r.dy() == 2 && r.wheel() == 0);
+    // ブートプロトコルでも4バイト目のホイールとサイドボタンを読む
+    let r = MouseLayout::BOOT.decode(&[0b1_1000, 0, 0, 0xff]).unwrap();
+    assert!(r.buttons() == 0b1_1000 && r.wheel() == -1 && r.pan() == 0);
+    assert!(MouseLayout::BOOT.decode(&[0, 1]).is_none());
+
+    // レポートID 2 に5ボタン、16bitのX, Y、8bitのホイールと横スクロール。ID 1 はキーボードのもの
+    const GAMING_MOUSE: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x85, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01,
+        0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0xc0,
+        0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x85, 0x02, 0x09, 0x01, 0xa1, 0x00,
+        0x05, 0x09, 0x19, 0x01, 0x29, 0x05, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x05, 0x81, 0x02,
+        0x75, 0x03, 0x95, 0x01, 0x81, 0x03,
+        0xa4, 0x05, 0x01, 0x16, 0x01, 0x80, 0x26, 0xff, 0x7f, 0x75, 0x10, 0x95, 0x02, 0x09, 0x30, 0x09, 0x31, 0x81, 0x06, 0xb4,
+        0x05, 0x01, 0x09, 0x38, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x01, 0x81, 0x06,
+        0x05, 0x0c, 0x0a, 0x38, 0x02, 0x81, 0x06,
+        0xc0, 0xc0,
+    ];
+    let layout = parse_mouse_layout(GAMING_MOUSE).unwrap();
+    assert!(layout.report_id == Some(2) && layout.buttons == Some(Field { offset: 0, size: 5, signed: false }));
+    assert!(layout.x == Field { offset: 8, size: 16, signed: true } && layout.y.offset == 24);
+    assert!(layout.wheel == Some(Field { offset: 40, size: 8, signed: true }) && layout.pan == Some(Field { offset: 48, size: 8, signed: true }));
+    // X, YはPushとPopで囲んであり、その後のホイールは16bitではなく8bit
+    let r = layout.decode(&[2, 0b1_0001, 0x2c, 0x01, 0x00, 0xff, 0xfe, 0x01]).unwrap();
+    assert!(r.buttons() == 0b1_0001 && r.dx() == 300 && r.dy() == -256 && r.wheel() == -2 && r.pan() == 1);
+    // 別のIDのレポートは読まない
+    assert!(layout.decode(&[1, 0, 0, 0, 0, 0, 0, 0]).is_none());
+
+    // Xの無いもの、途中で切れたものは使えない
+    assert!(parse_mouse_layout(&BOOT_MOUSE[..34]).is_none());
+    assert!(parse_mouse_layout(&[0x05]).is_none());
+    // Popしすぎ
+    assert!(parse_mouse_layout(&[0xb4]).is_none());
+
+    assert!(Field { offset: 4, size: 12, signed: true }.read(&[0xf0, 0xff]) == Some(-1));
+    assert!(Field { offset: 4, size: 12, signed: false }.read(&[0xf0, 0xff]) == Some(0xfff));
+    assert!(Field { offset: 4, size: 12, signed: false }.read(&[0xf0]).is_none());
+}
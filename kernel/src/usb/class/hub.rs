@@ -0,0 +1,331 @@
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use futures::channel::oneshot;
+use xhci::ring::trb::{
+    self,
+    transfer::{self, Normal},
+};
+
+use crate::addr::ptr_to_phys;
+use crate::timer::{get_current_tick, ms_to_ticks};
+use crate::usb::{
+    action::init_device::{address_device, teardown_slot, DeviceRoute, SPEED_FULL, SPEED_HIGH, SPEED_LOW, SPEED_SUPER},
+    doorbell::{ring_endpoint, Dci, PortId, SlotId},
+    power::{track_endpoint, wait_running},
+    publish_hotplug,
+    ready::{self, Resolution},
+    ring::transfer::{ControlRequestType, SetupData},
+    runtime::{sleep, Sender},
+    usbd::UsbInterfaceAlternate,
+    xhci::{control_transfer, push_transfer_trb, with_dcbaa, with_regs, ErrorKind, Operation, XhciError},
+    HotplugEvent,
+};
+
+pub const HUB_CLASS: u8 = 9;
+/// USB2のハブディスクリプタの種類(USB 2.0 11.23.2.1)
+const DESCRIPTOR_TYPE_HUB: u8 = 0x29;
+
+/* ポートの機能選択子 (USB 2.0 表11-17) */
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+/// C_PORT_CONNECTIONからC_PORT_RESETまでは、wPortChangeのビット0から4に順に対応する
+const C_PORT_CONNECTION: u16 = 16;
+const N_CHANGE_BITS: u16 = 5;
+
+/// 接続を検知してからポートをリセットするまでに待つ時間 (USB 2.0 7.1.7.3)
+const DEBOUNCE_MS: u64 = 100;
+const RESET_TIMEOUT_MS: u64 = 500;
+/// リセットの完了を確かめる間隔
+const RESET_POLL_MS: u64 = 10;
+/// リセットが終わってからアドレスを振るまでに待つ時間 (TRSTRCY)
+const RESET_RECOVERY_MS: u64 = 10;
+
+/// ハブディスクリプタのうち使うところ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HubInfo {
+    pub ports: u8,
+    /// wHubCharacteristicsのTT Think Time。スロットコンテキストにそのまま書く
+    pub tt_think_time: u8,
+    /// ポートの電源を入れてから安定するまでの時間
+    pub power_on_ms: u64,
+}
+
+impl HubInfo {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 7 || buf[1] != DESCRIPTOR_TYPE_HUB {
+            return None;
+        }
+        let characteristics = u16::from_le_bytes([buf[3], buf[4]]);
+        Some(Self {
+            ports: buf[2],
+            tt_think_time: ((characteristics >> 5) & 0b11) as u8,
+            power_on_ms: u64::from(buf[5]) * 2,
+        })
+    }
+}
+
+/// GetPortStatusで読んだwPortStatusとwPortChange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PortStatus {
+    status: u16,
+    change: u16,
+}
+
+impl PortStatus {
+    fn from_bytes(b: [u8; 4]) -> Self {
+        Self { status: u16::from_le_bytes([b[0], b[1]]), change: u16::from_le_bytes([b[2], b[3]]) }
+    }
+
+    fn connected(&self) -> bool {
+        self.status & 1 != 0
+    }
+
+    fn enabled(&self) -> bool {
+        self.status & 1 << 1 != 0
+    }
+
+    fn resetting(&self) -> bool {
+        self.status & 1 << 4 != 0
+    }
+
+    fn connection_changed(&self) -> bool {
+        self.change & 1 != 0
+    }
+
+    /// 繋がっているデバイスの速さ。値はPORTSCのPort Speedと同じ
+    fn speed(&self) -> u8 {
+        if self.status & 1 << 9 != 0 {
+            SPEED_LOW
+        } else if self.status & 1 << 10 != 0 {
+            SPEED_HIGH
+        } else {
+            SPEED_FULL
+        }
+    }
+}
+
+/// ステータス変化のビットマップでportのビットが立っているか。ビット0はハブ自身
+fn port_changed(bitmap: &[u8], port: u8) -> bool {
+    bitmap.get(usize::from(port / 8)).is_some_and(|b| b >> (port % 8) & 1 == 1)
+}
+
+/// USB2のハブ。ルートハブ直下のものだけを扱い、下のポートに繋がったデバイスにアドレスを振る
+pub struct HubClass {
+    slot_id: SlotId,
+    /// ステータス変化を知らせるInterrupt INエンドポイント
+    dci: Dci,
+    info: HubInfo,
+    route: DeviceRoute,
+    /// ポート番号ごとの、アドレスを振ったデバイスのスロット
+    children: BTreeMap<u8, SlotId>,
+}
+
+impl HubClass {
+    /// スロットのデバイスがこのドライバで扱えるハブか。ハブの下のハブとUSB3のハブは扱わない
+    pub fn supported(slot_id: SlotId) -> bool {
+        let route = Self::route_of(slot_id);
+        route.is_some_and(|r| r.route_string == 0 && r.speed != SPEED_SUPER)
+    }
+
+    fn route_of(slot_id: SlotId) -> Option<DeviceRoute> {
+        with_dcbaa(|d| {
+            let slot = d.get_context_at(slot_id).handler().slot();
+            Some(DeviceRoute {
+                root_port: PortId::new(slot.root_hub_port_number())?,
+                route_string: slot.route_string(),
+                speed: slot.speed(),
+                tt: None,
+            })
+        })
+    }
+
+    /// ハブディスクリプタを読む。設定する前でも読める
+    pub async fn read_descriptor(slot_id: SlotId) -> Result<HubInfo, XhciError> {
+        let mut buf = Box::new([0u8; 9]);
+        let setup = SetupData {
+            request_type: ControlRequestType::GetHubDescriptor,
+            value: u16::from(DESCRIPTOR_TYPE_HUB) << 8,
+            index: 0,
+            length: 9,
+        };
+        let done = control_transfer(slot_id, Operation::ReadDescriptor, setup, Some(buf.as_mut())).await?;
+        HubInfo::parse(done.valid(buf.as_ref()))
+            .ok_or(XhciError::from(ErrorKind::UnexpectedDescriptor).during(Operation::ReadDescriptor).on_slot(slot_id))
+    }
+
+    pub fn new(slot_id: SlotId, interface: &UsbInterfaceAlternate, info: HubInfo) -> Option<Self> {
+        let dci = interface.endpoints().iter().find(|e| e.is_in() && e.is_interrupt()).and_then(|e| e.calc_dci());
+        Some(Self {
+            slot_id,
+            dci: dci?,
+            info,
+            route: Self::route_of(slot_id)?,
+            children: BTreeMap::new(),
+        })
+    }
+
+    /// 全てのポートの電源を入れ、下のデバイスにアドレスを振ったらaddress_device_listenerに知らせ続ける。
+    /// ハブが外されたら、下のデバイスも外れたものとして後始末して終わる
+    pub async fn run(mut self, address_device_listener: Sender<SlotId>) -> Result<(), XhciError> {
+        for port in 1..=self.info.ports {
+            self.port_request(ControlRequestType::SetPortFeature, PORT_POWER, port).await?;
+        }
+        sleep(ms_to_ticks(self.info.power_on_ms)).await;
+
+        // 電源を入れた時点で繋がっていたデバイスも、起動時のデバイスとして揃うのを待たせる
+        let mut connected = Vec::new();
+        for port in 1..=self.info.ports {
+            if self.port_status(port).await?.connected() {
+                connected.push(port);
+            }
+        }
+        ready::expect_more(connected.len());
+        for port in connected {
+            if let Err(e) = self.on_port_change(port, &address_device_listener).await {
+                error!("hub slot {}: port {port}: {e}", self.slot_id);
+                ready::resolve(Resolution::Failed(None));
+            }
+        }
+
+        {
+            let _active = track_endpoint(self.slot_id, self.dci);
+            loop {
+                wait_running().await;
+                // ハブのスロットが後始末されていれば投入できない
+                let Ok((recv, bitmap)) = self.subscribe_once() else {
+                    break;
+                };
+                match recv.await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) if e.is_disconnected() => break,
+                    Ok(Err(_)) => continue,
+                    Err(_) => break,
+                }
+                for port in (1..=self.info.ports).filter(|p| port_changed(bitmap.as_ref(), *p)) {
+                    if let Err(e) = self.on_port_change(port, &address_device_listener).await {
+                        error!("hub slot {}: port {port}: {e}", self.slot_id);
+                    }
+                }
+            }
+        }
+
+        println!("hub slot {}: removed", self.slot_id);
+        for (_, slot) in core::mem::take(&mut self.children) {
+            publish_hotplug(HotplugEvent::Detached { slot });
+            teardown_slot(slot).await;
+        }
+        Ok(())
+    }
+
+    async fn on_port_change(&mut self, port: u8, address_device_listener: &Sender<SlotId>) -> Result<(), XhciError> {
+        let status = self.port_status(port).await?;
+        self.clear_changes(port, status).await?;
+
+        // 繋ぎ直されたら、前のデバイスは外れている
+        if status.connection_changed() || !status.connected() {
+            if let Some(slot) = self.children.remove(&port) {
+                publish_hotplug(HotplugEvent::Detached { slot });
+                teardown_slot(slot).await;
+            }
+        }
+        if !status.connected() || self.children.contains_key(&port) {
+            return Ok(());
+        }
+
+        sleep(ms_to_ticks(DEBOUNCE_MS)).await;
+        let status = self.reset_port(port).await?;
+        if !status.enabled() {
+            return Ok(());
+        }
+        sleep(ms_to_ticks(RESET_RECOVERY_MS)).await;
+
+        let Some(route) = self.route.behind_hub(self.slot_id, port, status.speed()) else {
+            warn!("hub slot {}: port {port} cannot be addressed", self.slot_id);
+            return Ok(());
+        };
+        println!("Addressing device at hub slot={}, port={port}", self.slot_id);
+        let slot_id = address_device(&route).await?;
+        println!("Addressing finished: hub slot={}, port={port}, slot={slot_id}", self.slot_id);
+        self.children.insert(port, slot_id);
+        address_device_listener.send(slot_id);
+        Ok(())
+    }
+
+    /// ポートをリセットし、終わった後の状態を返す
+    async fn reset_port(&self, port: u8) -> Result<PortStatus, XhciError> {
+        self.port_request(ControlRequestType::SetPortFeature, PORT_RESET, port).await?;
+        let deadline = get_current_tick() + ms_to_ticks(RESET_TIMEOUT_MS);
+        loop {
+            sleep(ms_to_ticks(RESET_POLL_MS)).await;
+            let status = self.port_status(port).await?;
+            if !status.resetting() {
+                self.clear_changes(port, status).await?;
+                return Ok(status);
+            }
+            if get_current_tick() >= deadline {
+                return Err(XhciError::from(ErrorKind::Timeout(RESET_TIMEOUT_MS)).during(Operation::HubPort).on_slot(self.slot_id));
+            }
+        }
+    }
+
+    /// 立っている変化ビットを全て下ろす。残っているとステータス変化が届き続ける
+    async fn clear_changes(&self, port: u8, status: PortStatus) -> Result<(), XhciError> {
+        for bit in (0..N_CHANGE_BITS).filter(|b| status.change >> b & 1 == 1) {
+            self.port_request(ControlRequestType::ClearPortFeature, C_PORT_CONNECTION + bit, port).await?;
+        }
+        Ok(())
+    }
+
+    async fn port_request(&self, request_type: ControlRequestType, feature: u16, port: u8) -> Result<(), XhciError> {
+        let setup = SetupData { request_type, value: feature, index: u16::from(port), length: 0 };
+        control_transfer(self.slot_id, Operation::HubPort, setup, None).await?;
+        Ok(())
+    }
+
+    async fn port_status(&self, port: u8) -> Result<PortStatus, XhciError> {
+        let mut buf = Box::new([0u8; 4]);
+        let setup = SetupData { request_type: ControlRequestType::GetPortStatus, value: 0, index: u16::from(port), length: 4 };
+        control_transfer(self.slot_id, Operation::HubPort, setup, Some(buf.as_mut())).await?;
+        Ok(PortStatus::from_bytes(*buf))
+    }
+
+    /// ステータス変化のビットマップを1回受け取る
+    fn subscribe_once(
+        &self,
+    ) -> Result<
+        (
+            oneshot::Receiver<Result<trb::event::TransferEvent, XhciError>>,
+            Box<[u8; 32]>,
+        ),
+        XhciError,
+    > {
+        let mut trb = Normal::new();
+        let buf: Box<[u8; 32]> = Box::new([0; 32]);
+        trb.set_interrupt_on_completion()
+            .set_data_buffer_pointer(ptr_to_phys(buf.as_ptr()).as_u64())
+            .set_trb_transfer_length(u32::from(self.info.ports) / 8 + 1);
+        let recv = push_transfer_trb(self.slot_id, self.dci, transfer::Allowed::Normal(trb))?.unwrap();
+        with_regs(|r|ring_endpoint(r, self.slot_id, self.dci));
+        Ok((recv, buf))
+    }
+}
+
+pub fn run_hub_tests() {
+    // 4ポート、個別の電源切り替え、TT Think Time=2、電源安定まで100ms
+    let desc = [9, DESCRIPTOR_TYPE_HUB, 4, 0b0100_0001, 0, 50, 100, 0, 0xff];
+    assert!(HubInfo::parse(&desc) == Some(HubInfo { ports: 4, tt_think_time: 2, power_on_ms: 100 }));
+    assert!(HubInfo::parse(&desc[..6]).is_none());
+    assert!(HubInfo::parse(&[9, 0x2a, 4, 0, 0, 50, 100]).is_none());
+
+    // 接続・有効・LS、接続の変化あり
+    let s = PortStatus::from_bytes([0b0000_0011, 0b0000_0010, 1, 0]);
+    assert!(s.connected() && s.enabled() && !s.resetting() && s.connection_changed() && s.speed() == SPEED_LOW);
+    let s = PortStatus::from_bytes([0b0001_0001, 0b0000_0100, 0, 0]);
+    assert!(s.resetting() && !s.enabled() && !s.connection_changed() && s.speed() == SPEED_HIGH);
+    assert!(PortStatus::from_bytes([1, 0, 0x10, 0]).speed() == SPEED_FULL);
+
+    // ビット0はハブ自身
+    let bitmap = [0b1000_0101, 0b0000_0010];
+    let changed: Vec<u8> = (1..=15).filter(|p| port_changed(&bitmap, *p)).collect();
+    assert!(changed == [2, 7, 9] && port_changed(&bitmap, 0));
+    assert!(!port_changed(&bitmap, 40));
+}
@@ -5,10 +5,15 @@ pub enum Modifier {
     RCtrl, RShift, RAlt, RGui, 
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct ModifierSet(u8);
 
 impl ModifierSet {
+    /// レポートの修飾キーのバイトから作る
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
     pub fn get(&self) -> Vec<Modifier>{
         let mut v = Vec::with_capacity(2);
         if self.l_ctrl() {
@@ -62,4 +67,176 @@ impl ModifierSet {
     pub fn r_gui(&self) -> bool {
         self.0 >> 7 & 1 == 1
     }
-}
\ No newline at end of file
+}
+impl ModifierSet {
+    /// 左右どちらかのShiftが押されている。Caps Lockは含まない
+    pub fn is_shifted(&self) -> bool {
+        self.l_shift() || self.r_shift()
+    }
+    pub fn alt(&self) -> bool {
+        self.l_alt() || self.r_alt()
+    }
+    pub fn ctrl(&self) -> bool {
+        self.l_ctrl() || self.r_ctrl()
+    }
+    pub fn gui(&self) -> bool {
+        self.l_gui() || self.r_gui()
+    }
+}
+
+pub const KEY_NUM_LOCK: u8 = 0x53;
+pub const KEY_CAPS_LOCK: u8 = 0x39;
+pub const KEY_SCROLL_LOCK: u8 = 0x47;
+
+/// ロックキーの状態。ビット配置はLEDの出力レポートと同じ
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockState(u8);
+
+impl LockState {
+    const NUM: u8 = 1 << 0;
+    const CAPS: u8 = 1 << 1;
+    const SCROLL: u8 = 1 << 2;
+
+    pub fn num_lock(&self) -> bool {
+        self.0 & Self::NUM != 0
+    }
+    pub fn caps_lock(&self) -> bool {
+        self.0 & Self::CAPS != 0
+    }
+    pub fn scroll_lock(&self) -> bool {
+        self.0 & Self::SCROLL != 0
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// LED出力レポートの1バイト
+    pub fn led_report(&self) -> u8 {
+        self.0
+    }
+
+    fn toggle(&mut self, keycode: u8) -> bool {
+        let bit = match keycode {
+            KEY_NUM_LOCK => Self::NUM,
+            KEY_CAPS_LOCK => Self::CAPS,
+            KEY_SCROLL_LOCK => Self::SCROLL,
+            _ => return false,
+        };
+        self.0 ^= bit;
+        true
+    }
+}
+
+/// キーコードを文字に変換する。ロックキーの状態を保持する
+#[derive(Debug, Default)]
+pub struct Keymap {
+    locks: LockState,
+    prev_keys: [u8; 6],
+}
+
+impl Keymap {
+    pub fn locks(&self) -> LockState {
+        self.locks
+    }
+
+    /// 新たに押されたロックキーの状態を切り替える。状態が変わった場合は新しい状態を返す
+    pub fn update(&mut self, keycodes: &[u8; 6]) -> Option<LockState> {
+        let mut changed = false;
+        for key in keycodes.iter().filter(|k| **k != 0 && !self.prev_keys.contains(k)) {
+            changed |= self.locks.toggle(*key);
+        }
+        self.prev_keys = *keycodes;
+        changed.then_some(self.locks)
+    }
+
+    /// キーが押されたのと同じようにロック状態を切り替える。ロックキーでなければNone
+    pub fn toggle(&mut self, keycode: u8) -> Option<LockState> {
+        self.locks.toggle(keycode).then_some(self.locks)
+    }
+
+    pub fn translate(&self, keycode: u8, modifier: ModifierSet) -> Option<char> {
+        translate(keycode, modifier.is_shifted(), self.locks)
+    }
+}
+
+pub fn translate(keycode: u8, shift: bool, locks: LockState) -> Option<char> {
+    const DIGITS: &[u8; 10] = b"1234567890";
+    const DIGITS_SHIFTED: &[u8; 10] = b"!@#$%^&*()";
+    const SYMBOLS: &[u8; 11] = b"-=[]\\\0;'`,.";
+    const SYMBOLS_SHIFTED: &[u8; 11] = b"_+{}|\0:\"~<>";
+    const KEYPAD_DIGITS: &[u8; 10] = b"1234567890";
+
+    let c = match keycode {
+        0x04..=0x1d => {
+            let c = b'a' + (keycode - 0x04);
+            // Caps LockはShiftと打ち消し合う
+            if shift != locks.caps_lock() {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        }
+        0x1e..=0x27 => {
+            let i = (keycode - 0x1e) as usize;
+            if shift { DIGITS_SHIFTED[i] } else { DIGITS[i] }
+        }
+        0x28 => b'\n',
+        0x2a => 0x08,
+        0x2b => b'\t',
+        0x2c => b' ',
+        0x2d..=0x37 => {
+            let i = (keycode - 0x2d) as usize;
+            if shift { SYMBOLS_SHIFTED[i] } else { SYMBOLS[i] }
+        }
+        0x38 => if shift { b'?' } else { b'/' },
+        0x54 => b'/',
+        0x55 => b'*',
+        0x56 => b'-',
+        0x57 => b'+',
+        0x58 => b'\n',
+        // Num Lockが無効のときテンキーはカーソルキーとして扱い、文字にはしない
+        0x59..=0x62 if locks.num_lock() => KEYPAD_DIGITS[(keycode - 0x59) as usize],
+        0x63 if locks.num_lock() => b'.',
+        _ => return None,
+    };
+    if c == 0 {
+        None
+    } else {
+        Some(c as char)
+    }
+}
+
+pub fn run_keymap_tests() {
+    let caps = LockState(LockState::CAPS);
+    let none = LockState::default();
+    assert!(translate(0x04, false, none) == Some('a'));
+    assert!(translate(0x04, true, none) == Some('A'));
+    assert!(translate(0x04, false, caps) == Some('A'));
+    assert!(translate(0x04, true, caps) == Some('a'));
+    // Caps Lockは英字以外には影響しない
+    assert!(translate(0x1e, false, caps) == Some('1'));
+    assert!(translate(0x1e, true, caps) == Some('!'));
+    assert!(translate(0x2d, true, none) == Some('_'));
+
+    let num = LockState(LockState::NUM);
+    assert!(translate(0x59, false, num) == Some('1'));
+    assert!(translate(0x62, false, num) == Some('0'));
+    assert!(translate(0x59, false, none).is_none());
+    assert!(translate(0x55, false, none) == Some('*'));
+
+    let mut keymap = Keymap::default();
+    assert!(keymap.update(&[KEY_CAPS_LOCK, 0, 0, 0, 0, 0]).map(|l| l.led_report()) == Some(0b010));
+    // 押しっぱなしでは切り替わらない
+    assert!(keymap.update(&[KEY_CAPS_LOCK, 0x04, 0, 0, 0, 0]).is_none());
+    assert!(keymap.update(&[0; 6]).is_none());
+    assert!(keymap.update(&[KEY_NUM_LOCK, 0, 0, 0, 0, 0]).map(|l| l.led_report()) == Some(0b011));
+    assert!(keymap.update(&[KEY_SCROLL_LOCK, 0, 0, 0, 0, 0]).map(|l| l.led_report()) == Some(0b111));
+    assert!(keymap.update(&[0; 6]).is_none());
+    assert!(keymap.update(&[KEY_CAPS_LOCK, KEY_NUM_LOCK, 0, 0, 0, 0]).map(|l| l.led_report()) == Some(0b100));
+    assert!(keymap.locks().scroll_lock() && !keymap.locks().caps_lock() && !keymap.locks().num_lock());
+    assert!(keymap.toggle(KEY_CAPS_LOCK).map(|l| l.led_report()) == Some(0b110));
+    assert!(keymap.toggle(0x04).is_none());
+    // 切り替えた後も、押されているキーの記録には影響しない
+    assert!(keymap.update(&[KEY_CAPS_LOCK, 0, 0, 0, 0, 0]).map(|l| l.led_report()) == Some(0b100));
+}
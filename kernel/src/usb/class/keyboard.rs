@@ -4,13 +4,15 @@ use xhci::ring::trb::{
     transfer::{self, Normal},
 };
 
+use crate::addr::ptr_to_phys;
 use crate::usb::{
-    ring::transfer::{ControlRequestType, SetupData}, usbd::{Descriptor, UsbInterfaceAlternate}, xhci::{control_request, push_transfer_trb, with_regs, XhciError}
+    doorbell::{ring_endpoint, Dci, SlotId},
+    ring::transfer::{ControlRequestType, SetupData}, usbd::UsbInterfaceAlternate, xhci::{control_transfer, push_transfer_trb, recover_endpoint, with_regs, ErrorContext, ErrorKind, Operation, XhciError}
 };
 
 use alloc::boxed::Box;
 
-use super::key::ModifierSet;
+use super::key::{Keymap, LockState, ModifierSet};
 
 #[repr(C)]
 #[derive(Debug, Default, Clone)]
@@ -21,18 +23,23 @@ pub struct KeyReport {
 }
 
 pub struct KeyboardClass {
-    slot_id: usize,
+    slot_id: SlotId,
     interface: u8,
-    dci: usize,
+    dci: Dci,
+    /// LED出力レポート用のInterrupt OUTエンドポイント
+    out_dci: Option<Dci>,
+    keymap: Keymap,
 }
 
 impl KeyboardClass {
-    pub fn new(slot_id: usize, interface: &UsbInterfaceAlternate) -> Option<Self> {
+    pub fn new(slot_id: SlotId, interface: &UsbInterfaceAlternate) -> Option<Self> {
         let mut dci = None;
+        let mut out_dci = None;
         for desc in interface.endpoints() {
-            if let Descriptor::Endpoint(desc) = desc {
-                dci = Some(desc.calc_dci());
-                break;
+            if desc.is_in() {
+                dci = dci.or(desc.calc_dci());
+            } else if desc.is_interrupt() {
+                out_dci = out_dci.or(desc.calc_dci());
             }
         }
 
@@ -40,6 +47,8 @@ impl KeyboardClass {
             slot_id,
             interface: interface.interface_num(),
             dci: dci?,
+            out_dci,
+            keymap: Keymap::default(),
         })
     }
 
@@ -51,11 +60,86 @@ impl KeyboardClass {
             index: self.interface as u16,
             length: 0,
         };
-        control_request(self.slot_id, setup, None)?.await.unwrap()?;
+        control_transfer(self.slot_id, Operation::SetProtocol, setup, None).await?;
 
+        /* キーに変化があったときだけレポートを送らせる */
+        let setup = SetupData {
+            request_type: ControlRequestType::SetIdle,
+            value: 0,
+            index: self.interface as u16,
+            length: 0,
+        };
+        control_transfer(self.slot_id, Operation::SetIdle, setup, None).await?;
+
+        Ok(())
+    }
+
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
+
+    /// 受け取ったレポートでロックキーの状態を更新し、変化があればLEDに反映する
+    pub async fn on_report(&mut self, report: &KeyReport) -> Result<(), XhciError> {
+        if let Some(locks) = self.keymap.update(&report.keycodes) {
+            self.set_leds(locks).await?;
+        }
+        Ok(())
+    }
+
+    /// ロックキーが押されたのと同じように状態を切り替え、LEDに反映する
+    pub async fn toggle_lock(&mut self, keycode: u8) -> Result<(), XhciError> {
+        if let Some(locks) = self.keymap.toggle(keycode) {
+            self.set_leds(locks).await?;
+        }
         Ok(())
     }
 
+    /// LED出力レポートを送る。Interrupt OUTエンドポイントがなければSET_REPORTを使う
+    /// SET_REPORTをSTALLするキーボードも多い。止まったエンドポイントは戻してからエラーを返すので、次の変更はまた送れる
+    pub async fn set_leds(&self, locks: LockState) -> Result<(), XhciError> {
+        let mut buf: Box<[u8; 1]> = Box::new([locks.led_report()]);
+
+        let dci = self.out_dci.unwrap_or(Dci::CONTROL);
+        let result = match self.out_dci {
+            Some(out_dci) => self.send_led_report(out_dci, &buf).await,
+            None => {
+                let setup = SetupData {
+                    request_type: ControlRequestType::SetReport,
+                    value: 0x0200, // Report Type = 2 (Output), Report ID = 0
+                    index: self.interface as u16,
+                    length: 1,
+                };
+                control_transfer(self.slot_id, Operation::SetReport, setup, Some(buf.as_mut_slice())).await.map(|_| ())
+            }
+        };
+        if let Err(e) = &result {
+            if e.halts_endpoint() {
+                recover_endpoint(self.slot_id, dci, e.is_stall()).await?;
+            }
+        }
+        result
+    }
+
+    /// Interrupt OUTエンドポイントにLED出力レポートを送る
+    async fn send_led_report(&self, out_dci: Dci, buf: &[u8; 1]) -> Result<(), XhciError> {
+        let mut trb = Normal::new();
+        trb.set_interrupt_on_completion()
+            .set_data_buffer_pointer(ptr_to_phys(buf.as_ptr()).as_u64())
+            .set_trb_transfer_length(1);
+        let recv = push_transfer_trb(self.slot_id, out_dci, transfer::Allowed::Normal(trb))
+            .during(Operation::SetReport).on_slot(self.slot_id)?
+            // IOCを立てたTRBなので、完了を待つ受け口が必ずある
+            .ok_or_else(|| XhciError::from(ErrorKind::InvalidTrb).during(Operation::SetReport).on_slot(self.slot_id).on_endpoint(out_dci))?;
+        with_regs(|r|ring_endpoint(r, self.slot_id, out_dci));
+        recv.await?.during(Operation::SetReport)?;
+        Ok(())
+    }
+
+    /// レポートを受け取るInterrupt INエンドポイント
+    pub fn dci(&self) -> Dci {
+        self.dci
+    }
+
     pub fn subscribe_once(
         &self,
     ) -> Result<
@@ -68,10 +152,10 @@ impl KeyboardClass {
         let mut trb = Normal::new();
         let buf: Box<KeyReport> = Box::default();
         trb.set_interrupt_on_completion()
-            .set_data_buffer_pointer(buf.as_ref() as *const KeyReport as u64)
+            .set_data_buffer_pointer(ptr_to_phys(buf.as_ref() as *const KeyReport).as_u64())
             .set_trb_transfer_length(8);
         let recv = push_transfer_trb(self.slot_id, self.dci, transfer::Allowed::Normal(trb))?.unwrap();
-        with_regs(|r|r.doorbell.update_volatile_at(self.slot_id, |d|{d.set_doorbell_target(self.dci as u8);}));
+        with_regs(|r|ring_endpoint(r, self.slot_id, self.dci));
         Ok((recv, buf))
     }
 }
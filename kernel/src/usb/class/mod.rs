@@ -1,3 +1,5 @@
+pub mod hid;
 pub mod mouse;
 pub mod keyboard;
-pub mod key;
\ No newline at end of file
+pub mod key;
+pub mod hub;
\ No newline at end of file
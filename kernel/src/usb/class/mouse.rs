@@ -4,68 +4,139 @@ use xhci::ring::trb::{
     transfer::{self, Normal},
 };
 
+use crate::addr::ptr_to_phys;
 use crate::usb::{
-    ring::transfer::{ControlRequestType, SetupData}, usbd::{Descriptor, UsbInterfaceAlternate}, xhci::{control_request, push_transfer_trb, with_regs, XhciError}
+    class::hid::{parse_mouse_layout, MouseLayout},
+    doorbell::{ring_endpoint, Dci, SlotId},
+    ring::transfer::{ControlRequestType, SetupData}, usbd::UsbInterfaceAlternate, xhci::{control_transfer, push_transfer_trb, with_regs, Operation, XhciError}
 };
 
 use alloc::boxed::Box;
 
-#[repr(C)]
+/// レポートの最大の長さ。これより長いレポートの後ろは読まない
+const MAX_REPORT_LEN: usize = 64;
+/// HIDクラスのディスクリプタ種別
+const REPORT_DESCRIPTOR: u16 = 0x22;
+const BOOT_PROTOCOL: u16 = 0;
+const REPORT_PROTOCOL: u16 = 1;
+
+/// レポートをデバイスの並びから読み直したもの
 #[derive(Debug, Default, Clone)]
 pub struct MouseReport {
     buttons: u8,
-    dx: i8,
-    dy: i8,
+    dx: i16,
+    dy: i16,
+    wheel: i8,
+    pan: i8,
 }
 
 impl MouseReport {
-    pub fn dx(&self) -> i8 {
+    pub fn new(buttons: u8, dx: i16, dy: i16, wheel: i8, pan: i8) -> Self {
+        Self { buttons, dx, dy, wheel, pan }
+    }
+
+    pub fn dx(&self) -> i16 {
         self.dx
     }
 
-    pub fn dy(&self) -> i8 {
+    pub fn dy(&self) -> i16 {
         self.dy
     }
 
+    /// ホイールの回転。奥へ回すと正。ホイールが無ければ0
+    pub fn wheel(&self) -> i8 {
+        self.wheel
+    }
+
+    /// 横スクロール。右が正。無ければ0
+    pub fn pan(&self) -> i8 {
+        self.pan
+    }
+
+    /// 押されているボタン。bit 0から左、右、中、戻る、進む
     pub fn buttons(&self) -> u8 {
         self.buttons
     }
 }
 
 pub struct MouseClass {
-    slot_id: usize,
+    slot_id: SlotId,
     interface: u8,
-    dci: usize,
+    dci: Dci,
+    max_packet: u16,
+    /// HIDディスクリプタにあるレポートディスクリプタの長さ。無ければ0
+    report_desc_len: u16,
+    layout: MouseLayout,
+    /// ブートインターフェース(サブクラス1)か
+    boot: bool,
 }
 
 impl MouseClass {
-    pub fn new(slot_id: usize, interface: &UsbInterfaceAlternate) -> Option<Self> {
-        let mut dci = None;
-        for desc in interface.endpoints() {
-            if let Descriptor::Endpoint(desc) = desc {
-                dci = Some(desc.calc_dci());
-                break;
-            }
-        }
+    pub fn new(slot_id: SlotId, interface: &UsbInterfaceAlternate) -> Option<Self> {
+        let ep = interface.endpoints().first()?;
 
         Some(Self {
             slot_id,
             interface: interface.interface_num(),
-            dci: dci?,
+            dci: ep.calc_dci()?,
+            max_packet: ep.max_packet_size(),
+            report_desc_len: interface.hid().map_or(0, |hid| hid.class_descriptor_length()),
+            layout: MouseLayout::BOOT,
+            boot: interface.subclass() == 1,
         })
     }
 
-    pub async fn initialize(&self) -> Result<(), XhciError> {
-        /* set boot protocol */
+    /// レポートディスクリプタを読めればその並びのレポートプロトコルで、読めなければブートプロトコルで使う。
+    /// ブートインターフェースでなく、レポートにX, Yも無ければマウスではないのでfalse
+    pub async fn initialize(&mut self) -> Result<bool, XhciError> {
+        let layout = self.read_layout().await?;
+        if layout.is_none() && !self.boot {
+            return Ok(false);
+        }
+        let protocol = if layout.is_some() { REPORT_PROTOCOL } else { BOOT_PROTOCOL };
         let setup = SetupData {
             request_type: ControlRequestType::SetProtocol,
-            value: 0,
+            value: protocol,
             index: self.interface as u16,
             length: 0,
         };
-        control_request(self.slot_id, setup, None)?.await.unwrap()?;
+        match control_transfer(self.slot_id, Operation::SetProtocol, setup, None).await {
+            Ok(_) => {}
+            // ブートプロトコルに対応しないマウスはSET_PROTOCOLを受け付けないが、はじめからレポートプロトコルで動いている
+            Err(e) if layout.is_some() && !e.is_disconnected() => {}
+            Err(e) => return Err(e),
+        }
+        self.layout = layout.unwrap_or(MouseLayout::BOOT);
+
+        Ok(true)
+    }
+
+    /// レポートディスクリプタを読んで並びを調べる。読めない、またはX, Yが見つからなければNone
+    async fn read_layout(&self) -> Result<Option<MouseLayout>, XhciError> {
+        if self.report_desc_len == 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; usize::from(self.report_desc_len)];
+        let setup = SetupData {
+            request_type: ControlRequestType::GetInterfaceDescriptor,
+            value: REPORT_DESCRIPTOR << 8,
+            index: self.interface as u16,
+            length: self.report_desc_len,
+        };
+        match control_transfer(self.slot_id, Operation::ReadDescriptor, setup, Some(&mut buf)).await {
+            Ok(done) => Ok(parse_mouse_layout(done.valid(&buf))),
+            Err(e) if e.is_disconnected() => Err(e),
+            Err(_) => Ok(None),
+        }
+    }
 
-        Ok(())
+    pub fn layout(&self) -> &MouseLayout {
+        &self.layout
+    }
+
+    /// レポートを受け取るInterrupt INエンドポイント
+    pub fn dci(&self) -> Dci {
+        self.dci
     }
 
     pub fn subscribe_once(
@@ -73,17 +144,31 @@ impl MouseClass {
     ) -> Result<
         (
             oneshot::Receiver<Result<trb::event::TransferEvent, XhciError>>,
-            Box<MouseReport>,
+            Box<[u8; MAX_REPORT_LEN]>,
         ),
         XhciError,
     > {
         let mut trb = Normal::new();
-        let buf: Box<MouseReport> = Box::default();
+        let buf: Box<[u8; MAX_REPORT_LEN]> = Box::new([0; MAX_REPORT_LEN]);
         trb.set_interrupt_on_completion()
-            .set_data_buffer_pointer(buf.as_ref() as *const MouseReport as u64)
-            .set_trb_transfer_length(8);
+            .set_data_buffer_pointer(ptr_to_phys(buf.as_ptr()).as_u64())
+            .set_trb_transfer_length(self.report_len() as u32);
         let recv = push_transfer_trb(self.slot_id, self.dci, transfer::Allowed::Normal(trb))?.unwrap();
-        with_regs(|r|r.doorbell.update_volatile_at(self.slot_id, |d|{d.set_doorbell_target(self.dci as u8);}));
+        with_regs(|r|ring_endpoint(r, self.slot_id, self.dci));
         Ok((recv, buf))
     }
+
+    /// subscribe_onceで受け取ったbufを読む。他のレポートIDのものや短すぎるものはNone
+    pub fn decode(&self, buf: &[u8], event: &trb::event::TransferEvent) -> Option<MouseReport> {
+        // TRB Transfer Lengthは転送されなかった残りの長さ
+        let len = self.report_len().saturating_sub(event.trb_transfer_length() as usize);
+        self.layout.decode(&buf[..len])
+    }
+
+    fn report_len(&self) -> usize {
+        match self.max_packet as usize {
+            0 => 8,
+            n => n.min(MAX_REPORT_LEN),
+        }
+    }
 }
@@ -1,5 +1,5 @@
 use core::alloc::Layout;
-use core::{fmt, slice};
+use core::{fmt, ptr::null_mut, slice};
 
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
@@ -8,13 +8,17 @@ use alloc::alloc::alloc;
 use xhci::context::{EndpointHandler, EndpointState, Input, Input32Byte, Input64Byte, InputHandler, SlotHandler};
 use xhci::{context::{Device32Byte, Device64Byte, DeviceHandler}, Registers};
 
-use crate::usb::util;
+use crate::{addr::{ptr_to_phys, PhysAddr}, memory_manager::{frame_to_ptr, try_allocate_frames, BYTES_PER_FRAME}, usb::util};
 
-use super::xhci::{AlignedAlloc, LinearMapper};
+use super::doorbell::SlotId;
+use super::slot::SlotTable;
+
+use super::xhci::{AlignedAlloc, ErrorKind, LinearMapper, XhciError};
 
 pub struct Dcbaa {
     dcbaa: Box<[u64]>,
-    contexts: BTreeMap<usize, DeviceContext>,
+    contexts: BTreeMap<SlotId, DeviceContext>,
+    slots: SlotTable,
     ctx_size: ContextSize,
     scratchpad_buf_arr: Option<Box<[u64], AlignedAlloc<64>>>
 }
@@ -31,7 +35,7 @@ pub enum DeviceContext {
     DC64Byte(Box<Device64Byte, AlignedAlloc<64>>),
 }
 
-pub fn init_dcbaa(regs: &mut Registers<LinearMapper>) -> Dcbaa{
+pub fn init_dcbaa(regs: &mut Registers<LinearMapper>) -> Result<Dcbaa, XhciError> {
     let max_slots = regs
         .capability
         .hcsparams1
@@ -52,13 +56,13 @@ pub fn init_dcbaa(regs: &mut Registers<LinearMapper>) -> Dcbaa{
     let page_size = 1 << (12 + pagesize_bit);
 
     let mut dcbaa: Box<[u64]> = unsafe {
-        util::aligned_zeros(max_slots as usize + 1, 64)
+        util::aligned_zeros(usize::from(max_slots) + 1, 64)
     };
 
     let scratchpad_buf_arr = 
         if num_scratch_pads > 0 {
-            let arr = make_scratchpad(num_scratch_pads, page_size);
-            dcbaa[0] = arr.as_ref() as *const [u64] as *const u64 as u64;
+            let arr = make_scratchpad(num_scratch_pads, page_size)?;
+            dcbaa[0] = ptr_to_phys(arr.as_ptr()).as_u64();
             Some(arr)
         } else {
             None
@@ -67,44 +71,62 @@ pub fn init_dcbaa(regs: &mut Registers<LinearMapper>) -> Dcbaa{
     regs.operational.config.update_volatile(|cfg| {
         cfg.set_max_device_slots_enabled(max_slots);
     });
-    regs.operational.dcbaap.update_volatile(|x| x.set(dcbaa.as_mut_ptr() as u64));
-    Dcbaa {
+    regs.operational.dcbaap.update_volatile(|x| x.set(ptr_to_phys(dcbaa.as_ptr()).as_u64()));
+    Ok(Dcbaa {
         dcbaa,
         contexts: BTreeMap::new(),
+        slots: SlotTable::new(),
         ctx_size,
         scratchpad_buf_arr
-    }
+    })
 }
 
-fn make_scratchpad(num_scratch_pads: usize, page_size: usize) -> Box<[u64], AlignedAlloc<64>> {
+fn make_scratchpad(num_scratch_pads: usize, page_size: usize) -> Result<Box<[u64], AlignedAlloc<64>>, XhciError> {
     let mut page_ptrs: Vec<u64, AlignedAlloc<64>> = Vec::with_capacity_in(num_scratch_pads, AlignedAlloc::<64> {});
     for _ in 0..num_scratch_pads {
-        unsafe {
-            let page = alloc(Layout::from_size_align(page_size, page_size).unwrap());
-            if page.is_null() {
-                panic!("Failed to allocate xHCI scratchpad buffer");
-            }
-            slice::from_raw_parts_mut(page, page_size).fill(0);
-            page_ptrs.push(page as u64);
+        // 4KiBならフレームを直接取る。足りなくてもOOMで止まらず、xHCIの初期化が失敗するだけにする
+        let page = if page_size == BYTES_PER_FRAME {
+            try_allocate_frames(1).map_or(null_mut(), frame_to_ptr)
+        } else {
+            unsafe { alloc(Layout::from_size_align(page_size, page_size).unwrap()) }
+        };
+        if page.is_null() {
+            return Err(ErrorKind::OutOfMemory.into());
         }
+        unsafe { slice::from_raw_parts_mut(page, page_size).fill(0) };
+        page_ptrs.push(ptr_to_phys(page).as_u64());
     }
 
-    page_ptrs.into_boxed_slice()
+    Ok(page_ptrs.into_boxed_slice())
 }
 
 impl Dcbaa {
-    pub fn get_context_at(&self, slot_id: usize) -> &DeviceContext {
+    pub fn get_context_at(&self, slot_id: SlotId) -> &DeviceContext {
         &self.contexts[&slot_id]
     }
 
-    pub fn init_context_at(&mut self, slot_id: usize) {
+    pub fn init_context_at(&mut self, slot_id: SlotId) {
         self.contexts.insert(slot_id, DeviceContext::new(self.ctx_size));
-        self.dcbaa[slot_id] = self.contexts[&slot_id].get_address();
+        self.dcbaa[slot_id.index()] = self.contexts[&slot_id].get_address().as_u64();
+    }
+
+    /// Disable Slotの後に、スロットのデバイスコンテキストを手放す
+    pub fn remove_context_at(&mut self, slot_id: SlotId) {
+        self.dcbaa[slot_id.index()] = 0;
+        self.contexts.remove(&slot_id);
     }
 
     pub fn ctx_size(&self) -> ContextSize {
         self.ctx_size
     }
+
+    pub fn slots(&self) -> &SlotTable {
+        &self.slots
+    }
+
+    pub fn slots_mut(&mut self) -> &mut SlotTable {
+        &mut self.slots
+    }
 }
 
 
@@ -133,10 +155,10 @@ impl DeviceContext {
         }
     }
 
-    pub fn get_address(&self) -> u64 {
+    pub fn get_address(&self) -> PhysAddr {
         match self {
-            DeviceContext::DC32Byte(dev) => dev.as_ref() as *const Device32Byte as u64,
-            DeviceContext::DC64Byte(dev) => dev.as_ref() as *const Device64Byte as u64,
+            DeviceContext::DC32Byte(dev) => ptr_to_phys(dev.as_ref() as *const Device32Byte),
+            DeviceContext::DC64Byte(dev) => ptr_to_phys(dev.as_ref() as *const Device64Byte),
         }
     }
 
@@ -249,10 +271,10 @@ impl InputContext {
         }
     }
 
-    pub fn get_address(&self) -> u64 {
+    pub fn get_address(&self) -> PhysAddr {
         match self {
-            InputContext::IC32Byte(dev) => dev.as_ref() as *const Input32Byte as u64,
-            InputContext::IC64Byte(dev) => dev.as_ref() as *const Input64Byte as u64,
+            InputContext::IC32Byte(dev) => ptr_to_phys(dev.as_ref() as *const Input32Byte),
+            InputContext::IC64Byte(dev) => ptr_to_phys(dev.as_ref() as *const Input64Byte),
         }
     }
 
@@ -0,0 +1,187 @@
+use core::{fmt, num::NonZeroU8};
+
+use alloc::collections::BTreeMap;
+use xhci::Registers;
+
+use super::xhci::LinearMapper;
+
+/// デバイススロットの番号。0はホストコントローラ自身のドアベルなので使えない
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SlotId(NonZeroU8);
+
+impl SlotId {
+    pub fn new(id: u8) -> Option<Self> {
+        NonZeroU8::new(id).map(Self)
+    }
+
+    pub fn get(self) -> u8 {
+        self.0.get()
+    }
+
+    /// DCBAAやドアベルレジスタの添字
+    pub fn index(self) -> usize {
+        usize::from(self.0.get())
+    }
+}
+
+impl fmt::Display for SlotId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Device Context Index (1..=31)。1はデフォルトコントロールパイプ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Dci(u8);
+
+impl Dci {
+    pub const CONTROL: Dci = Dci(1);
+
+    pub fn new(dci: u8) -> Option<Self> {
+        (1..=31).contains(&dci).then_some(Self(dci))
+    }
+
+    /// エンドポイントディスクリプタのbEndpointAddressから計算する
+    pub fn from_endpoint_address(addr: u8) -> Option<Self> {
+        Self::new(2 * (addr & 0b1111) + (addr >> 7))
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+
+    /// from_endpoint_addressの逆。DCIが奇数ならIN
+    pub fn endpoint_address(self) -> u8 {
+        (self.0 / 2) | (self.0 % 2) << 7
+    }
+
+    /// デバイスコンテキスト内の添字
+    pub fn index(self) -> usize {
+        usize::from(self.0)
+    }
+}
+
+impl fmt::Display for Dci {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// ルートハブのポート番号 (1..=255)。Port Status Changeイベントやスロットコンテキストと同じく1始まり
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortId(NonZeroU8);
+
+impl PortId {
+    pub fn new(num: u8) -> Option<Self> {
+        NonZeroU8::new(num).map(Self)
+    }
+
+    /// ポートレジスタの添字から作る
+    pub fn from_index(i: usize) -> Option<Self> {
+        i.checked_add(1).and_then(|n| u8::try_from(n).ok()).and_then(Self::new)
+    }
+
+    /// HCSPARAMS1のMaxPortsから、コントローラのポートを全部並べる
+    pub fn all(max_ports: u8) -> impl Iterator<Item = Self> {
+        (1..=max_ports).filter_map(Self::new)
+    }
+
+    pub fn get(self) -> u8 {
+        self.0.get()
+    }
+
+    /// ポートレジスタの添字 (0始まり)
+    pub fn index(self) -> usize {
+        usize::from(self.0.get() - 1)
+    }
+}
+
+impl fmt::Display for PortId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Command Ringのドアベル(ドアベル0, ターゲット0)を鳴らす
+pub fn ring_command_doorbell(regs: &mut Registers<LinearMapper>) {
+    trace(0, 0);
+    regs.doorbell.update_volatile_at(0, |d| {
+        d.set_doorbell_target(0);
+        d.set_doorbell_stream_id(0);
+    });
+}
+
+/// スロットのドアベルを鳴らし、エンドポイントdciのTransfer Ringを処理させる
+pub fn ring_endpoint(regs: &mut Registers<LinearMapper>, slot: SlotId, dci: Dci) {
+    trace(slot.get(), dci.get());
+    regs.doorbell.update_volatile_at(slot.index(), |d| {
+        d.set_doorbell_target(dci.get());
+        d.set_doorbell_stream_id(0);
+    });
+}
+
+#[cfg(debug_assertions)]
+static TRACE: crate::memory_manager::Mutex<([(u8, u8); TRACE_LEN], usize)> =
+    crate::memory_manager::Mutex::new(([(0, 0); TRACE_LEN], 0));
+#[cfg(debug_assertions)]
+const TRACE_LEN: usize = 32;
+
+/// デバッグビルドでは直近のドアベルの (ドアベル番号, ターゲット) を記録する
+fn trace(_doorbell: u8, _target: u8) {
+    #[cfg(debug_assertions)]
+    {
+        let mut trace = TRACE.lock();
+        let pos = trace.1;
+        trace.0[pos % TRACE_LEN] = (_doorbell, _target);
+        trace.1 = pos + 1;
+    }
+}
+
+/// 記録したドアベルを古い順に出力する
+pub fn dump_doorbell_trace() {
+    #[cfg(debug_assertions)]
+    {
+        let trace = TRACE.lock();
+        let start = trace.1.saturating_sub(TRACE_LEN);
+        for i in start..trace.1 {
+            let (doorbell, target) = trace.0[i % TRACE_LEN];
+            println!("doorbell[{i}]: db={doorbell} target={target}");
+        }
+    }
+}
+
+pub fn run_doorbell_tests() {
+    assert!(Dci::new(0).is_none());
+    assert!(Dci::new(32).is_none());
+    assert!(Dci::new(1) == Some(Dci::CONTROL));
+    assert!(Dci::new(31).map(Dci::get) == Some(31));
+    assert!(SlotId::new(0).is_none());
+    assert!(SlotId::new(255).map(SlotId::index) == Some(255));
+
+    // EP1 IN -> 3, EP2 OUT -> 4
+    assert!(Dci::from_endpoint_address(0x81) == Dci::new(3));
+    assert!(Dci::from_endpoint_address(0x02) == Dci::new(4));
+    assert!(Dci::new(3).map(Dci::endpoint_address) == Some(0x81) && Dci::new(4).map(Dci::endpoint_address) == Some(0x02));
+    assert!(Dci::CONTROL.endpoint_address() == 0x80);
+    // EP0 OUTはDCI 0になるので作れない
+    assert!(Dci::from_endpoint_address(0x00).is_none());
+
+    assert!(PortId::new(0).is_none());
+    assert!(PortId::from_index(0).map(PortId::get) == Some(1));
+    assert!(PortId::from_index(254).map(PortId::index) == Some(254));
+    // 255番より先のポートは表せないので、黙って丸めずにNoneにする
+    assert!(PortId::from_index(255).is_none());
+    assert!(PortId::from_index(usize::MAX).is_none());
+    assert!(PortId::all(0).count() == 0);
+    assert!(PortId::all(255).map(PortId::index).eq(0..255));
+
+    let mut map = BTreeMap::new();
+    for (slot, dci) in [(1, 1), (1, 3), (2, 1), (255, 31)] {
+        map.insert((SlotId::new(slot).unwrap(), Dci::new(dci).unwrap()), (slot, dci));
+    }
+    for (&(slot, dci), &(raw_slot, raw_dci)) in &map {
+        assert!(slot.get() == raw_slot && dci.get() == raw_dci);
+    }
+    assert!(map.get(&(SlotId::new(1).unwrap(), Dci::new(3).unwrap())) == Some(&(1, 3)));
+    assert!(map.get(&(SlotId::new(3).unwrap(), Dci::new(1).unwrap())).is_none());
+}
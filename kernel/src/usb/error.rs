@@ -0,0 +1,301 @@
+use core::fmt;
+
+use futures::channel::oneshot;
+use xhci::ring::trb::event::{CommandCompletion, CompletionCode, TransferEvent};
+
+use crate::paging::{MapError, IDENTITY_MAP_END};
+
+use super::{doorbell::{Dci, PortId, SlotId}, slot::SlotState};
+
+/// 失敗したときに行っていた操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    EnableSlot,
+    AddressDevice,
+    ReadDescriptor,
+    SetConfiguration,
+    SetInterface,
+    SetProtocol,
+    SetIdle,
+    SetReport,
+    /// ハブのポートへの要求
+    HubPort,
+    ConfigureEndpoint,
+    Suspend,
+    Resume,
+    DisableSlot,
+    /// 転送エラーで止まったエンドポイントを戻す
+    RecoverEndpoint,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    InvalidTrb,
+    RingIsFull,
+    InvalidCommandCompletionTrb,
+    /// コマンドが成功以外の完了コードで終わった
+    CommandFailed(CommandCompletion),
+    UnexpectedDescriptor,
+    /// 転送が成功以外の完了コードで終わった
+    TransferFailed(TransferEvent),
+    /// Run/Stopを変えてもHCHaltedが追従しない
+    HostControllerTimeout,
+    /// MMIOのBARを写像できなかった
+    BarNotMapped(u64, MapError),
+    /// BAR0がメモリ空間の領域を指していない
+    BarNotMemory,
+    /// スロットがその操作をできる状態にない。中身はそのときの状態
+    SlotStateInvalid(SlotState),
+    /// デバイスが期限(ミリ秒)までに応答しなかった
+    Timeout(u64),
+    /// デバイスが外され、スロットが後始末された
+    Disconnected,
+    /// スロット・エンドポイントに転送リングが無い
+    NoSuchRing,
+    /// 完了が通知されないまま待ち受けが捨てられた
+    Canceled,
+    /// コントローラに渡すメモリが取れなかった
+    OutOfMemory,
+}
+
+/// xHCIの操作の失敗。どのデバイスのどの操作で起きたかを持つ
+#[derive(Debug)]
+pub struct XhciError {
+    kind: ErrorKind,
+    operation: Option<Operation>,
+    port: Option<PortId>,
+    slot: Option<SlotId>,
+    dci: Option<Dci>,
+}
+
+impl From<ErrorKind> for XhciError {
+    fn from(kind: ErrorKind) -> Self {
+        Self { kind, operation: None, port: None, slot: None, dci: None }
+    }
+}
+
+impl From<oneshot::Canceled> for XhciError {
+    fn from(_: oneshot::Canceled) -> Self {
+        ErrorKind::Canceled.into()
+    }
+}
+
+impl XhciError {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// デバイスが外れたために失敗したか。クラスドライバのタスクはこれを見て静かに終わる
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self.kind, ErrorKind::Disconnected)
+    }
+
+    pub fn operation(&self) -> Option<Operation> {
+        self.operation
+    }
+
+    pub fn port(&self) -> Option<PortId> {
+        self.port
+    }
+
+    pub fn slot(&self) -> Option<SlotId> {
+        self.slot
+    }
+
+    pub fn dci(&self) -> Option<Dci> {
+        self.dci
+    }
+
+    /// 転送がエンドポイントを止める(Halted)エラーで終わったか。recover_endpointで戻すまで転送できない
+    pub fn halts_endpoint(&self) -> bool {
+        matches!(
+            self.kind,
+            ErrorKind::TransferFailed(t) if matches!(t.completion_code(), Ok(CompletionCode::StallError | CompletionCode::UsbTransactionError | CompletionCode::BabbleDetectedError))
+        )
+    }
+
+    /// デバイスがSTALLを返したか。デバイス側のエンドポイントもHaltしている
+    pub fn is_stall(&self) -> bool {
+        self.completion_code() == Some(Ok(CompletionCode::StallError))
+    }
+
+    /// 失敗を報告したTRBの完了コード。未知の値ならErrにその値が入る
+    pub fn completion_code(&self) -> Option<Result<CompletionCode, u8>> {
+        match &self.kind {
+            ErrorKind::CommandFailed(c) => Some(c.completion_code()),
+            ErrorKind::TransferFailed(t) => Some(t.completion_code()),
+            _ => None,
+        }
+    }
+
+    /// 失敗を報告したイベントTRBそのもの。トレースに残す用
+    pub fn raw_trb(&self) -> Option<[u32; 4]> {
+        match &self.kind {
+            ErrorKind::CommandFailed(c) => Some(c.into_raw()),
+            ErrorKind::TransferFailed(t) => Some(t.into_raw()),
+            _ => None,
+        }
+    }
+
+    // 内側で付けた情報の方が正確なので、既にあれば上書きしない
+
+    pub fn during(mut self, op: Operation) -> Self {
+        self.operation.get_or_insert(op);
+        self
+    }
+
+    pub fn on_port(mut self, port: PortId) -> Self {
+        self.port.get_or_insert(port);
+        self
+    }
+
+    pub fn on_slot(mut self, slot: SlotId) -> Self {
+        self.slot.get_or_insert(slot);
+        self
+    }
+
+    pub fn on_endpoint(mut self, dci: Dci) -> Self {
+        self.dci.get_or_insert(dci);
+        self
+    }
+}
+
+/// Result<_, XhciError>に失敗時の情報を付ける
+pub trait ErrorContext {
+    fn during(self, op: Operation) -> Self;
+    fn on_port(self, port: PortId) -> Self;
+    fn on_slot(self, slot: SlotId) -> Self;
+    fn on_endpoint(self, dci: Dci) -> Self;
+}
+
+impl<T> ErrorContext for Result<T, XhciError> {
+    fn during(self, op: Operation) -> Self {
+        self.map_err(|e| e.during(op))
+    }
+
+    fn on_port(self, port: PortId) -> Self {
+        self.map_err(|e| e.on_port(port))
+    }
+
+    fn on_slot(self, slot: SlotId) -> Self {
+        self.map_err(|e| e.on_slot(slot))
+    }
+
+    fn on_endpoint(self, dci: Dci) -> Self {
+        self.map_err(|e| e.on_endpoint(dci))
+    }
+}
+
+struct Code(Result<CompletionCode, u8>);
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Ok(c) => write!(f, "{c:?}"),
+            Err(c) => write!(f, "unknown code {c}"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::InvalidTrb => write!(f, "invalid TRB"),
+            ErrorKind::RingIsFull => write!(f, "ring is full"),
+            ErrorKind::InvalidCommandCompletionTrb => write!(f, "invalid command completion TRB"),
+            ErrorKind::CommandFailed(c) => write!(f, "command completed with {}", Code(c.completion_code())),
+            ErrorKind::UnexpectedDescriptor => write!(f, "unexpected descriptor"),
+            ErrorKind::TransferFailed(t) => write!(f, "transfer completed with {}", Code(t.completion_code())),
+            ErrorKind::HostControllerTimeout => write!(f, "host controller timeout"),
+            ErrorKind::SlotStateInvalid(state) => write!(f, "slot is {state}"),
+            ErrorKind::BarNotMapped(bar, e) => write!(f, "MMIO BAR {bar:#x} could not be mapped ({e:?})"),
+            ErrorKind::BarNotMemory => write!(f, "BAR0 is not a memory-space region"),
+            ErrorKind::Timeout(ms) => write!(f, "no response within {ms}ms"),
+            ErrorKind::Disconnected => write!(f, "device was disconnected"),
+            ErrorKind::NoSuchRing => write!(f, "no transfer ring"),
+            ErrorKind::Canceled => write!(f, "request was dropped before completion"),
+            ErrorKind::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+/// "<操作> failed [port=N] [slot=N] [dci=N]: <原因>" の1行
+impl fmt::Display for XhciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.operation {
+            Some(op) => write!(f, "{op} failed")?,
+            None => write!(f, "xHCI operation failed")?,
+        }
+        if let Some(port) = self.port {
+            write!(f, " port={port}")?;
+        }
+        if let Some(slot) = self.slot {
+            write!(f, " slot={slot}")?;
+        }
+        if let Some(dci) = self.dci {
+            write!(f, " dci={dci}")?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+pub fn run_error_tests() {
+    const TRB_TYPE_TRANSFER_EVENT: u32 = 32;
+    const TRB_TYPE_COMMAND_COMPLETION: u32 = 33;
+    const STALL_ERROR: u32 = 6;
+    const CONTEXT_STATE_ERROR: u32 = 19;
+
+    let slot = SlotId::new(2).unwrap();
+    let dci = Dci::new(1).unwrap();
+
+    let raw = [0x1000, 0, STALL_ERROR << 24, 2 << 24 | 1 << 16 | TRB_TYPE_TRANSFER_EVENT << 10];
+    let evt = TransferEvent::try_from(raw).unwrap();
+    let e = XhciError::from(ErrorKind::TransferFailed(evt)).on_slot(slot).on_endpoint(dci);
+    let e: Result<(), _> = Err(e);
+    let e = e.during(Operation::SetConfiguration).during(Operation::ReadDescriptor).on_port(PortId::new(3).unwrap()).unwrap_err();
+    assert!(format!("{e}") == "SetConfiguration failed port=3 slot=2 dci=1: transfer completed with StallError");
+    assert!(e.completion_code() == Some(Ok(CompletionCode::StallError)));
+    assert!(e.raw_trb() == Some(raw));
+    assert!(e.halts_endpoint() && e.is_stall());
+
+    let raw = [0, 0, CONTEXT_STATE_ERROR << 24, 2 << 24 | TRB_TYPE_COMMAND_COMPLETION << 10];
+    let e = XhciError::from(ErrorKind::CommandFailed(CommandCompletion::try_from(raw).unwrap()))
+        .during(Operation::AddressDevice).on_slot(slot);
+    assert!(format!("{e}") == "AddressDevice failed slot=2: command completed with ContextStateError");
+    assert!(!e.halts_endpoint());
+
+    let raw = [0, 0, 200 << 24, 2 << 24 | 1 << 16 | TRB_TYPE_TRANSFER_EVENT << 10];
+    let e = XhciError::from(ErrorKind::TransferFailed(TransferEvent::try_from(raw).unwrap()));
+    assert!(format!("{e}") == "xHCI operation failed: transfer completed with unknown code 200");
+    assert!(!e.halts_endpoint() && !e.is_stall());
+
+    let e = XhciError::from(ErrorKind::BarNotMapped(IDENTITY_MAP_END, MapError::OutOfMemory));
+    assert!(format!("{e}") == "xHCI operation failed: MMIO BAR 0x1000000000 could not be mapped (OutOfMemory)");
+
+    let e = XhciError::from(ErrorKind::BarNotMemory);
+    assert!(format!("{e}") == "xHCI operation failed: BAR0 is not a memory-space region");
+
+    let e = XhciError::from(ErrorKind::Timeout(500)).during(Operation::ReadDescriptor).on_slot(slot);
+    assert!(format!("{e}") == "ReadDescriptor failed slot=2: no response within 500ms");
+
+    let e = XhciError::from(ErrorKind::RingIsFull);
+    assert!(format!("{e}") == "xHCI operation failed: ring is full" && e.raw_trb().is_none());
+
+    let e = XhciError::from(ErrorKind::Disconnected).on_slot(slot).on_endpoint(Dci::new(3).unwrap());
+    assert!(format!("{e}") == "xHCI operation failed slot=2 dci=3: device was disconnected" && e.is_disconnected());
+
+    let e = XhciError::from(ErrorKind::NoSuchRing).on_slot(slot).on_endpoint(Dci::new(3).unwrap());
+    assert!(format!("{e}") == "xHCI operation failed slot=2 dci=3: no transfer ring" && !e.is_disconnected());
+
+    let e = XhciError::from(oneshot::Canceled).during(Operation::SetReport);
+    assert!(format!("{e}") == "SetReport failed: request was dropped before completion");
+
+    let e = XhciError::from(ErrorKind::OutOfMemory);
+    assert!(format!("{e}") == "xHCI operation failed: out of memory");
+}
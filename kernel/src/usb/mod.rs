@@ -1,51 +1,398 @@
-use alloc::boxed::Box;
+use core::{fmt::{self, Write}, sync::atomic::{AtomicBool, AtomicUsize, Ordering}};
+
+use alloc::{sync::Arc, vec::Vec};
 use futures::Future;
 
-use crate::{memory_manager::LazyInit, pci::PCIDevice};
+use crate::{deferred, event_loop::{Message, EVENTS}, introspect, memory_manager::{LazyInit, Mutex}, pci::PCIDevice, shortcut::{self, Mods}, timer::get_current_tick};
+
+use self::{runtime::{new_executor_and_spawner, wake_sleepers, Executor, Spawner}, xhci::{initialize_xhci, XhciError}};
 
-use self::{runtime::{new_channel, new_executor_and_spawner, Executor, Spawner}, xhci::{initialize_xhci, XhciError}};
+pub use self::{action::init_device::{port_power_states, port_stats, PortPower, PortStat}, class::{key::{translate as translate_key, Keymap, LockState, ModifierSet, KEY_CAPS_LOCK, KEY_NUM_LOCK, KEY_SCROLL_LOCK}, keyboard::KeyReport, mouse::MouseReport}, doorbell::{PortId, SlotId}, power::{power_state, resume, suspend, PowerState}, ready::{is_ready, ready_summary, wait_ready, ReadySummary, Resolution}, slot::SlotState, xhci::slot_states, runtime::{new_bounded_channel, new_channel, Receiver, SendPolicy, Sender}};
 
 pub mod usbd;
 pub mod xhci;
+pub mod registry;
 mod runtime;
 mod ring;
 mod class;
 mod device;
+mod doorbell;
 mod util;
 mod action;
+mod power;
+mod error;
+mod slot;
+mod ready;
 
 static EXECUTOR: LazyInit<Executor<'static, Result<(), XhciError>>> = LazyInit::new();
 pub static SPAWNER: LazyInit<Spawner<'static, Result<(), XhciError>>> = LazyInit::new();
 
-pub unsafe fn init_usb(
-    xhc: PCIDevice, 
-    intel_ehci_found: bool, 
-    mouse_callback: Box<dyn Fn(Box<class::mouse::MouseReport>) + Send>,
-    key_callback: Box<dyn Fn(Box<class::keyboard::KeyReport>) + Send>
-) {
-    let (executor, spawner) = new_executor_and_spawner::<Result<(), XhciError>>();
+pub unsafe fn init_usb(xhc: PCIDevice, intel_ehci_found: bool) {
+    runtime::init_sleep_timer();
+    class::key::run_keymap_tests();
+    class::hub::run_hub_tests();
+    class::hid::run_hid_tests();
+    doorbell::run_doorbell_tests();
+    ring::ring_core::run_ring_core_tests();
+    ring::ring::run_consumer_ring_tests();
+    ring::transfer::run_transfer_tests();
+    runtime::run_channel_tests();
+    runtime::run_executor_tests();
+    error::run_error_tests();
+    slot::run_slot_tests();
+    ready::run_ready_tests();
+    run_subscription_tests();
+    power::run_power_tests();
+    usbd::run_descriptor_tests();
+    registry::run_registry_tests();
+    action::init_device::run_init_device_tests();
+
+    let (executor, spawner) = new_executor_and_spawner::<Result<(), XhciError>>(request_poll);
     EXECUTOR.lock().init(executor);
     SPAWNER.lock().init(spawner);
+    ready::init_ready();
 
-    let (addr_send, addr_recv) = new_channel();
-    initialize_xhci(xhc, intel_ehci_found, &mut SPAWNER.lock(), addr_send);
-    let mut usbd = usbd::UsbDriver::new(addr_recv, mouse_callback, key_callback);
+    let (addr_send, addr_recv) = new_channel("usb-address");
+    if let Err(e) = initialize_xhci(xhc, intel_ehci_found, &mut SPAWNER.lock(), addr_send.clone()) {
+        error!("USB is disabled: {e}");
+        // 待っている側が止まらないよう、デバイス無しで準備完了にする
+        ready::set_expected(0);
+        return;
+    }
+    ready::start_ready_timeout();
+    let mut usbd = usbd::UsbDriver::new(addr_recv, addr_send);
     SPAWNER.lock().spawn(async move {
         usbd.main_loop().await
     });
 
 }
 
+fn show_ready(_: usize, out: &mut dyn Write) -> fmt::Result {
+    let Some((summary, devices)) = ready_summary() else {
+        return writeln!(out, "USB not ready");
+    };
+    writeln!(out, "{summary}")?;
+    for d in devices {
+        writeln!(out, "  {d:?}")?;
+    }
+    Ok(())
+}
+
+/// リセットに失敗したか、過電流のあったポートだけ
+fn show_port_errors(_: usize, out: &mut dyn Write) -> fmt::Result {
+    for (port, stat) in port_stats() {
+        if stat.failed || stat.over_currents > 0 {
+            writeln!(
+                out,
+                "port {port}: {}resets={} failures={} over-currents={}",
+                if stat.failed { "FAILED " } else { "" },
+                stat.resets,
+                stat.failures,
+                stat.over_currents
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn show_ports(_: usize, out: &mut dyn Write) -> fmt::Result {
+    let ports = port_power_states();
+    if ports.is_empty() {
+        writeln!(out, "no ports")?;
+    }
+    let flag = |on: bool, name: &'static str| if on { name } else { "-" };
+    for (port, p) in ports {
+        writeln!(
+            out,
+            "port {port:>2}: {:<5} {:<9} {:<7} {:<9} {:<12} {}",
+            flag(p.powered, "power"),
+            flag(p.connected, "connected"),
+            flag(p.enabled, "enabled"),
+            flag(p.suspended, "suspended"),
+            flag(p.over_current, "OVER-CURRENT"),
+            p.link_state
+        )?;
+    }
+    Ok(())
+}
+
+fn show_slots(_: usize, out: &mut dyn Write) -> fmt::Result {
+    let slots = slot_states();
+    if slots.is_empty() {
+        writeln!(out, "no slots")?;
+    }
+    for (slot, state) in slots {
+        writeln!(out, "slot {}: {}", slot.get(), state)?;
+    }
+    Ok(())
+}
+
+/// usb/の下を登録する。xHCが無くても読める
+pub fn register_nodes() {
+    introspect::register("usb/power", |_, out| writeln!(out, "{:?}", power_state()), 0).expect("usb: usb/power");
+    introspect::register("usb/ready", show_ready, 0).expect("usb: usb/ready");
+    introspect::register("usb/port-errors", show_port_errors, 0).expect("usb: usb/port-errors");
+    introspect::register("usb/ports", show_ports, 0).expect("usb: usb/ports");
+    introspect::register("usb/slots", show_slots, 0).expect("usb: usb/slots");
+    introspect::register("usb/devices", |_, out| registry::write_devices(out, &registry::snapshot()), 0).expect("usb: usb/devices");
+    introspect::register("usb/channels", |_, out| runtime::write_channels(out), 0).expect("usb: usb/channels");
+}
+
+const KEY_U: u8 = 0x18;
+
+/// Ctrl+Alt+Uで繋がっているデバイスの一覧をコンソールに出す
+pub fn register_debug_keys() {
+    shortcut::register_global(Mods::CTRL.with(Mods::ALT), KEY_U, "usb", |_| registry::dump_devices(), 0).expect("usb: Ctrl+Alt+U");
+}
+
 pub fn on_xhc_interrupt() {
     xhci::on_xhc_interrupt();
+    run_tasks();
+}
+
+/// sleepの期限が来ていればtrue。タイマー割り込みから直接通知されるので、メインループはこれを見て休眠をやめる
+pub fn timer_pending() -> bool {
+    runtime::sleep_timer_expired(false)
+}
+
+/// sleepの期限が来ていれば、待っているタスクを起こして実行する
+pub fn on_timer() {
+    if runtime::sleep_timer_expired(true) {
+        wake_sleepers(get_current_tick());
+        run_tasks();
+    }
+}
+
+/// Message::UsbPollをEVENTSに入れたがまだ処理していなければtrue
+static POLL_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// xHCの割り込みを待たずにタスクを実行するよう、メインループに頼む
+fn request_poll() {
+    if POLL_REQUESTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let pushed = EVENTS.lock().push(Message::UsbPoll);
+    if pushed.is_err() {
+        // 入らなかったら次に起こされたときにまた頼む
+        POLL_REQUESTED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Message::UsbPollを受けてメインループから呼ぶ。実行できるタスクを全て実行する
+pub fn poll_tasks() {
+    POLL_REQUESTED.store(false, Ordering::Relaxed);
+    run_tasks();
+}
+
+fn run_tasks() {
     let mut executor = EXECUTOR.lock();
     while executor.has_next_task() {
         if let Some(Err(e)) = executor.process_next_task().unwrap() {
-            println!("Error while running xHCI tasks: {e:?}");
+            error!("Error while running xHCI tasks: {e}");
         }
     }
 }
 
+/// 起動時のUSBデバイスが揃ったら、メインループでf(arg)を呼ばせる
+pub fn defer_until_ready(f: fn(usize), arg: usize) {
+    if is_ready() || !SPAWNER.lock().is_init() {
+        let _ = deferred::defer(f, arg);
+        return;
+    }
+    spawn(async move {
+        wait_ready().await;
+        let _ = deferred::defer(f, arg);
+        Ok(())
+    });
+}
+
 fn spawn(future: impl Future<Output = Result<(), XhciError>> + Send + 'static) {
     SPAWNER.lock().spawn(future);
 }
+
+#[derive(Debug, Clone)]
+pub struct MouseEvent {
+    pub slot: SlotId,
+    pub report: MouseReport,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyEvent {
+    pub slot: SlotId,
+    pub report: KeyReport,
+    /// このレポートを処理した後のロックキーの状態
+    pub locks: LockState,
+}
+
+/// 接続されたデバイスの情報。クラスは最初のインターフェースのもの
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HotplugEvent {
+    /// デバイスの設定が終わり、使えるようになった
+    Attached { slot: SlotId, info: DeviceInfo },
+    Detached { slot: SlotId },
+}
+
+/// 購読者ごとに溜めておけるイベントの数。これを超えた分は捨てて数える
+const SUBSCRIBER_QUEUE_LIMIT: usize = 64;
+
+struct Subscriber<T> {
+    id: usize,
+    sender: Sender<T>,
+    dropped: Arc<AtomicUsize>,
+}
+
+struct Subscribers<T> {
+    next_id: usize,
+    list: Vec<Subscriber<T>>,
+}
+
+impl<T: Clone> Subscribers<T> {
+    const fn new() -> Self {
+        Self { next_id: 0, list: Vec::new() }
+    }
+
+    fn add(&mut self, sender: Sender<T>) -> (usize, Arc<AtomicUsize>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let dropped = Arc::new(AtomicUsize::new(0));
+        self.list.push(Subscriber { id, sender, dropped: dropped.clone() });
+        (id, dropped)
+    }
+
+    fn remove(&mut self, id: usize) {
+        self.list.retain(|s| s.id != id);
+    }
+
+    /// 全購読者に送る。受け取りが追いついていない購読者の分は待たずに捨てる
+    fn publish(&self, event: &T) {
+        for sub in &self.list {
+            if sub.sender.len() >= SUBSCRIBER_QUEUE_LIMIT {
+                sub.dropped.fetch_add(1, Ordering::Relaxed);
+            } else {
+                sub.sender.send(event.clone());
+            }
+        }
+    }
+}
+
+static MOUSE_SUBSCRIBERS: Mutex<Subscribers<MouseEvent>> = Mutex::new(Subscribers::new());
+static KEYBOARD_SUBSCRIBERS: Mutex<Subscribers<KeyEvent>> = Mutex::new(Subscribers::new());
+static HOTPLUG_SUBSCRIBERS: Mutex<Subscribers<HotplugEvent>> = Mutex::new(Subscribers::new());
+/// ロックキーの切り替え要求(キーコード)を受け取るキーボードのタスク
+static LOCK_REQUESTS: Mutex<Subscribers<u8>> = Mutex::new(Subscribers::new());
+
+/// 購読の登録を表す。dropすると購読をやめる
+pub struct Subscription {
+    id: usize,
+    dropped: Arc<AtomicUsize>,
+    unsubscribe: fn(usize),
+}
+
+impl Subscription {
+    /// キューが溢れて捨てられたイベントの数
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        (self.unsubscribe)(self.id);
+    }
+}
+
+#[must_use]
+pub fn subscribe_mouse(sender: Sender<MouseEvent>) -> Subscription {
+    let (id, dropped) = MOUSE_SUBSCRIBERS.lock().add(sender);
+    Subscription { id, dropped, unsubscribe: |id| MOUSE_SUBSCRIBERS.lock().remove(id) }
+}
+
+#[must_use]
+pub fn subscribe_keyboard(sender: Sender<KeyEvent>) -> Subscription {
+    let (id, dropped) = KEYBOARD_SUBSCRIBERS.lock().add(sender);
+    Subscription { id, dropped, unsubscribe: |id| KEYBOARD_SUBSCRIBERS.lock().remove(id) }
+}
+
+#[must_use]
+pub fn subscribe_hotplug(sender: Sender<HotplugEvent>) -> Subscription {
+    let (id, dropped) = HOTPLUG_SUBSCRIBERS.lock().add(sender);
+    Subscription { id, dropped, unsubscribe: |id| HOTPLUG_SUBSCRIBERS.lock().remove(id) }
+}
+
+#[must_use]
+fn subscribe_lock_requests(sender: Sender<u8>) -> Subscription {
+    let (id, dropped) = LOCK_REQUESTS.lock().add(sender);
+    Subscription { id, dropped, unsubscribe: |id| LOCK_REQUESTS.lock().remove(id) }
+}
+
+/// 接続されている全てのキーボードで、keycodeのロックキーが押されたときと同じようにロック状態を切り替える。
+/// LEDも更新され、新しい状態はキーボードのイベントとして通知される
+pub fn toggle_lock(keycode: u8) {
+    LOCK_REQUESTS.lock().publish(&keycode);
+    run_tasks();
+}
+
+fn publish_mouse(event: MouseEvent) {
+    MOUSE_SUBSCRIBERS.lock().publish(&event);
+}
+
+fn publish_keyboard(event: KeyEvent) {
+    KEYBOARD_SUBSCRIBERS.lock().publish(&event);
+}
+
+fn publish_hotplug(event: HotplugEvent) {
+    HOTPLUG_SUBSCRIBERS.lock().publish(&event);
+}
+
+fn run_subscription_tests() {
+    let slot = SlotId::new(1).unwrap();
+    let event = |key: u8| {
+        let mut report = KeyReport::default();
+        report.keycodes[0] = key;
+        KeyEvent { slot, report, locks: LockState::default() }
+    };
+
+    // 起動中の購読者を巻き込まないよう、グローバルでない購読者の表で試す
+    let mut subs = Subscribers::new();
+    let (tx1, rx1) = new_channel("test-sub1");
+    let (tx2, rx2) = new_channel("test-sub2");
+    let (id1, dropped1) = subs.add(tx1);
+    let (id2, dropped2) = subs.add(tx2);
+
+    subs.publish(&event(0x04));
+    for rx in [&rx1, &rx2] {
+        let received = rx.receive().unwrap();
+        assert!(received.slot == slot && received.report.keycodes[0] == 0x04);
+        assert!(rx.receive().is_none());
+    }
+
+    // 受け取らない購読者の分は捨てられ、他の購読者には影響しない
+    for _ in 0..SUBSCRIBER_QUEUE_LIMIT + 3 {
+        subs.publish(&event(0x05));
+        rx2.receive().unwrap();
+    }
+    assert!(dropped1.load(Ordering::Relaxed) == 3 && dropped2.load(Ordering::Relaxed) == 0);
+
+    subs.remove(id1);
+    subs.publish(&event(0x06));
+    assert!(rx2.receive().unwrap().report.keycodes[0] == 0x06);
+    subs.remove(id2);
+    assert!(subs.list.is_empty());
+
+    // Subscriptionをdropすればグローバルの表からも外れる。イベントは流さない
+    let (tx, _rx) = new_channel("test-sub3");
+    let sub = subscribe_keyboard(tx);
+    let id = sub.id;
+    assert!(KEYBOARD_SUBSCRIBERS.lock().list.iter().any(|s| s.id == id));
+    drop(sub);
+    assert!(!KEYBOARD_SUBSCRIBERS.lock().list.iter().any(|s| s.id == id));
+}
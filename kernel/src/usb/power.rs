@@ -0,0 +1,297 @@
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+
+use alloc::{collections::BTreeSet, vec::Vec};
+use xhci::ring::trb::{command::{Allowed, SetTrDequeuePointer, StopEndpoint}, event::CompletionCode};
+
+use crate::{memory_manager::Mutex, println, timer::{get_current_tick, ms_to_ticks}};
+
+use super::{
+    action::init_device::update_portsc, doorbell::{Dci, PortId, SlotId}, run_tasks, runtime::{sleep, timeout_at}, spawn, xhci::{is_usb3_port, notify_port_status, push_command_async, root_ports, with_regs, with_trf_rings, ErrorKind, Operation, XhciError}
+};
+
+/// Stop Endpointコマンドの完了を待つ時間。全エンドポイントで共有する
+const STOP_TIMEOUT_MS: u64 = 100;
+/// Run/Stopを変えてからHCHaltedが追従するまでに待つ時間(仕様上は16ms以内)
+const HALT_TIMEOUT_MS: u64 = 50;
+/// ポートがU3に入るのを待つ時間
+const U3_TIMEOUT_MS: u64 = 20;
+/// USB2のポートでResume信号を送り続ける時間
+const RESUME_SIGNAL_MS: u64 = 20;
+
+const PLS_U0: u8 = 0;
+const PLS_U3: u8 = 3;
+const PLS_RESUME: u8 = 15;
+
+/// コントローラの電源状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    Running,
+    /// エンドポイントを止めている途中。新しいTDは投入されない
+    Suspending,
+    /// Run/Stopが0でポートはU3
+    Suspended,
+    Resuming,
+}
+
+static STATE: Mutex<PowerState> = Mutex::new(PowerState::Running);
+/// Runningに戻るのを待っているタスク
+static WAITERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+/// ポーリング中の割り込みエンドポイント。サスペンド時にこれらを止める
+static ACTIVE_ENDPOINTS: Mutex<BTreeSet<(SlotId, Dci)>> = Mutex::new(BTreeSet::new());
+/// サスペンド時にU3にしたポート
+static SUSPENDED_PORTS: Mutex<Vec<PortId>> = Mutex::new(Vec::new());
+
+pub fn power_state() -> PowerState {
+    *STATE.lock()
+}
+
+/// 状態が`from`のときだけ`to`に変える
+fn transition(from: PowerState, to: PowerState) -> bool {
+    let mut state = STATE.lock();
+    if *state != from {
+        return false;
+    }
+    *state = to;
+    true
+}
+
+fn set_running() {
+    *STATE.lock() = PowerState::Running;
+    for w in WAITERS.lock().drain(..) {
+        w.wake();
+    }
+}
+
+pub struct WaitRunning;
+
+impl Future for WaitRunning {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if power_state() == PowerState::Running {
+            return Poll::Ready(());
+        }
+        WAITERS.lock().push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// コントローラが動いている間は即座に完了するFuture。
+/// クラスドライバはTDを投入する前にこれを待つ
+pub fn wait_running() -> WaitRunning {
+    WaitRunning
+}
+
+/// ポーリング中のエンドポイントの登録。dropすると登録が外れる
+pub struct ActiveEndpoint {
+    slot: SlotId,
+    dci: Dci,
+}
+
+impl Drop for ActiveEndpoint {
+    fn drop(&mut self) {
+        ACTIVE_ENDPOINTS.lock().remove(&(self.slot, self.dci));
+    }
+}
+
+pub fn track_endpoint(slot: SlotId, dci: Dci) -> ActiveEndpoint {
+    ACTIVE_ENDPOINTS.lock().insert((slot, dci));
+    ActiveEndpoint { slot, dci }
+}
+
+/// コントローラをサスペンドする。
+/// 新しいTDの投入を止め、ポーリング中のエンドポイントを停止し、ポートをU3にしてからRun/Stopを0にする。
+/// 途中で失敗したらRunningに戻す
+pub fn suspend() {
+    spawn(suspend_async());
+    run_tasks();
+}
+
+/// サスペンドしたコントローラを再開し、ポーリングを再開する
+pub fn resume() {
+    spawn(resume_async());
+    run_tasks();
+}
+
+async fn suspend_async() -> Result<(), XhciError> {
+    if !transition(PowerState::Running, PowerState::Suspending) {
+        return Ok(());
+    }
+
+    let deadline = get_current_tick() + ms_to_ticks(STOP_TIMEOUT_MS);
+    let endpoints: Vec<_> = ACTIVE_ENDPOINTS.lock().iter().copied().collect();
+    for (slot, dci) in endpoints {
+        if !stop_endpoint(slot, dci, deadline).await {
+            warn!("usb: slot {slot} dci {dci} did not stop, cancelling suspend");
+            return restart().await;
+        }
+    }
+
+    suspend_ports().await;
+
+    with_regs(|r| r.operational.usbcmd.update_volatile(|c| {
+        c.clear_run_stop();
+    }));
+    if !wait_halted(true).await {
+        warn!("usb: xHC did not halt, cancelling suspend");
+        restart().await?;
+        return Err(XhciError::from(ErrorKind::HostControllerTimeout).during(Operation::Suspend));
+    }
+
+    *STATE.lock() = PowerState::Suspended;
+    println!("usb: suspended");
+    Ok(())
+}
+
+async fn resume_async() -> Result<(), XhciError> {
+    if !transition(PowerState::Suspended, PowerState::Resuming) {
+        return Ok(());
+    }
+    restart().await?;
+    println!("usb: resumed");
+    Ok(())
+}
+
+/// コントローラを動かし、U3にしたポートを戻してからポーリングを再開させる。
+/// コントローラが応答しなくてもRunningには戻すので、クラスドライバが止まったままになることはない
+async fn restart() -> Result<(), XhciError> {
+    with_regs(|r| r.operational.usbcmd.update_volatile(|c| {
+        c.set_run_stop();
+    }));
+    let started = wait_halted(false).await;
+
+    resume_ports().await;
+    set_running();
+
+    // 止まっている間の接続・切断はイベントにならないことがあるので、CSCが立っているポートを再列挙させる
+    for port_id in root_ports() {
+        if with_regs(|r| r.port_register_set.read_volatile_at(port_id.index()).portsc.connect_status_change()) {
+            notify_port_status(port_id);
+        }
+    }
+
+    if started {
+        Ok(())
+    } else {
+        error!("usb: xHC did not restart");
+        Err(XhciError::from(ErrorKind::HostControllerTimeout).during(Operation::Resume))
+    }
+}
+
+/// エンドポイントを止め、リングに残っているTDを捨てる。期限までに止まらなければfalse
+async fn stop_endpoint(slot: SlotId, dci: Dci, deadline: u64) -> bool {
+    let mut stop = StopEndpoint::new();
+    stop.set_slot_id(slot.get()).set_endpoint_id(dci.get());
+    let Ok(recv) = push_command_async(Allowed::StopEndpoint(stop)).await else {
+        return false;
+    };
+    match timeout_at(deadline, recv).await {
+        // Context State Errorは既に止まっているエンドポイント
+        Some(Ok(c)) if matches!(c.completion_code(), Ok(CompletionCode::Success | CompletionCode::ContextStateError)) => {}
+        _ => return false,
+    }
+
+    // 止めたTDは再開時に実行させず、クラスドライバに新しく投入させる
+    let Some((ptr, cycle)) = with_trf_rings(|t| t.discard_pending(slot, dci)) else {
+        return false;
+    };
+    let mut set_deq = SetTrDequeuePointer::new();
+    set_deq.set_new_tr_dequeue_pointer(ptr.as_u64())
+        .set_slot_id(slot.get())
+        .set_endpoint_id(dci.get());
+    if cycle {
+        set_deq.set_dequeue_cycle_state();
+    }
+    let Ok(recv) = push_command_async(Allowed::SetTrDequeuePointer(set_deq)).await else {
+        return false;
+    };
+    matches!(timeout_at(deadline, recv).await, Some(Ok(c)) if c.completion_code() == Ok(CompletionCode::Success))
+}
+
+/// 有効でU0にあるポートをU3にする
+async fn suspend_ports() {
+    let ports: Vec<PortId> = root_ports()
+        .filter(|&p| {
+            let portsc = with_regs(|r| r.port_register_set.read_volatile_at(p.index()).portsc);
+            portsc.port_enabled_disabled() && portsc.port_link_state() == PLS_U0
+        })
+        .collect();
+    for &port_id in &ports {
+        set_link_state(port_id, PLS_U3);
+    }
+    *SUSPENDED_PORTS.lock() = ports.clone();
+
+    let deadline = get_current_tick() + ms_to_ticks(U3_TIMEOUT_MS);
+    while !ports.iter().all(|&p| link_state(p) == PLS_U3) && get_current_tick() < deadline {
+        sleep(1).await;
+    }
+    for &port_id in ports.iter().filter(|&&p| link_state(p) != PLS_U3) {
+        warn!("usb: port {port_id} did not enter U3");
+    }
+}
+
+/// サスペンドしたポートをU0に戻す。その間に切断されたポートは触らない
+async fn resume_ports() {
+    let ports: Vec<PortId> = SUSPENDED_PORTS.lock().drain(..).filter(|&p| link_state(p) == PLS_U3).collect();
+
+    // USB3はU0を書けばよいが、USB2はResumeを一定時間送ってからU0にする
+    let (usb3, usb2): (Vec<PortId>, Vec<PortId>) = ports.into_iter().partition(|&p| is_usb3_port(p));
+    for &port_id in &usb3 {
+        set_link_state(port_id, PLS_U0);
+    }
+    if !usb2.is_empty() {
+        for &port_id in &usb2 {
+            set_link_state(port_id, PLS_RESUME);
+        }
+        sleep(ms_to_ticks(RESUME_SIGNAL_MS)).await;
+        for &port_id in &usb2 {
+            set_link_state(port_id, PLS_U0);
+        }
+    }
+}
+
+fn link_state(port_id: PortId) -> u8 {
+    with_regs(|r| r.port_register_set.read_volatile_at(port_id.index()).portsc.port_link_state())
+}
+
+fn set_link_state(port_id: PortId, pls: u8) {
+    update_portsc(port_id, |p| {
+        p.set_port_link_state(pls);
+        p.set_port_link_state_write_strobe();
+    });
+}
+
+/// HCHaltedが`halted`になるのを待つ。期限までにならなければfalse
+async fn wait_halted(halted: bool) -> bool {
+    let deadline = get_current_tick() + ms_to_ticks(HALT_TIMEOUT_MS);
+    loop {
+        if with_regs(|r| r.operational.usbsts.read_volatile().hc_halted()) == halted {
+            return true;
+        }
+        if get_current_tick() >= deadline {
+            return false;
+        }
+        sleep(1).await;
+    }
+}
+
+pub fn run_power_tests() {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    assert!(power_state() == PowerState::Running);
+    assert!(Pin::new(&mut wait_running()).poll(&mut cx).is_ready());
+
+    // Running以外では待たされ、Runningに戻ると起こされる
+    assert!(transition(PowerState::Running, PowerState::Suspending));
+    assert!(!transition(PowerState::Running, PowerState::Suspended));
+    assert!(Pin::new(&mut wait_running()).poll(&mut cx).is_pending());
+    assert!(WAITERS.lock().len() == 1);
+    set_running();
+    assert!(WAITERS.lock().is_empty());
+    assert!(Pin::new(&mut wait_running()).poll(&mut cx).is_ready());
+
+    let (slot, dci) = (SlotId::new(1).unwrap(), Dci::new(3).unwrap());
+    let ep = track_endpoint(slot, dci);
+    assert!(ACTIVE_ENDPOINTS.lock().contains(&(slot, dci)));
+    drop(ep);
+    assert!(!ACTIVE_ENDPOINTS.lock().contains(&(slot, dci)));
+}
@@ -0,0 +1,209 @@
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{memory_manager::{LazyInit, Mutex}, timer::{get_current_tick, ms_to_ticks, TIMER_FREQ}};
+
+use super::{doorbell::SlotId, runtime::{new_broadcast_channel, sleep, BroadcastReceiver, BroadcastSender}, spawn};
+
+/// 起動時に繋がっていたデバイスを待つ最長の時間
+pub const READY_TIMEOUT_MS: u64 = 3000;
+
+/// 1台のデバイスの列挙の結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// クラスドライバの初期化まで終わった
+    Attached(SlotId),
+    /// 列挙か設定に失敗した。スロットを割り当てる前ならNone
+    Failed(Option<SlotId>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadySummary {
+    pub devices: usize,
+    pub failed: usize,
+    /// 全部が揃う前にREADY_TIMEOUT_MSが過ぎた
+    pub timed_out: bool,
+    pub elapsed_ms: u64,
+}
+
+/// "USB ready: 2 devices, 0 failed, 640ms" の1行
+impl fmt::Display for ReadySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "USB ready: {} devices, {} failed, {}ms", self.devices, self.failed, self.elapsed_ms)?;
+        if self.timed_out {
+            write!(f, " (timed out)")?;
+        }
+        Ok(())
+    }
+}
+
+/// 起動時に繋がっていたデバイスの列挙が終わったかを数える
+pub struct ReadyTracker {
+    start: u64,
+    /// 起動時に接続されていたポートの数。数え終わるまではNone
+    expected: Option<usize>,
+    resolutions: Vec<Resolution>,
+    summary: Option<ReadySummary>,
+}
+
+impl ReadyTracker {
+    pub const fn new(start: u64) -> Self {
+        Self { start, expected: None, resolutions: Vec::new(), summary: None }
+    }
+
+    pub fn set_expected(&mut self, n: usize, now: u64) -> Option<ReadySummary> {
+        self.expected = Some(n);
+        self.check(now, false)
+    }
+
+    /// ハブの下で見つかったデバイスも待つ。準備が終わった後なら何もしない
+    pub fn expect_more(&mut self, n: usize) {
+        if self.summary.is_none() {
+            if let Some(expected) = &mut self.expected {
+                *expected += n;
+            }
+        }
+    }
+
+    pub fn resolve(&mut self, r: Resolution, now: u64) -> Option<ReadySummary> {
+        if self.summary.is_none() {
+            self.resolutions.push(r);
+        }
+        self.check(now, false)
+    }
+
+    pub fn time_out(&mut self, now: u64) -> Option<ReadySummary> {
+        self.check(now, true)
+    }
+
+    /// 全部揃うかタイムアウトしたら準備完了にする。完了したときに一度だけSummaryを返す
+    fn check(&mut self, now: u64, timed_out: bool) -> Option<ReadySummary> {
+        if self.summary.is_some() {
+            return None;
+        }
+        let done = self.expected.is_some_and(|n| self.resolutions.len() >= n);
+        if !done && !timed_out {
+            return None;
+        }
+        let failed = self.resolutions.iter().filter(|r| matches!(r, Resolution::Failed(_))).count();
+        let summary = ReadySummary {
+            devices: self.resolutions.len() - failed,
+            failed,
+            timed_out: !done,
+            elapsed_ms: now.saturating_sub(self.start) * 1000 / TIMER_FREQ as u64,
+        };
+        self.summary = Some(summary);
+        Some(summary)
+    }
+
+    pub fn summary(&self) -> Option<ReadySummary> {
+        self.summary
+    }
+
+    pub fn resolutions(&self) -> &[Resolution] {
+        &self.resolutions
+    }
+}
+
+static TRACKER: Mutex<ReadyTracker> = Mutex::new(ReadyTracker::new(0));
+static READY: LazyInit<(BroadcastReceiver, BroadcastSender)> = LazyInit::new();
+
+/// 数え始める。タイムアウトはSPAWNERを初期化した後にstart_ready_timeoutで仕掛ける
+pub fn init_ready() {
+    *TRACKER.lock() = ReadyTracker::new(get_current_tick());
+    READY.lock().init(new_broadcast_channel());
+}
+
+pub fn start_ready_timeout() {
+    spawn(async {
+        sleep(ms_to_ticks(READY_TIMEOUT_MS)).await;
+        let summary = TRACKER.lock().time_out(get_current_tick());
+        publish(summary);
+        Ok(())
+    });
+}
+
+fn publish(summary: Option<ReadySummary>) {
+    if let Some(summary) = summary {
+        println!("{summary}");
+        READY.lock().1.send();
+    }
+}
+
+/// 起動時に接続されていたポートの数を知らせる
+pub fn set_expected(n: usize) {
+    let summary = TRACKER.lock().set_expected(n, get_current_tick());
+    publish(summary);
+}
+
+/// 起動時に繋がっていたハブの下にn台のデバイスがあることを知らせる
+pub fn expect_more(n: usize) {
+    TRACKER.lock().expect_more(n);
+}
+
+/// 1台の列挙が終わった(か失敗した)ことを知らせる
+pub fn resolve(r: Resolution) {
+    let summary = TRACKER.lock().resolve(r, get_current_tick());
+    publish(summary);
+}
+
+pub fn is_ready() -> bool {
+    TRACKER.lock().summary().is_some()
+}
+
+/// 準備ができていればその要約と、起動時のデバイスそれぞれの結果
+pub fn ready_summary() -> Option<(ReadySummary, Vec<Resolution>)> {
+    let tracker = TRACKER.lock();
+    tracker.summary().map(|s| (s, tracker.resolutions().to_vec()))
+}
+
+/// 起動時に繋がっていたデバイスが揃う(かタイムアウトする)と完了するFuture
+pub fn wait_ready() -> impl core::future::Future<Output = ()> {
+    READY.lock().0.clone()
+}
+
+pub fn run_ready_tests() {
+    let freq = TIMER_FREQ as u64;
+    let slot = |id| SlotId::new(id).unwrap();
+
+    // 2台とも揃ったところで一度だけ完了する
+    let mut t = ReadyTracker::new(100);
+    assert!(t.resolve(Resolution::Attached(slot(1)), 150).is_none());
+    assert!(t.set_expected(2, 160).is_none());
+    let s = t.resolve(Resolution::Attached(slot(2)), 100 + freq * 64 / 100).unwrap();
+    assert!(format!("{s}") == "USB ready: 2 devices, 0 failed, 640ms");
+    assert!(t.resolve(Resolution::Attached(slot(3)), 200 + freq).is_none());
+    assert!(t.time_out(300 + freq).is_none());
+    assert!(t.resolutions() == [Resolution::Attached(slot(1)), Resolution::Attached(slot(2))]);
+
+    // 失敗も結果に数える
+    let mut t = ReadyTracker::new(0);
+    assert!(t.set_expected(2, 0).is_none());
+    assert!(t.resolve(Resolution::Failed(None), 10).is_none());
+    let s = t.resolve(Resolution::Failed(Some(slot(1))), freq).unwrap();
+    assert!(s == ReadySummary { devices: 0, failed: 2, timed_out: false, elapsed_ms: 1000 });
+
+    // 繋がっていなければすぐに完了する
+    let mut t = ReadyTracker::new(0);
+    assert!(t.set_expected(0, 0).is_some_and(|s| s.devices == 0 && !s.timed_out));
+
+    // 揃わないままタイムアウトする
+    let mut t = ReadyTracker::new(0);
+    assert!(t.set_expected(2, 0).is_none());
+    assert!(t.resolve(Resolution::Attached(slot(1)), 10).is_none());
+    let s = t.time_out(freq * 3).unwrap();
+    assert!(format!("{s}") == "USB ready: 1 devices, 0 failed, 3000ms (timed out)");
+    assert!(t.summary() == Some(s));
+    assert!(t.resolve(Resolution::Attached(slot(2)), freq * 4).is_none());
+
+    // ハブの下のデバイスも揃うまで待つ。終わった後に増やしても変わらない
+    let mut t = ReadyTracker::new(0);
+    assert!(t.set_expected(1, 0).is_none());
+    t.expect_more(2);
+    assert!(t.resolve(Resolution::Attached(slot(1)), 1).is_none());
+    assert!(t.resolve(Resolution::Attached(slot(2)), 2).is_none());
+    assert!(t.resolve(Resolution::Failed(None), 3).is_some_and(|s| s.devices == 2 && s.failed == 1));
+    t.expect_more(1);
+    assert!(t.summary().is_some_and(|s| s.devices == 2));
+}
@@ -0,0 +1,116 @@
+use core::fmt::{self, Write};
+
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+
+use crate::{memory_manager::Mutex, println};
+
+use super::doorbell::{PortId, SlotId};
+
+/// 選ばれた代替設定のインターフェースと、それを使っているクラスドライバ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceInfo {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// どのドライバも使っていなければNone
+    pub claimed_by: Option<&'static str>,
+}
+
+/// 設定が終わって使えるようになったデバイス
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub slot: SlotId,
+    /// 繋がっているルートハブのポート
+    pub port: Option<PortId>,
+    /// ハブの下なら、ハブのポート番号を4ビットずつ並べたもの。ルートハブに直接繋がっていれば0
+    pub route_string: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: String,
+    pub product: String,
+    pub interfaces: Vec<InterfaceInfo>,
+}
+
+/// 設定の終わったデバイス。スロットの後始末で消える
+static DEVICES: Mutex<BTreeMap<SlotId, DeviceInfo>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn insert(info: DeviceInfo) {
+    DEVICES.lock().insert(info.slot, info);
+}
+
+pub(crate) fn remove(slot: SlotId) {
+    DEVICES.lock().remove(&slot);
+}
+
+/// 今繋がっているデバイスの写し。スロット番号順
+pub fn snapshot() -> Vec<DeviceInfo> {
+    DEVICES.lock().values().cloned().collect()
+}
+
+/// ルートハブのポートから辿るポート番号を.で区切って書く。例えばポート3のハブのポート2なら3.2
+fn write_port_path(out: &mut dyn Write, port: Option<PortId>, route_string: u32) -> fmt::Result {
+    match port {
+        Some(port) => write!(out, "{port}")?,
+        None => write!(out, "?")?,
+    }
+    let mut route = route_string;
+    while route & 0xf != 0 {
+        write!(out, ".{}", route & 0xf)?;
+        route >>= 4;
+    }
+    Ok(())
+}
+
+/// デバイスごとに1行と、その下にインターフェースごとに1行
+pub fn write_devices(out: &mut dyn Write, devices: &[DeviceInfo]) -> fmt::Result {
+    if devices.is_empty() {
+        writeln!(out, "no devices")?;
+    }
+    for d in devices {
+        write!(out, "slot {}: port ", d.slot)?;
+        write_port_path(out, d.port, d.route_string)?;
+        writeln!(out, " {:04x}:{:04x} {:?} {:?}", d.vendor_id, d.product_id, d.manufacturer, d.product)?;
+        for i in &d.interfaces {
+            writeln!(
+                out,
+                "  interface {}: class {:02x}/{:02x}/{:02x} {}",
+                i.number, i.class, i.subclass, i.protocol, i.claimed_by.unwrap_or("-")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// 繋がっているデバイスの一覧をコンソールに出す
+pub fn dump_devices() {
+    let mut out = String::new();
+    let _ = write_devices(&mut out, &snapshot());
+    for line in out.lines() {
+        println!("{line}");
+    }
+}
+
+pub fn run_registry_tests() {
+    let slot = SlotId::new(2).unwrap();
+    let keyboard = DeviceInfo {
+        slot,
+        port: PortId::new(3),
+        route_string: 0x12,
+        vendor_id: 0x046d,
+        product_id: 0xc31c,
+        manufacturer: String::from("Logi"),
+        product: String::new(),
+        interfaces: vec![
+            InterfaceInfo { number: 0, class: 3, subclass: 1, protocol: 1, claimed_by: Some("keyboard") },
+            InterfaceInfo { number: 1, class: 3, subclass: 0, protocol: 0, claimed_by: None },
+        ],
+    };
+    let mut out = String::new();
+    write_devices(&mut out, &[keyboard]).unwrap();
+    assert!(out == "slot 2: port 3.2.1 046d:c31c \"Logi\" \"\"\n  interface 0: class 03/01/01 keyboard\n  interface 1: class 03/00/00 -\n");
+
+    out.clear();
+    write_devices(&mut out, &[]).unwrap();
+    assert!(out == "no devices\n");
+}
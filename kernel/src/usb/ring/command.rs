@@ -1,6 +1,7 @@
 use super::ring::ProducerRing;
-use crate::usb::xhci::{LinearMapper, UnknownTRB_, XhciError};
-use alloc::collections::BTreeMap;
+use crate::{addr::PhysAddr, usb::{doorbell::ring_command_doorbell, xhci::{ErrorKind, LinearMapper, UnknownTRB_, XhciError}}};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::task::{Context, Poll, Waker};
 use futures::channel::oneshot;
 use xhci::{ring::trb::{self, event::CommandCompletion}, Registers};
 
@@ -8,43 +9,66 @@ use xhci::{ring::trb::{self, event::CommandCompletion}, Registers};
 /// リングへのTRB追加、CommandCompletionのリスナーへの通知
 pub struct CommandRing {
     ring: ProducerRing,
-    listener: BTreeMap<u64, oneshot::Sender<CommandCompletion>>,
+    listener: BTreeMap<PhysAddr, oneshot::Sender<CommandCompletion>>,
+    /// リングが一杯で投入を待っているタスク
+    space_waiters: Vec<Waker>,
 }
 
 pub fn init_command_ring(size: usize, regs: &mut Registers<LinearMapper>) -> CommandRing {
     let ring = ProducerRing::new(32);
     regs.operational.crcr.update_volatile(|x| {
-        x.set_command_ring_pointer(ring.get_buf_ptr());
+        x.set_command_ring_pointer(ring.get_buf_ptr().as_u64());
         x.set_ring_cycle_state();
     });
 
     CommandRing {
         ring,
-        listener: BTreeMap::new()
+        listener: BTreeMap::new(),
+        space_waiters: Vec::new(),
     }
 }
 
 impl CommandRing {
 
     pub fn push_command(&mut self, trb: trb::command::Allowed, regs: &mut Registers<LinearMapper>) -> Result<oneshot::Receiver<CommandCompletion>, XhciError> {
-        let ptr = self.ring.push(UnknownTRB_(trb.into_raw()))? as u64;
-        
-        regs.doorbell.update_volatile_at(0, |d|{
-            d.set_doorbell_target(0);
-        });
-        
+        let ptr = self.ring.push(UnknownTRB_(trb.into_raw()))?;
+
+        // ドアベルを鳴らした直後に完了が届いても取りこぼさないよう、先に待ち受けを登録する
         let (send, recv) = oneshot::channel();
         self.listener.insert(ptr, send);
+
+        ring_command_doorbell(regs);
         Ok(recv)
     }
 
-    pub async fn emit_command_async(&mut self, trb: trb::command::Allowed, regs: &mut Registers<LinearMapper>) -> Result<CommandCompletion, XhciError> {
-        Ok(self.push_command(trb, regs)?.await.unwrap())
+    /// push_commandと同じだが、リングが一杯なら完了で空きができるまで待つ
+    pub fn poll_push_command(
+        &mut self,
+        trb: trb::command::Allowed,
+        regs: &mut Registers<LinearMapper>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<oneshot::Receiver<CommandCompletion>, XhciError>> {
+        match self.push_command(trb, regs) {
+            Err(e) if matches!(e.kind(), ErrorKind::RingIsFull) => {
+                self.space_waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
     }
 
     pub fn on_command_completion(&mut self, completion: CommandCompletion) {
-        if let Some(rcv) = self.listener.remove(&completion.command_trb_pointer()) {
-            rcv.send(completion);
+        let ptr = PhysAddr::new(completion.command_trb_pointer());
+        // xHCはこのTRBまで読んだので、その枠は再び使える
+        self.ring.set_deque_ptr(ptr);
+        for waker in self.space_waiters.drain(..) {
+            waker.wake();
+        }
+
+        match self.listener.remove(&ptr) {
+            // 待っていた側が先に諦めていれば、送れなくてもよい
+            Some(rcv) => { let _ = rcv.send(completion); }
+            None => warn!("command completion for unknown TRB {:#x}, dropped", ptr.as_u64()),
         }
     }
-}
\ No newline at end of file
+}
@@ -1,14 +1,15 @@
-use core::mem::{size_of, ManuallyDrop};
-
+use alloc::vec::Vec;
 use bitfield::bitfield;
-use xhci::{ring::trb::{self, event::{CommandCompletion, PortStatusChange, TransferEvent}}, Registers};
+use xhci::{ring::trb::{self, event::{CommandCompletion, CompletionCode, PortStatusChange, TransferEvent}}, Registers};
 
 use super::ring::ConsumerRing;
-use crate::usb::{xhci::{LinearMapper, UnknownTRB}, runtime::Sender};
+use crate::{addr::ptr_to_phys, println, usb::{xhci::{AlignedAlloc, LinearMapper}, runtime::Sender}};
 
 /// XHCからの割り込みを受けて、EventRingに追加されたイベントを確認、Listenerに通知する
 pub struct EventRing {
     ring: ConsumerRing,
+    /// xHCが読み続けるので、リングと同じだけ生かしておく
+    _segment_table: Vec<EventRingSegmentTableEntry<[u64; 2]>, AlignedAlloc<64>>,
     trf_listener: Sender<TransferEvent>,
     cmd_listener: Sender<CommandCompletion>,
     port_listener: Sender<PortStatusChange>
@@ -22,29 +23,49 @@ bitfield! {
     ring_segment_size, set_ring_segment_size: 79,64;
 }
 
-pub fn init_event_ring(regs: &mut Registers<LinearMapper>, trf_listener: Sender<TransferEvent>, cmd_listener: Sender<CommandCompletion>, port_listener: Sender<PortStatusChange>) -> EventRing{
-    let ring = ConsumerRing::new(32);
-    
-    let mut entry = EventRingSegmentTableEntry([0; 2]);
-    entry.set_base_addr(ring.get_buf_ptr());
-    entry.set_ring_segment_size(ring.size() as u64);
-    
-    let er_table = ManuallyDrop::new(vec![entry]);
+/// Event Ringのセグメント数。xHCが扱えるERSTの大きさを超える分は切り詰める
+pub const EVENT_RING_SEGMENTS: usize = 4;
+/// 1セグメントのエントリ数。仕様上16以上4096以下
+pub const EVENT_RING_SEGMENT_SIZE: usize = 64;
+
+pub fn init_event_ring(
+    regs: &mut Registers<LinearMapper>,
+    segments: usize,
+    segment_size: usize,
+    trf_listener: Sender<TransferEvent>,
+    cmd_listener: Sender<CommandCompletion>,
+    port_listener: Sender<PortStatusChange>,
+) -> EventRing {
+    assert!((16..=4096).contains(&segment_size));
+    let erst_max = usize::from(regs.capability.hcsparams2.read_volatile().event_ring_segment_table_max());
+    let segments = segments.clamp(1, erst_max);
+    let ring = ConsumerRing::new(segments, segment_size);
+
+    let mut segment_table = Vec::with_capacity_in(segments, AlignedAlloc::<64> {});
+    for base in ring.segment_ptrs() {
+        let mut entry = EventRingSegmentTableEntry([0; 2]);
+        entry.set_base_addr(base.as_u64());
+        entry.set_ring_segment_size(ring.segment_size() as u64);
+        segment_table.push(entry);
+    }
 
     let mut iregs = regs.interrupter_register_set.interrupter_mut(0);
     iregs
         .erstsz
-        .update_volatile(|x| x.set(er_table.len() as u16));
-    iregs
-        .erstba
-        .update_volatile(|x| x.set(er_table.as_ptr() as u64));
+        .update_volatile(|x| x.set(segment_table.len() as u16));
     iregs.erdp.update_volatile(|x| {
-        x.set_0_event_handler_busy();
-        x.set_event_ring_dequeue_pointer(ring.get_buf_ptr())
+        x.set_event_ring_dequeue_pointer(ring.get_deque_ptr().as_u64());
+        x.set_dequeue_erst_segment_index(0);
+        x.clear_event_handler_busy();
     });
-    
+    // ERSTBAへの書き込みでxHCがテーブルを読むので、最後に書く
+    iregs
+        .erstba
+        .update_volatile(|x| x.set(ptr_to_phys(segment_table.as_ptr()).as_u64()));
+
     EventRing {
         ring,
+        _segment_table: segment_table,
         trf_listener,
         cmd_listener,
         port_listener
@@ -61,10 +82,22 @@ impl EventRing {
             self.process_event(trb);
         }
 
+        // DESIは0..8の範囲でしか表せないので、下位3ビットだけを書く
+        let segment = (self.ring.deque_segment() & 0b111) as u8;
         regs.interrupter_register_set.interrupter_mut(0).erdp.update_volatile(|x|{
-            x.set_event_ring_dequeue_pointer(self.ring.deque_index() as u64 * size_of::<UnknownTRB>() as u64 + self.ring.get_buf_ptr());
+            x.set_event_ring_dequeue_pointer(self.ring.get_deque_ptr().as_u64());
+            x.set_dequeue_erst_segment_index(segment);
             x.clear_event_handler_busy();
         });
+
+        let status = regs.operational.usbsts.read_volatile();
+        if status.host_controller_error() {
+            error!("xHC: host controller error, USBSTS={status:?}");
+        }
+        if status.host_system_error() {
+            error!("xHC: host system error, USBSTS={status:?}");
+            regs.operational.usbsts.update_volatile(|x| { x.clear_host_system_error(); });
+        }
     }
 
     fn process_event(&self, trb: trb::event::Allowed) {
@@ -81,7 +114,14 @@ impl EventRing {
             },
             trb::event::Allowed::BandwidthRequest(_) => todo!(),
             trb::event::Allowed::Doorbell(_) => todo!(),
-            trb::event::Allowed::HostController(_) => todo!(),
+            trb::event::Allowed::HostController(trb) if trb.completion_code() == Ok(CompletionCode::EventRingFullError) => {
+                // xHCはリングに空きができるまでイベントを書かずに待つ。ここまで溜まるなら大きさが足りない
+                warn!("xHC: event ring full ({} entries), events may have been delayed or lost", self.ring.size());
+            },
+            trb::event::Allowed::HostController(trb) => {
+                // サスペンド・レジュームの途中で来ることがあるので、止まらずに記録だけする
+                println!("xHC: host controller event {:?}", trb.completion_code());
+            },
             trb::event::Allowed::DeviceNotification(_) => todo!(),
             trb::event::Allowed::MfindexWrap(_) => todo!(),
         }
@@ -1,4 +1,5 @@
 pub mod command;
 pub mod event;
 pub mod ring;
+pub mod ring_core;
 pub mod transfer;
\ No newline at end of file
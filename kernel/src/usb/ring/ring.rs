@@ -1,95 +1,96 @@
 use core::{
+    fmt,
     iter::repeat_with,
     mem::{size_of, transmute},
 };
 
 use xhci::ring::trb::Link;
 
-use crate::usb::xhci::{UnknownTRB, XhciError};
+use super::ring_core::{RingCore, RingKind};
+use crate::{heap_profile::with_alloc_tag, addr::{ptr_to_phys, PhysAddr}, usb::xhci::{AlignedAlloc, ErrorKind, UnknownTRB, XhciError}};
 
 use alloc::vec::Vec;
 
-use alloc::string::ToString;
-
 use alloc::boxed::Box;
 
 use crate::{println, print};
 
 pub struct ProducerRing {
     data: Box<[UnknownTRB]>,
-    cycle_state: bool,
-    enque: usize,
-    deque: usize,
+    core: RingCore,
 }
 
 impl ProducerRing {
     pub fn new(size: usize) -> Self {
-        let mut data = repeat_with(UnknownTRB::default)
-            .take(size)
-            .collect::<Vec<UnknownTRB>>()
-            .into_boxed_slice();
+        let mut data = with_alloc_tag("usb-ring", || {
+            repeat_with(UnknownTRB::default).take(size).collect::<Vec<UnknownTRB>>().into_boxed_slice()
+        });
         data[size - 1] = unsafe {
             let mut link = Link::new();
-            link.set_ring_segment_pointer(data.as_ptr() as u64)
+            link.set_ring_segment_pointer(ptr_to_phys(data.as_ptr()).as_u64())
                 .set_toggle_cycle();
             transmute(link)
         };
 
         Self {
+            core: RingCore::new(size, RingKind::Producer),
             data,
-            cycle_state: true,
-            enque: 0,
-            deque: 0,
         }
     }
 
-    pub fn next_ptr(&mut self, ptr: usize) -> usize {
-        debug_assert!(ptr <= self.data.len() - 2);
-        if ptr + 1 == self.data.len() - 1 {
-            0
-        } else {
-            ptr + 1
+    /// TRBを追加し、その物理アドレスを返す
+    pub fn push(&mut self, mut trb: UnknownTRB) -> Result<PhysAddr, XhciError> {
+        if self.core.is_full() {
+            return Err(ErrorKind::RingIsFull.into());
         }
-    }
 
-    fn advance_enque_ptr(&mut self) {
-        self.enque += 1;
-        if self.enque == self.data.len() - 1 {
-            self.data[self.enque].set_cycle_bit(self.cycle_state);
-            self.enque = 0;
-            self.cycle_state = !self.cycle_state;
+        let enque = self.core.enque();
+        trb.set_cycle_bit(self.core.cycle());
+        self.data[enque] = trb;
+        let ret_ptr = ptr_to_phys(&self.data[enque]);
+
+        if let Some((link, cycle)) = self.core.advance_enque() {
+            self.data[link].set_cycle_bit(cycle);
         }
+
+        Ok(ret_ptr)
     }
 
-    pub fn push(&mut self, mut trb: UnknownTRB) -> Result<*mut UnknownTRB, XhciError> {
-        if self.next_ptr(self.enque) == self.deque {
-            return Err(XhciError::RingIsFull);
+    /// xHCが`deque_ptr`のTRBまで処理した。リングの外を指していたら何もしない
+    pub fn set_deque_ptr(&mut self, deque_ptr: PhysAddr) {
+        if !self.contains(deque_ptr) {
+            return;
         }
+        let index = (deque_ptr.as_u64() - self.get_buf_ptr().as_u64()) as usize / size_of::<UnknownTRB>();
+        self.core.complete(index);
+    }
 
-        trb.set_cycle_bit(self.cycle_state);
-        self.data[self.enque] = trb;
-        let ret_ptr = &mut self.data[self.enque] as *mut UnknownTRB;
-
-        self.advance_enque_ptr();
+    pub fn cycle_state(&self) -> bool {
+        self.core.cycle()
+    }
 
-        Ok(ret_ptr)
+    /// 未完了のTRBを全て捨て、デキューポインタをエンキューポインタに揃える
+    pub fn discard_pending(&mut self) {
+        self.core.discard_pending();
     }
 
-    pub fn set_deque_ptr(&mut self, deque_ptr: u64) {
-        let index = (deque_ptr - self.get_buf_ptr()) as usize / size_of::<UnknownTRB>();
-        self.deque = self.next_ptr(index);
+    /// リングのバッファが`ptr`を含むならtrue
+    pub fn contains(&self, ptr: PhysAddr) -> bool {
+        let base = self.get_buf_ptr().as_u64();
+        (base..base + (self.data.len() * size_of::<UnknownTRB>()) as u64).contains(&ptr.as_u64())
     }
 
-    pub fn cycle_state(&self) -> bool {
-        self.cycle_state
+    pub fn get_buf_ptr(&self) -> PhysAddr {
+        ptr_to_phys(self.data.as_ptr())
     }
 
-    pub fn get_buf_ptr(&self) -> u64 {
-        self.data.as_ptr() as u64
+    pub fn get_enque_ptr(&self) -> PhysAddr {
+        ptr_to_phys(&self.data[self.core.enque()])
     }
 
-    pub fn get_enque_ptr(&self) -> u64 {
-        &self.data[self.enque] as *const UnknownTRB as u64
+    /// エンキューポインタと、そこに次に書くTRBのサイクルビット。Set TR Dequeue Pointerコマンドに渡す
+    pub fn get_enque_ptr_with_cycle(&self) -> (PhysAddr, bool) {
+        (self.get_enque_ptr(), self.cycle_state())
     }
 
     pub fn size(&self) -> usize {
@@ -97,66 +98,102 @@ impl ProducerRing {
     }
 }
 
+/// xHCが書き込むリング。同じ大きさのセグメントを並べたもので、最後のセグメントの次は先頭に戻る
 pub struct ConsumerRing {
-    data: Vec<UnknownTRB>,
-    cycle_state: bool,
-    deque: usize,
+    segments: Vec<Vec<UnknownTRB, AlignedAlloc<64>>>,
+    segment_size: usize,
+    core: RingCore,
 }
 
 impl ConsumerRing {
-    pub fn new(size: usize) -> Self {
-        let data: Vec<UnknownTRB> = repeat_with(UnknownTRB::default).take(size).collect();
+    pub fn new(segments: usize, segment_size: usize) -> Self {
+        let core = RingCore::new(segments * segment_size, RingKind::Consumer);
+        let segments = with_alloc_tag("usb-ring", || {
+            repeat_with(|| {
+                let mut seg = Vec::with_capacity_in(segment_size, AlignedAlloc::<64> {});
+                seg.resize(segment_size, UnknownTRB::default());
+                seg
+            })
+            .take(segments)
+            .collect::<Vec<_>>()
+        });
 
         Self {
-            data,
-            cycle_state: true,
-            deque: 0,
+            segments,
+            segment_size,
+            core,
         }
     }
 
+    /// 通し番号iのTRB
+    fn at(&self, i: usize) -> &UnknownTRB {
+        &self.segments[i / self.segment_size][i % self.segment_size]
+    }
+
     pub fn deque_index(&self) -> usize {
-        self.deque
+        self.core.deque()
+    }
+
+    /// デキューポインタがあるセグメントの番号。ERDPのDESIに書く
+    pub fn deque_segment(&self) -> usize {
+        self.core.deque() / self.segment_size
     }
 
     pub fn pop(&mut self) -> Option<UnknownTRB> {
-        let trb = self.data[self.deque];
+        let trb = *self.at(self.core.deque());
 
-        if trb.cycle_bit() != self.cycle_state {
+        if trb.cycle_bit() != self.core.cycle() {
             return None;
         }
 
-        self.deque += 1;
-        if self.deque == self.data.len() {
-            self.deque = 0;
-            self.cycle_state = !self.cycle_state;
-        }
-
+        self.core.advance_deque();
         Some(trb)
     }
 
     pub fn cycle_state(&self) -> bool {
-        self.cycle_state
+        self.core.cycle()
     }
 
-    pub fn get_buf_ptr(&self) -> u64 {
-        self.data.as_ptr() as u64
+    /// 各セグメントの先頭の物理アドレス。Event Ring Segment Tableに書く
+    pub fn segment_ptrs(&self) -> impl Iterator<Item = PhysAddr> + '_ {
+        self.segments.iter().map(|seg| ptr_to_phys(seg.as_ptr()))
     }
 
+    pub fn segment_size(&self) -> usize {
+        self.segment_size
+    }
+
+    pub fn get_deque_ptr(&self) -> PhysAddr {
+        ptr_to_phys(self.at(self.core.deque()))
+    }
+
+    /// 全セグメントのTRBの数
     pub fn size(&self) -> usize {
-        self.data.len()
+        self.segments.len() * self.segment_size
+    }
+}
+
+/// TRBを文字列を経由せずに表示する
+struct TrbFmt<T>(Option<T>);
+
+impl<T: fmt::Debug> fmt::Display for TrbFmt<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(trb) => write!(f, "{trb:?}"),
+            None => f.write_str("Invalid TRB"),
+        }
     }
 }
 
 fn dump_command_ring(ring: &ProducerRing) {
     for i in 0..ring.size() {
         if ring.data[i].cycle_bit() == ring.cycle_state() {
-            let trb = unsafe { ring.data[i].into_cmd_trb() }
-                .map_or("Invalid TRB".to_string(), |x| format!("{x:?}"));
+            let trb = TrbFmt(unsafe { ring.data[i].into_cmd_trb() });
             println!(
                 "[{}{}{}]{}, {}",
                 i,
-                if ring.deque == i { " d" } else { "" },
-                if ring.enque == i { " e" } else { "" },
+                if ring.core.deque() == i { " d" } else { "" },
+                if ring.core.enque() == i { " e" } else { "" },
                 trb,
                 ring.data[i].cycle_bit()
             );
@@ -166,11 +203,11 @@ fn dump_command_ring(ring: &ProducerRing) {
 
 pub fn dump_event_ring(ring: &ConsumerRing) {
     for i in 0..ring.size() {
-        if ring.data[i].cycle_bit() == ring.cycle_state() {
-            let trb = unsafe { ring.data[i].into_event_trb() }
-                .map_or("Invalid TRB".to_string(), |x| format!("{x:?}"));
-            print!("{}", ring.data[i].cycle_bit() as usize);
-            println!("[{}]{}, {}", i, trb, ring.data[i].cycle_bit());
+        let data = ring.at(i);
+        if data.cycle_bit() == ring.cycle_state() {
+            let trb = TrbFmt(unsafe { data.into_event_trb() });
+            print!("{}", data.cycle_bit() as usize);
+            println!("[{}]{}, {}", i, trb, data.cycle_bit());
         }
     }
     println!("\nd={}", ring.deque_index())
@@ -179,16 +216,48 @@ pub fn dump_event_ring(ring: &ConsumerRing) {
 pub fn dump_trf_ring(ring: &ProducerRing) {
     for i in 0..ring.size() {
         if ring.data[i].cycle_bit() == ring.cycle_state() {
-            let trb = unsafe { ring.data[i].into_trans_trb() }
-                .map_or("Invalid TRB".to_string(), |x| format!("{x:?}"));
+            let trb = TrbFmt(unsafe { ring.data[i].into_trans_trb() });
             println!(
                 "[{}{}{}]{}, {}",
                 i,
-                if ring.deque == i { " d" } else { "" },
-                if ring.enque == i { " e" } else { "" },
+                if ring.core.deque() == i { " d" } else { "" },
+                if ring.core.enque() == i { " e" } else { "" },
                 trb,
                 ring.data[i].cycle_bit()
             );
         }
     }
 }
+
+/// xHCの代わりにサイクルビットを書き込み、セグメントをまたいで読めることを確かめる
+pub fn run_consumer_ring_tests() {
+    let mut ring = ConsumerRing::new(3, 16);
+    assert!(ring.size() == 48 && ring.segment_ptrs().count() == 3);
+    assert!(ring.segment_ptrs().all(|p| p.as_u64() % 64 == 0));
+
+    // 2周目の途中まで書く。1周目はサイクル1、2周目は0
+    let produce = |ring: &mut ConsumerRing, i: usize, cycle: bool| {
+        ring.segments[i / 16][i % 16].set_cycle_bit(cycle);
+    };
+    for i in 0..48 {
+        produce(&mut ring, i, true);
+    }
+    for i in 0..20 {
+        assert!(ring.pop().is_some());
+        assert!(ring.deque_segment() == (i + 1) / 16);
+    }
+    assert!(ring.get_deque_ptr() == ptr_to_phys(&ring.segments[1][4]));
+    for _ in 20..48 {
+        assert!(ring.pop().is_some());
+    }
+    // 末尾を越えたら先頭のセグメントに戻り、サイクルが反転する
+    assert!(ring.pop().is_none() && ring.deque_segment() == 0 && !ring.cycle_state());
+    assert!(ring.get_deque_ptr() == ring.segment_ptrs().next().unwrap());
+    for i in 0..17 {
+        produce(&mut ring, i, false);
+    }
+    for _ in 0..17 {
+        assert!(ring.pop().is_some());
+    }
+    assert!(ring.pop().is_none() && ring.deque_segment() == 1);
+}
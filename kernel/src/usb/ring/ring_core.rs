@@ -0,0 +1,178 @@
+/// リングの種類。どちらの添字がサイクルビットを反転させるかと、末尾の扱いが違う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingKind {
+    /// ソフトウェアが書き込むリング(Command Ring, Transfer Ring)
+    /// 最後の枠はLink TRBで、エンキューポインタがそこに来たら先頭に戻ってサイクルを反転する
+    Producer,
+    /// xHCが書き込むリング(Event Ring)
+    /// Link TRBは無く、デキューポインタが末尾を越えたら先頭に戻ってサイクルを反転する
+    Consumer,
+}
+
+/// TRBの中身を持たない、添字とサイクルビットだけの状態機械
+/// ProducerRingとConsumerRingはこれにTRBのバッファを被せたもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RingCore {
+    kind: RingKind,
+    size: usize,
+    enque: usize,
+    deque: usize,
+    cycle: bool,
+}
+
+impl RingCore {
+    pub fn new(size: usize, kind: RingKind) -> Self {
+        // Link TRBのほかに、少なくとも1つは使える枠が要る
+        assert!(size >= 2);
+        Self { kind, size, enque: 0, deque: 0, cycle: true }
+    }
+
+    /// Link TRBの枠。Consumerには無い
+    pub fn link_index(&self) -> Option<usize> {
+        match self.kind {
+            RingKind::Producer => Some(self.size - 1),
+            RingKind::Consumer => None,
+        }
+    }
+
+    /// TRBを置ける枠の数
+    pub fn slots(&self) -> usize {
+        match self.kind {
+            RingKind::Producer => self.size - 1,
+            RingKind::Consumer => self.size,
+        }
+    }
+
+    /// iの次にTRBを置く枠。Link TRBの枠は飛ばす
+    /// iがLink TRBの枠でもよい(その次は先頭)
+    pub fn next(&self, i: usize) -> usize {
+        debug_assert!(i < self.size);
+        if i + 1 >= self.slots() {
+            0
+        } else {
+            i + 1
+        }
+    }
+
+    pub fn enque(&self) -> usize {
+        self.enque
+    }
+
+    pub fn deque(&self) -> usize {
+        self.deque
+    }
+
+    /// Producerならエンキューポインタの、Consumerならデキューポインタのサイクル
+    pub fn cycle(&self) -> bool {
+        self.cycle
+    }
+
+    /// Producerで、完了していないTRBが無い
+    pub fn is_empty(&self) -> bool {
+        self.enque == self.deque
+    }
+
+    /// Producerで、これ以上置くとデキューポインタに追いつく
+    pub fn is_full(&self) -> bool {
+        self.next(self.enque) == self.deque
+    }
+
+    /// Producerのエンキューポインタを1つ進める
+    /// 先頭に戻ったら、Link TRBの枠と、それに書くべきサイクル(反転前のもの)を返す
+    pub fn advance_enque(&mut self) -> Option<(usize, bool)> {
+        debug_assert!(self.kind == RingKind::Producer && !self.is_full());
+        self.enque = self.next(self.enque);
+        if self.enque != 0 {
+            return None;
+        }
+        let link = (self.size - 1, self.cycle);
+        self.cycle = !self.cycle;
+        Some(link)
+    }
+
+    /// Producerで、index番目のTRBまでxHCが処理した
+    pub fn complete(&mut self, index: usize) {
+        debug_assert!(self.kind == RingKind::Producer);
+        self.deque = self.next(index);
+    }
+
+    /// Producerの未完了のTRBを全て捨てる
+    pub fn discard_pending(&mut self) {
+        self.deque = self.enque;
+    }
+
+    /// Consumerのデキューポインタを1つ進める。先頭に戻ったらサイクルを反転する
+    pub fn advance_deque(&mut self) {
+        debug_assert!(self.kind == RingKind::Consumer);
+        self.deque = self.next(self.deque);
+        if self.deque == 0 {
+            self.cycle = !self.cycle;
+        }
+    }
+}
+
+/// 再現できる疑似乱数。テストで操作を選ぶのに使う
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) as usize % n
+    }
+}
+
+/// 置いたTRBの通し番号だけを持つ参照モデルと突き合わせながら、何周も出し入れする
+pub fn run_ring_core_tests() {
+    // 通し番号nのTRBの枠とサイクル
+    let pos = |slots: usize, n: usize| (n % slots, (n / slots) % 2 == 0);
+
+    for size in [2, 3, 4, 5, 8, 32] {
+        for seed in 0..4 {
+            let mut rng = Lcg(seed * 7919 + size as u64);
+            let mut ring = RingCore::new(size, RingKind::Producer);
+            let slots = ring.slots();
+            assert!(slots == size - 1 && ring.link_index() == Some(size - 1));
+            // 置いた数と、完了した数
+            let (mut pushed, mut completed) = (0usize, 0usize);
+            for _ in 0..4000 {
+                let in_flight = pushed - completed;
+                assert!(ring.is_empty() == (in_flight == 0));
+                assert!(ring.is_full() == (in_flight == slots - 1));
+                assert!((ring.enque(), ring.cycle()) == pos(slots, pushed));
+                assert!(ring.deque() == completed % slots);
+                match rng.next(4) {
+                    0 | 1 if !ring.is_full() => {
+                        let wrapped = ring.advance_enque();
+                        pushed += 1;
+                        // Link TRBは、周回を終えたTRBと同じサイクルで書く
+                        let expected = (pushed % slots == 0).then(|| (size - 1, pos(slots, pushed - 1).1));
+                        assert!(wrapped == expected);
+                    }
+                    2 if in_flight > 0 => {
+                        // 未完了のうちどれかまでをまとめて完了させる
+                        completed += 1 + rng.next(in_flight);
+                        ring.complete((completed - 1) % slots);
+                    }
+                    3 if rng.next(16) == 0 => {
+                        ring.discard_pending();
+                        completed = pushed;
+                    }
+                    _ => (),
+                }
+            }
+            // Link TRBを指すイベントが来ても、先頭に進むだけ
+            ring.complete(size - 1);
+            assert!(ring.deque() == 0);
+        }
+    }
+
+    for size in [2, 3, 32] {
+        let mut ring = RingCore::new(size, RingKind::Consumer);
+        let slots = ring.slots();
+        assert!(slots == size && ring.link_index().is_none());
+        for popped in 0..3000 {
+            assert!((ring.deque(), ring.cycle()) == pos(slots, popped));
+            ring.advance_deque();
+        }
+    }
+}
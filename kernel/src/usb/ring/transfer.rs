@@ -1,20 +1,32 @@
 use super::ring::ProducerRing;
-use crate::usb::xhci::{LinearMapper, UnknownTRB_, XhciError};
-use alloc::collections::BTreeMap;
-use futures::channel::oneshot;
+use crate::{addr::{ptr_to_phys, PhysAddr}, usb::{doorbell::{ring_endpoint, Dci, SlotId}, xhci::{ErrorKind, LinearMapper, UnknownTRB_, XhciError}}};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{future::Future, pin::Pin, task::{Context, Poll}};
+use futures::{channel::oneshot, FutureExt};
 use xhci::{ring::trb::{self, event::{CompletionCode, TransferEvent}, transfer::{Allowed, DataStage, Direction, SetupStage, StatusStage, TransferType}}, Registers};
 
 pub struct TransferRingSet {
-    rings: BTreeMap<(usize, usize), ProducerRing>,
-    listener: BTreeMap<u64, oneshot::Sender<Result<TransferEvent, XhciError>>>,
+    rings: BTreeMap<(SlotId, Dci), ProducerRing>,
+    listener: BTreeMap<PhysAddr, oneshot::Sender<Result<TransferEvent, XhciError>>>,
     ring_size: usize
 }
 
 pub enum ControlRequestType {
     GetDescriptor,
+    /// インターフェースに属するディスクリプタ(HIDのレポートディスクリプタなど)を読む
+    GetInterfaceDescriptor,
     SetConfigutation,
     SetProtocol,
     SetInterface,
+    SetReport,
+    SetIdle,
+    /// エンドポイントのHaltを解く標準要求。wValueはENDPOINT_HALT、wIndexはbEndpointAddress
+    ClearEndpointFeature,
+    /// 以下はハブクラスの要求(USB 2.0 11.24.2)
+    GetHubDescriptor,
+    GetPortStatus,
+    SetPortFeature,
+    ClearPortFeature,
 }
 
 enum TransferDirection {
@@ -26,9 +38,17 @@ impl ControlRequestType {
     fn get_actual_value(&self) -> (u8, u8) {
         match self {
             Self::GetDescriptor => (0b10000000, 6),
+            Self::GetInterfaceDescriptor => (0b10000001, 6),
             Self::SetConfigutation => (0b00000000, 9),
             Self::SetProtocol => (0b00100001, 11),
             Self::SetInterface => (0b00000001, 11),
+            Self::SetReport => (0b00100001, 9),
+            Self::SetIdle => (0b00100001, 10),
+            Self::ClearEndpointFeature => (0b00000010, 1),
+            Self::GetHubDescriptor => (0b10100000, 6),
+            Self::GetPortStatus => (0b10100011, 0),
+            Self::SetPortFeature => (0b00100011, 3),
+            Self::ClearPortFeature => (0b00100011, 1),
         }
     }
 }
@@ -40,42 +60,15 @@ pub struct SetupData {
     pub length: u16,
 }
 
-impl TransferRingSet {
-    pub fn new(ring_size: usize) -> Self {
-        Self {
-            rings: BTreeMap::new(),
-            listener: BTreeMap::new(),
-            ring_size
-        }
-    }
-
-    pub fn on_trf_event(&mut self, evt: TransferEvent) {
-        self.rings.get_mut(&(evt.slot_id() as usize, evt.endpoint_id() as usize))
-                    .unwrap()
-                    .set_deque_ptr(evt.trb_pointer());
-        let result = match evt.completion_code() {
-            Ok(CompletionCode::Success | CompletionCode::ShortPacket) => Ok(evt),
-            _ => Err(XhciError::TransferError(evt))
-        };
-        
-        if let Some(rcv) = self.listener.remove(&evt.trb_pointer()) {
-            let _ = rcv.send(result);
-        }
-    }
-
-    pub fn init_ring_at(&mut self, slot_id: usize, endpoint_id: usize) -> u64{
-        self.rings.insert((slot_id, endpoint_id), ProducerRing::new(self.ring_size));
-        self.rings[&(slot_id, endpoint_id)].get_buf_ptr()
-    }
+/// コントロール転送の3つのステージのTRB。wLengthが0ならデータステージは無い
+struct ControlTrbs {
+    setup: SetupStage,
+    data: Option<DataStage>,
+    status: StatusStage,
+}
 
-    
-    pub fn control_request(
-        &mut self,
-        slot_id: usize,
-        setup: SetupData,
-        data: Option<&mut [u8]>,
-        regs: &mut Registers<LinearMapper>
-    ) -> Result<oneshot::Receiver<Result<TransferEvent, XhciError>>, XhciError> {
+impl ControlTrbs {
+    fn new(setup: &SetupData, data_ptr: Option<PhysAddr>) -> Self {
         let (req_type, req) = setup.request_type.get_actual_value();
 
         let direction_bit = match req_type >> 7 == 1 {
@@ -83,25 +76,27 @@ impl TransferRingSet {
             false => TransferDirection::HostToDevice,
         };
 
+        // ステータスステージはデータステージの逆向き。データステージが無ければ、要求の向きによらずIN
+        // (USB 2.0 8.5.3, xHCI 4.11.2.2)。そのため長さ0の2行はどちらもDeviceToHostになる
         let (setup_transfer_type, data_dir, status_dir) = match (direction_bit, setup.length) {
             (TransferDirection::HostToDevice, 0) => (
                 TransferType::No,
-                Direction::Out,
+                None,
                 TransferDirection::DeviceToHost,
             ),
             (TransferDirection::HostToDevice, _) => (
                 TransferType::Out,
-                Direction::Out,
+                Some(Direction::Out),
                 TransferDirection::DeviceToHost,
             ),
             (TransferDirection::DeviceToHost, 0) => (
                 TransferType::No,
-                Direction::In,
+                None,
                 TransferDirection::DeviceToHost,
             ),
             (TransferDirection::DeviceToHost, _) => (
                 TransferType::In,
-                Direction::In,
+                Some(Direction::In),
                 TransferDirection::HostToDevice,
             ),
         };
@@ -116,51 +111,170 @@ impl TransferRingSet {
             .set_length(setup.length)
             .set_interrupt_on_completion();
 
+        // デバイスが要求より短く返したら、ShortPacketで残りの長さが報告される
+        let data_trb = data_dir.map(|dir| {
+            let mut data_trb = DataStage::new();
+            data_trb
+                .set_data_buffer_pointer(data_ptr.expect("control_request: no buffer for the data stage").as_u64())
+                .set_trb_transfer_length(setup.length as u32)
+                .set_td_size(0)
+                .set_direction(dir)
+                .set_interrupt_on_short_packet()
+                .set_interrupt_on_completion();
+            data_trb
+        });
+
         let mut status_trb = StatusStage::new();
         status_trb.set_interrupt_on_completion();
         if matches!(status_dir, TransferDirection::DeviceToHost) {
             status_trb.set_direction();
         }
 
-        if setup.length == 0 {
-            let trb = self
-                .push_transfer_trb(slot_id, 1, Allowed::SetupStage(setup_trb))?
-                .unwrap();
-            self.push_transfer_trb(slot_id, 1, Allowed::StatusStage(status_trb))?;
+        Self { setup: setup_trb, data: data_trb, status: status_trb }
+    }
+}
 
-            regs.doorbell.update_volatile_at(slot_id, |d|{d.set_doorbell_target(1);});
+/// コントロール転送の結果
+#[derive(Debug, Clone, Copy)]
+pub struct ControlCompletion {
+    pub event: TransferEvent,
+    /// データステージで実際に転送したバイト数。データステージが無ければ0
+    pub bytes_transferred: usize,
+}
 
-            Ok(trb)
-        } else {
-            let mut data_trb = DataStage::new();
-            data_trb
-                .set_data_buffer_pointer(data.unwrap().as_mut_ptr() as u64)
-                .set_trb_transfer_length(setup.length as u32)
-                .set_td_size(0)
-                .set_direction(data_dir)
-                .set_interrupt_on_short_packet()
-                .set_interrupt_on_completion();
+impl ControlCompletion {
+    /// requestedバイトを要求したデータステージのイベントから。TRB Transfer Lengthは転送されなかった残りの長さ
+    fn new(event: TransferEvent, requested: u16) -> Self {
+        let residue = event.trb_transfer_length() as usize;
+        Self { event, bytes_transferred: (requested as usize).saturating_sub(residue) }
+    }
+
+    /// bufのうち、デバイスが実際に書いた部分
+    pub fn valid<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[..self.bytes_transferred.min(buf.len())]
+    }
+}
+
+/// control_requestの完了を待つFuture
+pub struct ControlRequest {
+    recv: oneshot::Receiver<Result<TransferEvent, XhciError>>,
+    /// データステージで要求したバイト数
+    requested: u16,
+}
 
-            self.push_transfer_trb(slot_id, 1, Allowed::SetupStage(setup_trb))?;
-            let trb = self
-                .push_transfer_trb(slot_id, 1, Allowed::DataStage(data_trb))?
-                .unwrap();
-            self.push_transfer_trb(slot_id, 1, Allowed::StatusStage(status_trb))?;
+impl Future for ControlRequest {
+    type Output = Result<ControlCompletion, XhciError>;
 
-            regs.doorbell.update_volatile_at(slot_id, |d|{d.set_doorbell_target(1);});
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let requested = self.requested;
+        self.recv.poll_unpin(cx).map(|r| r?.map(|event| ControlCompletion::new(event, requested)))
+    }
+}
 
-            Ok(trb)
+impl TransferRingSet {
+    pub fn new(ring_size: usize) -> Self {
+        Self {
+            rings: BTreeMap::new(),
+            listener: BTreeMap::new(),
+            ring_size
         }
     }
 
+    pub fn on_trf_event(&mut self, evt: TransferEvent) {
+        // 後始末でリングを捨てた後に届いたイベントや、コントローラが壊れた値を返したイベントは捨てる
+        let Some(ring) = SlotId::new(evt.slot_id())
+            .zip(Dci::new(evt.endpoint_id()))
+            .and_then(|(slot_id, dci)| Some((slot_id, dci, self.rings.get_mut(&(slot_id, dci))?)))
+        else {
+            warn!("transfer event for unknown ring: slot={}, dci={}, dropped", evt.slot_id(), evt.endpoint_id());
+            return;
+        };
+        let (slot_id, dci, ring) = ring;
+        ring.set_deque_ptr(PhysAddr::new(evt.trb_pointer()));
+        let result = match evt.completion_code() {
+            Ok(CompletionCode::Success | CompletionCode::ShortPacket) => Ok(evt),
+            _ => Err(XhciError::from(ErrorKind::TransferFailed(evt)).on_slot(slot_id).on_endpoint(dci))
+        };
+        
+        if let Some(rcv) = self.listener.remove(&PhysAddr::new(evt.trb_pointer())) {
+            let _ = rcv.send(result);
+        }
+    }
+
+    /// 停止したエンドポイントのリングに残ったTDを捨てる。
+    /// 完了を待っていたタスクにはCanceledが通知される。
+    /// Set TR Dequeue Pointerコマンドに渡す (新しいデキューポインタ, サイクルビット) を返す。リングが無ければNone
+    pub fn discard_pending(&mut self, slot_id: SlotId, dci: Dci) -> Option<(PhysAddr, bool)> {
+        let ring = self.rings.get_mut(&(slot_id, dci))?;
+        ring.discard_pending();
+        self.listener.retain(|ptr, _| !ring.contains(*ptr));
+        Some(ring.get_enque_ptr_with_cycle())
+    }
+
+    /// スロットの全てのリングを捨てる。完了を待っていたタスクにはDisconnectedが通知される
+    pub fn remove_slot(&mut self, slot_id: SlotId) {
+        let listener = &mut self.listener;
+        self.rings.retain(|&(s, dci), ring| {
+            if s != slot_id {
+                return true;
+            }
+            let pending: Vec<PhysAddr> = listener.keys().copied().filter(|ptr| ring.contains(*ptr)).collect();
+            for ptr in pending {
+                let sender = listener.remove(&ptr).unwrap();
+                let _ = sender.send(Err(XhciError::from(ErrorKind::Disconnected).on_slot(slot_id).on_endpoint(dci)));
+            }
+            false
+        });
+    }
+
+    pub fn init_ring_at(&mut self, slot_id: SlotId, dci: Dci) -> PhysAddr {
+        self.rings.insert((slot_id, dci), ProducerRing::new(self.ring_size));
+        self.rings[&(slot_id, dci)].get_buf_ptr()
+    }
+
+    
+    pub fn control_request(
+        &mut self,
+        slot_id: SlotId,
+        setup: SetupData,
+        data: Option<&mut [u8]>,
+        regs: &mut Registers<LinearMapper>
+    ) -> Result<ControlRequest, XhciError> {
+        // データステージがあるなら、バッファはwLength以上なければならない
+        let data_ptr = data.map(|buf| {
+            assert!(buf.len() >= setup.length as usize);
+            ptr_to_phys(buf.as_ptr())
+        });
+        let trbs = ControlTrbs::new(&setup, data_ptr);
+
+        // データステージがあればその完了を、無ければステータスステージの完了を待つ
+        let recv = match trbs.data {
+            None => {
+                let recv = self.push_transfer_trb(slot_id, Dci::CONTROL, Allowed::SetupStage(trbs.setup))?.unwrap();
+                self.push_transfer_trb(slot_id, Dci::CONTROL, Allowed::StatusStage(trbs.status))?;
+                recv
+            }
+            Some(data_trb) => {
+                self.push_transfer_trb(slot_id, Dci::CONTROL, Allowed::SetupStage(trbs.setup))?;
+                let recv = self.push_transfer_trb(slot_id, Dci::CONTROL, Allowed::DataStage(data_trb))?.unwrap();
+                self.push_transfer_trb(slot_id, Dci::CONTROL, Allowed::StatusStage(trbs.status))?;
+                recv
+            }
+        };
+        ring_endpoint(regs, slot_id, Dci::CONTROL);
+
+        Ok(ControlRequest { recv, requested: if trbs.data.is_some() { setup.length } else { 0 } })
+    }
+
     
     pub fn push_transfer_trb(
         &mut self,
-        slot_id: usize,
-        endpoint_id: usize,
+        slot_id: SlotId,
+        dci: Dci,
         trb: trb::transfer::Allowed,
     ) -> Result<Option<oneshot::Receiver<Result<TransferEvent, XhciError>>>, XhciError> {
-        let trf_ring = self.rings.get_mut(&(slot_id, endpoint_id)).unwrap();
+        let trf_ring = self.rings.get_mut(&(slot_id, dci))
+            .ok_or_else(|| XhciError::from(ErrorKind::NoSuchRing).on_slot(slot_id).on_endpoint(dci))?;
         // println!("{:?}", trb);
         let ptr = trf_ring.push(UnknownTRB_(trb.into_raw()))?;
 
@@ -172,10 +286,76 @@ impl TransferRingSet {
 
         if trb.interrupt_on_completion() || int_on_short_packet {
             let (sender, receiver) = oneshot::channel();
-            self.listener.insert(ptr as u64, sender);
+            self.listener.insert(ptr, sender);
             Ok(Some(receiver))
         } else {
             Ok(None)
         }
     }
-}
\ No newline at end of file
+}
+
+pub fn run_transfer_tests() {
+    const TRB_TYPE_TRANSFER_EVENT: u32 = 32;
+    const SHORT_PACKET: u32 = 13;
+    const SUCCESS: u32 = 1;
+    let buf = PhysAddr::new(0x1000);
+    let setup = |request_type, length| SetupData { request_type, value: 0, index: 0, length };
+
+    // IN、データあり。ステータスはOUT
+    let t = ControlTrbs::new(&setup(ControlRequestType::GetDescriptor, 18), Some(buf));
+    assert!(t.setup.transfer_type() == TransferType::In && t.setup.length() == 18);
+    assert!(t.data.is_some_and(|d| d.direction() == Direction::In && d.trb_transfer_length() == 18 && d.data_buffer_pointer() == 0x1000));
+    assert!(!t.status.direction());
+    // OUT、データあり。ステータスはIN
+    let t = ControlTrbs::new(&setup(ControlRequestType::SetReport, 1), Some(buf));
+    assert!(t.setup.transfer_type() == TransferType::Out);
+    assert!(t.data.is_some_and(|d| d.direction() == Direction::Out && d.trb_transfer_length() == 1));
+    assert!(t.status.direction());
+    // データ無し。向きによらずステータスはIN
+    let t = ControlTrbs::new(&setup(ControlRequestType::SetConfigutation, 0), None);
+    assert!(t.setup.transfer_type() == TransferType::No && t.data.is_none() && t.status.direction());
+    let t = ControlTrbs::new(&setup(ControlRequestType::GetDescriptor, 0), None);
+    assert!(t.setup.transfer_type() == TransferType::No && t.data.is_none() && t.status.direction());
+
+    // 残りの長さから実際の長さを出す。短く返ったら有効な部分だけを見せる
+    let event = |code: u32, residue: u32| TransferEvent::try_from([0x1000, 0, code << 24 | residue, 1 << 24 | 1 << 16 | TRB_TYPE_TRANSFER_EVENT << 10]).unwrap();
+    let bytes = [1u8, 2, 3, 4, 5, 6, 7, 8];
+    let c = ControlCompletion::new(event(SUCCESS, 0), 8);
+    assert!(c.bytes_transferred == 8 && c.valid(&bytes) == bytes);
+    let c = ControlCompletion::new(event(SHORT_PACKET, 5), 8);
+    assert!(c.bytes_transferred == 3 && c.valid(&bytes) == [1, 2, 3]);
+    let c = ControlCompletion::new(event(SHORT_PACKET, 8), 8);
+    assert!(c.bytes_transferred == 0 && c.valid(&bytes).is_empty());
+    // データステージが無ければ0。壊れた残りの長さでも溢れない
+    assert!(ControlCompletion::new(event(SUCCESS, 0), 0).bytes_transferred == 0);
+    assert!(ControlCompletion::new(event(SHORT_PACKET, 100), 8).bytes_transferred == 0);
+    // バッファより長いと報告されても、バッファの外は見せない
+    assert!(ControlCompletion::new(event(SUCCESS, 0), 16).valid(&bytes).len() == 8);
+
+    // スロットを捨てると、待っていたTDにはDisconnectedが届き、他のスロットのTDは残る
+    let (slot1, slot2, dci) = (SlotId::new(1).unwrap(), SlotId::new(2).unwrap(), Dci::new(3).unwrap());
+    let mut set = TransferRingSet::new(8);
+    set.init_ring_at(slot1, dci);
+    set.init_ring_at(slot2, dci);
+    let mut normal = trb::transfer::Normal::new();
+    normal.set_interrupt_on_completion();
+    let mut recv1 = set.push_transfer_trb(slot1, dci, Allowed::Normal(normal)).unwrap().unwrap();
+    let mut recv2 = set.push_transfer_trb(slot2, dci, Allowed::Normal(normal)).unwrap().unwrap();
+    set.remove_slot(slot1);
+    let e = recv1.try_recv().unwrap().unwrap().unwrap_err();
+    assert!(e.is_disconnected() && e.slot() == Some(slot1) && e.dci() == Some(dci));
+    assert!(matches!(recv2.try_recv(), Ok(None)));
+    assert!(set.rings.len() == 1 && set.listener.len() == 1);
+    // 捨てたリングへの投入はエラーになり、捨てたリングへのイベントは無視される
+    let e = set.push_transfer_trb(slot1, dci, Allowed::Normal(normal)).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::NoSuchRing) && e.slot() == Some(slot1) && e.dci() == Some(dci));
+    set.on_trf_event(event(SUCCESS, 0));
+    set.on_trf_event(TransferEvent::try_from([0x1000, 0, SUCCESS << 24, TRB_TYPE_TRANSFER_EVENT << 10]).unwrap());
+    assert!(set.listener.len() == 1);
+    // 待ち受けが捨てられたコントロール転送はエラーで終わる
+    let (sender, recv) = oneshot::channel();
+    drop(sender);
+    let mut req = ControlRequest { recv, requested: 0 };
+    let e = req.poll_unpin(&mut Context::from_waker(futures::task::noop_waker_ref())).map(|r| r.err());
+    assert!(matches!(e, Poll::Ready(Some(e)) if matches!(e.kind(), ErrorKind::Canceled)));
+}
@@ -25,24 +25,103 @@
  *     SOFTWARE.
  */
 use core::{
+    fmt::{self, Write},
     pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
     task::{Context, Poll, Waker},
 };
 
-use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
-use futures::{future::BoxFuture, task::ArcWake, Future, FutureExt};
+use alloc::{collections::{BTreeMap, VecDeque}, sync::{Arc, Weak}, vec::Vec};
+use futures::{future::{select, BoxFuture, Either}, task::ArcWake, Future, FutureExt};
 
-use crate::memory_manager::Mutex;
+use crate::{memory_manager::{LazyInit, Mutex}, timer::{add_timer_sender, get_current_tick}};
+
+
+/// 容量のあるチャネルが満杯のときの送信の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendPolicy {
+    /// 送ろうとした値を捨てる
+    DropNewest,
+    /// 最も古い値を捨てて送る
+    DropOldest,
+    Panic,
+}
+
+/// チャネルごとの統計。値の出し入れのたびに更新する
+pub struct ChannelStats {
+    name: &'static str,
+    capacity: Option<usize>,
+    policy: SendPolicy,
+    depth: AtomicUsize,
+    high_water: AtomicUsize,
+    sent: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl ChannelStats {
+    fn update_depth(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+        self.high_water.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ChannelInfo {
+        ChannelInfo {
+            name: self.name,
+            capacity: self.capacity,
+            policy: self.policy,
+            depth: self.depth.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelInfo {
+    pub name: &'static str,
+    pub capacity: Option<usize>,
+    pub policy: SendPolicy,
+    pub depth: usize,
+    pub high_water: usize,
+    pub sent: usize,
+    pub dropped: usize,
+}
+
+/// 生きているチャネルの統計。両端がdropされたチャネルは一覧に出ない
+static CHANNELS: Mutex<Vec<Weak<ChannelStats>>> = Mutex::new(Vec::new());
+
+/// 生きているチャネルの統計のスナップショット
+pub fn channels() -> Vec<ChannelInfo> {
+    let mut channels = CHANNELS.lock();
+    channels.retain(|c| c.strong_count() > 0);
+    channels.iter().filter_map(|c| c.upgrade()).map(|c| c.snapshot()).collect()
+}
+
+pub fn write_channels(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "{:<16}{:>6}{:>6}{:>6}{:>8}{:>6}  policy", "name", "cap", "depth", "max", "sent", "drop")?;
+    for c in channels() {
+        match c.capacity {
+            Some(cap) => write!(out, "{:<16}{:>6}", c.name, cap)?,
+            None => write!(out, "{:<16}{:>6}", c.name, "-")?,
+        }
+        writeln!(out, "{:>6}{:>6}{:>8}{:>6}  {:?}", c.depth, c.high_water, c.sent, c.dropped, c.policy)?;
+    }
+    Ok(())
+}
 
 pub struct Receiver<T> {
     queue: Arc<Mutex<VecDeque<T>>>,
     waker: Arc<Mutex<Option<Waker>>>,
+    stats: Arc<ChannelStats>,
 }
 
 impl<T> Receiver<T> {
     pub fn receive(&self) -> Option<T> {
         let mut queue = self.queue.lock();
-        queue.pop_front()
+        let val = queue.pop_front();
+        self.stats.update_depth(queue.len());
+        val
     }
 
     pub fn receive_async(&self) -> Recv<'_, T> {
@@ -60,7 +139,7 @@ pub struct Recv<'a, T> {
 impl<'a, T> Future for Recv<'a, T> {
     type Output = T;
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.receiver.queue.lock().pop_front() {
+        match self.receiver.receive() {
             Some(val) => Poll::Ready(val),
             None => {
                 *self.receiver.waker.lock() = Some(cx.waker().clone());
@@ -74,31 +153,99 @@ impl<'a, T> Future for Recv<'a, T> {
 pub struct Sender<T> {
     queue: Arc<Mutex<VecDeque<T>>>,
     waker: Arc<Mutex<Option<Waker>>>,
+    stats: Arc<ChannelStats>,
 }
 impl<T> Sender<T> {
+    /// 受け取られていない値の数
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+
     pub fn send(&self, value: T) {
-        self.queue.lock().push_back(value);
+        self.push(&mut self.queue.lock(), value);
         if let Some(w) = self.waker.lock().take() {
             w.wake();
         }
     }
+
+    /// ロックが取れなければ何もせずに値を返す。
+    /// 容量のあるチャネルはバッファを確保済みなのでメモリ割り当ても起こらず、割り込みハンドラから呼べる。
+    /// ただし受信側が待っていれば、そのWakerは割り込みハンドラの中で呼ばれる
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        let Some(mut waker) = self.waker.try_lock() else {
+            return Err(value);
+        };
+        let Some(mut queue) = self.queue.try_lock() else {
+            return Err(value);
+        };
+        self.push(&mut queue, value);
+        drop(queue);
+        if let Some(w) = waker.take() {
+            w.wake();
+        }
+        Ok(())
+    }
+
+    fn push(&self, queue: &mut VecDeque<T>, value: T) {
+        let stats = &self.stats;
+        if stats.capacity.is_some_and(|cap| queue.len() >= cap) {
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+            match stats.policy {
+                SendPolicy::DropNewest => return,
+                SendPolicy::DropOldest => {
+                    queue.pop_front();
+                }
+                SendPolicy::Panic => panic!("channel {} overflowed", stats.name),
+            }
+        }
+        queue.push_back(value);
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+        stats.update_depth(queue.len());
+    }
+}
+
+/// 容量に制限のないチャネル。統計は取る
+pub fn new_channel<T>(name: &'static str) -> (Sender<T>, Receiver<T>) {
+    make_channel(name, None, SendPolicy::Panic)
+}
+
+/// capacityを超えて送るとpolicyに従うチャネル
+pub fn new_bounded_channel<T>(name: &'static str, capacity: usize, policy: SendPolicy) -> (Sender<T>, Receiver<T>) {
+    make_channel(name, Some(capacity), policy)
 }
 
-pub fn new_channel<T>() -> (Sender<T>, Receiver<T>) {
-    let queue = Arc::new(Mutex::new(VecDeque::new()));
+fn make_channel<T>(name: &'static str, capacity: Option<usize>, policy: SendPolicy) -> (Sender<T>, Receiver<T>) {
+    let queue = Arc::new(Mutex::new(VecDeque::with_capacity(capacity.unwrap_or(0))));
     let waker = Arc::new(Mutex::new(None));
+    let stats = Arc::new(ChannelStats {
+        name,
+        capacity,
+        policy,
+        depth: AtomicUsize::new(0),
+        high_water: AtomicUsize::new(0),
+        sent: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+    });
+    {
+        let mut channels = CHANNELS.lock();
+        channels.retain(|c| c.strong_count() > 0);
+        channels.push(Arc::downgrade(&stats));
+    }
     (
         Sender {
             queue: queue.clone(),
             waker: waker.clone(),
+            stats: stats.clone(),
         },
-        Receiver { queue, waker },
+        Receiver { queue, waker, stats },
     )
 }
 
 struct Task<'a, T> {
     future: Mutex<Option<BoxFuture<'a, T>>>,
     sender: Sender<Arc<Self>>,
+    /// キューに積んだ後で呼び、Executorを回す側に知らせる
+    notify: fn(),
 }
 
 impl<'a, T> Task<'a, T> {
@@ -125,6 +272,7 @@ impl<'a, T> Task<'a, T> {
 impl<'a, T> ArcWake for Task<'a, T> {
     fn wake_by_ref(arc_self: &Arc<Self>) {
         arc_self.sender.send(arc_self.clone());
+        (arc_self.notify)();
     }
 }
 
@@ -150,6 +298,7 @@ impl<'a, E> Executor<'a, E> {
 
 pub struct Spawner<'a, E> {
     sender: Sender<Arc<Task<'a, E>>>,
+    notify: fn(),
 }
 
 impl<'a, E> Spawner<'a, E> {
@@ -157,17 +306,20 @@ impl<'a, E> Spawner<'a, E> {
         self.sender.send(Arc::new(Task {
             future: Mutex::new(Some(future.boxed::<'a>())),
             sender: self.sender.clone(),
+            notify: self.notify,
         }));
+        (self.notify)();
     }
 }
 
-pub fn new_executor_and_spawner<'a, E>() -> (Executor<'a, E>, Spawner<'a, E>) {
-    let (sender, receiver) = new_channel();
+/// タスクがキューに積まれるたびにnotifyが呼ばれる。割り込みハンドラの中からも呼ばれる
+pub fn new_executor_and_spawner<'a, E>(notify: fn()) -> (Executor<'a, E>, Spawner<'a, E>) {
+    let (sender, receiver) = new_channel("usb-tasks");
     (
         Executor {
             task_queue: receiver,
         },
-        Spawner { sender },
+        Spawner { sender, notify },
     )
 }
 
@@ -212,3 +364,188 @@ pub fn new_broadcast_channel() -> (BroadcastReceiver, BroadcastSender) {
         BroadcastSender { flag, wakers },
     )
 }
+
+/// 待っているSleepごとの (起床時刻, Waker)。キーはSleepに振った番号
+static SLEEPERS: Mutex<BTreeMap<usize, (u64, Waker)>> = Mutex::new(BTreeMap::new());
+static NEXT_SLEEPER: AtomicUsize = AtomicUsize::new(0);
+
+/// Sleepの期限が来たことをタイマー割り込みから受け取るチャネル。
+/// 期限が重なっても一度起こせば十分なので、容量は1で溢れた分は捨てる
+static SLEEP_TIMER: LazyInit<(Sender<u64>, Receiver<u64>)> = LazyInit::new();
+
+pub fn init_sleep_timer() {
+    SLEEP_TIMER.lock().init(new_bounded_channel("usb-timer", 1, SendPolicy::DropNewest));
+}
+
+/// Sleepの期限が来ていればtrue。consumeなら通知を消す
+pub fn sleep_timer_expired(consume: bool) -> bool {
+    let timer = SLEEP_TIMER.lock();
+    if !timer.is_init() {
+        return false;
+    }
+    match consume {
+        true => timer.1.receive().is_some(),
+        false => timer.1.has_content(),
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    /// SLEEPERSに登録した番号。selectなどで何度pollされても登録は1つ
+    id: Option<usize>,
+}
+
+/// wakerを登録する。既に登録していればWakerだけ差し替える。新しく登録したときはtrueで、そのときだけタイマーをかける
+fn register_sleeper(id: &mut Option<usize>, deadline: u64, waker: &Waker) -> bool {
+    let mut sleepers = SLEEPERS.lock();
+    if let Some((_, registered)) = id.and_then(|id| sleepers.get_mut(&id)) {
+        if !registered.will_wake(waker) {
+            *registered = waker.clone();
+        }
+        return false;
+    }
+    let id = *id.get_or_insert_with(|| NEXT_SLEEPER.fetch_add(1, Ordering::Relaxed));
+    sleepers.insert(id, (deadline, waker.clone()));
+    true
+}
+
+impl Future for Sleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if get_current_tick() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        if register_sleeper(&mut self.id, deadline, cx.waker()) {
+            add_timer_sender(deadline, SLEEP_TIMER.lock().0.clone(), deadline);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    /// selectで相手が先に終わったときなど、期限の前に捨てられたら登録を消す
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            SLEEPERS.lock().remove(&id);
+        }
+    }
+}
+
+/// `ticks`だけ経過すると完了するFuture
+pub fn sleep(ticks: u64) -> Sleep {
+    sleep_until(get_current_tick() + ticks)
+}
+
+/// tickが`deadline`に達すると完了するFuture
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep { deadline, id: None }
+}
+
+/// `fut`がtick `deadline`までに完了しなければNoneを返す
+pub async fn timeout_at<F: Future + Unpin>(deadline: u64, fut: F) -> Option<F::Output> {
+    match select(fut, sleep_until(deadline)).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// `fut`が`ticks`の間に完了しなければNoneを返す。期限はsleepと同じくタイマーで知らされる
+pub async fn with_timeout<F: Future>(fut: F, ticks: u64) -> Option<F::Output> {
+    let deadline = get_current_tick() + ticks;
+    futures::pin_mut!(fut);
+    timeout_at(deadline, fut).await
+}
+
+/// 起床時刻を過ぎたタスクを起こす
+pub fn wake_sleepers(current_tick: u64) {
+    SLEEPERS.lock().retain(|_, (deadline, waker)| {
+        if *deadline <= current_tick {
+            waker.wake_by_ref();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+static TEST_NOTIFIED: AtomicUsize = AtomicUsize::new(0);
+
+/// 割り込みが無くても、起こされたタスクはnotifyを通じて知らされる
+pub fn run_executor_tests() {
+    let (mut executor, spawner) = new_executor_and_spawner::<usize>(|| {
+        TEST_NOTIFIED.fetch_add(1, Ordering::Relaxed);
+    });
+    let (tx, rx) = new_channel::<usize>("test-executor");
+    spawner.spawn(async move { rx.receive_async().await });
+    assert!(TEST_NOTIFIED.load(Ordering::Relaxed) == 1);
+    assert!(matches!(executor.process_next_task(), Ok(None)) && !executor.has_next_task());
+
+    // 受信を待っているタスクは送信で起こされる
+    tx.send(5);
+    assert!(TEST_NOTIFIED.load(Ordering::Relaxed) == 2);
+    assert!(matches!(executor.process_next_task(), Ok(Some(5))));
+    assert!(matches!(executor.process_next_task(), Err(NoMoreTask)));
+
+    // 何度pollされても登録は1つで、捨てれば消える
+    let waker = futures::task::noop_waker();
+    let before = SLEEPERS.lock().len();
+    let mut id = None;
+    assert!(register_sleeper(&mut id, u64::MAX, &waker));
+    assert!(!register_sleeper(&mut id, u64::MAX, &waker) && !register_sleeper(&mut id, u64::MAX, &waker));
+    assert!(SLEEPERS.lock().len() == before + 1);
+    drop(Sleep { deadline: u64::MAX, id });
+    assert!(SLEEPERS.lock().len() == before);
+    // 期限が来て起こされたものは登録から外れ、次のpollで登録し直す
+    let mut id = None;
+    register_sleeper(&mut id, 1, &waker);
+    wake_sleepers(1);
+    assert!(SLEEPERS.lock().len() == before && register_sleeper(&mut id, 1, &waker));
+    drop(Sleep { deadline: 1, id });
+    assert!(SLEEPERS.lock().len() == before);
+}
+
+pub fn run_channel_tests() {
+    let find = |name: &str| channels().into_iter().find(|c| c.name == name).unwrap();
+
+    let (tx, rx) = new_bounded_channel("test-newest", 2, SendPolicy::DropNewest);
+    for i in 0..5 {
+        tx.send(i);
+    }
+    let info = find("test-newest");
+    assert!(info.depth == 2 && info.high_water == 2 && info.sent == 2 && info.dropped == 3);
+    assert!(rx.receive() == Some(0) && rx.receive() == Some(1) && rx.receive().is_none());
+    assert!(find("test-newest").depth == 0);
+
+    let (tx, rx) = new_bounded_channel("test-oldest", 2, SendPolicy::DropOldest);
+    for i in 0..5 {
+        tx.send(i);
+    }
+    let info = find("test-oldest");
+    assert!(info.depth == 2 && info.high_water == 2 && info.sent == 5 && info.dropped == 3);
+    assert!(rx.receive() == Some(3) && rx.receive() == Some(4) && rx.receive().is_none());
+
+    // Panicは満杯になるまでは他と同じように振る舞う
+    let (tx, rx) = new_bounded_channel("test-panic", 3, SendPolicy::Panic);
+    for i in 0..3 {
+        tx.send(i);
+    }
+    assert!(rx.receive() == Some(0));
+    tx.send(3);
+    let info = find("test-panic");
+    assert!(info.depth == 3 && info.high_water == 3 && info.sent == 4 && info.dropped == 0);
+
+    let (tx, rx) = new_channel("test-unbounded");
+    for i in 0..100 {
+        tx.send(i);
+    }
+    for _ in 0..40 {
+        rx.receive().unwrap();
+    }
+    let info = find("test-unbounded");
+    assert!(info.capacity.is_none() && info.depth == 60 && info.high_water == 100 && info.sent == 100 && info.dropped == 0);
+
+    // 両端をdropしたチャネルは一覧から消える
+    drop((tx, rx));
+    assert!(channels().iter().all(|c| c.name != "test-unbounded"));
+}
@@ -0,0 +1,127 @@
+use core::fmt;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use super::{doorbell::SlotId, error::{ErrorKind, XhciError}};
+
+/// デバイススロットの状態。コマンドの完了と切断で進む
+///
+/// Empty -> Enabled (Enable Slot) -> Addressed (Address Device) -> Configured (Configure Endpoint)
+/// Enabled/Addressed/Configured -> TearingDown (切断) -> Empty (Disable Slot)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotState {
+    Empty,
+    Enabled,
+    Addressed,
+    Configured,
+    TearingDown,
+}
+
+impl fmt::Display for SlotState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// fromからtoに移ってよいか
+pub fn is_legal(from: SlotState, to: SlotState) -> bool {
+    use SlotState::*;
+    matches!(
+        (from, to),
+        (Empty, Enabled)
+            | (Enabled, Addressed)
+            | (Addressed, Configured)
+            | (Enabled | Addressed | Configured, TearingDown)
+            | (TearingDown, Empty)
+    )
+}
+
+/// スロットごとの状態。載っていないスロットはEmpty
+pub struct SlotTable {
+    states: BTreeMap<SlotId, SlotState>,
+}
+
+impl SlotTable {
+    pub const fn new() -> Self {
+        Self { states: BTreeMap::new() }
+    }
+
+    pub fn state(&self, slot: SlotId) -> SlotState {
+        self.states.get(&slot).copied().unwrap_or(SlotState::Empty)
+    }
+
+    /// 状態を進める。許されない遷移なら今の状態を持つSlotStateInvalidを返し、何も変えない
+    pub fn transition(&mut self, slot: SlotId, to: SlotState) -> Result<(), XhciError> {
+        let from = self.state(slot);
+        if !is_legal(from, to) {
+            return Err(XhciError::from(ErrorKind::SlotStateInvalid(from)).on_slot(slot));
+        }
+        if to == SlotState::Empty {
+            self.states.remove(&slot);
+        } else {
+            self.states.insert(slot, to);
+        }
+        Ok(())
+    }
+
+    /// スロットがallowedのどれかの状態でなければSlotStateInvalid
+    pub fn require(&self, slot: SlotId, allowed: &[SlotState]) -> Result<(), XhciError> {
+        let state = self.state(slot);
+        if allowed.contains(&state) {
+            Ok(())
+        } else {
+            Err(XhciError::from(ErrorKind::SlotStateInvalid(state)).on_slot(slot))
+        }
+    }
+
+    /// 後始末を始める。既に始まっている・空のスロットならfalseを返し、何度呼んでもよい
+    pub fn begin_teardown(&mut self, slot: SlotId) -> bool {
+        self.transition(slot, SlotState::TearingDown).is_ok()
+    }
+
+    pub fn states(&self) -> Vec<(SlotId, SlotState)> {
+        self.states.iter().map(|(s, st)| (*s, *st)).collect()
+    }
+}
+
+pub fn run_slot_tests() {
+    use SlotState::*;
+    const ALL: [SlotState; 5] = [Empty, Enabled, Addressed, Configured, TearingDown];
+    let legal = [
+        (Empty, Enabled),
+        (Enabled, Addressed),
+        (Addressed, Configured),
+        (Enabled, TearingDown),
+        (Addressed, TearingDown),
+        (Configured, TearingDown),
+        (TearingDown, Empty),
+    ];
+    for from in ALL {
+        for to in ALL {
+            assert!(is_legal(from, to) == legal.contains(&(from, to)));
+        }
+    }
+
+    let slot = SlotId::new(1).unwrap();
+    let mut table = SlotTable::new();
+    assert!(table.state(slot) == Empty);
+    // Address Deviceの前にエンドポイントを構成しようとした
+    let e = table.transition(slot, Configured).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::SlotStateInvalid(Empty)) && e.slot() == Some(slot));
+
+    table.transition(slot, Enabled).unwrap();
+    table.transition(slot, Addressed).unwrap();
+    assert!(table.require(slot, &[Addressed, Configured]).is_ok());
+    assert!(table.require(slot, &[Configured]).is_err());
+    table.transition(slot, Configured).unwrap();
+    assert!(table.states() == [(slot, Configured)]);
+
+    // 後始末は1回だけ始まり、その間の転送は型付きのエラーで断られる
+    assert!(table.begin_teardown(slot));
+    assert!(!table.begin_teardown(slot));
+    let e = table.require(slot, &[Configured]).unwrap_err();
+    assert!(format!("{e}") == "xHCI operation failed slot=1: slot is TearingDown");
+    table.transition(slot, Empty).unwrap();
+    assert!(!table.begin_teardown(slot));
+    assert!(table.states().is_empty());
+}
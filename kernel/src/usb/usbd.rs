@@ -3,16 +3,17 @@ use core::{
     slice::from_raw_parts,
 };
 
-use alloc::{boxed::Box, vec::Vec};
-use xhci::{context::EndpointType, ring::trb::{self, command::ConfigureEndpoint}};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use xhci::{context::EndpointType, ring::trb::{self, command::ConfigureEndpoint, event::CompletionCode}};
 
-use crate::{println, usb::{class::keyboard::KeyboardClass, device::InputContext, spawn, xhci::{push_command, with_dcbaa, with_trf_rings}}};
+use crate::{heap_profile::with_alloc_tag, println, timer::ms_to_ticks, usb::{action::init_device::teardown_slot, class::{hub::{HubClass, HubInfo, HUB_CLASS}, keyboard::KeyboardClass}, device::InputContext, doorbell::{Dci, PortId, SlotId}, registry::{self, InterfaceInfo}, slot::SlotState, spawn, xhci::{push_command_async, recover_endpoint, with_dcbaa, with_trf_rings}}};
 
 use super::{
-    class::{keyboard::KeyReport, mouse::{MouseClass, MouseReport}}, ring::transfer::{ControlRequestType, SetupData}, runtime::Receiver, xhci::{control_request, XhciError}
+    class::{keyboard::KeyReport, mouse::MouseClass}, new_channel, power::{track_endpoint, wait_running}, ready::{self, Resolution}, subscribe_lock_requests, publish_hotplug, publish_keyboard, publish_mouse, DeviceInfo, HotplugEvent, KeyEvent, MouseEvent, ring::transfer::{ControlRequestType, SetupData}, runtime::{with_timeout, Receiver, Sender}, xhci::{control_transfer, ErrorContext, ErrorKind, Operation, XhciError}
 };
 
 use bitfield::bitfield;
+use futures::{future::{select, Either}, Future};
 
 bitfield! {
     #[derive(Clone,Copy, Debug)]
@@ -26,8 +27,8 @@ bitfield! {
     device_sub_class, _: 47, 40;
     device_protocol, _: 55, 48;
     max_packet_size_0, _: 63, 56;
-    id_vendor, _: 79, 64;
-    id_product, _: 95, 80;
+    u16, id_vendor, _: 79, 64;
+    u16, id_product, _: 95, 80;
     bcd_device, _: 111, 96;
     i_manufacturer, _: 119, 112;
     i_product, _: 127, 120;
@@ -99,9 +100,21 @@ pub struct EndpointDescriptor {
 }
 
 impl EndpointDescriptor {
-    pub fn calc_dci(&self) -> usize {
-        let addr = self.endpoint_addr;
-        (2 * (addr & 0b1111) + (addr >> 7)) as usize
+    pub fn calc_dci(&self) -> Option<Dci> {
+        Dci::from_endpoint_address(self.endpoint_addr)
+    }
+
+    /// デバイスからホストへの方向ならtrue
+    pub fn is_in(&self) -> bool {
+        self.endpoint_addr >> 7 == 1
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        self.bm_attributes & 0b11 == 3
+    }
+
+    pub fn max_packet_size(&self) -> u16 {
+        self.max_packet_size
     }
 }
 
@@ -116,7 +129,7 @@ bitfield! {
     country_code, _: 39, 32;
     num_descriptors, _: 47, 40;
     class_descriptor_type, _: 55, 48;
-    class_descriptor_length, _: 71, 56;
+    u16, class_descriptor_length, _: 71, 56;
 }
 pub type HidDescriptor = HidDescriptor_<[u8; 9]>;
 
@@ -154,19 +167,129 @@ impl Debug for UnknownDescriptor {
 }
 
 pub struct UsbDevice {
-    slot_id: usize,
+    slot_id: SlotId,
     configs: Vec<UsbConfiguration>,
     config_selected: Option<usize>,
     alternates_selected: Vec<u8>,
+    /// ハブならそのハブディスクリプタ
+    hub: Option<HubInfo>,
+    vendor_id: u16,
+    product_id: u16,
+    /// iManufacturer, iProductの文字列。無いか読めなければ空
+    manufacturer: String,
+    product: String,
 }
 
 impl UsbDevice {
-    fn new(slot_id: usize, configs: Vec<UsbConfiguration>) -> Self {
+    fn new(slot_id: SlotId, configs: Vec<UsbConfiguration>) -> Self {
         Self {
             slot_id,
             configs,
             config_selected: None,
             alternates_selected: Vec::new(),
+            hub: None,
+            vendor_id: 0,
+            product_id: 0,
+            manufacturer: String::new(),
+            product: String::new(),
+        }
+    }
+
+    /// レジストリに載せる。設定中に外れて後始末が済んでいたら、消されずに残るので載せない
+    fn register(self, interfaces: Vec<InterfaceInfo>) {
+        let route = with_dcbaa(|d| {
+            if d.slots().state(self.slot_id) != SlotState::Configured {
+                return None;
+            }
+            let slot = d.get_context_at(self.slot_id).handler().slot();
+            Some((PortId::new(slot.root_hub_port_number()), slot.route_string()))
+        });
+        let Some((port, route_string)) = route else {
+            return;
+        };
+        registry::insert(registry::DeviceInfo {
+            slot: self.slot_id,
+            port,
+            route_string,
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            manufacturer: self.manufacturer,
+            product: self.product,
+            interfaces,
+        });
+    }
+
+    /// GET_DESCRIPTOR(STRING)で読み、デバイスが書いた部分だけを返す
+    async fn read_string_raw(&self, index: u8, lang_id: u16) -> Result<Vec<u8>, XhciError> {
+        let mut buf = vec![0u8; STRING_DESCRIPTOR_MAX];
+        let setup = SetupData {
+            request_type: ControlRequestType::GetDescriptor,
+            value: (DESCRIPTOR_STRING as u16) << 8 | index as u16,
+            index: lang_id,
+            length: STRING_DESCRIPTOR_MAX as u16,
+        };
+        let done = with_request_timeout(self.slot_id, Operation::ReadDescriptor, control_transfer(self.slot_id, Operation::ReadDescriptor, setup, Some(&mut buf))).await?;
+        let len = done.valid(&buf).len();
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// デバイスが対応しているLANGID。0番の文字列ディスクリプタに並んでいる
+    pub async fn read_lang_ids(&self) -> Result<Vec<u16>, XhciError> {
+        let buf = self.read_string_raw(0, 0).await?;
+        let payload = string_payload(&buf)
+            .ok_or_else(|| XhciError::from(ErrorKind::UnexpectedDescriptor).during(Operation::ReadDescriptor).on_slot(self.slot_id))?;
+        Ok(payload.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect())
+    }
+
+    /// index番の文字列ディスクリプタをlang_idの言語で読み、UTF-16LEから変換する
+    pub async fn read_string_descriptor(&self, index: u8, lang_id: u16) -> Result<String, XhciError> {
+        let buf = self.read_string_raw(index, lang_id).await?;
+        decode_string_descriptor(&buf)
+            .ok_or_else(|| XhciError::from(ErrorKind::UnexpectedDescriptor).during(Operation::ReadDescriptor).on_slot(self.slot_id))
+    }
+
+    /// ベンダーID、プロダクトIDと、製造元、製品名の文字列を覚える。
+    /// 文字列を返さないデバイスは名前が無いものとして扱い、設定は続ける
+    async fn read_identity(&mut self, dev_desc: &DeviceDescriptor) -> Result<(), XhciError> {
+        self.vendor_id = dev_desc.id_vendor();
+        self.product_id = dev_desc.id_product();
+        let (i_manufacturer, i_product) = (dev_desc.i_manufacturer(), dev_desc.i_product());
+        if i_manufacturer == 0 && i_product == 0 {
+            return Ok(());
+        }
+        let lang_id = match self.read_lang_ids().await {
+            Ok(ids) => match choose_lang_id(&ids) {
+                Some(id) => id,
+                None => return Ok(()),
+            },
+            Err(e) => return self.skip_missing_string(e).await,
+        };
+        self.manufacturer = self.read_name(i_manufacturer, lang_id).await?;
+        self.product = self.read_name(i_product, lang_id).await?;
+        Ok(())
+    }
+
+    /// index番の文字列。0番は文字列が無いことを表す
+    async fn read_name(&self, index: u8, lang_id: u16) -> Result<String, XhciError> {
+        if index == 0 {
+            return Ok(String::new());
+        }
+        match self.read_string_descriptor(index, lang_id).await {
+            Err(e) => self.skip_missing_string(e).await.map(|()| String::new()),
+            result => result,
+        }
+    }
+
+    /// STALLや短すぎるディスクリプタは文字列が無いものとして成功にし、他のエラーはそのまま返す。
+    /// STALLでxHC側のデフォルトコントロールパイプも止まるので、次の要求の前に戻す
+    async fn skip_missing_string(&self, e: XhciError) -> Result<(), XhciError> {
+        if e.is_stall() {
+            return recover_endpoint(self.slot_id, Dci::CONTROL, true).await;
+        }
+        match e.kind() {
+            ErrorKind::UnexpectedDescriptor => Ok(()),
+            _ => Err(e),
         }
     }
 
@@ -178,11 +301,12 @@ impl UsbDevice {
             index: 0,
             length: 0,
         };
-        let _ = control_request(self.slot_id, setup, None)?.await.unwrap()?;
+        with_request_timeout(self.slot_id, Operation::SetConfiguration, control_transfer(self.slot_id, Operation::SetConfiguration, setup, None)).await?;
 
         self.config_selected = Some(config);
-        self.alternates_selected
-            .append(&mut vec![0; conf.interfaces.len()]); // alternate setting defaults to zero
+        // alternate setting defaults to zero。インターフェース番号で引くので、番号が飛んでいても足りる長さにする
+        let len = conf.interfaces.iter().map(|i| i.interface_num as usize + 1).max().unwrap_or(0);
+        self.alternates_selected = vec![0; len];
 
         Ok(())
     }
@@ -193,8 +317,8 @@ impl UsbDevice {
         alternate_setting: usize,
     ) -> Result<(), XhciError> {
         let config = &self.configs[self.config_selected.unwrap()];
-        let intf = &config.interfaces[interface];
-        assert!(intf.alternates.len() > alternate_setting);
+        let intf = config.interfaces.iter().find(|i| i.interface_num as usize == interface).unwrap();
+        assert!(intf.alternate(alternate_setting as u8).is_some());
         self.alternates_selected[interface] = alternate_setting as u8;
 
         let setup = SetupData {
@@ -203,12 +327,14 @@ impl UsbDevice {
             index: interface as u16,
             length: 0,
         };
-        let _ = control_request(self.slot_id, setup, None)?.await.unwrap()?;
+        with_request_timeout(self.slot_id, Operation::SetInterface, control_transfer(self.slot_id, Operation::SetInterface, setup, None)).await?;
 
         Ok(())
     }
 
     async fn enable_endpoints(&mut self) -> Result<(), XhciError> {
+        // 切断と競合しうるので、panicではなくエラーにする
+        with_dcbaa(|d| d.slots().require(self.slot_id, &[SlotState::Addressed])).during(Operation::ConfigureEndpoint)?;
         let mut input_ctx = InputContext::new(with_dcbaa(|d|d.ctx_size()));
         input_ctx
             .handler_mut()
@@ -218,69 +344,99 @@ impl UsbDevice {
             with_dcbaa(|dcbaa| {
                 let this = input_ctx.handler_mut().device_mut().slot_mut();
                 let other = dcbaa.get_context_at(self.slot_id).handler().slot();
-                this.set_route_string(0);
+                // Address Deviceで決めた繋がり方はそのまま引き継ぐ
+                this.set_route_string(other.route_string());
                 this.set_root_hub_port_number(other.root_hub_port_number());
                 this.set_interrupter_target(0);
                 this.set_speed(other.speed());
+                this.set_parent_hub_slot_id(other.parent_hub_slot_id());
+                this.set_parent_port_number(other.parent_port_number());
+                if let Some(hub) = self.hub {
+                    this.set_hub();
+                    this.set_number_of_ports(hub.ports);
+                    this.set_tt_think_time(hub.tt_think_time);
+                }
             });
         }
 
-        let intf_arr = &self.configs[self.config_selected.unwrap()].interfaces;
-        let mut context_entries = 1;
-        for intf in intf_arr {
-            let alt =
-                &intf.alternates[self.alternates_selected[intf.interface_num as usize] as usize];
-            for ep in &alt.endpoints {
-                if let Descriptor::Endpoint(ep) = ep {
-                    // endpoint no. =  ep_addr[3..0], direction = ep_addr[7]
-                    let ep_addr = ep.endpoint_addr;
-                    let direction = ep_addr >> 7;
-                    let dci = (2 * (ep_addr & 0b1111) + direction) as usize;
-
-                    input_ctx
-                        .handler_mut()
-                        .control_mut()
-                        .set_add_context_flag(dci);
-
-                    let ep_context = input_ctx.handler_mut().device_mut().endpoint_mut(dci);
-                    let transfer_type = ep.bm_attributes & 0b11;
-                    ep_context.set_endpoint_type(match (direction, transfer_type) {
-                        (0, 1) => EndpointType::IsochOut,
-                        (0, 2) => EndpointType::BulkOut,
-                        (0, 3) => EndpointType::InterruptOut,
-                        (_, 0) => EndpointType::Control,
-                        (1, 1) => EndpointType::IsochIn,
-                        (1, 2) => EndpointType::BulkIn,
-                        (1, 3) => EndpointType::InterruptIn,
-                        _ => panic!("illegal endpoint type"),
-                    });
-                    ep_context.set_max_packet_size(ep.max_packet_size);
-                    ep_context.set_max_burst_size(0);
-                    let ring_ptr = with_trf_rings(|r|r.init_ring_at(self.slot_id, dci));
-                    ep_context.set_tr_dequeue_pointer(ring_ptr);
-                    ep_context.set_dequeue_cycle_state();
-                    ep_context.set_interval(ep.interval);
-                    ep_context.set_max_primary_streams(0);
-                    ep_context.set_mult(0);
-                    ep_context.set_error_count(3);
-
-                    context_entries = context_entries.max(dci + 1);
-                }
-            }
+        let (_, context_entries) = self.endpoint_context_layout();
+        for ep in self.selected_endpoints() {
+            // endpoint no. =  ep_addr[3..0], direction = ep_addr[7]
+            let direction = ep.endpoint_addr >> 7;
+            let Some(dci) = ep.calc_dci() else {
+                continue;
+            };
+
+            input_ctx
+                .handler_mut()
+                .control_mut()
+                .set_add_context_flag(dci.index());
+
+            let ep_context = input_ctx.handler_mut().device_mut().endpoint_mut(dci.index());
+            let transfer_type = ep.bm_attributes & 0b11;
+            ep_context.set_endpoint_type(match (direction, transfer_type) {
+                (0, 1) => EndpointType::IsochOut,
+                (0, 2) => EndpointType::BulkOut,
+                (0, 3) => EndpointType::InterruptOut,
+                (_, 0) => EndpointType::Control,
+                (1, 1) => EndpointType::IsochIn,
+                (1, 2) => EndpointType::BulkIn,
+                (1, 3) => EndpointType::InterruptIn,
+                _ => panic!("illegal endpoint type"),
+            });
+            ep_context.set_max_packet_size(ep.max_packet_size);
+            ep_context.set_max_burst_size(0);
+            let ring_ptr = with_trf_rings(|r|r.init_ring_at(self.slot_id, dci));
+            ep_context.set_tr_dequeue_pointer(ring_ptr.as_u64());
+            ep_context.set_dequeue_cycle_state();
+            ep_context.set_interval(ep.interval);
+            ep_context.set_max_primary_streams(0);
+            ep_context.set_mult(0);
+            ep_context.set_error_count(3);
         }
 
         input_ctx
             .handler_mut()
             .device_mut()
             .slot_mut()
-            .set_context_entries(context_entries as u8);
+            .set_context_entries(context_entries.get());
 
         let mut cmd = ConfigureEndpoint::new();
-        cmd.set_slot_id(self.slot_id as u8);
-        cmd.set_input_context_pointer(input_ctx.get_address());
+        cmd.set_slot_id(self.slot_id.get());
+        cmd.set_input_context_pointer(input_ctx.get_address().as_u64());
         println!("{:?}", input_ctx);
-        push_command(trb::command::Allowed::ConfigureEndpoint(cmd))?.await.unwrap();
-        Ok(())
+        let recv = push_command_async(trb::command::Allowed::ConfigureEndpoint(cmd)).await.during(Operation::ConfigureEndpoint).on_slot(self.slot_id)?;
+        let result = with_request_timeout(self.slot_id, Operation::ConfigureEndpoint, async { Ok(recv.await?) }).await?;
+        if result.completion_code() != Ok(CompletionCode::Success) {
+            return Err(XhciError::from(ErrorKind::CommandFailed(result)).during(Operation::ConfigureEndpoint).on_slot(self.slot_id));
+        }
+        with_dcbaa(|d| d.slots_mut().transition(self.slot_id, SlotState::Configured)).during(Operation::ConfigureEndpoint)
+    }
+
+    /// 選択中の構成の全インターフェースについて、選択中の代替設定
+    fn selected_interfaces(&self) -> impl Iterator<Item = &UsbInterfaceAlternate> {
+        let config = &self.configs[self.config_selected.unwrap()];
+        config.interfaces.iter().filter_map(|intf| {
+            intf.alternate(self.alternates_selected[intf.interface_num as usize])
+        })
+    }
+
+    /// 選択中の構成・代替設定に含まれるエンドポイント
+    fn selected_endpoints(&self) -> impl Iterator<Item = &EndpointDescriptor> {
+        self.selected_interfaces().flat_map(|alt| alt.endpoints.iter())
+    }
+
+    /// Configure Endpointで使う (Add Contextフラグ, Context Entries)。
+    /// Context Entriesは最後の有効なエンドポイントコンテキストのDCI
+    /// フラグのビット0はスロットコンテキスト
+    fn endpoint_context_layout(&self) -> (u32, Dci) {
+        let mut flags = 1;
+        let mut context_entries = Dci::CONTROL;
+        for dci in self.selected_endpoints().filter_map(|ep| ep.calc_dci()) {
+            flags |= 1 << dci.index();
+            context_entries = context_entries.max(dci);
+        }
+        (flags, context_entries)
     }
 }
 
@@ -305,7 +461,10 @@ impl UsbConfiguration {
 }
 
 pub struct UsbInterfaceAlternate {
-    endpoints: Vec<Descriptor>,
+    endpoints: Vec<EndpointDescriptor>,
+    hid: Option<HidDescriptor>,
+    /// 解釈していないクラス固有のディスクリプタなど
+    other: Vec<UnknownDescriptor>,
     interface_num: u8,
     alternate_setting_num: u8,
     class: u8,
@@ -315,9 +474,11 @@ pub struct UsbInterfaceAlternate {
 }
 
 impl UsbInterfaceAlternate {
-    fn new(desc: InterfaceDescriptor, endpoints: Vec<Descriptor>) -> Self {
+    fn new(desc: InterfaceDescriptor) -> Self {
         Self {
-            endpoints,
+            endpoints: Vec::new(),
+            hid: None,
+            other: Vec::new(),
             interface_num: desc.interface_number(),
             alternate_setting_num: desc.alternate_setting(),
             class: desc.interface_class(),
@@ -331,12 +492,29 @@ impl UsbInterfaceAlternate {
         self.interface_num
     }
 
-    pub fn endpoints(&self) -> &Vec<Descriptor> {
+    pub fn alternate_setting_num(&self) -> u8 {
+        self.alternate_setting_num
+    }
+
+    pub fn subclass(&self) -> u8 {
+        self.subclass
+    }
+
+    pub fn endpoints(&self) -> &[EndpointDescriptor] {
         &self.endpoints
     }
+
+    pub fn hid(&self) -> Option<&HidDescriptor> {
+        self.hid.as_ref()
+    }
+
+    pub fn other(&self) -> &[UnknownDescriptor] {
+        &self.other
+    }
 }
 
 pub struct UsbInterface {
+    /// alternate_setting_numの昇順
     alternates: Vec<UsbInterfaceAlternate>,
     interface_num: u8,
 }
@@ -352,6 +530,10 @@ impl UsbInterface {
     pub fn interface_num(&self) -> u8 {
         self.interface_num
     }
+
+    pub fn alternate(&self, alternate_setting: u8) -> Option<&UsbInterfaceAlternate> {
+        self.alternates.iter().find(|a| a.alternate_setting_num == alternate_setting)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -368,7 +550,7 @@ impl TryFrom<Descriptor> for ConfigurationDescriptor {
     fn try_from(value: Descriptor) -> Result<Self, Self::Error> {
         match value {
             Descriptor::Configuration(desc) => Ok(desc),
-            _ => Err(XhciError::UnexpectedDescriptor),
+            _ => Err(ErrorKind::UnexpectedDescriptor.into()),
         }
     }
 }
@@ -378,7 +560,7 @@ impl TryFrom<Descriptor> for InterfaceDescriptor {
     fn try_from(value: Descriptor) -> Result<Self, Self::Error> {
         match value {
             Descriptor::Interface(desc) => Ok(desc),
-            _ => Err(XhciError::UnexpectedDescriptor),
+            _ => Err(ErrorKind::UnexpectedDescriptor.into()),
         }
     }
 }
@@ -402,150 +584,230 @@ pub unsafe fn read_descriptor(ptr: *const u8) -> Option<(Descriptor, *const u8)>
     Some((desc, ptr.add(length)))
 }
 
+/// インターフェースディスクリプタから次のインターフェースディスクリプタの手前までを1つの代替設定にまとめる
 fn construct_interface_alternate(
     desc_arr: &[Descriptor],
 ) -> Option<(UsbInterfaceAlternate, &[Descriptor])> {
-    let alt = InterfaceDescriptor::try_from(desc_arr.get(0)?.clone()).ok()?;
-    let mut eps: Vec<Descriptor> = Vec::new();
+    let mut alt = UsbInterfaceAlternate::new(InterfaceDescriptor::try_from(desc_arr.get(0)?.clone()).ok()?);
 
     for i in 1..desc_arr.len() {
         match &desc_arr[i] {
-            Descriptor::Interface(_) => {
-                let intf = UsbInterfaceAlternate::new(alt, eps);
-                return Some((intf, &desc_arr[i..]));
-            }
+            Descriptor::Interface(_) => return Some((alt, &desc_arr[i..])),
             Descriptor::Configuration(_) => return None,
-            desc => {
-                eps.push(desc.clone());
-            }
-        }
-    }
-    let intf = UsbInterfaceAlternate::new(alt, eps);
-    Some((intf, &desc_arr[desc_arr.len()..]))
-}
-
-fn construct_interface(mut desc_arr: &[Descriptor]) -> Option<(UsbInterface, &[Descriptor])> {
-    let intf_num = InterfaceDescriptor::try_from(desc_arr.get(0)?.clone())
-        .ok()?
-        .interface_number();
-    let mut alts: Vec<UsbInterfaceAlternate> = Vec::new();
-
-    while let Some((alt, remain)) = construct_interface_alternate(desc_arr) {
-        if alt.interface_num == intf_num {
-            alts.push(alt);
-            desc_arr = remain;
-        } else {
-            let intf = UsbInterface::new(intf_num, alts);
-            return Some((intf, desc_arr));
+            Descriptor::Endpoint(ep) => alt.endpoints.push(*ep),
+            Descriptor::Hid(hid) if alt.hid.is_none() => alt.hid = Some(*hid),
+            Descriptor::Hid(hid) => alt.other.push(UnknownDescriptor { content: hid.0.to_vec() }),
+            Descriptor::Unknown(desc) => alt.other.push(desc.clone()),
         }
     }
-    let intf = UsbInterface::new(intf_num, alts);
-    Some((intf, desc_arr))
+    Some((alt, &desc_arr[desc_arr.len()..]))
 }
 
 fn construct_configuration(mut desc_arr: &[Descriptor]) -> Option<UsbConfiguration> {
     let conf_desc = ConfigurationDescriptor::try_from(desc_arr.get(0)?.clone()).ok()?;
     desc_arr = &desc_arr[1..];
-    let mut intfs: Vec<UsbInterface> = Vec::new();
 
-    while let Some((intf, remain)) = construct_interface(desc_arr) {
-        intfs.push(intf);
+    // 同じインターフェースの代替設定が連続しているとは限らない (intf 0 alt 0, intf 1 alt 0, intf 0 alt 1 など) ので、番号でまとめる
+    let mut grouped: BTreeMap<u8, Vec<UsbInterfaceAlternate>> = BTreeMap::new();
+    while let Some((alt, remain)) = construct_interface_alternate(desc_arr) {
+        grouped.entry(alt.interface_num).or_default().push(alt);
         desc_arr = remain;
     }
 
+    let intfs = grouped
+        .into_iter()
+        .map(|(num, mut alts)| {
+            alts.sort_by_key(|a| a.alternate_setting_num);
+            UsbInterface::new(num, alts)
+        })
+        .collect();
+
     let conf = UsbConfiguration::new(&conf_desc, intfs);
     Some(conf)
 }
 
+/// 構成ディスクリプタとそれに続くディスクリプタの列を読む
+fn parse_descriptors(buf: &[u8]) -> Vec<Descriptor> {
+    with_alloc_tag("usb-desc", || parse_descriptors_untagged(buf))
+}
+
+fn parse_descriptors_untagged(buf: &[u8]) -> Vec<Descriptor> {
+    if buf.len() < 4 {
+        return Vec::new();
+    }
+    let total_len = (u16::from_le_bytes([buf[2], buf[3]]) as usize).min(buf.len());
+    let mut descs: Vec<Descriptor> = Vec::new();
+    let mut offset = 0;
+
+    while offset + 2 <= total_len {
+        let length = buf[offset] as usize;
+        // 途中で切れたディスクリプタは読まない
+        if offset + length > total_len {
+            break;
+        }
+        let Some((desc, _)) = (unsafe { read_descriptor(buf[offset..].as_ptr()) }) else {
+            break;
+        };
+        descs.push(desc);
+        offset += length;
+    }
+    descs
+}
+
+/// 文字列ディスクリプタの種類と、bLengthが1バイトなので決まる最大の長さ
+const DESCRIPTOR_STRING: u8 = 3;
+const STRING_DESCRIPTOR_MAX: usize = 255;
+/// 英語(米国)のLANGID。対応していればこれで読む
+const LANG_EN_US: u16 = 0x0409;
+
+/// 文字列ディスクリプタのbLength, bDescriptorTypeの後ろ。bLengthより短く届いたら届いた分だけを使う
+fn string_payload(desc: &[u8]) -> Option<&[u8]> {
+    if desc.len() < 2 || desc[1] != DESCRIPTOR_STRING || desc[0] < 2 {
+        return None;
+    }
+    Some(&desc[2..(desc[0] as usize).min(desc.len())])
+}
+
+/// UTF-16LEの文字列ディスクリプタを変換する。対になっていないサロゲートはU+FFFDにする
+fn decode_string_descriptor(desc: &[u8]) -> Option<String> {
+    let units = string_payload(desc)?.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+    Some(char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect())
+}
+
+/// 英語(米国)があればそれ、無ければデバイスが最初に挙げたもの
+fn choose_lang_id(ids: &[u16]) -> Option<u16> {
+    if ids.contains(&LANG_EN_US) {
+        return Some(LANG_EN_US);
+    }
+    ids.first().copied()
+}
+
+/// デバイスの設定中に送る要求の期限
+const REQUEST_TIMEOUT_MS: u64 = 500;
+
+/// futが期限までに終わらなければスロットを後始末し、Timeoutにする。
+/// 後始末が終わるまで戻らないので、futが貸しているバッファにその後でデバイスが書き込むことはない
+async fn with_request_timeout<T>(
+    slot_id: SlotId,
+    op: Operation,
+    fut: impl Future<Output = Result<T, XhciError>>,
+) -> Result<T, XhciError> {
+    match with_timeout(fut, ms_to_ticks(REQUEST_TIMEOUT_MS)).await {
+        Some(result) => result,
+        None => {
+            warn!("usb: slot {slot_id} did not answer {op} within {REQUEST_TIMEOUT_MS}ms, disabling it");
+            teardown_slot(slot_id).await;
+            Err(XhciError::from(ErrorKind::Timeout(REQUEST_TIMEOUT_MS)).during(op).on_slot(slot_id))
+        }
+    }
+}
+
 pub struct UsbDriver {
-    address_device_notifier: Receiver<usize>,
-    mouse_callback: Option<Box<dyn Fn(Box<MouseReport>) + Send>>,
-    keyboard_callback: Option<Box<dyn Fn(Box<KeyReport>) + Send>>,
+    address_device_notifier: Receiver<SlotId>,
+    /// ハブの下のデバイスにアドレスを振ったときに、ハブのタスクから知らせてもらう
+    address_device_sender: Sender<SlotId>,
 }
 
 impl UsbDriver {
-    pub fn new(
-        address_device_notifier: Receiver<usize>,
-        mouse_callback: Box<dyn Fn(Box<MouseReport>) + Send>,
-        keyboard_callback: Box<dyn Fn(Box<KeyReport>) + Send>,
-    ) -> Self {
-        Self {
-            address_device_notifier,
-            mouse_callback: Some(mouse_callback),
-            keyboard_callback: Some(keyboard_callback)
-        }
+    pub fn new(address_device_notifier: Receiver<SlotId>, address_device_sender: Sender<SlotId>) -> Self {
+        Self { address_device_notifier, address_device_sender }
     }
 
     pub async fn main_loop(&mut self) -> Result<(), XhciError> {
         loop {
             let slot_id = self.address_device_notifier.receive_async().await;
             println!("device configuration: slot_id={slot_id}");
+            // 1台の失敗で以降のデバイスの設定が止まらないよう、ここでエラーを受け止める
+            match self.configure_device(slot_id).await {
+                Ok(()) => ready::resolve(Resolution::Attached(slot_id)),
+                Err(e) => {
+                    error!("failed to configure device: {e}");
+                    ready::resolve(Resolution::Failed(Some(slot_id)));
+                }
+            }
+        }
+    }
 
-            let dev_desc = self.read_device_descriptor(slot_id).await?;
-
-            let mut confs: Vec<Vec<Descriptor>> = Vec::new();
-            for i_conf in 0..dev_desc.b_num_configurations() {
-                let conf = self.read_config(slot_id, i_conf as usize, 64).await?;
+    /// デバイスを設定し、クラスドライバのタスクを起動する
+    async fn configure_device(&mut self, slot_id: SlotId) -> Result<(), XhciError> {
+        let dev_desc = self.read_device_descriptor(slot_id).await?;
 
-                for desc in &conf {
-                    println!("{desc:?}");
-                }
+        let mut confs: Vec<Vec<Descriptor>> = Vec::new();
+        for i_conf in 0..dev_desc.b_num_configurations() {
+            let conf = self.read_config(slot_id, i_conf as usize, 64).await?;
 
-                confs.push(conf);
+            for desc in &conf {
+                println!("{desc:?}");
             }
-            let mut dev = self.construct_device(slot_id, confs).await?;
-
-            dev.set_configuration(0).await?;
-            dev.enable_endpoints().await?;
-
-            let intf = &dev.configs[0].interfaces[0].alternates[0];
-
-            if self.mouse_callback.is_some()
-                && intf.class == 3
-                && intf.subclass == 1
-                && intf.protocol == 2
-            {
-                let callback = self.mouse_callback.take().unwrap();
-                let mouse = MouseClass::new(slot_id, intf).unwrap();
-                mouse.initialize().await?;
-
-                spawn(async move {
-                    loop {
-                        let (recv, buf) = mouse.subscribe_once()?;
-                        if recv.await.unwrap().is_ok() {
-                            callback(buf);
-                        }
+
+            confs.push(conf);
+        }
+        let mut dev = self.construct_device(slot_id, confs).await?;
+        dev.read_identity(&dev_desc).await?;
+        println!("slot {slot_id}: {:04x}:{:04x} manufacturer={:?} product={:?}", dev.vendor_id, dev.product_id, dev.manufacturer, dev.product);
+
+        // ハブであることはConfigure Endpointでスロットコンテキストに書くので、先にハブディスクリプタを読む
+        if dev.configs[0].interfaces[0].alternates[0].class == HUB_CLASS && HubClass::supported(slot_id) {
+            dev.hub = Some(with_request_timeout(slot_id, Operation::ReadDescriptor, HubClass::read_descriptor(slot_id)).await?);
+        }
+        dev.set_configuration(0).await?;
+        dev.enable_endpoints().await?;
+
+        let intf = &dev.configs[0].interfaces[0].alternates[0];
+        publish_hotplug(HotplugEvent::Attached {
+            slot: slot_id,
+            info: DeviceInfo {
+                vendor_id: dev_desc.id_vendor(),
+                product_id: dev_desc.id_product(),
+                class: intf.class,
+                subclass: intf.subclass,
+                protocol: intf.protocol,
+            },
+        });
+
+        // 複合デバイスはインターフェースごとに別のクラスを持つので、全部にドライバを付ける
+        let mut interfaces = Vec::new();
+        for intf in dev.selected_interfaces() {
+            let claimed_by = match (intf.class, intf.subclass, intf.protocol) {
+                // ブートインターフェースでないHIDはレポートディスクリプタにX, Yがあればマウスとして使う
+                (3, 1, 2) | (3, 0, _) => start_mouse(slot_id, intf).await?.then_some("mouse"),
+                (3, 1, 1) => {
+                    start_keyboard(slot_id, intf).await?;
+                    Some("keyboard")
+                }
+                (HUB_CLASS, _, _) => match dev.hub.and_then(|info| HubClass::new(slot_id, intf, info)) {
+                    Some(hub) => {
+                        let sender = self.address_device_sender.clone();
+                        spawn(async move { hub.run(sender).await });
+                        Some("hub")
                     }
-                })
-            } else if self.keyboard_callback.is_some()
-                && intf.class == 3
-                && intf.subclass == 1
-                && intf.protocol == 1
-            {
-                let callback = self.keyboard_callback.take().unwrap();
-                let key = KeyboardClass::new(slot_id, intf).unwrap();
-                key.initialize().await?;
-
-                spawn(async move {
-                    loop {
-                        let (recv, buf) = key.subscribe_once()?;
-                        if recv.await.unwrap().is_ok() {
-                            callback(buf);
-                        }
+                    None => {
+                        warn!("slot {slot_id}: only USB2 hubs on a root port are supported");
+                        None
                     }
-                })
-            }
+                },
+                _ => None,
+            };
+            interfaces.push(InterfaceInfo {
+                number: intf.interface_num,
+                class: intf.class,
+                subclass: intf.subclass,
+                protocol: intf.protocol,
+                claimed_by,
+            });
         }
+        dev.register(interfaces);
+        Ok(())
     }
 
     async fn construct_device(
         &mut self,
-        slot_id: usize,
+        slot_id: SlotId,
         confdesc_arr: Vec<Vec<Descriptor>>,
     ) -> Result<UsbDevice, XhciError> {
         let mut conf_arr: Vec<UsbConfiguration> = Vec::new();
         for conf in confdesc_arr {
-            let conf = construct_configuration(&conf).ok_or(XhciError::UnexpectedDescriptor)?;
+            let conf = construct_configuration(&conf).ok_or(XhciError::from(ErrorKind::UnexpectedDescriptor))?;
             conf_arr.push(conf);
         }
 
@@ -555,7 +817,7 @@ impl UsbDriver {
 
     async fn read_device_descriptor(
         &mut self,
-        slot_id: usize,
+        slot_id: SlotId,
     ) -> Result<DeviceDescriptor, XhciError> {
         let mut dev_desc = Box::<DeviceDescriptor>::default();
 
@@ -566,13 +828,17 @@ impl UsbDriver {
             length: 18,
         };
 
-        control_request(slot_id, setup, Some(&mut dev_desc.0))?.await.unwrap()?;
+        let done = with_request_timeout(slot_id, Operation::ReadDescriptor, control_transfer(slot_id, Operation::ReadDescriptor, setup, Some(&mut dev_desc.0))).await?;
+        // 短いデバイスディスクリプタの残りは初期値のままなので使えない
+        if done.bytes_transferred < dev_desc.0.len() {
+            return Err(XhciError::from(ErrorKind::UnexpectedDescriptor).during(Operation::ReadDescriptor).on_slot(slot_id));
+        }
 
         Ok(*dev_desc.as_ref())
     }
 
     async fn get_config_descriptor(
-        slot_id: usize,
+        slot_id: SlotId,
         i_conf: usize,
         buf_sz: usize,
     ) -> Result<Result<Vec<u8>, usize>, XhciError> {
@@ -585,18 +851,24 @@ impl UsbDriver {
             length: buf_sz as u16,
         };
 
-        control_request(slot_id, setup, Some(&mut buf))?.await.unwrap()?;
+        let done = with_request_timeout(slot_id, Operation::ReadDescriptor, control_transfer(slot_id, Operation::ReadDescriptor, setup, Some(&mut buf))).await?;
+        let valid = done.valid(&buf);
+        if valid.len() < 4 {
+            return Err(XhciError::from(ErrorKind::UnexpectedDescriptor).during(Operation::ReadDescriptor).on_slot(slot_id));
+        }
 
-        let total_len = u16::from_le_bytes([buf[2], buf[3]]);
+        let total_len = u16::from_le_bytes([valid[2], valid[3]]);
         if (total_len as usize) < buf_sz {
             return Ok(Err(total_len as usize));
         }
+        // wTotalLengthより短く返ってきたら、届いた分だけを解釈する
+        buf.truncate(done.bytes_transferred);
         Ok(Ok(buf))
     }
 
     async fn read_config(
         &mut self,
-        slot_id: usize,
+        slot_id: SlotId,
         i_conf: usize,
         buf_sz: usize,
     ) -> Result<Vec<Descriptor>, XhciError> {
@@ -606,21 +878,194 @@ impl UsbDriver {
                 .await?
                 .unwrap(),
         };
-        let mut ptr = buf.as_ptr();
-        let mut descs: Vec<Descriptor> = Vec::new();
-
-        let total_len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
-        let buf_end = unsafe { buf.as_ptr().add(total_len) };
-
-        while ptr < buf_end {
-            let Some((desc, next_ptr)) = (unsafe { read_descriptor(ptr) }) else {
-                break;
-            };
+        let descs = parse_descriptors(&buf);
+        for desc in &descs {
             println!("{:?}", desc);
-            descs.push(desc);
-            ptr = next_ptr;
         }
 
         Ok(descs)
     }
 }
+
+/// マウスを初期化し、レポートを配信するタスクを起動する。ブートインターフェースでないマウスはレポートディスクリプタの並びで読む。
+/// マウスとして使わないインターフェースならfalse
+async fn start_mouse(slot_id: SlotId, intf: &UsbInterfaceAlternate) -> Result<bool, XhciError> {
+    let mut mouse = MouseClass::new(slot_id, intf).ok_or(XhciError::from(ErrorKind::UnexpectedDescriptor))?;
+    if !mouse.initialize().await? {
+        return Ok(false);
+    }
+    println!("slot {slot_id}: mouse layout {:?}", mouse.layout());
+
+    spawn(async move {
+        ignore_disconnect(async {
+            let _active = track_endpoint(slot_id, mouse.dci());
+            let mut failures = 0;
+            loop {
+                // サスペンド中はTDを投入しない。止められたTDはErrかCanceledで返ってくる
+                wait_running().await;
+                let (recv, buf) = mouse.subscribe_once()?;
+                match recv.await {
+                    Ok(Ok(event)) => {
+                        failures = 0;
+                        if let Some(report) = mouse.decode(buf.as_ref(), &event) {
+                            publish_mouse(MouseEvent { slot: slot_id, report });
+                        }
+                    }
+                    Ok(Err(e)) if e.is_disconnected() => return Err(e),
+                    Ok(Err(e)) if e.halts_endpoint() => recover_or_give_up(slot_id, mouse.dci(), e, &mut failures).await?,
+                    _ => {}
+                }
+            }
+        }).await
+    });
+    Ok(true)
+}
+
+/// ブートプロトコルのキーボードを初期化し、レポートとロックキーの要求を処理するタスクを起動する
+async fn start_keyboard(slot_id: SlotId, intf: &UsbInterfaceAlternate) -> Result<(), XhciError> {
+    let mut key = KeyboardClass::new(slot_id, intf).ok_or(XhciError::from(ErrorKind::UnexpectedDescriptor))?;
+    key.initialize().await?;
+
+    spawn(async move {
+        ignore_disconnect(async {
+            let _active = track_endpoint(slot_id, key.dci());
+            let (lock_tx, lock_rx) = new_channel("usb-lock-request");
+            let _lock_requests = subscribe_lock_requests(lock_tx);
+            let mut last_report = KeyReport::default();
+            let mut pending = None;
+            let mut failures = 0;
+            loop {
+                if pending.is_none() {
+                    wait_running().await;
+                    pending = Some(key.subscribe_once()?);
+                }
+                let (recv, _) = pending.as_mut().unwrap();
+                match select(recv, lock_rx.receive_async()).await {
+                    Either::Left((result, _)) => {
+                        let (_, buf) = pending.take().unwrap();
+                        match result {
+                            Ok(Ok(_)) => {
+                                failures = 0;
+                                if let Err(e) = key.on_report(&buf).await {
+                                    led_failed(slot_id, e)?;
+                                }
+                                last_report = (*buf).clone();
+                                publish_keyboard(KeyEvent { slot: slot_id, report: *buf, locks: key.keymap().locks() });
+                            }
+                            Ok(Err(e)) if e.is_disconnected() => return Err(e),
+                            Ok(Err(e)) if e.halts_endpoint() => recover_or_give_up(slot_id, key.dci(), e, &mut failures).await?,
+                            _ => {}
+                        }
+                    }
+                    // 投入したTDはそのまま待ち続ける
+                    Either::Right((keycode, _)) => {
+                        if let Err(e) = key.toggle_lock(keycode).await {
+                            led_failed(slot_id, e)?;
+                        }
+                        publish_keyboard(KeyEvent { slot: slot_id, report: last_report.clone(), locks: key.keymap().locks() });
+                    }
+                }
+            }
+        }).await
+    });
+    Ok(())
+}
+
+/// 続けてこの回数だけエンドポイントが止まったら、戻すのを諦めてタスクを終える
+const MAX_ENDPOINT_RECOVERIES: u32 = 5;
+
+/// 転送エラーで止まったエンドポイントを戻す。成功せずに続けて止まった回数がMAX_ENDPOINT_RECOVERIESを超えたらeを返す
+async fn recover_or_give_up(slot_id: SlotId, dci: Dci, e: XhciError, failures: &mut u32) -> Result<(), XhciError> {
+    *failures += 1;
+    if *failures > MAX_ENDPOINT_RECOVERIES {
+        return Err(e);
+    }
+    warn!("slot {slot_id}: {e}, recovering the endpoint ({}/{MAX_ENDPOINT_RECOVERIES})", *failures);
+    recover_endpoint(slot_id, dci, e.is_stall()).await
+}
+
+/// LEDを変えられなくてもキーの入力は続ける。デバイスが外れていたときだけeを返す
+fn led_failed(slot_id: SlotId, e: XhciError) -> Result<(), XhciError> {
+    if e.is_disconnected() {
+        return Err(e);
+    }
+    warn!("slot {slot_id}: failed to set keyboard LEDs: {e}");
+    Ok(())
+}
+
+/// デバイスが外れて終わったクラスドライバのタスクは、エラーとして報告しない
+async fn ignore_disconnect(fut: impl Future<Output = Result<(), XhciError>>) -> Result<(), XhciError> {
+    match fut.await {
+        Err(e) if e.is_disconnected() => Ok(()),
+        result => result,
+    }
+}
+
+pub fn run_descriptor_tests() {
+    fn with_total_len(mut blob: Vec<u8>) -> Vec<u8> {
+        let len = blob.len() as u16;
+        blob[2..4].copy_from_slice(&len.to_le_bytes());
+        blob
+    }
+    const CONF: [u8; 9] = [9, 2, 0, 0, 2, 1, 0, 0x80, 50];
+    const HID: [u8; 9] = [9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0];
+    let intf = |num: u8, alt: u8, n_eps: u8| [9, 4, num, alt, n_eps, 3, 1, 1, 0];
+    let ep = |addr: u8| [7, 5, addr, 3, 8, 0, 10];
+    let slot = SlotId::new(1).unwrap();
+
+    // intf 0 alt 0, intf 1 alt 0, intf 0 alt 1 の順に並んだもの
+    let blob = with_total_len([
+        &CONF[..], &intf(0, 0, 1), &HID, &ep(0x81),
+        &intf(1, 0, 1), &[4, 0x24, 1, 2], &ep(0x82),
+        &intf(0, 1, 2), &ep(0x83), &ep(0x03),
+    ].concat());
+    let descs = parse_descriptors(&blob);
+    assert!(descs.len() == 10);
+    let conf = construct_configuration(&descs).unwrap();
+    assert!(conf.interfaces.len() == 2);
+    let (i0, i1) = (&conf.interfaces[0], &conf.interfaces[1]);
+    assert!(i0.interface_num() == 0 && i0.alternates.len() == 2 && i1.interface_num() == 1 && i1.alternates.len() == 1);
+    let alt0 = i0.alternate(0).unwrap();
+    assert!(alt0.hid().is_some_and(|h| h.class_descriptor_length() == 63) && alt0.endpoints().len() == 1 && alt0.other().is_empty());
+    assert!(alt0.endpoints()[0].max_packet_size() == 8);
+    assert!(i0.alternate(1).unwrap().endpoints().len() == 2 && i0.alternate(1).unwrap().hid().is_none());
+    let alt1 = i1.alternate(0).unwrap();
+    assert!(alt1.other().len() == 1 && alt1.endpoints()[0].calc_dci() == Dci::new(5));
+
+    let mut dev = UsbDevice::new(slot, vec![conf]);
+    dev.config_selected = Some(0);
+    dev.alternates_selected = vec![0, 0];
+    assert!(dev.endpoint_context_layout() == (1 | 1 << 3 | 1 << 5, Dci::new(5).unwrap()));
+    dev.alternates_selected = vec![1, 0];
+    assert!(dev.endpoint_context_layout() == (1 | 1 << 5 | 1 << 6 | 1 << 7, Dci::new(7).unwrap()));
+    // インターフェース1も代替設定に関係なく列挙される
+    let selected: Vec<(u8, u8)> = dev.selected_interfaces().map(|a| (a.interface_num(), a.alternate_setting_num())).collect();
+    assert!(selected == [(0, 1), (1, 0)]);
+
+    // 順番通りに並んだものは従来通りのフラグになる
+    let blob = with_total_len([
+        &CONF[..], &intf(0, 0, 1), &HID, &ep(0x81),
+        &intf(1, 0, 2), &ep(0x82), &ep(0x02),
+    ].concat());
+    let conf = construct_configuration(&parse_descriptors(&blob)).unwrap();
+    let mut dev = UsbDevice::new(slot, vec![conf]);
+    dev.config_selected = Some(0);
+    dev.alternates_selected = vec![0, 0];
+    assert!(dev.endpoint_context_layout() == (1 | 1 << 3 | 1 << 4 | 1 << 5, Dci::new(5).unwrap()));
+
+    // 末尾で切れたディスクリプタは捨てる
+    let mut truncated = blob.clone();
+    truncated.truncate(blob.len() - 3);
+    truncated[2..4].copy_from_slice(&(blob.len() as u16).to_le_bytes());
+    assert!(parse_descriptors(&truncated).len() == 6);
+
+    // 文字列ディスクリプタはUTF-16LE。bLengthより短く届いたら届いた分だけ、種類が違うものや短すぎるものは読めない
+    assert!(decode_string_descriptor(&[8, 3, b'U', 0, b'S', 0, b'B', 0]).as_deref() == Some("USB"));
+    assert!(decode_string_descriptor(&[8, 3, b'U', 0, b'S', 0]).as_deref() == Some("US"));
+    assert!(decode_string_descriptor(&[2, 3]).as_deref() == Some(""));
+    assert!(decode_string_descriptor(&[6, 3, 0x42, 0x30, 0x00, 0xd8]).as_deref() == Some("あ\u{fffd}"));
+    assert!(decode_string_descriptor(&[4, 2, b'A', 0]).is_none());
+    assert!(decode_string_descriptor(&[1, 3]).is_none() && decode_string_descriptor(&[4]).is_none());
+    assert!(choose_lang_id(&[0x0411, 0x0409]) == Some(0x0409));
+    assert!(choose_lang_id(&[0x0411]) == Some(0x0411) && choose_lang_id(&[]).is_none());
+}
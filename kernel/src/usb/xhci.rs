@@ -1,12 +1,14 @@
 use core::{
     alloc::{AllocError, Allocator, Layout},
     mem::transmute,
+    future::poll_fn,
+    pin::pin,
     ptr::{read_volatile, write_volatile, NonNull},
 };
 
-use alloc::
-    alloc::Global
-;
+use alloc::{
+    alloc::Global, vec::Vec
+};
 use bitfield::bitfield;
 use futures::channel::oneshot;
 use num_traits::cast::FromPrimitive;
@@ -14,20 +16,20 @@ use xhci::{
     accessor::Mapper,
     ring::trb,
     ring::trb::{
-        event::{CommandCompletion, TransferEvent},
+        event::{CommandCompletion, CompletionCode, PortStatusChange, TransferEvent},
         Type,
     },
     Registers,
 };
 
 use crate::{
-    memory_manager::LazyInit, pci::PCIDevice, println, usb::{
-        action::init_device::DeviceInitAction, device::init_dcbaa, ring::{command::init_command_ring, event::init_event_ring, transfer::TransferRingSet}, runtime::new_channel
+    addr::PhysAddr, memory_manager::{LazyInit, Mutex}, paging::map_mmio, pci::PCIDevice, println, timer::{get_current_tick, ms_to_ticks}, usb::{
+        action::init_device::DeviceInitAction, device::init_dcbaa, ring::{command::init_command_ring, event::{init_event_ring, EVENT_RING_SEGMENTS, EVENT_RING_SEGMENT_SIZE}, transfer::TransferRingSet}, runtime::{new_bounded_channel, new_channel, SendPolicy}
     }
 };
 
 use super::{
-    device::Dcbaa, ring::{command::CommandRing, event::EventRing, transfer::SetupData}, runtime::{Sender, Spawner}, 
+    device::Dcbaa, doorbell::{Dci, PortId, SlotId}, ring::{command::CommandRing, event::EventRing, transfer::{ControlCompletion, ControlRequest, ControlRequestType, SetupData}}, runtime::{timeout_at, Sender, Spawner}, slot::SlotState,
 };
 
 static EVENT_RING: LazyInit<EventRing> = LazyInit::new();
@@ -35,16 +37,12 @@ static CMD_RING: LazyInit<CommandRing> = LazyInit::new();
 static TRF_RINGS: LazyInit<TransferRingSet> = LazyInit::new();
 static DCBAA: LazyInit<Dcbaa> = LazyInit::new();
 static REGS: LazyInit<Registers<LinearMapper>> = LazyInit::new();
+/// ポートごとのUSBのメジャーバージョン(Supported Protocol Capabilityによる)。0は不明
+static PORT_MAJOR_REVISION: Mutex<[u8; 256]> = Mutex::new([0; 256]);
+/// ポートの状態変化をデバイス初期化タスクに伝えるチャネル。レジューム時の再列挙に使う
+static PORT_EVENTS: LazyInit<Sender<PortStatusChange>> = LazyInit::new();
 
-#[derive(Debug)]
-pub enum XhciError {
-    InvalidTrb,
-    RingIsFull,
-    InvalidCommandCompletionTrb,
-    AddressDeviceCommandFailed(CommandCompletion),
-    UnexpectedDescriptor,
-    TransferError(TransferEvent),
-}
+pub use super::error::{ErrorContext, ErrorKind, Operation, XhciError};
 
 #[repr(C)]
 struct XhciCapability {
@@ -65,26 +63,142 @@ impl XhciCapability {
     }
 }
 
-pub fn push_command(trb: trb::command::Allowed) -> Result<oneshot::Receiver<CommandCompletion>, XhciError> {
-    CMD_RING.lock().push_command(trb, &mut REGS.lock())
+/// コマンドを投入する。リングが一杯なら、先に投入したコマンドが完了して空きができるまで待つ
+pub async fn push_command_async(trb: trb::command::Allowed) -> Result<oneshot::Receiver<CommandCompletion>, XhciError> {
+    poll_fn(|cx| CMD_RING.lock().poll_push_command(trb, &mut REGS.lock(), cx)).await
+}
+
+/// リングの大きさを超える数のNo Opコマンドを続けて投入し、全て成功で完了することを確かめる (usb-stress-test feature)
+#[cfg(feature = "usb-stress-test")]
+async fn run_command_ring_stress_test() -> Result<(), XhciError> {
+    const COUNT: usize = 100;
+    let mut pending = Vec::with_capacity(COUNT);
+    for _ in 0..COUNT {
+        pending.push(push_command_async(trb::command::Allowed::Noop(trb::command::Noop::new())).await?);
+    }
+    for recv in pending {
+        let c = recv.await?;
+        if c.completion_code() != Ok(CompletionCode::Success) {
+            return Err(ErrorKind::CommandFailed(c).into());
+        }
+    }
+    println!("xHCI: {COUNT} no-op commands completed");
+    Ok(())
+}
+
+/// コントロール転送はAddressed以降、それ以外のエンドポイントはConfiguredのときだけ受け付ける
+fn check_slot_for_transfer(slot_id: SlotId, dci: Dci) -> Result<(), XhciError> {
+    let allowed: &[SlotState] = if dci == Dci::CONTROL {
+        &[SlotState::Addressed, SlotState::Configured]
+    } else {
+        &[SlotState::Configured]
+    };
+    let dcbaa = DCBAA.lock();
+    // 後始末中・後始末済みのスロットは、外されたデバイスのものとして扱う
+    if matches!(dcbaa.slots().state(slot_id), SlotState::TearingDown | SlotState::Empty) {
+        return Err(XhciError::from(ErrorKind::Disconnected).on_slot(slot_id).on_endpoint(dci));
+    }
+    dcbaa.slots().require(slot_id, allowed).on_endpoint(dci)
 }
 
 pub fn push_transfer_trb(
-    slot_id: usize,
-    endpoint_id: usize,
+    slot_id: SlotId,
+    dci: Dci,
     trb: trb::transfer::Allowed,
 ) -> Result<Option<oneshot::Receiver<Result<TransferEvent, XhciError>>>, XhciError> {
-    TRF_RINGS.lock().push_transfer_trb(slot_id, endpoint_id, trb)
+    check_slot_for_transfer(slot_id, dci)?;
+    TRF_RINGS.lock().push_transfer_trb(slot_id, dci, trb)
 }
 
 pub fn control_request(
-    slot_id: usize,
+    slot_id: SlotId,
     setup: SetupData,
     data: Option<&mut [u8]>,
-) -> Result<oneshot::Receiver<Result<TransferEvent, XhciError>>, XhciError> {
+) -> Result<ControlRequest, XhciError> {
+    check_slot_for_transfer(slot_id, Dci::CONTROL)?;
     TRF_RINGS.lock().control_request(slot_id, setup, data, &mut REGS.lock())
 }
 
+/// 使用中のスロットとその状態。xHCIを初期化していなければ空
+pub fn slot_states() -> Vec<(SlotId, SlotState)> {
+    let dcbaa = DCBAA.lock();
+    if !dcbaa.is_init() {
+        return Vec::new();
+    }
+    dcbaa.slots().states()
+}
+
+/// コントロール転送を行って完了を待つ。失敗したらopとslot_idをエラーに付ける
+/// デバイスは要求より短く返すことがあるので、読んだ内容はvalidで切り出して使う
+pub async fn control_transfer(
+    slot_id: SlotId,
+    op: Operation,
+    setup: SetupData,
+    data: Option<&mut [u8]>,
+) -> Result<ControlCompletion, XhciError> {
+    let req = control_request(slot_id, setup, data).during(op).on_slot(slot_id)?;
+    req.await.during(op).on_slot(slot_id)
+}
+
+/// recover_endpointの各コマンドと要求を待つ時間
+const RECOVERY_TIMEOUT_MS: u64 = 100;
+/// USB 2.0 9.4.5の標準機能セレクタ
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// コマンドを投入して期限まで完了を待つ。Context State Errorは、既に望みの状態にあるものとして成功に含める
+async fn run_command(trb: trb::command::Allowed, deadline: u64) -> Result<(), XhciError> {
+    let recv = push_command_async(trb).await?;
+    let c = timeout_at(deadline, recv).await.ok_or(ErrorKind::Timeout(RECOVERY_TIMEOUT_MS))??;
+    match c.completion_code() {
+        Ok(CompletionCode::Success | CompletionCode::ContextStateError) => Ok(()),
+        _ => Err(ErrorKind::CommandFailed(c).into()),
+    }
+}
+
+/// 転送エラーで止まった(Halted)エンドポイントを、また転送できるようにする。クラスドライバはこの後でTDを投入し直す
+/// Reset Endpointで止めた状態にし、リングに残ったTDを捨ててデキューポインタをエンキューの位置に合わせる。
+/// STALLならデバイス側のHaltもClearFeature(ENDPOINT_HALT)で解く。デフォルトコントロールパイプのSTALLは次のSETUPで解ける
+pub async fn recover_endpoint(slot_id: SlotId, dci: Dci, stalled: bool) -> Result<(), XhciError> {
+    let deadline = get_current_tick() + ms_to_ticks(RECOVERY_TIMEOUT_MS);
+    let mut reset = trb::command::ResetEndpoint::new();
+    reset.set_slot_id(slot_id.get()).set_endpoint_id(dci.get());
+    run_command(trb::command::Allowed::ResetEndpoint(reset), deadline).await
+        .during(Operation::RecoverEndpoint).on_slot(slot_id).on_endpoint(dci)?;
+
+    let (ptr, cycle) = with_trf_rings(|t| t.discard_pending(slot_id, dci))
+        .ok_or_else(|| XhciError::from(ErrorKind::NoSuchRing).during(Operation::RecoverEndpoint).on_slot(slot_id).on_endpoint(dci))?;
+    let mut set_deq = trb::command::SetTrDequeuePointer::new();
+    set_deq.set_new_tr_dequeue_pointer(ptr.as_u64())
+        .set_slot_id(slot_id.get())
+        .set_endpoint_id(dci.get());
+    if cycle {
+        set_deq.set_dequeue_cycle_state();
+    }
+    run_command(trb::command::Allowed::SetTrDequeuePointer(set_deq), deadline).await
+        .during(Operation::RecoverEndpoint).on_slot(slot_id).on_endpoint(dci)?;
+
+    if stalled && dci != Dci::CONTROL {
+        let setup = SetupData {
+            request_type: ControlRequestType::ClearEndpointFeature,
+            value: FEATURE_ENDPOINT_HALT,
+            index: dci.endpoint_address() as u16,
+            length: 0,
+        };
+        let clear = pin!(control_transfer(slot_id, Operation::RecoverEndpoint, setup, None));
+        timeout_at(deadline, clear).await
+            .ok_or_else(|| XhciError::from(ErrorKind::Timeout(RECOVERY_TIMEOUT_MS)).during(Operation::RecoverEndpoint).on_slot(slot_id))??;
+    }
+    Ok(())
+}
+
+/// ポートの状態変化があったものとしてデバイス初期化タスクに通知する。
+/// コントローラが止まっている間に起きた変化はイベントにならないことがあるので、その補填に使う
+pub fn notify_port_status(port_id: PortId) {
+    let mut raw = PortStatusChange::default().into_raw();
+    raw[0] = u32::from(port_id.get()) << 24;
+    PORT_EVENTS.lock().send(PortStatusChange::try_from(raw).unwrap());
+}
+
 pub fn on_xhc_interrupt() {
     EVENT_RING.lock().on_xhc_interrupt(&mut REGS.lock());
 }
@@ -93,6 +207,11 @@ pub fn with_regs<R>(f: impl FnOnce(&mut Registers<LinearMapper>)->R) -> R {
     f(&mut REGS.lock())
 }
 
+/// コントローラのルートハブのポート全部
+pub fn root_ports() -> impl Iterator<Item = PortId> {
+    PortId::all(with_regs(|r| r.capability.hcsparams1.read_volatile().number_of_ports()))
+}
+
 pub fn with_dcbaa<R>(f: impl FnOnce(&mut Dcbaa)->R) -> R {
     f(&mut DCBAA.lock())
 }
@@ -105,15 +224,26 @@ pub unsafe fn initialize_xhci(
     xhc: PCIDevice,
     intel_ehci_found: bool,
     spawner: &mut Spawner<'static, Result<(), XhciError>>,
-    addr_send: Sender<usize>
-)
+    addr_send: Sender<SlotId>
+) -> Result<(), XhciError>
 {
-    let xhc_bar = xhc.read_bar(0);
-    let mmio_base = (xhc_bar & !0b1111_u64) as usize;
+    let bar = match xhc.bar_info(0) {
+        Some(bar) if !bar.is_io && bar.size != 0 => bar,
+        _ => return Err(ErrorKind::BarNotMemory.into()),
+    };
+    let mmio_base = bar.addr as usize;
+    // レジスタがキャッシュされないよう、触る前にキャッシュ無効で写像する。恒等写像の範囲の外にあってもここで届く
+    if let Err(e) = map_mmio(PhysAddr::new(bar.addr), bar.size) {
+        return Err(ErrorKind::BarNotMapped(bar.addr, e).into());
+    }
+    // ファームウェアが有効にしていなければ、レジスタにもリングのDMAにもMSIにも応答しない
+    xhc.enable_memory_space();
+    xhc.enable_bus_master();
 
     let mut regs = xhci::Registers::new(mmio_base, LinearMapper {});
 
     ownership_handoff(&regs, mmio_base as u64);
+    read_supported_protocols(&regs, mmio_base as u64);
 
     if intel_ehci_found {
         println!("Switching eHCI ports to xHCI");
@@ -127,14 +257,16 @@ pub unsafe fn initialize_xhci(
     reset_hc(&mut regs);
 
     let num_ports = regs.capability.hcsparams1.read_volatile().number_of_ports();
-    let mut dcbaa = init_dcbaa(&mut regs);
+    let mut dcbaa = init_dcbaa(&mut regs)?;
     
-    let (cmd_send, cmd_recv) = new_channel();
-    let (trf_send, trf_recv) = new_channel();
-    let (port_send, port_recv) = new_channel();
+    // コマンド・転送の完了を落とすと待っているタスクが永遠に止まるので、これらには容量を設けない
+    let (cmd_send, cmd_recv) = new_channel("xhci-command");
+    let (trf_send, trf_recv) = new_channel("xhci-transfer");
+    let (port_send, port_recv) = new_bounded_channel("xhci-port", EVENT_RING_SEGMENT_SIZE, SendPolicy::DropOldest);
     
     let cmd_ring = init_command_ring(32, &mut regs);
-    let event_ring = init_event_ring(&mut regs, trf_send, cmd_send, port_send);
+    PORT_EVENTS.lock().init(port_send.clone());
+    let event_ring = init_event_ring(&mut regs, EVENT_RING_SEGMENTS, EVENT_RING_SEGMENT_SIZE, trf_send, cmd_send, port_send);
 
     enable_xhci_interrupt_and_start(&mut regs);
 
@@ -159,12 +291,17 @@ pub unsafe fn initialize_xhci(
         }
     });
 
+    #[cfg(feature = "usb-stress-test")]
+    spawner.spawn(run_command_ring_stress_test());
+
     spawner.spawn(async move {
         let mut device_initializer = DeviceInitAction::new(port_recv, addr_send);
         device_initializer.main_loop().await;
         Ok(())
     });
 
+    Ok(())
+
     // let mut usbd = UsbDriver::new(addr_receiver, Box::new(mouse_callback));
 
     // println!("xHCI initialization complete");
@@ -223,6 +360,46 @@ fn ownership_handoff(regs: &Registers<LinearMapper>, mmio_base: u64) {
     }
 }
 
+/// Supported Protocol Capabilityを読み、各ポートがUSB2とUSB3のどちらかを記録する
+fn read_supported_protocols(regs: &Registers<LinearMapper>, mmio_base: u64) {
+    let ex_cap_ptr = regs
+        .capability
+        .hccparams1
+        .read_volatile()
+        .xhci_extended_capabilities_pointer() as u64;
+    if ex_cap_ptr == 0 {
+        return;
+    }
+    let mut cap = (mmio_base + ex_cap_ptr * 4) as *const XhciCapability;
+    let mut revisions = PORT_MAJOR_REVISION.lock();
+
+    loop {
+        let c = unsafe { read_volatile(cap) };
+        if c.cap_id == 2 {
+            let [_, major] = c.cap_specific.to_le_bytes();
+            let ports = unsafe { read_volatile((cap as u64 + 8) as *const u32) };
+            // Compatible Port Offset (1始まり), Compatible Port Count
+            let [offset, count, ..] = ports.to_le_bytes();
+            let range: Vec<PortId> = (offset..offset.saturating_add(count)).filter_map(PortId::new).collect();
+            if let (Some(first), Some(last)) = (range.first(), range.last()) {
+                for port_id in &range {
+                    revisions[port_id.index()] = major;
+                }
+                println!("xHCI: ports {first}-{last} are USB{major}");
+            }
+        }
+        match unsafe { (*cap).next() } {
+            Some(next) => cap = next,
+            None => return,
+        }
+    }
+}
+
+/// ポートがUSB3(SuperSpeed)のポートならtrue
+pub fn is_usb3_port(port_id: PortId) -> bool {
+    PORT_MAJOR_REVISION.lock()[port_id.index()] >= 3
+}
+
 fn find_lsb(bits: u16) -> usize {
     for i in 0..15 {
         if (bits >> i) & 1 == 1 {
@@ -0,0 +1,239 @@
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::{console::StackWriter, draw_window, initrd, graphic::{focus, font::write_string, graphics::{Color, PixelWriter, Rect}, palette, window::{close_button_rect, title_bar_rect, LayerHandle, LayerId, Placement, Window}, with_layers}, memory_manager::Mutex, shortcut::{self, Mods}};
+
+/// 表示できるファイル。ファイルシステムがないのでカーネルに埋め込んでおく
+const FILES: [(&str, &str); 3] = [
+    ("README.md", include_str!("../../README.md")),
+    ("LICENSE.txt", include_str!("../../LICENSE.txt")),
+    ("LICENSE_mikanOS.txt", include_str!("../../LICENSE_mikanOS.txt")),
+];
+
+/// ビューアを開くキー (F9)
+const KEY_OPEN: u8 = 0x42;
+const KEY_Q: u8 = 0x14;
+const KEY_PAGE_UP: u8 = 0x4b;
+const KEY_PAGE_DOWN: u8 = 0x4e;
+const KEY_DOWN: u8 = 0x51;
+const KEY_UP: u8 = 0x52;
+
+const COLS: usize = 48;
+const ROWS: usize = 12;
+/// 本文の左上の位置
+const TEXT_X: i32 = 8;
+const TEXT_Y: i32 = 28;
+const WIN_W: usize = 8 * COLS + 16;
+const WIN_H: usize = 16 * ROWS + 36;
+const BG: Color = palette::TEXT_BG;
+const FG: Color = palette::contrast_text_color(palette::TEXT_BG);
+
+/// テキストを折り返して表示し、スクロールできるウィンドウ
+struct Viewer {
+    layer: LayerHandle,
+    name: &'static str,
+    text: &'static str,
+    /// 折り返した各行の text 上の範囲
+    lines: Vec<(usize, usize)>,
+    /// 一番上に表示している行
+    top: usize,
+    open: bool,
+}
+
+static VIEWER: Mutex<Option<Viewer>> = Mutex::new(None);
+
+/// 埋め込みのファイルか、initrdの中のUTF-8のファイルを探す
+fn find(name: &str) -> Option<(&'static str, &'static str)> {
+    if let Some(&file) = FILES.iter().find(|(n, _)| *n == name) {
+        return Some(file);
+    }
+    let entry = initrd::find_entry(name)?;
+    Some((entry.name, core::str::from_utf8(entry.data).ok()?))
+}
+
+/// ファイルをビューアで開く。既に開いていれば中身を差し替える
+/// 見つからないかUTF-8でなければfalse
+pub fn open(name: &str) -> bool {
+    let Some((name, text)) = find(name) else {
+        return false;
+    };
+
+    let mut viewer = VIEWER.lock();
+    if viewer.is_none() {
+        let mut win = Window::new(WIN_W, WIN_H, Some(palette::WINDOW_GRAY));
+        win.move_to((300, 120).into());
+        win.set_client_area(Some(Rect::from_wh(TEXT_X, TEXT_Y, 8 * COLS as i32, 16 * ROWS as i32)));
+        let layer = with_layers(|l| l.new_layer_deferred(win, Placement::Hidden));
+        register_keys(layer.layer_id());
+        *viewer = Some(Viewer { layer, name, text, lines: Vec::new(), top: 0, open: false });
+    }
+    let v = viewer.as_mut().unwrap();
+    v.name = name;
+    v.text = text;
+    v.lines = wrap(text, COLS);
+    v.top = 0;
+    v.render_all();
+    if !v.open {
+        v.open = true;
+        let layer_id = v.layer.layer_id();
+        drop(viewer);
+        focus::register_window(layer_id, name);
+        focus::set_close_handler(layer_id, |_| close());
+    } else {
+        let layer_id = v.layer.layer_id();
+        drop(viewer);
+        focus::set_focus(layer_id);
+    }
+    true
+}
+
+/// ウィンドウを閉じる。レイヤは次に開くときに使い回す
+pub fn close() {
+    let mut viewer = VIEWER.lock();
+    let Some(v) = viewer.as_mut().filter(|v| v.open) else {
+        return;
+    };
+    v.open = false;
+    let layer_id = v.layer.layer_id();
+    drop(viewer);
+    focus::unregister_window(layer_id);
+    with_layers(|l| l.hide(layer_id));
+}
+
+/// F9でLICENSE.txtを開けるようにする
+pub fn init_viewer() {
+    shortcut::register_global(Mods::NONE, KEY_OPEN, "viewer", |_| { open("LICENSE.txt"); }, 0).expect("viewer: open hotkey");
+}
+
+/// ビューアにフォーカスがあるときだけ効くキー。argはスクロールする行数
+fn register_keys(layer_id: LayerId) {
+    let keys: [(u8, fn(usize), isize); 5] = [
+        (KEY_Q, |_| close(), 0),
+        (KEY_UP, scroll_by, -1),
+        (KEY_DOWN, scroll_by, 1),
+        (KEY_PAGE_UP, scroll_by, -(ROWS as isize)),
+        (KEY_PAGE_DOWN, scroll_by, ROWS as isize),
+    ];
+    for (key, handler, lines) in keys {
+        shortcut::register_window(layer_id, Mods::NONE, key, "viewer", handler, lines as usize).expect("viewer: keys");
+    }
+}
+
+fn scroll_by(lines: usize) {
+    let mut viewer = VIEWER.lock();
+    if let Some(v) = viewer.as_mut().filter(|v| v.open) {
+        let top = v.top as isize;
+        v.scroll_to(top + lines as isize);
+    }
+}
+
+/// textを幅colsで折り返し、各行の範囲を返す
+fn wrap(text: &str, cols: usize) -> Vec<(usize, usize)> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, b) in text.bytes().enumerate() {
+        if b == b'\n' {
+            lines.push((start, i));
+            start = i + 1;
+        } else if i - start == cols {
+            lines.push((start, i));
+            start = i;
+        }
+    }
+    if start < text.len() {
+        lines.push((start, text.len()));
+    }
+    lines
+}
+
+impl Viewer {
+    fn max_top(&self) -> usize {
+        self.lines.len().saturating_sub(ROWS)
+    }
+
+    fn render_all(&self) {
+        let window = self.layer.window().read();
+        window.buffer().write_with(|back| {
+            draw_window(back, b"");
+            back.fill_rect((TEXT_X - 4, TEXT_Y - 4).into(), (8 * COLS as u32 + 8, 16 * ROWS as u32 + 8).into(), BG);
+            for row in 0..ROWS {
+                self.render_line(back, row);
+            }
+        });
+        drop(window);
+        self.render_title();
+    }
+
+    /// タイトルバーだけを描き直す
+    fn render_title(&self) {
+        let percent = match self.max_top() {
+            0 => 100,
+            max => self.top * 100 / max,
+        };
+        let mut title = StackWriter::new();
+        let _ = write!(title, "view {} [{}%]", self.name, percent);
+        let window = self.layer.window().read();
+        window.buffer().write_with(|back| {
+            // 閉じるボタンは残す
+            let bar = title_bar_rect(WIN_W);
+            back.fill_rect((bar.x1, bar.y1).into(), ((close_button_rect(WIN_W).x1 - bar.x1) as u32, (bar.y2 - bar.y1) as u32).into(), palette::TITLE_BLUE);
+            write_string(back, 24, 4, title.as_bytes(), palette::TITLE_TEXT);
+        });
+        window.buffer().flush();
+    }
+
+    fn render_line(&self, back: &mut impl PixelWriter, row: usize) {
+        let y = TEXT_Y + 16 * row as i32;
+        back.fill_rect((TEXT_X, y).into(), (8 * COLS as u32, 16).into(), BG);
+        if let Some(&(start, end)) = self.lines.get(self.top + row) {
+            write_string(back, TEXT_X as u32, y as u32, &self.text.as_bytes()[start..end], FG);
+        }
+    }
+
+    /// 表示位置を変える。画面内に残る行はmove_rectで動かし、新しく見える行だけを描く
+    fn scroll_to(&mut self, top: isize) {
+        let top = top.clamp(0, self.max_top() as isize) as usize;
+        if top == self.top {
+            return;
+        }
+        let delta = top as isize - self.top as isize;
+        self.top = top;
+
+        let n = delta.unsigned_abs();
+        let window = self.layer.window().read();
+        window.buffer().write_with(|back| {
+            if n >= ROWS {
+                for row in 0..ROWS {
+                    self.render_line(back, row);
+                }
+                return;
+            }
+            let keep = (16 * (ROWS - n)) as i32;
+            let shift = (16 * n) as i32;
+            let w = 8 * COLS as i32;
+            if delta > 0 {
+                back.move_rect((TEXT_X, TEXT_Y).into(), Rect::from_wh(TEXT_X, TEXT_Y + shift, w, keep));
+                for row in ROWS - n..ROWS {
+                    self.render_line(back, row);
+                }
+            } else {
+                back.move_rect((TEXT_X, TEXT_Y + shift).into(), Rect::from_wh(TEXT_X, TEXT_Y, w, keep));
+                for row in 0..n {
+                    self.render_line(back, row);
+                }
+            }
+        });
+        drop(window);
+        self.render_title();
+    }
+}
+
+pub fn run_viewer_tests() {
+    let text = "abc\n\n0123456789abc\nxy";
+    let lines = wrap(text, 5);
+    let expected = [(0, 3), (4, 4), (5, 10), (10, 15), (15, 18), (19, 21)];
+    assert!(lines.len() == expected.len());
+    assert!(lines.iter().zip(expected.iter()).all(|(a, b)| a == b));
+    assert!(wrap("", 5).is_empty());
+    assert!(wrap("12345\n", 5).len() == 1);
+}
@@ -4,19 +4,30 @@ SRC_DIR=/app/workspace
 IMG_FILE=$WORK_DIR/disk2.img
 
 QEMU_ARGS="-monitor stdio"
-while getopts :d option 
+AUTOEXEC=""
+INITRD=""
+while getopts :da:i: option 
 do
     case $option in 
         d)
             QEMU_ARGS="-gdb tcp::12345 -S -daemonize"
             ;;
+        a)
+            # 起動後に実行するスクリプト。#exit-qemuで終わると、成功なら33、失敗なら35で終了する
+            AUTOEXEC=$(realpath $OPTARG)
+            QEMU_ARGS="-serial stdio"
+            ;;
+        i)
+            # ESPに置くinitrd.img。cpio(newc)で、例えば find . | cpio -o -H newc で作る
+            INITRD=$(realpath $OPTARG)
+            ;;
         *) 
             echo "unexpected option"
             exit 1;
     esac
 done
 
-cd $SRC_DIR/kernel && cargo build
+cd $SRC_DIR/kernel && ./check_id_casts.sh && ./check_raw_colors.sh && cargo build
 cd $SRC_DIR/bootloader && cargo build
 
 cd $WORK_DIR
@@ -28,6 +39,12 @@ mmd -i $IMG_FILE EFI/BOOT
 cp $SRC_DIR/bootloader/target/x86_64-unknown-uefi/debug/Loader.efi $WORK_DIR/BOOTX64.EFI
 mcopy -i $IMG_FILE $WORK_DIR/BOOTX64.EFI ::EFI/BOOT
 mcopy -i $IMG_FILE $SRC_DIR/kernel/kernel.elf ::/
+if [ -n "$AUTOEXEC" ]; then
+    mcopy -i $IMG_FILE $AUTOEXEC ::/autoexec.sh
+fi
+if [ -n "$INITRD" ]; then
+    mcopy -i $IMG_FILE $INITRD ::/initrd.img
+fi
 
 DEVENV_DIR=$WORK_DIR/mikanos-build/devenv
 
@@ -40,6 +57,8 @@ qemu-system-x86_64 \
     -drive if=ide,index=0,media=disk,format=raw,file=$IMG_FILE \
     -device nec-usb-xhci,id=xhci \
     -device usb-mouse -device usb-kbd \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x01 \
+    -debugcon file:$WORK_DIR/debugcon.log \
     -vnc :0 \
     $QEMU_ARGS
 